@@ -0,0 +1,953 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::encryption::{Vault, is_encrypted};
+use crate::exit_codes::{CliError, ExitCode};
+use crate::keepalive::Keepalive;
+use crate::offline_queue::{QueuedAction, QueuedOperation, ReplayTarget};
+use crate::pending::PendingRequests;
+use crate::quic::{self, QuicPlane, QUIC_BUILT};
+use crate::retry::RetryPolicy;
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, ChecksumHasher, checksum_file};
+use hermes_common::compression::{Block, Compression, skip_blocks};
+use hermes_common::file_io::{receive_network_binary, receive_network_file_checked, send_network_file, detect_file_type, DirectoryContent, DirectoryInfo, Encryption, FileChunkIter, FileType};
+use hermes_common::codec::Codec;
+use hermes_common::framing::{read_frame, read_any_frame, write_frame, write_frame_with, Frame};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, connect_message, extract_connect_ack_message, extract_session_ack, attach_session, close_message, extract_ack_message};
+use hermes_common::messages::{dir_query_request, extract_dir_response_message, download_message_request, extract_download_response_message, DirQuery};
+use hermes_common::messages::{upload_message, extract_upload_response_message, delete_message, subfolder_message, SubfolderAction, change_dir_message, move_message, rename_message};
+use hermes_common::messages::{advertise_compressions, extract_compressions, attach_compression, extract_compression, stats_request_message, extract_stats_response_message};
+use hermes_common::messages::{advertise_capabilities, extract_capabilities, extract_idle_timeout, ping_message, extract_pong_message, extract_close_reason, cancel_message};
+use hermes_common::messages::{timed_ping_message, attach_latency, advertise_codecs, extract_codecs, attach_channel, extract_channel, DownloadResponse};
+use hermes_common::messages::{preview_message, extract_preview_response, FilePreview, usage_request, extract_usage_response, UsageReport};
+use hermes_common::messages::{attach_quic, extract_quic, extract_quic_offer, attach_encryption, attach_interactive, extract_queued_message, MessageType};
+use hermes_common::messages::{attach_idempotency_key, stat_message_request, extract_stat_response_message};
+use hermes_common::messages::{changes_since_request, extract_changes_since_response, ChangeReport, FileChange, FileEvent, FileEventKind};
+use hermes_common::network_stats::{Latency, TransferStats};
+use hermes_common::progress::Progress;
+use hermes_common::protocol::{Capabilities, Capability, CURRENT_PROTOCOL_VERSION};
+use hermes_common::socket::SocketOptions;
+use hermes_common::transport::{SyncTransport, Transport};
+use rustls::ClientConfig;
+
+// Downloads are written beside their destination under this suffix, and only moved into place once the checksum matches
+pub const PARTIAL_SUFFIX: &str = ".hermes-sync.part";
+// Encrypted downloads arrive under this suffix, and are decrypted into the partial file before it is moved into place
+const SEALED_SUFFIX: &str = ".hermes-sealed";
+// How many pings are timed before each transfer
+const LATENCY_PINGS: usize = 3;
+
+// Why a request did not go through. A refusal keeps its status, so the CLI can exit with the matching code.
+#[derive(Debug)]
+pub enum RequestError {
+    Refused(HttpCodes, String),
+    Cancelled, //Stopped through the transfer's CancelToken, and the connection can still be used
+    Local(String), //Something went wrong on this machine, such as a file that would not decrypt, and the connection can still be used
+    Failed(String) //The connection broke, or the server answered with something unexpected
+}
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Refused(code, message) => write!(f, "{code} '{message}'"),
+            Self::Cancelled => write!(f, "the transfer was cancelled"),
+            Self::Local(e) | Self::Failed(e) => write!(f, "{e}")
+        }
+    }
+}
+impl From<String> for RequestError {
+    fn from(value: String) -> Self {
+        Self::Failed(value)
+    }
+}
+impl From<RequestError> for String {
+    fn from(value: RequestError) -> Self {
+        value.to_string()
+    }
+}
+impl From<RequestError> for CliError {
+    fn from(value: RequestError) -> Self {
+        match value {
+            RequestError::Refused(code, message) => CliError::from_status(&code, message),
+            RequestError::Cancelled | RequestError::Local(_) => CliError::new(ExitCode::General, value.to_string()),
+            RequestError::Failed(e) => CliError::network(e)
+        }
+    }
+}
+
+// The server refuses absolute paths, so '/docs' is sent as the way to it from the current folder
+pub fn relative_to(cwd: &str, path: &str) -> String {
+    let absolute = match path.strip_prefix('/') {
+        Some(p) => p,
+        None => return path.to_string()
+    };
+
+    let mut parts: Vec<&str> = cwd.split('/').filter(|x| !x.is_empty()).map(|_| "..").collect();
+    parts.extend(absolute.split('/').filter(|x| !x.is_empty()));
+    match parts.is_empty() {
+        true => String::from("."),
+        false => parts.join("/")
+    }
+}
+fn expect_ok(response: Option<(HttpCodes, String)>, kind: &str) -> Result<String, RequestError> {
+    match response {
+        Some((HttpCodes::Ok, message)) => Ok(message),
+        Some((code, message)) => Err(RequestError::Refused(code, message)),
+        None => Err(RequestError::Failed(format!("malformed {kind} response")))
+    }
+}
+
+// A download arriving on its channel. It is written beside its destination and only moved into place once the checksum matches.
+struct ChannelDownload {
+    index: usize, //Which of the files asked for this is
+    destination: PathBuf,
+    partial: PathBuf,
+    file: File,
+    hasher: ChecksumHasher,
+    expected: Option<Checksum>
+}
+impl ChannelDownload {
+    fn start(index: usize, destination: &Path, response: DownloadResponse) -> Result<Self, String> {
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let partial = partial_path(destination);
+        Ok(
+            Self {
+                index,
+                destination: destination.to_path_buf(),
+                file: File::create(&partial).map_err(|e| e.to_string())?,
+                partial,
+                hasher: ChecksumHasher::new(response.checksum.as_ref().map(|x| x.algorithm()).unwrap_or_default()),
+                expected: response.checksum
+            }
+        )
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.hasher.update(data);
+        self.file.write_all(data).map_err(|e| e.to_string())
+    }
+    fn finish(self) -> Result<Checksum, RequestError> {
+        drop(self.file);
+        let actual = self.hasher.finish();
+        match self.expected {
+            Some(e) if e != actual => {
+                let _ = std::fs::remove_file(&self.partial);
+                Err(RequestError::Failed(format!("checksum mismatch, expected '{e}' but received '{actual}'")))
+            },
+            _ => std::fs::rename(&self.partial, &self.destination).map(|_| actual).map_err(|e| RequestError::Failed(e.to_string()))
+        }
+    }
+    fn abandon(self) {
+        drop(self.file);
+        let _ = std::fs::remove_file(&self.partial);
+    }
+}
+fn partial_path(destination: &Path) -> PathBuf {
+    destination.with_file_name(format!("{}{PARTIAL_SUFFIX}", destination.file_name().unwrap_or_default().to_string_lossy()))
+}
+fn sealed_path(destination: &Path) -> PathBuf {
+    destination.with_file_name(format!("{}{SEALED_SUFFIX}", destination.file_name().unwrap_or_default().to_string_lossy()))
+}
+// Uploads are encrypted into the temp directory, since the folder the file is in may not be writable
+fn sealed_upload_path() -> PathBuf {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    std::env::temp_dir().join(format!("hermes-upload-{}-{n}{SEALED_SUFFIX}", std::process::id()))
+}
+// Shows the names in a listing that were encrypted with the vault's passphrase as they were before
+fn reveal_names(vault: &Vault, listing: &mut DirectoryInfo) {
+    let contents = listing.contents().iter().cloned().map(|x| match x {
+        DirectoryContent::File(mut f) => {
+            if let Some(name) = f.encryption().filter(|x| x.hidden_name).and_then(|_| vault.decrypt_name(f.name())) {
+                f.set_name(name);
+            }
+            DirectoryContent::File(f)
+        },
+        DirectoryContent::Dir(mut d) => {
+            if let Some(name) = vault.decrypt_name(d.name()) {
+                d = DirectoryInfo::new(name, d.contents().clone());
+            }
+            reveal_names(vault, &mut d);
+            DirectoryContent::Dir(d)
+        },
+        link => link
+    }).collect();
+    listing.set_content(contents);
+}
+
+// How connections to the server are opened, as given on the command line
+#[derive(Clone, Default, Debug)]
+pub struct ConnectOptions {
+    pub socket: SocketOptions, //Applied to the stream as soon as it is connected
+    pub tls: Option<Arc<ClientConfig>> //Set when the server is spoken to over TLS
+}
+
+// The host in an address like 'files.example.com:9090' or '[::1]:9090', which a TLS server's certificate must name
+pub fn server_name(address: &str) -> &str {
+    let host = address.rsplit_once(':').map(|x| x.0).unwrap_or(address);
+    host.strip_prefix('[').and_then(|x| x.strip_suffix(']')).unwrap_or(host)
+}
+
+// One logged-in connection, spoken to one request at a time. Paths are resolved by the server, against its working directory.
+pub struct Connection {
+    stream: Box<dyn SyncTransport>,
+    session: String,
+    compression: Option<Compression>, //Only when the server shares one
+    codec: Codec, //What the server chose for messages and listings, JSON when it did not say
+    pending: PendingRequests,
+    keepalive: Option<Keepalive>, //Only when the server answers pings
+    cancellable: bool, //Whether a cancelled transfer can be ended cleanly, rather than by dropping the connection
+    multiplexed: bool, //Whether several downloads can be sent at once on their own channels
+    quic: Option<QuicPlane>, //Carries file frames when the server offers QUIC and it could be reached
+    login: (String, String, String), //The address, username, and password, kept for reconnecting
+    options: ConnectOptions, //How the stream was opened, kept for reconnecting
+    cwd: Option<String>, //Where change_dir last left the server, such as '/docs'
+    retry: RetryPolicy,
+    vault: Option<Vault>, //Encrypts uploads and decrypts downloads when a passphrase is set
+    interactive: bool, //Whether someone is waiting on each transfer, so the server starts it ahead of bulk ones
+    opened: Instant //What ping timestamps count from
+}
+impl Connection {
+    pub fn open(address: &str, username: &str, password: &str, options: &ConnectOptions) -> Result<Self, CliError> {
+        let stream = Transport::connect(address, server_name(address), options.tls.clone(), &options.socket).map_err(|e| CliError::network(format!("unable to reach '{address}' because {e}")))?;
+        let peer = stream.peer_addr().ok();
+        let mut result = Self::over(Box::new(stream), peer, address, username, password)?;
+        result.options = options.clone();
+
+        Ok(result)
+    }
+    // Logs in over a stream that is already open. QUIC is only tried when there is a peer to reach it at.
+    pub fn over(mut stream: Box<dyn SyncTransport>, peer: Option<SocketAddr>, address: &str, username: &str, password: &str) -> Result<Self, CliError> {
+        let wanted: Capabilities = [Capability::Keepalive, Capability::Cancel, Capability::Multiplex, Capability::Preview, Capability::Quic, Capability::Queue].into_iter().filter(|x| *x != Capability::Quic || QUIC_BUILT).collect();
+        let connect = connect_message(username.to_string(), password.to_string(), CURRENT_PROTOCOL_VERSION);
+        let connect = advertise_capabilities(connect, &wanted);
+        let connect = advertise_codecs(connect, &Codec::supported());
+        write_frame(&mut stream, &advertise_compressions(connect, &[Compression::Zstd, Compression::Gzip])).map_err(CliError::network)?;
+        let response = read_frame(&mut stream).map_err(CliError::network)?;
+
+        let session = extract_session_ack(&response);
+        let compression = extract_compressions(&response).first().copied();
+        let codec = extract_codecs(&response).first().copied().unwrap_or_default();
+        let capabilities = extract_capabilities(&response).unwrap_or_default();
+        let keepalive = capabilities.contains(Capability::Keepalive).then(|| Keepalive::new(extract_idle_timeout(&response).map(Duration::from_secs)));
+        let offer = extract_quic_offer(&response).filter(|_| capabilities.contains(Capability::Quic)).zip(peer);
+        match (extract_connect_ack_message(response), session, compression) {
+            (Some((HttpCodes::Ok, _, _, _)), Some(s), compression) => Ok(
+                Self {
+                    stream,
+                    session: s.token().to_string(),
+                    compression,
+                    codec,
+                    pending: PendingRequests::new(),
+                    keepalive,
+                    cancellable: capabilities.contains(Capability::Cancel),
+                    multiplexed: capabilities.contains(Capability::Multiplex),
+                    quic: offer.and_then(|(o, peer)| quic::connect(peer, &o, s.token())),
+                    login: (address.to_string(), username.to_string(), password.to_string()),
+                    options: ConnectOptions::default(),
+                    cwd: None,
+                    retry: RetryPolicy::from_env(),
+                    vault: None,
+                    interactive: false,
+                    opened: Instant::now()
+                }
+            ),
+            (Some((code, message, _, _)), _, _) => Err(CliError::from_status(&code, format!("the server refused the login with '{message}'"))),
+            (None, _, _) => Err(CliError::network(String::from("malformed response from the server")))
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+    // Single-file transfers are marked as someone waiting on them, and where one waits in the server's queue is shown on stderr
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+    // From here on, files are encrypted before they are uploaded and decrypted after they are downloaded, and names too when the vault hides them.
+    // Paths are still given as they are shown, and the connection works out what the server calls them.
+    pub fn set_vault(&mut self, vault: Option<Vault>) {
+        self.vault = vault;
+    }
+    fn remote(&self, path: &str) -> Result<String, RequestError> {
+        match self.vault.as_ref() {
+            Some(v) => v.remote_path(path).map_err(RequestError::Local),
+            None => Ok(path.to_string())
+        }
+    }
+
+    // Logs in again, and goes back to the folder this connection was in
+    fn reconnect(&mut self) -> Result<(), RequestError> {
+        let (address, username, password) = &self.login;
+        let mut fresh = Self::open(address, username, password, &self.options).map_err(|e| RequestError::Failed(e.message().to_string()))?;
+        fresh.retry = self.retry;
+        fresh.vault = self.vault.clone();
+        if let Some(cwd) = self.cwd.as_deref() {
+            let home = fresh.change_dir(".")?;
+            fresh.change_dir(&relative_to(&home, cwd))?;
+        }
+
+        *self = fresh;
+        Ok(())
+    }
+    // Runs a request that is safe to repeat, trying again on a new connection while it keeps failing on the network. The attempt counts from 0.
+    // Refusals and cancellations are answers, so they are returned at once.
+    fn with_retry<T>(&mut self, what: &str, mut op: impl FnMut(&mut Self, u32) -> Result<T, RequestError>) -> Result<T, RequestError> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(attempt, &mut op) {
+                Err(RequestError::Failed(e)) if self.back_off(what, &mut attempt, &e) => continue,
+                result => return result
+            }
+        }
+    }
+    fn attempt<T>(&mut self, attempt: u32, op: &mut impl FnMut(&mut Self, u32) -> Result<T, RequestError>) -> Result<T, RequestError> {
+        match attempt {
+            0 => op(self, attempt),
+            _ => self.reconnect().and_then(|_| op(self, attempt))
+        }
+    }
+    // Waits before the next attempt, or says there are none left
+    fn back_off(&self, what: &str, attempt: &mut u32, error: &str) -> bool {
+        if *attempt + 1 >= self.retry.max_attempts {
+            return false;
+        }
+
+        *attempt += 1;
+        let delay = self.retry.delay(*attempt);
+        tracing::warn!("{what} failed because '{error}', trying again in {:.1}s", delay.as_secs_f32());
+        std::thread::sleep(delay);
+        true
+    }
+
+    fn compressed(&self, message: Message) -> Message {
+        match self.compression {
+            Some(c) => attach_compression(message, c),
+            None => message
+        }
+    }
+    fn prioritized(&self, message: Message) -> Message {
+        match self.interactive {
+            true => attach_interactive(message),
+            false => message
+        }
+    }
+
+    pub fn request(&mut self, message: Message) -> Result<Message, String> {
+        if let Some(k) = self.keepalive.as_mut() {
+            k.touch();
+        }
+        let (id, message) = self.pending.issue(attach_session(message, &self.session));
+        let response = self.send_and_wait(id, &message);
+        if response.is_err() {
+            self.pending.forget(id);
+        }
+        let response = response?;
+        // The server is going away, and will not answer anything else on this connection
+        match extract_close_reason(&response) {
+            Some(reason) => Err(format!("the server closed the connection: {reason}")),
+            None => Ok(response)
+        }
+    }
+    fn send_and_wait(&mut self, id: u64, message: &Message) -> Result<Message, String> {
+        write_frame_with(&mut self.stream, message, self.codec)?;
+        loop {
+            if let Some(response) = self.pending.take(id) {
+                return Ok(response);
+            }
+            let frame = read_frame(&mut self.stream)?;
+            // The server has no room for the transfer yet, and its response comes once it starts
+            if *frame.message_type() == MessageType::Queued {
+                if let Some(position) = extract_queued_message(frame) {
+                    tracing::info!(position, "the server queued the transfer");
+                    if self.interactive {
+                        eprintln!("waiting for the server, {position} in the queue");
+                    }
+                }
+                continue;
+            }
+            if let Err(unclaimed) = self.pending.deliver(frame) {
+                tracing::debug!("ignoring a {} that answers no request", unclaimed.message_type());
+            }
+        }
+    }
+    // Pings when nothing has been sent for a while, so time spent on local work does not get the connection closed as idle
+    pub fn keep_alive(&mut self) -> Result<(), String> {
+        let sequence = match self.keepalive.as_mut() {
+            Some(k) if k.is_due() => k.next_sequence(),
+            _ => return Ok(())
+        };
+
+        match extract_pong_message(self.request(ping_message(sequence))?) {
+            Some((s, _)) if s == sequence => Ok(()),
+            _ => Err(String::from("the server did not answer the ping"))
+        }
+    }
+    // Times a few pings before a transfer, so the server can record the round trip apart from the transfer's throughput.
+    // Only servers that answer pings are asked, and a ping that goes wrong only leaves the latency unrecorded.
+    fn measure_latency(&mut self) -> Option<Latency> {
+        let mut samples = Vec::with_capacity(LATENCY_PINGS);
+        for _ in 0..LATENCY_PINGS {
+            let sequence = self.keepalive.as_mut()?.next_sequence();
+            let sent_at = self.opened.elapsed().as_micros() as u64;
+            match extract_pong_message(self.request(timed_ping_message(sequence, sent_at)).ok()?) {
+                Some((s, Some(echo))) if s == sequence && echo == sent_at => samples.push(self.opened.elapsed().saturating_sub(Duration::from_micros(sent_at))),
+                _ => return None
+            }
+        }
+
+        Latency::from_samples(&samples)
+    }
+    fn timed(&mut self, message: Message) -> Message {
+        match self.measure_latency() {
+            Some(l) => attach_latency(message, &l),
+            None => message
+        }
+    }
+
+    pub fn list(&mut self, path: &str) -> Result<DirectoryInfo, RequestError> {
+        self.with_retry("the listing", |c, _| c.list_once(path, 0, None))
+    }
+    // Names starting with '.' are listed too, whatever the server shows by default
+    pub fn list_all(&mut self, path: &str) -> Result<DirectoryInfo, RequestError> {
+        self.with_retry("the listing", |c, _| c.list_once(path, 0, Some(true)))
+    }
+    // The folders beneath are filled in as well, depth levels deep, in the one round trip
+    pub fn tree(&mut self, path: &str, depth: u32) -> Result<DirectoryInfo, RequestError> {
+        self.with_retry("the listing", |c, _| c.list_once(path, depth, None))
+    }
+    fn list_once(&mut self, path: &str, depth: u32, dotfiles: Option<bool>) -> Result<DirectoryInfo, RequestError> {
+        let query = DirQuery {
+            path: if path.is_empty() { None } else { Some(self.remote(path)?) },
+            depth,
+            dotfiles,
+            ..Default::default()
+        };
+
+        let frame_count = match extract_dir_response_message(self.request(dir_query_request(&query))?) {
+            Some((HttpCodes::Ok, _, _, f)) => f,
+            Some((code, message, _, _)) => return Err(RequestError::Refused(code, message)),
+            None => return Err(RequestError::Failed(String::from("malformed dir response")))
+        };
+
+        let contents = receive_network_binary(&mut self.stream, frame_count).ok_or_else(|| String::from("the listing was interrupted"))?;
+        let mut listing = self.codec.decode(&contents).map_err(RequestError::Failed)?;
+        if let Some(v) = self.vault.as_ref().filter(|x| x.hides_names()) {
+            reveal_names(v, &mut listing);
+        }
+        Ok(listing)
+    }
+    // Moves the server's working directory, and answers with where it is now, such as '/docs'
+    pub fn change_dir(&mut self, path: &str) -> Result<String, RequestError> {
+        let cwd = expect_ok(extract_ack_message(self.request(change_dir_message(&self.remote(path)?))?), "change dir")?;
+        let cwd = match self.vault.as_ref() {
+            Some(v) => v.local_path(&cwd),
+            None => cwd
+        };
+        self.cwd = Some(cwd.clone());
+        Ok(cwd)
+    }
+
+    // Asks for the checksum of a file without transferring any of it. Nothing follows an empty, uncompressed download.
+    pub fn checksum(&mut self, path: &str) -> Result<Option<Checksum>, RequestError> {
+        let path = self.remote(path)?;
+        self.with_retry("the checksum", |c, _| c.checksum_once(&path))
+    }
+    fn checksum_once(&mut self, path: &str) -> Result<Option<Checksum>, RequestError> {
+        let response = extract_download_response_message(self.request(download_message_request(path, 0, Some(0)))?).ok_or_else(|| String::from("malformed download response"))?;
+        match response.status {
+            HttpCodes::Ok => Ok(response.checksum),
+            code => Err(RequestError::Refused(code, response.message))
+        }
+    }
+
+    // The first lines of a text file, or the first bytes of any other, without downloading the rest
+    pub fn preview(&mut self, path: &str, limit: u64) -> Result<FilePreview, RequestError> {
+        let path = self.remote(path)?;
+        self.with_retry("the preview", |c, _| c.preview_once(&path, limit))
+    }
+    fn preview_once(&mut self, path: &str, limit: u64) -> Result<FilePreview, RequestError> {
+        let response = self.request(preview_message(path, limit))?;
+        match extract_preview_response(response.clone()) {
+            Some((HttpCodes::Ok, _, Some(p))) => Ok(p),
+            Some((HttpCodes::Ok, _, None)) => Err(RequestError::Failed(String::from("malformed preview response"))),
+            Some((code, message, _)) => Err(RequestError::Refused(code, message)),
+            // A server without the capability answers with an ack
+            None => expect_ok(extract_ack_message(response), "preview").and(Err(RequestError::Failed(String::from("malformed preview response"))))
+        }
+    }
+
+    // Fetches a file for the user. Until it checks out it is only a '.hermes-sync.part' file beside the destination, so a local copy is never left half replaced.
+    // Cancelling deletes that file. A dropped connection keeps it, and the retry asks the server for whatever comes after its length.
+    // With a vault, a file that arrives encrypted is decrypted before it is moved into place. The checksum is always that of what the server holds.
+    pub fn download(&mut self, path: &str, destination: &Path, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        let path = self.remote(path)?;
+        let vault = match self.vault.clone() {
+            Some(v) => v,
+            None => return self.download_stored(&path, destination, progress)
+        };
+
+        let sealed = sealed_path(destination);
+        let checksum = self.download_stored(&path, &sealed, progress)?;
+        let partial = partial_path(destination);
+        let opened = match is_encrypted(&sealed) {
+            true => vault.decrypt_file(&sealed, &partial).and_then(|_| std::fs::rename(&partial, destination).map_err(|e| e.to_string())),
+            false => std::fs::rename(&sealed, destination).map_err(|e| e.to_string())
+        };
+        let _ = std::fs::remove_file(&sealed);
+        opened.map(|_| checksum).map_err(RequestError::Local)
+    }
+    fn download_stored(&mut self, path: &str, destination: &Path, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        // Not through with_retry, since a transfer the user cancelled must not be started again
+        let mut attempt = 0;
+        loop {
+            match self.attempt(attempt, &mut |c, attempt| c.download_once(path, destination, attempt > 0, progress)) {
+                Err(RequestError::Failed(e)) if !progress.is_cancelled() && self.back_off("the download", &mut attempt, &e) => continue,
+                result => return result
+            }
+        }
+    }
+    fn download_once(&mut self, path: &str, destination: &Path, resume: bool, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        let partial = partial_path(destination);
+        let offset = match resume {
+            true => std::fs::metadata(&partial).map(|x| x.len()).unwrap_or_default(),
+            false => 0
+        };
+
+        let request = self.timed(download_message_request(path, offset, None));
+        let request = self.prioritized(self.compressed(request));
+        let request = match self.quic {
+            Some(_) => attach_quic(request),
+            None => request
+        };
+        let response = self.request(request)?;
+        let compression = extract_compression(&response);
+        let over_quic = extract_quic(&response);
+        let response = extract_download_response_message(response).ok_or_else(|| String::from("malformed download response"))?;
+        if response.status != HttpCodes::Ok {
+            return Err(RequestError::Refused(response.status, response.message));
+        }
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        progress.expect(offset + response.length);
+        if over_quic {
+            return self.download_over_quic(&partial, destination, offset, &response, compression, progress);
+        }
+        let received = receive_network_file_checked(&partial, &mut self.stream, response.frame_count, offset, response.checksum.as_ref(), compression, progress);
+        if received.is_err() && progress.is_cancelled() {
+            let _ = std::fs::remove_file(&partial);
+            return self.cancel_download(compression.is_some());
+        }
+        let checksum = received?;
+        std::fs::rename(&partial, destination).map_err(|e| e.to_string())?;
+        Ok(checksum)
+    }
+
+    // The frames arrive on a stream of their own, and an ack over TCP follows them. Stopping the stream ends the download early, whether it is compressed or not.
+    fn download_over_quic(&mut self, partial: &Path, destination: &Path, offset: u64, response: &DownloadResponse, compression: Option<Compression>, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        let plane = self.quic.as_ref().ok_or_else(|| String::from("the server sent the download over QUIC, which is not connected"))?;
+        let received = {
+            let mut stream = plane.accept()?;
+            let received = receive_network_file_checked(partial, &mut stream, response.frame_count, offset, response.checksum.as_ref(), compression, progress);
+            // The server only answers once it stops sending, so a download that went wrong here has to stop it
+            if received.is_err() {
+                stream.abandon();
+            }
+            received
+        };
+
+        let ack = extract_ack_message(read_frame(&mut self.stream)?);
+        if received.is_err() && progress.is_cancelled() {
+            let _ = std::fs::remove_file(partial);
+            return Err(RequestError::Cancelled);
+        }
+        let checksum = received?;
+        expect_ok(ack, "download")?;
+        std::fs::rename(partial, destination).map_err(|e| e.to_string())?;
+        Ok(checksum)
+    }
+
+    // Downloads several files over this one connection. When the server speaks multiplex they are all asked for at once and arrive on their own channels,
+    // so small files are not held up behind large ones. Otherwise they are downloaded one after another. Each file gets its own result.
+    // Encrypted files have to be decrypted one at a time, so with a vault they are always downloaded one after another.
+    pub fn download_many(&mut self, files: &[(String, PathBuf)]) -> Vec<Result<Checksum, RequestError>> {
+        if !self.multiplexed || self.vault.is_some() {
+            return files.iter().map(|(path, destination)| self.download(path, destination, &mut Progress::none())).collect();
+        }
+
+        let mut results: Vec<Option<Result<Checksum, RequestError>>> = files.iter().map(|_| None).collect();
+        let mut channels = HashMap::new();
+        let received = self.receive_channels(files, &mut results, &mut channels);
+        // Whatever was still arriving when the connection broke is thrown away, since channel downloads are not resumed
+        for (_, c) in channels.drain() {
+            c.abandon();
+        }
+        if let Err(e) = received {
+            for result in results.iter_mut().filter(|x| x.is_none()) {
+                *result = Some(Err(RequestError::Failed(e.clone())));
+            }
+        }
+
+        results.into_iter().map(|x| x.unwrap_or_else(|| Err(RequestError::Failed(String::from("the download was never answered"))))).collect()
+    }
+    fn receive_channels(&mut self, files: &[(String, PathBuf)], results: &mut [Option<Result<Checksum, RequestError>>], channels: &mut HashMap<u64, ChannelDownload>) -> Result<(), String> {
+        if let Some(k) = self.keepalive.as_mut() {
+            k.touch();
+        }
+        let mut unanswered = Vec::with_capacity(files.len());
+        for (index, (path, _)) in files.iter().enumerate() {
+            let (id, request) = self.pending.issue(attach_session(attach_channel(download_message_request(path, 0, None)), &self.session));
+            write_frame_with(&mut self.stream, &request, self.codec)?;
+            unanswered.push((id, index));
+        }
+
+        while results.iter().any(|x| x.is_none()) {
+            match read_any_frame(&mut self.stream)? {
+                // A channel that could not be opened here is still sent, and is read and thrown away
+                Frame::Channel(id, data) if !data.is_empty() => {
+                    if let Some(c) = channels.get_mut(&id) {
+                        c.write(&data)?;
+                    }
+                },
+                Frame::Channel(id, _) => {
+                    if let Some(c) = channels.remove(&id) {
+                        let index = c.index;
+                        results[index] = Some(c.finish());
+                    }
+                },
+                Frame::Message(message) => match self.pending.deliver(message) {
+                    Ok(()) => { },
+                    // An open channel is only answered again when its file could not be sent in full
+                    Err(message) => match channels.remove(&message.request_id()) {
+                        Some(c) => {
+                            results[c.index] = Some(match extract_ack_message(message) {
+                                Some((code, reason)) => Err(RequestError::Refused(code, reason)),
+                                None => Err(RequestError::Failed(String::from("malformed download response")))
+                            });
+                            c.abandon();
+                        },
+                        None => tracing::debug!("ignoring a {} that answers no request", message.message_type())
+                    }
+                }
+            }
+
+            let mut i = 0;
+            while i < unanswered.len() {
+                let (id, index) = unanswered[i];
+                match self.pending.take(id) {
+                    Some(response) => {
+                        unanswered.remove(i);
+                        Self::open_channel(id, index, &files[index].1, response, results, channels);
+                    },
+                    None => i += 1
+                }
+            }
+        }
+
+        Ok(())
+    }
+    fn open_channel(id: u64, index: usize, destination: &Path, response: Message, results: &mut [Option<Result<Checksum, RequestError>>], channels: &mut HashMap<u64, ChannelDownload>) {
+        let channel = extract_channel(&response);
+        let opened = match extract_download_response_message(response) {
+            Some(r) if r.status == HttpCodes::Ok && channel => ChannelDownload::start(index, destination, r).map_err(RequestError::Failed),
+            Some(r) if r.status == HttpCodes::Ok => Err(RequestError::Failed(String::from("the server did not send the download on a channel"))),
+            Some(r) => Err(RequestError::Refused(r.status, r.message)),
+            None => Err(RequestError::Failed(String::from("malformed download response")))
+        };
+
+        match opened {
+            Ok(c) => {
+                channels.insert(id, c);
+            },
+            Err(e) => results[index] = Some(Err(e))
+        }
+    }
+
+    // The server refuses the upload unless what arrives matches the checksum, so a file that changes while it is sent is never stored half-written.
+    // With a vault, the file is encrypted into the temp directory first, and the checksum is that of what was sent.
+    pub fn upload(&mut self, path: &str, source: &Path, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        self.upload_keyed(path, source, None, progress)
+    }
+    // With a key, the server answers an upload it already received without asking for the file again
+    fn upload_keyed(&mut self, path: &str, source: &Path, key: Option<&str>, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        let path = self.remote(path)?;
+        let vault = match self.vault.clone() {
+            Some(v) => v,
+            None => return self.upload_stored(&path, source, None, key, progress)
+        };
+
+        let sealed = sealed_upload_path();
+        let result = vault.encrypt_file(source, &sealed).map_err(RequestError::Local).and_then(|_| self.upload_stored(&path, &sealed, Some(vault.marker()), key, progress));
+        let _ = std::fs::remove_file(&sealed);
+        result
+    }
+    fn upload_stored(&mut self, path: &str, source: &Path, encryption: Option<Encryption>, key: Option<&str>, progress: &mut Progress) -> Result<Checksum, RequestError> {
+        let checksum = checksum_file(source, ChecksumAlgorithm::Sha256).map_err(|e| e.to_string())?;
+        let chunks = FileChunkIter::open(source, 0, None)?;
+        let kind = match encryption {
+            Some(_) => FileType::Binary,
+            None => detect_file_type(source).unwrap_or(FileType::Binary)
+        };
+
+        let request = self.timed(upload_message(path, kind, chunks.frame_count(), 0, Some(checksum.clone()), None));
+        let request = match encryption.as_ref() {
+            Some(e) => attach_encryption(request, e),
+            None => request
+        };
+        let request = match self.quic {
+            Some(_) => attach_quic(request),
+            None => request
+        };
+        let request = match key {
+            Some(k) => attach_idempotency_key(request, k),
+            None => request
+        };
+        let response = self.request(self.prioritized(self.compressed(request)))?;
+        let over_quic = extract_quic(&response);
+        match extract_upload_response_message(response) {
+            Some((HttpCodes::Ok, _, _)) => (),
+            Some((HttpCodes::NoContent, _, _)) if key.is_some() => return Ok(checksum),
+            Some((code, message, _)) => return Err(RequestError::Refused(code, message)),
+            None => return Err(RequestError::Failed(String::from("malformed upload response")))
+        }
+
+        // A cancel block on the QUIC stream is still followed by a Cancel over TCP
+        let sent = match (over_quic, self.quic.as_ref()) {
+            (true, Some(q)) => q.open().and_then(|mut s| {
+                let sent = send_network_file(&mut s, chunks, self.compression, progress);
+                let _ = s.finish();
+                sent
+            }),
+            (true, None) => Err(String::from("the server asked for the upload over QUIC, which is not connected")),
+            (false, _) => send_network_file(&mut self.stream, chunks, self.compression, progress)
+        };
+        if sent.is_err() && progress.is_cancelled() {
+            return self.cancel_upload();
+        }
+        sent?;
+        expect_ok(extract_ack_message(read_frame(&mut self.stream)?), "upload").map(|_| checksum)
+    }
+
+    // The cancel block has ended the stream, and the server waits for the Cancel before it answers
+    fn cancel_upload<T>(&mut self) -> Result<T, RequestError> {
+        if !self.cancellable {
+            return Err(RequestError::Failed(String::from("the upload was cancelled, and the server cannot tell where it stopped")));
+        }
+
+        expect_ok(extract_ack_message(self.request(cancel_message(None))?), "cancel")?;
+        Err(RequestError::Cancelled)
+    }
+    // The server keeps sending until it sees the Cancel, so the rest of the stream is read and thrown away before the answer
+    fn cancel_download<T>(&mut self, compressed: bool) -> Result<T, RequestError> {
+        if !self.cancellable || !compressed {
+            return Err(RequestError::Failed(String::from("the download was cancelled, and the rest of it is still on the way")));
+        }
+
+        write_frame_with(&mut self.stream, &attach_session(cancel_message(None), &self.session), self.codec)?;
+        if skip_blocks(&mut self.stream)? == Block::End {
+            tracing::debug!("the download was sent in full before it could be cancelled");
+        }
+        // Refused when the download had already been sent, which is no different to us
+        extract_ack_message(read_frame(&mut self.stream)?).ok_or_else(|| String::from("malformed cancel response"))?;
+        Err(RequestError::Cancelled)
+    }
+
+    // A folder is only deleted with recursive set, and then everything beneath it goes too
+    pub fn delete(&mut self, path: &str, recursive: bool) -> Result<(), RequestError> {
+        self.delete_keyed(path, recursive, None)
+    }
+    // With a key, the server answers a delete it already carried out as it did the first time
+    fn delete_keyed(&mut self, path: &str, recursive: bool, key: Option<&str>) -> Result<(), RequestError> {
+        let path = self.remote(path)?;
+        let request = match key {
+            Some(k) => attach_idempotency_key(delete_message(&path, recursive), k),
+            None => delete_message(&path, recursive)
+        };
+        expect_ok(extract_ack_message(self.request(request)?), "delete").map(|_| ())
+    }
+    // When a file or folder last changed, or None when it is not there
+    pub fn modified(&mut self, path: &str) -> Result<Option<u64>, RequestError> {
+        let path = self.remote(path)?;
+        match extract_stat_response_message(self.request(stat_message_request(&path))?) {
+            Some((HttpCodes::Ok, _, info)) => Ok(info.and_then(|x| x.modified())),
+            Some((HttpCodes::NotFound, _, _)) => Ok(None),
+            Some((code, message, _)) => Err(RequestError::Refused(code, message)),
+            None => Err(RequestError::Failed(String::from("malformed stat response")))
+        }
+    }
+    // What changed at or beneath a path after a time, in seconds since the epoch. An older server that does not know the request refuses it.
+    pub fn changes_since(&mut self, path: &str, since: u64) -> Result<ChangeReport, RequestError> {
+        let path = self.remote(path)?;
+        let response = self.request(changes_since_request(&path, since))?;
+        match extract_changes_since_response(response.clone()) {
+            Some((HttpCodes::Ok, _, Some(r))) => Ok(r),
+            Some((HttpCodes::Ok, _, None)) => Err(RequestError::Failed(String::from("malformed changes response"))),
+            Some((code, message, _)) => Err(RequestError::Refused(code, message)),
+            None => expect_ok(extract_ack_message(response), "changes").and(Err(RequestError::Failed(String::from("malformed changes response"))))
+        }
+    }
+    pub fn make_dir(&mut self, path: &str) -> Result<(), RequestError> {
+        let path = self.remote(path)?;
+        expect_ok(extract_ack_message(self.request(subfolder_message(&path, SubfolderAction::Add, false))?), "subfolder").map(|_| ())
+    }
+    // Nothing is overwritten, so a destination that already exists is refused
+    pub fn rename(&mut self, source: &str, destination: &str) -> Result<(), RequestError> {
+        let (source, destination) = (self.remote(source)?, self.remote(destination)?);
+        expect_ok(extract_ack_message(self.request(rename_message(&source, &destination))?), "rename").map(|_| ())
+    }
+    // Like rename, except that a destination that is an existing folder receives the source under its own name
+    pub fn move_path(&mut self, source: &str, destination: &str) -> Result<(), RequestError> {
+        let (source, destination) = (self.remote(source)?, self.remote(destination)?);
+        expect_ok(extract_ack_message(self.request(move_message(&source, &destination))?), "move").map(|_| ())
+    }
+    // The last transfer the server recorded from this address
+    pub fn stats(&mut self) -> Result<TransferStats, RequestError> {
+        self.with_retry("the stats request", |c, _| c.stats_once())
+    }
+    fn stats_once(&mut self) -> Result<TransferStats, RequestError> {
+        let response = self.request(stats_request_message())?;
+        match extract_stats_response_message(response.clone()) {
+            Some(s) => Ok(s),
+            None => expect_ok(extract_ack_message(response), "stats").and(Err(RequestError::Failed(String::from("malformed stats response"))))
+        }
+    }
+
+    // How full the server's storage is, and what is stored where
+    pub fn usage(&mut self) -> Result<UsageReport, RequestError> {
+        self.with_retry("the usage request", |c, _| c.usage_once())
+    }
+    // Asks once, for callers that would rather go without than wait on retries
+    pub fn usage_once(&mut self) -> Result<UsageReport, RequestError> {
+        match extract_usage_response(self.request(usage_request())?) {
+            Some((HttpCodes::Ok, _, Some(r))) => Ok(r),
+            Some((HttpCodes::Ok, _, None)) | None => Err(RequestError::Failed(String::from("malformed usage response"))),
+            Some((code, message, _)) => Err(RequestError::Refused(code, message))
+        }
+    }
+
+    pub fn close(mut self) {
+        let _ = write_frame_with(&mut self.stream, &close_message(), self.codec);
+    }
+}
+// Queued operations are replayed right after logging in, so paths that do not start with '/' are taken from the folder the login starts in
+impl ReplayTarget for Connection {
+    fn actor(&self) -> &str {
+        &self.login.1
+    }
+    // A server that is not watching, or no longer remembers back that far, is asked when the path last changed instead, which cannot say by whom
+    fn remote_changes(&mut self, remote_path: &str, since: u64) -> Result<Vec<FileChange>, String> {
+        let path = relative_to(self.cwd.as_deref().unwrap_or_default(), remote_path);
+        match self.changes_since(&path, since) {
+            Ok(r) if r.complete => return Ok(r.changes),
+            Err(RequestError::Failed(e)) => return Err(e),
+            _ => ()
+        }
+
+        match self.modified(&path) {
+            Ok(m) => Ok(m.filter(|x| *x > since).map(|at| FileChange { event: FileEvent { kind: FileEventKind::Modified, path: path.clone(), actor: None }, at }).into_iter().collect()),
+            Err(RequestError::Failed(e)) => Err(e),
+            // Refused here is refused when it is applied too, which is where it is reported
+            Err(_) => Ok(Vec::new())
+        }
+    }
+    fn apply(&mut self, operation: &QueuedOperation) -> Result<Result<(), String>, String> {
+        let path = relative_to(self.cwd.as_deref().unwrap_or_default(), operation.action.remote_path());
+        let result = match &operation.action {
+            QueuedAction::Upload { local_path, .. } => self.upload_keyed(&path, local_path, Some(&operation.key), &mut Progress::none()).map(|_| ()),
+            QueuedAction::Delete { recursive, .. } => self.delete_keyed(&path, *recursive, Some(&operation.key))
+        };
+
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(RequestError::Failed(e)) => Err(e),
+            Err(e) => Ok(Err(e.to_string()))
+        }
+    }
+}
+
+#[test]
+fn test_connection_over_memory() {
+    use hermes_common::chunking::ChunkWriter;
+    use hermes_common::file_io::{send_network_frames, FileInfo};
+    use hermes_common::messages::{connect_ack_message, session_ack, dir_message_response, extract_session, queued_message};
+    use hermes_common::session::SessionToken;
+    use hermes_common::testing::{reply, MockServer};
+
+    let listing = DirectoryInfo::new(String::from("docs"), vec![DirectoryContent::File(FileInfo::new(String::from("a.txt"), String::from("alice"), FileType::Text, 5))]);
+    let sent = listing.clone();
+    let (server, stream) = MockServer::spawn(move |request, s| match request.message_type() {
+        MessageType::Connect => reply(s, request, session_ack(connect_ack_message(HttpCodes::Ok, Some(String::from("ok")), None, None), &SessionToken::new(String::from("token"), u64::MAX))).map(|_| true),
+        MessageType::Dir => {
+            // A queue notice under the request's ID is not its response, which still follows
+            let frames = ChunkWriter::frames(&Codec::Json.encode(&sent)?);
+            reply(s, request, queued_message(1))?;
+            reply(s, request, dir_message_response(HttpCodes::Ok, "ok", "/docs", frames.len() as u64))?;
+            Ok(send_network_frames(s, &frames))
+        },
+        _ => Ok(false)
+    });
+
+    let mut connection = Connection::over(Box::new(stream), None, "memory", "alice", "pass").unwrap();
+    assert!(connection.list("docs").unwrap() == listing);
+    connection.close();
+
+    // Requests after the login carry the session it handed out
+    let seen = server.join().unwrap();
+    assert_eq!(seen.iter().map(|x| *x.message_type()).collect::<Vec<_>>(), vec![MessageType::Connect, MessageType::Dir, MessageType::Close]);
+    assert_eq!(extract_session(&seen[1]).as_deref(), Some("token"));
+}
+#[test]
+fn test_connection_over_tls() {
+    use hermes_common::framing::write_frame_with;
+    use hermes_common::messages::{connect_ack_message, session_ack};
+    use hermes_common::session::SessionToken;
+    use hermes_common::transport::{client_tls_config, server_tls_config};
+    use std::net::TcpListener;
+
+    assert_eq!((server_name("files.example.com:9090"), server_name("[::1]:9090"), server_name("localhost")), ("files.example.com", "::1", "localhost"));
+
+    let dir = std::env::temp_dir().join(format!("hermes_client_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    std::fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+    let server_config = server_tls_config(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+
+    // The server only speaks TLS, and answers the login before waiting for the client to hang up
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let mut transport = Transport::accept_tls(listener.accept().unwrap().0, server_config).unwrap();
+        let login = read_frame(&mut transport).unwrap();
+        let ack = session_ack(connect_ack_message(HttpCodes::Ok, Some(String::from("ok")), None, None), &SessionToken::new(String::from("token"), u64::MAX));
+        write_frame_with(&mut transport, &ack.with_request_id(login.request_id()), Codec::Json).unwrap();
+        (*login.message_type(), *read_frame(&mut transport).unwrap().message_type())
+    });
+
+    let options = ConnectOptions { tls: Some(client_tls_config(Some(&dir.join("cert.pem")), None).unwrap()), ..ConnectOptions::default() };
+    let connection = Connection::open(&format!("localhost:{port}"), "alice", "pass", &options).unwrap();
+    assert_eq!(connection.session, "token");
+    connection.close();
+    assert_eq!(server.join().unwrap(), (MessageType::Connect, MessageType::Close));
+
+    // A certificate the client does not trust fails the connection rather than falling back to plain TCP
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server_config = server_tls_config(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+    let server = std::thread::spawn(move || Transport::accept_tls(listener.accept().unwrap().0, server_config).is_err());
+    let options = ConnectOptions { tls: Some(client_tls_config(None, None).unwrap()), ..ConnectOptions::default() };
+    assert_eq!(Connection::open(&format!("localhost:{port}"), "alice", "pass", &options).err().map(|e| e.kind()), Some(ExitCode::Network));
+    assert!(server.join().unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
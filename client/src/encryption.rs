@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf::{KeyType, Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use hermes_common::file_io::Encryption;
+
+// The layout written by this client, recorded on the server with each upload. It is the only one read.
+pub const FORMAT: u32 = 2;
+// Every encrypted file starts with this, then the format as one byte, then the salt the passphrase was stretched with, then the random id its key is made from.
+const MAGIC: &[u8; 8] = b"HERMES-E";
+const SALT_LENGTH: usize = 16;
+const ID_LENGTH: usize = 16;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + SALT_LENGTH + ID_LENGTH;
+// The contents are sealed this much at a time, so files of any size stream through without being held in memory
+const CHUNK: usize = 64 * 1024;
+const TAG_LENGTH: usize = 16;
+const ITERATIONS: u32 = 600_000;
+// Hidden names have to come out the same on every machine so they can be asked for, so they are stretched with this rather than a random salt
+const NAME_SALT: &[u8] = b"hermes end-to-end encryption";
+// Most filesystems take names of up to 255 bytes, which an encrypted name has to fit in once it is spelled out
+const MAX_NAME: usize = 255;
+const BASE32: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+// Whether a file starts like one this client encrypted
+pub fn is_encrypted(path: &Path) -> bool {
+    let mut start = [0; MAGIC.len()];
+    File::open(path).and_then(|mut f| f.read_exact(&mut start)).is_ok() && &start == MAGIC
+}
+
+// Reads until the buffer is full or the file ends, so a short read always means the end
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n
+        }
+    }
+
+    Ok(filled)
+}
+
+// Chunks are numbered, and the last is marked, so chunks cannot be reordered, dropped, or cut off the end without it being noticed
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..8].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = u8::from(last);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Lowercase base32 without padding, since a name that only differs by case is the same name on some servers
+fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    result
+}
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|x| *x == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+
+    // Whatever is left over has to be the zero padding of the last character
+    (buffer & ((1 << bits) - 1) == 0).then_some(result)
+}
+
+// Every key here is 32 bytes, for ChaCha20-Poly1305 or HMAC-SHA256
+struct KeyLength;
+impl KeyType for KeyLength {
+    fn len(&self) -> usize {
+        32
+    }
+}
+
+// The keys made from a passphrase. Each vault stretches the passphrase with a random salt of its own, which every file it encrypts keeps in its header,
+// and each file gets a key of its own from that and a random id kept beside it. Files from other vaults are opened with the salt in their header.
+// Names are sealed the same way every time, with a nonce taken from the name, so a path can be found again by encrypting it.
+#[derive(Clone)]
+pub struct Vault {
+    passphrase: String, //Kept to stretch the salts of files from other vaults
+    iterations: u32,
+    salt: [u8; SALT_LENGTH],
+    stretched: Arc<Mutex<HashMap<Vec<u8>, [u8; 32]>>>, //The passphrase stretched with each salt seen so far, since stretching is slow on purpose
+    hide_names: bool
+}
+impl Vault {
+    pub fn new(passphrase: &str, hide_names: bool) -> Self {
+        Self::with_iterations(passphrase, hide_names, ITERATIONS)
+    }
+    fn with_iterations(passphrase: &str, hide_names: bool, iterations: u32) -> Self {
+        let result = Self {
+            passphrase: passphrase.to_string(),
+            iterations,
+            salt: rand::random(),
+            stretched: Arc::new(Mutex::new(HashMap::new())),
+            hide_names
+        };
+
+        // Stretched now, so the first transfer does not wait on it
+        result.master(&result.salt);
+        if hide_names {
+            result.master(NAME_SALT);
+        }
+        result
+    }
+    // HERMES_PASSPHRASE turns encryption on, and HERMES_HIDE_NAMES=1 encrypts names as well. None when there is no passphrase.
+    pub fn from_env() -> Option<Self> {
+        let passphrase = std::env::var("HERMES_PASSPHRASE").ok().filter(|x| !x.is_empty())?;
+        let hide_names = std::env::var("HERMES_HIDE_NAMES").is_ok_and(|x| x == "1" || x == "true");
+        Some(Self::new(&passphrase, hide_names))
+    }
+
+    pub fn hides_names(&self) -> bool {
+        self.hide_names
+    }
+    // What is sent with each upload, so listings show how the file was stored
+    pub fn marker(&self) -> Encryption {
+        Encryption {
+            format: FORMAT,
+            hidden_name: self.hide_names
+        }
+    }
+
+    fn master(&self, salt: &[u8]) -> [u8; 32] {
+        let mut stretched = self.stretched.lock().unwrap_or_else(|e| e.into_inner());
+        *stretched.entry(salt.to_vec()).or_insert_with(|| {
+            let mut master = [0; 32];
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, NonZeroU32::new(self.iterations).unwrap_or(NonZeroU32::MIN), salt, self.passphrase.as_bytes(), &mut master);
+            master
+        })
+    }
+    // Keys come from the passphrase stretched with one salt, then expanded with another, which is the file's id or nothing for names
+    fn derive(&self, stretch: &[u8], salt: &[u8], info: &[u8]) -> Result<[u8; 32], String> {
+        let info = [info];
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.master(stretch));
+        let mut result = [0; 32];
+        prk.expand(&info, KeyLength).and_then(|x| x.fill(&mut result)).map_err(|_| String::from("unable to derive a key"))?;
+        Ok(result)
+    }
+    fn key(&self, stretch: &[u8], salt: &[u8], info: &[u8]) -> Result<LessSafeKey, String> {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &self.derive(stretch, salt, info)?).map_err(|_| String::from("unable to derive a key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+    fn name_nonce(&self, name: &str) -> Result<[u8; NONCE_LEN], String> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.derive(NAME_SALT, &[], b"hermes name nonce")?);
+        let tag = hmac::sign(&key, name.as_bytes());
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&tag.as_ref()[..NONCE_LEN]);
+        Ok(nonce)
+    }
+
+    // Writes source to destination encrypted. The header is bound to every chunk, so it cannot be swapped for another file's.
+    pub fn encrypt_file(&self, source: &Path, destination: &Path) -> Result<(), String> {
+        let mut id = [0; ID_LENGTH];
+        SystemRandom::new().fill(&mut id).map_err(|_| String::from("unable to make a random file id"))?;
+        let mut header = MAGIC.to_vec();
+        header.push(FORMAT as u8);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&id);
+        let key = self.key(&self.salt, &id, b"hermes file")?;
+
+        Self::seal(source, destination, &header, &key)
+    }
+    fn seal(source: &Path, destination: &Path, header: &[u8], key: &LessSafeKey) -> Result<(), String> {
+        let mut input = File::open(source).map_err(|e| e.to_string())?;
+        let mut output = File::create(destination).map_err(|e| e.to_string())?;
+        output.write_all(header).map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; CHUNK];
+        let mut counter = 0;
+        loop {
+            let len = read_full(&mut input, &mut buffer).map_err(|e| e.to_string())?;
+            // A file that fills its last chunk exactly still ends with an empty one, so the end is always marked
+            let last = len < CHUNK;
+            let mut sealed = buffer[..len].to_vec();
+            key.seal_in_place_append_tag(chunk_nonce(counter, last), Aad::from(header), &mut sealed).map_err(|_| String::from("unable to encrypt the file"))?;
+            output.write_all(&sealed).map_err(|e| e.to_string())?;
+            if last {
+                break;
+            }
+            counter += 1;
+        }
+
+        output.sync_all().map_err(|e| e.to_string())
+    }
+    // Writes source to destination decrypted. A chunk that does not open means the passphrase is wrong or the file was changed, and the destination is then removed.
+    pub fn decrypt_file(&self, source: &Path, destination: &Path) -> Result<(), String> {
+        let result = self.decrypt_into(source, destination);
+        if result.is_err() {
+            let _ = std::fs::remove_file(destination);
+        }
+        result
+    }
+    fn decrypt_into(&self, source: &Path, destination: &Path) -> Result<(), String> {
+        let mut input = File::open(source).map_err(|e| e.to_string())?;
+        let mut header = vec![0; MAGIC.len() + 1];
+        if read_full(&mut input, &mut header).map_err(|e| e.to_string())? < header.len() || &header[..MAGIC.len()] != MAGIC {
+            return Err(String::from("the file is not encrypted"));
+        }
+        match u32::from(header[MAGIC.len()]) {
+            FORMAT => (),
+            other => return Err(format!("the file is encrypted in format {other}, which this client cannot read"))
+        }
+        header.resize(HEADER_LENGTH, 0);
+        if read_full(&mut input, &mut header[MAGIC.len() + 1..]).map_err(|e| e.to_string())? < HEADER_LENGTH - MAGIC.len() - 1 {
+            return Err(String::from("the file is not encrypted"));
+        }
+        let (salt, id) = header[MAGIC.len() + 1..].split_at(SALT_LENGTH);
+        let key = self.key(salt, id, b"hermes file")?;
+
+        let mut output = File::create(destination).map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; CHUNK + TAG_LENGTH];
+        let mut counter = 0;
+        loop {
+            let len = read_full(&mut input, &mut buffer).map_err(|e| e.to_string())?;
+            let last = len < buffer.len();
+            let opened = key.open_in_place(chunk_nonce(counter, last), Aad::from(&header), &mut buffer[..len])
+                .map_err(|_| String::from("unable to decrypt the file, either the passphrase is wrong or the file was changed"))?;
+            output.write_all(opened).map_err(|e| e.to_string())?;
+            if last {
+                break;
+            }
+            counter += 1;
+        }
+
+        output.sync_all().map_err(|e| e.to_string())
+    }
+
+    // The same name always gives the same result, so it can be asked for by name later
+    pub fn encrypt_name(&self, name: &str) -> Result<String, String> {
+        let nonce = self.name_nonce(name)?;
+        let mut sealed = name.as_bytes().to_vec();
+        self.key(NAME_SALT, &[], b"hermes name")?.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed).map_err(|_| String::from("unable to encrypt the name"))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.append(&mut sealed);
+        let encoded = base32_encode(&bytes);
+        match encoded.len() > MAX_NAME {
+            true => Err(format!("'{name}' is too long to be stored with its name hidden")),
+            false => Ok(encoded)
+        }
+    }
+    // None for names that were not encrypted with this passphrase
+    pub fn decrypt_name(&self, name: &str) -> Option<String> {
+        let mut bytes = base32_decode(name).filter(|x| x.len() > NONCE_LEN + TAG_LENGTH)?;
+        let nonce = Nonce::try_assume_unique_for_key(&bytes[..NONCE_LEN]).ok()?;
+        let opened = self.key(NAME_SALT, &[], b"hermes name").ok()?.open_in_place(nonce, Aad::empty(), &mut bytes[NONCE_LEN..]).ok()?;
+        String::from_utf8(opened.to_vec()).ok()
+    }
+
+    // The path as it is stored on the server, with each name encrypted when names are hidden. '.' and '..' are left alone.
+    pub fn remote_path(&self, path: &str) -> Result<String, String> {
+        if !self.hide_names {
+            return Ok(path.to_string());
+        }
+
+        let parts: Result<Vec<String>, String> = path.split('/').map(|x| match x {
+            "" | "." | ".." => Ok(x.to_string()),
+            name => self.encrypt_name(name)
+        }).collect();
+        Ok(parts?.join("/"))
+    }
+    // The path as it is shown, with every name that can be decrypted decrypted
+    pub fn local_path(&self, path: &str) -> String {
+        path.split('/').map(|x| self.decrypt_name(x).unwrap_or_else(|| x.to_string())).collect::<Vec<_>>().join("/")
+    }
+}
+
+#[test]
+fn test_vault() {
+    let dir = std::env::temp_dir().join(format!("hermes_vault_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let vault = Vault::with_iterations("correct horse", true, 1000);
+    let other = Vault::with_iterations("battery staple", true, 1000);
+
+    // Empty files, files that fill their last chunk exactly, and ones that do not, all come back as they were
+    for (i, len) in [0, 5, CHUNK, CHUNK * 2 + 17].into_iter().enumerate() {
+        let contents: Vec<u8> = (0..len).map(|x| (x * 7 + i) as u8).collect();
+        let (plain, sealed, opened) = (dir.join(format!("{i}.txt")), dir.join(format!("{i}.sealed")), dir.join(format!("{i}.opened")));
+        std::fs::write(&plain, &contents).unwrap();
+        vault.encrypt_file(&plain, &sealed).unwrap();
+        assert!(is_encrypted(&sealed) && !is_encrypted(&plain));
+        assert_eq!(std::fs::metadata(&sealed).unwrap().len() as usize, HEADER_LENGTH + len + (len / CHUNK + 1) * TAG_LENGTH);
+        vault.decrypt_file(&sealed, &opened).unwrap();
+        assert_eq!(std::fs::read(&opened).unwrap(), contents);
+    }
+
+    // The wrong passphrase, a flipped bit, and a missing last chunk are all refused, and leave nothing behind
+    let (sealed, opened) = (dir.join("3.sealed"), dir.join("bad.opened"));
+    assert!(other.decrypt_file(&sealed, &opened).is_err());
+    assert!(!opened.exists());
+    let mut bytes = std::fs::read(&sealed).unwrap();
+    bytes[HEADER_LENGTH + 3] ^= 1;
+    std::fs::write(dir.join("flipped"), &bytes).unwrap();
+    assert!(vault.decrypt_file(&dir.join("flipped"), &opened).is_err());
+    std::fs::write(dir.join("cut"), &std::fs::read(&sealed).unwrap()[..HEADER_LENGTH + 2 * (CHUNK + TAG_LENGTH)]).unwrap();
+    assert!(vault.decrypt_file(&dir.join("cut"), &opened).is_err());
+
+    // Each vault stretches the passphrase with a salt of its own, so the same file and passphrase give different keys, yet either vault opens the other's files
+    let again = Vault::with_iterations("correct horse", true, 1000);
+    again.encrypt_file(&dir.join("1.txt"), &dir.join("again.sealed")).unwrap();
+    let (first, second) = (std::fs::read(dir.join("1.sealed")).unwrap(), std::fs::read(dir.join("again.sealed")).unwrap());
+    assert_eq!(&first[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LENGTH], &vault.salt);
+    assert_eq!(&second[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LENGTH], &again.salt);
+    assert!(vault.salt != again.salt && vault.master(&vault.salt) != again.master(&again.salt));
+    assert!(first[HEADER_LENGTH..] != second[HEADER_LENGTH..]);
+    vault.decrypt_file(&dir.join("again.sealed"), &opened).unwrap();
+    assert_eq!(std::fs::read(&opened).unwrap(), std::fs::read(dir.join("1.txt")).unwrap());
+
+    // Any other format is refused, including files from before salts were kept in the header
+    let id = [7; ID_LENGTH];
+    let header = [MAGIC.as_slice(), &[1], &id].concat();
+    Vault::seal(&dir.join("1.txt"), &dir.join("old.sealed"), &header, &vault.key(&vault.salt, &id, b"hermes file").unwrap()).unwrap();
+    assert_eq!(again.decrypt_file(&dir.join("old.sealed"), &opened), Err(String::from("the file is encrypted in format 1, which this client cannot read")));
+    assert!(!opened.exists());
+
+    // Names are the same every time, fit a filesystem, and only open with the same passphrase
+    let name = vault.encrypt_name("Report Q3.pdf").unwrap();
+    assert_eq!(vault.encrypt_name("Report Q3.pdf").unwrap(), name);
+    assert_eq!(again.encrypt_name("Report Q3.pdf").unwrap(), name);
+    assert!(name.bytes().all(|x| BASE32.contains(&x)));
+    assert_eq!(vault.decrypt_name(&name), Some(String::from("Report Q3.pdf")));
+    assert_eq!(other.decrypt_name(&name), None);
+    assert_eq!(vault.decrypt_name("notes.txt"), None);
+    assert!(vault.encrypt_name(&"x".repeat(200)).is_err());
+
+    let remote = vault.remote_path("../docs/./a.txt").unwrap();
+    assert!(remote.starts_with("../") && remote.contains("/./") && !remote.contains("docs"));
+    assert_eq!(vault.local_path(&format!("/{remote}")), "/../docs/./a.txt");
+    assert_eq!(Vault::with_iterations("x", false, 1).remote_path("docs/a.txt").unwrap(), "docs/a.txt");
+    assert_eq!(base32_decode(&base32_encode(b"hermes")), Some(b"hermes".to_vec()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,121 @@
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use std::fmt::{Debug, Display};
+
+use hermes_common::http_codes::HttpCodes;
+
+// These values are part of the CLI contract. Scripts branch on them, so existing values must never be renumbered.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ExitCode {
+    Success = 0,
+    General = 1,
+    Usage = 2,
+    AuthFailure = 3,
+    NotFound = 4,
+    Conflict = 5,
+    Network = 6,
+    Quota = 7
+}
+impl Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Success => "success",
+            Self::General => "general",
+            Self::Usage => "usage",
+            Self::AuthFailure => "auth_failure",
+            Self::NotFound => "not_found",
+            Self::Conflict => "conflict",
+            Self::Network => "network",
+            Self::Quota => "quota"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl From<&HttpCodes> for ExitCode {
+    fn from(value: &HttpCodes) -> Self {
+        match value {
+            HttpCodes::Ok | HttpCodes::Created | HttpCodes::NoContent => Self::Success,
+            HttpCodes::Unauthorized | HttpCodes::Forbidden => Self::AuthFailure,
+            HttpCodes::NotFound => Self::NotFound,
+            HttpCodes::Conflict => Self::Conflict,
+            HttpCodes::TooManyRequests | HttpCodes::RequestTimeout => Self::Network,
+            HttpCodes::InsufficientStorage => Self::Quota,
+            HttpCodes::BadRequest | HttpCodes::PayloadTooLarge | HttpCodes::UnsupportedMediaType | HttpCodes::UnprocessableContent | HttpCodes::ImNotATeapot | HttpCodes::InternalServerError | HttpCodes::VersionNotSupported => Self::General
+        }
+    }
+}
+impl ExitCode {
+    pub fn code(&self) -> i32 {
+        *self as i32
+    }
+}
+
+pub struct CliError {
+    kind: ExitCode,
+    message: String
+}
+impl Debug for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({}): {}", self.kind, self.kind.code(), &self.message)
+    }
+}
+impl Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error: {}", &self.message)
+    }
+}
+impl CliError {
+    pub fn new(kind: ExitCode, message: String) -> Self {
+        Self {
+            kind,
+            message
+        }
+    }
+    pub fn from_status(status: &HttpCodes, message: String) -> Self {
+        Self::new(ExitCode::from(status), message)
+    }
+    pub fn usage(message: String) -> Self {
+        Self::new(ExitCode::Usage, message)
+    }
+    pub fn network(message: String) -> Self {
+        Self::new(ExitCode::Network, message)
+    }
+
+    pub fn kind(&self) -> ExitCode {
+        self.kind
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "kind": self.kind.to_string(),
+                "exit_code": self.kind.code(),
+                "message": &self.message
+            }
+        })
+    }
+
+    // Writes the error to stderr, either as text or as a single JSON line
+    pub fn report(&self, as_json: bool) {
+        if as_json {
+            eprintln!("{}", self.to_json());
+        } else {
+            eprintln!("{}", self);
+        }
+    }
+}
+
+#[test]
+fn test_cli_error_json() {
+    let err = CliError::from_status(&HttpCodes::NotFound, String::from("no such file 'a.txt'"));
+    assert_eq!(err.kind(), ExitCode::NotFound);
+
+    let value = err.to_json();
+    assert_eq!(value["error"]["kind"], "not_found");
+    assert_eq!(value["error"]["exit_code"], 4);
+    assert_eq!(value["error"]["message"], "no such file 'a.txt'");
+}
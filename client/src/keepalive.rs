@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+// How often to ping a server that did not say when it closes idle connections
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Decides when a connection has been quiet long enough to need a ping. The client only talks one request at a time, so nothing is sent
+// from here. Long running commands call due between steps, and ping when it says so.
+#[derive(Debug)]
+pub struct Keepalive {
+    interval: Duration,
+    last_activity: Instant,
+    sequence: u64
+}
+impl Keepalive {
+    // Pings at half the server's idle timeout, so one late step does not get the connection closed
+    pub fn new(idle_timeout: Option<Duration>) -> Self {
+        Self {
+            interval: idle_timeout.map(|x| x / 2).unwrap_or(DEFAULT_PING_INTERVAL).max(Duration::from_secs(1)),
+            last_activity: Instant::now(),
+            sequence: 0
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+    pub fn is_due(&self) -> bool {
+        self.last_activity.elapsed() >= self.interval
+    }
+    // Any request counts, not only pings
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+    pub fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+}
+
+#[test]
+fn test_keepalive_interval() {
+    assert_eq!(Keepalive::new(Some(Duration::from_secs(300))).interval(), Duration::from_secs(150));
+    assert_eq!(Keepalive::new(None).interval(), DEFAULT_PING_INTERVAL);
+    assert_eq!(Keepalive::new(Some(Duration::ZERO)).interval(), Duration::from_secs(1));
+
+    let mut keepalive = Keepalive::new(Some(Duration::from_secs(300)));
+    assert!(!keepalive.is_due());
+    keepalive.last_activity -= Duration::from_secs(200);
+    assert!(keepalive.is_due());
+    keepalive.touch();
+    assert!(!keepalive.is_due());
+    assert_eq!((keepalive.next_sequence(), keepalive.next_sequence()), (1, 2));
+}
@@ -0,0 +1,39 @@
+use keyring::{Entry, Error};
+
+// Passwords saved with 'login --save' are kept in the operating system's keychain under this service, one entry for each user on each server
+const SERVICE: &str = "hermes";
+
+// Which saved login an entry is for, such as 'alice@files.example.com:9090'
+pub fn profile(address: &str, username: &str) -> String {
+    format!("{username}@{address}")
+}
+
+fn entry(address: &str, username: &str) -> Result<Entry, Error> {
+    Entry::new(SERVICE, &profile(address, username))
+}
+
+// Keeps the password for a user on a server, replacing any kept before
+pub fn save_password(address: &str, username: &str, password: &str) -> Result<(), String> {
+    entry(address, username).and_then(|x| x.set_password(password)).map_err(|e| format!("unable to save the password in the keychain because '{e}'"))
+}
+
+// The password kept for a user on a server. A keychain that cannot be reached is treated as holding nothing, so the password is asked for instead.
+pub fn saved_password(address: &str, username: &str) -> Option<String> {
+    match entry(address, username).and_then(|x| x.get_password()) {
+        Ok(p) => Some(p),
+        Err(Error::NoEntry) => None,
+        Err(e) => {
+            tracing::debug!("unable to read the keychain because '{e}'");
+            None
+        }
+    }
+}
+
+// Forgets the password kept for a user on a server, answering whether there was one
+pub fn forget_password(address: &str, username: &str) -> Result<bool, String> {
+    match entry(address, username).and_then(|x| x.delete_credential()) {
+        Ok(()) => Ok(true),
+        Err(Error::NoEntry) => Ok(false),
+        Err(e) => Err(format!("unable to remove the password from the keychain because '{e}'"))
+    }
+}
@@ -1,3 +1,356 @@
+pub mod exit_codes;
+pub mod session_store;
+pub mod offline_queue;
+pub mod probe;
+pub mod sync;
+pub mod keepalive;
+pub mod retry;
+pub mod pending;
+pub mod connection;
+pub mod shell;
+pub mod quic;
+pub mod sftp;
+pub mod encryption;
+pub mod keychain;
+
+use exit_codes::{CliError, ExitCode};
+use offline_queue::{default_queue_path, OfflineQueue};
+use probe::{ProbeOptions, run_probe, print_probe, parse_size, parse_size_list, DEFAULT_FRAME_SIZES, DEFAULT_TRANSFER_SIZES, DEFAULT_PINGS};
+use sync::{ConflictPolicy, SyncOptions, run_sync};
+use connection::{ConnectOptions, Connection};
+use encryption::Vault;
+use keychain::saved_password;
+use sftp::SftpBridge;
+use shell::{Shell, ShellCommand, install_interrupt_handler, run_batch, run_interactive};
+use hermes_common::socket::SocketOptions;
+use hermes_common::transport::client_tls_config;
+use std::path::Path;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+// Operations made while the server was unreachable wait in the queue until the next connection replays them
+fn run_queue(args: &[String]) -> Result<(), CliError> {
+    let path = default_queue_path().ok_or_else(|| CliError::usage(String::from("unable to find the home directory")))?;
+    let mut queue = OfflineQueue::open(&path).map_err(|e| CliError::new(ExitCode::General, e))?;
+
+    match args.first().map(|x| x.as_str()) {
+        Some("list") | None => {
+            for operation in queue.operations() {
+                println!("{operation}");
+            }
+            Ok(())
+        },
+        Some("drop") => {
+            let key = args.get(1).ok_or_else(|| CliError::usage(String::from("usage: queue drop <key>")))?;
+            match queue.remove(key) {
+                Some(_) => queue.save().map_err(|e| CliError::new(ExitCode::General, e)),
+                None => Err(CliError::new(ExitCode::NotFound, format!("no queued operation with key '{key}'")))
+            }
+        },
+        Some("clear") => {
+            queue.clear();
+            queue.save().map_err(|e| CliError::new(ExitCode::General, e))
+        },
+        Some(other) => Err(CliError::usage(format!("unrecognized queue command '{other}'")))
+    }
+}
+
+const PROBE_USAGE: &str = "usage: probe <address> <username> [--pings <n>] [--frames <bytes,...>] [--sizes <size,...>] [--record] [--json]";
+
+// The password comes from HERMES_PASSWORD, or the first line of stdin, so it never shows up in the process list
+fn read_password() -> Result<String, CliError> {
+    if let Ok(p) = std::env::var("HERMES_PASSWORD") {
+        return Ok(p);
+    }
+
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).map_err(|e| CliError::new(ExitCode::General, e.to_string()))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+// A password saved with 'login --save' is used before any other
+fn login_password(address: &str, username: &str) -> Result<String, CliError> {
+    match saved_password(address, username) {
+        Some(p) => Ok(p),
+        None => read_password()
+    }
+}
+
+// Measures the connection to a server, for diagnosing slow or failing transfers
+fn run_probe_command(args: &[String], connect: ConnectOptions) -> Result<(), CliError> {
+    let (address, username) = match (args.first(), args.get(1)) {
+        (Some(a), Some(u)) => (a.clone(), u.clone()),
+        _ => return Err(CliError::usage(String::from(PROBE_USAGE)))
+    };
+
+    let mut options = ProbeOptions {
+        address,
+        username,
+        password: String::new(),
+        pings: DEFAULT_PINGS,
+        frame_sizes: DEFAULT_FRAME_SIZES.to_vec(),
+        transfer_sizes: DEFAULT_TRANSFER_SIZES.to_vec(),
+        record: false,
+        connect
+    };
+    let mut json = false;
+
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = |name: &str| rest.next().cloned().ok_or_else(|| CliError::usage(format!("{name} needs a value")));
+        match arg.as_str() {
+            "--pings" => options.pings = value("--pings")?.parse().map_err(|_| CliError::usage(String::from("--pings takes a count")))?,
+            "--frames" => options.frame_sizes = parse_size_list(&value("--frames")?).ok_or_else(|| CliError::usage(String::from("--frames takes a list of sizes")))?.into_iter().map(|x| x as usize).collect(),
+            "--sizes" => options.transfer_sizes = parse_size_list(&value("--sizes")?).ok_or_else(|| CliError::usage(String::from("--sizes takes a list of sizes")))?,
+            "--record" => options.record = true,
+            "--json" => json = true,
+            other => return Err(CliError::usage(format!("unrecognized probe option '{other}'")))
+        }
+    }
+
+    options.password = login_password(&options.address, &options.username)?;
+    let result = run_probe(&options)?;
+    if json {
+        println!("{}", serde_json::to_string(&result).map_err(|e| CliError::new(ExitCode::General, e.to_string()))?);
+    } else {
+        print_probe(&result);
+    }
+
+    Ok(())
+}
+
+const SYNC_USAGE: &str = "usage: sync <address> <username> <local folder> <remote folder> [--dry-run] [--conflict newest-wins|keep-both]";
+
+// Makes a local folder and a remote one hold the same files, in both directions
+fn run_sync_command(args: &[String], connect: ConnectOptions) -> Result<(), CliError> {
+    let (address, username, local, remote) = match (args.first(), args.get(1), args.get(2), args.get(3)) {
+        (Some(a), Some(u), Some(l), Some(r)) => (a.clone(), u.clone(), l.clone(), r.clone()),
+        _ => return Err(CliError::usage(String::from(SYNC_USAGE)))
+    };
+
+    let mut options = SyncOptions {
+        address,
+        username,
+        password: String::new(),
+        local: local.into(),
+        remote,
+        dry_run: false,
+        policy: ConflictPolicy::NewestWins,
+        connect
+    };
+
+    let mut rest = args[4..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--conflict" => options.policy = rest.next().ok_or_else(|| CliError::usage(String::from("--conflict needs a value")))?.parse().map_err(CliError::usage)?,
+            other => return Err(CliError::usage(format!("unrecognized sync option '{other}'")))
+        }
+    }
+
+    options.password = login_password(&options.address, &options.username)?;
+    let report = run_sync(&options)?;
+    for action in &report.actions {
+        match options.dry_run {
+            true => println!("would {action}"),
+            false => println!("{action}")
+        }
+    }
+    for (action, reason) in &report.failures {
+        eprintln!("failed to {action}: {reason}");
+    }
+
+    match report.failures.len() {
+        0 => Ok(()),
+        n => Err(CliError::new(ExitCode::General, format!("{n} file(s) could not be synced")))
+    }
+}
+
+const BRIDGE_USAGE: &str = "usage: bridge-sftp <address> <username>";
+
+// Speaks SFTP on stdin and stdout, for 'sftp -D' or an sshd Subsystem line, and carries it out on the server.
+// Stdin carries the protocol, so the password can only be one saved with 'login --save', or come from HERMES_PASSWORD.
+fn run_bridge_command(args: &[String], connect: ConnectOptions) -> Result<(), CliError> {
+    let (address, username) = match (args.first(), args.get(1), args.len()) {
+        (Some(a), Some(u), 2) => (a, u),
+        _ => return Err(CliError::usage(String::from(BRIDGE_USAGE)))
+    };
+    let password = saved_password(address, username).or_else(|| std::env::var("HERMES_PASSWORD").ok())
+        .ok_or_else(|| CliError::usage(String::from("bridge-sftp needs a password saved with 'login --save', or HERMES_PASSWORD, since stdin carries SFTP")))?;
+
+    let mut connection = Connection::open(address, username, &password, &connect)?;
+    connection.set_vault(Vault::from_env());
+    let bridge = SftpBridge::new(connection).map_err(|e| CliError::new(ExitCode::General, e))?;
+    bridge.run(&mut std::io::stdin().lock(), &mut std::io::stdout().lock()).map_err(CliError::network)
+}
+
+// Removes '--name <value>' from the arguments, wherever it is
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, CliError> {
+    match args.iter().position(|x| x == name) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        },
+        Some(_) => Err(CliError::usage(format!("{name} needs a value"))),
+        None => Ok(None)
+    }
+}
+
+// --nodelay on|off, --keepalive <seconds|off>, --send-buffer <size>, and --recv-buffer <size> set up the sockets of every connection opened.
+// --tls speaks to the server over TLS, checking its certificate against the public web roots, and --ca <file> checks it against that CA instead.
+// Anything left out keeps the default.
+fn take_connect_options(args: &mut Vec<String>) -> Result<ConnectOptions, CliError> {
+    let mut socket = SocketOptions::default();
+    if let Some(value) = take_option(args, "--nodelay")? {
+        socket.nodelay = match value.as_str() {
+            "on" => true,
+            "off" => false,
+            _ => return Err(CliError::usage(String::from("--nodelay takes 'on' or 'off'")))
+        };
+    }
+    if let Some(value) = take_option(args, "--keepalive")? {
+        socket.keepalive = match value.as_str() {
+            "off" => None,
+            secs => Some(Duration::from_secs(secs.parse().ok().filter(|x| *x > 0).ok_or_else(|| CliError::usage(String::from("--keepalive takes a number of seconds, or 'off'")))?))
+        };
+    }
+    for (name, buffer) in [("--send-buffer", &mut socket.send_buffer), ("--recv-buffer", &mut socket.recv_buffer)] {
+        if let Some(value) = take_option(args, name)? {
+            *buffer = Some(parse_size(&value).and_then(|x| u32::try_from(x).ok()).ok_or_else(|| CliError::usage(format!("{name} takes a size, such as 262144 or 256K")))?);
+        }
+    }
+
+    let tls = args.iter().position(|x| x == "--tls").map(|i| args.remove(i)).is_some();
+    let tls = match take_option(args, "--ca")? {
+        Some(ca) => Some(client_tls_config(Some(Path::new(&ca)), None).map_err(|e| CliError::new(ExitCode::General, format!("unable to load the CA because {e}")))?),
+        None if tls => Some(client_tls_config(None, None).map_err(|e| CliError::new(ExitCode::General, e))?),
+        None => None
+    };
+
+    Ok(ConnectOptions { socket, tls })
+}
+
+// With no command, or 'connect', opens the interactive shell. --batch runs a script of shell commands, and any other shell command runs once.
+// The server and user come from --server and --user, or HERMES_SERVER and HERMES_USER.
+fn run_shell(args: &[String], connect: ConnectOptions) -> Result<(), CliError> {
+    let mut args = args.to_vec();
+    let server = take_option(&mut args, "--server")?.or_else(|| std::env::var("HERMES_SERVER").ok());
+    let user = take_option(&mut args, "--user")?.or_else(|| std::env::var("HERMES_USER").ok());
+    let batch = take_option(&mut args, "--batch")?;
+    let queue_as = server.as_deref().zip(user.as_deref()).map(|(a, u)| keychain::profile(a, u));
+    let login = match (server, user) {
+        (Some(address), Some(username)) => Some(ShellCommand::Connect { address, username, save: false }),
+        _ => None
+    };
+
+    install_interrupt_handler();
+    let mut shell = Shell::with_options(connect);
+    if let Some(path) = batch {
+        let script = match path.as_str() {
+            "-" => std::io::read_to_string(std::io::stdin()),
+            p => std::fs::read_to_string(p)
+        };
+        let script = script.map_err(|e| CliError::new(ExitCode::NotFound, format!("unable to read the script '{path}' because '{e}'")))?;
+        if let Some(c) = login {
+            shell.execute(c, &mut read_password)?;
+        }
+        return run_batch(&mut shell, &script, &mut read_password);
+    }
+
+    let command = match args.is_empty() {
+        true => return run_interactive(shell, login),
+        false => ShellCommand::from_words(&args).map_err(CliError::usage)?
+    };
+    match command {
+        // Saving a password from the command line only logs in to check it
+        ShellCommand::Connect { save: true, .. } => {
+            let result = shell.execute(command, &mut read_password);
+            shell.close();
+            result
+        },
+        ShellCommand::Connect { .. } => run_interactive(shell, Some(command)),
+        ShellCommand::Help => shell.execute(command, &mut read_password),
+        ShellCommand::Logout(None) => match login {
+            Some(ShellCommand::Connect { address, username, .. }) => shell.execute(ShellCommand::Logout(Some((address, username))), &mut read_password),
+            _ => Err(CliError::usage(String::from("give the login to forget, or the server with --server and --user")))
+        },
+        ShellCommand::Logout(_) => shell.execute(command, &mut read_password),
+        command => {
+            let login = login.ok_or_else(|| CliError::usage(String::from("give the server with --server and --user, or HERMES_SERVER and HERMES_USER")))?;
+            // An upload or delete that cannot reach the server waits in the queue for the next time this login connects
+            let action = shell.offline_action(&command);
+            if let Err(e) = shell.execute(login, &mut read_password) {
+                return shell.enqueue(&queue_as.unwrap_or_default(), action, e);
+            }
+            let result = shell.execute(command, &mut read_password);
+            shell.close();
+            result
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let mut args = args.to_vec();
+    let connect = take_connect_options(&mut args)?;
+    match args.first().map(|x| x.as_str()) {
+        Some("queue") => run_queue(&args[1..]),
+        Some("probe") => run_probe_command(&args[1..], connect),
+        Some("sync") => run_sync_command(&args[1..], connect),
+        Some("bridge-sftp") => run_bridge_command(&args[1..], connect),
+        _ => run_shell(&args, connect)
+    }
+}
+
+// Logs go to stderr. HERMES_LOG takes a level or filter, and otherwise only warnings are shown, or every step with --verbose.
+// --json-errors keeps stderr to the one JSON object unless HERMES_LOG asks for more.
+fn init_logging(verbose: bool, json_errors: bool) {
+    let default = match (verbose, json_errors) {
+        (true, _) => "warn,hermes_cli=debug,hermes_common=debug",
+        (false, true) => "off",
+        (false, false) => "warn"
+    };
+    let filter = std::env::var("HERMES_LOG").ok()
+        .and_then(|x| EnvFilter::try_new(x).ok())
+        .unwrap_or_else(|| EnvFilter::new(default));
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .without_time()
+        .try_init();
+}
+
 fn main() {
-    println!("Hello, world!");
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let json_errors = args.iter().any(|x| x == "--json-errors");
+    let verbose = args.iter().any(|x| x == "--verbose");
+    args.retain(|x| x != "--json-errors" && x != "--verbose");
+    init_logging(verbose, json_errors);
+
+    if let Err(e) = run(&args) {
+        e.report(json_errors);
+        std::process::exit(e.kind().code());
+    }
+}
+
+#[test]
+fn test_connect_options() {
+    let words = |x: &str| x.split(' ').map(String::from).collect::<Vec<_>>();
+
+    // The options are taken out wherever they are, leaving the command
+    let mut args = words("--nodelay off get a.txt --keepalive 30 --send-buffer 256K --recv-buffer 65536");
+    let options = take_connect_options(&mut args).unwrap();
+    assert_eq!(args, words("get a.txt"));
+    assert_eq!(options.socket, SocketOptions { nodelay: false, send_buffer: Some(256 * 1024), recv_buffer: Some(65536), keepalive: Some(Duration::from_secs(30)) });
+
+    // Nothing given keeps the defaults, and values that make no sense are refused
+    assert_eq!(take_connect_options(&mut words("ls")).unwrap().socket, SocketOptions::default());
+    assert_eq!(take_connect_options(&mut words("--keepalive off ls")).unwrap().socket.keepalive, None);
+    assert!(take_connect_options(&mut words("ls")).unwrap().tls.is_none());
+    assert!(take_connect_options(&mut words("--tls ls")).unwrap().tls.is_some());
+    assert_eq!(take_connect_options(&mut words("--ca /missing/ca.pem ls")).unwrap_err().kind(), ExitCode::General);
+    for bad in ["--nodelay yes", "--keepalive 0", "--send-buffer 8G", "--recv-buffer", "--ca"] {
+        assert_eq!(take_connect_options(&mut words(bad)).unwrap_err().kind(), ExitCode::Usage);
+    }
 }
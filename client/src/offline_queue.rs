@@ -0,0 +1,271 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::session_store::hermes_directory;
+use hermes_common::messages::FileChange;
+
+pub fn default_queue_path() -> Option<PathBuf> {
+    Some(hermes_directory()?.join("queue.json"))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Only has to be unique among the keys of one user, which the time, process, and a counter cover
+pub fn generate_idempotency_key() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{:x}-{:x}-{:x}", nanos, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum QueuedAction {
+    Upload { local_path: PathBuf, remote_path: String },
+    Delete {
+        remote_path: String,
+        #[serde(default)]
+        recursive: bool
+    }
+}
+impl Display for QueuedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upload { local_path, remote_path } => write!(f, "upload '{}' to '{}'", local_path.display(), remote_path),
+            Self::Delete { remote_path, .. } => write!(f, "delete '{}'", remote_path)
+        }
+    }
+}
+impl QueuedAction {
+    pub fn remote_path(&self) -> &str {
+        match self {
+            Self::Upload { remote_path, .. } => remote_path,
+            Self::Delete { remote_path, .. } => remote_path
+        }
+    }
+}
+
+// A mutating operation made while the server was unreachable. The key travels with it when it is replayed, so one the server already applied is not applied twice.
+// Paths starting with '/' are from the top of the server, and any other from the folder a login starts in.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct QueuedOperation {
+    pub key: String,
+    #[serde(default)]
+    pub login: String, //Who made it, such as 'alice@files.example.com:9090', since it is only replayed when they connect
+    pub action: QueuedAction,
+    pub queued_at: u64,
+    #[serde(default)]
+    pub sent_at: Option<u64>, //When a replay first sent it, so the changes it made are not taken for conflicts if it has to be sent again
+    #[serde(default)]
+    pub conflict: Option<String> //Set when the remote file changed after this was queued, so the user has to decide
+}
+impl Display for QueuedOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} as {} (queued at {})", &self.key, &self.action, &self.login, self.queued_at)?;
+        if let Some(c) = self.conflict.as_ref() {
+            write!(f, " CONFLICT: {c}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// How the connection layer answers while a queue is replayed
+pub trait ReplayTarget {
+    // The user the operations are replayed as, whom the server credits with the changes they make
+    fn actor(&self) -> &str;
+    // What changed at or beneath the remote path after a time. An Err means the server is unreachable again.
+    fn remote_changes(&mut self, remote_path: &str, since: u64) -> Result<Vec<FileChange>, String>;
+    // Sends the operation with its idempotency key. Err means the server is unreachable again, while Ok(Err(..)) means the server refused it.
+    fn apply(&mut self, operation: &QueuedOperation) -> Result<Result<(), String>, String>;
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct ReplayReport {
+    pub applied: usize,
+    pub conflicts: usize,
+    pub refused: Vec<(QueuedOperation, String)>,
+    pub interrupted: Option<String>
+}
+
+pub struct OfflineQueue {
+    path: PathBuf,
+    operations: Vec<QueuedOperation>
+}
+impl OfflineQueue {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let operations = match fs::read_to_string(path) {
+            Ok(contents) if !contents.trim().is_empty() => {
+                match serde_json::from_str(&contents) {
+                    Ok(o) => o,
+                    Err(e) => return Err(format!("could not parse offline queue because '{e}'"))
+                }
+            },
+            Ok(_) => Vec::new(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string())
+        };
+
+        Ok(
+            Self {
+                path: path.to_path_buf(),
+                operations
+            }
+        )
+    }
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let contents = serde_json::to_string(&self.operations).map_err(|e| e.to_string())?;
+        fs::write(&self.path, contents).map_err(|e| e.to_string())
+    }
+
+    pub fn operations(&self) -> &Vec<QueuedOperation> {
+        &self.operations
+    }
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.operations.clear();
+    }
+
+    pub fn enqueue(&mut self, login: &str, action: QueuedAction) -> &QueuedOperation {
+        self.operations.push(
+            QueuedOperation {
+                key: generate_idempotency_key(),
+                login: login.to_string(),
+                action,
+                queued_at: unix_now(),
+                sent_at: None,
+                conflict: None
+            }
+        );
+
+        self.operations.last().unwrap()
+    }
+    pub fn remove(&mut self, key: &str) -> Option<QueuedOperation> {
+        let index = self.operations.iter().position(|x| x.key == key)?;
+        Some(self.operations.remove(index))
+    }
+
+    // Replays what the login queued, in order. Anything changed on the server since it was queued is marked as a conflict and left for the user,
+    // except what the replay changed itself, including whatever an earlier replay of the same operation got done before it was cut off.
+    // Replay stops at the first sign that the server is unreachable again, keeping the rest for next time.
+    pub fn replay<T: ReplayTarget>(&mut self, login: &str, target: &mut T) -> ReplayReport {
+        let started = unix_now();
+        let mut report = ReplayReport::default();
+        let mut kept = Vec::<QueuedOperation>::new();
+        let mut pending = std::mem::take(&mut self.operations).into_iter();
+
+        for mut operation in pending.by_ref() {
+            if operation.conflict.is_some() || operation.login != login {
+                kept.push(operation);
+                continue;
+            }
+
+            let changes = match target.remote_changes(operation.action.remote_path(), operation.queued_at) {
+                Ok(c) => c,
+                Err(e) => {
+                    report.interrupted = Some(e);
+                    kept.push(operation);
+                    break;
+                }
+            };
+
+            let own_since = operation.sent_at.unwrap_or(started);
+            let is_own = |x: &FileChange| x.at >= own_since && x.event.actor.as_deref() == Some(target.actor());
+            if let Some(c) = changes.iter().find(|x| !is_own(x)) {
+                operation.conflict = Some(format!("'{}' was changed on the server at {} after this was queued", c.event.path, c.at));
+                report.conflicts += 1;
+                kept.push(operation);
+                continue;
+            }
+
+            operation.sent_at.get_or_insert(started);
+            match target.apply(&operation) {
+                Ok(Ok(())) => report.applied += 1,
+                Ok(Err(e)) => report.refused.push((operation, e)),
+                Err(e) => {
+                    report.interrupted = Some(e);
+                    kept.push(operation);
+                    break;
+                }
+            }
+        }
+
+        kept.extend(pending);
+        self.operations = kept;
+        report
+    }
+}
+
+#[test]
+fn test_offline_queue_replay() {
+    use hermes_common::messages::{FileEvent, FileEventKind};
+
+    struct FakeServer {
+        changes: Vec<FileChange>,
+        reachable_for: usize,
+        applied: Vec<String>
+    }
+    impl ReplayTarget for FakeServer {
+        fn actor(&self) -> &str {
+            "alice"
+        }
+        fn remote_changes(&mut self, remote_path: &str, since: u64) -> Result<Vec<FileChange>, String> {
+            Ok(self.changes.iter().filter(|x| x.event.path == remote_path && x.at > since).cloned().collect())
+        }
+        fn apply(&mut self, operation: &QueuedOperation) -> Result<Result<(), String>, String> {
+            if self.reachable_for == 0 {
+                return Err(String::from("connection refused"));
+            }
+
+            self.reachable_for -= 1;
+            self.applied.push(operation.key.clone());
+            Ok(Ok(()))
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("hermes_queue_{}.json", std::process::id()));
+    let mut queue = OfflineQueue::open(&path).unwrap();
+    let first = queue.enqueue("alice@files:9090", QueuedAction::Delete { remote_path: String::from("a"), recursive: false }).key.clone();
+    queue.enqueue("bob@files:9090", QueuedAction::Delete { remote_path: String::from("a"), recursive: true });
+    queue.enqueue("alice@files:9090", QueuedAction::Delete { remote_path: String::from("changed"), recursive: false });
+    let last = queue.enqueue("alice@files:9090", QueuedAction::Upload { local_path: PathBuf::from("b"), remote_path: String::from("b") }).key.clone();
+    assert_ne!(first, last);
+
+    queue.save().unwrap();
+    let mut queue = OfflineQueue::open(&path).unwrap();
+    assert_eq!(queue.len(), 4);
+
+    // The server drops again after one operation, and the file bob changed is held back as a conflict. Another login's operations wait for it.
+    // The change alice's own replay makes to b is not a conflict.
+    let change = |path: &str, actor: &str| FileChange { event: FileEvent { kind: FileEventKind::Modified, path: path.to_string(), actor: Some(actor.to_string()) }, at: u64::MAX };
+    let mut server = FakeServer { changes: vec![change("changed", "bob"), change("b", "alice")], reachable_for: 1, applied: Vec::new() };
+    let report = queue.replay("alice@files:9090", &mut server);
+    assert_eq!((report.applied, report.conflicts), (1, 1));
+    assert!(report.interrupted.is_some());
+    assert_eq!(server.applied, vec![first]);
+    assert_eq!(queue.len(), 3);
+
+    // On the next replay only the conflict is left behind
+    server.reachable_for = 1;
+    let report = queue.replay("alice@files:9090", &mut server);
+    assert_eq!((report.applied, report.conflicts, report.interrupted), (1, 0, None));
+    assert_eq!(server.applied.last(), Some(&last));
+    assert!(queue.operations().iter().all(|x| x.conflict.is_some() || x.login == "bob@files:9090"));
+    assert_eq!(queue.len(), 2);
+
+    let _ = fs::remove_file(&path);
+}
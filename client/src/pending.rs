@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use hermes_common::messages::{Message, MessageType};
+
+// Requests sent on one connection that are still owed an answer, by ID. Responses are routed to them as they are read,
+// so a response that arrives while another request is being waited on is held until its own waiter asks for it.
+#[derive(Default, Debug)]
+pub struct PendingRequests {
+    last_id: u64,
+    waiting: BTreeMap<u64, Option<Message>> //None until the response arrives
+}
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Numbers the request and starts waiting on it. IDs count from 1, since 0 means a message answers nothing.
+    pub fn issue(&mut self, request: Message) -> (u64, Message) {
+        self.last_id += 1;
+        self.waiting.insert(self.last_id, None);
+        (self.last_id, request.with_request_id(self.last_id))
+    }
+    // Hands a response to its waiter. Servers that predate IDs answer in order, so a response without one goes to the oldest request still waiting.
+    // The response is given back when nothing is waiting on it, which is always so for events the server pushes.
+    pub fn deliver(&mut self, response: Message) -> Result<(), Message> {
+        let id = match response.request_id() {
+            _ if *response.message_type() == MessageType::Event => None,
+            0 => self.waiting.iter().find(|(_, x)| x.is_none()).map(|(id, _)| *id),
+            id => Some(id)
+        };
+
+        match id.and_then(|x| self.waiting.get_mut(&x)) {
+            Some(slot @ None) => {
+                *slot = Some(response);
+                Ok(())
+            },
+            _ => Err(response)
+        }
+    }
+    // The response, once it has been delivered. It is no longer waited on after this.
+    pub fn take(&mut self, id: u64) -> Option<Message> {
+        match self.waiting.get(&id) {
+            Some(Some(_)) => self.waiting.remove(&id).flatten(),
+            _ => None
+        }
+    }
+    // Stops waiting on a request, such as when the connection broke before it was answered
+    pub fn forget(&mut self, id: u64) {
+        self.waiting.remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[test]
+fn test_pending_requests() {
+    use hermes_common::messages::{ping_message, pong_message, event_message, FileEvent, FileEventKind};
+
+    let mut pending = PendingRequests::new();
+    let (first, request) = pending.issue(ping_message(1));
+    assert_eq!((first, request.request_id()), (1, 1));
+    let (second, _) = pending.issue(ping_message(2));
+    assert_eq!(pending.len(), 2);
+
+    // Answers arriving out of order each reach their own waiter
+    pending.deliver(pong_message(2, None).with_request_id(second)).unwrap();
+    assert!(pending.take(first).is_none());
+    pending.deliver(pong_message(1, None).with_request_id(first)).unwrap();
+    assert_eq!(pending.take(first).unwrap().request_id(), first);
+    assert_eq!(pending.take(second).unwrap().request_id(), second);
+    assert!(pending.is_empty());
+
+    // Without IDs, answers are taken to come in order
+    let (third, _) = pending.issue(ping_message(3));
+    let (fourth, _) = pending.issue(ping_message(4));
+    pending.deliver(pong_message(3, None)).unwrap();
+    pending.deliver(pong_message(4, None)).unwrap();
+    assert_eq!(pending.take(third), Some(pong_message(3, None)));
+    assert_eq!(pending.take(fourth), Some(pong_message(4, None)));
+
+    // Nothing waits on pushed events, on forgotten requests, or on a second answer to the same request
+    let (fifth, _) = pending.issue(ping_message(5));
+    let event = event_message(&FileEvent { kind: FileEventKind::Created, path: String::from("a.txt"), actor: None });
+    assert_eq!(pending.deliver(event.clone()), Err(event));
+    pending.forget(fifth);
+    assert!(pending.deliver(pong_message(5, None).with_request_id(fifth)).is_err());
+    let (sixth, _) = pending.issue(ping_message(6));
+    pending.deliver(pong_message(6, None).with_request_id(sixth)).unwrap();
+    assert!(pending.deliver(pong_message(6, None).with_request_id(sixth)).is_err());
+}
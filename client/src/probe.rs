@@ -0,0 +1,310 @@
+use serde::{Serialize, Deserialize};
+use std::time::{Duration, Instant};
+
+use crate::connection::{ConnectOptions, server_name};
+use crate::exit_codes::{CliError, ExitCode};
+use hermes_common::chunking::ChunkWriter;
+use hermes_common::file_io::{receive_network_binary, send_network_frames, frame_count_for, BUFF_SIZE};
+use hermes_common::framing::{read_frame, write_frame};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, connect_message, extract_connect_ack_message, extract_session_ack, attach_session, close_message, extract_ack_message};
+use hermes_common::messages::{heartbeat_message, extract_heartbeat_message, probe_message, ProbeDirection, diagnostics_message};
+use hermes_common::messages::{advertise_capabilities, extract_capabilities};
+use hermes_common::protocol::{Capabilities, Capability, CURRENT_PROTOCOL_VERSION};
+use hermes_common::transport::Transport;
+
+// Frame sizes around the usual MTUs (Ethernet, PPPoE, VPN tunnels, jumbo frames), then well past them
+pub const DEFAULT_FRAME_SIZES: [usize; 8] = [64, 512, 1200, 1400, 1500, 4000, 9000, 65000];
+pub const DEFAULT_TRANSFER_SIZES: [u64; 2] = [64 * 1024, 4 * 1024 * 1024];
+pub const DEFAULT_PINGS: u32 = 10;
+
+// A frame that does not come back in this long is taken as dropped somewhere on the path
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct ProbeOptions {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub pings: u32,
+    pub frame_sizes: Vec<usize>,
+    pub transfer_sizes: Vec<u64>,
+    pub record: bool,
+    pub connect: ConnectOptions
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Debug)]
+pub struct LatencySummary {
+    pub samples: u32,
+    pub lost: u32,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64
+}
+impl LatencySummary {
+    pub fn from_samples(samples: &[Duration], lost: u32) -> Self {
+        let ms: Vec<f64> = samples.iter().map(|x| x.as_secs_f64() * 1000.0).collect();
+        if ms.is_empty() {
+            return Self { lost, ..Default::default() };
+        }
+
+        Self {
+            samples: ms.len() as u32,
+            lost,
+            min_ms: ms.iter().cloned().fold(f64::INFINITY, f64::min),
+            avg_ms: ms.iter().sum::<f64>() / ms.len() as f64,
+            max_ms: ms.iter().cloned().fold(0.0, f64::max)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct FrameSizeCheck {
+    pub padding: usize,
+    pub ok: bool,
+    pub rtt_ms: Option<f64>,
+    pub error: Option<String>
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ThroughputSample {
+    pub direction: ProbeDirection,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub mbps: f64,
+    pub error: Option<String>
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ProbeResult {
+    pub address: String,
+    pub latency: LatencySummary,
+    pub frame_sizes: Vec<FrameSizeCheck>,
+    pub throughput: Vec<ThroughputSample>,
+    #[serde(default)]
+    pub capabilities: Capabilities, //What the server agreed to speak, kept with the report for chasing interop problems
+    pub recorded: bool
+}
+impl ProbeResult {
+    // The largest padding that made it there and back, when a larger one did not
+    pub fn largest_working_frame(&self) -> Option<usize> {
+        self.frame_sizes.iter().filter(|x| x.ok).map(|x| x.padding).max()
+    }
+}
+
+// Reads sizes like 4096, 64K, or 16M. Transfers only move whole frames, so sizes are rounded up to one.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, scale) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1024),
+        (i, 'm' | 'M') => (&text[..i], 1024 * 1024),
+        (i, 'g' | 'G') => (&text[..i], 1024 * 1024 * 1024),
+        _ => (text, 1)
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(scale).filter(|x| *x > 0)
+}
+pub fn parse_size_list(text: &str) -> Option<Vec<u64>> {
+    text.split(',').map(parse_size).collect()
+}
+
+// One logged-in connection, spoken to one request at a time
+struct ProbeConnection {
+    stream: Transport,
+    session: String,
+    capabilities: Capabilities
+}
+impl ProbeConnection {
+    fn open(options: &ProbeOptions) -> Result<Self, CliError> {
+        let mut stream = Transport::connect(options.address.as_str(), server_name(&options.address), options.connect.tls.clone(), &options.connect.socket).map_err(|e| CliError::network(format!("unable to reach '{}' because {e}", &options.address)))?;
+        stream.tcp_stream().set_read_timeout(Some(PROBE_TIMEOUT)).map_err(|e| CliError::network(e.to_string()))?;
+
+        // The probe only needs the diagnostics requests, and a server that predates them cannot be probed
+        let wanted: Capabilities = [Capability::Diagnostics].into_iter().collect();
+        let connect = connect_message(options.username.clone(), options.password.clone(), CURRENT_PROTOCOL_VERSION);
+        write_frame(&mut stream, &advertise_capabilities(connect, &wanted)).map_err(CliError::network)?;
+        let response = read_frame(&mut stream).map_err(CliError::network)?;
+        let session = extract_session_ack(&response);
+        let capabilities = extract_capabilities(&response).unwrap_or_else(Capabilities::legacy);
+        match (extract_connect_ack_message(response), session) {
+            (Some((HttpCodes::Ok, _, _, _)), Some(_)) if !capabilities.contains(Capability::Diagnostics) => Err(CliError::new(ExitCode::General, format!("the server does not support probes (capabilities: {capabilities})"))),
+            (Some((HttpCodes::Ok, _, _, _)), Some(s)) => Ok(Self { stream, session: s.token().to_string(), capabilities }),
+            (Some((code, message, _, _)), _) => Err(CliError::from_status(&code, format!("the server refused the login with '{message}'"))),
+            (None, _) => Err(CliError::network(String::from("malformed response from the server")))
+        }
+    }
+
+    fn request(&mut self, message: Message) -> Result<Message, String> {
+        write_frame(&mut self.stream, &attach_session(message, &self.session))?;
+        read_frame(&mut self.stream)
+    }
+
+    fn heartbeat(&mut self, sequence: u64, padding: usize) -> Result<Duration, String> {
+        let start = Instant::now();
+        let response = self.request(heartbeat_message(sequence, padding))?;
+        let elapsed = start.elapsed();
+
+        match extract_heartbeat_message(response.clone()) {
+            Some((s, p)) if s == sequence && p.len() == padding => Ok(elapsed),
+            Some(_) => Err(String::from("the echo did not match the heartbeat")),
+            None => Err(extract_ack_message(response).map(|(_, m)| m).unwrap_or_else(|| String::from("malformed heartbeat response")))
+        }
+    }
+
+    // Times from the request until the last byte has moved, so the RTT of the ack is part of it, as it is for a real transfer
+    fn transfer(&mut self, direction: ProbeDirection, bytes: u64) -> Result<Duration, String> {
+        let frame_count = frame_count_for(bytes);
+        let start = Instant::now();
+        match extract_ack_message(self.request(probe_message(direction, frame_count))?) {
+            Some((HttpCodes::Ok, _)) => (),
+            Some((_, message)) => return Err(message),
+            None => return Err(String::from("malformed probe response"))
+        }
+
+        match direction {
+            ProbeDirection::Upload => {
+                let frames = ChunkWriter::frames(&vec![0u8; (frame_count * BUFF_SIZE) as usize]);
+                if !send_network_frames(&mut self.stream, &frames) {
+                    return Err(String::from("the probe was interrupted"));
+                }
+                match extract_ack_message(read_frame(&mut self.stream)?) {
+                    Some((HttpCodes::Ok, _)) => (),
+                    Some((_, message)) => return Err(message),
+                    None => return Err(String::from("malformed probe response"))
+                }
+            },
+            ProbeDirection::Download => {
+                receive_network_binary(&mut self.stream, frame_count).ok_or_else(|| String::from("the probe was interrupted"))?;
+            }
+        }
+
+        Ok(start.elapsed())
+    }
+
+    fn close(mut self) {
+        let _ = write_frame(&mut self.stream, &close_message());
+    }
+}
+
+// Measures the RTT, which frame sizes get through, and throughput both ways. A frame size that fails leaves the connection
+// in an unknown state, so the probe carries on over a new one.
+pub fn run_probe(options: &ProbeOptions) -> Result<ProbeResult, CliError> {
+    let mut connection = ProbeConnection::open(options)?;
+    let mut sequence = 0u64;
+
+    let mut samples = Vec::new();
+    let mut lost = 0;
+    for _ in 0..options.pings {
+        sequence += 1;
+        match connection.heartbeat(sequence, 0) {
+            Ok(rtt) => samples.push(rtt),
+            Err(_) => {
+                lost += 1;
+                connection = ProbeConnection::open(options)?;
+            }
+        }
+    }
+
+    let mut frame_sizes = Vec::with_capacity(options.frame_sizes.len());
+    for padding in options.frame_sizes.iter().cloned() {
+        sequence += 1;
+        let check = match connection.heartbeat(sequence, padding) {
+            Ok(rtt) => FrameSizeCheck { padding, ok: true, rtt_ms: Some(rtt.as_secs_f64() * 1000.0), error: None },
+            Err(e) => {
+                connection = ProbeConnection::open(options)?;
+                FrameSizeCheck { padding, ok: false, rtt_ms: None, error: Some(e) }
+            }
+        };
+        frame_sizes.push(check);
+    }
+
+    let mut throughput = Vec::with_capacity(options.transfer_sizes.len() * 2);
+    for bytes in options.transfer_sizes.iter().map(|x| frame_count_for(*x) * BUFF_SIZE) {
+        for direction in [ProbeDirection::Upload, ProbeDirection::Download] {
+            let sample = match connection.transfer(direction, bytes) {
+                Ok(elapsed) => {
+                    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+                    ThroughputSample { direction, bytes, seconds, mbps: bytes as f64 * 8.0 / seconds / 1_000_000.0, error: None }
+                },
+                Err(e) => {
+                    connection = ProbeConnection::open(options)?;
+                    ThroughputSample { direction, bytes, seconds: 0.0, mbps: 0.0, error: Some(e) }
+                }
+            };
+            throughput.push(sample);
+        }
+    }
+
+    let mut result = ProbeResult {
+        address: options.address.clone(),
+        latency: LatencySummary::from_samples(&samples, lost),
+        frame_sizes,
+        throughput,
+        capabilities: connection.capabilities.clone(),
+        recorded: false
+    };
+
+    if options.record {
+        let report = serde_json::to_value(&result).map_err(|e| CliError::new(ExitCode::General, e.to_string()))?;
+        match connection.request(diagnostics_message(&report)).ok().and_then(extract_ack_message) {
+            Some((HttpCodes::Ok, _)) => result.recorded = true,
+            Some((code, message)) => return Err(CliError::from_status(&code, format!("the server did not record the results because '{message}'"))),
+            None => return Err(CliError::network(String::from("the server did not answer the diagnostics report")))
+        }
+    }
+
+    connection.close();
+    Ok(result)
+}
+
+pub fn print_probe(result: &ProbeResult) {
+    let latency = &result.latency;
+    println!("probe of {} (capabilities: {})", &result.address, &result.capabilities);
+    println!("  rtt: {} samples, {} lost, min {:.2} ms, avg {:.2} ms, max {:.2} ms", latency.samples, latency.lost, latency.min_ms, latency.avg_ms, latency.max_ms);
+    for check in &result.frame_sizes {
+        match (check.ok, check.rtt_ms, check.error.as_ref()) {
+            (true, Some(rtt), _) => println!("  frame +{} bytes: ok ({rtt:.2} ms)", check.padding),
+            (_, _, error) => println!("  frame +{} bytes: FAILED ({})", check.padding, error.map(|x| x.as_str()).unwrap_or("no response"))
+        }
+    }
+    for sample in &result.throughput {
+        match sample.error.as_ref() {
+            None => println!("  {} {} bytes: {:.2} Mbit/s ({:.3} s)", sample.direction, sample.bytes, sample.mbps, sample.seconds),
+            Some(e) => println!("  {} {} bytes: FAILED ({e})", sample.direction, sample.bytes)
+        }
+    }
+    if result.recorded {
+        println!("  results recorded on the server");
+    }
+}
+
+#[test]
+fn test_probe_summaries() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64K"), Some(64 * 1024));
+    assert_eq!(parse_size("16m"), Some(16 * 1024 * 1024));
+    assert_eq!(parse_size("0"), None);
+    assert_eq!(parse_size("lots"), None);
+    assert_eq!(parse_size_list("64K,1M"), Some(vec![64 * 1024, 1024 * 1024]));
+    assert_eq!(parse_size_list("64K,x"), None);
+
+    let latency = LatencySummary::from_samples(&[Duration::from_millis(10), Duration::from_millis(30)], 1);
+    assert_eq!((latency.samples, latency.lost), (2, 1));
+    assert!((latency.min_ms - 10.0).abs() < 1e-9 && (latency.avg_ms - 20.0).abs() < 1e-9 && (latency.max_ms - 30.0).abs() < 1e-9);
+    assert_eq!(LatencySummary::from_samples(&[], 3), LatencySummary { lost: 3, ..Default::default() });
+
+    let check = |padding, ok| FrameSizeCheck { padding, ok, rtt_ms: None, error: None };
+    let result = ProbeResult {
+        address: String::from("localhost:9090"),
+        latency,
+        frame_sizes: vec![check(512, true), check(1400, true), check(9000, false)],
+        throughput: Vec::new(),
+        capabilities: [Capability::Diagnostics].into_iter().collect(),
+        recorded: false
+    };
+    assert_eq!(result.largest_working_frame(), Some(1400));
+
+    // The report the server records reads back as the same result
+    let report = serde_json::to_value(&result).unwrap();
+    assert_eq!(serde_json::from_value::<ProbeResult>(report).unwrap(), result);
+}
@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+
+use hermes_common::messages::QuicOffer;
+
+// File frames go over QUIC when the client is built with the quic feature and the server offers it. Otherwise everything stays on the TCP connection.
+#[cfg(feature = "quic")]
+pub use hermes_common::quic::{BlockingQuic as QuicPlane, BlockingQuicStream as QuicStream};
+
+#[cfg(feature = "quic")]
+pub const QUIC_BUILT: bool = true;
+#[cfg(not(feature = "quic"))]
+pub const QUIC_BUILT: bool = false;
+
+// Connects to the port the server offered, on the host the TCP connection reached. A failure only leaves transfers on TCP.
+pub fn connect(peer: SocketAddr, offer: &QuicOffer, session: &str) -> Option<QuicPlane> {
+    match QuicPlane::connect(SocketAddr::new(peer.ip(), offer.port), &offer.fingerprint, session) {
+        Ok(q) => Some(q),
+        Err(e) => {
+            tracing::info!("transfers stay on TCP, since QUIC could not connect: {e}");
+            None
+        }
+    }
+}
+
+// Never made, since connecting always fails without the feature
+#[cfg(not(feature = "quic"))]
+pub enum QuicPlane { }
+#[cfg(not(feature = "quic"))]
+pub enum QuicStream { }
+
+#[cfg(not(feature = "quic"))]
+impl QuicPlane {
+    pub fn connect(_addr: SocketAddr, _fingerprint: &str, _session: &str) -> Result<Self, String> {
+        Err(String::from("this client was built without QUIC support"))
+    }
+    pub fn open(&self) -> Result<QuicStream, String> {
+        match *self { }
+    }
+    pub fn accept(&self) -> Result<QuicStream, String> {
+        match *self { }
+    }
+}
+#[cfg(not(feature = "quic"))]
+impl QuicStream {
+    pub fn finish(&mut self) -> Result<(), String> {
+        match *self { }
+    }
+    pub fn abandon(&mut self) {
+        match *self { }
+    }
+}
+#[cfg(not(feature = "quic"))]
+impl std::io::Read for QuicStream {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self { }
+    }
+}
+#[cfg(not(feature = "quic"))]
+impl std::io::Write for QuicStream {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        match *self { }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self { }
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+use rand::Rng;
+
+// How many times, and how patiently, a request that broke off on the network is tried again on a new connection
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, //Counting the first, so 1 never retries
+    pub base_delay: Duration,
+    pub max_delay: Duration
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(8)
+        }
+    }
+}
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+    // HERMES_RETRIES sets how many attempts are made in all, and 1 turns retrying off
+    pub fn from_env() -> Self {
+        match std::env::var("HERMES_RETRIES").ok().and_then(|x| x.parse::<u32>().ok()) {
+            Some(n) => Self { max_attempts: n.max(1), ..Default::default() },
+            None => Self::default()
+        }
+    }
+
+    // The wait before the given retry, counting from 1. It doubles each time up to max_delay,
+    // and up to half of it is taken off at random so clients that lost the server together do not all come back at once.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self.base_delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(self.max_delay);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[test]
+fn test_retry_delays() {
+    let policy = RetryPolicy::default();
+    for retry in 1..=10 {
+        let ceiling = (policy.base_delay * 2u32.pow(retry - 1)).min(policy.max_delay);
+        let delay = policy.delay(retry);
+        assert!(delay <= ceiling && delay >= ceiling / 2, "retry {retry} waited {delay:?}");
+    }
+
+    // Far past the cap, the doubling does not overflow
+    assert!(policy.delay(u32::MAX) <= policy.max_delay);
+    assert_eq!(RetryPolicy::none().max_attempts, 1);
+}
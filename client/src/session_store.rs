@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hermes_common::session::ResumeToken;
+
+// Everything the client keeps between runs lives in ~/.hermes
+pub fn hermes_directory() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".hermes"))
+}
+
+// Resumption tokens are kept per server, so unattended clients can reconnect after a restart without storing the password.
+pub fn default_session_path() -> Option<PathBuf> {
+    Some(hermes_directory()?.join("sessions.json"))
+}
+
+pub struct SessionStore {
+    path: PathBuf,
+    tokens: HashMap<String, ResumeToken>
+}
+impl SessionStore {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let tokens = match fs::read_to_string(path) {
+            Ok(contents) if !contents.trim().is_empty() => {
+                match serde_json::from_str(&contents) {
+                    Ok(t) => t,
+                    Err(e) => return Err(format!("could not parse session store because '{e}'"))
+                }
+            },
+            Ok(_) => HashMap::new(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.to_string())
+        };
+
+        Ok(
+            Self {
+                path: path.to_path_buf(),
+                tokens
+            }
+        )
+    }
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let contents = serde_json::to_string(&self.tokens).map_err(|e| e.to_string())?;
+        fs::write(&self.path, contents).map_err(|e| e.to_string())?;
+
+        // The token is as good as a password until it expires, so keep it private to the user
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600)).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, server: &str) -> Option<&ResumeToken> {
+        self.tokens.get(server).filter(|x| !x.is_expired())
+    }
+    pub fn insert(&mut self, server: &str, token: ResumeToken) {
+        self.tokens.insert(server.to_string(), token);
+    }
+    pub fn remove(&mut self, server: &str) -> Option<ResumeToken> {
+        self.tokens.remove(server)
+    }
+}
@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::connection::{Connection, RequestError};
+use hermes_common::file_io::DirectoryContent;
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::progress::Progress;
+
+// Speaks version 3 of the SFTP protocol, the one OpenSSH uses, over a pair of streams, and carries it out on a Hermes connection.
+// This is what sftp-server does beneath sshd, so 'sftp -D' or an sshd Subsystem line can put any SFTP tool in front of a Hermes server.
+// Hermes moves whole files, so an opened file is downloaded into a staging file, read and written there, and uploaded when it is closed.
+const SFTP_VERSION: u32 = 3;
+// Larger than anything OpenSSH sends, which keeps a corrupt length from allocating without bound
+const MAX_PACKET: usize = 1024 * 1024;
+// The most a single read answers with. Clients ask again for the rest.
+const MAX_READ: u32 = 64 * 1024;
+const DIR_BATCH: usize = 100;
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_LSTAT: u8 = 7;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_RMDIR: u8 = 15;
+const FXP_REALPATH: u8 = 16;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_FAILURE: u32 = 4;
+const FX_BAD_MESSAGE: u32 = 5;
+const FX_CONNECTION_LOST: u32 = 7;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+const FXF_WRITE: u32 = 0x02;
+const FXF_APPEND: u32 = 0x04;
+const FXF_CREAT: u32 = 0x08;
+const FXF_TRUNC: u32 = 0x10;
+const FXF_EXCL: u32 = 0x20;
+
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const ATTR_ACMODTIME: u32 = 0x08;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// Reads the fields of one packet, in order
+pub struct PacketReader<'a> {
+    data: &'a [u8],
+    at: usize
+}
+impl<'a> PacketReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, at: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let end = self.at.checked_add(count).filter(|x| *x <= self.data.len())?;
+        let taken = &self.data[self.at..end];
+        self.at = end;
+        Some(taken)
+    }
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+    pub fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+    }
+    pub fn bytes(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+    pub fn string(&mut self) -> Option<String> {
+        self.bytes().map(|x| String::from_utf8_lossy(x).into_owned())
+    }
+}
+
+// Builds one packet, which is sent with its length in front
+pub struct PacketWriter {
+    data: Vec<u8>
+}
+impl PacketWriter {
+    pub fn new(kind: u8) -> Self {
+        Self { data: vec![kind] }
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+    pub fn u64(mut self, value: u64) -> Self {
+        self.data.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        self = self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+        self
+    }
+    pub fn string(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+    pub fn attributes(mut self, attributes: &Attributes) -> Self {
+        self = self.u32(attributes.flags());
+        if let Some(s) = attributes.size {
+            self = self.u64(s);
+        }
+        if let Some(p) = attributes.permissions {
+            self = self.u32(p);
+        }
+        if let Some(m) = attributes.modified {
+            self = self.u32(m as u32).u32(m as u32);
+        }
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut packet = (self.data.len() as u32).to_be_bytes().to_vec();
+        packet.extend(self.data);
+        packet
+    }
+}
+
+// What the bridge can say about a file or folder. Hermes has no owners SFTP would understand, so none are sent.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Attributes {
+    pub size: Option<u64>,
+    pub permissions: Option<u32>, //With the file type bits, as stat gives them
+    pub modified: Option<u64>
+}
+impl Attributes {
+    pub fn directory(modified: Option<u64>) -> Self {
+        Self {
+            size: None,
+            permissions: Some(S_IFDIR | 0o755),
+            modified
+        }
+    }
+    pub fn file(size: u64, permissions: Option<u32>, modified: Option<u64>) -> Self {
+        Self {
+            size: Some(size),
+            permissions: Some(S_IFREG | permissions.unwrap_or(0o644)),
+            modified
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= ATTR_SIZE;
+        }
+        if self.permissions.is_some() {
+            flags |= ATTR_PERMISSIONS;
+        }
+        if self.modified.is_some() {
+            flags |= ATTR_ACMODTIME;
+        }
+        flags
+    }
+    pub fn is_directory(&self) -> bool {
+        self.permissions.is_some_and(|x| x & S_IFDIR != 0)
+    }
+}
+
+// Resolves a path from the client against the top of the bridge, which is the folder the login starts in. Nothing can climb above it.
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            },
+            p => parts.push(p)
+        }
+    }
+
+    format!("/{}", parts.join("/"))
+}
+// The same path as the server takes it, relative to the folder the login started in
+fn remote(path: &str) -> String {
+    normalize(path).trim_start_matches('/').to_string()
+}
+fn split_parent(path: &str) -> (String, String) {
+    let path = remote(path);
+    match path.rsplit_once('/') {
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (String::new(), path)
+    }
+}
+
+// The line 'ls -l' prints, which SFTP clients show as it is
+pub fn long_name(name: &str, attributes: &Attributes) -> String {
+    let mode = attributes.permissions.unwrap_or(0);
+    let mut text = String::from(if attributes.is_directory() { "d" } else { "-" });
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 2 != 0 { 'w' } else { '-' });
+        text.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+
+    let modified = match attributes.modified {
+        Some(m) => {
+            let (_, month, day) = civil_date(m / 86400);
+            format!("{} {day:>2} {:02}:{:02}", MONTHS[month as usize - 1], m % 86400 / 3600, m % 3600 / 60)
+        },
+        None => String::from("Jan  1 00:00")
+    };
+    format!("{text}    1 hermes   hermes   {:>8} {modified} {name}", attributes.size.unwrap_or(0))
+}
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+// The year, month, and day a count of days since 1970 falls on
+fn civil_date(days: u64) -> (i64, u32, u32) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Why a request failed, in the terms SFTP has for it
+struct Failure(u32, String);
+impl From<RequestError> for Failure {
+    fn from(value: RequestError) -> Self {
+        let code = match &value {
+            RequestError::Refused(HttpCodes::NotFound, _) => FX_NO_SUCH_FILE,
+            RequestError::Refused(HttpCodes::Unauthorized | HttpCodes::Forbidden, _) => FX_PERMISSION_DENIED,
+            RequestError::Refused(..) | RequestError::Cancelled | RequestError::Local(_) => FX_FAILURE,
+            RequestError::Failed(_) => FX_CONNECTION_LOST
+        };
+        Self(code, value.to_string())
+    }
+}
+impl From<std::io::Error> for Failure {
+    fn from(value: std::io::Error) -> Self {
+        Self(FX_FAILURE, value.to_string())
+    }
+}
+fn failure(code: u32, message: &str) -> Failure {
+    Failure(code, message.to_string())
+}
+
+// A file held in the staging folder while it is open. Writes are uploaded when it is closed.
+struct OpenFile {
+    file: File,
+    staged: PathBuf,
+    remote: String,
+    writable: bool,
+    append: bool,
+    changed: bool //Whether the server's copy is out of date, and has to be uploaded on close
+}
+enum Handle {
+    File(OpenFile),
+    Dir(Vec<(String, Attributes)>) //What has not been read yet
+}
+
+pub struct SftpBridge {
+    connection: Connection,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    staging: PathBuf
+}
+impl SftpBridge {
+    pub fn new(connection: Connection) -> Result<Self, String> {
+        let staging = std::env::temp_dir().join(format!("hermes-sftp-{}", std::process::id()));
+        std::fs::create_dir_all(&staging).map_err(|e| format!("unable to make the staging folder '{}' because '{e}'", staging.display()))?;
+        Ok(
+            Self {
+                connection,
+                handles: HashMap::new(),
+                next_handle: 0,
+                staging
+            }
+        )
+    }
+
+    // Answers packets until the client hangs up. Files still open then are closed, so their writes are not lost.
+    pub fn run(mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), String> {
+        let result = self.serve(input, output);
+        let open: Vec<u32> = self.handles.keys().copied().collect();
+        for handle in open {
+            if let Err(Failure(_, e)) = self.close(handle) {
+                tracing::warn!("an upload was lost when the SFTP client left: {e}");
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.staging);
+        self.connection.close();
+        result
+    }
+    fn serve(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), String> {
+        while let Some(packet) = read_packet(input)? {
+            let mut reader = PacketReader::new(&packet);
+            let reply = match reader.u8() {
+                Some(FXP_INIT) => PacketWriter::new(FXP_VERSION).u32(SFTP_VERSION).finish(),
+                Some(kind) => {
+                    let id = reader.u32().ok_or_else(|| String::from("a request arrived without its id"))?;
+                    match self.answer(kind, id, &mut reader) {
+                        Ok(reply) => reply,
+                        Err(Failure(code, message)) => status(id, code, &message)
+                    }
+                },
+                None => return Err(String::from("an empty packet arrived"))
+            };
+            output.write_all(&reply).and_then(|_| output.flush()).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn answer(&mut self, kind: u8, id: u32, reader: &mut PacketReader) -> Result<Vec<u8>, Failure> {
+        let malformed = || failure(FX_BAD_MESSAGE, "the request is malformed");
+        match kind {
+            FXP_OPEN => {
+                let path = reader.string().ok_or_else(malformed)?;
+                let flags = reader.u32().ok_or_else(malformed)?;
+                let handle = self.open(&path, flags)?;
+                Ok(self.handle(id, Handle::File(handle)))
+            },
+            FXP_CLOSE => {
+                let handle = handle_id(reader).ok_or_else(malformed)?;
+                self.close(handle).map(|_| status(id, FX_OK, ""))
+            },
+            FXP_READ => {
+                let handle = handle_id(reader).ok_or_else(malformed)?;
+                let (offset, length) = reader.u64().zip(reader.u32()).ok_or_else(malformed)?;
+                let data = self.read(handle, offset, length.min(MAX_READ))?;
+                match data.is_empty() {
+                    true => Ok(status(id, FX_EOF, "end of file")),
+                    false => Ok(PacketWriter::new(FXP_DATA).u32(id).bytes(&data).finish())
+                }
+            },
+            FXP_WRITE => {
+                let handle = handle_id(reader).ok_or_else(malformed)?;
+                let (offset, data) = reader.u64().zip(reader.bytes()).ok_or_else(malformed)?;
+                self.write(handle, offset, data).map(|_| status(id, FX_OK, ""))
+            },
+            FXP_STAT | FXP_LSTAT => {
+                let path = reader.string().ok_or_else(malformed)?;
+                let attributes = self.stat(&path)?;
+                Ok(PacketWriter::new(FXP_ATTRS).u32(id).attributes(&attributes).finish())
+            },
+            FXP_FSTAT => {
+                let handle = handle_id(reader).ok_or_else(malformed)?;
+                let attributes = match self.handles.get(&handle) {
+                    Some(Handle::File(f)) => Attributes::file(f.file.metadata()?.len(), None, None),
+                    Some(Handle::Dir(_)) => Attributes::directory(None),
+                    None => return Err(failure(FX_FAILURE, "no such handle"))
+                };
+                Ok(PacketWriter::new(FXP_ATTRS).u32(id).attributes(&attributes).finish())
+            },
+            // Hermes keeps its own times and modes, so there is nothing to change. Refusing would fail 'put -p' outright.
+            FXP_SETSTAT | FXP_FSETSTAT => Ok(status(id, FX_OK, "")),
+            FXP_OPENDIR => {
+                let path = reader.string().ok_or_else(malformed)?;
+                let entries = self.list(&path)?;
+                Ok(self.handle(id, Handle::Dir(entries)))
+            },
+            FXP_READDIR => {
+                let handle = handle_id(reader).ok_or_else(malformed)?;
+                let entries = match self.handles.get_mut(&handle) {
+                    Some(Handle::Dir(d)) => d.drain(..d.len().min(DIR_BATCH)).collect::<Vec<_>>(),
+                    _ => return Err(failure(FX_FAILURE, "no such folder handle"))
+                };
+                match entries.is_empty() {
+                    true => Ok(status(id, FX_EOF, "end of folder")),
+                    false => Ok(names(id, &entries))
+                }
+            },
+            FXP_REMOVE => {
+                let path = reader.string().ok_or_else(malformed)?;
+                self.connection.delete(&remote(&path), false)?;
+                Ok(status(id, FX_OK, ""))
+            },
+            FXP_MKDIR => {
+                let path = reader.string().ok_or_else(malformed)?;
+                self.connection.make_dir(&remote(&path))?;
+                Ok(status(id, FX_OK, ""))
+            },
+            // Only an empty folder is removed, as rmdir would
+            FXP_RMDIR => {
+                let path = reader.string().ok_or_else(malformed)?;
+                if !self.connection.list(&remote(&path))?.contents().is_empty() {
+                    return Err(failure(FX_FAILURE, "the folder is not empty"));
+                }
+                self.connection.delete(&remote(&path), true)?;
+                Ok(status(id, FX_OK, ""))
+            },
+            FXP_REALPATH => {
+                let path = normalize(&reader.string().ok_or_else(malformed)?);
+                Ok(PacketWriter::new(FXP_NAME).u32(id).u32(1).string(&path).string(&path).attributes(&Attributes::default()).finish())
+            },
+            FXP_RENAME => {
+                let (source, destination) = reader.string().zip(reader.string()).ok_or_else(malformed)?;
+                self.connection.rename(&remote(&source), &remote(&destination))?;
+                Ok(status(id, FX_OK, ""))
+            },
+            _ => Err(failure(FX_OP_UNSUPPORTED, "the bridge does not support this request"))
+        }
+    }
+
+    fn handle(&mut self, id: u32, handle: Handle) -> Vec<u8> {
+        let key = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(key, handle);
+        PacketWriter::new(FXP_HANDLE).u32(id).bytes(&key.to_be_bytes()).finish()
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Attributes, Failure> {
+        let (parent, name) = split_parent(path);
+        if name.is_empty() {
+            return Ok(Attributes::directory(None));
+        }
+
+        self.list(&parent)?.into_iter().find(|(n, _)| *n == name).map(|(_, a)| a).ok_or_else(|| failure(FX_NO_SUCH_FILE, "no such file"))
+    }
+    fn list(&mut self, path: &str) -> Result<Vec<(String, Attributes)>, Failure> {
+        let listing = self.connection.list(&remote(path))?;
+        Ok(
+            // Links cannot be read through here, so they are left out
+            listing.contents().iter().filter_map(|x| match x {
+                DirectoryContent::File(f) => Some((f.name().to_string(), Attributes::file(f.size(), f.permissions().map(|x| x & 0o777), f.modified()))),
+                DirectoryContent::Dir(d) => Some((d.name().to_string(), Attributes::directory(None))),
+                DirectoryContent::Symlink(_) => None
+            }).collect()
+        )
+    }
+
+    // The server's copy is fetched unless the file is truncated or made new, so reads and partial writes see what is there
+    fn open(&mut self, path: &str, flags: u32) -> Result<OpenFile, Failure> {
+        let target = remote(path);
+        let staged = self.staging.join(format!("{}", self.next_handle));
+        let writable = flags & (FXF_WRITE | FXF_APPEND) != 0;
+        let exclusive = writable && flags & FXF_EXCL != 0;
+        if exclusive && self.stat(path).is_ok() {
+            return Err(failure(FX_FAILURE, "the file already exists"));
+        }
+
+        let fetched = match exclusive || (writable && flags & FXF_TRUNC != 0) {
+            true => false,
+            false => match self.connection.download(&target, &staged, &mut Progress::none()) {
+                Ok(_) => true,
+                Err(RequestError::Refused(HttpCodes::NotFound, _)) if writable && flags & FXF_CREAT != 0 => false,
+                Err(e) => return Err(e.into())
+            }
+        };
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(!fetched).open(&staged)?;
+        Ok(
+            OpenFile {
+                file,
+                staged,
+                remote: target,
+                writable,
+                append: flags & FXF_APPEND != 0,
+                changed: writable && !fetched
+            }
+        )
+    }
+    fn open_file(&mut self, handle: u32) -> Result<&mut OpenFile, Failure> {
+        match self.handles.get_mut(&handle) {
+            Some(Handle::File(f)) => Ok(f),
+            _ => Err(failure(FX_FAILURE, "no such file handle"))
+        }
+    }
+    fn read(&mut self, handle: u32, offset: u64, length: u32) -> Result<Vec<u8>, Failure> {
+        let file = &mut self.open_file(handle)?.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(length as usize);
+        file.take(length as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+    fn write(&mut self, handle: u32, offset: u64, data: &[u8]) -> Result<(), Failure> {
+        let open = self.open_file(handle)?;
+        if !open.writable {
+            return Err(failure(FX_PERMISSION_DENIED, "the file was opened for reading"));
+        }
+        match open.append {
+            true => open.file.seek(SeekFrom::End(0))?,
+            false => open.file.seek(SeekFrom::Start(offset))?
+        };
+        open.file.write_all(data)?;
+        open.changed = true;
+        Ok(())
+    }
+    fn close(&mut self, handle: u32) -> Result<(), Failure> {
+        let open = match self.handles.remove(&handle) {
+            Some(Handle::File(f)) => f,
+            Some(Handle::Dir(_)) => return Ok(()),
+            None => return Err(failure(FX_FAILURE, "no such handle"))
+        };
+
+        drop(open.file);
+        let uploaded = match open.changed {
+            true => self.connection.upload(&open.remote, &open.staged, &mut Progress::none()).map(|_| ()),
+            false => Ok(())
+        };
+        let _ = std::fs::remove_file(&open.staged);
+        Ok(uploaded?)
+    }
+}
+
+// None once the client has hung up between packets
+fn read_packet(input: &mut impl Read) -> Result<Option<Vec<u8>>, String> {
+    let mut length = [0u8; 4];
+    match input.read_exact(&mut length) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string())
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_PACKET {
+        return Err(format!("a packet of {length} bytes is larger than the bridge accepts"));
+    }
+    let mut packet = vec![0u8; length];
+    input.read_exact(&mut packet).map_err(|e| e.to_string())?;
+    Ok(Some(packet))
+}
+fn handle_id(reader: &mut PacketReader) -> Option<u32> {
+    reader.bytes().and_then(|x| x.try_into().ok()).map(u32::from_be_bytes)
+}
+fn status(id: u32, code: u32, message: &str) -> Vec<u8> {
+    PacketWriter::new(FXP_STATUS).u32(id).u32(code).string(message).string("en").finish()
+}
+fn names(id: u32, entries: &[(String, Attributes)]) -> Vec<u8> {
+    let mut packet = PacketWriter::new(FXP_NAME).u32(id).u32(entries.len() as u32);
+    for (name, attributes) in entries {
+        packet = packet.string(name).string(&long_name(name, attributes)).attributes(attributes);
+    }
+    packet.finish()
+}
+
+#[test]
+fn test_sftp_packets() {
+    assert_eq!(normalize("docs/./a.txt"), "/docs/a.txt");
+    assert_eq!(normalize("/../../etc/passwd"), "/etc/passwd");
+    assert_eq!(normalize("."), "/");
+    assert_eq!(remote("/docs/"), "docs");
+    assert_eq!(split_parent("/docs/a.txt"), (String::from("docs"), String::from("a.txt")));
+    assert_eq!(split_parent("a.txt"), (String::new(), String::from("a.txt")));
+
+    // A status, read back the way a client would
+    let packet = status(7, FX_NO_SUCH_FILE, "no such file");
+    let mut reader = PacketReader::new(&packet);
+    assert_eq!(reader.u32(), Some(packet.len() as u32 - 4));
+    assert_eq!((reader.u8(), reader.u32(), reader.u32()), (Some(FXP_STATUS), Some(7), Some(FX_NO_SUCH_FILE)));
+    assert_eq!((reader.string(), reader.string(), reader.u8()), (Some(String::from("no such file")), Some(String::from("en")), None));
+
+    // Attributes carry only the fields they have, with the access and modify times together
+    let file = Attributes::file(1234, Some(0o600), Some(1792192354));
+    let packet = PacketWriter::new(FXP_ATTRS).u32(1).attributes(&file).finish();
+    let mut reader = PacketReader::new(&packet[9..]);
+    assert_eq!(reader.u32(), Some(ATTR_SIZE | ATTR_PERMISSIONS | ATTR_ACMODTIME));
+    assert_eq!((reader.u64(), reader.u32(), reader.u32(), reader.u32()), (Some(1234), Some(S_IFREG | 0o600), Some(1792192354), Some(1792192354)));
+    assert_eq!(reader.u8(), None);
+
+    assert_eq!(long_name("a.txt", &file), "-rw-------    1 hermes   hermes       1234 Oct 16 23:12 a.txt");
+    assert_eq!(long_name("docs", &Attributes::directory(Some(951782400))), "drwxr-xr-x    1 hermes   hermes          0 Feb 29 00:00 docs");
+
+    // A truncated packet is malformed rather than read past its end
+    assert_eq!(PacketReader::new(&[0, 0, 0, 9, b'a']).string(), None);
+    assert_eq!(read_packet(&mut &[0u8, 0, 0][..]), Ok(None));
+}
@@ -0,0 +1,939 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::connection::{ConnectOptions, Connection, relative_to};
+use crate::encryption::Vault;
+use crate::exit_codes::{CliError, ExitCode};
+use crate::keychain::{forget_password, profile, save_password, saved_password};
+use crate::offline_queue::{default_queue_path, OfflineQueue, QueuedAction};
+use crate::session_store::hermes_directory;
+use hermes_common::file_io::{DirectoryContent, DirectoryInfo, FileType};
+use hermes_common::messages::UsageReport;
+use hermes_common::progress::{CancelToken, Progress, ProgressUpdate};
+
+// How many lines or bytes 'preview' shows when not told
+const DEFAULT_PREVIEW_LIMIT: u64 = 10;
+// How many levels of folders 'tree' shows beneath the one it lists
+const TREE_DEPTH: u32 = 3;
+
+// Each command with its arguments, and what it does, in the order 'help' lists them
+const COMMANDS: [(&str, &str, &str); 18] = [
+    ("connect", "connect <address> <username>", "log in to a server, with the password saved for it or else asking for one"),
+    ("login", "login <address> <username> [--save]", "log in like connect, with --save asking for the password and keeping it in the system keychain"),
+    ("logout", "logout [<address> <username>]", "forget the password saved for this login, or the one given, and disconnect"),
+    ("ls", "ls [-a] [path]", "list a remote folder, the current one by default, with -a showing names starting with '.'"),
+    ("tree", "tree [path]", "list a remote folder and the folders beneath it, three levels deep"),
+    ("cd", "cd <path>", "change the current remote folder"),
+    ("pwd", "pwd", "show the current remote folder"),
+    ("get", "get <remote> [local]", "download a file, into the local folder by default"),
+    ("mget", "mget <remote>...", "download several files at once into the local folder"),
+    ("preview", "preview <remote> [count]", "show the first lines of a text file, or the first bytes of any other, 10 by default"),
+    ("put", "put <local> [remote]", "upload a file, into the current remote folder by default"),
+    ("rm", "rm [-r] <path>", "delete a file, or with -r a folder and everything in it"),
+    ("mv", "mv <source> <destination>", "rename a file or folder, or move it into an existing folder"),
+    ("mkdir", "mkdir <path>", "make a remote folder"),
+    ("stats", "stats", "show the last transfer the server recorded from this address"),
+    ("df", "df", "show how full the server's storage is, and what each folder holds"),
+    ("help", "help", "show this list"),
+    ("exit", "exit", "close the connection and leave the shell")
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ShellCommand {
+    Connect {
+        address: String,
+        username: String,
+        save: bool //Whether the password is kept in the keychain once it has logged in
+    },
+    Logout(Option<(String, String)>), //The address and username, or the current login
+    Ls { path: Option<String>, all: bool },
+    Tree(Option<String>),
+    Cd(String),
+    Pwd,
+    Get {
+        remote: String,
+        local: Option<PathBuf>
+    },
+    Mget(Vec<String>),
+    Preview {
+        remote: String,
+        limit: u64
+    },
+    Put {
+        local: PathBuf,
+        remote: Option<String>
+    },
+    Rm {
+        path: String,
+        recursive: bool
+    },
+    Mv {
+        source: String,
+        destination: String
+    },
+    Mkdir(String),
+    Stats,
+    Df,
+    Help,
+    Exit
+}
+impl ShellCommand {
+    // Blank lines, and comments starting with '#', are no command at all
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let words: Vec<String> = split_words(line)?.into_iter().map(|x| x.text).collect();
+        match words.first() {
+            None => Ok(None),
+            Some(w) if w.starts_with('#') => Ok(None),
+            Some(_) => Self::from_words(&words).map(Some)
+        }
+    }
+
+    pub fn from_words(words: &[String]) -> Result<Self, String> {
+        let (name, args) = match words.split_first() {
+            Some((n, a)) => (n.as_str(), a),
+            None => return Err(String::from("no command given"))
+        };
+
+        let command = match (name, args) {
+            ("connect" | "login", [address, username]) => Self::Connect { address: address.clone(), username: username.clone(), save: false },
+            ("login", [address, username, flag]) if flag == "--save" => Self::Connect { address: address.clone(), username: username.clone(), save: true },
+            ("logout", []) => Self::Logout(None),
+            ("logout", [address, username]) => Self::Logout(Some((address.clone(), username.clone()))),
+            ("ls", []) => Self::Ls { path: None, all: false },
+            ("ls", [flag]) if flag == "-a" => Self::Ls { path: None, all: true },
+            ("ls", [path]) => Self::Ls { path: Some(path.clone()), all: false },
+            ("ls", [flag, path]) if flag == "-a" => Self::Ls { path: Some(path.clone()), all: true },
+            ("tree", []) => Self::Tree(None),
+            ("tree", [path]) => Self::Tree(Some(path.clone())),
+            ("cd", [path]) => Self::Cd(path.clone()),
+            ("pwd", []) => Self::Pwd,
+            ("get", [remote]) => Self::Get { remote: remote.clone(), local: None },
+            ("get", [remote, local]) => Self::Get { remote: remote.clone(), local: Some(local.into()) },
+            ("mget", remotes) if !remotes.is_empty() => Self::Mget(remotes.to_vec()),
+            ("preview", [remote]) => Self::Preview { remote: remote.clone(), limit: DEFAULT_PREVIEW_LIMIT },
+            ("preview", [remote, limit]) => Self::Preview { remote: remote.clone(), limit: limit.parse().map_err(|_| format!("'{limit}' is not a count"))? },
+            ("put", [local]) => Self::Put { local: local.into(), remote: None },
+            ("put", [local, remote]) => Self::Put { local: local.into(), remote: Some(remote.clone()) },
+            ("rm", [path]) => Self::Rm { path: path.clone(), recursive: false },
+            ("rm", [flag, path]) if flag == "-r" => Self::Rm { path: path.clone(), recursive: true },
+            ("mv", [source, destination]) => Self::Mv { source: source.clone(), destination: destination.clone() },
+            ("mkdir", [path]) => Self::Mkdir(path.clone()),
+            ("stats", []) => Self::Stats,
+            ("df", []) => Self::Df,
+            ("help", []) => Self::Help,
+            ("exit" | "quit", []) => Self::Exit,
+            (name, _) => return match COMMANDS.iter().find(|x| x.0 == name) {
+                Some((_, usage, _)) => Err(format!("usage: {usage}")),
+                None => Err(format!("unknown command '{name}', try 'help'"))
+            }
+        };
+
+        Ok(command)
+    }
+}
+
+// One word of a command line, and where it sits in the line
+#[derive(Clone, PartialEq, Debug)]
+struct Word {
+    start: usize,
+    end: usize,
+    text: String
+}
+
+// Splits a line into words. Quotes keep spaces inside a word, and a backslash keeps the character after it as it is.
+fn split_words(line: &str) -> Result<Vec<Word>, String> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        let mut end = i + c.len_utf8();
+        let pushed = match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+                None
+            },
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                None
+            },
+            (None, c) if c.is_whitespace() => {
+                words.extend(current.take());
+                continue;
+            },
+            (None | Some('"'), '\\') => match chars.next() {
+                Some((j, next)) => {
+                    end = j + next.len_utf8();
+                    Some(next)
+                },
+                None => Some('\\')
+            },
+            (_, c) => Some(c)
+        };
+
+        let word = current.get_or_insert(Word { start: i, end, text: String::new() });
+        word.end = end;
+        word.text.extend(pushed);
+    }
+
+    if quote.is_some() {
+        return Err(String::from("unterminated quote"));
+    }
+    words.extend(current);
+    Ok(words)
+}
+// Completed names are written back so that they split into the same one word
+fn escape_word(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_whitespace() || matches!(c, '"' | '\'' | '\\') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+fn file_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+// Kept so a connection the server closed while the shell sat idle can be opened again without asking
+struct Login {
+    address: String,
+    username: String,
+    password: String
+}
+
+// What the shell remembers between commands
+#[derive(Default)]
+pub struct Shell {
+    connection: Option<Connection>,
+    login: Option<Login>,
+    vault: Option<Vault>, //Made from HERMES_PASSPHRASE at each connect, so reconnecting does not stretch it again
+    cwd: String, //As the server shows it, such as '/docs'
+    listings: BTreeMap<String, Vec<(String, bool)>>, //The names in each folder listed since the last change, and whether each is a folder
+    interactive: bool, //Whether commands are typed at the prompt, rather than read from a script
+    options: ConnectOptions, //How each connection is opened, reconnecting included
+    queue: Option<PathBuf>, //Where uploads and deletes wait while the server cannot be reached. None fails them instead.
+    offline: bool //Whether the last attempt to open the connection again could not reach the server
+}
+impl Shell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_options(options: ConnectOptions) -> Self {
+        Self { options, queue: default_queue_path(), ..Self::default() }
+    }
+
+    pub fn prompt(&self) -> String {
+        match &self.login {
+            Some(l) => format!("{}@{}:{}> ", &l.username, &l.address, &self.cwd),
+            None => String::from("hermes> ")
+        }
+    }
+
+    pub fn connect(&mut self, address: &str, username: &str, password: &str) -> Result<(), CliError> {
+        self.close();
+        self.vault = Vault::from_env();
+        let mut connection = Connection::open(address, username, password, &self.options)?;
+        connection.set_vault(self.vault.clone());
+        connection.set_interactive(self.interactive);
+        self.cwd = connection.change_dir(".")?;
+        self.replay_queue(&mut connection, &profile(address, username));
+        self.connection = Some(connection);
+        self.login = Some(Login { address: address.to_string(), username: username.to_string(), password: password.to_string() });
+        self.listings.clear();
+        Ok(())
+    }
+    pub fn close(&mut self) {
+        if let Some(c) = self.connection.take() {
+            c.close();
+        }
+    }
+
+    // The open connection, opened again and returned to the current folder if the server has closed it since the last command
+    fn connection(&mut self) -> Result<&mut Connection, CliError> {
+        let alive = self.connection.as_mut().is_some_and(|x| x.keep_alive().is_ok());
+        if !alive {
+            let login = self.login.as_ref().ok_or_else(|| CliError::usage(String::from("not connected, use 'connect <address> <username>' first")))?;
+            tracing::info!("reconnecting to '{}'", &login.address);
+            let opened = Connection::open(&login.address, &login.username, &login.password, &self.options);
+            self.offline = opened.as_ref().is_err_and(|e| e.kind() == ExitCode::Network);
+            let mut connection = opened?;
+            connection.set_vault(self.vault.clone());
+            connection.set_interactive(self.interactive);
+            let home = connection.change_dir(".")?;
+            let login = profile(&login.address, &login.username);
+            self.replay_queue(&mut connection, &login);
+            self.cwd = connection.change_dir(&relative_to(&home, &self.cwd))?;
+            self.connection = Some(connection);
+        }
+
+        self.connection.as_mut().ok_or_else(|| CliError::network(String::from("the connection was lost")))
+    }
+    fn remote(&self, path: &str) -> String {
+        relative_to(&self.cwd, path)
+    }
+
+    // What a command would change on the server, kept to be done later while the server cannot be reached. Only uploads and deletes are kept.
+    // Remote paths are kept from the top of the server, unless no folder is known yet, when they are taken from where the login starts.
+    pub fn offline_action(&self, command: &ShellCommand) -> Option<QueuedAction> {
+        let absolute = |path: &str| match path.starts_with('/') || self.cwd.is_empty() {
+            true => path.to_string(),
+            false => format!("{}/{path}", self.cwd.trim_end_matches('/'))
+        };
+
+        match command {
+            ShellCommand::Put { local, remote } => Some(QueuedAction::Upload { local_path: std::fs::canonicalize(local).ok()?, remote_path: absolute(&put_target(local, remote.as_deref())) }),
+            ShellCommand::Rm { path, recursive } => Some(QueuedAction::Delete { remote_path: absolute(path), recursive: *recursive }),
+            _ => None
+        }
+    }
+    // Keeps the action for the login when the server could not be reached, and otherwise gives back the error
+    pub fn enqueue(&self, login: &str, action: Option<QueuedAction>, error: CliError) -> Result<(), CliError> {
+        let (path, action) = match (self.queue.as_ref(), action) {
+            (Some(p), Some(a)) if error.kind() == ExitCode::Network => (p, a),
+            _ => return Err(error)
+        };
+
+        let mut queue = OfflineQueue::open(path).map_err(|e| CliError::new(ExitCode::General, e))?;
+        println!("the server could not be reached, so '{action}' is queued until '{login}' connects again");
+        queue.enqueue(login, action);
+        queue.save().map_err(|e| CliError::new(ExitCode::General, e))
+    }
+    // Carries out what the login queued while the server could not be reached. Nothing here fails the connection, since the queue keeps what is left.
+    fn replay_queue(&mut self, connection: &mut Connection, login: &str) {
+        let path = match self.queue.as_ref() {
+            Some(p) => p,
+            None => return
+        };
+        let mut queue = match OfflineQueue::open(path) {
+            Ok(q) if q.operations().iter().any(|x| x.login == login && x.conflict.is_none()) => q,
+            Ok(_) => return,
+            Err(e) => return eprintln!("warning: unable to read the offline queue because {e}")
+        };
+
+        let report = queue.replay(login, connection);
+        if let Err(e) = queue.save() {
+            eprintln!("warning: unable to save the offline queue because '{e}'");
+        }
+        if report.applied > 0 {
+            println!("carried out {} queued operation(s)", report.applied);
+        }
+        for (operation, reason) in &report.refused {
+            eprintln!("the server refused to {} because {reason}", &operation.action);
+        }
+        if report.conflicts > 0 {
+            eprintln!("{} queued operation(s) conflict with changes on the server, see 'queue list'", report.conflicts);
+        }
+        if let Some(e) = report.interrupted {
+            eprintln!("the server was lost again while replaying the queue because {e}");
+        }
+        self.listings.clear();
+    }
+
+    // Runs one command. The password is only asked for by 'connect' and 'login', and not at all when one was saved.
+    pub fn execute(&mut self, command: ShellCommand, password: &mut dyn FnMut() -> Result<String, CliError>) -> Result<(), CliError> {
+        let action = self.offline_action(&command);
+        self.offline = false;
+        let result = self.run(command, password);
+        // A broken connection is opened again by the next command
+        if result.as_ref().is_err_and(|e| e.kind() == ExitCode::Network) {
+            self.connection = None;
+        }
+
+        // A change that could not reach the server at all waits in the queue
+        match (result, self.login.as_ref()) {
+            (Err(e), Some(l)) if self.offline => self.enqueue(&profile(&l.address, &l.username), action, e),
+            (result, _) => result
+        }
+    }
+    fn run(&mut self, command: ShellCommand, password: &mut dyn FnMut() -> Result<String, CliError>) -> Result<(), CliError> {
+        match command {
+            // Saving asks for the password again, so a changed one can be saved over the old
+            ShellCommand::Connect { address, username, save } => {
+                let saved = saved_password(&address, &username).filter(|_| !save);
+                let used = match saved.clone() {
+                    Some(p) => p,
+                    None => password()?
+                };
+                let used = match self.connect(&address, &username, &used) {
+                    Err(e) if saved.is_some() && e.kind() == ExitCode::AuthFailure => {
+                        eprintln!("the password saved for '{}' was refused", profile(&address, &username));
+                        let typed = password()?;
+                        self.connect(&address, &username, &typed)?;
+                        typed
+                    },
+                    result => result.map(|_| used)?
+                };
+
+                if save {
+                    save_password(&address, &username, &used).map_err(|e| CliError::new(ExitCode::General, e))?;
+                    println!("saved the password for '{}'", profile(&address, &username));
+                }
+            },
+            ShellCommand::Logout(named) => {
+                let (address, username) = match (named, self.login.as_ref()) {
+                    (Some(n), _) => n,
+                    (None, Some(l)) => (l.address.clone(), l.username.clone()),
+                    (None, None) => return Err(CliError::usage(String::from("not logged in, use 'logout <address> <username>'")))
+                };
+
+                let forgotten = forget_password(&address, &username).map_err(|e| CliError::new(ExitCode::General, e))?;
+                if self.login.as_ref().is_some_and(|l| l.address == address && l.username == username) {
+                    self.close();
+                    self.login = None;
+                }
+                match forgotten {
+                    true => println!("forgot the password for '{}'", profile(&address, &username)),
+                    false => println!("no password was saved for '{}'", profile(&address, &username))
+                }
+            },
+            ShellCommand::Ls { path, all } => {
+                let path = self.remote(path.as_deref().unwrap_or(""));
+                let listing = match all {
+                    true => self.connection()?.list_all(&path)?,
+                    false => self.connection()?.list(&path)?
+                };
+                for entry in listing.contents() {
+                    match entry {
+                        DirectoryContent::File(f) => println!("{:>12}  {}", f.size(), f.name()),
+                        DirectoryContent::Dir(d) => println!("{:>12}  {}/", "", d.name()),
+                        DirectoryContent::Symlink(l) => println!("{:>12}  {} -> {}", "", l.name(), l.target().unwrap_or("?"))
+                    }
+                }
+                self.remember(&path, listing.contents());
+            },
+            ShellCommand::Tree(path) => {
+                let path = self.remote(path.as_deref().unwrap_or(""));
+                print!("{}", format_tree(&self.connection()?.tree(&path, TREE_DEPTH)?, 0));
+            },
+            ShellCommand::Cd(path) => {
+                let path = self.remote(&path);
+                self.cwd = self.connection()?.change_dir(&path)?;
+                self.listings.clear();
+            },
+            ShellCommand::Pwd => println!("{}", &self.cwd),
+            ShellCommand::Get { remote, local } => {
+                let mut local = local.unwrap_or_else(|| PathBuf::from(file_name(&remote)));
+                if local.is_dir() {
+                    local.push(file_name(&remote));
+                }
+
+                let path = self.remote(&remote);
+                let result = self.connection()?.download(&path, &local, &mut start_transfer(file_name(&remote)));
+                end_transfer();
+                result?;
+                println!("downloaded '{remote}' to '{}'", local.display());
+            },
+            // Every file is tried, and the command fails with the kind of the first failure once they are all done
+            ShellCommand::Mget(remotes) => {
+                let files: Vec<(String, PathBuf)> = remotes.iter().map(|x| (self.remote(x), PathBuf::from(file_name(x)))).collect();
+                let results = self.connection()?.download_many(&files);
+                let mut failed = Vec::new();
+                for (remote, result) in remotes.iter().zip(results) {
+                    match result.map_err(CliError::from) {
+                        Ok(_) => println!("downloaded '{remote}' to '{}'", file_name(remote)),
+                        Err(e) => {
+                            eprintln!("unable to download '{remote}': {}", e.message());
+                            failed.push(e.kind());
+                        }
+                    }
+                }
+                if let Some(kind) = failed.first() {
+                    return Err(CliError::new(*kind, format!("{} of {} downloads failed", failed.len(), remotes.len())));
+                }
+            },
+            ShellCommand::Preview { remote, limit } => {
+                let path = self.remote(&remote);
+                let preview = self.connection()?.preview(&path, limit)?;
+                print!("{}", format_preview(&preview.kind, &preview.content));
+                if preview.truncated {
+                    println!("...");
+                }
+            },
+            ShellCommand::Put { local, remote } => {
+                if !local.is_file() {
+                    return Err(CliError::new(ExitCode::NotFound, format!("'{}' is not a file", local.display())));
+                }
+                let name = local.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let remote = put_target(&local, remote.as_deref());
+
+                // Servers too old to say how full they are are uploaded to without a warning
+                let size = local.metadata().map(|x| x.len()).unwrap_or_default();
+                if let Ok(Some(free)) = self.connection()?.usage_once().map(|x| x.free_bytes) {
+                    if size > free {
+                        eprintln!("warning: '{}' is {}, but the server has only {} free", local.display(), format_bytes(size as f64), format_bytes(free as f64));
+                    }
+                }
+
+                let path = self.remote(&remote);
+                let result = self.connection()?.upload(&path, &local, &mut start_transfer(&name));
+                end_transfer();
+                result?;
+                self.listings.clear();
+                println!("uploaded '{}' to '{remote}'", local.display());
+            },
+            ShellCommand::Rm { path, recursive } => {
+                let path = self.remote(&path);
+                self.connection()?.delete(&path, recursive)?;
+                self.listings.clear();
+            },
+            ShellCommand::Mv { source, destination } => {
+                let (source, destination) = (self.remote(&source), self.remote(&destination));
+                self.connection()?.move_path(&source, &destination)?;
+                self.listings.clear();
+            },
+            ShellCommand::Mkdir(path) => {
+                let path = self.remote(&path);
+                self.connection()?.make_dir(&path)?;
+                self.listings.clear();
+            },
+            ShellCommand::Stats => println!("{}", self.connection()?.stats()?),
+            ShellCommand::Df => print!("{}", format_usage(&self.connection()?.usage()?)),
+            ShellCommand::Help => {
+                for (_, usage, description) in COMMANDS {
+                    println!("{usage:<30}{description}");
+                }
+            },
+            ShellCommand::Exit => self.close()
+        }
+
+        Ok(())
+    }
+
+    fn remember(&mut self, path: &str, contents: &[DirectoryContent]) {
+        let names = contents.iter().map(|x| (x.name().to_string(), x.is_directory())).collect();
+        self.listings.insert(path.trim_end_matches('/').to_string(), names);
+    }
+    // Completes a remote path from the listing of the folder it is in, asking the server for one that has not been listed yet
+    fn complete_remote(&mut self, prefix: &str) -> Vec<Pair> {
+        let (dir, partial) = match prefix.rfind('/') {
+            Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+            None => ("", prefix)
+        };
+        let path = self.remote(dir).trim_end_matches('/').to_string();
+        if !self.listings.contains_key(&path) {
+            match self.connection().ok().map(|x| x.list(&path)) {
+                Some(Ok(listing)) => self.remember(&path, listing.contents()),
+                _ => return Vec::new()
+            }
+        }
+
+        self.listings.get(&path).into_iter().flatten()
+            .filter(|(name, _)| name.starts_with(partial))
+            .map(|(name, is_dir)| {
+                let suffix = if *is_dir { "/" } else { "" };
+                Pair { display: format!("{name}{suffix}"), replacement: format!("{}{suffix}", escape_word(&format!("{dir}{name}"))) }
+            })
+            .collect()
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{value:.0} {}", UNITS[0]),
+        _ => format!("{value:.1} {}", UNITS[unit])
+    }
+}
+// Each entry on its own line, indented two spaces for each level, with each folder's contents below it
+fn format_tree(listing: &DirectoryInfo, level: usize) -> String {
+    let indent = "  ".repeat(level);
+    listing.contents().iter()
+        .map(|entry| match entry {
+            DirectoryContent::File(f) => format!("{indent}{}\n", f.name()),
+            DirectoryContent::Dir(d) => format!("{indent}{}/\n{}", d.name(), format_tree(d, level + 1)),
+            DirectoryContent::Symlink(l) => format!("{indent}{} -> {}\n", l.name(), l.target().unwrap_or("?"))
+        })
+        .collect()
+}
+// The volume first, then what each user stores, then each folder
+fn format_usage(report: &UsageReport) -> String {
+    let mut lines = vec![match (report.total_bytes, report.free_bytes) {
+        (Some(total), Some(free)) => format!("{} free of {}", format_bytes(free as f64), format_bytes(total as f64)),
+        _ => String::from("the server cannot tell how much space is free")
+    }];
+    lines.extend(report.users.iter().map(|(name, bytes)| format!("{:>12}  {name}", format_bytes(*bytes as f64))));
+    lines.extend(report.folders.iter().map(|(name, x)| format!("{:>12}  {name}/ ({} files)", format_bytes(x.bytes as f64), x.files)));
+
+    lines.iter().map(|x| format!("{x}\n")).collect()
+}
+// Text is shown as it is, and anything else as rows of 16 bytes in hex beside their offset
+fn format_preview(kind: &FileType, content: &[u8]) -> String {
+    if *kind == FileType::Text {
+        let text = String::from_utf8_lossy(content);
+        return match text.ends_with('\n') || text.is_empty() {
+            true => text.into_owned(),
+            false => format!("{text}\n")
+        };
+    }
+
+    content.chunks(16).enumerate()
+        .map(|(i, row)| {
+            let bytes: Vec<String> = row.iter().map(|x| format!("{x:02x}")).collect();
+            format!("{:08x}  {}\n", i * 16, bytes.join(" "))
+        })
+        .collect()
+}
+// One line describing a transfer: 'report.pdf [######              ]  30%  1.2 MiB/s  ETA 0:04'
+fn format_progress(label: &str, update: &ProgressUpdate) -> String {
+    const WIDTH: usize = 20;
+    let rate = format!("{}/s", format_bytes(update.rate));
+    match update.fraction() {
+        Some(f) => {
+            let filled = (f * WIDTH as f64) as usize;
+            let eta = update.eta.map(|x| format!("  ETA {}:{:02}", x.as_secs() / 60, x.as_secs() % 60)).unwrap_or_default();
+            format!("{label} [{}{}] {:>3}%  {rate}{eta}", "#".repeat(filled), " ".repeat(WIDTH - filled), (f * 100.0) as u32)
+        },
+        None => format!("{label} {}  {rate}", format_bytes(update.done as f64))
+    }
+}
+// The transfer Ctrl-C stops. Outside of one, or pressed again while the transfer is winding down, Ctrl-C ends the program as usual.
+static TRANSFER: Mutex<Option<CancelToken>> = Mutex::new(None);
+
+pub fn install_interrupt_handler() {
+    let handled = ctrlc::set_handler(|| {
+        match TRANSFER.lock().ok().and_then(|x| x.clone()) {
+            Some(t) if !t.is_cancelled() => t.cancel(),
+            _ => std::process::exit(130)
+        }
+    });
+    if let Err(e) = handled {
+        tracing::debug!("Ctrl-C will not cancel transfers because '{e}'");
+    }
+}
+
+// Redraws a line of stderr as a transfer goes, when stderr is a terminal, and lets Ctrl-C cancel it
+fn start_transfer(label: &str) -> Progress<'static> {
+    let token = CancelToken::new();
+    if let Ok(mut t) = TRANSFER.lock() {
+        *t = Some(token.clone());
+    }
+    if !std::io::stderr().is_terminal() {
+        return Progress::none().with_cancel(token);
+    }
+
+    let label = label.to_string();
+    Progress::new(None, move |x| {
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r\x1b[2K{}", format_progress(&label, &x));
+        let _ = stderr.flush();
+    }).with_cancel(token)
+}
+fn end_transfer() {
+    if let Ok(mut t) = TRANSFER.lock() {
+        *t = None;
+    }
+    if std::io::stderr().is_terminal() {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+enum Argument {
+    Remote,
+    Local
+}
+// Which side of the connection a command's argument names, so it can be completed from there
+fn argument_kind(command: &str, index: usize) -> Option<Argument> {
+    match (command, index) {
+        ("ls" | "cd" | "rm" | "mv" | "mkdir" | "mget", _) | ("get" | "preview", 1) | ("put", 2) => Some(Argument::Remote),
+        ("get", 2) | ("put", 1) => Some(Argument::Local),
+        _ => None
+    }
+}
+
+struct ShellHelper {
+    shell: Rc<RefCell<Shell>>,
+    files: FilenameCompleter,
+    masking: bool //Set while a password is typed, so it shows as stars
+}
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let words = match split_words(&line[..pos]) {
+            Ok(w) if !self.masking => w,
+            _ => return Ok((pos, Vec::new()))
+        };
+        // The word under the cursor, or a new one after a space
+        let (index, start, prefix) = match words.last() {
+            Some(w) if w.end == pos => (words.len() - 1, w.start, w.text.as_str()),
+            _ => (words.len(), pos, "")
+        };
+
+        if index == 0 {
+            let names = COMMANDS.iter()
+                .filter(|x| x.0.starts_with(prefix))
+                .map(|x| Pair { display: x.0.to_string(), replacement: format!("{} ", x.0) })
+                .collect();
+            return Ok((start, names));
+        }
+
+        // The first word is there, since the cursor is past it
+        match argument_kind(&words[0].text, index) {
+            Some(Argument::Remote) => Ok((start, self.shell.borrow_mut().complete_remote(prefix))),
+            Some(Argument::Local) => self.files.complete_path(line, pos),
+            None => Ok((pos, Vec::new()))
+        }
+    }
+}
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        match self.masking {
+            true => Cow::Owned("*".repeat(line.chars().count())),
+            false => Cow::Borrowed(line)
+        }
+    }
+    fn highlight_char(&self, _line: &str, _pos: usize, _kind: CmdKind) -> bool {
+        self.masking
+    }
+}
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    Some(hermes_directory()?.join("history"))
+}
+
+// HERMES_PASSWORD is used when it is set, so scripts driving the shell never have to type one
+fn ask_password(editor: &mut Editor<ShellHelper, DefaultHistory>) -> Result<String, CliError> {
+    if let Ok(p) = std::env::var("HERMES_PASSWORD") {
+        return Ok(p);
+    }
+
+    let set_masking = |editor: &mut Editor<ShellHelper, DefaultHistory>, masking: bool| {
+        if let Some(h) = editor.helper_mut() {
+            h.masking = masking;
+        }
+    };
+    set_masking(editor, true);
+    let password = editor.readline("password: ");
+    set_masking(editor, false);
+    password.map_err(|e| CliError::new(ExitCode::General, format!("unable to read the password because '{e}'")))
+}
+
+// Where 'put' stores a file: into the folder given when it ends in '/', at the path given, or else under its own name in the current folder
+fn put_target(local: &Path, remote: Option<&str>) -> String {
+    let name = local.file_name().unwrap_or_default().to_string_lossy().to_string();
+    match remote {
+        Some(r) if r.ends_with('/') => format!("{r}{name}"),
+        Some(r) => r.to_string(),
+        None => name
+    }
+}
+
+// Reads commands from the terminal until 'exit' or the end of input. A command that fails is reported, and the shell carries on.
+pub fn run_interactive(mut shell: Shell, first: Option<ShellCommand>) -> Result<(), CliError> {
+    // Transfers typed at the prompt are started ahead of the server's bulk ones
+    shell.interactive = true;
+    let shell = Rc::new(RefCell::new(shell));
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(|e| CliError::new(ExitCode::General, e.to_string()))?;
+    editor.set_helper(Some(ShellHelper { shell: shell.clone(), files: FilenameCompleter::new(), masking: false }));
+    let history = history_path();
+    if let Some(h) = &history {
+        let _ = editor.load_history(h);
+    }
+
+    let mut next = first;
+    loop {
+        let command = match next.take() {
+            Some(c) => c,
+            None => {
+                let prompt = shell.borrow().prompt();
+                let line = match editor.readline(&prompt) {
+                    Ok(l) => l,
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => break,
+                    Err(e) => return Err(CliError::new(ExitCode::General, e.to_string()))
+                };
+
+                match ShellCommand::parse(&line) {
+                    Ok(Some(c)) => {
+                        let _ = editor.add_history_entry(line.as_str());
+                        c
+                    },
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("error: {e}");
+                        continue;
+                    }
+                }
+            }
+        };
+        if command == ShellCommand::Exit {
+            break;
+        }
+
+        let result = shell.borrow_mut().execute(command, &mut || ask_password(&mut editor));
+        if let Err(e) = result {
+            eprintln!("{e}");
+        }
+    }
+
+    if let Some(h) = &history {
+        if let Some(parent) = h.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let _ = editor.save_history(h);
+    }
+    shell.borrow_mut().close();
+    Ok(())
+}
+
+// Runs a script of shell commands, one a line, stopping at the first that fails
+pub fn run_batch(shell: &mut Shell, script: &str, password: &mut dyn FnMut() -> Result<String, CliError>) -> Result<(), CliError> {
+    for (number, line) in script.lines().enumerate() {
+        let at_line = |e: CliError| CliError::new(e.kind(), format!("line {}: {}", number + 1, e.message()));
+        let command = match ShellCommand::parse(line).map_err(|e| at_line(CliError::usage(e)))? {
+            Some(ShellCommand::Exit) => break,
+            Some(c) => c,
+            None => continue
+        };
+
+        tracing::debug!("{}", line.trim());
+        shell.execute(command, password).map_err(at_line)?;
+    }
+
+    shell.close();
+    Ok(())
+}
+
+#[test]
+fn test_shell_commands() {
+    let words = |line: &str| split_words(line).unwrap().into_iter().map(|x| x.text).collect::<Vec<String>>();
+    assert_eq!(words(r#"put "my notes.txt" docs/it\'s\ here"#), vec!["put", "my notes.txt", "docs/it's here"]);
+    assert_eq!(words("  ls  "), vec!["ls"]);
+    assert!(split_words("get 'open").is_err());
+
+    // Completion needs to know where the word under the cursor starts, including its escapes
+    let split = split_words(r"get my\ fi").unwrap();
+    assert_eq!((split[1].start, split[1].end), (4, 10));
+    assert_eq!(escape_word("my file's"), r"my\ file\'s");
+    assert_eq!(words(&format!("get {}", escape_word("my file's"))), vec!["get", "my file's"]);
+
+    assert_eq!(ShellCommand::parse("rm -r old").unwrap(), Some(ShellCommand::Rm { path: String::from("old"), recursive: true }));
+    assert_eq!(ShellCommand::parse("get a.txt out/").unwrap(), Some(ShellCommand::Get { remote: String::from("a.txt"), local: Some(PathBuf::from("out/")) }));
+    assert_eq!(ShellCommand::parse("mget a.txt 'b c.txt'").unwrap(), Some(ShellCommand::Mget(vec![String::from("a.txt"), String::from("b c.txt")])));
+    assert_eq!(ShellCommand::parse("mget").unwrap_err(), "usage: mget <remote>...");
+    assert_eq!(ShellCommand::parse("preview notes.txt").unwrap(), Some(ShellCommand::Preview { remote: String::from("notes.txt"), limit: DEFAULT_PREVIEW_LIMIT }));
+    assert_eq!(ShellCommand::parse("preview notes.txt many").unwrap_err(), "'many' is not a count");
+    assert_eq!(ShellCommand::parse("login files:9090 alice --save").unwrap(), Some(ShellCommand::Connect { address: String::from("files:9090"), username: String::from("alice"), save: true }));
+    assert_eq!(ShellCommand::parse("logout").unwrap(), Some(ShellCommand::Logout(None)));
+    assert_eq!(ShellCommand::parse("login files:9090 alice --keep").unwrap_err(), "usage: login <address> <username> [--save]");
+    assert_eq!(ShellCommand::parse("ls -a docs").unwrap(), Some(ShellCommand::Ls { path: Some(String::from("docs")), all: true }));
+    assert_eq!(ShellCommand::parse("  # a comment").unwrap(), None);
+    assert_eq!(ShellCommand::parse("mv a").unwrap_err(), "usage: mv <source> <destination>");
+    assert_eq!(ShellCommand::parse("df").unwrap(), Some(ShellCommand::Df));
+    assert_eq!(ShellCommand::parse("tree docs").unwrap(), Some(ShellCommand::Tree(Some(String::from("docs")))));
+    assert!(ShellCommand::parse("frobnicate").unwrap_err().starts_with("unknown command"));
+
+    assert_eq!(relative_to("/docs/reports", "/photos/cat.png"), "../../photos/cat.png");
+    assert_eq!(relative_to("/", "/docs"), "docs");
+    assert_eq!(relative_to("/docs", "/"), "..");
+    assert_eq!(relative_to("/", "/"), ".");
+    assert_eq!(relative_to("/docs", "notes.txt"), "notes.txt");
+    assert_eq!(file_name("docs/reports/"), "reports");
+    assert_eq!(format_preview(&FileType::Text, b"one\ntwo"), "one\ntwo\n");
+    assert_eq!(format_preview(&FileType::Binary, &[0u8; 18]), format!("00000000  {}\n00000010  00 00\n", ["00"; 16].join(" ")));
+    let usage = UsageReport { total_bytes: Some(4 << 30), free_bytes: Some(1 << 30), users: [(String::from("alice"), 1536)].into(), folders: [(String::from("docs"), hermes_common::messages::FolderUsage { files: 2, bytes: 1536 })].into() };
+    assert_eq!(format_usage(&usage), "1.0 GiB free of 4.0 GiB\n     1.5 KiB  alice\n     1.5 KiB  docs/ (2 files)\n");
+    let file = |name: &str| DirectoryContent::File(hermes_common::file_io::FileInfo::new(name.to_string(), String::from("any"), FileType::Text, 1));
+    let link = DirectoryContent::Symlink(hermes_common::file_io::LinkInfo::new(String::from("latest"), Some(String::from("/docs/a.txt"))));
+    let tree = DirectoryInfo::new(String::new(), vec![DirectoryContent::Dir(DirectoryInfo::new(String::from("docs"), vec![file("a.txt")])), file("b.txt"), link]);
+    assert_eq!(format_tree(&tree, 0), "docs/\n  a.txt\nb.txt\nlatest -> /docs/a.txt\n");
+
+    let update = ProgressUpdate { done: 3 * 1024 * 1024, total: Some(10 * 1024 * 1024), rate: 1536.0 * 1024.0, eta: Some(std::time::Duration::from_secs(65)) };
+    assert_eq!(format_progress("a.bin", &update), "a.bin [######              ]  30%  1.5 MiB/s  ETA 1:05");
+    assert_eq!(format_progress("a.bin", &ProgressUpdate { total: None, eta: None, ..update }), "a.bin 3.0 MiB  1.5 MiB/s");
+}
+
+#[test]
+fn test_offline_queue() {
+    use hermes_common::codec::Codec;
+    use hermes_common::framing::{read_frame, write_frame_with};
+    use hermes_common::http_codes::HttpCodes;
+    use hermes_common::messages::{Message, MessageDirection, MessageType, ack_messsage, connect_ack_message, session_ack, stat_message_response, changes_since_response, extract_delete_message, extract_idempotency_key};
+    use hermes_common::session::SessionToken;
+    use std::net::TcpListener;
+
+    let dir = std::env::temp_dir().join(format!("hermes_shell_queue_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    let mut shell = Shell { queue: Some(dir.join("queue.json")), ..Shell::default() };
+
+    // Uploads and deletes are kept from the top of the server once the folder is known, and nothing else is kept
+    shell.cwd = String::from("/docs");
+    assert_eq!(shell.offline_action(&ShellCommand::Rm { path: String::from("old.txt"), recursive: true }), Some(QueuedAction::Delete { remote_path: String::from("/docs/old.txt"), recursive: true }));
+    let put = ShellCommand::Put { local: dir.join("a.txt"), remote: Some(String::from("/backups/")) };
+    assert_eq!(shell.offline_action(&put), Some(QueuedAction::Upload { local_path: std::fs::canonicalize(dir.join("a.txt")).unwrap(), remote_path: String::from("/backups/a.txt") }));
+    assert_eq!(shell.offline_action(&ShellCommand::Mkdir(String::from("new"))), None);
+    shell.cwd.clear();
+
+    // Nothing listens at an address just given up, so logging in there fails on the network, and the delete waits in the queue
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let login = profile(&address.to_string(), "alice");
+    let rm = ShellCommand::Rm { path: String::from("old.txt"), recursive: false };
+    let refused = shell.connect(&address.to_string(), "alice", "pass").unwrap_err();
+    assert_eq!(refused.kind(), ExitCode::Network);
+    shell.enqueue(&login, shell.offline_action(&rm), refused).unwrap();
+    assert!(shell.enqueue(&login, shell.offline_action(&rm), CliError::usage(String::from("bad"))).is_err());
+    let queued = OfflineQueue::open(&dir.join("queue.json")).unwrap().operations()[0].clone();
+    assert_eq!((queued.login.as_str(), queued.action.remote_path()), (login.as_str(), "old.txt"));
+
+    // The next login replays it with its key, and the queue is left empty. The server is not watching, so it is asked when the file last changed instead.
+    let listener = TcpListener::bind(address).unwrap();
+    let server = std::thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        let mut seen = Vec::<Message>::new();
+        loop {
+            let request = read_frame(&mut stream).unwrap();
+            let response = match request.message_type() {
+                MessageType::Connect => session_ack(connect_ack_message(HttpCodes::Ok, None, None, None), &SessionToken::new(String::from("token"), u64::MAX)),
+                MessageType::ChangeDir => ack_messsage(MessageDirection::Response, HttpCodes::Ok, Some(String::from("/"))),
+                MessageType::ChangesSince => changes_since_response(HttpCodes::Conflict, "this server is not watching for changes", None),
+                MessageType::Stat => stat_message_response(HttpCodes::NotFound, "not found", None),
+                MessageType::Delete => ack_messsage(MessageDirection::Response, HttpCodes::Ok, Some(String::from("deleted"))),
+                _ => return seen
+            };
+            write_frame_with(&mut stream, &response.with_request_id(request.request_id()), Codec::Json).unwrap();
+            seen.push(request);
+        }
+    });
+
+    shell.connect(&address.to_string(), "alice", "pass").unwrap();
+    shell.close();
+    let seen = server.join().unwrap();
+    assert_eq!(seen.iter().map(|x| *x.message_type()).collect::<Vec<_>>(), vec![MessageType::Connect, MessageType::ChangeDir, MessageType::ChangesSince, MessageType::Stat, MessageType::Delete]);
+    assert_eq!(extract_idempotency_key(&seen[4]), Some(queued.key));
+    assert_eq!(extract_delete_message(seen[4].clone()), Some((String::from("old.txt"), false)));
+    assert!(OfflineQueue::open(&dir.join("queue.json")).unwrap().is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
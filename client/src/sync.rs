@@ -0,0 +1,472 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::connection::{ConnectOptions, Connection, RequestError, PARTIAL_SUFFIX};
+use crate::exit_codes::{CliError, ExitCode};
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, checksum_file};
+use hermes_common::file_io::DirectoryContent;
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::progress::Progress;
+
+// Kept in the root of the local folder, and never synced itself
+pub const STATE_FILE_NAME: &str = ".hermes-sync.json";
+
+// What to do with a file that changed on both sides since the last sync
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy {
+    NewestWins, //The copy modified last replaces the other
+    KeepBoth //The local copy is renamed, and both are synced
+}
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::NewestWins => "newest-wins",
+            Self::KeepBoth => "keep-both"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest-wins" => Ok(Self::NewestWins),
+            "keep-both" => Ok(Self::KeepBoth),
+            _ => Err(format!("unknown conflict policy '{s}'"))
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub local: PathBuf,
+    pub remote: String, //Relative to the account's home, and empty for the home itself
+    pub dry_run: bool,
+    pub policy: ConflictPolicy,
+    pub connect: ConnectOptions
+}
+
+// What one side held, as cheaply as it can be read. Local and remote times are not compared with each other, only with what the same side held before.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct FileState {
+    pub size: u64,
+    pub modified: Option<u64>
+}
+
+// What both sides held after the last sync, when they were the same file
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct SyncRecord {
+    pub local: FileState,
+    pub remote: FileState,
+    pub checksum: Checksum
+}
+
+// Without the last sync to compare against, a file missing on one side could just as well have been deleted there as created on the other
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct SyncState {
+    files: BTreeMap<String, SyncRecord>
+}
+impl SyncState {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) if !contents.trim().is_empty() => serde_json::from_str(&contents).map_err(|e| format!("could not parse the sync state because '{e}'")),
+            Ok(_) => Ok(Self::default()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.to_string())
+        }
+    }
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, contents).map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self, path: &str) -> Option<&SyncRecord> {
+        self.files.get(path)
+    }
+}
+
+// How a file on one side compares with the last sync. A file the last sync did not see counts as changed.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Side {
+    Absent,
+    Same,
+    Changed {
+        modified: Option<u64>,
+        checksum: Option<Checksum> //Only worked out when it decides something
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SyncAction {
+    Upload(String),
+    Download(String),
+    DeleteLocal(String),
+    DeleteRemote(String),
+    KeepBoth {
+        path: String,
+        copy: String //What the local copy is renamed to before the remote one is downloaded
+    }
+}
+impl Display for SyncAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upload(p) => write!(f, "upload '{p}'"),
+            Self::Download(p) => write!(f, "download '{p}'"),
+            Self::DeleteLocal(p) => write!(f, "delete local '{p}'"),
+            Self::DeleteRemote(p) => write!(f, "delete remote '{p}'"),
+            Self::KeepBoth { path, copy } => write!(f, "keep both '{path}', the local copy as '{copy}'")
+        }
+    }
+}
+
+// Where the local side of a conflict is kept, beside the original: 'notes (conflict 1700000000).txt'
+pub fn conflict_copy_name(path: &str, now: u64) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => (&path[..=i], &path[i + 1..]),
+        None => ("", path)
+    };
+    match name.rfind('.').filter(|x| *x > 0) {
+        Some(i) => format!("{dir}{} (conflict {now}){}", &name[..i], &name[i..]),
+        None => format!("{dir}{name} (conflict {now})")
+    }
+}
+
+// Works out what one file needs. A change wins over a delete, so nothing edited since the last sync is ever lost.
+pub fn decide(path: &str, local: &Side, remote: &Side, policy: ConflictPolicy, now: u64) -> Option<SyncAction> {
+    match (local, remote) {
+        (Side::Absent, Side::Absent) | (Side::Same, Side::Same) => None,
+        (Side::Changed { .. }, Side::Absent | Side::Same) => Some(SyncAction::Upload(path.to_string())),
+        (Side::Absent | Side::Same, Side::Changed { .. }) => Some(SyncAction::Download(path.to_string())),
+        (Side::Same, Side::Absent) => Some(SyncAction::DeleteLocal(path.to_string())),
+        (Side::Absent, Side::Same) => Some(SyncAction::DeleteRemote(path.to_string())),
+        (Side::Changed { checksum: Some(a), .. }, Side::Changed { checksum: Some(b), .. }) if a == b => None,
+        (Side::Changed { modified: ours, .. }, Side::Changed { modified: theirs, .. }) => match policy {
+            ConflictPolicy::NewestWins if ours > theirs => Some(SyncAction::Upload(path.to_string())),
+            ConflictPolicy::NewestWins => Some(SyncAction::Download(path.to_string())),
+            ConflictPolicy::KeepBoth => Some(SyncAction::KeepBoth { path: path.to_string(), copy: conflict_copy_name(path, now) })
+        }
+    }
+}
+
+fn unix_secs(time: std::io::Result<SystemTime>) -> Option<u64> {
+    time.ok()?.duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs())
+}
+fn local_state(path: &Path) -> Result<FileState, String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    Ok(FileState { size: metadata.len(), modified: unix_secs(metadata.modified()) })
+}
+
+// Every file under the local folder, by its path relative to it with '/' between parts. Links, and the sync's own files, are left out.
+pub fn scan_local(root: &Path) -> Result<BTreeMap<String, FileState>, String> {
+    fn walk(dir: &Path, prefix: &str, result: &mut BTreeMap<String, FileState>) -> Result<(), String> {
+        for entry in std::fs::read_dir(dir).map_err(|e| e.to_string())?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative = format!("{prefix}{name}");
+            let metadata = entry.path().symlink_metadata().map_err(|e| e.to_string())?;
+
+            if metadata.is_dir() {
+                walk(&entry.path(), &format!("{relative}/"), result)?;
+            } else if metadata.is_file() && relative != STATE_FILE_NAME && !name.ends_with(PARTIAL_SUFFIX) {
+                result.insert(relative, FileState { size: metadata.len(), modified: unix_secs(metadata.modified()) });
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut result = BTreeMap::new();
+    walk(root, "", &mut result)?;
+    Ok(result)
+}
+
+// What a sync did, or with a dry run, would do
+#[derive(Default, Debug)]
+pub struct SyncReport {
+    pub actions: Vec<SyncAction>,
+    pub failures: Vec<(String, String)> //What could not be done, and why
+}
+
+// The connection, with the remote folder being synced. Paths given to it are relative to that folder.
+struct SyncConnection {
+    connection: Connection,
+    remote_root: String,
+    remote_dirs: BTreeSet<String> //Remote folders known to exist, so uploads only create the ones that are missing
+}
+impl SyncConnection {
+    fn open(options: &SyncOptions) -> Result<Self, CliError> {
+        Ok(
+            Self {
+                connection: Connection::open(&options.address, &options.username, &options.password, &options.connect)?,
+                remote_root: options.remote.trim_matches('/').to_string(),
+                remote_dirs: BTreeSet::new()
+            }
+        )
+    }
+
+    fn remote_path(&self, relative: &str) -> String {
+        match self.remote_root.is_empty() {
+            true => relative.to_string(),
+            false => format!("{}/{relative}", &self.remote_root)
+        }
+    }
+
+    fn keep_alive(&mut self) -> Result<(), String> {
+        self.connection.keep_alive()
+    }
+    // Every file under the remote folder, keyed the same way as scan_local
+    fn scan(&mut self) -> Result<BTreeMap<String, FileState>, String> {
+        let mut result = BTreeMap::new();
+        let mut pending = vec![String::new()];
+        while let Some(relative) = pending.pop() {
+            let listing = self.connection.list(self.remote_path(&relative).trim_end_matches('/'))?;
+            self.remote_dirs.insert(relative.trim_end_matches('/').to_string());
+
+            for entry in listing.contents() {
+                match entry {
+                    DirectoryContent::File(f) => {
+                        result.insert(format!("{relative}{}", f.name()), FileState { size: f.size(), modified: f.modified() });
+                    },
+                    DirectoryContent::Dir(d) => pending.push(format!("{relative}{}/", d.name())),
+                    // What a link leads to is synced where it really is
+                    DirectoryContent::Symlink(_) => { }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn checksum(&mut self, relative: &str) -> Result<Option<Checksum>, String> {
+        let path = self.remote_path(relative);
+        Ok(self.connection.checksum(&path)?)
+    }
+    fn download(&mut self, relative: &str, destination: &Path) -> Result<Checksum, String> {
+        let path = self.remote_path(relative);
+        Ok(self.connection.download(&path, destination, &mut Progress::none())?)
+    }
+
+    fn make_parents(&mut self, relative: &str) -> Result<(), String> {
+        let parts: Vec<&str> = relative.split('/').collect();
+        for i in 1..parts.len() {
+            let dir = parts[..i].join("/");
+            if self.remote_dirs.contains(&dir) {
+                continue;
+            }
+
+            match self.connection.make_dir(&self.remote_path(&dir)) {
+                Ok(()) | Err(RequestError::Refused(HttpCodes::Conflict, _)) => { self.remote_dirs.insert(dir); },
+                Err(e) => return Err(e.into())
+            }
+        }
+
+        Ok(())
+    }
+    fn upload(&mut self, relative: &str, source: &Path) -> Result<Checksum, String> {
+        self.make_parents(relative)?;
+        let path = self.remote_path(relative);
+        Ok(self.connection.upload(&path, source, &mut Progress::none())?)
+    }
+
+    fn delete(&mut self, relative: &str) -> Result<(), String> {
+        match self.connection.delete(&self.remote_path(relative), false) {
+            Ok(()) | Err(RequestError::Refused(HttpCodes::NotFound, _)) => Ok(()),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn close(self) {
+        self.connection.close();
+    }
+}
+
+// Sizes and times are compared with the last sync first, and files are only hashed when those differ, so an unchanged folder costs a listing and a walk
+fn compare(
+    path: &str,
+    local: Option<&FileState>,
+    remote: Option<&FileState>,
+    record: Option<&SyncRecord>,
+    options: &SyncOptions,
+    connection: &mut SyncConnection
+) -> Result<(Side, Side), String> {
+    let local_file = options.local.join(path);
+    let mut local_side = match (local, record) {
+        (None, _) => Side::Absent,
+        (Some(s), Some(r)) if *s == r.local => Side::Same,
+        (Some(s), Some(r)) => {
+            let checksum = checksum_file(&local_file, ChecksumAlgorithm::Sha256).map_err(|e| e.to_string())?;
+            if checksum == r.checksum { Side::Same } else { Side::Changed { modified: s.modified, checksum: Some(checksum) } }
+        },
+        (Some(s), None) => Side::Changed { modified: s.modified, checksum: None }
+    };
+    let mut remote_side = match (remote, record) {
+        (None, _) => Side::Absent,
+        (Some(s), Some(r)) if *s == r.remote => Side::Same,
+        (Some(s), Some(r)) => {
+            let checksum = connection.checksum(path)?;
+            if checksum.as_ref() == Some(&r.checksum) { Side::Same } else { Side::Changed { modified: s.modified, checksum } }
+        },
+        (Some(s), None) => Side::Changed { modified: s.modified, checksum: None }
+    };
+
+    // Two new copies are only a conflict if they differ
+    if let (Side::Changed { checksum: ours @ None, .. }, Side::Changed { .. }) = (&mut local_side, &remote_side) {
+        *ours = Some(checksum_file(&local_file, ChecksumAlgorithm::Sha256).map_err(|e| e.to_string())?);
+    }
+    if let (Side::Changed { .. }, Side::Changed { checksum: theirs @ None, .. }) = (&local_side, &mut remote_side) {
+        *theirs = connection.checksum(path)?;
+    }
+
+    Ok((local_side, remote_side))
+}
+
+fn apply(action: &SyncAction, options: &SyncOptions, remote: &BTreeMap<String, FileState>, connection: &mut SyncConnection, state: &mut SyncState) -> Result<(), String> {
+    let local_path = |relative: &str| options.local.join(relative);
+    let remote_state = |relative: &str| remote.get(relative).copied().unwrap_or(FileState { size: 0, modified: None });
+
+    match action {
+        SyncAction::Upload(p) => {
+            let checksum = connection.upload(p, &local_path(p))?;
+            // The remote time is only known from the next listing, which then matches by checksum
+            let local = local_state(&local_path(p))?;
+            state.files.insert(p.clone(), SyncRecord { local, remote: FileState { size: local.size, modified: None }, checksum });
+        },
+        SyncAction::Download(p) => {
+            let checksum = connection.download(p, &local_path(p))?;
+            state.files.insert(p.clone(), SyncRecord { local: local_state(&local_path(p))?, remote: remote_state(p), checksum });
+        },
+        SyncAction::DeleteLocal(p) => {
+            std::fs::remove_file(local_path(p)).map_err(|e| e.to_string())?;
+            state.files.remove(p);
+        },
+        SyncAction::DeleteRemote(p) => {
+            connection.delete(p)?;
+            state.files.remove(p);
+        },
+        SyncAction::KeepBoth { path, copy } => {
+            std::fs::rename(local_path(path), local_path(copy)).map_err(|e| e.to_string())?;
+            apply(&SyncAction::Download(path.clone()), options, remote, connection, state)?;
+            apply(&SyncAction::Upload(copy.clone()), options, remote, connection, state)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Makes the local folder and the remote folder hold the same files. A dry run only works out what would be done.
+// One file failing does not stop the others, and is reported with the rest.
+pub fn run_sync(options: &SyncOptions) -> Result<SyncReport, CliError> {
+    if !options.local.is_dir() {
+        return Err(CliError::new(ExitCode::NotFound, format!("'{}' is not a directory", options.local.display())));
+    }
+    let _span = tracing::info_span!("sync", server = %options.address, username = %options.username).entered();
+    let state_path = options.local.join(STATE_FILE_NAME);
+    let mut state = SyncState::open(&state_path).map_err(|e| CliError::new(ExitCode::General, e))?;
+
+    let mut connection = SyncConnection::open(options)?;
+    let local = scan_local(&options.local).map_err(|e| CliError::new(ExitCode::General, e))?;
+    let remote = connection.scan().map_err(CliError::network)?;
+    tracing::debug!("{} local and {} remote files", local.len(), remote.len());
+    let now = unix_secs(Ok(SystemTime::now())).unwrap_or_default();
+
+    let mut report = SyncReport::default();
+    let paths: BTreeSet<String> = local.keys().chain(remote.keys()).chain(state.files.keys()).cloned().collect();
+    for path in paths {
+        connection.keep_alive().map_err(CliError::network)?;
+        let (ours, theirs) = match compare(&path, local.get(&path), remote.get(&path), state.get(&path), options, &mut connection) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!("unable to compare '{path}' because '{e}'");
+                report.failures.push((format!("compare '{path}'"), e));
+                continue;
+            }
+        };
+
+        match decide(&path, &ours, &theirs, options.policy, now) {
+            Some(action) => report.actions.push(action),
+            // Nothing to move, but what each side holds now is what the next sync compares against
+            None => match (local.get(&path), remote.get(&path)) {
+                (Some(l), Some(r)) => {
+                    let checksum = match (&ours, state.get(&path)) {
+                        (Side::Changed { checksum: Some(c), .. }, _) => Some(c.clone()),
+                        (_, Some(record)) => Some(record.checksum.clone()),
+                        _ => None
+                    };
+                    if let Some(checksum) = checksum {
+                        state.files.insert(path.clone(), SyncRecord { local: *l, remote: *r, checksum });
+                    }
+                },
+                _ => { state.files.remove(&path); }
+            }
+        }
+    }
+
+    if !options.dry_run {
+        let actions = std::mem::take(&mut report.actions);
+        for action in actions {
+            // What was done so far is still saved
+            if let Err(e) = connection.keep_alive() {
+                report.failures.push((action.to_string(), e));
+                break;
+            }
+            tracing::debug!("{action}");
+            match apply(&action, options, &remote, &mut connection, &mut state) {
+                Ok(()) => report.actions.push(action),
+                Err(e) => {
+                    tracing::warn!("unable to {action} because '{e}'");
+                    report.failures.push((action.to_string(), e));
+                }
+            }
+        }
+        state.save(&state_path).map_err(|e| CliError::new(ExitCode::General, format!("unable to save the sync state because '{e}'")))?;
+    }
+
+    connection.close();
+    Ok(report)
+}
+
+#[test]
+fn test_sync_decisions() {
+    use hermes_common::checksum::checksum_bytes;
+
+    let changed = |modified: u64, text: &str| Side::Changed { modified: Some(modified), checksum: Some(checksum_bytes(text.as_bytes(), ChecksumAlgorithm::Sha256)) };
+    let decide = |local: &Side, remote: &Side, policy: ConflictPolicy| decide("docs/a.txt", local, remote, policy, 1700000000);
+    let upload = Some(SyncAction::Upload(String::from("docs/a.txt")));
+    let download = Some(SyncAction::Download(String::from("docs/a.txt")));
+
+    assert_eq!(decide(&Side::Same, &Side::Same, ConflictPolicy::NewestWins), None);
+    assert_eq!(decide(&changed(5, "new"), &Side::Same, ConflictPolicy::NewestWins), upload);
+    assert_eq!(decide(&Side::Same, &changed(5, "new"), ConflictPolicy::NewestWins), download);
+    assert_eq!(decide(&Side::Same, &Side::Absent, ConflictPolicy::NewestWins), Some(SyncAction::DeleteLocal(String::from("docs/a.txt"))));
+    assert_eq!(decide(&Side::Absent, &Side::Same, ConflictPolicy::NewestWins), Some(SyncAction::DeleteRemote(String::from("docs/a.txt"))));
+
+    // An edit on one side wins over a delete on the other
+    assert_eq!(decide(&changed(5, "new"), &Side::Absent, ConflictPolicy::NewestWins), upload);
+    assert_eq!(decide(&Side::Absent, &changed(5, "new"), ConflictPolicy::NewestWins), download);
+
+    // Both sides changed: identical copies are left alone, and different ones follow the policy
+    assert_eq!(decide(&changed(5, "same"), &changed(9, "same"), ConflictPolicy::NewestWins), None);
+    assert_eq!(decide(&changed(9, "ours"), &changed(5, "theirs"), ConflictPolicy::NewestWins), upload);
+    assert_eq!(decide(&changed(5, "ours"), &changed(9, "theirs"), ConflictPolicy::NewestWins), download);
+    assert_eq!(decide(&changed(5, "ours"), &changed(9, "theirs"), ConflictPolicy::KeepBoth), Some(SyncAction::KeepBoth { path: String::from("docs/a.txt"), copy: String::from("docs/a (conflict 1700000000).txt") }));
+
+    assert_eq!(conflict_copy_name("README", 7), "README (conflict 7)");
+    assert_eq!(conflict_copy_name("dir/.hidden", 7), "dir/.hidden (conflict 7)");
+    assert_eq!("keep-both".parse::<ConflictPolicy>(), Ok(ConflictPolicy::KeepBoth));
+
+    let dir = std::env::temp_dir().join(format!("hermes_sync_scan_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("nested")).unwrap();
+    std::fs::write(dir.join("nested/b.txt"), "bravo").unwrap();
+    std::fs::write(dir.join(STATE_FILE_NAME), "{}").unwrap();
+    std::fs::write(dir.join(format!("c.txt{PARTIAL_SUFFIX}")), "partial").unwrap();
+    assert_eq!(scan_local(&dir).unwrap().into_keys().collect::<Vec<_>>(), vec![String::from("nested/b.txt")]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+// What an archive holds, by path relative to the directory it was made from. Directories are listed before anything inside them.
+#[derive(Default, PartialEq, Debug)]
+pub struct ArchiveContents {
+    pub directories: Vec<PathBuf>,
+    pub files: Vec<PathBuf>,
+    pub bytes: u64 //The size of the files once unpacked
+}
+
+fn append_tree<W: Write, F: Fn(&Path) -> bool>(builder: &mut tar::Builder<W>, dir: &Path, name: &Path, include: &F, contents: &mut ArchiveContents) -> Result<(), String> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir).map_err(|e| e.to_string())?.flatten().collect();
+    entries.sort_by_key(|x| x.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if !include(&path) {
+            continue;
+        }
+
+        // Links are left out, since they could point anywhere once unpacked
+        let entry_name = name.join(entry.file_name());
+        let metadata = path.symlink_metadata().map_err(|e| e.to_string())?;
+        if metadata.is_dir() {
+            builder.append_dir(&entry_name, &path).map_err(|e| e.to_string())?;
+            contents.directories.push(entry_name.clone());
+            append_tree(builder, &path, &entry_name, include, contents)?;
+        } else if metadata.is_file() {
+            builder.append_path_with_name(&path, &entry_name).map_err(|e| e.to_string())?;
+            contents.files.push(entry_name);
+            contents.bytes += metadata.len();
+        }
+    }
+
+    Ok(())
+}
+
+// Writes everything under a directory into a tar archive, with paths relative to it. Entries the filter refuses are left out, along with everything beneath them.
+pub fn pack_directory<F: Fn(&Path) -> bool>(dir: &Path, target: &Path, include: F) -> Result<ArchiveContents, String> {
+    let mut contents = ArchiveContents::default();
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(target).map_err(|e| e.to_string())?));
+    builder.follow_symlinks(false);
+
+    append_tree(&mut builder, dir, Path::new(""), &include, &mut contents)?;
+    builder.into_inner().and_then(|x| x.into_inner().map_err(|e| e.into_error())).and_then(|x| x.sync_all()).map_err(|e| e.to_string())?;
+    Ok(contents)
+}
+
+// Only plain directories and files whose paths stay below the root are accepted
+fn read_entry<R: std::io::Read>(entry: &tar::Entry<R>) -> Result<(PathBuf, bool), String> {
+    let path = entry.path().map_err(|e| e.to_string())?.to_path_buf();
+    if path.as_os_str().is_empty() || !path.components().all(|x| matches!(x, Component::Normal(_))) {
+        return Err(format!("the archive entry '{}' is not a relative path", path.display()));
+    }
+
+    let kind = entry.header().entry_type();
+    if kind.is_dir() {
+        Ok((path, true))
+    } else if kind.is_file() {
+        Ok((path, false))
+    } else {
+        Err(format!("the archive entry '{}' is neither a file nor a directory", path.display()))
+    }
+}
+
+// Reads what an archive holds without unpacking it, refusing the whole archive if any entry could not be unpacked
+pub fn list_archive(archive: &Path) -> Result<ArchiveContents, String> {
+    let mut contents = ArchiveContents::default();
+    let mut archive = tar::Archive::new(File::open(archive).map_err(|e| e.to_string())?);
+
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        match read_entry(&entry)? {
+            (path, true) => contents.directories.push(path),
+            (path, false) => {
+                contents.bytes += entry.size();
+                contents.files.push(path);
+            }
+        }
+    }
+
+    Ok(contents)
+}
+
+// Unpacks an archive into a directory, which is made if it does not exist. Files already there are replaced.
+pub fn unpack_archive(archive: &Path, destination: &Path) -> Result<ArchiveContents, String> {
+    let mut contents = ArchiveContents::default();
+    let mut archive = tar::Archive::new(File::open(archive).map_err(|e| e.to_string())?);
+    std::fs::create_dir_all(destination).map_err(|e| e.to_string())?;
+
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let (path, is_dir) = read_entry(&entry)?;
+        let target = destination.join(&path);
+
+        if is_dir {
+            std::fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+            contents.directories.push(path);
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut file = BufWriter::new(File::create(&target).map_err(|e| e.to_string())?);
+        contents.bytes += std::io::copy(&mut entry, &mut file).map_err(|e| e.to_string())?;
+        file.flush().map_err(|e| e.to_string())?;
+        contents.files.push(path);
+    }
+
+    Ok(contents)
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let dir = std::env::temp_dir().join(format!("hermes_archive_{}", std::process::id()));
+    let source = dir.join("source");
+    std::fs::create_dir_all(source.join("docs/empty")).unwrap();
+    std::fs::write(source.join("a.txt"), "alpha").unwrap();
+    std::fs::write(source.join("docs/b.txt"), "bravo").unwrap();
+    std::fs::write(source.join("skip.part"), "partial").unwrap();
+
+    let packed = pack_directory(&source, &dir.join("out.tar"), |x| x.extension().is_none_or(|e| e != "part")).unwrap();
+    assert_eq!(packed.files, vec![PathBuf::from("a.txt"), PathBuf::from("docs/b.txt")]);
+    assert_eq!(packed.directories, vec![PathBuf::from("docs"), PathBuf::from("docs/empty")]);
+    assert_eq!(list_archive(&dir.join("out.tar")).unwrap(), packed);
+
+    let unpacked = unpack_archive(&dir.join("out.tar"), &dir.join("copy")).unwrap();
+    assert_eq!(unpacked, packed);
+    assert_eq!(std::fs::read_to_string(dir.join("copy/docs/b.txt")).unwrap(), "bravo");
+    assert!(dir.join("copy/docs/empty").is_dir());
+    assert!(!dir.join("copy/skip.part").exists());
+
+    // Nothing may be unpacked outside of the destination
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(4);
+    header.set_mode(0o644);
+    header.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"../escape.txt");
+    header.set_cksum();
+    builder.append(&header, "evil".as_bytes()).unwrap();
+    std::fs::write(dir.join("evil.tar"), builder.into_inner().unwrap()).unwrap();
+    assert!(list_archive(&dir.join("evil.tar")).is_err());
+    assert!(unpack_archive(&dir.join("evil.tar"), &dir.join("evil")).is_err());
+    assert!(!dir.join("escape.txt").exists());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,152 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256
+}
+impl Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Sha256 => "sha256"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!("unknown checksum algorithm '{s}'"))
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    algorithm: ChecksumAlgorithm,
+    value: String
+}
+impl Debug for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (self as &dyn Display).fmt(f)
+    }
+}
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm, &self.value)
+    }
+}
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm, value: String) -> Self {
+        Self {
+            algorithm,
+            value: value.to_lowercase()
+        }
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+// Computes a checksum incrementally, so it can be fed frame by frame while a file is streamed
+pub enum ChecksumHasher {
+    Sha256(Sha256)
+}
+impl ChecksumHasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data)
+        }
+    }
+    pub fn finish(self) -> Checksum {
+        match self {
+            Self::Sha256(h) => Checksum::new(ChecksumAlgorithm::Sha256, to_hex(&h.finalize()))
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn checksum_bytes(data: &[u8], algorithm: ChecksumAlgorithm) -> Checksum {
+    let mut hasher = ChecksumHasher::new(algorithm);
+    hasher.update(data);
+    hasher.finish()
+}
+// Feeds at most limit bytes of a reader into the hasher, returning how many bytes were consumed
+pub fn hash_reader<R: Read>(reader: &mut R, hasher: &mut ChecksumHasher, limit: Option<u64>) -> std::io::Result<u64> {
+    let mut buff = [0u8; 8192];
+    let mut total: u64 = 0;
+
+    loop {
+        let want = match limit {
+            Some(l) if l - total < buff.len() as u64 => (l - total) as usize,
+            _ => buff.len()
+        };
+        if want == 0 {
+            break;
+        }
+
+        let len = reader.read(&mut buff[..want])?;
+        if len == 0 {
+            break;
+        }
+
+        hasher.update(&buff[..len]);
+        total += len as u64;
+    }
+
+    Ok(total)
+}
+pub fn checksum_file(path: &Path, algorithm: ChecksumAlgorithm) -> std::io::Result<Checksum> {
+    let mut file = File::open(path)?;
+    let mut hasher = ChecksumHasher::new(algorithm);
+    hash_reader(&mut file, &mut hasher, None)?;
+
+    Ok(hasher.finish())
+}
+pub fn verify_file_checksum(path: &Path, expected: &Checksum) -> Result<(), String> {
+    let actual = checksum_file(path, expected.algorithm()).map_err(|e| e.to_string())?;
+    if actual == *expected {
+        Ok(())
+    } else {
+        Err(format!("checksum mismatch, expected '{}' but computed '{}'", expected, actual))
+    }
+}
+
+#[test]
+fn test_checksum_streaming() {
+    let whole = checksum_bytes(b"hello world", ChecksumAlgorithm::Sha256);
+    assert_eq!(whole.value(), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+
+    let mut hasher = ChecksumHasher::new(ChecksumAlgorithm::Sha256);
+    hasher.update(b"hello ");
+    hasher.update(b"world");
+    assert_eq!(hasher.finish(), whole);
+
+    let mut partial = ChecksumHasher::new(ChecksumAlgorithm::Sha256);
+    let read = hash_reader(&mut &b"hello world, again"[..], &mut partial, Some(11)).unwrap();
+    assert_eq!(read, 11);
+    assert_eq!(partial.finish(), whole);
+}
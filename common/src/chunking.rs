@@ -0,0 +1,163 @@
+use std::io::Read;
+
+use crate::file_io::{BUFF_SIZE, frame_count_for};
+
+const FRAME: usize = BUFF_SIZE as usize;
+
+// Cuts a stream of known length into the frames of an uncompressed transfer. Every frame but the last is BUFF_SIZE bytes.
+// The last one may be short, so it starts with its length as a big endian u32, and the receiver never waits for bytes that are not coming.
+#[derive(Debug)]
+pub struct ChunkWriter {
+    length: u64,
+    written: u64
+}
+impl ChunkWriter {
+    pub fn new(length: u64) -> Self {
+        Self {
+            length,
+            written: 0
+        }
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        frame_count_for(self.length)
+    }
+    fn last_frame_start(&self) -> u64 {
+        self.frame_count().saturating_sub(1) * BUFF_SIZE
+    }
+    pub fn is_finished(&self) -> bool {
+        self.written == self.length
+    }
+
+    // What goes on the wire for the next bytes of the stream. The stream may be handed over in pieces of any size.
+    pub fn encode(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let end = self.written + data.len() as u64;
+        if end > self.length {
+            return Err(format!("{end} bytes is more than the {} the stream was announced with", self.length));
+        }
+
+        let start = self.last_frame_start();
+        let mut result = Vec::with_capacity(data.len() + 4);
+        if (self.written..end).contains(&start) {
+            let (before, after) = data.split_at((start - self.written) as usize);
+            result.extend_from_slice(before);
+            result.extend_from_slice(&((self.length - start) as u32).to_be_bytes());
+            result.extend_from_slice(after);
+        } else {
+            result.extend_from_slice(data);
+        }
+
+        self.written = end;
+        Ok(result)
+    }
+
+    // A whole buffer as its frames, for listings and probes
+    pub fn frames(contents: &[u8]) -> Vec<Vec<u8>> {
+        let mut writer = Self::new(contents.len() as u64);
+        contents.chunks(FRAME).map(|x| writer.encode(x).unwrap_or_default()).collect()
+    }
+}
+
+// Reads back what a ChunkWriter sent. Every read is exact, so nothing that follows the stream is taken from the connection.
+#[derive(Debug)]
+pub struct ChunkReader {
+    frames_left: u64
+}
+impl ChunkReader {
+    pub fn new(frame_count: u64) -> Self {
+        Self {
+            frames_left: frame_count
+        }
+    }
+
+    pub fn frames_left(&self) -> u64 {
+        self.frames_left
+    }
+    pub fn is_finished(&self) -> bool {
+        self.frames_left == 0
+    }
+
+    // How many full frames the next read takes, when the last frame is not next
+    fn full_frames(&mut self, max: usize) -> usize {
+        let count = (self.frames_left - 1).min((max / FRAME).max(1) as u64);
+        self.frames_left -= count;
+        count as usize
+    }
+    fn last_frame_length(&mut self, header: [u8; 4]) -> Result<usize, String> {
+        let length = u32::from_be_bytes(header) as usize;
+        if length == 0 || length > FRAME {
+            return Err(format!("the last frame claims {length} bytes, where a frame holds 1 to {FRAME}"));
+        }
+
+        self.frames_left = 0;
+        Ok(length)
+    }
+
+    // Reads one frame. Returns None once the last one has been read.
+    pub fn read_frame<S: Read>(&mut self, s: &mut S) -> Result<Option<Vec<u8>>, String> {
+        self.read_frames(s, FRAME)
+    }
+    // Reads as many full frames as fit in max bytes, and at least one. The last frame is always read on its own.
+    pub fn read_frames<S: Read>(&mut self, s: &mut S, max: usize) -> Result<Option<Vec<u8>>, String> {
+        let mut contents = match self.frames_left {
+            0 => return Ok(None),
+            1 => {
+                let mut header = [0u8; 4];
+                s.read_exact(&mut header).map_err(|e| e.to_string())?;
+                vec![0; self.last_frame_length(header)?]
+            },
+            _ => vec![0; self.full_frames(max) * FRAME]
+        };
+
+        s.read_exact(&mut contents).map_err(|e| e.to_string())?;
+        Ok(Some(contents))
+    }
+    #[cfg(feature = "async")]
+    pub async fn read_frames_async<S: tokio::io::AsyncRead + Unpin>(&mut self, s: &mut S, max: usize) -> Result<Option<Vec<u8>>, String> {
+        use tokio::io::AsyncReadExt;
+
+        let mut contents = match self.frames_left {
+            0 => return Ok(None),
+            1 => {
+                let mut header = [0u8; 4];
+                s.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+                vec![0; self.last_frame_length(header)?]
+            },
+            _ => vec![0; self.full_frames(max) * FRAME]
+        };
+
+        s.read_exact(&mut contents).await.map_err(|e| e.to_string())?;
+        Ok(Some(contents))
+    }
+}
+
+#[test]
+fn test_chunk_round_trip() {
+    for length in [0, 1, 10, FRAME - 1, FRAME, FRAME + 1, 3 * FRAME, 3 * FRAME + 7] {
+        let contents: Vec<u8> = (0..length).map(|x| (x % 251) as u8).collect();
+
+        // Handed over in awkward pieces, the wire is the same as for whole frames
+        let mut writer = ChunkWriter::new(length as u64);
+        let mut wire: Vec<u8> = contents.chunks(1000).flat_map(|x| writer.encode(x).unwrap()).collect();
+        assert!(writer.is_finished());
+        assert_eq!(wire, ChunkWriter::frames(&contents).concat());
+        assert_eq!(wire.len(), length + if length == 0 { 0 } else { 4 });
+
+        wire.extend_from_slice(b"next");
+        let mut s = std::io::Cursor::new(wire);
+        let mut reader = ChunkReader::new(writer.frame_count());
+        let mut received = Vec::new();
+        while let Some(frame) = reader.read_frames(&mut s, 2 * FRAME).unwrap() {
+            received.extend(frame);
+        }
+        assert_eq!(received, contents);
+        assert_eq!(&s.get_ref()[s.position() as usize..], b"next");
+    }
+
+    // Nothing past the announced length is framed, and a last frame that lies about its length is refused
+    assert!(ChunkWriter::new(3).encode(b"four").is_err());
+    let mut bad = std::io::Cursor::new((FRAME as u32 + 1).to_be_bytes());
+    assert!(ChunkReader::new(1).read_frame(&mut bad).is_err());
+    let mut short = std::io::Cursor::new(vec![0u8; 10]);
+    assert!(ChunkReader::new(2).read_frame(&mut short).is_err());
+}
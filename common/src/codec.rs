@@ -0,0 +1,91 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::str::FromStr;
+
+// How messages, and the listings that follow some of them, are encoded on the wire. Each side lists the ones it speaks at Connect.
+// bincode is not offered, since it cannot carry the free-form JSON values in a message's data.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack
+}
+impl Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MessagePack),
+            _ => Err(format!("unknown codec '{s}'"))
+        }
+    }
+}
+impl Codec {
+    // MessagePack is offered ahead of JSON, since it is smaller on the wire and quicker to decode
+    pub fn supported() -> Vec<Codec> {
+        vec![Self::MessagePack, Self::Json]
+    }
+
+    // The first codec the peer listed that this side speaks. JSON is always understood, so it is the fallback.
+    pub fn negotiate(ours: &[Codec], theirs: &[Codec]) -> Codec {
+        theirs.iter().find(|x| ours.contains(x)).copied().unwrap_or_default()
+    }
+
+    // Frames carry the magic of the codec their payload is in, so a reader never has to be told which to expect
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Self::Json => *b"HRMS",
+            Self::MessagePack => *b"HRMP"
+        }
+    }
+    pub fn from_magic(magic: &[u8]) -> Option<Codec> {
+        [Self::Json, Self::MessagePack].into_iter().find(|x| x.magic() == magic)
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string())
+        }
+    }
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+        }
+    }
+}
+
+#[test]
+fn test_codec_round_trip() {
+    use std::collections::BTreeMap;
+
+    assert_eq!(Codec::negotiate(&Codec::supported(), &[Codec::MessagePack, Codec::Json]), Codec::MessagePack);
+    assert_eq!(Codec::negotiate(&[Codec::Json], &[Codec::MessagePack]), Codec::Json);
+    assert_eq!(Codec::negotiate(&Codec::supported(), &[]), Codec::Json);
+    assert_eq!("msgpack".parse::<Codec>().unwrap(), Codec::MessagePack);
+    assert_eq!(serde_json::to_string(&Codec::MessagePack).unwrap(), "\"msgpack\"");
+    assert_eq!(Codec::from_magic(b"HRMP"), Some(Codec::MessagePack));
+    assert_eq!(Codec::from_magic(b"NOPE"), None);
+
+    let listing: BTreeMap<String, Vec<u64>> = (0..100).map(|x| (format!("file-{x}.txt"), vec![x, x * 1024])).collect();
+    let json = Codec::Json.encode(&listing).unwrap();
+    let packed = Codec::MessagePack.encode(&listing).unwrap();
+    assert!(packed.len() < json.len());
+    assert_eq!(Codec::MessagePack.decode::<BTreeMap<String, Vec<u64>>>(&packed).unwrap(), listing);
+    assert!(Codec::MessagePack.decode::<BTreeMap<String, Vec<u64>>>(&json).is_err());
+}
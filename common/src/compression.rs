@@ -0,0 +1,260 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::{Debug, Display};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use flate2::write::{GzDecoder, GzEncoder};
+
+// A compressed block larger than this is refused rather than read into memory
+pub const MAX_COMPRESSED_BLOCK: u32 = 16 * 1024 * 1024;
+// A block header of this length means the sender gave up part way through. Nothing follows it.
+pub const CANCEL_BLOCK: u32 = u32::MAX;
+
+// Ways file frames can be compressed on the wire. Each side lists the ones it speaks at Connect, in the order it prefers them.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd
+}
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown compression '{s}'"))
+        }
+    }
+}
+impl Compression {
+    // Everything this build can speak, most preferred first
+    pub fn supported() -> Vec<Compression> {
+        vec![Self::Zstd, Self::Gzip]
+    }
+
+    // The compressions both sides listed, in the order the peer prefers them
+    pub fn negotiate(ours: &[Compression], theirs: &[Compression]) -> Vec<Compression> {
+        let mut result: Vec<Compression> = Vec::new();
+        for c in theirs.iter().filter(|x| ours.contains(x)) {
+            if !result.contains(c) {
+                result.push(*c);
+            }
+        }
+
+        result
+    }
+}
+
+// Compresses a stream a chunk at a time. Whatever the encoder has produced so far is handed back after each chunk, so a file is never held in memory.
+pub enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>)
+}
+impl Debug for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gzip(_) => write!(f, "Compressor(gzip)"),
+            Self::Zstd(_) => write!(f, "Compressor(zstd)")
+        }
+    }
+}
+impl Compressor {
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        match compression {
+            Compression::Gzip => Ok(Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))),
+            Compression::Zstd => Ok(Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), zstd::DEFAULT_COMPRESSION_LEVEL)?))
+        }
+    }
+
+    // May return nothing, when the encoder is still filling its window
+    pub fn compress(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(e) => {
+                e.write_all(chunk)?;
+                Ok(std::mem::take(e.get_mut()))
+            },
+            Self::Zstd(e) => {
+                e.write_all(chunk)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+    // Whatever is left, including the stream's trailer
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(e) => e.finish(),
+            Self::Zstd(e) => e.finish()
+        }
+    }
+}
+
+// The receiving end of a Compressor
+pub enum Decompressor {
+    Gzip(GzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>)
+}
+impl Debug for Decompressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gzip(_) => write!(f, "Decompressor(gzip)"),
+            Self::Zstd(_) => write!(f, "Decompressor(zstd)")
+        }
+    }
+}
+impl Decompressor {
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        match compression {
+            Compression::Gzip => Ok(Self::Gzip(GzDecoder::new(Vec::new()))),
+            Compression::Zstd => Ok(Self::Zstd(zstd::stream::write::Decoder::new(Vec::new())?))
+        }
+    }
+
+    pub fn decompress(&mut self, block: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(d) => {
+                d.write_all(block)?;
+                Ok(std::mem::take(d.get_mut()))
+            },
+            Self::Zstd(d) => {
+                d.write_all(block)?;
+                d.flush()?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+        }
+    }
+    // Fails if the stream was cut short
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(d) => d.finish(),
+            Self::Zstd(mut d) => {
+                d.flush()?;
+                Ok(d.into_inner())
+            }
+        }
+    }
+}
+
+// The encoder's output is sent as blocks that each start with their length, since its size is not known up front. An empty block ends the stream.
+pub fn encode_block(block: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(block.len() + 4);
+    result.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    result.extend_from_slice(block);
+    result
+}
+pub fn decode_block_header(header: [u8; 4]) -> Result<u32, String> {
+    let length = u32::from_be_bytes(header);
+    if length > MAX_COMPRESSED_BLOCK {
+        return Err(format!("compressed block of {length} bytes is over the limit of {MAX_COMPRESSED_BLOCK}"));
+    }
+
+    Ok(length)
+}
+// What one block header announced
+#[derive(PartialEq, Eq, Debug)]
+pub enum Block {
+    Data(Vec<u8>),
+    End, //The empty block that ends the stream
+    Cancelled //The sender stopped, and the stream ends without the rest of the file
+}
+pub fn cancel_block() -> Vec<u8> {
+    CANCEL_BLOCK.to_be_bytes().to_vec()
+}
+pub fn read_block<S: Read>(s: &mut S) -> Result<Block, String> {
+    let mut header = [0u8; 4];
+    s.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if u32::from_be_bytes(header) == CANCEL_BLOCK {
+        return Ok(Block::Cancelled);
+    }
+
+    let mut block = vec![0; decode_block_header(header)? as usize];
+    if block.is_empty() {
+        return Ok(Block::End);
+    }
+    s.read_exact(&mut block).map_err(|e| e.to_string())?;
+    Ok(Block::Data(block))
+}
+#[cfg(feature = "async")]
+pub async fn read_block_async<S: tokio::io::AsyncRead + Unpin>(s: &mut S) -> Result<Block, String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; 4];
+    s.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+    if u32::from_be_bytes(header) == CANCEL_BLOCK {
+        return Ok(Block::Cancelled);
+    }
+
+    let mut block = vec![0; decode_block_header(header)? as usize];
+    if block.is_empty() {
+        return Ok(Block::End);
+    }
+    s.read_exact(&mut block).await.map_err(|e| e.to_string())?;
+    Ok(Block::Data(block))
+}
+// Reads and throws away the rest of a stream, so the connection is back at a frame boundary. Returns how the stream ended.
+pub fn skip_blocks<S: Read>(s: &mut S) -> Result<Block, String> {
+    loop {
+        match read_block(s)? {
+            Block::Data(_) => continue,
+            end => return Ok(end)
+        }
+    }
+}
+
+#[test]
+fn test_compression_round_trip() {
+    let contents: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+    assert_eq!(Compression::negotiate(&Compression::supported(), &[Compression::Gzip, Compression::Zstd, Compression::Gzip]), vec![Compression::Gzip, Compression::Zstd]);
+    assert_eq!(serde_json::to_string(&Compression::supported()).unwrap(), r#"["zstd","gzip"]"#);
+
+    for compression in Compression::supported() {
+        let mut compressor = Compressor::new(compression).unwrap();
+        let mut wire = Vec::new();
+        for chunk in contents.chunks(4096) {
+            let out = compressor.compress(chunk).unwrap();
+            if !out.is_empty() {
+                wire.extend(encode_block(&out));
+            }
+        }
+        wire.extend(encode_block(&compressor.finish().unwrap()));
+        wire.extend(encode_block(&[]));
+        assert!(wire.len() < contents.len() / 10, "{compression} did not compress");
+
+        let mut reader = std::io::Cursor::new(wire);
+        let mut decompressor = Decompressor::new(compression).unwrap();
+        let mut received = Vec::new();
+        while let Block::Data(block) = read_block(&mut reader).unwrap() {
+            received.extend(decompressor.decompress(&block).unwrap());
+        }
+        received.extend(decompressor.finish().unwrap());
+        assert_eq!(received, contents);
+    }
+
+    // A truncated stream is not mistaken for a whole one
+    let mut compressor = Compressor::new(Compression::Gzip).unwrap();
+    let mut partial = compressor.compress(&contents).unwrap();
+    partial.extend(compressor.finish().unwrap());
+    let mut decompressor = Decompressor::new(Compression::Gzip).unwrap();
+    decompressor.decompress(&partial[..partial.len() / 2]).unwrap();
+    assert!(decompressor.finish().is_err());
+    assert!(read_block(&mut std::io::Cursor::new((MAX_COMPRESSED_BLOCK + 1).to_be_bytes())).is_err());
+
+    // A cancelled stream ends at its marker, and whatever comes after it is left unread
+    let mut wire = encode_block(b"abc");
+    wire.extend(cancel_block());
+    wire.extend(encode_block(b"next"));
+    let mut reader = std::io::Cursor::new(wire);
+    assert_eq!(skip_blocks(&mut reader), Ok(Block::Cancelled));
+    assert_eq!(read_block(&mut reader), Ok(Block::Data(b"next".to_vec())));
+}
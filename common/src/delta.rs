@@ -0,0 +1,364 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::checksum::{Checksum, ChecksumAlgorithm, ChecksumHasher, to_hex};
+
+pub const MIN_BLOCK_SIZE: u32 = 2 * 1024;
+pub const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+// Past this many blocks the block size grows instead, so a signature always fits in one message
+const MAX_BLOCKS: u64 = 100_000;
+// Unmatched bytes are sent once this many have built up, which bounds the memory a delta takes to make
+const MAX_LITERAL: usize = 64 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+
+// A delta is laid out as MAGIC | block size (u32) | operations | END. Every number is big endian.
+const DELTA_MAGIC: [u8; 4] = *b"HDLT";
+const OP_COPY: u8 = 0; //Followed by the index of a block of the basis (u64)
+const OP_LITERAL: u8 = 1; //Followed by a length (u32) and that many bytes
+const OP_END: u8 = 2;
+
+// Roughly the square root of the file, like rsync, so small files get small blocks and large files do not get huge signatures
+pub fn block_size_for(size: u64) -> u32 {
+    let root = (size as f64).sqrt() as u64;
+    root.max(size.div_ceil(MAX_BLOCKS)).clamp(MIN_BLOCK_SIZE as u64, MAX_BLOCK_SIZE as u64) as u32
+}
+
+// The weak checksum of a window, which can be moved along one byte at a time without reading the window again
+#[derive(Clone, Copy, Debug)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32
+}
+impl RollingChecksum {
+    pub fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, x) in window.iter().enumerate() {
+            a = a.wrapping_add(*x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+
+        Self {
+            a: a & 0xffff,
+            b: b & 0xffff,
+            len
+        }
+    }
+
+    // Drops the first byte of the window and takes in the next one
+    pub fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(incoming as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+    pub fn digest(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+fn strong_checksum(block: &[u8]) -> String {
+    to_hex(&Sha256::digest(block)[..16])
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String
+}
+
+// What the receiver already holds, block by block. The sender only sends the parts of its file that do not match one of these blocks.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Signature {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>
+}
+impl Signature {
+    pub fn of<R: Read>(reader: &mut R, block_size: u32) -> std::io::Result<Self> {
+        let block_size = block_size.clamp(1, MAX_BLOCK_SIZE);
+        let mut blocks = Vec::new();
+        let mut buff = vec![0u8; block_size as usize];
+        loop {
+            let len = read_full(reader, &mut buff)?;
+            if len == 0 {
+                break;
+            }
+
+            blocks.push(
+                BlockSignature {
+                    weak: RollingChecksum::new(&buff[..len]).digest(),
+                    strong: strong_checksum(&buff[..len])
+                }
+            );
+            if len < buff.len() {
+                break;
+            }
+        }
+
+        Ok(
+            Self {
+                block_size,
+                blocks
+            }
+        )
+    }
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let block_size = block_size_for(file.metadata()?.len());
+        Self::of(&mut BufReader::new(file), block_size)
+    }
+
+    // A short last block never matches a whole window, so it is sent again if it is still there
+    fn index(&self) -> HashMap<u32, Vec<usize>> {
+        let mut result: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            result.entry(block.weak).or_default().push(i);
+        }
+
+        result
+    }
+}
+
+// How much of a file a delta sends, and how much it takes from the receiver's copy
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct DeltaStats {
+    pub matched_blocks: u64,
+    pub literal_bytes: u64
+}
+
+fn read_full<R: Read>(reader: &mut R, buff: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buff.len() {
+        match reader.read(&mut buff[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(filled)
+}
+fn write_literal<W: Write>(out: &mut W, literal: &[u8], stats: &mut DeltaStats) -> std::io::Result<()> {
+    if literal.is_empty() {
+        return Ok(());
+    }
+
+    out.write_all(&[OP_LITERAL])?;
+    out.write_all(&(literal.len() as u32).to_be_bytes())?;
+    out.write_all(literal)?;
+    stats.literal_bytes += literal.len() as u64;
+    Ok(())
+}
+
+// Writes the operations that turn the file the signature describes into the source. The source is read once, front to back.
+pub fn compute_delta<R: Read, W: Write>(signature: &Signature, source: &mut R, out: &mut W) -> std::io::Result<DeltaStats> {
+    let block_size = signature.block_size as usize;
+    let index = signature.index();
+    let mut stats = DeltaStats::default();
+
+    out.write_all(&DELTA_MAGIC)?;
+    out.write_all(&signature.block_size.to_be_bytes())?;
+
+    // With nothing to match against, the whole source is sent
+    if index.is_empty() {
+        let mut literal = vec![0u8; MAX_LITERAL];
+        loop {
+            let len = read_full(source, &mut literal)?;
+            if len == 0 {
+                break;
+            }
+            write_literal(out, &literal[..len], &mut stats)?;
+        }
+
+        out.write_all(&[OP_END])?;
+        out.flush()?;
+        return Ok(stats);
+    }
+
+    // buff holds the pending literal, buff[..start], then the window, buff[start..start + block_size]
+    let mut buff: Vec<u8> = Vec::new();
+    let mut start = 0;
+    let mut rolling: Option<RollingChecksum> = None;
+    let mut eof = false;
+    loop {
+        // Rolling needs the byte after the window as well
+        if !eof && buff.len() <= start + block_size {
+            let filled = buff.len();
+            buff.resize(filled + READ_SIZE, 0);
+            let len = read_full(source, &mut buff[filled..])?;
+            buff.truncate(filled + len);
+            eof = len == 0;
+            continue;
+        }
+        if buff.len() < start + block_size {
+            break;
+        }
+
+        let window = &buff[start..start + block_size];
+        let weak = *rolling.get_or_insert_with(|| RollingChecksum::new(window));
+        let matched = index.get(&weak.digest()).and_then(|candidates| {
+            let strong = strong_checksum(window);
+            candidates.iter().find(|x| signature.blocks[**x].strong == strong).copied()
+        });
+
+        match matched {
+            Some(block) => {
+                write_literal(out, &buff[..start], &mut stats)?;
+                out.write_all(&[OP_COPY])?;
+                out.write_all(&(block as u64).to_be_bytes())?;
+                stats.matched_blocks += 1;
+
+                buff.drain(..start + block_size);
+                start = 0;
+                rolling = None;
+            },
+            None if buff.len() > start + block_size => {
+                if let Some(r) = rolling.as_mut() {
+                    r.roll(buff[start], buff[start + block_size]);
+                }
+                start += 1;
+
+                if start >= MAX_LITERAL {
+                    write_literal(out, &buff[..start], &mut stats)?;
+                    buff.drain(..start);
+                    start = 0;
+                }
+            },
+            None => break
+        }
+    }
+
+    // Whatever is left never matched, or is shorter than a block
+    for literal in buff.chunks(MAX_LITERAL) {
+        write_literal(out, literal, &mut stats)?;
+    }
+    out.write_all(&[OP_END])?;
+    out.flush()?;
+    Ok(stats)
+}
+
+// Rebuilds the source from a delta and the receiver's copy, handing each piece to write in order. Returns the number of bytes rebuilt.
+pub fn apply_delta<D, B, P>(delta: &mut D, basis: &mut B, write: &mut P) -> std::io::Result<u64>
+    where D: Read, B: Read + Seek, P: FnMut(&[u8]) -> std::io::Result<()> {
+    let invalid = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string());
+
+    let mut header = [0u8; 8];
+    delta.read_exact(&mut header)?;
+    if header[..4] != DELTA_MAGIC {
+        return Err(invalid("not a delta"));
+    }
+    let block_size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        return Err(invalid("the delta's block size is out of range"));
+    }
+
+    let mut block = vec![0u8; block_size as usize];
+    let mut total = 0;
+    loop {
+        let mut op = [0u8; 1];
+        delta.read_exact(&mut op)?;
+        match op[0] {
+            OP_COPY => {
+                let mut number = [0u8; 8];
+                delta.read_exact(&mut number)?;
+                let offset = u64::from_be_bytes(number).checked_mul(block_size as u64).ok_or_else(|| invalid("block index out of range"))?;
+
+                basis.seek(SeekFrom::Start(offset))?;
+                basis.read_exact(&mut block).map_err(|_| invalid("the delta copies a block the basis does not have"))?;
+                write(&block)?;
+                total += block.len() as u64;
+            },
+            OP_LITERAL => {
+                let mut length = [0u8; 4];
+                delta.read_exact(&mut length)?;
+                let length = u32::from_be_bytes(length) as usize;
+                if length > MAX_LITERAL {
+                    return Err(invalid("literal is over the size limit"));
+                }
+
+                let mut literal = vec![0u8; length];
+                delta.read_exact(&mut literal)?;
+                write(&literal)?;
+                total += length as u64;
+            },
+            OP_END => return Ok(total),
+            _ => return Err(invalid("unknown delta operation"))
+        }
+    }
+}
+
+// Writes the delta between a signature and a file into another file, so it can be sent like any other
+pub fn write_delta_file<R: Read>(signature: &Signature, source: &mut R, target: &Path) -> Result<DeltaStats, String> {
+    let mut out = BufWriter::new(File::create(target).map_err(|e| e.to_string())?);
+    compute_delta(signature, source, &mut out).map_err(|e| e.to_string())
+}
+// Rebuilds a file from a received delta and the copy it was made against, hashing it on the way. The target must not be the basis.
+pub fn apply_delta_file(delta: &Path, basis: &Path, target: &Path, algorithm: ChecksumAlgorithm) -> Result<Checksum, String> {
+    let mut delta = BufReader::new(File::open(delta).map_err(|e| e.to_string())?);
+    let mut basis = File::open(basis).map_err(|e| e.to_string())?;
+    let mut out = BufWriter::new(File::create(target).map_err(|e| e.to_string())?);
+
+    let mut hasher = ChecksumHasher::new(algorithm);
+    apply_delta(&mut delta, &mut basis, &mut |x| {
+        hasher.update(x);
+        out.write_all(x)
+    }).map_err(|e| format!("unable to apply the delta because '{e}'"))?;
+
+    out.flush().map_err(|e| e.to_string())?;
+    Ok(hasher.finish())
+}
+
+#[test]
+fn test_delta_round_trip() {
+    use std::io::Cursor;
+
+    // The rolling checksum agrees with one computed from scratch after every step
+    let data: Vec<u8> = (0..5000u32).map(|x| (x.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let mut rolling = RollingChecksum::new(&data[..64]);
+    for i in 0..1000 {
+        rolling.roll(data[i], data[i + 64]);
+        assert_eq!(rolling.digest(), RollingChecksum::new(&data[i + 1..i + 65]).digest());
+    }
+
+    let basis: Vec<u8> = (0..300_000u32).map(|x| (x.wrapping_mul(2654435761) >> 11) as u8).collect();
+    let mut changed = basis.clone();
+    changed[150_000..150_010].copy_from_slice(b"0123456789");
+    changed.splice(1000..1000, b"inserted".iter().copied());
+    changed.truncate(250_000);
+
+    let rebuild = |basis: &[u8], target: &[u8]| -> (Vec<u8>, DeltaStats, usize) {
+        let signature = Signature::of(&mut Cursor::new(basis), block_size_for(basis.len() as u64)).unwrap();
+        let mut delta = Vec::new();
+        let stats = compute_delta(&signature, &mut Cursor::new(target), &mut delta).unwrap();
+
+        let mut rebuilt = Vec::new();
+        let total = apply_delta(&mut Cursor::new(&delta), &mut Cursor::new(basis), &mut |x| {
+            rebuilt.extend_from_slice(x);
+            Ok(())
+        }).unwrap();
+        assert_eq!(total, rebuilt.len() as u64);
+        (rebuilt, stats, delta.len())
+    };
+
+    // Only the blocks around each change are sent
+    let (rebuilt, stats, size) = rebuild(&basis, &changed);
+    assert_eq!(rebuilt, changed);
+    assert!(stats.matched_blocks > 0 && size < changed.len() / 10, "{stats:?}, {size} bytes");
+
+    // Nothing in common, and nothing to compare against
+    assert_eq!(rebuild(&basis, b"short").0, b"short");
+    assert_eq!(rebuild(b"", &changed).0, changed);
+    assert_eq!(rebuild(&basis, b"").0, b"");
+
+    // A delta that copies blocks the basis does not have is refused
+    let signature = Signature::of(&mut Cursor::new(&basis), MIN_BLOCK_SIZE).unwrap();
+    let mut delta = Vec::new();
+    compute_delta(&signature, &mut Cursor::new(&basis), &mut delta).unwrap();
+    assert!(apply_delta(&mut Cursor::new(&delta), &mut Cursor::new(&basis[..1000]), &mut |_| Ok(())).is_err());
+    assert!(apply_delta(&mut Cursor::new(&delta[..delta.len() - 1]), &mut Cursor::new(&basis), &mut |_| Ok(())).is_err());
+}
@@ -0,0 +1,92 @@
+use std::fmt::{Debug, Display};
+
+use crate::http_codes::HttpCodes;
+
+// Why an operation failed, so callers can branch on the cause instead of matching on text
+pub enum HermesError {
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    Protocol(String), //The peer sent something malformed or unexpected
+    Auth(String),
+    NotFound(String),
+    Conflict(String), //Duplicates, legal holds, and anything else that clashes with what is already there
+    NotOpen(String), //The store has no file behind it yet
+    Invalid(String) //A value the operation cannot accept
+}
+impl Debug for HermesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Io({e:?})"),
+            Self::Serde(e) => write!(f, "Serde({e:?})"),
+            Self::Protocol(s) => write!(f, "Protocol({s})"),
+            Self::Auth(s) => write!(f, "Auth({s})"),
+            Self::NotFound(s) => write!(f, "NotFound({s})"),
+            Self::Conflict(s) => write!(f, "Conflict({s})"),
+            Self::NotOpen(s) => write!(f, "NotOpen({s})"),
+            Self::Invalid(s) => write!(f, "Invalid({s})")
+        }
+    }
+}
+impl Display for HermesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Serde(e) => write!(f, "parsing error '{e}'"),
+            Self::Protocol(s) => write!(f, "protocol error: {s}"),
+            Self::Auth(s) | Self::NotFound(s) | Self::Conflict(s) | Self::NotOpen(s) | Self::Invalid(s) => write!(f, "{s}")
+        }
+    }
+}
+impl std::error::Error for HermesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Serde(e) => Some(e),
+            _ => None
+        }
+    }
+}
+impl From<std::io::Error> for HermesError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<serde_json::Error> for HermesError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Serde(value)
+    }
+}
+// Code that still reports failures as text can keep using ?
+impl From<HermesError> for String {
+    fn from(value: HermesError) -> Self {
+        value.to_string()
+    }
+}
+impl HermesError {
+    // The status a server should answer with when this error ends a request
+    pub fn status(&self) -> HttpCodes {
+        match self {
+            Self::Io(e) if e.kind() == std::io::ErrorKind::NotFound => HttpCodes::NotFound,
+            Self::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => HttpCodes::Forbidden,
+            Self::Io(_) | Self::NotOpen(_) => HttpCodes::Conflict,
+            Self::Serde(_) | Self::Protocol(_) | Self::Invalid(_) => HttpCodes::BadRequest,
+            Self::Auth(_) => HttpCodes::Unauthorized,
+            Self::NotFound(_) => HttpCodes::NotFound,
+            Self::Conflict(_) => HttpCodes::Conflict
+        }
+    }
+}
+
+#[test]
+fn test_hermes_error_conversions() {
+    let missing: HermesError = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+    assert_eq!(missing.status(), HttpCodes::NotFound);
+    assert!(std::error::Error::source(&missing).is_some());
+
+    let parse: HermesError = serde_json::from_str::<u32>("nope").unwrap_err().into();
+    assert!(matches!(parse, HermesError::Serde(_)));
+    assert_eq!(parse.status(), HttpCodes::BadRequest);
+
+    let text: String = HermesError::Conflict(String::from("already open")).into();
+    assert_eq!(text, "already open");
+}
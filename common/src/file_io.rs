@@ -1,17 +1,30 @@
 use serde::{Serialize, Deserialize};
-use std::fs::File;
-use std::net::TcpStream;
+use std::fs::{File, OpenOptions};
 use std::{fmt::{Debug, Display}, str::FromStr};
-use std::path::Path;
-use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, SeekFrom, Write};
 
-#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+use crate::error::HermesError;
+use crate::checksum::{Checksum, ChecksumHasher, hash_reader};
+use crate::compression::{Block, Compression, Compressor, Decompressor, cancel_block, encode_block, read_block};
+use crate::progress::Progress;
+use crate::chunking::{ChunkReader, ChunkWriter};
+use crate::sniff;
+#[cfg(feature = "async")]
+use crate::tuning::FrameSizeTuner;
+#[cfg(feature = "async")]
+use std::time::Instant;
+#[cfg(feature = "async")]
+use crate::compression::read_block_async;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub enum FileType {
     Text,
     Audio,
     Video,
     Binary,
-    Archive
+    Archive,
+    Other(String) //The MIME type of anything that fits none of the others, such as an image
 }
 impl Display for FileType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -23,7 +36,8 @@ impl Display for FileType {
                 Self::Audio => "audio",
                 Self::Video => "video",
                 Self::Archive => "archive",
-                Self::Binary => "binary"
+                Self::Binary => "binary",
+                Self::Other(mime) => mime
             }
         )
     }
@@ -37,33 +51,38 @@ impl FromStr for FileType{
             "video" => Ok(Self::Video),
             "binary" => Ok(Self::Binary),
             "archive" => Ok(Self::Archive),
+            s if s.contains('/') => Ok(Self::Other(s.to_string())),
             _ => Err(format!("could not deduce file type from '{s}'"))
         }
     }
 }
 
+// Goes by the extension alone, for paths that need not exist
 pub fn get_file_type(path: &Path) -> Option<FileType> {
-    let extr = path.extension()?.to_str()?;
-    match extr {
-        "mp4" | "mov" | "avi" | "wvm" => Some(FileType::Video),
-        "mp3" | "wav" | "aac" | "flac" | "aiff" => Some(FileType::Audio),
-        "pdf" | "docx" | "pptx" | "xlsx" => Some(FileType::Binary),
-        "tar" | "gz" | "zip" => Some(FileType::Archive),
-        "txt" | "rtf" | "md" => Some(FileType::Text),
-        _ => None
+    sniff::from_extension(path.extension()?.to_str()?)
+}
+// Looks at the start of the file as well, so files with a missing or misleading extension are still recognised
+pub fn detect_file_type(path: &Path) -> Option<FileType> {
+    let mut contents = Vec::with_capacity(sniff::SNIFF_LENGTH);
+    if let Ok(f) = File::open(path) {
+        let _ = f.take(sniff::SNIFF_LENGTH as u64).read_to_end(&mut contents);
     }
+
+    sniff::detect(path.extension().and_then(|x| x.to_str()), &contents)
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum DirectoryContent{
     File(FileInfo),
-    Dir(DirectoryInfo)
+    Dir(DirectoryInfo),
+    Symlink(LinkInfo)
 }
 impl Debug for DirectoryContent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::File(fi) => write!(f, "File '{}'", fi.name()),
-            Self::Dir(d) => write!(f, "Directory '{}' (Len {})", d.name(), d.contents().len())
+            Self::Dir(d) => write!(f, "Directory '{}' (Len {})", d.name(), d.contents().len()),
+            Self::Symlink(l) => write!(f, "Link '{}'", l.name())
         }
     }
 }
@@ -71,7 +90,8 @@ impl Display for DirectoryContent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::File(fi) => write!(f, "{}", fi),
-            Self::Dir(d) => write!(f, "{}", d)
+            Self::Dir(d) => write!(f, "{}", d),
+            Self::Symlink(l) => write!(f, "{}", l)
         }
     }
 }
@@ -82,6 +102,16 @@ impl DirectoryContent {
     pub fn is_directory(&self) -> bool {
         matches!(self, Self::Dir(_))
     }
+    pub fn is_symlink(&self) -> bool {
+        matches!(self, Self::Symlink(_))
+    }
+    pub fn name(&self) -> &str {
+        match self {
+            Self::File(f) => f.name(),
+            Self::Dir(d) => d.name(),
+            Self::Symlink(l) => l.name()
+        }
+    }
 
     pub fn as_file(self) -> Option<FileInfo> {
         match self {
@@ -107,6 +137,117 @@ impl DirectoryContent {
             _ => None
         }
     }
+    pub fn as_symlink_ref(&self) -> Option<&LinkInfo> {
+        match self {
+            Self::Symlink(l) => Some(l),
+            _ => None
+        }
+    }
+
+    // Describes a file, folder, or link on disk, with a folder's contents filled in for depth more levels and left empty past them.
+    // Links are never followed, and are described with where they point as written. Special files, and anything that cannot be read, give None.
+    // See DirectoryInfo::from_path for what the lookup is for.
+    pub fn from_path(path: &Path, depth: u32, lookup: &FileLookup) -> Option<Self> {
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+        let name = path.file_name()?.to_string_lossy().to_string();
+        if metadata.is_symlink() {
+            Some(Self::Symlink(LinkInfo::new(name, std::fs::read_link(path).ok().map(|x| x.to_string_lossy().to_string()))))
+        }
+        else if metadata.is_dir() {
+            let contents = match depth {
+                0 => Vec::new(),
+                d => DirectoryInfo::from_path(path, d - 1, lookup).ok()?.contents
+            };
+            Some(Self::Dir(DirectoryInfo::new(name, contents)))
+        }
+        else if metadata.is_file() {
+            Some(Self::File(lookup(path, &metadata).unwrap_or_else(|| FileInfo::from_disk(path, &metadata))))
+        }
+        else {
+            None
+        }
+    }
+}
+
+// A link inside a folder. Whoever sends one decides what its target may reveal, so it can be left out.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct LinkInfo {
+    name: String,
+    target: Option<String> //Where the link points, such as '/docs/a.txt'
+}
+impl Display for LinkInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.target {
+            Some(t) => write!(f, "Link: {} -> {}", self.name, t),
+            None => write!(f, "Link: {}", self.name)
+        }
+    }
+}
+impl LinkInfo {
+    pub fn new(name: String, target: Option<String>) -> Self {
+        Self {
+            name,
+            target
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+    pub fn set_target(&mut self, target: Option<String>) {
+        self.target = target;
+    }
+}
+
+// Describes a file its caller knows more about than the disk does, such as who owns it, or gives None to have it described from the disk alone
+pub type FileLookup<'a> = dyn Fn(&Path, &std::fs::Metadata) -> Option<FileInfo> + 'a;
+
+// Where an upload came from, as told by the uploading client. None of it is verified by the server, so it must never be used for access decisions.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct Provenance {
+    pub hostname: Option<String>,
+    pub client_version: Option<String>,
+    pub local_path: Option<String>
+}
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hostname = self.hostname.as_deref().unwrap_or("unknown");
+        let client_version = self.client_version.as_deref().unwrap_or("unknown");
+        let local_path = self.local_path.as_deref().unwrap_or("unknown");
+
+        write!(f, "Provenance (client-supplied, untrusted):\n\t\tHost: {}\n\t\tClient Version: {}\n\t\tLocal Path: {}", hostname, client_version, local_path)
+    }
+}
+impl Provenance {
+    pub fn new(hostname: Option<String>, client_version: Option<String>, local_path: Option<String>) -> Self {
+        Self {
+            hostname,
+            client_version,
+            local_path
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.client_version.is_none() && self.local_path.is_none()
+    }
+}
+
+// How a client encrypted a file before uploading it. The server keeps it as it was told, since it never holds the key and cannot check it.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Encryption {
+    pub format: u32, //The layout of the contents, so a client knows whether it can read them
+    pub hidden_name: bool //Whether the name on the server is encrypted too
+}
+impl Display for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.hidden_name {
+            true => write!(f, "Encrypted (format {}, name hidden)", self.format),
+            false => write!(f, "Encrypted (format {})", self.format)
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
@@ -114,7 +255,23 @@ pub struct FileInfo {
     name: String,
     kind: FileType,
     owner: String,
-    size: u32
+    size: u64,
+    #[serde(default)]
+    immutable: bool,
+    #[serde(default)]
+    provenance: Option<Provenance>,
+    #[serde(default)]
+    modified: Option<u64>, //Unix seconds, used by clients to notice changes made while they were away
+    #[serde(default)]
+    created: Option<u64>, //Unix seconds, where the filesystem records it
+    #[serde(default)]
+    permissions: Option<u32>, //The Unix mode bits, or 0o444 for a read-only file elsewhere
+    #[serde(default)]
+    checksum: Option<Checksum>, //Only when the server knows it for the current contents
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    encryption: Option<Encryption> //Only for files a client encrypted before uploading
 }
 impl Debug for FileInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -123,31 +280,126 @@ impl Debug for FileInfo {
 }
 impl Display for FileInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}\n\tSize: {}\n\tType: {}\n\tOwner: {}\n\t", &self.name, &self.size, &self.kind, &self.owner)
+        write!(f, "{}\n\tSize: {}\n\tType: {}\n\tOwner: {}\n\t", &self.name, &self.size, &self.kind, &self.owner)?;
+        if self.immutable {
+            write!(f, "Immutable (legal hold)\n\t")?;
+        }
+        if let Some(p) = self.permissions {
+            write!(f, "Permissions: {:o}\n\t", p)?;
+        }
+        if let Some(c) = self.checksum.as_ref() {
+            write!(f, "Checksum: {}\n\t", c)?;
+        }
+        if let Some(e) = self.encryption.as_ref() {
+            write!(f, "{}\n\t", e)?;
+        }
+        if let Some(p) = self.provenance.as_ref() {
+            write!(f, "{}\n\t", p)?;
+        }
+
+        Ok(())
     }   
 }
 impl FileInfo {
-    pub fn new(name: String, owner: String, kind: FileType, size: u32) -> Self {
+    pub fn new(name: String, owner: String, kind: FileType, size: u64) -> Self {
         Self {
+            hidden: name.starts_with('.'),
             name,
             owner, 
             kind,
-            size
+            size,
+            immutable: false,
+            provenance: None,
+            modified: None,
+            created: None,
+            permissions: None,
+            checksum: None,
+            encryption: None
         }
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
-    pub fn kind(&self) -> FileType {
-        self.kind
+    // For clients that show a name other than the one stored, such as one they decrypted
+    pub fn set_name(&mut self, name: String) {
+        self.hidden = name.starts_with('.');
+        self.name = name;
+    }
+    pub fn kind(&self) -> &FileType {
+        &self.kind
     }
     pub fn owner(&self) -> &str {
         &self.owner
     }
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
+    pub fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+    pub fn set_immutable(&mut self, immutable: bool) {
+        self.immutable = immutable;
+    }
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+    pub fn set_provenance(&mut self, provenance: Option<Provenance>) {
+        self.provenance = provenance;
+    }
+    pub fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+    pub fn set_modified(&mut self, modified: Option<u64>) {
+        self.modified = modified;
+    }
+    pub fn created(&self) -> Option<u64> {
+        self.created
+    }
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+    pub fn checksum(&self) -> Option<&Checksum> {
+        self.checksum.as_ref()
+    }
+    pub fn set_checksum(&mut self, checksum: Option<Checksum>) {
+        self.checksum = checksum;
+    }
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+    pub fn set_encryption(&mut self, encryption: Option<Encryption>) {
+        self.encryption = encryption;
+    }
+    // Dot files, as Unix hides them
+    // A file nothing is known about but what the disk says. Anyone owns it, and its type is taken from its extension alone, so nothing is read.
+    pub fn from_disk(path: &Path, metadata: &std::fs::Metadata) -> Self {
+        let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+
+        let mut result = Self::new(name, String::from("any"), get_file_type(path).unwrap_or(FileType::Binary), metadata.len());
+        result.set_metadata(metadata);
+        result
+    }
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    // Fills in the times and permissions from what the filesystem says about the file
+    pub fn set_metadata(&mut self, metadata: &std::fs::Metadata) {
+        let secs = |x: std::io::Result<std::time::SystemTime>| x.ok()?.duration_since(std::time::UNIX_EPOCH).ok().map(|x| x.as_secs());
+        self.modified = secs(metadata.modified());
+        self.created = secs(metadata.created());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.permissions = Some(metadata.permissions().mode() & 0o7777);
+        }
+        #[cfg(not(unix))]
+        {
+            self.permissions = Some(if metadata.permissions().readonly() { 0o444 } else { 0o644 });
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
@@ -167,6 +419,21 @@ impl Display for DirectoryInfo {
     }
 }
 impl DirectoryInfo {
+    pub fn new(name: String, contents: Vec<DirectoryContent>) -> Self {
+        Self {
+            name,
+            contents
+        }
+    }
+    // Lists a folder on disk by name, with the folders inside filled in for depth more levels. Only the folder itself must be readable.
+    // Each file is described by the lookup, so a caller can fill in owners and checksums it keeps elsewhere, and from the disk when it gives None.
+    pub fn from_path(path: &Path, depth: u32, lookup: &FileLookup) -> std::io::Result<Self> {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?.flatten().map(|x| x.path()).collect();
+        entries.sort();
+
+        let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        Ok(Self::new(name, entries.iter().filter_map(|x| DirectoryContent::from_path(x, depth, lookup)).collect()))
+    }
 
     pub fn name(&self) -> &str {
         &self.name
@@ -174,6 +441,9 @@ impl DirectoryInfo {
     pub fn contents(&self) -> &Vec<DirectoryContent> {
         &self.contents
     }
+    pub fn into_contents(self) -> Vec<DirectoryContent> {
+        self.contents
+    }
     pub fn get_files(&self) -> Vec<&FileInfo> {
         self.contents.iter().filter(|x| x.is_file()).map(|x| x.as_file_ref().unwrap()).collect()
     }
@@ -187,7 +457,8 @@ impl DirectoryInfo {
         for item in self.contents {
             match item {
                 DirectoryContent::File(f) => files.push(f),
-                DirectoryContent::Dir(d) => dirs.push(d)
+                DirectoryContent::Dir(d) => dirs.push(d),
+                DirectoryContent::Symlink(_) => {}
             }
         }
 
@@ -200,7 +471,8 @@ impl DirectoryInfo {
         for item in &self.contents {
             match item {
                 DirectoryContent::File(f) => files.push(f),
-                DirectoryContent::Dir(d) => dirs.push(d)
+                DirectoryContent::Dir(d) => dirs.push(d),
+                DirectoryContent::Symlink(_) => {}
             }
         }
 
@@ -216,79 +488,313 @@ impl DirectoryInfo {
     pub fn set_content(&mut self, items: Vec<DirectoryContent>) {
         self.contents = items;
     }
+
+    // Every file in the listing and the folders inside it, as paths beneath base, which is where the listing was taken
+    pub fn file_paths(&self, base: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for item in &self.contents {
+            match item {
+                DirectoryContent::File(f) => paths.push(base.join(f.name())),
+                DirectoryContent::Dir(d) => paths.extend(d.file_paths(&base.join(d.name()))),
+                DirectoryContent::Symlink(_) => {}
+            }
+        }
+
+        paths
+    }
 }
 
-const BUFF_SIZE: u32 = 4096;
+pub const BUFF_SIZE: u64 = 4096;
 
-pub fn read_file_for_network(path: &Path) -> Option<Vec<Vec<u8>>> {
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => return None
-    };
+// Matches a file name against a glob, where '*' is any run of characters and '?' is any single one
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
 
-    let mut buff = String::new();
-    if file.read_to_string(&mut buff).is_err() {
-        return None;
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; //Where the last '*' was, and how much of the name it has taken
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    p = star + 1;
+                    n = taken + 1;
+                    backtrack = Some((star, taken + 1));
+                },
+                None => return false
+            }
+        }
     }
 
-    Some(split_binary_for_network(buff.into_bytes()))
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+pub fn frame_count_for(length: u64) -> u64 {
+    length.div_ceil(BUFF_SIZE)
+}
+
+// Reads a file (or a range of it) from disk one block at a time, so that sending a file never holds more than a block in memory and binary contents go out untouched
+pub struct FileChunkIter {
+    file: File,
+    remaining: u64,
+    chunk_size: usize
 }
-pub fn split_binary_for_network(contents: Vec<u8>) -> Vec<Vec<u8>> {
-    let windows = (contents.len() / 4096) + 1;
-    if windows == 1 {
-        vec![contents]
+impl Debug for FileChunkIter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FileChunkIter(remaining: {}, chunk size: {})", self.remaining, self.chunk_size)
     }
-    else {
-        let mut result = Vec::<Vec<u8>>::new();
-        let mut vals = contents.into_iter().peekable();
+}
+impl Iterator for FileChunkIter {
+    type Item = std::io::Result<Vec<u8>>;
 
-        while vals.peek().is_some() {
-            result.push(vals.by_ref().take(10).collect());
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
         }
 
-        result
+        let mut chunk = vec![0; std::cmp::min(self.remaining, self.chunk_size as u64) as usize];
+        match self.file.read_exact(&mut chunk) {
+            Ok(_) => {
+                self.remaining -= chunk.len() as u64;
+                Some(Ok(chunk))
+            },
+            Err(e) => {
+                // The file shrank underneath us, so nothing after this can be trusted
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
     }
 }
+// Reads the same range the chunks would, for callers that want a stream rather than blocks
+impl Read for FileChunkIter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let want = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
 
-fn receive_network_data<P>(s: &mut TcpStream, frame_count: u32, p: &mut P) -> bool 
-    where P: FnMut(&mut Vec<u8>) -> bool{
-    if frame_count == 0 {
-        return false;
+        let len = self.file.read(&mut buf[..want])?;
+        if len == 0 {
+            self.remaining = 0;
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the file shrank while it was being read"));
+        }
+
+        self.remaining -= len as u64;
+        Ok(len)
     }
+}
+impl FileChunkIter {
+    // Reads at most length bytes starting at offset, so an interrupted download can pick up where it left off
+    pub fn open(path: &Path, offset: u64, length: Option<u64>) -> Result<Self, String> {
+        let mut file = File::open(path).map_err(|e| e.to_string())?;
+        let file_len = file.metadata().map_err(|e| e.to_string())?.len();
+        if offset > file_len {
+            return Err(format!("offset {offset} is past the end of the file"));
+        }
 
-    let total_windows = frame_count as f32;
-    let mut frame_size = frame_count * BUFF_SIZE;
-    let mut windows_so_far: f32 = 0.0;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
 
-    while frame_size > 0 && windows_so_far < total_windows {
-        let mut contents = vec![0; std::mem::size_of::<u32>() * BUFF_SIZE as usize];
+        let available = file_len - offset;
+        Ok(
+            Self {
+                file,
+                remaining: length.map(|l| l.min(available)).unwrap_or(available),
+                chunk_size: BUFF_SIZE as usize
+            }
+        )
+    }
 
-        match s.read(&mut contents) {
-            Ok(len) => {
-                if !p(&mut contents) {
+    // The number of bytes that have not been read yet
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+    pub fn frame_count(&self) -> u64 {
+        frame_count_for(self.remaining)
+    }
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size.max(1);
+    }
+}
+
+// Progress is counted, and cancellation checked, after every frame. No frames at all is an empty stream.
+fn receive_network_data<S, P>(s: &mut S, frame_count: u64, progress: &mut Progress, p: &mut P) -> bool 
+    where S: Read, P: FnMut(&mut Vec<u8>) -> bool{
+    let mut reader = ChunkReader::new(frame_count);
+    loop {
+        let mut contents = match reader.read_frames(s, std::mem::size_of::<u32>() * BUFF_SIZE as usize) {
+            Ok(Some(c)) => c,
+            Ok(None) => return true,
+            Err(e) => {
+                tracing::warn!("unable to read from the connection because '{e}'");
+                return false;
+            }
+        };
+
+        let len = contents.len() as u64;
+        if !p(&mut contents) {
+            tracing::warn!("the received data could not be stored");
+            return false;
+        }
+        if !progress.advance(len) {
+            tracing::debug!("the transfer was cancelled with {} frames still to come", reader.frames_left());
+            return false;
+        }
+    }
+}
+// Compressed frames arrive as blocks that carry their own length, ending with an empty one, so the frame count is not needed to find the end
+fn receive_compressed_data<S, P>(s: &mut S, compression: Compression, progress: &mut Progress, p: &mut P) -> bool
+    where S: Read, P: FnMut(&mut Vec<u8>) -> bool {
+    let mut decompressor = match Decompressor::new(compression) {
+        Ok(d) => d,
+        Err(e) => {
+            tracing::warn!("unable to start decompressing because '{e}'");
+            return false;
+        }
+    };
+
+    loop {
+        let block = match read_block(s) {
+            Ok(Block::Data(b)) => b,
+            Ok(Block::End) => break,
+            Ok(Block::Cancelled) => {
+                tracing::debug!("the sender cancelled the transfer");
+                progress.cancel();
+                return false;
+            },
+            Err(e) => {
+                tracing::warn!("unable to read a compressed block because '{e}'");
+                return false;
+            }
+        };
+
+        match decompressor.decompress(&block) {
+            Ok(mut x) if !x.is_empty() => {
+                let len = x.len() as u64;
+                if !p(&mut x) {
+                    tracing::warn!("the received data could not be stored");
                     return false;
                 }
-
-                frame_size -= len as u32;
-                windows_so_far += len as f32 / BUFF_SIZE as f32;
+                if !progress.advance(len) {
+                    tracing::debug!("the transfer was cancelled");
+                    return false;
+                }
+            },
+            Ok(_) => { },
+            Err(e) => {
+                tracing::warn!("unable to decompress a block because '{e}'");
+                return false;
             }
-            Err(_) => return false
         }
     }
 
-    true
+    match decompressor.finish() {
+        Ok(mut x) => {
+            progress.advance(x.len() as u64);
+            x.is_empty() || p(&mut x)
+        },
+        Err(e) => {
+            tracing::warn!("unable to finish decompressing because '{e}'");
+            false
+        }
+    }
+}
+fn receive_file_data<S, P>(s: &mut S, frame_count: u64, compression: Option<Compression>, progress: &mut Progress, p: &mut P) -> bool
+    where S: Read, P: FnMut(&mut Vec<u8>) -> bool {
+    match compression {
+        Some(c) => receive_compressed_data(s, c, progress, p),
+        None => receive_network_data(s, frame_count, progress, p)
+    }
+}
+fn interrupted(progress: &Progress) -> String {
+    match progress.is_cancelled() {
+        true => String::from("the transfer was cancelled"),
+        false => String::from("the transfer was interrupted")
+    }
 }
-pub fn receive_network_file(path: &Path, s: &mut TcpStream, frame_count: u32) -> bool {
+pub fn receive_network_file<S: Read>(path: &Path, s: &mut S, frame_count: u64) -> bool {
     let mut file = match File::create(path) {
         Ok(f) => f,
-        Err(_) => return false
+        Err(e) => {
+            tracing::warn!("unable to create '{}' because '{e}'", path.display());
+            return false;
+        }
     };
 
-    receive_network_data(s, frame_count, &mut |x| -> bool {
+    receive_network_data(s, frame_count, &mut Progress::none(), &mut |x| -> bool {
         file.write(x).is_ok()
     })
 }
-pub fn receive_network_binary(s: &mut TcpStream, frame_count: u32) -> Option<Vec<u8>> {
+// Continues a partial file, discarding anything past offset before appending the incoming frames
+pub fn receive_network_file_at<S: Read>(path: &Path, s: &mut S, frame_count: u64, offset: u64) -> bool {
+    let mut file = match OpenOptions::new().write(true).create(true).truncate(false).open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::warn!("unable to open '{}' because '{e}'", path.display());
+            return false;
+        }
+    };
+
+    let existing = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => {
+            tracing::warn!("unable to read the size of '{}' because '{e}'", path.display());
+            return false;
+        }
+    };
+    if offset > existing || file.set_len(offset).is_err() || file.seek(SeekFrom::Start(offset)).is_err() {
+        tracing::warn!("unable to resume '{}' at byte {offset} of {existing}", path.display());
+        return false;
+    }
+
+    receive_network_data(s, frame_count, &mut Progress::none(), &mut |x| -> bool {
+        file.write_all(x).is_ok()
+    })
+}
+// Receives a (possibly resumed) file while hashing it. The bytes already on disk before offset are hashed first, so the result always covers the whole file.
+// If an expected checksum is given and it does not match, the file is removed, since its contents cannot be trusted.
+// The checksum covers the file as it lands on disk, after any compression is undone.
+// Progress counts only what arrives, not what was already on disk. A cancelled transfer keeps what arrived, so it can be resumed.
+pub fn receive_network_file_checked<S: Read>(path: &Path, s: &mut S, frame_count: u64, offset: u64, expected: Option<&Checksum>, compression: Option<Compression>, progress: &mut Progress) -> Result<Checksum, String> {
+    let mut hasher = ChecksumHasher::new(expected.map(|x| x.algorithm()).unwrap_or_default());
+
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(|e| e.to_string())?;
+    let hashed = hash_reader(&mut file, &mut hasher, Some(offset)).map_err(|e| e.to_string())?;
+    if hashed != offset {
+        return Err(format!("cannot resume at {offset}, only {hashed} bytes are present"));
+    }
+
+    file.set_len(offset).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+
+    let received = receive_file_data(s, frame_count, compression, progress, &mut |x| -> bool {
+        hasher.update(x);
+        file.write_all(x).is_ok()
+    });
+    if !received {
+        return Err(interrupted(progress));
+    }
+    progress.finish();
+
+    let actual = hasher.finish();
+    match expected {
+        Some(e) if *e != actual => {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            Err(format!("checksum mismatch, expected '{}' but received '{}'", e, actual))
+        },
+        _ => Ok(actual)
+    }
+}
+pub fn receive_network_binary<S: Read>(s: &mut S, frame_count: u64) -> Option<Vec<u8>> {
     let mut result = Vec::<u8>::new();
 
     let mut collect = |x: &mut Vec<u8>| -> bool {
@@ -296,13 +802,301 @@ pub fn receive_network_binary(s: &mut TcpStream, frame_count: u32) -> Option<Vec
         true
     };
 
-    if !receive_network_data(s, frame_count, &mut collect) {
+    if !receive_network_data(s, frame_count, &mut Progress::none(), &mut collect) {
         None
     } else {
         Some(result)
     } 
 }
 
+// What goes on the wire for one chunk of a file. A compressor may hold on to a chunk, and then nothing is sent for it yet.
+fn encode_chunk(compressor: Option<&mut Compressor>, writer: &mut ChunkWriter, chunk: Vec<u8>) -> Result<Vec<u8>, String> {
+    match compressor {
+        Some(c) => c.compress(&chunk).map(|x| if x.is_empty() { x } else { encode_block(&x) }).map_err(|e| e.to_string()),
+        None => writer.encode(&chunk)
+    }
+}
+// The rest of a compressed stream, and the empty block that ends it
+fn finish_chunks(compressor: Option<Compressor>) -> std::io::Result<Vec<u8>> {
+    match compressor {
+        Some(c) => {
+            let mut result = encode_block(&c.finish()?);
+            result.extend(encode_block(&[]));
+            Ok(result)
+        },
+        None => Ok(Vec::new())
+    }
+}
+
+// Returns the number of bytes read from the file, which is more than went on the wire when the frames are compressed
+pub fn send_network_file<S: Write>(s: &mut S, chunks: FileChunkIter, compression: Option<Compression>, progress: &mut Progress) -> Result<u64, String> {
+    let mut compressor = compression.map(Compressor::new).transpose().map_err(|e| e.to_string())?;
+    let mut writer = ChunkWriter::new(chunks.remaining());
+    progress.expect(chunks.remaining());
+    let mut sent = 0;
+    for chunk in chunks {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        let len = chunk.len() as u64;
+        sent += len;
+
+        let encoded = encode_chunk(compressor.as_mut(), &mut writer, chunk)?;
+        s.write_all(&encoded).map_err(|e| e.to_string())?;
+        if !progress.advance(len) {
+            // A compressed stream can still be ended cleanly, so the receiver knows to stop waiting for the rest
+            if compressor.is_some() {
+                s.write_all(&cancel_block()).and_then(|_| s.flush()).map_err(|e| e.to_string())?;
+            }
+            return Err(String::from("the transfer was cancelled"));
+        }
+    }
+
+    let trailer = finish_chunks(compressor).map_err(|e| e.to_string())?;
+    s.write_all(&trailer).map_err(|e| e.to_string())?;
+    s.flush().map_err(|e| e.to_string())?;
+    progress.finish();
+    Ok(sent)
+}
+pub fn send_network_frames<S: Write>(s: &mut S, frames: &[Vec<u8>]) -> bool {
+    for frame in frames {
+        if let Err(e) = s.write_all(frame) {
+            tracing::warn!("unable to send a frame because '{e}'");
+            return false;
+        }
+    }
+
+    s.flush().is_ok()
+}
+
+// Reads are sized by the tuner, which learns from how long each one takes. The first read also measures the RTT, since the sender starts the moment it sees our response.
+#[cfg(feature = "async")]
+async fn receive_network_data_async<S, P>(s: &mut S, frame_count: u64, tuner: &mut FrameSizeTuner, progress: &mut Progress<'_>, p: &mut P) -> bool
+    where S: tokio::io::AsyncRead + Unpin, P: FnMut(&mut Vec<u8>) -> bool {
+    let mut reader = ChunkReader::new(frame_count);
+    let mut first = true;
+    loop {
+        let start = Instant::now();
+        let mut contents = match reader.read_frames_async(s, tuner.current() as usize).await {
+            Ok(Some(c)) => c,
+            Ok(None) => return true,
+            Err(e) => {
+                tracing::warn!("unable to read from the connection because '{e}'");
+                return false;
+            }
+        };
+
+        let len = contents.len();
+        if first {
+            tuner.record_rtt(start.elapsed());
+            first = false;
+        } else {
+            tuner.record_frame(len, start.elapsed());
+        }
+
+        if !p(&mut contents) {
+            tracing::warn!("the received data could not be stored");
+            return false;
+        }
+        if !progress.advance(len as u64) {
+            tracing::debug!("the transfer was cancelled with {} frames still to come", reader.frames_left());
+            return false;
+        }
+    }
+}
+// Each compressed block is one read as far as the tuner is concerned
+#[cfg(feature = "async")]
+async fn receive_compressed_data_async<S, P>(s: &mut S, compression: Compression, tuner: &mut FrameSizeTuner, progress: &mut Progress<'_>, p: &mut P) -> bool
+    where S: tokio::io::AsyncRead + Unpin, P: FnMut(&mut Vec<u8>) -> bool {
+    let mut decompressor = match Decompressor::new(compression) {
+        Ok(d) => d,
+        Err(e) => {
+            tracing::warn!("unable to start decompressing because '{e}'");
+            return false;
+        }
+    };
+
+    let mut first = true;
+    loop {
+        let start = Instant::now();
+        let block = match read_block_async(s).await {
+            Ok(Block::Data(b)) => b,
+            Ok(Block::End) => break,
+            Ok(Block::Cancelled) => {
+                tracing::debug!("the sender cancelled the transfer");
+                progress.cancel();
+                return false;
+            },
+            Err(e) => {
+                tracing::warn!("unable to read a compressed block because '{e}'");
+                return false;
+            }
+        };
+        if first {
+            tuner.record_rtt(start.elapsed());
+            first = false;
+        } else {
+            tuner.record_frame(block.len(), start.elapsed());
+        }
+
+        match decompressor.decompress(&block) {
+            Ok(mut x) if !x.is_empty() => {
+                let len = x.len() as u64;
+                if !p(&mut x) {
+                    tracing::warn!("the received data could not be stored");
+                    return false;
+                }
+                if !progress.advance(len) {
+                    tracing::debug!("the transfer was cancelled");
+                    return false;
+                }
+            },
+            Ok(_) => { },
+            Err(e) => {
+                tracing::warn!("unable to decompress a block because '{e}'");
+                return false;
+            }
+        }
+    }
+
+    match decompressor.finish() {
+        Ok(mut x) => {
+            progress.advance(x.len() as u64);
+            x.is_empty() || p(&mut x)
+        },
+        Err(e) => {
+            tracing::warn!("unable to finish decompressing because '{e}'");
+            false
+        }
+    }
+}
+// How an async receive goes, beyond where it is written and how many frames are coming
+#[cfg(feature = "async")]
+pub struct ReceiveOptions<'a, 'p> {
+    pub offset: u64, //Bytes already in the file, which are hashed again but never sent twice
+    pub expected: Option<&'a Checksum>, //The received file is removed when it does not match this
+    pub compression: Option<Compression>,
+    pub tuner: &'a mut FrameSizeTuner,
+    pub progress: &'a mut Progress<'p>
+}
+// The async counterpart of receive_network_file_checked, used by the server so one slow transfer does not hold up other connections
+#[cfg(feature = "async")]
+pub async fn receive_network_file_checked_async<S>(path: &Path, s: &mut S, frame_count: u64, options: ReceiveOptions<'_, '_>) -> Result<Checksum, String>
+    where S: tokio::io::AsyncRead + Unpin {
+    let ReceiveOptions { offset, expected, compression, tuner, progress } = options;
+    let mut hasher = ChecksumHasher::new(expected.map(|x| x.algorithm()).unwrap_or_default());
+
+    // Disk access is small compared to the network, so it is done with std inside the task
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(|e| e.to_string())?;
+    let hashed = hash_reader(&mut file, &mut hasher, Some(offset)).map_err(|e| e.to_string())?;
+    if hashed != offset {
+        return Err(format!("cannot resume at {offset}, only {hashed} bytes are present"));
+    }
+
+    file.set_len(offset).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+
+    let mut write = |x: &mut Vec<u8>| -> bool {
+        hasher.update(x);
+        file.write_all(x).is_ok()
+    };
+    let received = match compression {
+        Some(c) => receive_compressed_data_async(s, c, tuner, progress, &mut write).await,
+        None => receive_network_data_async(s, frame_count, tuner, progress, &mut write).await
+    };
+    if !received {
+        return Err(interrupted(progress));
+    }
+    progress.finish();
+
+    let actual = hasher.finish();
+    match expected {
+        Some(e) if *e != actual => {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            Err(format!("checksum mismatch, expected '{}' but received '{}'", e, actual))
+        },
+        _ => Ok(actual)
+    }
+}
+#[cfg(feature = "async")]
+pub async fn receive_network_binary_async<S>(s: &mut S, frame_count: u64, tuner: &mut FrameSizeTuner) -> Option<Vec<u8>>
+    where S: tokio::io::AsyncRead + Unpin {
+    let mut result = Vec::<u8>::new();
+
+    let mut collect = |x: &mut Vec<u8>| -> bool {
+        result.append(x);
+        true
+    };
+
+    if !receive_network_data_async(s, frame_count, tuner, &mut Progress::none(), &mut collect).await {
+        None
+    } else {
+        Some(result)
+    }
+}
+// Blocks are read from disk in whatever size the tuner currently prefers, so the file is never held in memory.
+// Compression works on the same blocks, so only what the encoder has produced so far is held.
+#[cfg(feature = "async")]
+pub async fn send_network_file_async<S>(s: &mut S, mut chunks: FileChunkIter, compression: Option<Compression>, tuner: &mut FrameSizeTuner, progress: &mut Progress<'_>) -> Result<u64, String>
+    where S: tokio::io::AsyncWrite + Unpin {
+    use tokio::io::AsyncWriteExt;
+
+    let mut compressor = compression.map(Compressor::new).transpose().map_err(|e| e.to_string())?;
+    let mut writer = ChunkWriter::new(chunks.remaining());
+    progress.expect(chunks.remaining());
+    let mut sent = 0;
+    loop {
+        chunks.set_chunk_size(tuner.current() as usize);
+        let chunk = match chunks.next() {
+            Some(c) => c.map_err(|e| e.to_string())?,
+            None => break
+        };
+        let len = chunk.len() as u64;
+        sent += len;
+
+        let encoded = encode_chunk(compressor.as_mut(), &mut writer, chunk)?;
+        if !encoded.is_empty() {
+            let start = Instant::now();
+            s.write_all(&encoded).await.map_err(|e| e.to_string())?;
+            tuner.record_frame(encoded.len(), start.elapsed());
+        }
+        if !progress.advance(len) {
+            if compressor.is_some() {
+                s.write_all(&cancel_block()).await.map_err(|e| e.to_string())?;
+                s.flush().await.map_err(|e| e.to_string())?;
+            }
+            return Err(String::from("the transfer was cancelled"));
+        }
+    }
+
+    let trailer = finish_chunks(compressor).map_err(|e| e.to_string())?;
+    s.write_all(&trailer).await.map_err(|e| e.to_string())?;
+    s.flush().await.map_err(|e| e.to_string())?;
+    progress.finish();
+    Ok(sent)
+}
+// The frames only describe the byte stream, so they are rewritten in whatever sizes the tuner currently prefers
+#[cfg(feature = "async")]
+pub async fn send_network_frames_async<S>(s: &mut S, frames: &[Vec<u8>], tuner: &mut FrameSizeTuner) -> bool
+    where S: tokio::io::AsyncWrite + Unpin {
+    use tokio::io::AsyncWriteExt;
+
+    let data = frames.concat();
+    let mut sent = 0;
+    while sent < data.len() {
+        let end = std::cmp::min(sent + tuner.current() as usize, data.len());
+
+        let start = Instant::now();
+        if let Err(e) = s.write_all(&data[sent..end]).await {
+            tracing::warn!("unable to send a frame because '{e}'");
+            return false;
+        }
+        tuner.record_frame(end - sent, start.elapsed());
+        sent = end;
+    }
+
+    s.flush().await.is_ok()
+}
+
 pub struct JsonFile {
     path: Option<String>
 }
@@ -325,48 +1119,161 @@ impl JsonFile {
         self.path.as_deref()
     }
 
-    pub fn open(&mut self, path: &str) -> Result<String, String> {
+    pub fn open(&mut self, path: &str) -> Result<String, HermesError> {
         if self.is_open() {
-            return Err(format!("file already opened, at path '{}'", self.path().unwrap()));
+            return Err(HermesError::Conflict(format!("file already opened, at path '{}'", self.path().unwrap())));
         }
 
         let mut file = match File::open(path) {
-            Err(e) => {
-                //Try to open up as a new file
-                match File::create(self.path.as_ref().unwrap()) {
-                    Err(e2) => return Err(format!("failed to open because '{}' and failed to create because '{}'", e, e2)),
-                    Ok(f) => f
-                }
-            },
+            Err(_) => File::create(path)?, //Try to open up as a new file
             Ok(f) => f
         };
 
         let mut contents = String::new();
-        match file.read_to_string(&mut contents)  {
-            Err(e) => Err(e.to_string()),
-            Ok(_) => {
-                self.path = Some(path.to_string()); //Update path after all errors could occur
-                Ok(contents)
-            }
-        }
+        file.read_to_string(&mut contents)?;
+        self.path = Some(path.to_string()); //Update path after all errors could occur
+        Ok(contents)
     }
-    pub fn save(&self, contents: &str) -> Result<(), String> {
+    pub fn save(&self, contents: &str) -> Result<(), HermesError> {
         if !self.is_open() {
             return Ok(());
         }
 
-        let mut file = match File::create(self.path.as_ref().unwrap()) {
-            Ok(f) => f,
-            Err(e) => return Err(format!("{}", e))
-        };
-
-        match file.write_all(contents.as_bytes()) {
-            Err(e) => Err(e.to_string()),
-            Ok(_) => Ok(())
-        }
+        let mut file = File::create(self.path.as_ref().unwrap())?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
     }
 
     pub fn close(&mut self) {
         self.path = None;
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_glob_matches() {
+    assert!(glob_matches("*.txt", "notes.txt"));
+    assert!(!glob_matches("*.txt", "notes.txt.bak"));
+    assert!(glob_matches("report-??.*", "report-01.pdf"));
+    assert!(glob_matches("*a*b*", "xaxxbx"));
+    assert!(!glob_matches("a?", "a"));
+    assert!(glob_matches("*", ""));
+}
+#[test]
+fn test_file_info_metadata() {
+    let path = std::env::temp_dir().join(format!(".hermes_info_{}.txt", std::process::id()));
+    std::fs::write(&path, b"hidden").unwrap();
+
+    let mut info = FileInfo::new(path.file_name().unwrap().to_string_lossy().to_string(), String::from("any"), FileType::Text, 6);
+    info.set_metadata(&std::fs::metadata(&path).unwrap());
+    assert!(info.is_hidden());
+    assert!(info.modified().is_some() && info.permissions().is_some());
+    let decoded: FileInfo = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+    assert!(decoded == info);
+
+    // Listings from older servers have none of it
+    let old: FileInfo = serde_json::from_str(r#"{"name": "a.txt", "kind": "Text", "owner": "any", "size": 1}"#).unwrap();
+    assert!(!old.is_hidden() && old.created().is_none() && old.checksum().is_none());
+
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn test_directory_from_path() {
+    let dir = std::env::temp_dir().join(format!("hermes_listing_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("docs").join("old")).unwrap();
+    std::fs::write(dir.join("docs").join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("docs").join("old").join("b.txt"), "bb").unwrap();
+    std::fs::write(dir.join("c.bin"), "ccc").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(dir.join("docs"), dir.join("link")).unwrap();
+
+    // The lookup fills in what it knows, and the rest is read from the disk
+    let owned = dir.join("c.bin");
+    let lookup = |path: &Path, metadata: &std::fs::Metadata| (path == owned).then(|| FileInfo::new(String::from("c.bin"), String::from("alice"), FileType::Binary, metadata.len()));
+    let listing = DirectoryInfo::from_path(&dir, 0, &lookup).unwrap();
+    let (files, dirs) = listing.spill_ref();
+    assert_eq!((files.len(), files[0].owner(), files[0].size()), (1, "alice", 3));
+    assert_eq!((dirs.len(), dirs[0].name(), dirs[0].contents().len()), (1, "docs", 0));
+
+    // Folders are filled in as deep as asked, and links are listed as they are, never followed
+    let listing = DirectoryInfo::from_path(&dir, u32::MAX, &|_, _| None).unwrap();
+    assert_eq!(listing.file_paths(&dir), vec![dir.join("c.bin"), dir.join("docs").join("a.txt"), dir.join("docs").join("old").join("b.txt")]);
+    #[cfg(unix)]
+    assert_eq!(listing.contents()[2], DirectoryContent::Symlink(LinkInfo::new(String::from("link"), Some(dir.join("docs").to_string_lossy().to_string()))));
+    assert_eq!(listing.get_files()[0].owner(), "any");
+    assert!(DirectoryInfo::from_path(&dir.join("missing"), 0, &|_, _| None).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_read_file_range() {
+    let path = std::env::temp_dir().join(format!("hermes_range_{}.bin", std::process::id()));
+    std::fs::write(&path, b"0123456789").unwrap();
+
+    let read = |offset, length| -> Vec<u8> {
+        let mut chunks = FileChunkIter::open(&path, offset, length).unwrap();
+        chunks.set_chunk_size(3);
+        chunks.map(|x| x.unwrap()).collect::<Vec<_>>().concat()
+    };
+    assert_eq!(read(0, None), b"0123456789");
+    assert_eq!(read(4, None), b"456789");
+    assert_eq!(read(4, Some(3)), b"456");
+    let mut streamed = Vec::new();
+    FileChunkIter::open(&path, 2, Some(5)).unwrap().read_to_end(&mut streamed).unwrap();
+    assert_eq!(streamed, b"23456");
+    assert_eq!(read(10, None), b"");
+    assert!(FileChunkIter::open(&path, 11, None).is_err());
+
+    let chunks = FileChunkIter::open(&path, 0, None).unwrap();
+    assert_eq!((chunks.remaining(), chunks.frame_count()), (10, 1));
+    let mut sent = Vec::<u8>::new();
+    assert_eq!(send_network_file(&mut sent, chunks, None, &mut Progress::none()).unwrap(), 10);
+    assert_eq!(sent, b"\0\0\0\x0a0123456789");
+
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn test_compressed_file_transfer() {
+    let source = std::env::temp_dir().join(format!("hermes_compress_src_{}.bin", std::process::id()));
+    let target = std::env::temp_dir().join(format!("hermes_compress_dst_{}.bin", std::process::id()));
+    let contents = b"all work and no play makes jack a dull boy. ".repeat(2000);
+    std::fs::write(&source, &contents).unwrap();
+
+    for compression in Compression::supported() {
+        let mut wire = Vec::<u8>::new();
+        assert_eq!(send_network_file(&mut wire, FileChunkIter::open(&source, 0, None).unwrap(), Some(compression), &mut Progress::none()).unwrap(), contents.len() as u64);
+        assert!(wire.len() < contents.len() / 10);
+
+        // Anything after the stream is left for the next message
+        wire.extend_from_slice(b"next");
+        let mut reader = std::io::Cursor::new(wire);
+        let mut last = None;
+        let mut progress = Progress::new(None, |x| last = Some(x));
+        let checksum = receive_network_file_checked(&target, &mut reader, frame_count_for(contents.len() as u64), 0, None, Some(compression), &mut progress).unwrap();
+        drop(progress);
+        assert_eq!(last.map(|x| x.done), Some(contents.len() as u64));
+        assert_eq!(std::fs::read(&target).unwrap(), contents);
+        assert_eq!(checksum, crate::checksum::checksum_file(&source, checksum.algorithm()).unwrap());
+        assert_eq!(&reader.get_ref()[reader.position() as usize..], b"next");
+        std::fs::remove_file(&target).unwrap();
+    }
+
+    // A cancelled transfer stops before its next frame
+    let token = crate::progress::CancelToken::new();
+    token.cancel();
+    let cancelled = send_network_file(&mut Vec::new(), FileChunkIter::open(&source, 0, None).unwrap(), None, &mut Progress::none().with_cancel(token));
+    assert_eq!(cancelled.unwrap_err(), "the transfer was cancelled");
+
+    // A compressed one ends with the cancel block, which the receiver reports as a cancel and reads no further than
+    let token = crate::progress::CancelToken::new();
+    token.cancel();
+    let mut wire = Vec::<u8>::new();
+    assert!(send_network_file(&mut wire, FileChunkIter::open(&source, 0, None).unwrap(), Some(Compression::Zstd), &mut Progress::none().with_cancel(token)).is_err());
+    wire.extend_from_slice(b"next");
+    let mut reader = std::io::Cursor::new(wire);
+    let received = receive_network_file_checked(&target, &mut reader, 1, 0, None, Some(Compression::Zstd), &mut Progress::none());
+    assert_eq!(received.unwrap_err(), "the transfer was cancelled");
+    assert_eq!(&reader.get_ref()[reader.position() as usize..], b"next");
+    std::fs::remove_file(&target).unwrap();
+
+    std::fs::remove_file(&source).unwrap();
+}
@@ -0,0 +1,260 @@
+use std::fmt::Display;
+use std::io::{Read, Write};
+
+use crate::codec::Codec;
+use crate::messages::{Message, MessageParseError};
+
+// Every message on the wire is laid out as MAGIC | length (u32, big endian) | payload. The magic says which codec the payload is in, FRAME_MAGIC being JSON's.
+pub const FRAME_MAGIC: [u8; 4] = *b"HRMS";
+pub const FRAME_HEADER_SIZE: usize = FRAME_MAGIC.len() + std::mem::size_of::<u32>();
+pub const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+// A file sent on a channel is cut into frames laid out as CHANNEL_MAGIC | request ID (u64, big endian) | length (u32, big endian) | bytes, and an empty one ends it.
+// They can sit between message frames, so a reader expecting them uses read_any_frame.
+pub const CHANNEL_MAGIC: [u8; 4] = *b"HRMC";
+pub const CHANNEL_HEADER_SIZE: usize = CHANNEL_MAGIC.len() + std::mem::size_of::<u64>() + std::mem::size_of::<u32>();
+
+#[derive(PartialEq, Debug)]
+pub enum Frame {
+    Message(Message),
+    Channel(u64, Vec<u8>) //The request ID, and the bytes, which are empty at the end of the channel
+}
+
+// Why a frame could not be read. One that arrived whole but whose message was refused leaves the stream in step, so the peer can be told and the connection kept.
+#[derive(PartialEq, Debug)]
+pub enum FrameError {
+    Broken(String), //The connection, or the frame's header, could not be trusted any further
+    Rejected(MessageParseError)
+}
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Broken(e) => write!(f, "{e}"),
+            Self::Rejected(e) => write!(f, "{e}")
+        }
+    }
+}
+impl From<String> for FrameError {
+    fn from(value: String) -> Self {
+        Self::Broken(value)
+    }
+}
+
+pub fn encode_frame(message: &Message) -> Result<Vec<u8>, String> {
+    encode_frame_with(message, Codec::Json)
+}
+pub fn encode_frame_with(message: &Message, codec: Codec) -> Result<Vec<u8>, String> {
+    let payload = message.to_bytes(codec)?;
+
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        return Err(format!("message of {} bytes exceeds the maximum frame size of {} bytes", payload.len(), MAX_FRAME_SIZE));
+    }
+
+    let mut result = Vec::<u8>::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    result.extend_from_slice(&codec.magic());
+    result.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    result.extend_from_slice(&payload);
+
+    Ok(result)
+}
+pub fn decode_header(header: &[u8; FRAME_HEADER_SIZE]) -> Result<(Codec, u32), String> {
+    let codec = match Codec::from_magic(&header[..FRAME_MAGIC.len()]) {
+        Some(c) => c,
+        None => return Err(format!("invalid frame magic '{:?}'", &header[..FRAME_MAGIC.len()]))
+    };
+
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&header[FRAME_MAGIC.len()..]);
+    let len = u32::from_be_bytes(len_bytes);
+
+    if len > MAX_FRAME_SIZE {
+        Err(format!("frame length {len} exceeds the maximum frame size of {MAX_FRAME_SIZE} bytes"))
+    } else {
+        Ok((codec, len))
+    }
+}
+pub fn decode_payload(codec: Codec, payload: &[u8]) -> Result<Message, String> {
+    Message::from_bytes(codec, payload)
+}
+pub fn encode_channel_frame(request_id: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() > MAX_FRAME_SIZE as usize {
+        return Err(format!("channel frame of {} bytes exceeds the maximum frame size of {} bytes", data.len(), MAX_FRAME_SIZE));
+    }
+
+    let mut result = Vec::<u8>::with_capacity(CHANNEL_HEADER_SIZE + data.len());
+    result.extend_from_slice(&CHANNEL_MAGIC);
+    result.extend_from_slice(&request_id.to_be_bytes());
+    result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    result.extend_from_slice(data);
+
+    Ok(result)
+}
+
+pub fn write_frame<S: Write>(s: &mut S, message: &Message) -> Result<(), String> {
+    write_frame_with(s, message, Codec::Json)
+}
+pub fn write_frame_with<S: Write>(s: &mut S, message: &Message, codec: Codec) -> Result<(), String> {
+    let frame = encode_frame_with(message, codec)?;
+
+    s.write_all(&frame).map_err(|e| e.to_string())?;
+    s.flush().map_err(|e| e.to_string())
+}
+// Frames in any codec are read, whatever was negotiated
+pub fn read_frame<S: Read>(s: &mut S) -> Result<Message, String> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    s.read_exact(&mut header).map_err(|e| e.to_string())?;
+
+    let (codec, len) = decode_header(&header)?;
+    let mut payload = vec![0u8; len as usize];
+    s.read_exact(&mut payload).map_err(|e| e.to_string())?;
+
+    decode_payload(codec, &payload)
+}
+pub fn write_channel_frame<S: Write>(s: &mut S, request_id: u64, data: &[u8]) -> Result<(), String> {
+    let frame = encode_channel_frame(request_id, data)?;
+
+    s.write_all(&frame).map_err(|e| e.to_string())?;
+    s.flush().map_err(|e| e.to_string())
+}
+// Reads either kind of frame, for connections where channels are open
+pub fn read_any_frame<S: Read>(s: &mut S) -> Result<Frame, String> {
+    let mut magic = [0u8; 4];
+    s.read_exact(&mut magic).map_err(|e| e.to_string())?;
+    if magic != CHANNEL_MAGIC {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        header[..magic.len()].copy_from_slice(&magic);
+        s.read_exact(&mut header[magic.len()..]).map_err(|e| e.to_string())?;
+
+        let (codec, len) = decode_header(&header)?;
+        let mut payload = vec![0u8; len as usize];
+        s.read_exact(&mut payload).map_err(|e| e.to_string())?;
+        return decode_payload(codec, &payload).map(Frame::Message);
+    }
+
+    let mut id_bytes = [0u8; 8];
+    let mut len_bytes = [0u8; 4];
+    s.read_exact(&mut id_bytes).and_then(|_| s.read_exact(&mut len_bytes)).map_err(|e| e.to_string())?;
+    let len = u32::from_be_bytes(len_bytes);
+    if len > MAX_FRAME_SIZE {
+        return Err(format!("channel frame length {len} exceeds the maximum frame size of {MAX_FRAME_SIZE} bytes"));
+    }
+
+    let mut data = vec![0u8; len as usize];
+    s.read_exact(&mut data).map_err(|e| e.to_string())?;
+    Ok(Frame::Channel(u64::from_be_bytes(id_bytes), data))
+}
+
+#[cfg(feature = "async")]
+pub async fn write_frame_async<S: tokio::io::AsyncWrite + Unpin>(s: &mut S, message: &Message) -> Result<(), String> {
+    write_frame_with_async(s, message, Codec::Json).await
+}
+#[cfg(feature = "async")]
+pub async fn write_frame_with_async<S: tokio::io::AsyncWrite + Unpin>(s: &mut S, message: &Message, codec: Codec) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let frame = encode_frame_with(message, codec)?;
+
+    s.write_all(&frame).await.map_err(|e| e.to_string())?;
+    s.flush().await.map_err(|e| e.to_string())
+}
+#[cfg(feature = "async")]
+pub async fn write_channel_frame_async<S: tokio::io::AsyncWrite + Unpin>(s: &mut S, request_id: u64, data: &[u8]) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let frame = encode_channel_frame(request_id, data)?;
+
+    s.write_all(&frame).await.map_err(|e| e.to_string())?;
+    s.flush().await.map_err(|e| e.to_string())
+}
+#[cfg(feature = "async")]
+pub async fn read_frame_async<S: tokio::io::AsyncRead + Unpin>(s: &mut S) -> Result<Message, String> {
+    read_message_async(s).await.map_err(|e| e.to_string())
+}
+// As read_frame_async, but says whether the frame was read whole, for servers that answer a message they cannot accept instead of hanging up
+#[cfg(feature = "async")]
+pub async fn read_message_async<S: tokio::io::AsyncRead + Unpin>(s: &mut S) -> Result<Message, FrameError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    s.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+
+    let (codec, len) = decode_header(&header)?;
+    let mut payload = vec![0u8; len as usize];
+    s.read_exact(&mut payload).await.map_err(|e| e.to_string())?;
+
+    Message::parse(codec, &payload).map_err(FrameError::Rejected)
+}
+
+#[test]
+fn test_frame_round_trip() {
+    use crate::messages::{connect_message, close_message};
+    use crate::protocol::CURRENT_PROTOCOL_VERSION;
+    use std::io::Cursor;
+
+    let first = connect_message("user".to_string(), "pass".to_string(), CURRENT_PROTOCOL_VERSION);
+    let second = close_message();
+
+    // Two frames written back to back must be read back individually
+    let mut buffer = Vec::<u8>::new();
+    write_frame(&mut buffer, &first).unwrap();
+    write_frame(&mut buffer, &second).unwrap();
+
+    let mut cursor = Cursor::new(buffer);
+    assert_eq!(read_frame(&mut cursor).unwrap(), first);
+    assert_eq!(read_frame(&mut cursor).unwrap(), second);
+    assert!(read_frame(&mut cursor).is_err());
+}
+#[test]
+fn test_frame_rejects_bad_header() {
+    use std::io::Cursor;
+
+    let mut bad_magic = Cursor::new(b"NOPE\x00\x00\x00\x02{}".to_vec());
+    assert!(read_frame(&mut bad_magic).is_err());
+
+    let mut too_long = FRAME_MAGIC.to_vec();
+    too_long.extend_from_slice(&(MAX_FRAME_SIZE + 1).to_be_bytes());
+    assert!(read_frame(&mut Cursor::new(too_long)).is_err());
+}
+#[test]
+fn test_frame_codecs() {
+    use crate::http_codes::HttpCodes;
+    use crate::messages::{dir_message_response, close_message};
+    use std::io::Cursor;
+
+    let listing = dir_message_response(HttpCodes::Ok, "listing follows", "some/folder/with/a/long/name", 12);
+
+    // A MessagePack frame is smaller, and sits in the same stream as JSON ones
+    let json = encode_frame(&listing).unwrap();
+    let packed = encode_frame_with(&listing, Codec::MessagePack).unwrap();
+    assert_eq!(packed[..4], *b"HRMP");
+    assert!(packed.len() < json.len());
+
+    let mut buffer = Vec::<u8>::new();
+    write_frame_with(&mut buffer, &listing, Codec::MessagePack).unwrap();
+    write_frame(&mut buffer, &close_message()).unwrap();
+    let mut cursor = Cursor::new(buffer);
+    assert_eq!(read_frame(&mut cursor).unwrap(), listing);
+    assert_eq!(read_frame(&mut cursor).unwrap(), close_message());
+}
+#[test]
+fn test_channel_frames() {
+    use crate::messages::close_message;
+    use std::io::Cursor;
+
+    // Channels for two requests interleave with a message, and an empty frame ends each
+    let mut buffer = Vec::<u8>::new();
+    write_channel_frame(&mut buffer, 1, b"first").unwrap();
+    write_channel_frame(&mut buffer, 2, b"second").unwrap();
+    write_frame_with(&mut buffer, &close_message(), Codec::MessagePack).unwrap();
+    write_channel_frame(&mut buffer, 2, &[]).unwrap();
+
+    let mut cursor = Cursor::new(buffer);
+    assert_eq!(read_any_frame(&mut cursor).unwrap(), Frame::Channel(1, b"first".to_vec()));
+    assert_eq!(read_any_frame(&mut cursor).unwrap(), Frame::Channel(2, b"second".to_vec()));
+    assert_eq!(read_any_frame(&mut cursor).unwrap(), Frame::Message(close_message()));
+    assert_eq!(read_any_frame(&mut cursor).unwrap(), Frame::Channel(2, Vec::new()));
+    assert!(read_any_frame(&mut cursor).is_err());
+
+    // Only read_any_frame expects a channel
+    let mut stray = Cursor::new(encode_channel_frame(3, b"data").unwrap());
+    assert!(read_frame(&mut stray).is_err());
+}
@@ -1,26 +1,145 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use std::fmt::Display;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum HttpCodes {
     Ok = 200,
+    Created = 201,
+    NoContent = 204,
+    BadRequest = 400,
     Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
+    RequestTimeout = 408,
     Conflict = 409,
-    ImNotATeapot = 418
+    PayloadTooLarge = 413,
+    UnsupportedMediaType = 415,
+    UnprocessableContent = 422,
+    ImNotATeapot = 418,
+    TooManyRequests = 429,
+    InternalServerError = 500,
+    VersionNotSupported = 505,
+    InsufficientStorage = 507
 }
 impl Display for HttpCodes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let text = match self {
             Self::Ok => "OK",
+            Self::Created => "Created",
+            Self::NoContent => "No Content",
+            Self::BadRequest => "Bad Request",
             Self::Unauthorized => "Unauthorized",
             Self::Forbidden => "Forbidden",
             Self::NotFound => "Not Found",
+            Self::RequestTimeout => "Request Timeout",
             Self::Conflict => "Conflict",
-            Self::ImNotATeapot => "I'm not a Teapot"
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::UnsupportedMediaType => "Unsupported Media Type",
+            Self::UnprocessableContent => "Unprocessable Content",
+            Self::ImNotATeapot => "I'm not a Teapot",
+            Self::TooManyRequests => "Too Many Requests",
+            Self::InternalServerError => "Internal Server Error",
+            Self::VersionNotSupported => "Version Not Supported",
+            Self::InsufficientStorage => "Insufficient Storage"
         };
 
         write!(f, "{text}")
     }
-}
\ No newline at end of file
+}
+// Codes this side does not know fall back to the general code of their class, so a newer peer's answer is still understood as a success or a failure
+impl From<u16> for HttpCodes {
+    fn from(value: u16) -> Self {
+        match value {
+            200 => Self::Ok,
+            201 => Self::Created,
+            204 => Self::NoContent,
+            400 => Self::BadRequest,
+            401 => Self::Unauthorized,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            408 => Self::RequestTimeout,
+            409 => Self::Conflict,
+            413 => Self::PayloadTooLarge,
+            415 => Self::UnsupportedMediaType,
+            422 => Self::UnprocessableContent,
+            418 => Self::ImNotATeapot,
+            429 => Self::TooManyRequests,
+            500 => Self::InternalServerError,
+            505 => Self::VersionNotSupported,
+            507 => Self::InsufficientStorage,
+            c if (200..300).contains(&c) => Self::Ok,
+            c if (400..500).contains(&c) => Self::BadRequest,
+            _ => Self::InternalServerError
+        }
+    }
+}
+impl HttpCodes {
+    pub fn as_u16(&self) -> u16 {
+        self.clone() as u16
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+}
+
+// Sent as the number. The names older versions sent are still read, since remembered responses may hold them.
+impl Serialize for HttpCodes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.as_u16())
+    }
+}
+impl<'de> Deserialize<'de> for HttpCodes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Code(u16),
+            Name(String)
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Code(c) => Ok(Self::from(c)),
+            Repr::Name(n) => match n.as_str() {
+                "Ok" => Ok(Self::Ok),
+                "BadRequest" => Ok(Self::BadRequest),
+                "Unauthorized" => Ok(Self::Unauthorized),
+                "Forbidden" => Ok(Self::Forbidden),
+                "NotFound" => Ok(Self::NotFound),
+                "RequestTimeout" => Ok(Self::RequestTimeout),
+                "Conflict" => Ok(Self::Conflict),
+                "ImNotATeapot" => Ok(Self::ImNotATeapot),
+                "TooManyRequests" => Ok(Self::TooManyRequests),
+                "VersionNotSupported" => Ok(Self::VersionNotSupported),
+                "InsufficientStorage" => Ok(Self::InsufficientStorage),
+                _ => Err(serde::de::Error::custom(format!("unknown status '{n}'")))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_http_code_conversions() {
+    for code in [HttpCodes::Ok, HttpCodes::Created, HttpCodes::PayloadTooLarge, HttpCodes::UnsupportedMediaType, HttpCodes::UnprocessableContent, HttpCodes::InsufficientStorage] {
+        assert_eq!(HttpCodes::from(code.as_u16()), code);
+        assert_eq!(serde_json::from_value::<HttpCodes>(serde_json::to_value(&code).unwrap()).unwrap(), code);
+    }
+    assert_eq!(serde_json::to_string(&HttpCodes::NotFound).unwrap(), "404");
+    assert_eq!(serde_json::from_str::<HttpCodes>("\"Conflict\"").unwrap(), HttpCodes::Conflict);
+    assert!(serde_json::from_str::<HttpCodes>("\"Maybe\"").is_err());
+
+    // Codes from a newer peer keep their class
+    assert_eq!(HttpCodes::from(202), HttpCodes::Ok);
+    assert_eq!(HttpCodes::from(451), HttpCodes::BadRequest);
+    assert_eq!(HttpCodes::from(503), HttpCodes::InternalServerError);
+
+    assert!(HttpCodes::NoContent.is_success() && !HttpCodes::NoContent.is_client_error());
+    assert!(HttpCodes::TooManyRequests.is_client_error());
+    assert!(HttpCodes::InsufficientStorage.is_server_error() && !HttpCodes::InsufficientStorage.is_success());
+}
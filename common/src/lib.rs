@@ -1,4 +1,22 @@
 pub mod file_io;
+pub mod chunking;
+pub mod sniff;
 pub mod messages;
 pub mod http_codes;
 pub mod network_stats;
+pub mod framing;
+pub mod protocol;
+pub mod session;
+pub mod checksum;
+pub mod transport;
+pub mod tuning;
+pub mod socket;
+pub mod error;
+pub mod compression;
+pub mod codec;
+pub mod delta;
+pub mod archive;
+pub mod progress;
+pub mod testing;
+#[cfg(feature = "quic")]
+pub mod quic;
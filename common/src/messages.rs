@@ -1,11 +1,22 @@
 use serde::{Deserialize, de::DeserializeOwned, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, fmt::Display, str::FromStr};
-use std::iter::zip;
+use std::{collections::{BTreeMap, HashMap}, fmt::Display, str::FromStr};
 
 use crate::http_codes::HttpCodes;
-use crate::file_io::FileType;
-use crate::network_stats::TransferStats;
+use crate::file_io::{Encryption, FileInfo, FileType, Provenance, glob_matches};
+use crate::checksum::Checksum;
+use crate::compression::Compression;
+use crate::codec::Codec;
+use crate::delta::Signature;
+use crate::error::HermesError;
+use crate::network_stats::{Latency, TransferStats};
+use crate::protocol::{Capabilities, Capability, ProtocolVersion};
+use crate::session::{ResumeToken, SessionToken, ShareLink, UploadGrant};
+use crate::tuning::FrameSizeBounds;
+
+// What a peer may send in one message, on top of the frame's own size limit. Nothing this side builds comes close to either.
+pub const MAX_MESSAGE_FIELDS: usize = 64;
+pub const MAX_VALUE_DEPTH: usize = 32;
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum MessageType {
@@ -16,9 +27,42 @@ pub enum MessageType {
     Download,
     Delete,
     Dir,
+    ChangeDir,
     Move,
     Subfolder,
-    Stats
+    Stats,
+    Grant,
+    Hold,
+    Stat,
+    CanI,
+    Rename,
+    Copy,
+    Share,
+    UserAdmin,
+    Heartbeat,
+    Probe,
+    Diagnostics,
+    Restore,
+    PurgeTrash,
+    Versions,
+    Quota,
+    Signature,
+    UploadDir,
+    DownloadDir,
+    Subscribe,
+    Event,
+    Ping,
+    Pong,
+    Cancel,
+    Preview,
+    Thumbnail,
+    ShareLink,
+    Lockouts,
+    Audit,
+    Dedupe,
+    Usage,
+    Queued,
+    ChangesSince
 }
 impl Display for MessageType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -30,9 +74,42 @@ impl Display for MessageType {
             Self::Download => "download",
             Self::Delete => "delete",
             Self::Dir => "dir",
+            Self::ChangeDir => "change_dir",
             Self::Move => "move",
             Self::Subfolder => "subfolder",
-            Self::Stats => "stats"
+            Self::Stats => "stats",
+            Self::Grant => "grant",
+            Self::Hold => "hold",
+            Self::Stat => "stat",
+            Self::CanI => "can_i",
+            Self::Rename => "rename",
+            Self::Copy => "copy",
+            Self::Share => "share",
+            Self::UserAdmin => "user_admin",
+            Self::Heartbeat => "heartbeat",
+            Self::Probe => "probe",
+            Self::Diagnostics => "diagnostics",
+            Self::Restore => "restore",
+            Self::PurgeTrash => "purge_trash",
+            Self::Versions => "versions",
+            Self::Quota => "quota",
+            Self::Signature => "signature",
+            Self::UploadDir => "upload_dir",
+            Self::DownloadDir => "download_dir",
+            Self::Subscribe => "subscribe",
+            Self::Event => "event",
+            Self::Ping => "ping",
+            Self::Pong => "pong",
+            Self::Cancel => "cancel",
+            Self::Preview => "preview",
+            Self::Thumbnail => "thumbnail",
+            Self::ShareLink => "share_link",
+            Self::Lockouts => "lockouts",
+            Self::Audit => "audit",
+            Self::Dedupe => "dedupe",
+            Self::Usage => "usage",
+            Self::Queued => "queued",
+            Self::ChangesSince => "changes_since"
         };
 
         write!(f, "{}", str)
@@ -50,14 +127,68 @@ impl FromStr for MessageType {
             "download" => Ok(Self::Download),
             "delete" => Ok(Self::Delete),
             "dir" => Ok(Self::Dir),
+            "change_dir" => Ok(Self::ChangeDir),
             "move" => Ok(Self::Move),
             "subfolder" => Ok(Self::Subfolder),
             "stats" => Ok(Self::Stats),
+            "grant" => Ok(Self::Grant),
+            "hold" => Ok(Self::Hold),
+            "stat" => Ok(Self::Stat),
+            "can_i" => Ok(Self::CanI),
+            "rename" => Ok(Self::Rename),
+            "copy" => Ok(Self::Copy),
+            "share" => Ok(Self::Share),
+            "user_admin" => Ok(Self::UserAdmin),
+            "heartbeat" => Ok(Self::Heartbeat),
+            "probe" => Ok(Self::Probe),
+            "diagnostics" => Ok(Self::Diagnostics),
+            "restore" => Ok(Self::Restore),
+            "purge_trash" => Ok(Self::PurgeTrash),
+            "versions" => Ok(Self::Versions),
+            "quota" => Ok(Self::Quota),
+            "signature" => Ok(Self::Signature),
+            "upload_dir" => Ok(Self::UploadDir),
+            "download_dir" => Ok(Self::DownloadDir),
+            "subscribe" => Ok(Self::Subscribe),
+            "event" => Ok(Self::Event),
+            "ping" => Ok(Self::Ping),
+            "pong" => Ok(Self::Pong),
+            "cancel" => Ok(Self::Cancel),
+            "preview" => Ok(Self::Preview),
+            "thumbnail" => Ok(Self::Thumbnail),
+            "share_link" => Ok(Self::ShareLink),
+            "lockouts" => Ok(Self::Lockouts),
+            "audit" => Ok(Self::Audit),
+            "dedupe" => Ok(Self::Dedupe),
+            "usage" => Ok(Self::Usage),
+            "queued" => Ok(Self::Queued),
+            "changes_since" => Ok(Self::ChangesSince),
             _ => Err(format!("unable to parse literal '{}'", s))
         }
     }
 }
 
+impl MessageType {
+    // The capability both sides must have negotiated before a request of this type may be sent
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Self::Heartbeat | Self::Probe | Self::Diagnostics => Some(Capability::Diagnostics),
+            Self::Restore | Self::PurgeTrash => Some(Capability::Trash),
+            Self::Versions => Some(Capability::Versions),
+            Self::Quota => Some(Capability::Quota),
+            Self::Signature => Some(Capability::Delta),
+            Self::UploadDir | Self::DownloadDir => Some(Capability::Archive),
+            Self::Subscribe | Self::Event => Some(Capability::Events),
+            Self::Ping | Self::Pong => Some(Capability::Keepalive),
+            Self::Cancel => Some(Capability::Cancel),
+            Self::Preview => Some(Capability::Preview),
+            Self::Thumbnail => Some(Capability::Thumbnail),
+            Self::Queued => Some(Capability::Queue),
+            _ => None
+        }
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum MessageDirection {
     Request,
@@ -116,13 +247,21 @@ impl FromStr for SubfolderAction {
 pub struct Message {
     message_type: MessageType,
     direction: MessageDirection,
+    // Matches a response to its request when several are in flight. 0 is left off the wire, and marks messages that answer nothing, or come from a peer that predates IDs.
+    #[serde(default, skip_serializing_if = "is_unset")]
+    request_id: u64,
     data: HashMap<String, serde_json::Value>
 }
+fn is_unset(id: &u64) -> bool {
+    *id == 0
+}
 impl Message {
-    fn new(message_type: MessageType, direction: MessageDirection, data: HashMap<String, serde_json::Value>) -> Self {
+    // For messages that are meant to be malformed, such as in tests
+    fn unchecked(message_type: MessageType, direction: MessageDirection, data: HashMap<String, serde_json::Value>) -> Self {
         Self {
             message_type,
             direction,
+            request_id: 0,
             data
         }
     }
@@ -133,6 +272,14 @@ impl Message {
     pub fn direction(&self) -> &MessageDirection {
         &self.direction
     }
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+    // A response takes the ID of the request it answers
+    pub fn with_request_id(mut self, id: u64) -> Self {
+        self.request_id = id;
+        self
+    }
 
     pub fn extract(&self, property: &str) -> Option<&serde_json::Value> {
         self.data.get(property)
@@ -147,66 +294,313 @@ impl Message {
         let val = self.extract_clone(property)?;
 
         let result: Result<T, _> = serde_json::from_value(val);
-        if let Ok(r) = result {
-            Some(r)
+        result.ok()
+    }
+
+    pub fn to_bytes(&self, codec: Codec) -> Result<Vec<u8>, String> {
+        codec.encode(self).map_err(|e| format!("unable to serialize message because '{e}'"))
+    }
+    pub fn from_bytes(codec: Codec, bytes: &[u8]) -> Result<Self, String> {
+        Self::parse(codec, bytes).map_err(|e| e.to_string())
+    }
+    // Tells a message of a type this side has never heard of apart from one that is not a message at all, since the first can be answered
+    pub fn parse(codec: Codec, bytes: &[u8]) -> Result<Self, MessageParseError> {
+        let error = match codec.decode::<Self>(bytes) {
+            Ok(m) => return Ok(m),
+            Err(e) => e
+        };
+
+        match codec.decode::<Envelope>(bytes) {
+            Ok(e) if serde_json::from_value::<MessageType>(json!(e.message_type)).is_err() => Err(MessageParseError::UnknownType(e.message_type, e.request_id)),
+            _ => Err(MessageParseError::Malformed(error))
+        }
+    }
+
+    // Whether the message keeps within what a peer may send, and has every field its type and direction need. A field set to null is present.
+    pub fn validate(&self) -> Result<(), MessageBuildError> {
+        if self.data.len() > MAX_MESSAGE_FIELDS {
+            return Err(MessageBuildError::Limit(format!("has {} fields, more than the {MAX_MESSAGE_FIELDS} allowed", self.data.len())));
+        }
+        if let Some((name, _)) = self.data.iter().find(|(_, v)| nested_deeper(v, MAX_VALUE_DEPTH)) {
+            return Err(MessageBuildError::Limit(format!("nests the field '{name}' more than {MAX_VALUE_DEPTH} deep")));
+        }
+
+        let required = required_fields(self.message_type, self.direction, &self.data).ok_or(MessageBuildError::Direction(self.message_type, self.direction))?;
+        let missing: Vec<String> = required.iter().filter(|x| !self.data.contains_key(**x)).map(|x| x.to_string()).collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(MessageBuildError::Missing(self.message_type, self.direction, missing))
         }
-        else {
-            None
+    }
+}
+
+// Just enough of a message to say what it was, for one whose type is not known
+#[derive(Deserialize)]
+struct Envelope {
+    message_type: String,
+    #[serde(default)]
+    request_id: u64
+}
+
+// Whether a value holds arrays or objects more than depth levels deep. It stops looking once it is that deep, so a hostile value costs no more than the limit.
+fn nested_deeper(value: &serde_json::Value, depth: usize) -> bool {
+    match value {
+        serde_json::Value::Array(a) => depth == 0 || a.iter().any(|x| nested_deeper(x, depth - 1)),
+        serde_json::Value::Object(o) => depth == 0 || o.values().any(|x| nested_deeper(x, depth - 1)),
+        _ => false
+    }
+}
+
+// Why a payload could not be read as a message
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageParseError {
+    Malformed(String), //Not a message at all, and why
+    UnknownType(String, u64) //A message of a type this side does not know, such as one added in a later version, and the ID it came with
+}
+impl Display for MessageParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "unable to parse message because '{e}'"),
+            Self::UnknownType(kind, _) => write!(f, "unknown message type '{kind}'")
         }
     }
 }
+impl std::error::Error for MessageParseError { }
 
-fn make_message_data(properties: Vec<&str>, values: Vec<serde_json::Value>) -> HashMap<String, serde_json::Value> {
-    assert_eq!(properties.len(), values.len());
-    let property_strs = properties.iter().map(|x| x.to_string());
+// The fields a message cannot be read without, which are the ones its extract function fails without. None when the type is never sent that way.
+fn required_fields(message_type: MessageType, direction: MessageDirection, data: &HashMap<String, serde_json::Value>) -> Option<&'static [&'static str]> {
+    use MessageType as T;
+    use MessageDirection::{Request, Response};
 
-    let total_list = zip(property_strs, values);
+    let result: &'static [&'static str] = match (message_type, direction) {
+        (T::Ack, _) => &["code", "message"],
+        (T::Heartbeat, _) => &["sequence", "padding"],
+        // A login carries credentials, a resumption token, an upload grant, or a share link
+        (T::Connect, Request) if data.contains_key("token") || data.contains_key("grant") || data.contains_key("share") || data.contains_key("guest") => &[],
+        (T::Connect, Request) => &["username", "password"],
+        (T::Close, Request) | (T::Stats, Request) | (T::Quota, Request) | (T::Cancel, Request) | (T::Lockouts, Request) | (T::Audit, Request) | (T::Dedupe, Request) | (T::Usage, Request) => &[],
+        (T::Close, Response) => &["reason"],
+        (T::Upload, Request) => &["name", "type", "size"],
+        (T::Upload, Response) => &["status", "message", "offset"],
+        (T::Download, Response) => &["status", "message", "kind", "size", "offset", "length"],
+        (T::Download | T::Delete | T::Dir | T::ChangeDir | T::Stat | T::Restore | T::PurgeTrash | T::Versions | T::Signature | T::DownloadDir | T::Subscribe | T::Thumbnail, Request) => &["path"],
+        (T::Dir, Response) => &["status", "message", "curr_dir", "size"],
+        (T::Subfolder, Request) => &["path", "action"],
+        (T::ChangesSince, Request) => &["path", "since"],
+        (T::Stats, Response) if data.contains_key("connections") => &[],
+        (T::Stats, Response) => &["stats"],
+        (T::Grant, Request) => &["path", "max_size", "ttl"],
+        (T::ShareLink, Request) if data.contains_key("revoke") => &[],
+        (T::ShareLink, Request) => &["path", "ttl"],
+        (T::ShareLink, Response) => &["status", "message"],
+        (T::Hold, Request) => &["path", "hold"],
+        (T::Move | T::Rename | T::Copy, Request) => &["path", "destination"],
+        (T::Share, Request) => &["path", "principal", "permissions", "revoke"],
+        (T::UserAdmin, Request) => &["change"],
+        (T::Lockouts, Response) => &["status", "message", "lockouts"],
+        (T::Audit, Response) => &["status", "message", "entries"],
+        (T::Dedupe | T::Usage | T::ChangesSince, Response) => &["status", "message"],
+        (T::Probe, Request) => &["direction", "frames"],
+        (T::Diagnostics, Request) => &["report"],
+        (T::CanI, Request) => &["operation"],
+        (T::Stat | T::Grant | T::Signature | T::UploadDir | T::CanI | T::Thumbnail, Response) => &["status", "message"],
+        (T::Versions, Response) => &["status", "message", "versions"],
+        (T::Preview, Request) => &["path", "limit"],
+        (T::Preview, Response) => &["status", "message"],
+        (T::UploadDir, Request) => &["path", "size"],
+        (T::DownloadDir, Response) => &["status", "message", "size"],
+        (T::Quota, Response) => &["usage"],
+        (T::Event, Response) => &["event"],
+        (T::Queued, Response) => &["position"],
+        (T::Ping, Request) | (T::Pong, Response) => &["sequence"],
+        _ => return None
+    };
 
-    HashMap::<String, serde_json::Value>::from_iter(total_list)
+    Some(result)
 }
 
-pub fn connect_message(username: String, password: String) -> Message {
-    Message::new(
-        MessageType::Connect, 
-        MessageDirection::Request, 
-        make_message_data(
-            vec!["username", "password"],
-            vec![json!(username), json!(password)]
-        )
-    )
+// Why a MessageBuilder would not build, or a message from a peer was not accepted
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageBuildError {
+    Missing(MessageType, MessageDirection, Vec<String>), //The required fields that were not set
+    Direction(MessageType, MessageDirection), //Such as a Pong request
+    Invalid(String, String), //A field whose value could not be serialized, and why
+    Limit(String) //Which limit on what a peer may send the message goes past
+}
+impl Display for MessageBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(kind, direction, fields) => write!(f, "a {kind} {direction} needs the field(s) {}", fields.join(", ")),
+            Self::Direction(kind, direction) => write!(f, "a {kind} is never sent as a {direction}"),
+            Self::Invalid(field, reason) => write!(f, "the field '{field}' could not be serialized because '{reason}'"),
+            Self::Limit(reason) => write!(f, "the message {reason}")
+        }
+    }
+}
+impl std::error::Error for MessageBuildError { }
+impl From<MessageBuildError> for HermesError {
+    fn from(value: MessageBuildError) -> Self {
+        Self::Invalid(value.to_string())
+    }
+}
+
+// Builds a message field by field, checking when it is built that it has everything its type and direction need,
+// rather than leaving a missing field to be found when the other side cannot extract it.
+pub struct MessageBuilder {
+    message_type: MessageType,
+    direction: MessageDirection,
+    data: HashMap<String, serde_json::Value>,
+    invalid: Option<MessageBuildError> //The first field that could not be serialized
+}
+impl MessageBuilder {
+    pub fn new(message_type: MessageType, direction: MessageDirection) -> Self {
+        Self {
+            message_type,
+            direction,
+            data: HashMap::new(),
+            invalid: None
+        }
+    }
+    pub fn request(message_type: MessageType) -> Self {
+        Self::new(message_type, MessageDirection::Request)
+    }
+    pub fn response(message_type: MessageType) -> Self {
+        Self::new(message_type, MessageDirection::Response)
+    }
+
+    pub fn field<T: Serialize>(mut self, name: &str, value: T) -> Self {
+        match serde_json::to_value(value) {
+            Ok(v) => {
+                self.data.insert(name.to_string(), v);
+            },
+            Err(e) => {
+                self.invalid.get_or_insert(MessageBuildError::Invalid(name.to_string(), e.to_string()));
+            }
+        }
+
+        self
+    }
+    // Leaves the field out when there is no value, rather than sending null
+    pub fn optional_field<T: Serialize>(self, name: &str, value: Option<T>) -> Self {
+        match value {
+            Some(v) => self.field(name, v),
+            None => self
+        }
+    }
+
+    pub fn build(self) -> Result<Message, MessageBuildError> {
+        if let Some(e) = self.invalid {
+            return Err(e);
+        }
+
+        let result = Message::unchecked(self.message_type, self.direction, self.data);
+        result.validate()?;
+        Ok(result)
+    }
+    // The free functions below build every message through here. They always set what their message needs,
+    // so a failure is a mistake in the function, and is caught the first time it runs in any build.
+    fn built(self) -> Message {
+        self.build().unwrap_or_else(|e| panic!("{e}"))
+    }
 }
-pub fn extract_connect_message(message: Message) -> Option<(String, String)> {
+
+pub fn connect_message(username: String, password: String, version: ProtocolVersion) -> Message {
+    MessageBuilder::request(MessageType::Connect)
+        .field("username", username)
+        .field("password", password)
+        .field("version", version)
+        .built()
+}
+// Clients that predate version negotiation do not send a version, so it is reported as None rather than failing the extraction.
+pub fn extract_connect_message(message: Message) -> Option<(String, String, Option<ProtocolVersion>)> {
     if *message.message_type() != MessageType::Connect {
         return None
     }   
 
     let username: Option<String> = message.extract_as("username");
     let password: Option<String> = message.extract_as("password");
+    let version: Option<ProtocolVersion> = message.extract_as("version");
     
     match (username, password) {
-        (Some(u), Some(p)) => Some( (u, p) ),
+        (Some(u), Some(p)) => Some( (u, p, version) ),
         (_, _) => None
     }
 }
 
+pub fn resume_connect_message(token: &str, version: ProtocolVersion) -> Message {
+    MessageBuilder::request(MessageType::Connect)
+        .field("token", token)
+        .field("version", version)
+        .built()
+}
+pub fn extract_resume_connect_message(message: Message) -> Option<(String, Option<ProtocolVersion>)> {
+    if *message.message_type() != MessageType::Connect {
+        return None
+    }
+
+    let token: Option<String> = message.extract_as("token");
+    let version: Option<ProtocolVersion> = message.extract_as("version");
+
+    Some((token?, version))
+}
+
+pub fn grant_connect_message(grant: &str, version: ProtocolVersion) -> Message {
+    MessageBuilder::request(MessageType::Connect)
+        .field("grant", grant)
+        .field("version", version)
+        .built()
+}
+pub fn extract_grant_connect_message(message: Message) -> Option<(String, Option<ProtocolVersion>)> {
+    if *message.message_type() != MessageType::Connect {
+        return None
+    }
+
+    let grant: Option<String> = message.extract_as("grant");
+    let version: Option<ProtocolVersion> = message.extract_as("version");
+
+    Some((grant?, version))
+}
+
+// Connects anonymously with a share link, which then allows downloading its one file
+pub fn share_connect_message(link: &str, version: ProtocolVersion) -> Message {
+    MessageBuilder::request(MessageType::Connect)
+        .field("share", link)
+        .field("version", version)
+        .built()
+}
+pub fn extract_share_connect_message(message: Message) -> Option<(String, Option<ProtocolVersion>)> {
+    if *message.message_type() != MessageType::Connect {
+        return None
+    }
+
+    let link: Option<String> = message.extract_as("share");
+    let version: Option<ProtocolVersion> = message.extract_as("version");
+
+    Some((link?, version))
+}
+
+// Connects anonymously as the server's read-only guest, on servers that allow guests
+pub fn guest_connect_message(version: ProtocolVersion) -> Message {
+    MessageBuilder::request(MessageType::Connect)
+        .field("guest", true)
+        .field("version", version)
+        .built()
+}
+pub fn extract_guest_connect_message(message: Message) -> Option<Option<ProtocolVersion>> {
+    if *message.message_type() != MessageType::Connect || message.extract_as::<bool>("guest") != Some(true) {
+        return None
+    }
+
+    Some(message.extract_as("version"))
+}
+
 pub fn ack_messsage(direction: MessageDirection, code: HttpCodes, message: Option<String>) -> Message {
-    let code_str = code.to_string();
-    let data = make_message_data(
-        vec!["code", "message"], 
-        vec![
-            json!(code), 
-            json!(
-                if let Some(msg) = message {
-                    msg
-                }
-                else {
-                    code_str
-                }
-            )
-        ]
-    );
+    let message = message.unwrap_or_else(|| code.to_string());
 
-    Message::new(MessageType::Ack, direction, data)
+    MessageBuilder::new(MessageType::Ack, direction)
+        .field("code", code)
+        .field("message", message)
+        .built()
 }
 pub fn extract_ack_message(message: Message) -> Option<(HttpCodes, String)> {
     if *message.message_type() != MessageType::Ack {
@@ -222,64 +616,307 @@ pub fn extract_ack_message(message: Message) -> Option<(HttpCodes, String)> {
     }
 }
 
+// Acks carry their status as 'code', while every other response calls it 'status'
+pub fn response_status(message: &Message) -> Option<HttpCodes> {
+    message.extract_as("status").or_else(|| message.extract_as("code"))
+}
+
+pub fn connect_ack_message(code: HttpCodes, message: Option<String>, version: Option<ProtocolVersion>, resume: Option<ResumeToken>) -> Message {
+    let mut result = ack_messsage(MessageDirection::Response, code, message);
+    if let Some(v) = version {
+        result.data.insert(String::from("version"), json!(v));
+    }
+    if let Some(r) = resume {
+        result.data.insert(String::from("resume"), json!(r));
+    }
+
+    result
+}
+pub fn extract_connect_ack_message(message: Message) -> Option<(HttpCodes, String, Option<ProtocolVersion>, Option<ResumeToken>)> {
+    let version: Option<ProtocolVersion> = message.extract_as("version");
+    let resume: Option<ResumeToken> = message.extract_as("resume");
+    let (code, msg) = extract_ack_message(message)?;
+
+    Some((code, msg, version, resume))
+}
+
+// Servers that tune frame sizes say so in their Connect ack. Clients that find nothing here use a fixed size.
+pub fn advertise_frame_bounds(mut message: Message, bounds: FrameSizeBounds) -> Message {
+    message.data.insert(String::from("frame_bounds"), json!(bounds));
+    message
+}
+pub fn extract_frame_bounds(message: &Message) -> Option<FrameSizeBounds> {
+    message.extract_as("frame_bounds")
+}
+// The server closes a connection that sends nothing for this many seconds, so clients know how often to ping. Absent when idle connections are kept.
+pub fn advertise_idle_timeout(mut message: Message, secs: u64) -> Message {
+    message.data.insert(String::from("idle_timeout"), json!(secs));
+    message
+}
+pub fn extract_idle_timeout(message: &Message) -> Option<u64> {
+    message.extract_as("idle_timeout")
+}
+
+// Both the Connect request and its ack list the optional features their sender speaks. Peers that list nothing predate the exchange.
+pub fn advertise_capabilities(mut message: Message, capabilities: &Capabilities) -> Message {
+    message.data.insert(String::from("capabilities"), json!(capabilities));
+    message
+}
+pub fn extract_capabilities(message: &Message) -> Option<Capabilities> {
+    message.extract_as("capabilities")
+}
+
+// Both the Connect request and its ack list the compressions their sender speaks, most preferred first. Names a side does not know are dropped.
+pub fn advertise_compressions(mut message: Message, compressions: &[Compression]) -> Message {
+    message.data.insert(String::from("compressions"), json!(compressions));
+    message
+}
+pub fn extract_compressions(message: &Message) -> Vec<Compression> {
+    message.extract_as::<Vec<String>>("compressions")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|x| x.parse().ok())
+        .collect()
+}
+// Both the Connect request and its ack list the codecs their sender reads. Once the ack is sent, each side writes in the codec the server chose.
+pub fn advertise_codecs(mut message: Message, codecs: &[Codec]) -> Message {
+    message.data.insert(String::from("codecs"), json!(codecs));
+    message
+}
+pub fn extract_codecs(message: &Message) -> Vec<Codec> {
+    message.extract_as::<Vec<String>>("codecs")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|x| x.parse().ok())
+        .collect()
+}
+// Marks the file frames after an Upload or UploadDir request, or after a Download, Versions, or DownloadDir response, as compressed.
+// A Download or DownloadDir request carries it to ask for compression, which the server may decline.
+pub fn attach_compression(mut message: Message, compression: Compression) -> Message {
+    message.data.insert(String::from("compression"), json!(compression));
+    message
+}
+pub fn extract_compression(message: &Message) -> Option<Compression> {
+    message.extract_as("compression")
+}
+// An Upload, Download, or UploadDir request carries the round trips the client timed before it, so the server can record them with the transfer
+pub fn attach_latency(mut message: Message, latency: &Latency) -> Message {
+    message.data.insert(String::from("latency"), json!(latency));
+    message
+}
+pub fn extract_latency(message: &Message) -> Option<Latency> {
+    message.extract_as("latency")
+}
+// A Download request carries it to have the file sent as channel frames tagged with the request's ID, which sessions that agreed on multiplex allow.
+// The server sets it on the response when it does so, and the frame count is then meaningless.
+pub fn attach_channel(mut message: Message) -> Message {
+    message.data.insert(String::from("channel"), json!(true));
+    message
+}
+pub fn extract_channel(message: &Message) -> bool {
+    message.extract_as("channel").unwrap_or(false)
+}
+// Servers with a QUIC listener name its port, and the fingerprint of the certificate it presents, in their Connect ack.
+// The client connects to the same host it reached over TCP, and trusts only that certificate.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct QuicOffer {
+    pub port: u16,
+    pub fingerprint: String //SHA-256 of the certificate, in hex
+}
+pub fn advertise_quic(mut message: Message, offer: &QuicOffer) -> Message {
+    message.data.insert(String::from("quic"), json!(offer));
+    message
+}
+pub fn extract_quic_offer(message: &Message) -> Option<QuicOffer> {
+    message.extract_as("quic")
+}
+// A Download or plain Upload request carries it to move the file frames over the session's QUIC connection instead of the TCP one.
+// The server sets it on the response only when it will do so, so a response without it means the frames stay on TCP.
+pub fn attach_quic(mut message: Message) -> Message {
+    message.data.insert(String::from("quic"), json!(true));
+    message
+}
+pub fn extract_quic(message: &Message) -> bool {
+    message.extract_as("quic").unwrap_or(false)
+}
+// A transfer someone is waiting on, such as one typed at the shell, carries it to be started before the bulk of what is queued.
+// The server only honours it for single files.
+pub fn attach_interactive(mut message: Message) -> Message {
+    message.data.insert(String::from("interactive"), json!(true));
+    message
+}
+pub fn extract_interactive(message: &Message) -> bool {
+    message.extract_as("interactive").unwrap_or(false)
+}
+
+// The session token is handed out in the Connect ack, and every later request must carry it
+pub fn session_ack(mut message: Message, session: &SessionToken) -> Message {
+    message.data.insert(String::from("session"), json!(session));
+    message
+}
+pub fn extract_session_ack(message: &Message) -> Option<SessionToken> {
+    message.extract_as("session")
+}
+// Mutating requests replayed from an offline queue carry a key, so the server can tell a replay of something it already did from a new request
+pub fn attach_idempotency_key(mut message: Message, key: &str) -> Message {
+    message.data.insert(String::from("idempotency_key"), json!(key.to_string()));
+    message
+}
+pub fn extract_idempotency_key(message: &Message) -> Option<String> {
+    message.extract_as("idempotency_key")
+}
+pub fn attach_session(mut message: Message, token: &str) -> Message {
+    message.data.insert(String::from("session"), json!(token.to_string()));
+    message
+}
+pub fn extract_session(message: &Message) -> Option<String> {
+    message.extract_as("session")
+}
+
 pub fn close_message() -> Message {
-    Message::new(MessageType::Close, MessageDirection::Request, HashMap::new())
+    MessageBuilder::request(MessageType::Close).built()
 }
+// Sent by the server when it ends a connection itself, such as when it is shutting down, in place of the next response
+pub fn close_with_reason(reason: &str) -> Message {
+    MessageBuilder::response(MessageType::Close)
+        .field("reason", reason)
+        .built()
+}
+pub fn extract_close_reason(message: &Message) -> Option<String> {
+    if *message.message_type() != MessageType::Close {
+        return None;
+    }
 
-pub fn upload_message(name: &str, f_type: FileType, frame_count: u32) -> Message {
-    Message::new(
-        MessageType::Upload,
-        MessageDirection::Request,
-        make_message_data(
-            vec!["name", "type", "size"],
-            vec![json!(name.to_string()), json!(f_type), json!(frame_count)]
-        )
-    )
+    message.extract_as("reason")
+}
+
+// The checksum always covers the complete file, even when the upload resumes at an offset
+pub fn upload_message(name: &str, f_type: FileType, frame_count: u64, offset: u64, checksum: Option<Checksum>, provenance: Option<Provenance>) -> Message {
+    MessageBuilder::request(MessageType::Upload)
+        .field("name", name.to_string())
+        .field("type", f_type)
+        .field("size", frame_count)
+        .field("offset", offset)
+        .field("checksum", checksum)
+        .field("provenance", provenance)
+        .built()
+}
+// Provenance is optional, and is kept apart from extract_upload_message so older uploads are read the same way
+pub fn extract_upload_provenance(message: &Message) -> Option<Provenance> {
+    if *message.message_type() != MessageType::Upload {
+        return None;
+    }
+
+    message.extract_as::<Provenance>("provenance").filter(|x| !x.is_empty())
+}
+// A client that encrypted the file says how, so listings can tell other clients. The server only keeps it.
+pub fn attach_encryption(mut message: Message, encryption: &Encryption) -> Message {
+    message.data.insert(String::from("encryption"), json!(encryption));
+    message
+}
+pub fn extract_upload_encryption(message: &Message) -> Option<Encryption> {
+    if *message.message_type() != MessageType::Upload {
+        return None;
+    }
+
+    message.extract_as("encryption")
 }
-pub fn extract_upload_message(message: Message) -> Option<(String, FileType, u32)> {
+// An upload without an offset starts from the beginning of the file
+pub fn extract_upload_message(message: Message) -> Option<(String, FileType, u64, u64, Option<Checksum>)> {
     if *message.message_type() != MessageType::Upload {
         return None
     } 
 
     let name: Option<String> = message.extract_as("name");
     let f_type: Option<FileType> = message.extract_as("type");
-    let frame_count: Option<u32> = message.extract_as("size");
+    let frame_count: Option<u64> = message.extract_as("size");
+    let offset: u64 = message.extract_as("offset").unwrap_or(0);
+    let checksum: Option<Checksum> = message.extract_as("checksum");
 
     match (name, f_type, frame_count) {
-        (Some(n), Some(t), Some(f)) => Some((n, t, f)),
+        (Some(n), Some(t), Some(f)) => Some((n, t, f, offset, checksum)),
         _ => None
     }
 }
+// Tells the uploader where the server will start writing. On a Conflict, offset is the number of bytes the server has already confirmed.
+pub fn upload_message_response(status: HttpCodes, message: &str, offset: u64) -> Message {
+    MessageBuilder::response(MessageType::Upload)
+        .field("status", status)
+        .field("message", message)
+        .field("offset", offset)
+        .built()
+}
+pub fn extract_upload_response_message(message: Message) -> Option<(HttpCodes, String, u64)> {
+    if *message.message_type() != MessageType::Upload {
+        return None
+    }
 
-pub fn download_message_request(path: &str) -> Message {
-    Message::new(
-        MessageType::Download,
-        MessageDirection::Request,
-        make_message_data(
-            vec!["path"],
-            vec![json!(path)]
-        )
-    )
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let offset: Option<u64> = message.extract_as("offset");
+
+    match (status, msg, offset) {
+        (Some(s), Some(m), Some(o)) => Some((s, m, o)),
+        _ => None
+    }
 }
-pub fn download_message_response(status: HttpCodes, message: &str, kind: FileType, frame_count: u32) -> Message {
-    Message::new(
-        MessageType::Download, 
-        MessageDirection::Response,
-        make_message_data(
-            vec!["status", "message", "kind", "size"],
-            vec![json!(status), json!(message), json!(kind), json!(frame_count)]
-        )
-    )
+
+pub fn download_message_request(path: &str, offset: u64, length: Option<u64>) -> Message {
+    MessageBuilder::request(MessageType::Download)
+        .field("path", path)
+        .field("offset", offset)
+        .field("length", length)
+        .built()
+}
+#[derive(Clone, PartialEq, Debug)]
+pub struct DownloadResponse {
+    pub status: HttpCodes,
+    pub message: String,
+    pub kind: FileType,
+    pub frame_count: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub checksum: Option<Checksum> //Covers the complete file, not just the requested range, so a resumed download can be verified once it is whole
+}
+impl DownloadResponse {
+    // A response that carries no data, used for every failure
+    pub fn failure(status: HttpCodes, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            kind: FileType::Binary,
+            frame_count: 0,
+            offset: 0,
+            length: 0,
+            checksum: None
+        }
+    }
+}
+
+pub fn download_message_response(response: DownloadResponse) -> Message {
+    MessageBuilder::response(MessageType::Download)
+        .field("status", response.status)
+        .field("message", response.message)
+        .field("kind", response.kind)
+        .field("size", response.frame_count)
+        .field("offset", response.offset)
+        .field("length", response.length)
+        .field("checksum", response.checksum)
+        .built()
 }
-pub fn extract_download_request_message(message: Message) -> Option<String> {
+// A missing offset means the start of the file, and a missing length means everything after the offset
+pub fn extract_download_request_message(message: Message) -> Option<(String, u64, Option<u64>)> {
     if *message.message_type() != MessageType::Download {
         return None;
     }
 
     let path: Option<String> = message.extract_as("path");
-    path
+    let offset: u64 = message.extract_as("offset").unwrap_or(0);
+    let length: Option<u64> = message.extract_as("length");
+
+    Some((path?, offset, length))
 }
-pub fn extract_download_response_message(message: Message) -> Option<(HttpCodes, String, FileType, u32)> {
+pub fn extract_download_response_message(message: Message) -> Option<DownloadResponse> {
     if *message.message_type() != MessageType::Download {
         return None;
     }
@@ -287,51 +924,118 @@ pub fn extract_download_response_message(message: Message) -> Option<(HttpCodes,
     let status: Option<HttpCodes> = message.extract_as("status");
     let msg: Option<String> = message.extract_as("message");
     let kind: Option<FileType> = message.extract_as("kind");
-    let size: Option<u32> = message.extract_as("size");
+    let size: Option<u64> = message.extract_as("size");
+    let offset: Option<u64> = message.extract_as("offset");
+    let length: Option<u64> = message.extract_as("length");
+    let checksum: Option<Checksum> = message.extract_as("checksum");
 
-    match (status, msg, kind, size) {
-        (Some(c), Some(m), Some(t), Some(s)) => Some((c, m, t, s)),
+    match (status, msg, kind, size, offset, length) {
+        (Some(c), Some(m), Some(t), Some(s), Some(o), Some(l)) => Some(
+            DownloadResponse {
+                status: c,
+                message: m,
+                kind: t,
+                frame_count: s,
+                offset: o,
+                length: l,
+                checksum
+            }
+        ),
         _ => None
     }
 }
 
-pub fn delete_message(path: &str) -> Message {
-    Message::new(
-        MessageType::Delete,
-        MessageDirection::Request,
-        make_message_data(
-            vec!["path"],
-            vec![json!(path)]
-        )
-    )
+// Directories are only deleted when recursive is set, and then everything beneath them goes too
+pub fn delete_message(path: &str, recursive: bool) -> Message {
+    MessageBuilder::request(MessageType::Delete)
+        .field("path", path)
+        .field("recursive", recursive)
+        .built()
 }
-pub fn extract_delete_message(message: Message) -> Option<String> {
+pub fn extract_delete_message(message: Message) -> Option<(String, bool)> {
     if *message.message_type() != MessageType::Delete {
         return None;
     }
 
     let path: Option<String> = message.extract_as("path");
-    path
+    let recursive: bool = message.extract_as("recursive").unwrap_or(false);
+    Some((path?, recursive))
+}
+// The ack to a delete says how many files and directories went, counting the target itself
+pub fn attach_removed_count(mut message: Message, removed: u64) -> Message {
+    message.data.insert(String::from("removed"), json!(removed));
+    message
+}
+pub fn extract_removed_count(message: &Message) -> Option<u64> {
+    message.extract_as("removed")
+}
+
+// Which part of a directory to list. Everything is optional, so a plain Dir request lists the whole working directory.
+// Filters and paging apply to the folder itself, and the folders beneath it are listed whole.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct DirQuery {
+    pub path: Option<String>, //Relative to the working directory
+    pub filters: Vec<String>, //Globs such as '*.txt'. An entry is listed if its name matches any of them.
+    pub offset: u64,
+    pub limit: Option<u64>,
+    pub depth: u32, //How many levels of folders beneath are listed too. Zero lists only the folder itself, with its folders left empty.
+    pub dotfiles: Option<bool>, //Whether names starting with '.' are listed. None leaves it to the server.
+    pub internal: bool //Whether files the server keeps for itself are listed, which only administrators may ask for, and only when the server allows it
+}
+impl DirQuery {
+    pub fn matches(&self, name: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|x| glob_matches(x, name))
+    }
 }
 
 pub fn dir_message_request() -> Message {
-    Message::new(
-        MessageType::Dir,
-        MessageDirection::Request,
-        HashMap::<String, serde_json::Value>::new()
-    )
+    dir_query_request(&DirQuery::default())
 }
-pub fn dir_message_response(status: HttpCodes, message: &str, curr_dir: &str, frame_count: u32) -> Message {
-    Message::new(
-        MessageType::Dir,
-        MessageDirection::Response,
-        make_message_data(
-            vec!["status", "message", "curr_dir", "size"],
-            vec![json!(status), json!(message), json!(curr_dir), json!(frame_count)]
-        )
+pub fn dir_query_request(query: &DirQuery) -> Message {
+    MessageBuilder::request(MessageType::Dir)
+        .field("path", &query.path)
+        .field("filters", &query.filters)
+        .field("offset", query.offset)
+        .field("limit", query.limit)
+        .field("depth", query.depth)
+        .field("dotfiles", query.dotfiles)
+        .field("internal", query.internal)
+        .built()
+}
+pub fn extract_dir_request_message(message: Message) -> Option<DirQuery> {
+    if *message.message_type() != MessageType::Dir {
+        return None;
+    }
+
+    Some(
+        DirQuery {
+            path: message.extract_as("path"),
+            filters: message.extract_as("filters").unwrap_or_default(),
+            offset: message.extract_as("offset").unwrap_or(0),
+            limit: message.extract_as("limit"),
+            depth: message.extract_as("depth").unwrap_or(0),
+            dotfiles: message.extract_as("dotfiles"),
+            internal: message.extract_as("internal").unwrap_or(false)
+        }
     )
 }
-pub fn extract_dir_response_message(message: Message) -> Option<(HttpCodes, String, String, u32)> {
+// How many entries matched before paging, so a client knows when it has seen them all
+pub fn dir_page_total(mut message: Message, total: u64) -> Message {
+    message.data.insert(String::from("total"), json!(total));
+    message
+}
+pub fn extract_dir_page_total(message: &Message) -> Option<u64> {
+    message.extract_as("total")
+}
+pub fn dir_message_response(status: HttpCodes, message: &str, curr_dir: &str, frame_count: u64) -> Message {
+    MessageBuilder::response(MessageType::Dir)
+        .field("status", status)
+        .field("message", message)
+        .field("curr_dir", curr_dir)
+        .field("size", frame_count)
+        .built()
+}
+pub fn extract_dir_response_message(message: Message) -> Option<(HttpCodes, String, String, u64)> {
     if *message.message_type() != MessageType::Dir {
         return None;
     }
@@ -339,7 +1043,7 @@ pub fn extract_dir_response_message(message: Message) -> Option<(HttpCodes, Stri
     let status: Option<HttpCodes> = message.extract_as("status");
     let msg: Option<String> = message.extract_as("message");
     let curr_dir: Option<String> = message.extract_as("curr_dir");
-    let size: Option<u32> = message.extract_as("size");
+    let size: Option<u64> = message.extract_as("size");
 
     match (status, msg, curr_dir, size) {
         (Some(s), Some(m), Some(c), Some(sz)) => Some((s, m, c, sz)),
@@ -347,18 +1051,14 @@ pub fn extract_dir_response_message(message: Message) -> Option<(HttpCodes, Stri
     }
 }
 
-pub fn move_message(path: &str) -> Message {
-    Message::new(
-        MessageType::Move,
-        MessageDirection::Request,
-        make_message_data(
-            vec!["path"],
-            vec![json!(path)]
-        )
-    )
+// Changes the working directory. Nothing on the server is changed.
+pub fn change_dir_message(path: &str) -> Message {
+    MessageBuilder::request(MessageType::ChangeDir)
+        .field("path", path)
+        .built()
 }
-pub fn extract_move_message(message: Message) -> Option<String> {
-    if *message.message_type() != MessageType::Move {
+pub fn extract_change_dir_message(message: Message) -> Option<String> {
+    if *message.message_type() != MessageType::ChangeDir {
         return None;
     }
 
@@ -366,52 +1066,1509 @@ pub fn extract_move_message(message: Message) -> Option<String> {
     path
 }
 
-pub fn subfolder_message(path: &str, action: SubfolderAction) -> Message {
-    Message::new(
-        MessageType::Subfolder,
-        MessageDirection::Request,
-        make_message_data(
-            vec!["path", "action"],
-            vec![json!(path), json!(action)]
-        )
-    )
+// Recursive only matters for deletes. Without it, a folder that still has anything in it is refused.
+pub fn subfolder_message(path: &str, action: SubfolderAction, recursive: bool) -> Message {
+    MessageBuilder::request(MessageType::Subfolder)
+        .field("path", path)
+        .field("action", action)
+        .field("recursive", recursive)
+        .built()
 }
-pub fn extract_subfolder_message(message: Message) -> Option<(String, SubfolderAction)> {
+pub fn extract_subfolder_message(message: Message) -> Option<(String, SubfolderAction, bool)> {
     if *message.message_type() != MessageType::Subfolder {
         return None;
     }
 
     let path: Option<String> = message.extract_as("path");
     let action: Option<SubfolderAction> = message.extract_as("action");
+    let recursive: bool = message.extract_as("recursive").unwrap_or(false);
 
     match (path, action) {
-        (Some(p), Some(a)) => Some((p, a)),
+        (Some(p), Some(a)) => Some((p, a, recursive)),
         _ => None
     }
 }
 
-pub fn stats_request_message() -> Message {
-    Message::new(
-        MessageType::Stats,
-        MessageDirection::Request,
-        HashMap::<String, serde_json::Value>::new()
-    )
+fn source_destination_message(kind: MessageType, source: &str, destination: &str) -> Message {
+    MessageBuilder::request(kind)
+        .field("path", source)
+        .field("destination", destination)
+        .built()
 }
-pub fn stats_response_message(stats: TransferStats) -> Message {
-    Message::new(
-        MessageType::Stats,
-        MessageDirection::Response,
-        make_message_data(
-            vec!["stats"], 
-            vec![json!(stats)]
-        )
-    )
+fn extract_source_destination(kind: MessageType, message: Message) -> Option<(String, String)> {
+    if *message.message_type() != kind {
+        return None;
+    }
+
+    let source: Option<String> = message.extract_as("path");
+    let destination: Option<String> = message.extract_as("destination");
+
+    match (source, destination) {
+        (Some(s), Some(d)) => Some((s, d)),
+        _ => None
+    }
 }
-pub fn extract_stats_response_message(message: Message) -> Option<TransferStats> {
-    if *message.message_type() != MessageType::Stats {
+// Moves a file or folder. A destination that is an existing folder receives it under its own name, as mv does. Nothing is overwritten.
+pub fn move_message(source: &str, destination: &str) -> Message {
+    source_destination_message(MessageType::Move, source, destination)
+}
+pub fn extract_move_message(message: Message) -> Option<(String, String)> {
+    extract_source_destination(MessageType::Move, message)
+}
+// Gives a file or folder exactly the path it is given. Nothing is overwritten.
+pub fn rename_message(source: &str, destination: &str) -> Message {
+    source_destination_message(MessageType::Rename, source, destination)
+}
+pub fn extract_rename_message(message: Message) -> Option<(String, String)> {
+    extract_source_destination(MessageType::Rename, message)
+}
+// Duplicates a file. The copy belongs to whoever asked for it.
+pub fn copy_message(source: &str, destination: &str) -> Message {
+    source_destination_message(MessageType::Copy, source, destination)
+}
+pub fn extract_copy_message(message: Message) -> Option<(String, String)> {
+    extract_source_destination(MessageType::Copy, message)
+}
+
+// What an access list entry lets someone do with a file, or with everything beneath a folder
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read, //Download and copy
+    Write, //Overwrite, rename, and create things inside a folder
+    Delete
+}
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Delete => "delete"
+        };
+
+        write!(f, "{text}")
+    }
+}
+// Who an access list entry applies to
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Principal {
+    User(String),
+    Group(String)
+}
+impl Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::User(u) => write!(f, "user '{u}'"),
+            Self::Group(g) => write!(f, "group '{g}'")
+        }
+    }
+}
+// Grants, or with revoke set takes back, permissions on a file or folder. Only its owner, or an administrator, may share it.
+pub fn share_message(path: &str, principal: &Principal, permissions: &[Permission], revoke: bool) -> Message {
+    MessageBuilder::request(MessageType::Share)
+        .field("path", path)
+        .field("principal", principal)
+        .field("permissions", permissions)
+        .field("revoke", revoke)
+        .built()
+}
+pub fn extract_share_message(message: Message) -> Option<(String, Principal, Vec<Permission>, bool)> {
+    if *message.message_type() != MessageType::Share {
         return None;
     }
 
-    let stats: Option<TransferStats> = message.extract_as("stats");
-    stats
-}
\ No newline at end of file
+    let path: Option<String> = message.extract_as("path");
+    let principal: Option<Principal> = message.extract_as("principal");
+    let permissions: Option<Vec<Permission>> = message.extract_as("permissions");
+    let revoke: Option<bool> = message.extract_as("revoke");
+
+    match (path, principal, permissions, revoke) {
+        (Some(p), Some(who), Some(perms), Some(r)) => Some((p, who, perms, r)),
+        _ => None
+    }
+}
+
+// What a user may do on the server. Read-only users can look at and download anything they have access to, but change nothing.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    #[default]
+    User,
+    ReadOnly
+}
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Admin => "admin",
+            Self::User => "user",
+            Self::ReadOnly => "read only"
+        };
+
+        write!(f, "{text}")
+    }
+}
+// Changes an administrator can make to the user database without editing it by hand
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum UserAdminAction {
+    Create { username: String, password: String, role: Role },
+    Delete { username: String },
+    Disable { username: String, disabled: bool },
+    SetPassword { username: String, password: String },
+    SetRole { username: String, role: Role },
+    SetQuota { username: String, quota: Option<u64> } //In bytes. None falls back to the server's default.
+}
+impl UserAdminAction {
+    pub fn username(&self) -> &str {
+        match self {
+            Self::Create { username, .. } | Self::Delete { username } | Self::Disable { username, .. } | Self::SetPassword { username, .. } | Self::SetRole { username, .. } | Self::SetQuota { username, .. } => username
+        }
+    }
+}
+pub fn user_admin_message(action: &UserAdminAction) -> Message {
+    MessageBuilder::request(MessageType::UserAdmin)
+        .field("change", action)
+        .built()
+}
+pub fn extract_user_admin_message(message: Message) -> Option<UserAdminAction> {
+    if *message.message_type() != MessageType::UserAdmin {
+        return None;
+    }
+
+    message.extract_as("change")
+}
+
+// What a run of failed logins was counted against
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum LockoutSubject {
+    Address(String),
+    User(String)
+}
+impl Display for LockoutSubject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Address(a) => write!(f, "address {a}"),
+            Self::User(u) => write!(f, "user '{u}'")
+        }
+    }
+}
+// A subject that has to wait before it may try to log in again
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Lockout {
+    pub subject: LockoutSubject,
+    pub failures: u32,
+    pub until: u64, //Seconds since the Unix epoch
+    pub locked: bool //Whether it has failed often enough to be locked out, rather than only slowed down
+}
+// Lists the subjects that are locked out or slowed down. Only administrators may ask.
+pub fn lockouts_request() -> Message {
+    MessageBuilder::request(MessageType::Lockouts).built()
+}
+// Forgets the failed logins of one subject, which lets it log in straight away
+pub fn unlock_request(subject: &LockoutSubject) -> Message {
+    MessageBuilder::request(MessageType::Lockouts)
+        .field("unlock", subject)
+        .built()
+}
+// Some(None) lists the lockouts, and Some(Some(subject)) clears one
+pub fn extract_lockouts_request(message: Message) -> Option<Option<LockoutSubject>> {
+    if *message.message_type() != MessageType::Lockouts {
+        return None;
+    }
+
+    match message.extract("unlock") {
+        Some(_) => message.extract_as("unlock").map(Some),
+        None => Some(None)
+    }
+}
+pub fn lockouts_response(status: HttpCodes, message: &str, lockouts: &[Lockout]) -> Message {
+    MessageBuilder::response(MessageType::Lockouts)
+        .field("status", status)
+        .field("message", message)
+        .field("lockouts", lockouts)
+        .built()
+}
+pub fn extract_lockouts_response(message: Message) -> Option<(HttpCodes, String, Vec<Lockout>)> {
+    if *message.message_type() != MessageType::Lockouts {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let lockouts: Option<Vec<Lockout>> = message.extract_as("lockouts");
+
+    match (status, msg, lockouts) {
+        (Some(s), Some(m), Some(l)) => Some((s, m, l)),
+        _ => None
+    }
+}
+
+// One request as the audit log recorded it
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub time: u64, //Seconds since the Unix epoch
+    pub peer: String,
+    pub user: String,
+    pub operation: String, //The message type, such as 'upload'
+    pub status: Option<u16>, //None when the request was never answered
+    pub path: Option<String> //As the server shows it, such as '/docs/a.txt'
+}
+// Which entries an Audit request wants. Every filter that is set has to match.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub path: Option<String>, //The path itself, or anything beneath it
+    pub limit: Option<usize> //The server picks one when it is not set, and caps it
+}
+// Asks for the newest entries in the audit log, newest first. Only administrators may ask.
+pub fn audit_request(filter: &AuditFilter) -> Message {
+    MessageBuilder::request(MessageType::Audit)
+        .field("filter", filter)
+        .built()
+}
+// A request without a filter asks for everything
+pub fn extract_audit_request(message: Message) -> Option<AuditFilter> {
+    if *message.message_type() != MessageType::Audit {
+        return None;
+    }
+
+    match message.extract("filter") {
+        Some(_) => message.extract_as("filter"),
+        None => Some(AuditFilter::default())
+    }
+}
+pub fn audit_response(status: HttpCodes, message: &str, entries: &[AuditEntry]) -> Message {
+    MessageBuilder::response(MessageType::Audit)
+        .field("status", status)
+        .field("message", message)
+        .field("entries", entries)
+        .built()
+}
+pub fn extract_audit_response(message: Message) -> Option<(HttpCodes, String, Vec<AuditEntry>)> {
+    if *message.message_type() != MessageType::Audit {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let entries: Option<Vec<AuditEntry>> = message.extract_as("entries");
+
+    match (status, msg, entries) {
+        (Some(s), Some(m), Some(e)) => Some((s, m, e)),
+        _ => None
+    }
+}
+
+// How much disk identical files take, and how much linking them together saves. Only files whose checksum is known are compared.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct DedupeReport {
+    pub files: u64, //Files with a known checksum
+    pub unhashed: u64, //Files left out, since their checksum is not known
+    pub unique: u64, //Distinct contents among the files
+    pub duplicates: u64, //Files whose contents another file has too
+    pub saved_bytes: u64, //What the duplicates already linked together would take as separate copies
+    pub linkable_bytes: u64 //What linking the rest of the duplicates would save
+}
+// Asks how much space duplicate files take. Only administrators may ask.
+pub fn dedupe_request() -> Message {
+    MessageBuilder::request(MessageType::Dedupe).built()
+}
+// The report is left off when the request is refused
+pub fn dedupe_response(status: HttpCodes, message: &str, report: Option<&DedupeReport>) -> Message {
+    MessageBuilder::response(MessageType::Dedupe)
+        .field("status", status)
+        .field("message", message)
+        .field("report", report)
+        .built()
+}
+pub fn extract_dedupe_response(message: Message) -> Option<(HttpCodes, String, Option<DedupeReport>)> {
+    if *message.message_type() != MessageType::Dedupe {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, message.extract_as("report"))),
+        _ => None
+    }
+}
+
+// How much one top-level folder holds. Files are counted from the file database, and bytes from what is on disk, which the server may have measured a little while ago.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct FolderUsage {
+    pub files: u64, //Files the server has on record beneath the folder
+    pub bytes: u64 //Everything beneath the folder, recorded or not
+}
+// How full the server's storage is, so a client can warn before an upload that will not fit
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct UsageReport {
+    pub total_bytes: Option<u64>, //The size of the volume the files are kept on, when the server can tell
+    pub free_bytes: Option<u64>, //What is left on it for the server to write
+    pub users: BTreeMap<String, u64>, //What each user stores, counting versions. Only administrators see anyone but themselves.
+    pub folders: BTreeMap<String, FolderUsage> //Each folder at the top of the asker's home
+}
+pub fn usage_request() -> Message {
+    MessageBuilder::request(MessageType::Usage).built()
+}
+// The report is left off when the request is refused
+pub fn usage_response(status: HttpCodes, message: &str, report: Option<&UsageReport>) -> Message {
+    MessageBuilder::response(MessageType::Usage)
+        .field("status", status)
+        .field("message", message)
+        .field("report", report)
+        .built()
+}
+pub fn extract_usage_response(message: Message) -> Option<(HttpCodes, String, Option<UsageReport>)> {
+    if *message.message_type() != MessageType::Usage {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, message.extract_as("report"))),
+        _ => None
+    }
+}
+
+// The server echoes a heartbeat back unchanged. The padding makes the frame as large as the client wants, to find sizes the path drops.
+fn heartbeat(direction: MessageDirection, sequence: u64, padding: &str) -> Message {
+    MessageBuilder::new(MessageType::Heartbeat, direction)
+        .field("sequence", sequence)
+        .field("padding", padding)
+        .built()
+}
+pub fn heartbeat_message(sequence: u64, padding: usize) -> Message {
+    heartbeat(MessageDirection::Request, sequence, &"x".repeat(padding))
+}
+pub fn heartbeat_response(sequence: u64, padding: &str) -> Message {
+    heartbeat(MessageDirection::Response, sequence, padding)
+}
+pub fn extract_heartbeat_message(message: Message) -> Option<(u64, String)> {
+    if *message.message_type() != MessageType::Heartbeat {
+        return None;
+    }
+
+    let sequence: Option<u64> = message.extract_as("sequence");
+    let padding: Option<String> = message.extract_as("padding");
+
+    match (sequence, padding) {
+        (Some(s), Some(p)) => Some((s, p)),
+        _ => None
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeDirection {
+    Upload,
+    Download
+}
+impl Display for ProbeDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Upload => "upload",
+            Self::Download => "download"
+        };
+
+        write!(f, "{text}")
+    }
+}
+// Moves throwaway frames in one direction to measure throughput. Once the server acks, the frames follow, and an upload is acked again once they all arrived.
+pub fn probe_message(direction: ProbeDirection, frame_count: u64) -> Message {
+    MessageBuilder::request(MessageType::Probe)
+        .field("direction", direction)
+        .field("frames", frame_count)
+        .built()
+}
+pub fn extract_probe_message(message: Message) -> Option<(ProbeDirection, u64)> {
+    if *message.message_type() != MessageType::Probe {
+        return None;
+    }
+
+    let direction: Option<ProbeDirection> = message.extract_as("direction");
+    let frame_count: Option<u64> = message.extract_as("frames");
+
+    match (direction, frame_count) {
+        (Some(d), Some(f)) => Some((d, f)),
+        _ => None
+    }
+}
+
+// Hands the server a client's probe results, which it keeps for support to look at later
+pub fn diagnostics_message(report: &serde_json::Value) -> Message {
+    MessageBuilder::request(MessageType::Diagnostics)
+        .field("report", report.clone())
+        .built()
+}
+pub fn extract_diagnostics_message(message: Message) -> Option<serde_json::Value> {
+    if *message.message_type() != MessageType::Diagnostics {
+        return None;
+    }
+
+    message.extract_as::<serde_json::Value>("report").filter(|x| x.is_object())
+}
+
+pub fn stat_message_request(path: &str) -> Message {
+    MessageBuilder::request(MessageType::Stat)
+        .field("path", path.to_string())
+        .built()
+}
+pub fn extract_stat_request_message(message: Message) -> Option<String> {
+    if *message.message_type() != MessageType::Stat {
+        return None;
+    }
+
+    message.extract_as("path")
+}
+pub fn stat_message_response(status: HttpCodes, message: &str, info: Option<FileInfo>) -> Message {
+    MessageBuilder::response(MessageType::Stat)
+        .field("status", status)
+        .field("message", message.to_string())
+        .field("info", info)
+        .built()
+}
+pub fn extract_stat_response_message(message: Message) -> Option<(HttpCodes, String, Option<FileInfo>)> {
+    if *message.message_type() != MessageType::Stat {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let info: Option<FileInfo> = message.extract_as("info");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, info)),
+        _ => None
+    }
+}
+
+pub fn stats_request_message() -> Message {
+    MessageBuilder::request(MessageType::Stats).built()
+}
+pub fn stats_response_message(stats: TransferStats) -> Message {
+    MessageBuilder::response(MessageType::Stats)
+        .field("stats", stats)
+        .built()
+}
+pub fn extract_stats_response_message(message: Message) -> Option<TransferStats> {
+    if *message.message_type() != MessageType::Stats {
+        return None;
+    }
+
+    let stats: Option<TransferStats> = message.extract_as("stats");
+    stats
+}
+// What an administrator sees of each open connection. Times are Unix seconds, and the user is absent until the connection logs in.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ConnectionActivity {
+    pub peer: String,
+    pub username: Option<String>,
+    pub connected_at: u64,
+    pub last_activity: u64,
+    pub requests: u64
+}
+// Asks for every open connection instead of the last transfer, which only administrators may do
+pub fn connections_request_message() -> Message {
+    MessageBuilder::request(MessageType::Stats)
+        .field("connections", true)
+        .built()
+}
+pub fn extract_connections_request(message: &Message) -> bool {
+    *message.message_type() == MessageType::Stats && message.extract_as("connections").unwrap_or(false)
+}
+pub fn connections_response_message(connections: &[ConnectionActivity]) -> Message {
+    MessageBuilder::response(MessageType::Stats)
+        .field("connections", connections)
+        .built()
+}
+pub fn extract_connections_response_message(message: Message) -> Option<Vec<ConnectionActivity>> {
+    if *message.message_type() != MessageType::Stats {
+        return None;
+    }
+
+    message.extract_as("connections")
+}
+
+pub fn grant_message_request(path: &str, max_size: u64, ttl_secs: u64) -> Message {
+    MessageBuilder::request(MessageType::Grant)
+        .field("path", path)
+        .field("max_size", max_size)
+        .field("ttl", ttl_secs)
+        .built()
+}
+pub fn extract_grant_request_message(message: Message) -> Option<(String, u64, u64)> {
+    if *message.message_type() != MessageType::Grant {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let max_size: Option<u64> = message.extract_as("max_size");
+    let ttl: Option<u64> = message.extract_as("ttl");
+
+    match (path, max_size, ttl) {
+        (Some(p), Some(m), Some(t)) => Some((p, m, t)),
+        _ => None
+    }
+}
+pub fn grant_message_response(status: HttpCodes, message: &str, grant: Option<UploadGrant>) -> Message {
+    MessageBuilder::response(MessageType::Grant)
+        .field("status", status)
+        .field("message", message)
+        .field("grant", grant)
+        .built()
+}
+pub fn extract_grant_response_message(message: Message) -> Option<(HttpCodes, String, Option<UploadGrant>)> {
+    if *message.message_type() != MessageType::Grant {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let grant: Option<UploadGrant> = message.extract_as("grant");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, grant)),
+        _ => None
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ShareLinkAction {
+    Create {
+        path: String,
+        ttl_secs: u64
+    },
+    Revoke(String) //The link's token
+}
+// Creates a link to a file that anyone holding it can download until it expires
+pub fn share_link_request(path: &str, ttl_secs: u64) -> Message {
+    MessageBuilder::request(MessageType::ShareLink)
+        .field("path", path)
+        .field("ttl", ttl_secs)
+        .built()
+}
+// Ends a link before it expires. Only the user who created it, or an administrator, may.
+pub fn share_link_revoke_request(token: &str) -> Message {
+    MessageBuilder::request(MessageType::ShareLink)
+        .field("revoke", token)
+        .built()
+}
+pub fn extract_share_link_request(message: Message) -> Option<ShareLinkAction> {
+    if *message.message_type() != MessageType::ShareLink {
+        return None;
+    }
+
+    if let Some(token) = message.extract_as::<String>("revoke") {
+        return Some(ShareLinkAction::Revoke(token));
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let ttl: Option<u64> = message.extract_as("ttl");
+
+    match (path, ttl) {
+        (Some(path), Some(ttl_secs)) => Some(ShareLinkAction::Create { path, ttl_secs }),
+        _ => None
+    }
+}
+// The link is only present when one was created
+pub fn share_link_response(status: HttpCodes, message: &str, link: Option<&ShareLink>) -> Message {
+    MessageBuilder::response(MessageType::ShareLink)
+        .field("status", status)
+        .field("message", message)
+        .field("link", link)
+        .built()
+}
+pub fn extract_share_link_response(message: Message) -> Option<(HttpCodes, String, Option<ShareLink>)> {
+    if *message.message_type() != MessageType::ShareLink {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let link: Option<ShareLink> = message.extract_as("link");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, link)),
+        _ => None
+    }
+}
+
+// Places (or clears) a legal hold on a file. Held files cannot be deleted, overwritten, moved, or expired.
+pub fn hold_message(path: &str, hold: bool) -> Message {
+    MessageBuilder::request(MessageType::Hold)
+        .field("path", path)
+        .field("hold", hold)
+        .built()
+}
+pub fn extract_hold_message(message: Message) -> Option<(String, bool)> {
+    if *message.message_type() != MessageType::Hold {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let hold: Option<bool> = message.extract_as("hold");
+
+    match (path, hold) {
+        (Some(p), Some(h)) => Some((p, h)),
+        _ => None
+    }
+}
+// Deleted files wait in the trash for a while. Restore puts the most recently deleted file at a path back where it was.
+pub fn restore_message(path: &str) -> Message {
+    MessageBuilder::request(MessageType::Restore)
+        .field("path", path)
+        .built()
+}
+pub fn extract_restore_message(message: Message) -> Option<String> {
+    if *message.message_type() != MessageType::Restore {
+        return None;
+    }
+
+    message.extract_as("path")
+}
+// Empties the trash for good, either entirely or only what was deleted at or beneath a path
+pub fn purge_trash_message(path: Option<&str>) -> Message {
+    MessageBuilder::request(MessageType::PurgeTrash)
+        .field("path", path)
+        .built()
+}
+pub fn extract_purge_trash_message(message: Message) -> Option<Option<String>> {
+    if *message.message_type() != MessageType::PurgeTrash {
+        return None;
+    }
+
+    message.extract_as("path")
+}
+// An older copy of a file, kept when an upload replaced it
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct VersionInfo {
+    pub number: u32, //Counts up from 1 for each file, and is never reused
+    pub size: u64,
+    pub modified: Option<u64>, //When the replaced content was last written
+    pub replaced_at: u64,
+    pub replaced_by: Option<String> //Who uploaded over it
+}
+// Without a number, lists the versions kept for a file. With one, the server answers as it would a Download of the whole version, and its frames follow.
+pub fn versions_message(path: &str, number: Option<u32>) -> Message {
+    MessageBuilder::request(MessageType::Versions)
+        .field("path", path)
+        .field("number", number)
+        .built()
+}
+pub fn extract_versions_message(message: Message) -> Option<(String, Option<u32>)> {
+    if *message.message_type() != MessageType::Versions {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let number: Option<u32> = message.extract_as("number");
+
+    Some((path?, number))
+}
+pub fn versions_response(status: HttpCodes, message: &str, versions: &[VersionInfo]) -> Message {
+    MessageBuilder::response(MessageType::Versions)
+        .field("status", status)
+        .field("message", message)
+        .field("versions", versions)
+        .built()
+}
+pub fn extract_versions_response(message: Message) -> Option<(HttpCodes, String, Vec<VersionInfo>)> {
+    if *message.message_type() != MessageType::Versions {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let versions: Option<Vec<VersionInfo>> = message.extract_as("versions");
+
+    match (status, msg, versions) {
+        (Some(s), Some(m), Some(v)) => Some((s, m, v)),
+        _ => None
+    }
+}
+// The start of a file, small enough to answer in the response itself
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct FilePreview {
+    pub kind: FileType,
+    pub content: Vec<u8>,
+    pub truncated: bool //Whether the file goes on past what was sent
+}
+// Asks for the first limit lines of a text file, or the first limit bytes of any other file, without downloading it. The server may send less.
+pub fn preview_message(path: &str, limit: u64) -> Message {
+    MessageBuilder::request(MessageType::Preview)
+        .field("path", path)
+        .field("limit", limit)
+        .built()
+}
+pub fn extract_preview_message(message: Message) -> Option<(String, u64)> {
+    if *message.message_type() != MessageType::Preview {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let limit: Option<u64> = message.extract_as("limit");
+
+    Some((path?, limit?))
+}
+// The preview is only present when the status is Ok
+pub fn preview_response(status: HttpCodes, message: &str, preview: Option<&FilePreview>) -> Message {
+    MessageBuilder::response(MessageType::Preview)
+        .field("status", status)
+        .field("message", message)
+        .field("preview", preview)
+        .built()
+}
+pub fn extract_preview_response(message: Message) -> Option<(HttpCodes, String, Option<FilePreview>)> {
+    if *message.message_type() != MessageType::Preview {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let preview: Option<FilePreview> = message.extract_as("preview");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, preview)),
+        _ => None
+    }
+}
+// Asks for a small PNG of an image, a frame of a video, or the waveform of audio, fitting in a square of size pixels.
+// Without a size the server picks one.
+pub fn thumbnail_message(path: &str, size: Option<u32>) -> Message {
+    MessageBuilder::request(MessageType::Thumbnail)
+        .field("path", path)
+        .field("size", size)
+        .built()
+}
+pub fn extract_thumbnail_message(message: Message) -> Option<(String, Option<u32>)> {
+    if *message.message_type() != MessageType::Thumbnail {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let size: Option<u32> = message.extract_as("size");
+
+    Some((path?, size))
+}
+// The PNG is only present when the status is Ok
+pub fn thumbnail_response(status: HttpCodes, message: &str, png: Option<&[u8]>) -> Message {
+    MessageBuilder::response(MessageType::Thumbnail)
+        .field("status", status)
+        .field("message", message)
+        .field("thumbnail", png)
+        .built()
+}
+pub fn extract_thumbnail_response(message: Message) -> Option<(HttpCodes, String, Option<Vec<u8>>)> {
+    if *message.message_type() != MessageType::Thumbnail {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let png: Option<Vec<u8>> = message.extract_as("thumbnail");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, png)),
+        _ => None
+    }
+}
+// Asks for the signature of the server's copy of a file, so an upload can send only what changed
+pub fn signature_message(path: &str) -> Message {
+    MessageBuilder::request(MessageType::Signature)
+        .field("path", path)
+        .built()
+}
+pub fn extract_signature_message(message: Message) -> Option<String> {
+    if *message.message_type() != MessageType::Signature {
+        return None;
+    }
+
+    message.extract_as("path")
+}
+// The signature is only present when the status is Ok
+pub fn signature_response(status: HttpCodes, message: &str, signature: Option<&Signature>) -> Message {
+    MessageBuilder::response(MessageType::Signature)
+        .field("status", status)
+        .field("message", message)
+        .field("signature", signature)
+        .built()
+}
+pub fn extract_signature_response(message: Message) -> Option<(HttpCodes, String, Option<Signature>)> {
+    if *message.message_type() != MessageType::Signature {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let signature: Option<Signature> = message.extract_as("signature");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, signature)),
+        _ => None
+    }
+}
+// A Download request carrying the signature of the client's copy asks for a delta against it instead of the whole file
+pub fn attach_signature(mut message: Message, signature: &Signature) -> Message {
+    message.data.insert(String::from("signature"), json!(signature));
+    message
+}
+pub fn extract_signature(message: &Message) -> Option<Signature> {
+    if *message.message_type() != MessageType::Download {
+        return None;
+    }
+
+    message.extract_as("signature")
+}
+// Sends a directory as a tar archive, which the server unpacks at the path once it has all arrived. The frame count and checksum are those of the archive.
+pub fn upload_dir_message(path: &str, frame_count: u64, checksum: Option<Checksum>) -> Message {
+    MessageBuilder::request(MessageType::UploadDir)
+        .field("path", path)
+        .field("size", frame_count)
+        .field("checksum", checksum)
+        .built()
+}
+pub fn extract_upload_dir_message(message: Message) -> Option<(String, u64, Option<Checksum>)> {
+    if *message.message_type() != MessageType::UploadDir {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let frame_count: Option<u64> = message.extract_as("size");
+    let checksum: Option<Checksum> = message.extract_as("checksum");
+
+    Some((path?, frame_count?, checksum))
+}
+// Answers before the archive is sent. Once it has been unpacked, the server answers again with an Ack.
+pub fn upload_dir_response(status: HttpCodes, message: &str) -> Message {
+    MessageBuilder::response(MessageType::UploadDir)
+        .field("status", status)
+        .field("message", message)
+        .built()
+}
+pub fn extract_upload_dir_response(message: Message) -> Option<(HttpCodes, String)> {
+    if *message.message_type() != MessageType::UploadDir {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+
+    Some((status?, msg?))
+}
+// Asks for a directory, and everything under it, as a tar archive
+pub fn download_dir_message(path: &str) -> Message {
+    MessageBuilder::request(MessageType::DownloadDir)
+        .field("path", path)
+        .built()
+}
+pub fn extract_download_dir_message(message: Message) -> Option<String> {
+    if *message.message_type() != MessageType::DownloadDir {
+        return None;
+    }
+
+    message.extract_as("path")
+}
+// When the status is Ok, the archive's frames follow
+pub fn download_dir_response(status: HttpCodes, message: &str, frame_count: u64, checksum: Option<Checksum>) -> Message {
+    MessageBuilder::response(MessageType::DownloadDir)
+        .field("status", status)
+        .field("message", message)
+        .field("size", frame_count)
+        .field("checksum", checksum)
+        .built()
+}
+pub fn extract_download_dir_response(message: Message) -> Option<(HttpCodes, String, u64, Option<Checksum>)> {
+    if *message.message_type() != MessageType::DownloadDir {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+    let frame_count: Option<u64> = message.extract_as("size");
+    let checksum: Option<Checksum> = message.extract_as("checksum");
+
+    Some((status?, msg?, frame_count?, checksum))
+}
+// Marks the frames after an Upload request, or after a Download response, as a delta against the receiver's copy rather than the file itself.
+// Its frame count is then the size of the delta.
+pub fn attach_delta(mut message: Message) -> Message {
+    message.data.insert(String::from("delta"), json!(true));
+    message
+}
+pub fn extract_delta(message: &Message) -> bool {
+    message.extract_as("delta").unwrap_or(false)
+}
+// How much a user stores against their quota. Without a quota, their storage is unlimited.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct QuotaInfo {
+    pub used: u64,
+    pub quota: Option<u64>,
+    pub remaining: Option<u64>
+}
+pub fn quota_message() -> Message {
+    MessageBuilder::request(MessageType::Quota).built()
+}
+pub fn quota_response(info: &QuotaInfo) -> Message {
+    MessageBuilder::response(MessageType::Quota)
+        .field("status", HttpCodes::Ok)
+        .field("usage", info)
+        .built()
+}
+pub fn extract_quota_response(message: Message) -> Option<QuotaInfo> {
+    if *message.message_type() != MessageType::Quota {
+        return None;
+    }
+
+    message.extract_as("usage")
+}
+// Starts, or with unsubscribe stops, watching a directory and everything under it. The server answers with an Ack.
+pub fn subscribe_message(path: &str, unsubscribe: bool) -> Message {
+    MessageBuilder::request(MessageType::Subscribe)
+        .field("path", path)
+        .field("unsubscribe", unsubscribe)
+        .built()
+}
+pub fn extract_subscribe_message(message: Message) -> Option<(String, bool)> {
+    if *message.message_type() != MessageType::Subscribe {
+        return None;
+    }
+
+    let path: Option<String> = message.extract_as("path");
+    let unsubscribe: bool = message.extract_as("unsubscribe").unwrap_or(false);
+
+    Some((path?, unsubscribe))
+}
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FileEventKind {
+    Created,
+    Modified,
+    Deleted
+}
+// A change under a watched directory. The actor is the user whose request made the change, and is absent for changes made outside of the server.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct FileEvent {
+    pub kind: FileEventKind,
+    pub path: String,
+    pub actor: Option<String>
+}
+// Pushed by the server whenever it is not answering a request, so a subscribed client must expect one in place of any response
+pub fn event_message(event: &FileEvent) -> Message {
+    MessageBuilder::response(MessageType::Event)
+        .field("event", event)
+        .built()
+}
+pub fn extract_event_message(message: Message) -> Option<FileEvent> {
+    if *message.message_type() != MessageType::Event {
+        return None;
+    }
+
+    message.extract_as("event")
+}
+// A change the server saw while it was watching, and when, in seconds since the epoch
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct FileChange {
+    pub event: FileEvent,
+    pub at: u64
+}
+// The changes at or beneath a path since a time, oldest first. The server only remembers so many, and nothing from before it started watching,
+// so complete is false when it cannot tell whether some are missing.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub struct ChangeReport {
+    pub changes: Vec<FileChange>,
+    pub complete: bool
+}
+// Asks what changed at or beneath a path after a time, in seconds since the epoch, without subscribing
+pub fn changes_since_request(path: &str, since: u64) -> Message {
+    MessageBuilder::request(MessageType::ChangesSince)
+        .field("path", path)
+        .field("since", since)
+        .built()
+}
+pub fn extract_changes_since_request(message: Message) -> Option<(String, u64)> {
+    if *message.message_type() != MessageType::ChangesSince {
+        return None;
+    }
+
+    Some((message.extract_as("path")?, message.extract_as("since")?))
+}
+// The report is left off when the request is refused
+pub fn changes_since_response(status: HttpCodes, message: &str, report: Option<&ChangeReport>) -> Message {
+    MessageBuilder::response(MessageType::ChangesSince)
+        .field("status", status)
+        .field("message", message)
+        .field("report", report)
+        .built()
+}
+pub fn extract_changes_since_response(message: Message) -> Option<(HttpCodes, String, Option<ChangeReport>)> {
+    if *message.message_type() != MessageType::ChangesSince {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m, message.extract_as("report"))),
+        _ => None
+    }
+}
+// Sent under a transfer's request ID while the server has no room to start it, and again each time it moves up the queue.
+// Position 1 is next. The transfer's own response follows once it starts.
+pub fn queued_message(position: u64) -> Message {
+    MessageBuilder::response(MessageType::Queued)
+        .field("position", position)
+        .built()
+}
+pub fn extract_queued_message(message: Message) -> Option<u64> {
+    if *message.message_type() != MessageType::Queued {
+        return None;
+    }
+
+    message.extract_as("position")
+}
+// Keeps an otherwise idle connection open. The server answers with a Pong carrying the same sequence.
+pub fn ping_message(sequence: u64) -> Message {
+    MessageBuilder::request(MessageType::Ping)
+        .field("sequence", sequence)
+        .built()
+}
+// A ping stamped with the client's own clock, in microseconds, which the Pong echoes so the client can time the round trip
+pub fn timed_ping_message(sequence: u64, sent_at: u64) -> Message {
+    let mut message = ping_message(sequence);
+    message.data.insert(String::from("sent_at"), json!(sent_at));
+    message
+}
+pub fn extract_ping_message(message: Message) -> Option<(u64, Option<u64>)> {
+    if *message.message_type() != MessageType::Ping {
+        return None;
+    }
+
+    Some((message.extract_as("sequence")?, message.extract_as("sent_at")))
+}
+pub fn pong_message(sequence: u64, sent_at: Option<u64>) -> Message {
+    MessageBuilder::response(MessageType::Pong)
+        .field("sequence", sequence)
+        .optional_field("sent_at", sent_at)
+        .built()
+}
+pub fn extract_pong_message(message: Message) -> Option<(u64, Option<u64>)> {
+    if *message.message_type() != MessageType::Pong {
+        return None;
+    }
+
+    Some((message.extract_as("sequence")?, message.extract_as("sent_at")))
+}
+// Stops the upload or download in flight. It follows the cancel block of an upload, and is answered with an Ack once the download's stream has ended.
+pub fn cancel_message(reason: Option<&str>) -> Message {
+    MessageBuilder::request(MessageType::Cancel)
+        .field("reason", reason)
+        .built()
+}
+pub fn extract_cancel_message(message: Message) -> Option<Option<String>> {
+    if *message.message_type() != MessageType::Cancel {
+        return None;
+    }
+
+    Some(message.extract_as("reason"))
+}
+// Asks whether an operation would succeed without doing it, so a GUI can grey out actions and a sync engine can plan ahead
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub enum IntendedOperation {
+    Upload { path: String, size: u64 },
+    Delete {
+        path: String,
+        #[serde(default)]
+        recursive: bool
+    }
+}
+pub fn can_i_request(operation: &IntendedOperation) -> Message {
+    MessageBuilder::request(MessageType::CanI)
+        .field("operation", operation)
+        .built()
+}
+pub fn extract_can_i_request(message: Message) -> Option<IntendedOperation> {
+    if *message.message_type() != MessageType::CanI {
+        return None;
+    }
+
+    message.extract_as("operation")
+}
+// Ok means the operation would be accepted. Any other status is the one the real request would be refused with, and the message says why.
+pub fn can_i_response(status: HttpCodes, message: &str) -> Message {
+    MessageBuilder::response(MessageType::CanI)
+        .field("status", status)
+        .field("message", message.to_string())
+        .built()
+}
+pub fn extract_can_i_response(message: Message) -> Option<(HttpCodes, String)> {
+    if *message.message_type() != MessageType::CanI {
+        return None;
+    }
+
+    let status: Option<HttpCodes> = message.extract_as("status");
+    let msg: Option<String> = message.extract_as("message");
+
+    match (status, msg) {
+        (Some(s), Some(m)) => Some((s, m)),
+        _ => None
+    }
+}
+
+#[test]
+fn test_message_builder() {
+    let built = MessageBuilder::request(MessageType::Download).field("path", "docs/a.txt").field("offset", 10u64).optional_field::<u64>("length", None).build().unwrap();
+    assert_eq!(extract_download_request_message(built.clone()), Some((String::from("docs/a.txt"), 10, None)));
+    assert!(built.extract("length").is_none());
+
+    // Everything missing is listed at once, instead of failing later in the extract function
+    let missing = MessageBuilder::response(MessageType::Download).field("status", HttpCodes::Ok).build();
+    assert_eq!(missing, Err(MessageBuildError::Missing(MessageType::Download, MessageDirection::Response, vec!["message", "kind", "size", "offset", "length"].into_iter().map(String::from).collect())));
+    assert_eq!(MessageBuilder::request(MessageType::Pong).field("sequence", 1).build(), Err(MessageBuildError::Direction(MessageType::Pong, MessageDirection::Request)));
+    let mut unserializable = HashMap::new();
+    unserializable.insert((1, 2), 3);
+    assert!(matches!(MessageBuilder::request(MessageType::Diagnostics).field("report", unserializable).build(), Err(MessageBuildError::Invalid(f, _)) if f == "report"));
+
+    // Logins need credentials unless they carry a token or grant, and a null field still counts as set
+    assert!(MessageBuilder::request(MessageType::Connect).field("username", "u").build().is_err());
+    assert!(MessageBuilder::request(MessageType::Connect).field("token", "t").build().is_ok());
+    assert!(MessageBuilder::request(MessageType::PurgeTrash).field("path", None::<String>).build().is_ok());
+    assert!(purge_trash_message(None).validate().is_ok() && ack_messsage(MessageDirection::Request, HttpCodes::Ok, None).validate().is_ok());
+}
+#[test]
+fn test_request_ids() {
+    // Unset IDs stay off the wire, so peers that predate them read the same JSON as before
+    let message = close_message();
+    assert_eq!(message.request_id(), 0);
+    assert!(!serde_json::to_string(&message).unwrap().contains("request_id"));
+
+    let numbered = message.with_request_id(7);
+    let json = serde_json::to_string(&numbered).unwrap();
+    assert!(json.contains("\"request_id\":7"));
+    assert_eq!(serde_json::from_str::<Message>(&json).unwrap().request_id(), 7);
+    assert_eq!(serde_json::from_str::<Message>(r#"{"message_type":"Close","direction":"Request","data":{}}"#).unwrap().request_id(), 0);
+}
+
+#[test]
+fn test_hostile_messages() {
+    // A type from a later version is told apart from garbage, and keeps its ID so it can be answered
+    let unknown = r#"{"message_type":"Teleport","direction":"Request","request_id":9,"data":{}}"#;
+    assert_eq!(Message::parse(Codec::Json, unknown.as_bytes()), Err(MessageParseError::UnknownType(String::from("Teleport"), 9)));
+    let packed = rmp_serde::to_vec_named(&serde_json::from_str::<serde_json::Value>(unknown).unwrap()).unwrap();
+    assert_eq!(Message::parse(Codec::MessagePack, &packed), Err(MessageParseError::UnknownType(String::from("Teleport"), 9)));
+    assert!(matches!(Message::parse(Codec::Json, br#"{"message_type":"Close","direction":"Sideways","data":{}}"#), Err(MessageParseError::Malformed(_))));
+    assert!(matches!(Message::parse(Codec::Json, b"\xff\x00not json"), Err(MessageParseError::Malformed(_))));
+    assert!(matches!(Message::parse(Codec::Json, br#"{"message_type":7}"#), Err(MessageParseError::Malformed(_))));
+
+    // Too many fields, or fields nested too deep, are refused however well formed they are
+    let crowded: HashMap<String, serde_json::Value> = (0..=MAX_MESSAGE_FIELDS).map(|x| (format!("field{x}"), json!(x))).collect();
+    assert!(matches!(Message::unchecked(MessageType::Close, MessageDirection::Request, crowded).validate(), Err(MessageBuildError::Limit(_))));
+    let nested = |depth: usize| (0..depth).fold(json!(1), |inner, _| json!([inner]));
+    let deep = |depth: usize| Message::unchecked(MessageType::Diagnostics, MessageDirection::Request, HashMap::from([(String::from("report"), nested(depth))]));
+    assert_eq!(deep(MAX_VALUE_DEPTH).validate(), Ok(()));
+    assert!(matches!(deep(MAX_VALUE_DEPTH + 1).validate(), Err(MessageBuildError::Limit(r)) if r.contains("report")));
+}
+
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use crate::framing::{write_frame, read_frame};
+    use crate::delta::BlockSignature;
+    use proptest::prelude::*;
+
+    // Everything a peer reads has been through a frame, so the round trips go through one too
+    fn through_frame(message: Message) -> Message {
+        let mut buffer = Vec::<u8>::new();
+        write_frame(&mut buffer, &message).unwrap();
+        read_frame(&mut std::io::Cursor::new(buffer)).unwrap()
+    }
+
+    fn any_file_type() -> impl Strategy<Value = FileType> {
+        prop_oneof![Just(FileType::Text), Just(FileType::Audio), Just(FileType::Video), Just(FileType::Binary), Just(FileType::Archive), "[a-z]{1,8}/[a-z0-9.+-]{1,12}".prop_map(FileType::Other)]
+    }
+    fn any_code() -> impl Strategy<Value = HttpCodes> {
+        prop_oneof![Just(HttpCodes::Ok), Just(HttpCodes::BadRequest), Just(HttpCodes::Forbidden), Just(HttpCodes::NotFound), Just(HttpCodes::Conflict), Just(HttpCodes::TooManyRequests), Just(HttpCodes::InsufficientStorage)]
+    }
+    fn any_value() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<u64>().prop_map(serde_json::Value::from),
+            any::<f64>().prop_filter("json has no nan or infinity", |x| x.is_finite()).prop_map(serde_json::Value::from),
+            any::<String>().prop_map(serde_json::Value::from)
+        ];
+        leaf.prop_recursive(3, 16, 4, |inner| prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+            prop::collection::hash_map("[a-z]{1,8}", inner, 0..4).prop_map(|x| serde_json::Value::Object(x.into_iter().collect()))
+        ])
+    }
+    // A message of any type carrying arbitrary values under the names the extractors look for
+    fn any_message() -> impl Strategy<Value = Message> {
+        let keys = prop::sample::select(vec!["path", "destination", "name", "recursive", "offset", "length", "size", "type", "filters", "limit", "operation", "status", "code", "message", "action", "hold", "checksum", "principal", "permissions", "revoke", "change", "sequence", "padding", "direction", "frames", "report", "capabilities", "number", "versions", "usage", "compressions", "compression", "codecs", "channel", "preview", "thumbnail", "ttl", "revoke", "link", "share", "guest", "unlock", "lockouts", "entries", "filter", "signature", "delta", "unsubscribe", "event", "idle_timeout", "connections", "reason", "quic"]);
+        let kind = prop::sample::select(vec![MessageType::Upload, MessageType::Download, MessageType::Delete, MessageType::Dir, MessageType::ChangeDir, MessageType::Move, MessageType::Subfolder, MessageType::Hold, MessageType::CanI, MessageType::Rename, MessageType::Copy, MessageType::Share, MessageType::UserAdmin, MessageType::Heartbeat, MessageType::Probe, MessageType::Diagnostics, MessageType::Restore, MessageType::PurgeTrash, MessageType::Versions, MessageType::Quota, MessageType::Signature, MessageType::UploadDir, MessageType::DownloadDir, MessageType::Subscribe, MessageType::Event, MessageType::Ping, MessageType::Pong, MessageType::Cancel, MessageType::Preview, MessageType::Thumbnail, MessageType::ShareLink, MessageType::Lockouts, MessageType::Audit, MessageType::Dedupe, MessageType::Usage, MessageType::Queued, MessageType::ChangesSince, MessageType::Ack]);
+        (kind, any::<bool>(), any::<u64>(), prop::collection::hash_map(keys.prop_map(String::from), any_value(), 0..8)).prop_map(|(kind, request, id, data)| {
+            Message::unchecked(kind, if request { MessageDirection::Request } else { MessageDirection::Response }, data).with_request_id(id)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_request_round_trips(path in any::<String>(), flag in any::<bool>(), number in any::<u64>(), length in any::<Option<u64>>(), kind in any_file_type()) {
+            prop_assert_eq!(extract_delete_message(through_frame(delete_message(&path, flag))), Some((path.clone(), flag)));
+            prop_assert_eq!(extract_hold_message(through_frame(hold_message(&path, flag))), Some((path.clone(), flag)));
+            prop_assert_eq!(extract_restore_message(through_frame(restore_message(&path))), Some(path.clone()));
+            let purged = flag.then_some(path.as_str());
+            let version = flag.then_some(number as u32);
+            prop_assert_eq!(extract_versions_message(through_frame(versions_message(&path, version))), Some((path.clone(), version)));
+            prop_assert_eq!(extract_preview_message(through_frame(preview_message(&path, number))), Some((path.clone(), number)));
+            prop_assert_eq!(extract_thumbnail_message(through_frame(thumbnail_message(&path, version))), Some((path.clone(), version)));
+            prop_assert_eq!(extract_share_link_request(through_frame(share_link_request(&path, number))), Some(ShareLinkAction::Create { path: path.clone(), ttl_secs: number }));
+            prop_assert_eq!(extract_share_link_request(through_frame(share_link_revoke_request(&path))), Some(ShareLinkAction::Revoke(path.clone())));
+            prop_assert_eq!(extract_share_connect_message(through_frame(share_connect_message(&path, ProtocolVersion::new(3, 1)))), Some((path.clone(), Some(ProtocolVersion::new(3, 1)))));
+            prop_assert_eq!(extract_guest_connect_message(through_frame(guest_connect_message(ProtocolVersion::new(3, 1)))), Some(Some(ProtocolVersion::new(3, 1))));
+            prop_assert_eq!(extract_purge_trash_message(through_frame(purge_trash_message(purged))), Some(purged.map(String::from)));
+            prop_assert_eq!(extract_change_dir_message(through_frame(change_dir_message(&path))), Some(path.clone()));
+            prop_assert_eq!(extract_move_message(through_frame(move_message(&path, &path))), Some((path.clone(), path.clone())));
+            prop_assert_eq!(extract_capabilities(&through_frame(advertise_capabilities(change_dir_message(&path), &Capabilities::supported()))), Some(Capabilities::supported()));
+            prop_assert_eq!(extract_compressions(&through_frame(advertise_compressions(change_dir_message(&path), &Compression::supported()))), Compression::supported());
+            prop_assert_eq!(extract_codecs(&through_frame(advertise_codecs(change_dir_message(&path), &Codec::supported()))), Codec::supported());
+            let compression = if flag { Compression::Gzip } else { Compression::Zstd };
+            prop_assert_eq!(extract_compression(&through_frame(attach_compression(download_message_request(&path, number, length), compression))), Some(compression));
+            prop_assert_eq!(extract_signature_message(through_frame(signature_message(&path))), Some(path.clone()));
+            let signature = Signature { block_size: number as u32, blocks: vec![BlockSignature { weak: number as u32, strong: path.clone() }] };
+            prop_assert_eq!(extract_signature(&through_frame(attach_signature(download_message_request(&path, number, length), &signature))), Some(signature));
+            prop_assert_eq!(extract_upload_dir_message(through_frame(upload_dir_message(&path, number, None))), Some((path.clone(), number, None)));
+            prop_assert_eq!(extract_compression(&through_frame(attach_compression(download_dir_message(&path), compression))), Some(compression));
+            prop_assert_eq!(extract_download_dir_message(through_frame(download_dir_message(&path))), Some(path.clone()));
+            prop_assert_eq!(extract_subscribe_message(through_frame(subscribe_message(&path, flag))), Some((path.clone(), flag)));
+            let event = FileEvent { kind: if flag { FileEventKind::Created } else { FileEventKind::Deleted }, path: path.clone(), actor: flag.then(|| path.clone()) };
+            prop_assert_eq!(extract_event_message(through_frame(event_message(&event))), Some(event.clone()));
+            prop_assert_eq!(extract_changes_since_request(through_frame(changes_since_request(&path, number))), Some((path.clone(), number)));
+            let changes = ChangeReport { changes: vec![FileChange { event, at: number }], complete: flag };
+            prop_assert_eq!(extract_changes_since_response(through_frame(changes_since_response(HttpCodes::Ok, &path, flag.then_some(&changes)))), Some((HttpCodes::Ok, path.clone(), flag.then_some(changes))));
+            prop_assert_eq!(extract_ping_message(through_frame(ping_message(number))), Some((number, None)));
+            prop_assert_eq!(extract_ping_message(through_frame(timed_ping_message(number, number))), Some((number, Some(number))));
+            prop_assert_eq!(extract_close_reason(&through_frame(close_with_reason(&path))), Some(path.clone()));
+            prop_assert_eq!(extract_pong_message(through_frame(pong_message(number, flag.then_some(number)))), Some((number, flag.then_some(number))));
+            prop_assert_eq!(extract_queued_message(through_frame(queued_message(number))), Some(number));
+            prop_assert!(extract_interactive(&through_frame(attach_interactive(download_message_request(&path, 0, None)))) && !extract_interactive(&download_message_request(&path, 0, None)));
+            let latency = Latency { rtt: number as f32, mean_rtt: number as f32, samples: number as u32 };
+            prop_assert_eq!(extract_latency(&through_frame(attach_latency(download_message_request(&path, 0, None), &latency))), Some(latency));
+            prop_assert!(extract_channel(&through_frame(attach_channel(download_message_request(&path, 0, None)))) && !extract_channel(&download_message_request(&path, 0, None)));
+            prop_assert!(extract_quic(&through_frame(attach_quic(download_message_request(&path, 0, None)))) && !extract_quic(&download_message_request(&path, 0, None)));
+            let offer = QuicOffer { port: number as u16, fingerprint: path.clone() };
+            prop_assert_eq!(extract_quic_offer(&through_frame(advertise_quic(ack_messsage(MessageDirection::Response, HttpCodes::Ok, None), &offer))), Some(offer));
+            let reason = flag.then_some(path.as_str());
+            prop_assert_eq!(extract_cancel_message(through_frame(cancel_message(reason))), Some(reason.map(String::from)));
+            prop_assert_eq!(extract_idle_timeout(&through_frame(advertise_idle_timeout(change_dir_message(&path), number))), Some(number));
+            prop_assert!(extract_connections_request(&through_frame(connections_request_message())));
+            let activity = ConnectionActivity { peer: path.clone(), username: flag.then(|| path.clone()), connected_at: number, last_activity: number, requests: number };
+            prop_assert_eq!(extract_connections_response_message(through_frame(connections_response_message(std::slice::from_ref(&activity)))), Some(vec![activity]));
+            prop_assert!(extract_delta(&through_frame(attach_delta(upload_message(&path, kind.clone(), number, 0, None, None)))));
+            prop_assert_eq!(extract_stat_request_message(through_frame(stat_message_request(&path))), Some(path.clone()));
+            prop_assert_eq!(extract_rename_message(through_frame(rename_message(&path, &path))), Some((path.clone(), path.clone())));
+            prop_assert_eq!(extract_copy_message(through_frame(copy_message(&path, &path))), Some((path.clone(), path.clone())));
+            let change = if flag { UserAdminAction::Create { username: path.clone(), password: path.clone(), role: Role::ReadOnly } } else { UserAdminAction::Disable { username: path.clone(), disabled: true } };
+            prop_assert_eq!(extract_user_admin_message(through_frame(user_admin_message(&change))), Some(change));
+            let change = UserAdminAction::SetQuota { username: path.clone(), quota: length };
+            prop_assert_eq!(extract_user_admin_message(through_frame(user_admin_message(&change))), Some(change));
+            let subject = if flag { LockoutSubject::User(path.clone()) } else { LockoutSubject::Address(path.clone()) };
+            prop_assert_eq!(extract_lockouts_request(through_frame(unlock_request(&subject))), Some(Some(subject.clone())));
+            prop_assert_eq!(extract_lockouts_request(through_frame(lockouts_request())), Some(None));
+            let lockouts = vec![Lockout { subject, failures: number as u32, until: length.unwrap_or_default(), locked: flag }];
+            prop_assert_eq!(extract_lockouts_response(through_frame(lockouts_response(HttpCodes::Ok, &path, &lockouts))), Some((HttpCodes::Ok, path.clone(), lockouts)));
+            let filter = AuditFilter { user: flag.then(|| path.clone()), path: Some(path.clone()), limit: length.map(|x| x as usize) };
+            prop_assert_eq!(extract_audit_request(through_frame(audit_request(&filter))), Some(filter));
+            let entries = vec![AuditEntry { time: number, peer: path.clone(), user: path.clone(), operation: String::from("upload"), status: flag.then_some(200), path: Some(path.clone()) }];
+            prop_assert_eq!(extract_audit_response(through_frame(audit_response(HttpCodes::Ok, &path, &entries))), Some((HttpCodes::Ok, path.clone(), entries)));
+            let report = DedupeReport { files: number, unhashed: number / 2, unique: number / 3, duplicates: number / 4, saved_bytes: number, linkable_bytes: length.unwrap_or_default() };
+            prop_assert_eq!(extract_dedupe_response(through_frame(dedupe_response(HttpCodes::Ok, &path, flag.then_some(&report)))), Some((HttpCodes::Ok, path.clone(), flag.then_some(report))));
+            let usage = UsageReport { total_bytes: length, free_bytes: Some(number), users: BTreeMap::from([(path.clone(), number)]), folders: BTreeMap::from([(path.clone(), FolderUsage { files: number / 2, bytes: number })]) };
+            prop_assert_eq!(extract_usage_response(through_frame(usage_response(HttpCodes::Ok, &path, flag.then_some(&usage)))), Some((HttpCodes::Ok, path.clone(), flag.then_some(usage))));
+            let principal = if flag { Principal::User(path.clone()) } else { Principal::Group(path.clone()) };
+            prop_assert_eq!(extract_share_message(through_frame(share_message(&path, &principal, &[Permission::Read, Permission::Delete], flag))), Some((path.clone(), principal, vec![Permission::Read, Permission::Delete], flag)));
+            prop_assert_eq!(extract_download_request_message(through_frame(download_message_request(&path, number, length))), Some((path.clone(), number, length)));
+            prop_assert_eq!(extract_upload_message(through_frame(upload_message(&path, kind.clone(), number, number / 2, None, None))), Some((path.clone(), kind, number, number / 2, None)));
+
+            let action = if flag { SubfolderAction::Add } else { SubfolderAction::Delete };
+            prop_assert_eq!(extract_subfolder_message(through_frame(subfolder_message(&path, action, !flag))), Some((path.clone(), action, !flag)));
+
+            prop_assert_eq!(extract_heartbeat_message(through_frame(heartbeat_message(number, path.len()))), Some((number, "x".repeat(path.len()))));
+            let direction = if flag { ProbeDirection::Upload } else { ProbeDirection::Download };
+            prop_assert_eq!(extract_probe_message(through_frame(probe_message(direction, number))), Some((direction, number)));
+            let report = json!({ "path": path.clone(), "bytes": number });
+            prop_assert_eq!(extract_diagnostics_message(through_frame(diagnostics_message(&report))), Some(report));
+
+            let operation = if flag { IntendedOperation::Upload { path: path.clone(), size: number } } else { IntendedOperation::Delete { path: path.clone(), recursive: true } };
+            prop_assert_eq!(extract_can_i_request(through_frame(can_i_request(&operation))), Some(operation));
+
+            let version = ProtocolVersion::new(number as u16, 1);
+            prop_assert_eq!(extract_connect_message(through_frame(connect_message(path.clone(), path.clone(), version))), Some((path.clone(), path.clone(), Some(version))));
+            prop_assert_eq!(extract_resume_connect_message(through_frame(resume_connect_message(&path, version))), Some((path.clone(), Some(version))));
+            prop_assert_eq!(extract_grant_connect_message(through_frame(grant_connect_message(&path, version))), Some((path.clone(), Some(version))));
+            prop_assert_eq!(extract_grant_request_message(through_frame(grant_message_request(&path, number, number / 2))), Some((path.clone(), number, number / 2)));
+            prop_assert_eq!(extract_idempotency_key(&through_frame(attach_idempotency_key(delete_message(&path, flag), &path))), Some(path.clone()));
+            prop_assert_eq!(extract_session(&through_frame(attach_session(delete_message(&path, flag), &path))), Some(path.clone()));
+            let provenance = Provenance { hostname: Some(path.clone()), client_version: flag.then(|| path.clone()), local_path: None };
+            let upload = through_frame(attach_encryption(upload_message(&path, FileType::Binary, number, 0, None, Some(provenance.clone())), &Encryption { format: number as u32, hidden_name: flag }));
+            prop_assert_eq!(extract_upload_provenance(&upload), Some(provenance));
+            prop_assert_eq!(extract_upload_encryption(&upload), Some(Encryption { format: number as u32, hidden_name: flag }));
+        }
+
+        #[test]
+        fn test_dir_query_round_trips(path in any::<Option<String>>(), filters in prop::collection::vec(any::<String>(), 0..4), offset in any::<u64>(), limit in any::<Option<u64>>(), depth in any::<u32>(), dotfiles in any::<Option<bool>>(), internal in any::<bool>()) {
+            let query = DirQuery { path, filters, offset, limit, depth, dotfiles, internal };
+            prop_assert_eq!(extract_dir_request_message(through_frame(dir_query_request(&query))), Some(query));
+        }
+
+        #[test]
+        fn test_response_round_trips(code in any_code(), text in any::<String>(), number in any::<u64>()) {
+            let ack = through_frame(attach_removed_count(ack_messsage(MessageDirection::Response, code.clone(), Some(text.clone())), number));
+            prop_assert_eq!(response_status(&ack), Some(code.clone()));
+            prop_assert_eq!(extract_removed_count(&ack), Some(number));
+            prop_assert_eq!(extract_ack_message(ack), Some((code.clone(), text.clone())));
+
+            prop_assert_eq!(extract_can_i_response(through_frame(can_i_response(code.clone(), &text))), Some((code.clone(), text.clone())));
+            let versions = vec![VersionInfo { number: number as u32, size: number, modified: None, replaced_at: number, replaced_by: Some(text.clone()) }];
+            prop_assert_eq!(extract_versions_response(through_frame(versions_response(code.clone(), &text, &versions))), Some((code.clone(), text.clone(), versions)));
+            let signature = (number % 2 == 0).then(|| Signature { block_size: number as u32, blocks: Vec::new() });
+            prop_assert_eq!(extract_signature_response(through_frame(signature_response(code.clone(), &text, signature.as_ref()))), Some((code.clone(), text.clone(), signature)));
+            let preview = (number % 2 == 0).then(|| FilePreview { kind: FileType::Text, content: text.clone().into_bytes(), truncated: number % 4 == 0 });
+            prop_assert_eq!(extract_preview_response(through_frame(preview_response(code.clone(), &text, preview.as_ref()))), Some((code.clone(), text.clone(), preview)));
+            let png = (number % 2 == 0).then(|| number.to_be_bytes().to_vec());
+            prop_assert_eq!(extract_thumbnail_response(through_frame(thumbnail_response(code.clone(), &text, png.as_deref()))), Some((code.clone(), text.clone(), png)));
+            let link = (number % 2 == 0).then(|| ShareLink::new(text.clone(), text.clone(), number));
+            prop_assert_eq!(extract_share_link_response(through_frame(share_link_response(code.clone(), &text, link.as_ref()))), Some((code.clone(), text.clone(), link)));
+            prop_assert_eq!(extract_upload_dir_response(through_frame(upload_dir_response(code.clone(), &text))), Some((code.clone(), text.clone())));
+            prop_assert_eq!(extract_download_dir_response(through_frame(download_dir_response(code.clone(), &text, number, None))), Some((code.clone(), text.clone(), number, None)));
+            let usage = QuotaInfo { used: number, quota: Some(number), remaining: Some(0) };
+            prop_assert_eq!(extract_quota_response(through_frame(quota_response(&usage))), Some(usage));
+            prop_assert_eq!(extract_dir_page_total(&through_frame(dir_page_total(dir_message_response(code.clone(), &text, &text, number), number))), Some(number));
+            prop_assert_eq!(extract_dir_response_message(through_frame(dir_message_response(code.clone(), &text, &text, number))), Some((code.clone(), text.clone(), text.clone(), number)));
+            prop_assert_eq!(extract_upload_response_message(through_frame(upload_message_response(code.clone(), &text, number))), Some((code.clone(), text.clone(), number)));
+
+            let resume = (number % 2 == 0).then(|| ResumeToken::new(text.clone(), number));
+            let version = Some(ProtocolVersion::new(number as u16, 0));
+            let connected = through_frame(session_ack(connect_ack_message(code.clone(), Some(text.clone()), version, resume.clone()), &SessionToken::new(text.clone(), number)));
+            prop_assert_eq!(extract_session_ack(&connected), Some(SessionToken::new(text.clone(), number)));
+            prop_assert_eq!(extract_connect_ack_message(connected), Some((code.clone(), text.clone(), version, resume)));
+            let bounds = FrameSizeBounds::new(number as u32, number as u32, number as u32);
+            prop_assert_eq!(extract_frame_bounds(&through_frame(advertise_frame_bounds(ack_messsage(MessageDirection::Response, code.clone(), None), bounds))), Some(bounds));
+
+            let download = DownloadResponse { status: code.clone(), message: text.clone(), kind: FileType::Text, frame_count: number, offset: number / 2, length: number / 3, checksum: None };
+            prop_assert_eq!(extract_download_response_message(through_frame(download_message_response(download.clone()))), Some(download));
+            let grant = (number % 2 == 0).then(|| UploadGrant::new(text.clone(), text.clone(), number, number));
+            prop_assert_eq!(extract_grant_response_message(through_frame(grant_message_response(code.clone(), &text, grant.clone()))), Some((code.clone(), text.clone(), grant)));
+            let info = (number % 2 == 0).then(|| FileInfo::new(text.clone(), text.clone(), FileType::Binary, number));
+            let stat = extract_stat_response_message(through_frame(stat_message_response(code.clone(), &text, info.clone())));
+            prop_assert!(stat == Some((code.clone(), text.clone(), info)));
+            let stats = TransferStats { schema: number as u32, ip: text.clone(), file_size: number, throughput: crate::network_stats::Throughput { transfer_time: number as f32, data_rate: 1.5 }, latency: None, frame_sizes: vec![number as u32], recorded_at: number };
+            prop_assert_eq!(extract_stats_response_message(through_frame(stats_response_message(stats.clone()))), Some(stats));
+        }
+
+        // Whatever arrives, parsing and validating it answers with an error rather than panicking
+        #[test]
+        fn test_parse_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..256), message in any_message()) {
+            for codec in [Codec::Json, Codec::MessagePack] {
+                if let Ok(m) = Message::parse(codec, &bytes) {
+                    let _ = m.validate();
+                }
+            }
+            let _ = message.validate();
+        }
+
+        // A peer can send anything, so every extractor has to turn garbage into None rather than panic
+        #[test]
+        fn test_extractors_never_panic(message in any_message()) {
+            let message = through_frame(message);
+            let _ = extract_connect_message(message.clone());
+            let _ = extract_resume_connect_message(message.clone());
+            let _ = extract_grant_connect_message(message.clone());
+            let _ = extract_connect_ack_message(message.clone());
+            let _ = extract_session_ack(&message);
+            let _ = extract_session(&message);
+            let _ = extract_frame_bounds(&message);
+            let _ = extract_grant_request_message(message.clone());
+            let _ = extract_grant_response_message(message.clone());
+            let _ = extract_stat_request_message(message.clone());
+            let _ = extract_stat_response_message(message.clone());
+            let _ = extract_stats_response_message(message.clone());
+            let _ = extract_upload_response_message(message.clone());
+            prop_assert_eq!(&Message::from_bytes(Codec::MessagePack, &message.to_bytes(Codec::MessagePack).unwrap()).unwrap(), &message);
+            let _ = extract_delete_message(message.clone());
+            let _ = extract_hold_message(message.clone());
+            let _ = extract_restore_message(message.clone());
+            let _ = extract_purge_trash_message(message.clone());
+            let _ = extract_versions_message(message.clone());
+            let _ = extract_versions_response(message.clone());
+            let _ = extract_preview_message(message.clone());
+            let _ = extract_preview_response(message.clone());
+            let _ = extract_thumbnail_message(message.clone());
+            let _ = extract_thumbnail_response(message.clone());
+            let _ = extract_share_link_request(message.clone());
+            let _ = extract_share_link_response(message.clone());
+            let _ = extract_share_connect_message(message.clone());
+            let _ = extract_quota_response(message.clone());
+            let _ = extract_subfolder_message(message.clone());
+            let _ = extract_upload_message(message.clone());
+            let _ = extract_upload_provenance(&message);
+            let _ = extract_upload_encryption(&message);
+            let _ = extract_download_request_message(message.clone());
+            let _ = extract_download_response_message(message.clone());
+            let _ = extract_dir_request_message(message.clone());
+            let _ = extract_dir_response_message(message.clone());
+            let _ = extract_can_i_request(message.clone());
+            let _ = extract_rename_message(message.clone());
+            let _ = extract_move_message(message.clone());
+            let _ = extract_change_dir_message(message.clone());
+            let _ = extract_copy_message(message.clone());
+            let _ = extract_share_message(message.clone());
+            let _ = extract_user_admin_message(message.clone());
+            let _ = extract_heartbeat_message(message.clone());
+            let _ = extract_probe_message(message.clone());
+            let _ = extract_diagnostics_message(message.clone());
+            let _ = extract_can_i_response(message.clone());
+            let _ = extract_removed_count(&message);
+            let _ = extract_idempotency_key(&message);
+            let _ = extract_capabilities(&message);
+            let _ = extract_compressions(&message);
+            let _ = extract_compression(&message);
+            let _ = extract_codecs(&message);
+            let _ = extract_channel(&message);
+            let _ = extract_signature_message(message.clone());
+            let _ = extract_signature_response(message.clone());
+            let _ = extract_signature(&message);
+            let _ = extract_delta(&message);
+            let _ = extract_upload_dir_message(message.clone());
+            let _ = extract_upload_dir_response(message.clone());
+            let _ = extract_download_dir_message(message.clone());
+            let _ = extract_download_dir_response(message.clone());
+            let _ = extract_subscribe_message(message.clone());
+            let _ = extract_event_message(message.clone());
+            let _ = extract_changes_since_request(message.clone());
+            let _ = extract_changes_since_response(message.clone());
+            let _ = extract_ping_message(message.clone());
+            let _ = extract_close_reason(&message);
+            let _ = extract_pong_message(message.clone());
+            let _ = extract_queued_message(message.clone());
+            let _ = extract_interactive(&message);
+            let _ = extract_cancel_message(message.clone());
+            let _ = extract_idle_timeout(&message);
+            let _ = extract_quic_offer(&message);
+            let _ = extract_connections_request(&message);
+            let _ = extract_dedupe_response(message.clone());
+            let _ = extract_usage_response(message.clone());
+            let _ = extract_connections_response_message(message.clone());
+            let _ = response_status(&message);
+            let _ = extract_ack_message(message);
+        }
+    }
+}
@@ -1,84 +1,366 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display};
-use std::sync::{Arc, Mutex};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::time::{Duration, Instant};
 
+use crate::error::HermesError;
 use crate::file_io::JsonFile;
+use crate::session::unix_now;
+
+// The layout records are written in. Records without a schema are from before latency was measured, and are converted when read.
+pub const STATS_SCHEMA: u32 = 2;
+
+// How fast the file itself moved
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Throughput {
+    pub transfer_time: f32, //Seconds
+    pub data_rate: f32 //MB/s
+}
+
+// Round trips the client timed with pings just before the transfer, kept apart from its throughput
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub struct Latency {
+    pub rtt: f32, //The fastest round trip, in seconds
+    pub mean_rtt: f32,
+    pub samples: u32
+}
+impl Latency {
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let fastest = samples.iter().min()?;
+        Some(
+            Self {
+                rtt: fastest.as_secs_f32(),
+                mean_rtt: samples.iter().sum::<Duration>().as_secs_f32() / samples.len() as f32,
+                samples: samples.len() as u32
+            }
+        )
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct TransferStats {
-    pub file_size: u32,
-    pub transfer_time: f32,
-    pub data_rate: f32,
-    pub latency: f32,
-    pub ip: String
+    pub schema: u32,
+    pub ip: String,
+    pub file_size: u64,
+    pub throughput: Throughput,
+    #[serde(default)]
+    pub latency: Option<Latency>, //None when the client did not time any pings
+    #[serde(default)]
+    pub frame_sizes: Vec<u32>, //Every frame size the transfer used, in order, so that the defaults can be tuned from real transfers
+    #[serde(default)]
+    pub recorded_at: u64 //Unix seconds. Records from before this was kept are given the time they were converted.
 }
 impl Debug for TransferStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {} bytes, {} seconds, {} MB/s, {} s", &self.ip, self.file_size, self.transfer_time, self.data_rate, self.latency)
+        write!(f, "{}: {} bytes, {} seconds, {} MB/s", &self.ip, self.file_size, self.throughput.transfer_time, self.throughput.data_rate)?;
+        if let Some(latency) = self.latency.as_ref() {
+            write!(f, ", {} s", latency.rtt)?;
+        }
+
+        Ok(())
     }
 }
 impl Display for TransferStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "IP: {}\nFile Size (bytes): {}\nTransfer Time (s):{}\nTransfer Rate (MB/s): {}\nLatency (s): {}", &self.ip, self.file_size, self.transfer_time, self.data_rate, self.latency)
+        write!(f, "IP: {}\nFile Size (bytes): {}\nTransfer Time (s):{}\nTransfer Rate (MB/s): {}", &self.ip, self.file_size, self.throughput.transfer_time, self.throughput.data_rate)?;
+        match self.latency.as_ref() {
+            Some(l) => write!(f, "\nLatency (s): {} (mean {} over {} pings)", l.rtt, l.mean_rtt, l.samples)?,
+            None => write!(f, "\nLatency (s): not measured")?
+        }
+        if !self.frame_sizes.is_empty() {
+            write!(f, "\nFrame Sizes (bytes): {:?}", &self.frame_sizes)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Records as they were written before the schema was kept. Their latency was only the inverse of the transfer time, so it is dropped.
+#[derive(Deserialize)]
+struct TransferStatsV1 {
+    file_size: u64,
+    transfer_time: f32,
+    data_rate: f32,
+    ip: String,
+    #[serde(default)]
+    frame_sizes: Vec<u32>,
+    #[serde(default)]
+    recorded_at: u64
+}
+impl From<TransferStatsV1> for TransferStats {
+    fn from(value: TransferStatsV1) -> Self {
+        Self {
+            schema: STATS_SCHEMA,
+            ip: value.ip,
+            file_size: value.file_size,
+            throughput: Throughput { transfer_time: value.transfer_time, data_rate: value.data_rate },
+            latency: None,
+            frame_sizes: value.frame_sizes,
+            recorded_at: value.recorded_at
+        }
+    }
+}
+
+// Returns the record, and whether it had to be converted
+fn parse_record(value: serde_json::Value) -> Result<(TransferStats, bool), serde_json::Error> {
+    match value.get("schema") {
+        Some(_) => Ok((serde_json::from_value(value)?, false)),
+        None => Ok((serde_json::from_value::<TransferStatsV1>(value)?.into(), true))
+    }
+}
+// Reads a statistics file, which is one record a line, or the single JSON array the first versions wrote. Lines that cannot be read,
+// such as one cut off by a crash part way through an append, are dropped. Also says whether the file should be written again in the current layout.
+fn read_records(contents: &str, now: u64) -> Result<(Vec<TransferStats>, bool), HermesError> {
+    let array = contents.trim_start().starts_with('[');
+    let values: Vec<Result<serde_json::Value, serde_json::Error>> = match array {
+        true => serde_json::from_str::<Vec<serde_json::Value>>(contents)?.into_iter().map(Ok).collect(),
+        false => contents.lines().filter(|x| !x.trim().is_empty()).map(serde_json::from_str).collect()
+    };
+
+    let total = values.len();
+    let mut stale = array;
+    let mut records = Vec::with_capacity(total);
+    for (mut record, converted) in values.into_iter().filter_map(|x| parse_record(x.ok()?).ok()) {
+        if record.recorded_at == 0 {
+            record.recorded_at = now;
+            stale = true;
+        }
+        stale |= converted;
+        records.push(record);
     }
+
+    if records.len() != total {
+        tracing::warn!("{} unreadable network statistics records were dropped", total - records.len());
+        stale = true;
+    }
+    Ok((records, stale))
+}
+fn write_records(records: &[TransferStats]) -> Result<String, HermesError> {
+    let mut contents = String::new();
+    for record in records {
+        contents.push_str(&serde_json::to_string(record)?);
+        contents.push('\n');
+    }
+
+    Ok(contents)
+}
+
+pub const DEFAULT_STATS_MAX_BYTES: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_KEPT_STATS_FILES: usize = 14;
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+// When the live statistics file is moved aside for a new one
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsRotation {
+    #[default]
+    Never,
+    Daily, //When the first record in it is from an earlier day, in UTC
+    Size //When it has reached max_bytes
 }
 
+fn default_max_bytes() -> u64 {
+    DEFAULT_STATS_MAX_BYTES
+}
+fn default_kept_files() -> usize {
+    DEFAULT_KEPT_STATS_FILES
+}
+
+// The stats section of config.json
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StatsConfig {
+    #[serde(default)]
+    pub rotation: StatsRotation,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64, //Only used by size rotation
+    #[serde(default = "default_kept_files")]
+    pub kept_files: usize, //How many rotated files are kept before the oldest is deleted
+    #[serde(default)]
+    pub retention_days: Option<u64> //Records, and rotated files, older than this are deleted. None keeps them.
+}
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            rotation: StatsRotation::default(),
+            max_bytes: default_max_bytes(),
+            kept_files: default_kept_files(),
+            retention_days: None
+        }
+    }
+}
+
+// A rotated file is named after the live one with the time it was rotated, so stats.json becomes stats.<unix seconds>.json
+fn rotated_path(live: &Path, at: u64) -> PathBuf {
+    let stem = live.file_stem().unwrap_or_default().to_string_lossy();
+    match live.extension() {
+        Some(ext) => live.with_file_name(format!("{stem}.{at}.{}", ext.to_string_lossy())),
+        None => live.with_file_name(format!("{stem}.{at}"))
+    }
+}
+// Oldest first
+fn rotated_files(live: &Path) -> Vec<(u64, PathBuf)> {
+    let directory = match live.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new(".")
+    };
+    let entries = match std::fs::read_dir(directory) {
+        Ok(e) => e,
+        Err(_) => return Vec::new()
+    };
+
+    let prefix = format!("{}.", live.file_stem().unwrap_or_default().to_string_lossy());
+    let suffix = live.extension().map(|x| format!(".{}", x.to_string_lossy())).unwrap_or_default();
+    let mut result: Vec<(u64, PathBuf)> = entries.filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter_map(|x| {
+            let name = x.file_name()?.to_string_lossy().to_string();
+            let at = name.strip_prefix(&prefix)?.strip_suffix(&suffix)?.parse().ok()?;
+            Some((at, x))
+        })
+        .collect();
+
+    result.sort();
+    result
+}
+
+// The live file holds one record a line, and a transfer is recorded by appending its line. Only the live file's records are kept in memory.
 struct NetworkAnalyzerData {
     file: JsonFile,
-    stats: Vec<TransferStats>
+    config: StatsConfig,
+    stats: Vec<TransferStats>,
+    live_bytes: u64 //How large the live file is
 }
 impl NetworkAnalyzerData {
     fn new() -> Self {
         Self { 
             file: JsonFile::new(),
-            stats: Vec::new()
+            config: StatsConfig::default(),
+            stats: Vec::new(),
+            live_bytes: 0
         }
     }
 
-    fn open(&mut self, path: &str) -> Result<(), String> {
+    fn open(&mut self, path: &str, config: StatsConfig, now: u64) -> Result<(), HermesError> {
         let contents = self.file.open(path)?;
+        self.config = config;
+        self.live_bytes = contents.len() as u64;
 
-        let values: Result<Vec<TransferStats>, _> = serde_json::from_str(&contents);
-        match values {
-            Ok(l) => {
-                self.stats = l;
-                Ok(())
-            },
-            Err(e) => Err(e.to_string())
+        let (stats, stale) = read_records(&contents, now)?;
+        self.stats = stats;
+        if stale {
+            self.save()?;
         }
+        // Rotated files are not read again, but are brought to the current layout once, so every file on disk reads the same way
+        for (_, rotated) in rotated_files(Path::new(path)) {
+            let (records, stale) = read_records(&std::fs::read_to_string(&rotated)?, now)?;
+            if stale {
+                std::fs::write(&rotated, write_records(&records)?)?;
+            }
+        }
+
+        self.maintain(now)
     }
-    fn save(&self) -> Result<(), String> {
-        let contents = match serde_json::to_string(&self.stats) {
-            Ok(s) => s,
-            Err(e) => return Err(format!("{}", e))
+    // Rewrites the live file from memory
+    fn save(&mut self) -> Result<(), HermesError> {
+        let contents = write_records(&self.stats)?;
+        self.file.save(&contents)?;
+        self.live_bytes = contents.len() as u64;
+        Ok(())
+    }
+    fn append(&mut self, stat: &TransferStats) -> Result<(), HermesError> {
+        let path = self.file.path().ok_or_else(|| HermesError::NotOpen(String::from("no file is loaded")))?;
+        let line = format!("{}\n", serde_json::to_string(stat)?);
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())?;
+
+        self.live_bytes += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotation_due(&self, now: u64) -> bool {
+        match self.config.rotation {
+            StatsRotation::Never => false,
+            StatsRotation::Daily => self.stats.first().is_some_and(|x| x.recorded_at / DAY_SECS != now / DAY_SECS),
+            StatsRotation::Size => !self.stats.is_empty() && self.live_bytes >= self.config.max_bytes
+        }
+    }
+    fn rotate(&mut self, now: u64) -> Result<(), HermesError> {
+        let live = match self.file.path() {
+            Some(p) => PathBuf::from(p),
+            None => return Ok(())
         };
 
-        self.file.save(&contents)
+        let mut at = now;
+        while rotated_path(&live, at).exists() {
+            at += 1;
+        }
+        std::fs::rename(&live, rotated_path(&live, at))?;
+        std::fs::File::create(&live)?;
+
+        self.stats.clear();
+        self.live_bytes = 0;
+        Ok(())
     }
+    // Rotates the live file if it is due, then deletes what the retention policy no longer keeps
+    fn maintain(&mut self, now: u64) -> Result<(), HermesError> {
+        let live = match self.file.path() {
+            Some(p) => PathBuf::from(p),
+            None => return Ok(())
+        };
+        if self.rotation_due(now) {
+            self.rotate(now)?;
+        }
+
+        let cutoff = self.config.retention_days.map(|x| now.saturating_sub(x * DAY_SECS));
+        if let Some(cutoff) = cutoff {
+            let before = self.stats.len();
+            self.stats.retain(|x| x.recorded_at >= cutoff);
+            if self.stats.len() != before {
+                self.save()?;
+            }
+        }
 
-    fn record_transfer(&mut self, file_size: u32, duration: f32, ip: &str) -> Result<(), String> {
+        let mut rotated = rotated_files(&live);
+        let expired = cutoff.map(|c| rotated.iter().filter(|(at, _)| *at < c).count()).unwrap_or_default();
+        let excess = rotated.len().saturating_sub(self.config.kept_files);
+        for (_, path) in rotated.drain(..expired.max(excess)) {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn record_transfer(&mut self, file_size: u64, duration: f32, ip: &str, frame_sizes: Vec<u32>, latency: Option<Latency>, now: u64) -> Result<(), HermesError> {
         if !self.file.is_open() {
-            return Err(String::from("no file is loaded"));
+            return Err(HermesError::NotOpen(String::from("no file is loaded")));
         }
 
         let rate = Self::calculate_data_rate(file_size, duration);
         if rate.is_none() {
-            return Err(String::from("duration is less than or equal to zero"));
+            return Err(HermesError::Invalid(String::from("duration is less than or equal to zero")));
         }
-        let latency = 1.0 / duration;
 
         let stat = TransferStats {
+            schema: STATS_SCHEMA,
+            ip: ip.to_string(),
             file_size,
-            transfer_time: duration,
-            data_rate: rate.unwrap(),
+            throughput: Throughput { transfer_time: duration, data_rate: rate.unwrap() },
             latency,
-            ip: ip.to_string()
+            frame_sizes,
+            recorded_at: now
         };
 
+        if self.rotation_due(now) {
+            self.maintain(now)?;
+        }
+        self.append(&stat)?;
         self.stats.push(stat);
         Ok(())
     }
-    fn calculate_data_rate(file_size: u32, transfer_time: f32) -> Option<f32> {
+    fn calculate_data_rate(file_size: u64, transfer_time: f32) -> Option<f32> {
         let conv: f32 = file_size as f32;
 
         if transfer_time > 0.0 {
@@ -109,8 +391,28 @@ impl NetworkAnalyzerData {
     }
 }
 
+// How hard the statistics lock has been fought over. A recovered poisoning means a thread panicked while holding it.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct LockMetrics {
+    pub acquisitions: u64,
+    pub contended: u64, //Acquisitions that had to wait for another thread
+    pub wait_micros: u64, //Time spent waiting, across every contended acquisition
+    pub poison_recoveries: u64
+}
+
+#[derive(Default)]
+struct LockCounters {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_micros: AtomicU64,
+    poison_recoveries: AtomicU64
+}
+
+// Shared by every connection. A panic while the lock is held poisons it, but the statistics are only ever appended to,
+// so the lock is recovered and counted instead of taking statistics down for the rest of the process.
 pub struct NetworkAnalyzer {
-    data: Arc<Mutex<NetworkAnalyzerData>>
+    data: Arc<Mutex<NetworkAnalyzerData>>,
+    counters: Arc<LockCounters>
 }
 impl Default for NetworkAnalyzer {
     fn default() -> Self {
@@ -120,26 +422,181 @@ impl Default for NetworkAnalyzer {
 impl NetworkAnalyzer {
     pub fn new() -> Self {
         Self {
-            data: Arc::new(Mutex::new(NetworkAnalyzerData::new()))
+            data: Arc::new(Mutex::new(NetworkAnalyzerData::new())),
+            counters: Arc::new(LockCounters::default())
         }
     }
 
-    pub fn open(&self, path: &str) -> Result<(), String> {
-        let mut data = self.data.lock().unwrap();
-        data.open(path)
+    fn lock(&self) -> MutexGuard<'_, NetworkAnalyzerData> {
+        self.counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        let result = match self.data.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(e)) => Err(e),
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let result = self.data.lock();
+                self.counters.contended.fetch_add(1, Ordering::Relaxed);
+                self.counters.wait_micros.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+                result
+            }
+        };
+
+        result.unwrap_or_else(|e: PoisonError<_>| {
+            self.counters.poison_recoveries.fetch_add(1, Ordering::Relaxed);
+            self.data.clear_poison();
+            e.into_inner()
+        })
     }
-    pub fn save(&self) -> Result<(), String> {
-        let data = self.data.lock().unwrap();
-        data.save()
+    pub fn lock_metrics(&self) -> LockMetrics {
+        LockMetrics {
+            acquisitions: self.counters.acquisitions.load(Ordering::Relaxed),
+            contended: self.counters.contended.load(Ordering::Relaxed),
+            wait_micros: self.counters.wait_micros.load(Ordering::Relaxed),
+            poison_recoveries: self.counters.poison_recoveries.load(Ordering::Relaxed)
+        }
     }
 
-    pub fn record_transfer(&self, file_size: u32, duration: f32, ip: &str) -> Result<(), String> {
-        let mut data = self.data.lock().unwrap();
-        data.record_transfer(file_size, duration, ip)
+    pub fn open(&self, path: &str) -> Result<(), HermesError> {
+        self.open_with(path, StatsConfig::default())
+    }
+    pub fn open_with(&self, path: &str, config: StatsConfig) -> Result<(), HermesError> {
+        self.lock().open(path, config, unix_now())
+    }
+    // Transfers are written as they are recorded, so this only compacts the live file
+    pub fn save(&self) -> Result<(), HermesError> {
+        self.lock().save()
+    }
+    // Run now and then, so files are rotated and expired even while nothing is being recorded
+    pub fn maintain(&self) -> Result<(), HermesError> {
+        self.lock().maintain(unix_now())
+    }
+
+    pub fn record_transfer(&self, file_size: u64, duration: f32, ip: &str) -> Result<(), HermesError> {
+        self.lock().record_transfer(file_size, duration, ip, Vec::new(), None, unix_now())
+    }
+    pub fn record_tuned_transfer(&self, file_size: u64, duration: f32, ip: &str, frame_sizes: Vec<u32>, latency: Option<Latency>) -> Result<(), HermesError> {
+        self.lock().record_transfer(file_size, duration, ip, frame_sizes, latency, unix_now())
     }
 
     pub fn get_last_stat_by_ip(&self, ip: &str) -> Option<TransferStats> {
-        let data = self.data.lock().unwrap();
-        data.get_last_stat_by_ip(ip)
+        self.lock().get_last_stat_by_ip(ip)
+    }
+    // How many transfers the live file holds, and how many bytes they carried between them
+    pub fn totals(&self) -> (usize, u64) {
+        let data = self.lock();
+        (data.stats.len(), data.stats.iter().map(|x| x.file_size).sum())
+    }
+}
+
+#[test]
+fn test_poisoned_lock_recovers() {
+    let path = std::env::temp_dir().join(format!("hermes_stats_{}.json", std::process::id()));
+    std::fs::write(&path, "").unwrap();
+
+    let analyzer = NetworkAnalyzer::new();
+    analyzer.open(&path.to_string_lossy()).unwrap();
+    analyzer.record_transfer(10, 1.0, "127.0.0.1").unwrap();
+
+    // A thread that panics while holding the lock must not take the statistics down with it
+    let data = Arc::clone(&analyzer.data);
+    let _ = std::thread::spawn(move || {
+        let _guard = data.lock().unwrap();
+        panic!("handler panicked");
+    }).join();
+    assert!(analyzer.data.is_poisoned());
+
+    analyzer.record_transfer(20, 1.0, "127.0.0.1").unwrap();
+    assert_eq!(analyzer.totals(), (2, 30));
+    assert_eq!(analyzer.get_last_stat_by_ip("127.0.0.1").map(|x| x.file_size), Some(20));
+
+    let metrics = analyzer.lock_metrics();
+    assert_eq!(metrics.poison_recoveries, 1);
+    assert_eq!(metrics.acquisitions, 5);
+    assert!(!analyzer.data.is_poisoned());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_stats_rotation_and_retention() {
+    let directory = std::env::temp_dir().join(format!("hermes_stats_rotation_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let live = directory.join("stats.json");
+    let now = 1_700_000_000;
+
+    // A file from an older version is converted to one record a line, dated when it was converted
+    std::fs::write(&live, r#"[{"file_size":10,"transfer_time":1.0,"data_rate":0.00001,"latency":1.0,"ip":"10.0.0.1"}]"#).unwrap();
+    let config = StatsConfig { rotation: StatsRotation::Size, kept_files: 2, retention_days: Some(1), ..Default::default() };
+    let mut data = NetworkAnalyzerData::new();
+    data.open(&live.to_string_lossy(), config, now).unwrap();
+    assert_eq!(data.stats.len(), 1);
+    assert_eq!(data.stats[0].recorded_at, now);
+    let converted = std::fs::read_to_string(&live).unwrap();
+    assert_eq!(converted.lines().count(), 1);
+
+    // Recording appends, and a full live file is rotated first
+    data.record_transfer(20, 1.0, "10.0.0.1", Vec::new(), None, now).unwrap();
+    let appended = std::fs::read_to_string(&live).unwrap();
+    assert!(appended.starts_with(&converted) && appended.lines().count() == 2);
+
+    data.config.max_bytes = 1;
+    for i in 1..=3 {
+        data.record_transfer(30, 1.0, "10.0.0.1", Vec::new(), None, now + i).unwrap();
+    }
+    assert_eq!(data.stats.len(), 1);
+    assert_eq!(std::fs::read_to_string(&live).unwrap().lines().count(), 1);
+    let rotated = rotated_files(&live);
+    assert_eq!(rotated.iter().map(|x| x.0).collect::<Vec<_>>(), vec![now + 2, now + 3]); //The oldest is past kept_files
+    assert_eq!(std::fs::read_to_string(&rotated[0].1).unwrap().lines().count(), 1);
+
+    // Past retention, old records and rotated files are deleted
+    data.config.rotation = StatsRotation::Never;
+    data.maintain(now + 2 * DAY_SECS).unwrap();
+    assert!(data.stats.is_empty() && rotated_files(&live).is_empty());
+    assert_eq!(std::fs::read_to_string(&live).unwrap(), "");
+
+    // A line cut off part way is dropped rather than failing the load
+    std::fs::write(&live, format!("{}\n{{\"file_size\":4", converted.trim())).unwrap();
+    let mut reopened = NetworkAnalyzerData::new();
+    reopened.open(&live.to_string_lossy(), StatsConfig::default(), now).unwrap();
+    assert_eq!(reopened.stats.len(), 1);
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn test_stats_schema_migration() {
+    let directory = std::env::temp_dir().join(format!("hermes_stats_schema_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let live = directory.join("stats.json");
+    let rotated = rotated_path(&live, 1_600_000_000);
+    let now = 1_700_000_000;
+
+    // The first schema's latency was only 1 / transfer_time, so it is dropped rather than carried into the new field
+    let v1 = r#"{"file_size":2000000,"transfer_time":2.0,"data_rate":1.0,"latency":0.5,"ip":"10.0.0.1","frame_sizes":[4096],"recorded_at":1650000000}"#;
+    std::fs::write(&live, format!("{v1}\n")).unwrap();
+    std::fs::write(&rotated, format!("{v1}\n")).unwrap();
+
+    let analyzer = NetworkAnalyzer::new();
+    analyzer.open(&live.to_string_lossy()).unwrap();
+    let migrated = analyzer.get_last_stat_by_ip("10.0.0.1").unwrap();
+    assert_eq!((migrated.schema, migrated.throughput, migrated.latency), (STATS_SCHEMA, Throughput { transfer_time: 2.0, data_rate: 1.0 }, None));
+    assert_eq!((migrated.frame_sizes.as_slice(), migrated.recorded_at), ([4096].as_slice(), 1_650_000_000));
+    for path in [&live, &rotated] {
+        let (records, stale) = read_records(&std::fs::read_to_string(path).unwrap(), now).unwrap();
+        assert!(!stale && records == vec![migrated.clone()]);
     }
-}
\ No newline at end of file
+
+    // Measured round trips are kept beside the throughput
+    let latency = Latency::from_samples(&[Duration::from_millis(30), Duration::from_millis(10), Duration::from_millis(20)]).unwrap();
+    assert_eq!((latency.rtt, latency.mean_rtt, latency.samples), (0.01, 0.02, 3));
+    assert!(Latency::from_samples(&[]).is_none());
+    analyzer.record_tuned_transfer(1000, 0.5, "10.0.0.1", Vec::new(), Some(latency)).unwrap();
+    let recorded = analyzer.get_last_stat_by_ip("10.0.0.1").unwrap();
+    assert_eq!((recorded.latency, recorded.throughput.data_rate), (Some(latency), 0.002));
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
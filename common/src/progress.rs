@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// How often a transfer that is still going is reported. The end of a transfer is always reported.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Where a transfer is, as told to whoever is watching it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProgressUpdate {
+    pub done: u64, //Bytes of the file moved so far, counted before compression
+    pub total: Option<u64>, //Absent when the size was not known ahead of time
+    pub rate: f64, //Bytes a second, averaged over the whole transfer so far
+    pub eta: Option<Duration>
+}
+impl ProgressUpdate {
+    // How much of the transfer is done, from 0 to 1
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(t) => Some((self.done as f64 / t as f64).min(1.0)),
+            None => None
+        }
+    }
+}
+
+// Stops a transfer from another thread or task. It is checked between frames, so the frame in flight is finished first.
+// A compressed stream is ended with a cancel block, and the connection can be used again once the Cancel request is answered.
+// An uncompressed one is left part way through its frames, so the connection has to be closed afterwards.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Counts the bytes of one transfer, and reports them to a callback no more often than PROGRESS_INTERVAL
+pub struct Progress<'a> {
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    last_report: Option<Instant>,
+    report: Option<Box<dyn FnMut(ProgressUpdate) + Send + 'a>>,
+    cancel: Option<CancelToken>
+}
+impl Default for Progress<'_> {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+impl<'a> Progress<'a> {
+    pub fn new(total: Option<u64>, report: impl FnMut(ProgressUpdate) + Send + 'a) -> Self {
+        Self {
+            report: Some(Box::new(report)),
+            ..Self::none()
+        }.with_total(total)
+    }
+    // For transfers nobody watches and nothing cancels
+    pub fn none() -> Self {
+        Self {
+            total: None,
+            done: 0,
+            started: Instant::now(),
+            last_report: None,
+            report: None,
+            cancel: None
+        }
+    }
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+    fn with_total(mut self, total: Option<u64>) -> Self {
+        self.total = total;
+        self
+    }
+
+    // Senders know how much they are about to read, so they fill in a size the caller left out
+    pub fn expect(&mut self, total: u64) {
+        self.total.get_or_insert(total);
+    }
+    // For when the other side is the one that gave up
+    pub fn cancel(&mut self) {
+        self.cancel.get_or_insert_default().cancel();
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|x| x.is_cancelled())
+    }
+
+    pub fn update(&self) -> ProgressUpdate {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.done as f64 / elapsed } else { 0.0 };
+        let eta = match self.total {
+            Some(t) if rate > 0.0 => Some(Duration::from_secs_f64(t.saturating_sub(self.done) as f64 / rate)),
+            _ => None
+        };
+
+        ProgressUpdate { done: self.done, total: self.total, rate, eta }
+    }
+
+    // Counts bytes that were just moved. Returns false once the transfer has been cancelled, and it should stop.
+    pub fn advance(&mut self, bytes: u64) -> bool {
+        self.done += bytes;
+        if self.last_report.is_none_or(|x| x.elapsed() >= PROGRESS_INTERVAL) {
+            self.report_now();
+        }
+
+        !self.is_cancelled()
+    }
+    // Reports the end of the transfer, however recently the last report was
+    pub fn finish(&mut self) {
+        self.report_now();
+    }
+    fn report_now(&mut self) {
+        let update = self.update();
+        if let Some(r) = self.report.as_mut() {
+            r(update);
+            self.last_report = Some(Instant::now());
+        }
+    }
+}
+
+#[test]
+fn test_progress_reports() {
+    use std::sync::Mutex;
+
+    let updates = Mutex::new(Vec::new());
+    let token = CancelToken::new();
+    let mut progress = Progress::new(None, |x| updates.lock().unwrap().push(x)).with_cancel(token.clone());
+    progress.expect(100);
+    progress.expect(50);
+
+    // The first bytes are reported at once, and the rest wait out the interval
+    assert!(progress.advance(40));
+    assert!(progress.advance(10));
+    progress.finish();
+    token.cancel();
+    assert!(!progress.advance(0));
+    drop(progress);
+
+    let updates = updates.into_inner().unwrap();
+    assert_eq!(updates.iter().map(|x| (x.done, x.total)).collect::<Vec<_>>(), vec![(40, Some(100)), (50, Some(100))]);
+    assert_eq!(updates[1].fraction(), Some(0.5));
+    assert!(updates[1].eta.is_some());
+    assert_eq!(ProgressUpdate { done: 0, total: Some(0), rate: 0.0, eta: None }.fraction(), Some(1.0));
+}
@@ -0,0 +1,263 @@
+use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+#[derive(PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    major: u16,
+    minor: u16
+}
+impl Debug for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (self as &dyn Display).fmt(f)
+    }
+}
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+impl PartialOrd for ProtocolVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for ProtocolVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.major.cmp(&other.major) {
+            Ordering::Equal => self.minor.cmp(&other.minor),
+            x => x
+        }
+    }
+}
+impl FromStr for ProtocolVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = match s.split_once('.') {
+            Some(parts) => parts,
+            None => return Err(format!("could not deduce protocol version from '{s}'"))
+        };
+
+        match (major.parse::<u16>(), minor.parse::<u16>()) {
+            (Ok(ma), Ok(mi)) => Ok(Self::new(ma, mi)),
+            _ => Err(format!("could not deduce protocol version from '{s}'"))
+        }
+    }
+}
+impl ProtocolVersion {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self {
+            major,
+            minor
+        }
+    }
+
+    pub fn major(&self) -> u16 {
+        self.major
+    }
+    pub fn minor(&self) -> u16 {
+        self.minor
+    }
+
+    // Versions only speak to each other if they share a major version. Minor versions are additive.
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+    pub fn is_supported(&self) -> bool {
+        *self >= MIN_SUPPORTED_PROTOCOL_VERSION && *self <= CURRENT_PROTOCOL_VERSION && self.is_compatible(&CURRENT_PROTOCOL_VERSION)
+    }
+
+    // Determines the highest version both sides understand, or None if there is no overlap.
+    pub fn negotiate(&self, peer: &ProtocolVersion) -> Option<ProtocolVersion> {
+        if !self.is_compatible(peer) {
+            return None;
+        }
+
+        let result = std::cmp::min(*self, *peer);
+        if result.is_supported() {
+            Some(result)
+        } else {
+            None
+        }
+    }
+}
+
+pub const PROTOCOL_VERSION_1_0: ProtocolVersion = ProtocolVersion::new(1, 0);
+// Sizes, offsets, and frame counts are 64 bit. 1.x peers would truncate them, so they are refused at Connect.
+pub const PROTOCOL_VERSION_2_0: ProtocolVersion = ProtocolVersion::new(2, 0);
+// Uncompressed streams end with a frame that carries its own length instead of padding. 2.x peers would wait for the padding, so they are refused too.
+pub const PROTOCOL_VERSION_3_0: ProtocolVersion = ProtocolVersion::new(3, 0);
+pub const CURRENT_PROTOCOL_VERSION: ProtocolVersion = PROTOCOL_VERSION_3_0;
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: ProtocolVersion = PROTOCOL_VERSION_3_0;
+
+// Optional features a peer can speak, exchanged at Connect alongside the version. Each side only uses what both sides listed.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Debug)]
+pub enum Capability {
+    Resume, //Resumption tokens in the Connect ack
+    FrameTuning, //Frame size bounds in the Connect ack
+    Idempotency, //Idempotency keys on uploads and deletes
+    Trash, //Restore and PurgeTrash
+    Diagnostics, //Heartbeat, Probe, and Diagnostics
+    Versions, //Listing and fetching the older versions of a file
+    Quota, //Asking how much of a storage quota is left
+    Delta, //Signatures, and uploads and downloads that only send what changed
+    Archive, //Uploading and downloading whole directories as one tar archive
+    Events, //Subscribing to changes under a directory
+    Keepalive, //Ping and Pong, and the idle timeout in the Connect ack
+    Cancel, //Stopping a compressed upload or download part way through
+    Multiplex, //Downloads sent on channels tagged with their request ID, so several can be in flight at once
+    Preview, //The start of a file sent in the response, without a download
+    Thumbnail, //Small images of media files, which servers built without them do not offer
+    Quic, //File frames over a QUIC connection beside the TCP one, which only servers listening for QUIC offer
+    Queue //Notices of where a transfer waiting for the server to have room is in the queue
+}
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Resume => "resume",
+            Self::FrameTuning => "frame_tuning",
+            Self::Idempotency => "idempotency",
+            Self::Trash => "trash",
+            Self::Diagnostics => "diagnostics",
+            Self::Versions => "versions",
+            Self::Quota => "quota",
+            Self::Delta => "delta",
+            Self::Archive => "archive",
+            Self::Events => "events",
+            Self::Keepalive => "keepalive",
+            Self::Cancel => "cancel",
+            Self::Multiplex => "multiplex",
+            Self::Preview => "preview",
+            Self::Thumbnail => "thumbnail",
+            Self::Quic => "quic",
+            Self::Queue => "queue"
+        };
+
+        write!(f, "{text}")
+    }
+}
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resume" => Ok(Self::Resume),
+            "frame_tuning" => Ok(Self::FrameTuning),
+            "idempotency" => Ok(Self::Idempotency),
+            "trash" => Ok(Self::Trash),
+            "diagnostics" => Ok(Self::Diagnostics),
+            "versions" => Ok(Self::Versions),
+            "quota" => Ok(Self::Quota),
+            "delta" => Ok(Self::Delta),
+            "archive" => Ok(Self::Archive),
+            "events" => Ok(Self::Events),
+            "keepalive" => Ok(Self::Keepalive),
+            "cancel" => Ok(Self::Cancel),
+            "multiplex" => Ok(Self::Multiplex),
+            "preview" => Ok(Self::Preview),
+            "thumbnail" => Ok(Self::Thumbnail),
+            "quic" => Ok(Self::Quic),
+            "queue" => Ok(Self::Queue),
+            _ => Err(format!("unknown capability '{s}'"))
+        }
+    }
+}
+
+// Sent as a list of names, so a peer that knows more capabilities than we do is not refused. Names we do not know are dropped.
+#[derive(PartialEq, Eq, Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct Capabilities {
+    set: BTreeSet<Capability>
+}
+impl From<Vec<String>> for Capabilities {
+    fn from(value: Vec<String>) -> Self {
+        value.iter().filter_map(|x| x.parse().ok()).collect()
+    }
+}
+impl From<Capabilities> for Vec<String> {
+    fn from(value: Capabilities) -> Self {
+        value.set.iter().map(|x| x.to_string()).collect()
+    }
+}
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        Self {
+            set: iter.into_iter().collect()
+        }
+    }
+}
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.set.is_empty() {
+            return write!(f, "none");
+        }
+
+        let names: Vec<String> = self.set.iter().map(|x| x.to_string()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+impl Capabilities {
+    // Everything this build can speak
+    pub fn supported() -> Self {
+        [Capability::Resume, Capability::FrameTuning, Capability::Idempotency, Capability::Trash, Capability::Diagnostics, Capability::Versions, Capability::Quota, Capability::Delta, Capability::Archive, Capability::Events, Capability::Keepalive, Capability::Cancel, Capability::Multiplex, Capability::Preview, Capability::Thumbnail, Capability::Quic, Capability::Queue].into_iter().collect()
+    }
+    // What a peer that sends no list is assumed to speak, which is what shipped before capabilities were exchanged
+    pub fn legacy() -> Self {
+        [Capability::Resume, Capability::FrameTuning, Capability::Idempotency].into_iter().collect()
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.set.contains(&capability)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Capability> {
+        self.set.iter()
+    }
+
+    // The capabilities both sides listed
+    pub fn negotiate(&self, peer: &Capabilities) -> Capabilities {
+        self.set.intersection(&peer.set).copied().collect()
+    }
+}
+
+#[test]
+pub fn test_protocol_version_negotiation() {
+    let v1_0 = ProtocolVersion::new(1, 0);
+    let v1_4 = ProtocolVersion::new(1, 4);
+    let v2_0 = ProtocolVersion::new(2, 0);
+    let v3_0 = ProtocolVersion::new(3, 0);
+    let v4_0 = ProtocolVersion::new(4, 0);
+
+    assert!(v1_0 < v1_4 && v1_4 < v2_0);
+    assert_eq!(v1_4.to_string(), "1.4");
+    assert_eq!("1.4".parse::<ProtocolVersion>().unwrap(), v1_4);
+    assert!("1".parse::<ProtocolVersion>().is_err());
+
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&CURRENT_PROTOCOL_VERSION), Some(CURRENT_PROTOCOL_VERSION));
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&v4_0), None);
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&ProtocolVersion::new(0, 9)), None);
+
+    // 1.x peers only know 32 bit sizes, and 2.x peers pad their last frame
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&v1_4), None);
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&v2_0), None);
+    assert_eq!(CURRENT_PROTOCOL_VERSION.negotiate(&v3_0), Some(v3_0));
+}
+
+#[test]
+fn test_capability_negotiation() {
+    let ours = Capabilities::supported();
+    let theirs: Capabilities = serde_json::from_str(r#"["diagnostics", "resume", "compression"]"#).unwrap();
+    assert_eq!(theirs, [Capability::Resume, Capability::Diagnostics].into_iter().collect());
+
+    let negotiated = ours.negotiate(&theirs);
+    assert!(negotiated.contains(Capability::Diagnostics) && !negotiated.contains(Capability::Trash));
+    assert_eq!(negotiated.to_string(), "resume,diagnostics");
+    assert_eq!(serde_json::to_string(&negotiated).unwrap(), r#"["resume","diagnostics"]"#);
+    assert_eq!(Capabilities::default().to_string(), "none");
+    assert!(ours.negotiate(&Capabilities::legacy()) == Capabilities::legacy());
+}
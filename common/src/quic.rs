@@ -0,0 +1,294 @@
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::runtime::Runtime;
+
+use crate::checksum::{ChecksumAlgorithm, checksum_bytes};
+
+// QUIC carries file frames beside the TCP connection, which keeps every message. Each transfer gets a stream of its own, opened by the side that sends the frames.
+// The server's certificate is checked against the fingerprint it sent over the TCP connection, so the certificate can be one it made for itself.
+// Endpoints are driven by tokio, so they are only made from within a runtime. Clients that have none use BlockingQuic, which brings its own.
+pub const QUIC_ALPN: &[u8] = b"hermes";
+// Written first on every stream, so the other side sees the stream even when no frames follow
+const STREAM_MARKER: u8 = b'H';
+// A registration stream only carries a session token
+const MAX_REGISTRATION: usize = 256;
+
+pub fn certificate_fingerprint(cert: &CertificateDer) -> String {
+    checksum_bytes(cert.as_ref(), ChecksumAlgorithm::Sha256).value().to_string()
+}
+// A certificate for a server with none of its own, which clients only trust through its fingerprint
+pub fn generate_certificate() -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), String> {
+    let generated = rcgen::generate_simple_self_signed(vec![String::from("hermes")]).map_err(|e| e.to_string())?;
+    let key = PrivateKeyDer::try_from(generated.key_pair.serialize_der()).map_err(|e| e.to_string())?;
+
+    Ok((generated.cert.der().clone(), key))
+}
+
+pub fn server_endpoint(bind: SocketAddr, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Endpoint, String> {
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())?;
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| e.to_string())?;
+    Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), bind).map_err(|e| format!("unable to listen for QUIC on '{bind}' because '{e}'"))
+}
+
+// Trusts exactly one certificate, the one the server named over the connection the client already logged in over
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>
+}
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _name: &ServerName<'_>, _ocsp: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        match certificate_fingerprint(end_entity) == self.fingerprint {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(String::from("the QUIC certificate is not the one the server named")))
+        }
+    }
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub async fn connect(addr: SocketAddr, fingerprint: &str) -> Result<Connection, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedCertificate { fingerprint: fingerprint.to_string(), provider: Arc::clone(&provider) };
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let crypto = QuicClientConfig::try_from(tls).map_err(|e| e.to_string())?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0))
+    };
+    let mut endpoint = Endpoint::client(local).map_err(|e| e.to_string())?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+
+    let connecting = endpoint.connect(addr, "hermes").map_err(|e| e.to_string())?;
+    connecting.await.map_err(|e| format!("unable to reach '{addr}' over QUIC because '{e}'"))
+}
+
+// The first stream a client opens names the session it belongs to, so the server can hand the connection to it.
+// The server answers once the connection is handed over, so the client never asks for a transfer over QUIC before the server can find it.
+pub async fn register(connection: &Connection, session: &str) -> Result<(), String> {
+    let mut stream = QuicStream::open(connection).await?;
+    stream.send.write_all(session.as_bytes()).await.map_err(|e| e.to_string())?;
+    stream.finish()?;
+
+    match stream.recv.read_u8().await {
+        Ok(1) => Ok(()),
+        _ => Err(String::from("the server did not accept the session over QUIC"))
+    }
+}
+pub struct Registration {
+    pub session: String,
+    stream: QuicStream
+}
+impl Registration {
+    pub async fn accept(connection: &Connection) -> Result<Self, String> {
+        let mut stream = QuicStream::accept(connection).await?;
+        let session = stream.recv.read_to_end(MAX_REGISTRATION).await.map_err(|e| e.to_string())?;
+
+        Ok(Self { session: String::from_utf8(session).map_err(|e| e.to_string())?, stream })
+    }
+    pub async fn answer(mut self, accepted: bool) -> Result<(), String> {
+        self.stream.send.write_u8(accepted as u8).await.map_err(|e| e.to_string())?;
+        self.stream.finish()
+    }
+}
+
+// One stream in each direction, read and written like a TCP connection, so the async file transfers run over it unchanged
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream
+}
+impl Debug for QuicStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "QuicStream({})", self.send.id())
+    }
+}
+impl AsyncRead for QuicStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.send), cx)
+    }
+}
+impl QuicStream {
+    pub async fn open(connection: &Connection) -> Result<Self, String> {
+        let (mut send, recv) = connection.open_bi().await.map_err(|e| e.to_string())?;
+        send.write_all(&[STREAM_MARKER]).await.map_err(|e| e.to_string())?;
+
+        Ok(Self { send, recv })
+    }
+    pub async fn accept(connection: &Connection) -> Result<Self, String> {
+        let (send, mut recv) = connection.accept_bi().await.map_err(|e| e.to_string())?;
+        match recv.read_u8().await {
+            Ok(STREAM_MARKER) => Ok(Self { send, recv }),
+            Ok(_) => Err(String::from("the QUIC stream did not start with the stream marker")),
+            Err(e) => Err(e.to_string())
+        }
+    }
+
+    // Ends what this side sends. Anything already written is still delivered.
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.send.finish().map_err(|e| e.to_string())
+    }
+    // Abandons the stream in both directions, which the other side sees as an error
+    pub fn abandon(&mut self) {
+        let _ = self.send.reset(0u32.into());
+        let _ = self.recv.stop(0u32.into());
+    }
+}
+
+// A QUIC connection for a client that does not otherwise run on tokio. Its streams are read and written through blocking calls.
+pub struct BlockingQuic {
+    runtime: Arc<Runtime>,
+    connection: Connection
+}
+impl Debug for BlockingQuic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BlockingQuic({})", self.connection.remote_address())
+    }
+}
+impl BlockingQuic {
+    // Connects and registers with the session the client logged in as
+    pub fn connect(addr: SocketAddr, fingerprint: &str, session: &str) -> Result<Self, String> {
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().map_err(|e| e.to_string())?;
+        let connection = runtime.block_on(async {
+            let connection = connect(addr, fingerprint).await?;
+            register(&connection, session).await?;
+            Ok::<Connection, String>(connection)
+        })?;
+
+        Ok(Self { runtime: Arc::new(runtime), connection })
+    }
+
+    pub fn open(&self) -> Result<BlockingQuicStream, String> {
+        let stream = self.runtime.block_on(QuicStream::open(&self.connection))?;
+        Ok(BlockingQuicStream { stream, runtime: Arc::clone(&self.runtime) })
+    }
+    pub fn accept(&self) -> Result<BlockingQuicStream, String> {
+        let stream = self.runtime.block_on(QuicStream::accept(&self.connection))?;
+        Ok(BlockingQuicStream { stream, runtime: Arc::clone(&self.runtime) })
+    }
+}
+impl Drop for BlockingQuic {
+    fn drop(&mut self) {
+        self.connection.close(0u32.into(), b"done");
+    }
+}
+
+pub struct BlockingQuicStream {
+    stream: QuicStream,
+    runtime: Arc<Runtime>
+}
+impl Read for BlockingQuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.runtime.block_on(self.stream.read(buf))
+    }
+}
+impl Write for BlockingQuicStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.runtime.block_on(self.stream.write(buf))
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.runtime.block_on(self.stream.flush())
+    }
+}
+impl BlockingQuicStream {
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.stream.finish()
+    }
+    pub fn abandon(&mut self) {
+        self.stream.abandon();
+    }
+}
+
+#[test]
+fn test_quic_streams() {
+    use crate::file_io::{FileChunkIter, receive_network_file_checked, send_network_file_async};
+    use crate::progress::Progress;
+    use crate::tuning::FrameSizeTuner;
+
+    let dir = std::env::temp_dir().join(format!("hermes_quic_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|x| (x % 251) as u8).collect();
+    std::fs::write(dir.join("source"), &data).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+    let (cert, key) = generate_certificate().unwrap();
+    let fingerprint = certificate_fingerprint(&cert);
+    let endpoint = runtime.block_on(async { server_endpoint(SocketAddr::from(([127, 0, 0, 1], 0)), vec![cert], key) }).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+
+    // The server learns the session, then sends a file on a stream of its own
+    let source = dir.join("source");
+    let server = runtime.spawn(async move {
+        // The connection that named the wrong certificate never gets past its handshake
+        let mut connection = None;
+        while connection.is_none() {
+            connection = endpoint.accept().await.unwrap().await.ok();
+        }
+        let connection = connection.unwrap();
+        let registration = Registration::accept(&connection).await.unwrap();
+        let session = registration.session.clone();
+        registration.answer(true).await.unwrap();
+        let mut stream = QuicStream::open(&connection).await.unwrap();
+        let chunks = FileChunkIter::open(&source, 0, None).unwrap();
+        send_network_file_async(&mut stream, chunks, None, &mut FrameSizeTuner::default(), &mut Progress::none()).await.unwrap();
+        stream.finish().unwrap();
+        connection.closed().await;
+        session
+    });
+
+    // A certificate other than the one named is refused
+    assert!(BlockingQuic::connect(addr, &"0".repeat(64), "session").is_err());
+
+    let quic = BlockingQuic::connect(addr, &fingerprint, "session").unwrap();
+    let mut stream = quic.accept().unwrap();
+    let frames = FileChunkIter::open(&dir.join("source"), 0, None).unwrap().frame_count();
+    receive_network_file_checked(&dir.join("received"), &mut stream, frames, 0, None, None, &mut Progress::none()).unwrap();
+    assert_eq!(std::fs::read(dir.join("received")).unwrap(), data);
+
+    drop(stream);
+    drop(quic);
+    assert_eq!(runtime.block_on(server).unwrap(), "session");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,160 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::{Debug, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Issued by the server after a successful Connect. The client may store it and present it on a later Connect instead of its credentials.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    token: String,
+    expires_at: u64
+}
+impl Debug for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ResumeToken(expires {})", self.expires_at)
+    }
+}
+impl Display for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "resume token expiring at {}", self.expires_at)
+    }
+}
+impl ResumeToken {
+    pub fn new(token: String, expires_at: u64) -> Self {
+        Self {
+            token,
+            expires_at
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+    pub fn is_expired(&self) -> bool {
+        unix_now() >= self.expires_at
+    }
+}
+
+// Issued alongside the Connect ack and carried by every later request on that connection. It lapses when the connection has been idle for too long, or when the client sends Close.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionToken {
+    token: String,
+    expires_at: u64
+}
+impl Debug for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SessionToken(expires {})", self.expires_at)
+    }
+}
+impl Display for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session token expiring at {}", self.expires_at)
+    }
+}
+impl SessionToken {
+    pub fn new(token: String, expires_at: u64) -> Self {
+        Self {
+            token,
+            expires_at
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    // Only the expiry at the time of issue, since every accepted request pushes it further out
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+}
+
+// A one time permission to upload a single file, handed by a user to someone without an account
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadGrant {
+    token: String,
+    path: String,
+    max_size: u64,
+    expires_at: u64
+}
+impl Debug for UploadGrant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UploadGrant('{}', {} bytes, expires {})", &self.path, self.max_size, self.expires_at)
+    }
+}
+impl Display for UploadGrant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload grant for '{}' (up to {} bytes) expiring at {}", &self.path, self.max_size, self.expires_at)
+    }
+}
+impl UploadGrant {
+    pub fn new(token: String, path: String, max_size: u64, expires_at: u64) -> Self {
+        Self {
+            token,
+            path,
+            max_size,
+            expires_at
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+    pub fn is_expired(&self) -> bool {
+        unix_now() >= self.expires_at
+    }
+}
+
+// Read access to a single file for anyone holding the token, until it expires, handed out by a user instead of an account
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareLink {
+    token: String,
+    path: String, //Relative to the server's root
+    expires_at: u64
+}
+impl Debug for ShareLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShareLink('{}', expires {})", &self.path, self.expires_at)
+    }
+}
+impl Display for ShareLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "share link for '{}' expiring at {}", &self.path, self.expires_at)
+    }
+}
+impl ShareLink {
+    pub fn new(token: String, path: String, expires_at: u64) -> Self {
+        Self {
+            token,
+            path,
+            expires_at
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+    pub fn is_expired(&self) -> bool {
+        unix_now() >= self.expires_at
+    }
+}
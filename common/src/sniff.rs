@@ -0,0 +1,128 @@
+use crate::file_io::FileType;
+
+// How many bytes from the start of a file are enough to recognise it. Tar keeps its marker the furthest in, at 257.
+pub const SNIFF_LENGTH: usize = 512;
+
+fn other(mime: &str) -> FileType {
+    FileType::Other(mime.to_string())
+}
+
+// The kind of file an extension usually means. Extensions are compared without case.
+pub fn from_extension(extension: &str) -> Option<FileType> {
+    let result = match extension.to_ascii_lowercase().as_str() {
+        "mp4" | "m4v" | "mov" | "avi" | "wvm" | "mkv" | "webm" | "wmv" | "flv" | "mpeg" | "mpg" | "3gp" | "ogv" => FileType::Video,
+        "mp3" | "wav" | "aac" | "flac" | "aiff" | "aif" | "ogg" | "oga" | "opus" | "m4a" | "wma" | "mid" | "midi" => FileType::Audio,
+        "pdf" | "doc" | "docx" | "ppt" | "pptx" | "xls" | "xlsx" | "odt" | "ods" | "odp" | "epub" => FileType::Binary,
+        "tar" | "gz" | "tgz" | "zip" | "bz2" | "xz" | "zst" | "7z" | "rar" => FileType::Archive,
+        "txt" | "rtf" | "md" | "csv" | "tsv" | "log" | "json" | "xml" | "yaml" | "yml" | "toml" | "ini" | "cfg" | "conf"
+            | "html" | "htm" | "css" | "js" | "ts" | "rs" | "py" | "c" | "h" | "cpp" | "hpp" | "java" | "go" | "rb" | "sh" | "sql" => FileType::Text,
+        "png" => other("image/png"),
+        "jpg" | "jpeg" => other("image/jpeg"),
+        "gif" => other("image/gif"),
+        "bmp" => other("image/bmp"),
+        "webp" => other("image/webp"),
+        "svg" => other("image/svg+xml"),
+        "ico" => other("image/vnd.microsoft.icon"),
+        "tif" | "tiff" => other("image/tiff"),
+        "heic" => other("image/heic"),
+        "ttf" => other("font/ttf"),
+        "otf" => other("font/otf"),
+        "woff" => other("font/woff"),
+        "woff2" => other("font/woff2"),
+        "exe" | "dll" => other("application/vnd.microsoft.portable-executable"),
+        "wasm" => other("application/wasm"),
+        "sqlite" | "db" => other("application/vnd.sqlite3"),
+        "iso" => other("application/x-iso9660-image"),
+        _ => return None
+    };
+
+    Some(result)
+}
+
+// The kind of file its first bytes say it is. Anything without a known signature that reads as UTF-8 and has no NUL bytes is taken as text.
+pub fn from_contents(contents: &[u8]) -> Option<FileType> {
+    let at = |offset: usize, magic: &[u8]| contents.get(offset..offset + magic.len()) == Some(magic);
+    let riff = |form: &[u8]| at(0, b"RIFF") && at(8, form);
+
+    let result = if at(0, b"\x89PNG\r\n\x1a\n") {
+        other("image/png")
+    } else if at(0, b"\xff\xd8\xff") {
+        other("image/jpeg")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        other("image/gif")
+    } else if riff(b"WEBP") {
+        other("image/webp")
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        other("image/tiff")
+    } else if riff(b"WAVE") || (at(0, b"FORM") && at(8, b"AIFF")) || at(0, b"fLaC") || at(0, b"OggS") || at(0, b"ID3")
+        || at(0, b"\xff\xfb") || at(0, b"\xff\xf3") || at(0, b"\xff\xf2") || at(0, b"MThd") {
+        FileType::Audio
+    } else if riff(b"AVI ") || at(4, b"ftyp") || at(0, b"\x1a\x45\xdf\xa3") {
+        FileType::Video
+    } else if at(0, b"%PDF-") {
+        FileType::Binary
+    } else if at(0, b"PK\x03\x04") || at(0, b"\x1f\x8b") || at(257, b"ustar") || at(0, b"7z\xbc\xaf\x27\x1c") || at(0, b"Rar!\x1a\x07")
+        || at(0, b"BZh") || at(0, b"\xfd7zXZ\0") || at(0, b"\x28\xb5\x2f\xfd") {
+        FileType::Archive
+    } else if at(0, b"\x7fELF") {
+        other("application/x-elf")
+    } else if at(0, b"MZ") {
+        other("application/vnd.microsoft.portable-executable")
+    } else if at(0, b"\0asm") {
+        other("application/wasm")
+    } else if at(0, b"SQLite format 3\0") {
+        other("application/vnd.sqlite3")
+    } else if is_text(contents) {
+        FileType::Text
+    } else {
+        return None;
+    };
+
+    Some(result)
+}
+
+// A sample may stop part way through a character, which is not held against it
+fn is_text(contents: &[u8]) -> bool {
+    if contents.is_empty() || contents.contains(&0) {
+        return false;
+    }
+
+    match std::str::from_utf8(contents) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && contents.len() - e.valid_up_to() < 4
+    }
+}
+
+// Puts the two together. The contents decide, except that office documents are zip files and source code is text,
+// so a known extension wins over those two.
+pub fn detect(extension: Option<&str>, contents: &[u8]) -> Option<FileType> {
+    let by_extension = extension.and_then(from_extension);
+    match from_contents(contents) {
+        Some(FileType::Archive | FileType::Text) if by_extension.is_some() => by_extension,
+        Some(t) => Some(t),
+        None => by_extension
+    }
+}
+
+#[test]
+fn test_file_type_sniffing() {
+    // Signatures win over a misleading extension
+    assert_eq!(detect(Some("txt"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some(other("image/png")));
+    assert_eq!(detect(None, b"\0\0\0\x18ftypmp42"), Some(FileType::Video));
+    assert_eq!(detect(None, b"ID3\x04\0\0"), Some(FileType::Audio));
+    let mut tar = vec![0u8; SNIFF_LENGTH];
+    tar[257..262].copy_from_slice(b"ustar");
+    assert_eq!(detect(None, &tar), Some(FileType::Archive));
+
+    // A zip that is really a document, and text that is really code, keep what their extension says
+    assert_eq!(detect(Some("docx"), b"PK\x03\x04\x14\0"), Some(FileType::Binary));
+    assert_eq!(detect(Some("SVG"), b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), Some(other("image/svg+xml")));
+    assert_eq!(detect(Some("zip"), b"PK\x03\x04\x14\0"), Some(FileType::Archive));
+
+    // Text may be cut off mid character, but binary data without a signature is unknown
+    assert_eq!(detect(None, "caf\u{e9}".as_bytes()), Some(FileType::Text));
+    assert_eq!(detect(None, &"caf\u{e9}".as_bytes()[..4]), Some(FileType::Text));
+    assert_eq!(detect(None, b"\x01\x02\0\x03"), None);
+    assert_eq!(detect(Some("unknown"), b""), None);
+    assert_eq!(detect(Some("unknown"), b"\xff\xfe\xfd"), None);
+}
@@ -0,0 +1,87 @@
+use serde::{Serialize, Deserialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt::Display;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Socket settings applied to every stream as soon as it is created, on both ends.
+// The defaults favor the many small control messages (no Nagle delay) and leave the buffers to the OS.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    pub keepalive: Option<Duration>
+}
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+            keepalive: Some(Duration::from_secs(60))
+        }
+    }
+}
+impl Display for SocketOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let buffer = |x: Option<u32>| x.map(|b| format!("{b} bytes")).unwrap_or_else(|| String::from("system default"));
+
+        write!(
+            f,
+            "TCP_NODELAY: {}, SO_SNDBUF: {}, SO_RCVBUF: {}, Keepalive: {}",
+            self.nodelay,
+            buffer(self.send_buffer),
+            buffer(self.recv_buffer),
+            self.keepalive.map(|x| format!("{}s", x.as_secs())).unwrap_or_else(|| String::from("off"))
+        )
+    }
+}
+impl SocketOptions {
+    pub fn apply(&self, stream: &TcpStream) -> Result<(), String> {
+        self.apply_to(SockRef::from(stream))
+    }
+    #[cfg(feature = "async")]
+    pub fn apply_async(&self, stream: &tokio::net::TcpStream) -> Result<(), String> {
+        self.apply_to(SockRef::from(stream))
+    }
+
+    fn apply_to(&self, socket: SockRef<'_>) -> Result<(), String> {
+        socket.set_nodelay(self.nodelay).map_err(|e| format!("unable to set TCP_NODELAY because '{e}'"))?;
+
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size as usize).map_err(|e| format!("unable to set SO_SNDBUF because '{e}'"))?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size as usize).map_err(|e| format!("unable to set SO_RCVBUF because '{e}'"))?;
+        }
+
+        match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+            None => socket.set_keepalive(false)
+        }.map_err(|e| format!("unable to set keepalive because '{e}'"))
+    }
+}
+
+#[test]
+fn test_apply_socket_options() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let options = SocketOptions {
+        nodelay: true,
+        send_buffer: Some(64 * 1024),
+        recv_buffer: Some(64 * 1024),
+        keepalive: None
+    };
+    options.apply(&stream).unwrap();
+
+    let socket = SockRef::from(&stream);
+    assert!(socket.nodelay().unwrap());
+    assert!(!socket.keepalive().unwrap());
+    // The OS is free to round the buffer sizes, but never below what was asked for
+    assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+}
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::framing::{read_frame, write_frame_with};
+use crate::codec::Codec;
+use crate::messages::Message;
+
+// Long enough for anything a test does, short enough that a test waiting on an answer that never comes fails instead of hanging
+pub const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The bytes going one way down a pipe, and whether the end writing them is gone
+#[derive(Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    closed: bool
+}
+
+#[derive(Default)]
+struct Shared {
+    pipe: Mutex<Pipe>,
+    ready: Condvar
+}
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Pipe> {
+        self.pipe.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+// One end of a connection held in memory, which reads and writes like a TcpStream, so it can stand in wherever a SyncTransport is taken.
+// Reads wait for the other end to write, and see the end of the stream once it is dropped. Writes to an end that is gone fail as a broken pipe.
+pub struct MemoryStream {
+    incoming: Arc<Shared>,
+    outgoing: Arc<Shared>,
+    read_timeout: Option<Duration>
+}
+impl MemoryStream {
+    // A read that waits longer than this fails as timed out, as TcpStream's does. None waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+    // Hangs up both ways while the stream is still held, as a dropped connection would
+    pub fn shutdown(&self) {
+        self.outgoing.close();
+        self.incoming.close();
+    }
+    // The bytes written by the other end that have not been read yet
+    pub fn pending(&self) -> usize {
+        self.incoming.lock().buffer.len()
+    }
+}
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let deadline = self.read_timeout.map(|x| Instant::now() + x);
+        let mut pipe = self.incoming.lock();
+        while pipe.buffer.is_empty() && !pipe.closed {
+            pipe = match deadline.map(|x| x.saturating_duration_since(Instant::now())) {
+                Some(left) if left.is_zero() => return Err(std::io::Error::new(ErrorKind::TimedOut, "nothing was written to the stream in time")),
+                Some(left) => self.incoming.ready.wait_timeout(pipe, left).unwrap_or_else(|e| e.into_inner()).0,
+                None => self.incoming.ready.wait(pipe).unwrap_or_else(|e| e.into_inner())
+            };
+        }
+
+        let len = buf.len().min(pipe.buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+}
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut pipe = self.outgoing.lock();
+        if pipe.closed {
+            return Err(std::io::Error::new(ErrorKind::BrokenPipe, "the other end of the stream is gone"));
+        }
+
+        pipe.buffer.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Two ends of one connection, with what is written to either read from the other
+pub fn memory_pair() -> (MemoryStream, MemoryStream) {
+    let (there, back) = (Arc::new(Shared::default()), Arc::new(Shared::default()));
+    (
+        MemoryStream { incoming: back.clone(), outgoing: there.clone(), read_timeout: Some(DEFAULT_TEST_TIMEOUT) },
+        MemoryStream { incoming: there, outgoing: back, read_timeout: Some(DEFAULT_TEST_TIMEOUT) }
+    )
+}
+
+// Answers a request, carrying over its ID so the client can match the two up
+pub fn reply(s: &mut MemoryStream, request: &Message, response: Message) -> Result<(), String> {
+    write_frame_with(s, &response.with_request_id(request.request_id()), Codec::Json)
+}
+
+// A server that lives on a thread of its own and speaks to one client over memory, for testing clients without sockets or a data directory.
+// Each request is handed to the handler, which answers over the stream as the real server would, and says whether to keep going.
+// The server stops when the handler says so, when it fails, or when the client hangs up.
+pub struct MockServer {
+    handle: JoinHandle<(Vec<Message>, Result<(), String>)>
+}
+impl MockServer {
+    // Returns the server, and the end of the connection a client talks over
+    pub fn spawn<H>(mut handler: H) -> (Self, MemoryStream)
+        where H: FnMut(&Message, &mut MemoryStream) -> Result<bool, String> + Send + 'static {
+        let (client, mut server) = memory_pair();
+        let handle = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            loop {
+                let request = match read_frame(&mut server) {
+                    Ok(m) => m,
+                    // Hanging up is how a client is done, so it is not a failure
+                    Err(_) if server.pending() == 0 && server.incoming.lock().closed => return (seen, Ok(())),
+                    Err(e) => return (seen, Err(e))
+                };
+
+                let result = handler(&request, &mut server);
+                seen.push(request);
+                match result {
+                    Ok(true) => continue,
+                    Ok(false) => return (seen, Ok(())),
+                    Err(e) => return (seen, Err(e))
+                }
+            }
+        });
+
+        (Self { handle }, client)
+    }
+
+    // Waits for the server to stop, returning every request it was sent, or why it failed
+    pub fn join(self) -> Result<Vec<Message>, String> {
+        match self.handle.join() {
+            Ok((seen, Ok(()))) => Ok(seen),
+            Ok((_, Err(e))) => Err(e),
+            Err(_) => Err(String::from("the mock server panicked"))
+        }
+    }
+}
+
+// A directory under the system's temporary directory, unique to the test and process, that is removed when dropped
+pub struct ScratchDir {
+    path: PathBuf
+}
+impl ScratchDir {
+    pub fn new(name: &str) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("hermes_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path)?;
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[test]
+fn test_memory_pair() {
+    use crate::framing::write_frame;
+    use crate::messages::{ping_message, close_message};
+
+    let (mut a, mut b) = memory_pair();
+    write_frame(&mut a, &ping_message(1)).unwrap();
+    write_frame(&mut a, &close_message()).unwrap();
+    assert_eq!(read_frame(&mut b).unwrap(), ping_message(1));
+    assert_eq!(read_frame(&mut b).unwrap(), close_message());
+
+    // Nothing written times out rather than hanging, and a dropped end reads as the end of the stream
+    b.set_read_timeout(Some(Duration::from_millis(10)));
+    assert_eq!(b.read(&mut [0; 4]).unwrap_err().kind(), ErrorKind::TimedOut);
+    drop(a);
+    assert_eq!(b.read(&mut [0; 4]).unwrap(), 0);
+    assert_eq!(b.write(b"gone").unwrap_err().kind(), ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn test_transfer_chunking() {
+    use crate::checksum::{checksum_file, ChecksumAlgorithm};
+    use crate::compression::Compression;
+    use crate::file_io::{receive_network_file_checked, send_network_file, FileChunkIter};
+    use crate::progress::Progress;
+
+    let dir = ScratchDir::new("testing_chunking").unwrap();
+    let contents: Vec<u8> = (0..10_000u32).map(|x| (x % 251) as u8).collect();
+    std::fs::write(dir.join("source.bin"), &contents).unwrap();
+    let expected = checksum_file(&dir.join("source.bin"), ChecksumAlgorithm::Sha256).unwrap();
+
+    // Small chunks, so the file crosses many frames. The pipe holds everything written, so the whole file can be sent before it is read.
+    for compression in [None, Some(Compression::Zstd)] {
+        let (mut sender, mut receiver) = memory_pair();
+        let mut chunks = FileChunkIter::open(&dir.join("source.bin"), 0, None).unwrap();
+        chunks.set_chunk_size(333);
+        let frame_count = chunks.frame_count();
+        assert_eq!(send_network_file(&mut sender, chunks, compression, &mut Progress::none()), Ok(contents.len() as u64));
+
+        let checksum = receive_network_file_checked(&dir.join("copy.bin"), &mut receiver, frame_count, 0, Some(&expected), compression, &mut Progress::none()).unwrap();
+        assert_eq!(checksum, expected);
+        assert_eq!(std::fs::read(dir.join("copy.bin")).unwrap(), contents);
+        std::fs::remove_file(dir.join("copy.bin")).unwrap();
+    }
+}
+
+#[test]
+fn test_mock_server() {
+    use crate::framing::write_frame;
+    use crate::messages::{ping_message, pong_message, close_message, MessageType};
+
+    let (server, mut client) = MockServer::spawn(|request, s| match request.message_type() {
+        MessageType::Ping => reply(s, request, pong_message(1, None)).map(|_| true),
+        _ => Ok(false)
+    });
+
+    write_frame(&mut client, &ping_message(1).with_request_id(3)).unwrap();
+    let response = read_frame(&mut client).unwrap();
+    assert_eq!((response.message_type(), response.request_id()), (&MessageType::Pong, 3));
+
+    write_frame(&mut client, &close_message()).unwrap();
+    let seen = server.join().unwrap();
+    assert_eq!(seen.iter().map(|x| *x.message_type()).collect::<Vec<_>>(), vec![MessageType::Ping, MessageType::Close]);
+
+    // A client that hangs up is done, not failed
+    let (server, client) = MockServer::spawn(|_, _| Ok(true));
+    drop(client);
+    assert_eq!(server.join(), Ok(Vec::new()));
+}
@@ -0,0 +1,340 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::socket::SocketOptions;
+
+// A connection between a client and a server, which is either plain TCP or TCP wrapped in TLS.
+// Everything that talks on the wire (framing, file transfers) works through Read + Write, so it does not care which one it has.
+pub enum Transport {
+    Plain(TcpStream),
+    ClientTls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    ServerTls(Box<StreamOwned<ServerConnection, TcpStream>>)
+}
+impl Debug for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Plain(_) => "plain",
+            Self::ClientTls(_) => "client tls",
+            Self::ServerTls(_) => "server tls"
+        };
+
+        match self.peer_addr() {
+            Ok(a) => write!(f, "Transport({kind}, {a})"),
+            Err(_) => write!(f, "Transport({kind}, disconnected)")
+        }
+    }
+}
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(s) => s.read(buf),
+            Self::ClientTls(s) => s.read(buf),
+            Self::ServerTls(s) => s.read(buf)
+        }
+    }
+}
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(s) => s.write(buf),
+            Self::ClientTls(s) => s.write(buf),
+            Self::ServerTls(s) => s.write(buf)
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(s) => s.flush(),
+            Self::ClientTls(s) => s.flush(),
+            Self::ServerTls(s) => s.flush()
+        }
+    }
+}
+impl Transport {
+    pub fn connect_plain<A: ToSocketAddrs>(addr: A, options: &SocketOptions) -> Result<Self, String> {
+        Self::open_stream(addr, options).map(Self::Plain)
+    }
+    // Connects over TLS when a config is given, and over plain TCP otherwise
+    pub fn connect<A: ToSocketAddrs>(addr: A, server_name: &str, tls: Option<Arc<ClientConfig>>, options: &SocketOptions) -> Result<Self, String> {
+        match tls {
+            Some(config) => {
+                let stream = Self::open_stream(addr, options)?;
+                Self::connect_tls(stream, server_name, config)
+            },
+            None => Self::connect_plain(addr, options)
+        }
+    }
+    // The address that won the race is the stream's peer, so peer_addr tells callers which one it was
+    fn open_stream<A: ToSocketAddrs>(addr: A, options: &SocketOptions) -> Result<TcpStream, String> {
+        let (stream, winner) = connect_happy_eyeballs(addr, CONNECTION_ATTEMPT_DELAY, CONNECT_TIMEOUT)?;
+        tracing::info!("connected to {winner}");
+        options.apply(&stream)?;
+
+        Ok(stream)
+    }
+    pub fn connect_tls(stream: TcpStream, server_name: &str, config: Arc<ClientConfig>) -> Result<Self, String> {
+        let name = match ServerName::try_from(server_name.to_string()) {
+            Ok(n) => n,
+            Err(e) => return Err(format!("invalid server name '{}' because '{}'", server_name, e))
+        };
+
+        let conn = ClientConnection::new(config, name).map_err(|e| e.to_string())?;
+        let mut result = StreamOwned::new(conn, stream);
+
+        // Drive the handshake now, so certificate problems show up at connect time instead of on the first message
+        while result.conn.is_handshaking() {
+            result.conn.complete_io(&mut result.sock).map_err(|e| format!("tls handshake failed because '{e}'"))?;
+        }
+
+        Ok(Self::ClientTls(Box::new(result)))
+    }
+    pub fn accept_tls(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Self, String> {
+        let conn = ServerConnection::new(config).map_err(|e| e.to_string())?;
+        let mut result = StreamOwned::new(conn, stream);
+
+        while result.conn.is_handshaking() {
+            result.conn.complete_io(&mut result.sock).map_err(|e| format!("tls handshake failed because '{e}'"))?;
+        }
+
+        Ok(Self::ServerTls(Box::new(result)))
+    }
+
+    pub fn is_secure(&self) -> bool {
+        !matches!(self, Self::Plain(_))
+    }
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Self::Plain(s) => s,
+            Self::ClientTls(s) => &s.sock,
+            Self::ServerTls(s) => &s.sock
+        }
+    }
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp_stream().peer_addr()
+    }
+    // The certificates a client presented, when the server requires client certificates
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match self {
+            Self::ServerTls(s) => s.conn.peer_certificates(),
+            Self::ClientTls(s) => s.conn.peer_certificates(),
+            Self::Plain(_) => None
+        }
+    }
+}
+
+// Anything a blocking client can move frames over: a Transport, or a stream of a QUIC connection
+pub trait SyncTransport: Read + Write + Send {}
+impl<T> SyncTransport for T where T: Read + Write + Send {}
+
+// Anything the async server can speak the protocol over: plain tokio sockets, TLS streams, QUIC streams, or in-memory pipes
+#[cfg(feature = "async")]
+pub trait AsyncTransport: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync {}
+#[cfg(feature = "async")]
+impl<T> AsyncTransport for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync {}
+
+// How long to wait on one address before also trying the next, as recommended by RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Alternates between IPv6 and IPv4, starting with whichever family the resolver put first, so one broken family cannot stall the connection
+pub fn interleave_addresses(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().map(|x| x.is_ipv6()).unwrap_or(true);
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|x| x.is_ipv6() == prefer_v6);
+
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => result.extend(a.into_iter().chain(b))
+        }
+    }
+
+    result
+}
+
+// Resolves every address of a host and races connection attempts to them, starting the next one every attempt_delay (or as soon as one fails).
+// Returns the first stream that connects along with the address that it connected to.
+pub fn connect_happy_eyeballs<A: ToSocketAddrs>(addr: A, attempt_delay: Duration, timeout: Duration) -> Result<(TcpStream, SocketAddr), String> {
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs().map_err(|e| format!("unable to resolve address because '{e}'"))?.collect();
+    connect_any(&interleave_addresses(addrs), attempt_delay, timeout)
+}
+pub fn connect_any(addrs: &[SocketAddr], attempt_delay: Duration, timeout: Duration) -> Result<(TcpStream, SocketAddr), String> {
+    if addrs.is_empty() {
+        return Err(String::from("the address did not resolve to anything"));
+    }
+
+    let (tx, rx) = mpsc::channel::<(SocketAddr, std::io::Result<TcpStream>)>();
+    let start_attempt = |addr: SocketAddr| {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            // Once a winner is chosen nobody is listening, so losing streams are simply dropped
+            let _ = tx.send((addr, TcpStream::connect_timeout(&addr, timeout)));
+        });
+    };
+
+    let mut remaining = addrs.iter();
+    let mut pending = 0;
+    let mut errors = Vec::<String>::new();
+
+    if let Some(first) = remaining.next() {
+        start_attempt(*first);
+        pending += 1;
+    }
+
+    loop {
+        let result = if remaining.len() > 0 {
+            rx.recv_timeout(attempt_delay)
+        } else {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+
+        match result {
+            Ok((addr, Ok(stream))) => return Ok((stream, addr)),
+            Ok((addr, Err(e))) => {
+                pending -= 1;
+                errors.push(format!("{addr}: {e}"));
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+
+        match remaining.next() {
+            Some(next) => {
+                start_attempt(*next);
+                pending += 1;
+            },
+            None if pending == 0 => break,
+            None => ()
+        }
+    }
+
+    Err(format!("unable to connect to any address ({})", errors.join(", ")))
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("could not open certificate '{}' because '{}'", path.display(), e))?;
+
+    let certs: Result<Vec<_>, _> = rustls_pemfile::certs(&mut BufReader::new(file)).collect();
+    match certs {
+        Ok(c) if c.is_empty() => Err(format!("no certificates found in '{}'", path.display())),
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("could not parse certificate '{}' because '{}'", path.display(), e))
+    }
+}
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("could not open private key '{}' because '{}'", path.display(), e))?;
+
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(k)) => Ok(k),
+        Ok(None) => Err(format!("no private key found in '{}'", path.display())),
+        Err(e) => Err(format!("could not parse private key '{}' because '{}'", path.display(), e))
+    }
+}
+fn load_root_store(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certificates(path)? {
+        roots.add(cert).map_err(|e| e.to_string())?;
+    }
+
+    Ok(roots)
+}
+
+// When client_ca is given, clients must present a certificate signed by it
+pub fn server_tls_config(cert_path: &Path, key_path: &Path, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certificates(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_root_store(ca)?)).build().map_err(|e| e.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+
+    builder.with_single_cert(certs, key).map(Arc::new).map_err(|e| e.to_string())
+}
+// Without a CA file, the server's certificate is checked against the public web roots
+pub fn client_tls_config(ca_path: Option<&Path>, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>, String> {
+    let roots = match ca_path {
+        Some(p) => load_root_store(p)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()
+        }
+    };
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certificates(cert)?, load_private_key(key)?).map_err(|e| e.to_string())?,
+        None => builder.with_no_client_auth()
+    };
+
+    Ok(Arc::new(config))
+}
+
+#[test]
+fn test_tls_round_trip() {
+    use crate::framing::{read_frame, write_frame};
+    use crate::messages::close_message;
+    use std::net::TcpListener;
+
+    let dir = std::env::temp_dir().join(format!("hermes_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let generated = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    std::fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+
+    let server_config = server_tls_config(&dir.join("cert.pem"), &dir.join("key.pem"), None).unwrap();
+    let client_config = client_tls_config(Some(&dir.join("cert.pem")), None).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut transport = Transport::accept_tls(stream, server_config).unwrap();
+        let message = read_frame(&mut transport).unwrap();
+        write_frame(&mut transport, &message).unwrap();
+    });
+
+    let mut transport = Transport::connect_tls(TcpStream::connect(addr).unwrap(), "localhost", client_config).unwrap();
+    assert!(transport.is_secure());
+    write_frame(&mut transport, &close_message()).unwrap();
+    assert_eq!(read_frame(&mut transport).unwrap(), close_message());
+
+    server.join().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn test_connect_happy_eyeballs() {
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+    let v4 = |p| SocketAddr::from((Ipv4Addr::LOCALHOST, p));
+    let v6 = |p| SocketAddr::from((Ipv6Addr::LOCALHOST, p));
+    assert_eq!(interleave_addresses(vec![v6(1), v6(2), v4(3), v4(4)]), vec![v6(1), v4(3), v6(2), v4(4)]);
+    assert_eq!(interleave_addresses(vec![v4(1), v4(2), v6(3)]), vec![v4(1), v6(3), v4(2)]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // A dead address ahead of the live one must not stop the connection
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (_, addr) = connect_any(&[dead, v4(port)], Duration::from_millis(50), CONNECT_TIMEOUT).unwrap();
+    assert_eq!(addr, v4(port));
+    let transport = Transport::connect_plain(("localhost", port), &SocketOptions::default()).unwrap();
+    assert_eq!(transport.peer_addr().unwrap(), v4(port));
+
+    assert!(connect_any(&[dead], Duration::from_millis(50), CONNECT_TIMEOUT).is_err());
+    assert!(connect_any(&[], Duration::from_millis(50), CONNECT_TIMEOUT).is_err());
+}
@@ -0,0 +1,188 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::{Debug, Display};
+use std::time::Duration;
+
+pub const MIN_TUNED_FRAME_SIZE: u32 = 4 * 1024;
+pub const DEFAULT_TUNED_FRAME_SIZE: u32 = 16 * 1024;
+pub const MAX_TUNED_FRAME_SIZE: u32 = 1024 * 1024;
+
+// Nothing is adjusted until a window has seen this many frames and this much time, so short transfers keep the initial size
+const SAMPLE_FRAMES: u32 = 8;
+const SAMPLE_TIME: Duration = Duration::from_millis(50);
+
+// Growing is kept as long as throughput does not fall below this share of the best seen so far
+const GROW_THRESHOLD: f64 = 0.95;
+// Throughput falling below this share of the best seen so far means the frames have grown too large
+const SHRINK_THRESHOLD: f64 = 0.8;
+// An RTT this many times the smallest seen means queues are building up
+const RTT_INFLATION: u32 = 2;
+
+// The range the server allows frame sizes to move in. It is advertised in the Connect ack so both ends tune within the same bounds.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct FrameSizeBounds {
+    pub min: u32,
+    pub max: u32,
+    pub initial: u32
+}
+impl Default for FrameSizeBounds {
+    fn default() -> Self {
+        Self::new(MIN_TUNED_FRAME_SIZE, MAX_TUNED_FRAME_SIZE, DEFAULT_TUNED_FRAME_SIZE)
+    }
+}
+impl Display for FrameSizeBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..{} bytes (starting at {})", self.min, self.max, self.initial)
+    }
+}
+impl FrameSizeBounds {
+    // The initial size is clamped into the range, and a reversed range is straightened out
+    pub fn new(min: u32, max: u32, initial: u32) -> Self {
+        let min = min.max(1);
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+
+        Self {
+            min,
+            max,
+            initial: initial.clamp(min, max)
+        }
+    }
+    // A single size that never changes
+    pub fn fixed(size: u32) -> Self {
+        Self::new(size, size, size)
+    }
+
+    // Both ends must agree, so the overlap of the two ranges is used
+    pub fn intersect(&self, other: &FrameSizeBounds) -> Option<FrameSizeBounds> {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        if min > max {
+            None
+        } else {
+            Some(Self::new(min, max, self.initial))
+        }
+    }
+}
+
+// Grows or shrinks the frame size of one transfer from the throughput and RTT it achieves.
+// Sizes double while throughput keeps up, and halve when throughput drops or the RTT inflates.
+pub struct FrameSizeTuner {
+    bounds: FrameSizeBounds,
+    current: u32,
+    best_rate: f64,
+    min_rtt: Option<Duration>,
+    last_rtt: Option<Duration>,
+    window_bytes: u64,
+    window_time: Duration,
+    window_frames: u32,
+    chosen: Vec<u32>
+}
+impl Debug for FrameSizeTuner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FrameSizeTuner(current: {}, bounds: {}, chosen: {:?})", self.current, self.bounds, &self.chosen)
+    }
+}
+impl Default for FrameSizeTuner {
+    fn default() -> Self {
+        Self::new(FrameSizeBounds::default())
+    }
+}
+impl FrameSizeTuner {
+    pub fn new(bounds: FrameSizeBounds) -> Self {
+        Self {
+            bounds,
+            current: bounds.initial,
+            best_rate: 0.0,
+            min_rtt: None,
+            last_rtt: None,
+            window_bytes: 0,
+            window_time: Duration::ZERO,
+            window_frames: 0,
+            chosen: vec![bounds.initial]
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+    pub fn bounds(&self) -> FrameSizeBounds {
+        self.bounds
+    }
+    // Every size used during the transfer, in order, starting with the initial size
+    pub fn chosen_sizes(&self) -> &Vec<u32> {
+        &self.chosen
+    }
+    pub fn into_chosen_sizes(self) -> Vec<u32> {
+        self.chosen
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |x| x.min(rtt)));
+        self.last_rtt = Some(rtt);
+    }
+    // Called after every frame with how many bytes moved and how long it took
+    pub fn record_frame(&mut self, bytes: usize, elapsed: Duration) {
+        self.window_bytes += bytes as u64;
+        self.window_time += elapsed;
+        self.window_frames += 1;
+
+        if self.window_frames >= SAMPLE_FRAMES && self.window_time >= SAMPLE_TIME {
+            self.evaluate();
+        }
+    }
+
+    fn evaluate(&mut self) {
+        let rate = self.window_bytes as f64 / self.window_time.as_secs_f64();
+        self.window_bytes = 0;
+        self.window_time = Duration::ZERO;
+        self.window_frames = 0;
+
+        let inflated = match (self.min_rtt, self.last_rtt) {
+            (Some(min), Some(last)) => last > min * RTT_INFLATION,
+            _ => false
+        };
+
+        let next = if inflated || rate < self.best_rate * SHRINK_THRESHOLD {
+            // Start measuring again from the smaller size, since the old best may no longer be reachable
+            self.best_rate = rate;
+            self.current / 2
+        } else if rate >= self.best_rate * GROW_THRESHOLD {
+            self.best_rate = rate;
+            self.current.saturating_mul(2)
+        } else {
+            self.current
+        };
+
+        let next = next.clamp(self.bounds.min, self.bounds.max);
+        if next != self.current {
+            self.current = next;
+            self.chosen.push(next);
+        }
+    }
+}
+
+#[test]
+fn test_frame_size_tuner() {
+    let mut tuner = FrameSizeTuner::new(FrameSizeBounds::new(1024, 8192, 2048));
+    let window = SAMPLE_TIME / SAMPLE_FRAMES;
+
+    // Throughput that scales with the frame size keeps growing it, up to the bound
+    for _ in 0..5 {
+        let size = tuner.current() as usize;
+        for _ in 0..SAMPLE_FRAMES {
+            tuner.record_frame(size, window);
+        }
+    }
+    assert_eq!(tuner.current(), 8192);
+    assert_eq!(tuner.chosen_sizes(), &vec![2048, 4096, 8192]);
+
+    // An inflated RTT shrinks it again
+    tuner.record_rtt(Duration::from_millis(10));
+    tuner.record_rtt(Duration::from_millis(50));
+    for _ in 0..SAMPLE_FRAMES {
+        tuner.record_frame(8192, window);
+    }
+    assert_eq!(tuner.current(), 4096);
+
+    assert_eq!(FrameSizeBounds::new(4096, 1024, 1).initial, 1024);
+    assert_eq!(FrameSizeBounds::fixed(10).intersect(&FrameSizeBounds::new(20, 30, 20)), None);
+}
@@ -0,0 +1,110 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// A block of addresses in CIDR notation, such as '10.0.0.0/8' or 'fd00::/8'. A bare address is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None)
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| format!("'{s}' is not an address or a CIDR block"))?;
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|x| *x <= bits).ok_or_else(|| format!("'{s}' has a prefix longer than {bits} bits"))?,
+            None => bits
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+impl Cidr {
+    // IPv4 addresses mapped into IPv6, as a dual-stack listener reports them, match IPv4 blocks
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4
+        };
+
+        match (self.network, address) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => masked(u32::from(n) as u128, 32, self.prefix) == masked(u32::from(a) as u128, 32, self.prefix),
+            (IpAddr::V6(n), IpAddr::V6(a)) => masked(u128::from(n), 128, self.prefix) == masked(u128::from(a), 128, self.prefix),
+            _ => false
+        }
+    }
+}
+fn masked(value: u128, bits: u8, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => value >> (bits - p)
+    }
+}
+
+// Which addresses may connect at all. A denied address is always refused. When the allowlist is not empty, only addresses on it are let in.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+    #[serde(default)]
+    pub max_per_address: Option<usize> //Connections from one address past this many are refused. None leaves them unlimited.
+}
+impl AccessConfig {
+    pub fn permits(&self, address: IpAddr) -> bool {
+        !self.deny.iter().any(|x| x.contains(address)) && (self.allow.is_empty() || self.allow.iter().any(|x| x.contains(address)))
+    }
+}
+
+#[test]
+fn test_access_lists() {
+    let parse = |x: &str| x.parse::<Cidr>();
+    assert_eq!(parse("10.0.0.0/8").map(|x| x.to_string()), Ok(String::from("10.0.0.0/8")));
+    assert_eq!(parse("192.168.1.7").map(|x| x.to_string()), Ok(String::from("192.168.1.7/32")));
+    assert!(parse("10.0.0.0/33").is_err());
+    assert!(parse("fd00::/129").is_err());
+    assert!(parse("example.com").is_err());
+
+    let access: AccessConfig = serde_json::from_str(r#"{ "allow": ["10.0.0.0/8", "fd00::/8"], "deny": ["10.0.5.0/24"] }"#).unwrap();
+    let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+    assert!(access.permits(ip("10.1.2.3")));
+    assert!(access.permits(ip("::ffff:10.1.2.3")));
+    assert!(access.permits(ip("fd12::1")));
+    assert!(!access.permits(ip("10.0.5.9")));
+    assert!(!access.permits(ip("192.168.1.1")));
+    assert!(serde_json::from_str::<AccessConfig>(r#"{ "deny": ["10.0.0.0/40"] }"#).is_err());
+
+    // With no lists, everyone is let in
+    assert!(AccessConfig::default().permits(ip("203.0.113.1")));
+    assert!(parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.1")));
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use hermes_common::messages::ConnectionActivity;
+use hermes_common::session::unix_now;
+
+// A connection that sends nothing for this long is closed. Clients that negotiated keepalive are told, so they can ping in time.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Every open connection, and when it last sent a request, for the administrators' view. Entries only live as long as their connection.
+#[derive(Default)]
+pub struct ConnectionTracker {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, ConnectionActivity>>
+}
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&self, peer: &str) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = unix_now();
+        self.lock().insert(id, ConnectionActivity { peer: peer.to_string(), username: None, connected_at: now, last_activity: now, requests: 0 });
+
+        id
+    }
+    // Called with every request once it has been read, whether or not it is then refused
+    pub fn touch(&self, id: u64) {
+        if let Some(x) = self.lock().get_mut(&id) {
+            x.last_activity = unix_now();
+            x.requests += 1;
+        }
+    }
+    pub fn identify(&self, id: u64, username: Option<&str>) {
+        if let Some(x) = self.lock().get_mut(&id) {
+            x.username = username.map(|x| x.to_string());
+        }
+    }
+    pub fn close(&self, id: u64) {
+        self.lock().remove(&id);
+    }
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+    pub fn from_address(&self, peer: &str) -> usize {
+        self.lock().values().filter(|x| x.peer == peer).count()
+    }
+    // Every connection ever opened, including those since closed
+    pub fn opened(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    // Oldest connections first
+    pub fn snapshot(&self) -> Vec<ConnectionActivity> {
+        let mut result: Vec<ConnectionActivity> = self.lock().values().cloned().collect();
+        result.sort_by(|a, b| a.connected_at.cmp(&b.connected_at).then_with(|| a.peer.cmp(&b.peer)));
+        result
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, ConnectionActivity>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[test]
+fn test_connection_tracking() {
+    let tracker = ConnectionTracker::new();
+    let first = tracker.open("10.0.0.1");
+    let second = tracker.open("10.0.0.2");
+    assert_ne!(first, second);
+
+    tracker.identify(first, Some("alice"));
+    tracker.touch(first);
+    tracker.touch(first);
+    let snapshot = tracker.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    let alice = snapshot.iter().find(|x| x.peer == "10.0.0.1").unwrap();
+    assert_eq!((alice.username.as_deref(), alice.requests), (Some("alice"), 2));
+    assert!(alice.last_activity >= alice.connected_at);
+
+    // Closing forgets the connection, and touching it afterwards does nothing
+    tracker.close(first);
+    tracker.touch(first);
+    assert_eq!(tracker.snapshot().into_iter().map(|x| x.peer).collect::<Vec<_>>(), vec![String::from("10.0.0.2")]);
+}
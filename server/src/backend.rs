@@ -0,0 +1,235 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::staging::STAGING_SUFFIX;
+
+fn default_region() -> String {
+    String::from("us-east-1")
+}
+
+// Where the shared files are kept for good. The local backend keeps them in the data directory, which is where files are always served from.
+// Any other backend holds a copy of every file there, written as requests change them, and files missing from the data directory are fetched back from it at startup.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileBackendConfig {
+    #[default]
+    Local,
+    S3(S3Config)
+}
+
+// An S3 bucket, or anything that speaks the same API, such as MinIO. Buckets are addressed by path, as 'endpoint/bucket/key'.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String, //Such as 'https://s3.eu-west-1.amazonaws.com', or 'http://127.0.0.1:9000' for MinIO
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    #[serde(default)]
+    pub access_key: String, //HERMES_S3_ACCESS_KEY and HERMES_S3_SECRET_KEY keep these out of the file
+    #[serde(default)]
+    pub secret_key: String,
+    #[serde(default)]
+    pub prefix: String //Put in front of every key, so one bucket can hold several servers
+}
+
+// Files are named by their path beneath the data directory, with '/' between the parts
+pub trait FileBackend: Send + Sync {
+    fn describe(&self) -> String;
+    // Whether the files are only kept in the data directory, so requests leave nothing else to update
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    // Stores a whole file under its key, replacing what was there
+    fn store(&self, key: &str, source: &Path) -> Result<(), String>;
+    // Writes the file kept under the key into destination. False when there is none.
+    fn fetch(&self, key: &str, destination: &Path) -> Result<bool, String>;
+    // Removing a key that is not there is not an error
+    fn remove(&self, key: &str) -> Result<(), String>;
+    // Every key beginning with the prefix
+    fn list(&self, prefix: &str) -> Result<Vec<String>, String>;
+}
+
+// The data directory itself, with nothing kept anywhere else
+pub struct LocalBackend {
+    base: PathBuf
+}
+impl LocalBackend {
+    pub fn new(base: &Path) -> Self {
+        Self {
+            base: base.to_path_buf()
+        }
+    }
+}
+impl FileBackend for LocalBackend {
+    fn describe(&self) -> String {
+        format!("the data directory '{}'", self.base.display())
+    }
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn store(&self, key: &str, source: &Path) -> Result<(), String> {
+        let target = self.base.join(key);
+        if target == source {
+            return Ok(());
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        std::fs::copy(source, target).map(|_| ()).map_err(|e| e.to_string())
+    }
+    fn fetch(&self, key: &str, destination: &Path) -> Result<bool, String> {
+        let source = self.base.join(key);
+        match (source.is_file(), source == destination) {
+            (false, _) => Ok(false),
+            (true, true) => Ok(true),
+            (true, false) => std::fs::copy(source, destination).map(|_| true).map_err(|e| e.to_string())
+        }
+    }
+    // A key naming a folder takes the folder with it, as S3 has no folders to leave behind
+    fn remove(&self, key: &str) -> Result<(), String> {
+        let path = self.base.join(key);
+        let removed = match path.is_dir() {
+            true => std::fs::remove_dir_all(path),
+            false => std::fs::remove_file(path)
+        };
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(())
+        }
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        for file in files_beneath(&self.base)? {
+            if let Some(k) = object_key(&self.base, &file).filter(|x| x.starts_with(prefix)) {
+                keys.push(k);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+pub fn open_backend(config: &FileBackendConfig, root: &Path) -> Result<Arc<dyn FileBackend>, String> {
+    match config {
+        FileBackendConfig::Local => Ok(Arc::new(LocalBackend::new(root))),
+        #[cfg(feature = "s3")]
+        FileBackendConfig::S3(c) => Ok(Arc::new(crate::s3::S3Backend::new(c.clone())?)),
+        #[cfg(not(feature = "s3"))]
+        FileBackendConfig::S3(_) => Err(String::from("this server was built without S3 support, rebuild it with '--features s3'"))
+    }
+}
+
+// The key a path beneath the data directory is kept under
+pub fn object_key(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<String> = relative.iter().map(|x| x.to_string_lossy().to_string()).collect();
+    Some(parts.join("/")).filter(|x| !x.is_empty())
+}
+
+// Every file beneath a folder, leaving out uploads that are still arriving
+fn files_beneath(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.to_string())
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if !entry.file_name().to_string_lossy().ends_with(STAGING_SUFFIX) {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// Brings the backend in step with a path a request changed. A file is stored, a folder has every file beneath it stored, and whatever is gone is removed along with everything beneath it.
+// Returns how many keys were stored and removed.
+pub fn mirror(backend: &dyn FileBackend, root: &Path, path: &Path) -> Result<(usize, usize), String> {
+    let key = match object_key(root, path) {
+        Some(k) => k,
+        None => return Ok((0, 0))
+    };
+
+    if path.is_file() {
+        backend.store(&key, path)?;
+        return Ok((1, 0));
+    }
+    if path.is_dir() {
+        let files = files_beneath(path)?;
+        for file in &files {
+            if let Some(k) = object_key(root, file) {
+                backend.store(&k, file)?;
+            }
+        }
+        return Ok((files.len(), 0));
+    }
+
+    let mut removed = backend.list(&format!("{key}/"))?;
+    removed.push(key);
+    for k in &removed {
+        backend.remove(k)?;
+    }
+    Ok((0, removed.len()))
+}
+
+// Fetches the files the database knows of that are missing from the data directory, such as after it was moved to a new disk. Returns how many came back.
+pub fn restore_missing(backend: &dyn FileBackend, root: &Path, paths: &[PathBuf]) -> Result<usize, String> {
+    let mut restored = 0;
+    for path in paths.iter().filter(|x| !x.exists()) {
+        let key = match object_key(root, path) {
+            Some(k) => k,
+            None => continue
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        if backend.fetch(&key, path)? {
+            restored += 1;
+        }
+    }
+
+    Ok(restored)
+}
+
+#[test]
+fn test_local_backend() {
+    let dir = std::env::temp_dir().join(format!("hermes_backend_{}", std::process::id()));
+    let (root, kept) = (dir.join("data"), dir.join("kept"));
+    std::fs::create_dir_all(root.join("docs/nested")).unwrap();
+    std::fs::write(root.join("docs/a.txt"), "alpha").unwrap();
+    std::fs::write(root.join("docs/nested/b.txt"), "bravo").unwrap();
+    std::fs::write(root.join(format!("docs/c.txt{STAGING_SUFFIX}")), "partial").unwrap();
+
+    // A second folder stands in for a remote backend
+    let backend = LocalBackend::new(&kept);
+    assert_eq!(object_key(&root, &root.join("docs/a.txt")), Some(String::from("docs/a.txt")));
+    assert_eq!(object_key(&root, &root), None);
+    assert_eq!(mirror(&backend, &root, &root.join("docs")), Ok((2, 0)));
+    let mut keys = backend.list("docs/").unwrap();
+    keys.sort();
+    assert_eq!(keys, vec![String::from("docs/a.txt"), String::from("docs/nested/b.txt")]);
+
+    // A folder that is gone takes everything beneath it, and a file that is gone is fetched back
+    std::fs::remove_dir_all(root.join("docs/nested")).unwrap();
+    assert_eq!(mirror(&backend, &root, &root.join("docs/nested")), Ok((0, 2)));
+    std::fs::remove_file(root.join("docs/a.txt")).unwrap();
+    assert_eq!(restore_missing(&backend, &root, &[root.join("docs/a.txt"), root.join("docs/gone.txt")]), Ok(1));
+    assert_eq!(std::fs::read_to_string(root.join("docs/a.txt")).unwrap(), "alpha");
+    assert_eq!(backend.list("").unwrap(), vec![String::from("docs/a.txt")]);
+
+    // The data directory is its own backend, so storing a file there is nothing at all
+    let own = LocalBackend::new(&root);
+    assert!(own.is_local());
+    assert_eq!(own.store("docs/a.txt", &root.join("docs/a.txt")), Ok(()));
+    assert_eq!(std::fs::read_to_string(root.join("docs/a.txt")).unwrap(), "alpha");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,394 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::io_loc::{backup_index_path, StoragePaths};
+use crate::migration::{state_file_names, rewrite_root, holds_state, MigrationReport};
+use crate::proxy::{Upstream, UpstreamConfig};
+use crate::staging::STAGING_SUFFIX;
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, checksum_file, verify_file_checksum};
+use hermes_common::session::unix_now;
+use hermes_common::socket::SocketOptions;
+use hermes_common::tuning::FrameSizeBounds;
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+const BLOBS_DIR: &str = "blobs";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+fn default_remote_dir() -> String {
+    String::from("hermes-backup")
+}
+fn default_interval() -> u64 {
+    24 * 60 * 60
+}
+
+// Where and how often a server backs itself up. The peer is any Hermes server, logged into like a proxy's upstream.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BackupConfig {
+    #[serde(flatten)]
+    pub peer: UpstreamConfig,
+    #[serde(default = "default_remote_dir")]
+    pub remote_dir: String, //Relative to where the peer account starts
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64
+}
+impl BackupConfig {
+    pub fn open(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string())
+        };
+
+        serde_json::from_str(&contents).map(Some).map_err(|e| e.to_string())
+    }
+
+    fn blob_path(&self, hash: &str) -> String {
+        format!("{}/{BLOBS_DIR}/{hash}", &self.remote_dir)
+    }
+    fn snapshot_path(&self, name: &str) -> String {
+        format!("{}/{SNAPSHOTS_DIR}/{name}", &self.remote_dir)
+    }
+}
+
+// One file under the root, named by its path relative to the root. Its contents are stored on the peer under their hash.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<u64>,
+    pub sha256: String
+}
+
+// Everything needed to rebuild a server at one point in time, apart from the blobs it points to
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: u64,
+    pub root: PathBuf, //The data root when the snapshot was taken
+    pub state: BTreeMap<String, String>, //State file name to its contents
+    pub files: Vec<SnapshotEntry>
+}
+impl Snapshot {
+    pub fn name(&self) -> String {
+        format!("{}.json", self.created_at)
+    }
+
+    // The blobs this snapshot needs, each once. Empty files are rebuilt without one.
+    pub fn blobs(&self) -> Vec<&SnapshotEntry> {
+        let mut seen = HashSet::new();
+        self.files.iter().filter(|x| x.size > 0 && seen.insert(x.sha256.as_str())).collect()
+    }
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct BackupReport {
+    pub snapshot: String,
+    pub files: usize,
+    pub uploaded: usize,
+    pub uploaded_bytes: u64,
+    pub skipped: Vec<String> //Files that changed or vanished while they were being sent, left out of this snapshot
+}
+
+#[derive(Default, PartialEq, Debug)]
+pub struct VerifyReport {
+    pub snapshot: String,
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub corrupt: Vec<String>
+}
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs())
+}
+
+fn collect_files(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf, std::fs::Metadata)>) -> Result<(), String> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir).map_err(|e| e.to_string())?.flatten().collect();
+    entries.sort_by_key(|x| x.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if prefix.is_empty() { name.clone() } else { format!("{prefix}/{name}") };
+        let metadata = entry.path().symlink_metadata().map_err(|e| e.to_string())?;
+        if metadata.is_dir() {
+            collect_files(&entry.path(), &path, out)?;
+        } else if metadata.is_file() && !name.ends_with(STAGING_SUFFIX) {
+            out.push((path, entry.path(), metadata));
+        }
+    }
+
+    Ok(())
+}
+
+// Reads the state files and hashes every file under the root. A file whose size and modification time match the last backup keeps
+// its stored hash, so only files that changed are read again.
+pub fn take_snapshot(paths: &StoragePaths, previous: &HashMap<String, SnapshotEntry>) -> Result<Snapshot, String> {
+    let root = paths.root.as_path();
+    let mut state = BTreeMap::new();
+    for name in state_file_names() {
+        let path = paths.state_file(&name);
+        if path.is_file() {
+            let contents = std::fs::read_to_string(&path).map_err(|e| format!("unable to read '{}' because '{e}'", path.display()))?;
+            state.insert(name.to_string_lossy().to_string(), contents);
+        }
+    }
+
+    let mut found = Vec::new();
+    if root.is_dir() {
+        collect_files(root, "", &mut found)?;
+    }
+
+    let mut files = Vec::with_capacity(found.len());
+    for (path, full, metadata) in found {
+        let size = metadata.len();
+        let modified = modified_secs(&metadata);
+        let sha256 = match previous.get(&path) {
+            Some(p) if p.size == size && p.modified == modified && modified.is_some() => p.sha256.clone(),
+            _ => checksum_file(&full, ChecksumAlgorithm::Sha256).map_err(|e| format!("unable to hash '{}' because '{e}'", full.display()))?.value().to_string()
+        };
+
+        files.push(SnapshotEntry { path, size, modified, sha256 });
+    }
+
+    Ok(
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            created_at: unix_now(),
+            root: root.to_path_buf(),
+            state,
+            files
+        }
+    )
+}
+
+// Kept in the host directory given, beside the state files
+fn index_path(host: &Path) -> PathBuf {
+    host.join(backup_index_path().file_name().unwrap_or_default())
+}
+pub fn load_index(path: &Path) -> HashMap<String, SnapshotEntry> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|x| serde_json::from_str::<Vec<SnapshotEntry>>(&x).ok())
+        .map(|x| x.into_iter().map(|e| (e.path.clone(), e)).collect())
+        .unwrap_or_default()
+}
+fn save_index(path: &Path, snapshot: &Snapshot) -> Result<(), String> {
+    let contents = serde_json::to_string(&snapshot.files).map_err(|e| e.to_string())?;
+    std::fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+// The newest snapshot on the peer, unless one is asked for by name
+async fn resolve_snapshot(peer: &mut Upstream, config: &BackupConfig, name: Option<&str>) -> Result<String, String> {
+    if let Some(n) = name {
+        return Ok(if n.ends_with(".json") { n.to_string() } else { format!("{n}.json") });
+    }
+
+    let listing = peer.list(&format!("{}/{SNAPSHOTS_DIR}", &config.remote_dir)).await?;
+    listing.contents()
+        .iter()
+        .filter_map(|x| x.as_file_ref())
+        .filter_map(|x| x.name().strip_suffix(".json").and_then(|s| s.parse::<u64>().ok()))
+        .max()
+        .map(|x| format!("{x}.json"))
+        .ok_or_else(|| String::from("the peer holds no snapshots"))
+}
+
+async fn fetch_snapshot(peer: &mut Upstream, config: &BackupConfig, name: &str, scratch: &Path) -> Result<Snapshot, String> {
+    let local = scratch.join(format!("backup-{name}"));
+    peer.fetch(&config.snapshot_path(name), &local).await.map_err(|e| format!("unable to fetch snapshot '{name}' because {e}"))?;
+    let contents = std::fs::read_to_string(&local).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&local);
+
+    let snapshot: Snapshot = serde_json::from_str(&contents?).map_err(|e| format!("snapshot '{name}' is unreadable because '{e}'"))?;
+    if snapshot.version > SNAPSHOT_VERSION {
+        return Err(format!("snapshot '{name}' is version {}, but this server only reads up to version {}", snapshot.version, SNAPSHOT_VERSION));
+    }
+
+    Ok(snapshot)
+}
+
+// Takes a snapshot and sends it to the peer, along with every blob the peer does not already hold
+pub async fn run_backup(config: &BackupConfig, paths: &StoragePaths, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<BackupReport, String> {
+    let (host, root) = (paths.host.as_path(), paths.root.as_path());
+    let index = index_path(host);
+    let mut snapshot = take_snapshot(paths, &load_index(&index))?;
+
+    let mut peer = Upstream::open(&config.peer, options, bounds).await?;
+    for dir in [config.remote_dir.clone(), format!("{}/{BLOBS_DIR}", &config.remote_dir), format!("{}/{SNAPSHOTS_DIR}", &config.remote_dir)] {
+        peer.make_dir(&dir).await.map_err(|e| format!("unable to create '{dir}' on the peer because {e}"))?;
+    }
+
+    let stored: HashSet<String> = peer.list(&format!("{}/{BLOBS_DIR}", &config.remote_dir)).await?
+        .contents()
+        .iter()
+        .filter_map(|x| x.as_file_ref().map(|f| f.name().to_string()))
+        .collect();
+
+    let mut report = BackupReport::default();
+    let mut failed = HashSet::new();
+    for entry in snapshot.blobs() {
+        if stored.contains(&entry.sha256) {
+            continue;
+        }
+
+        let expected = Checksum::new(ChecksumAlgorithm::Sha256, entry.sha256.clone());
+        match peer.upload(&config.blob_path(&entry.sha256), &root.join(&entry.path), Some(expected)).await {
+            Ok(_) => {
+                report.uploaded += 1;
+                report.uploaded_bytes += entry.size;
+            },
+            Err(_) => { failed.insert(entry.sha256.clone()); }
+        }
+    }
+
+    // A snapshot never points at a blob the peer does not have
+    let (kept, skipped): (Vec<_>, Vec<_>) = snapshot.files.drain(..).partition(|x| !failed.contains(&x.sha256));
+    snapshot.files = kept;
+    report.skipped = skipped.into_iter().map(|x| x.path).collect();
+    report.files = snapshot.files.len();
+    report.snapshot = snapshot.name();
+
+    let local = host.join(format!("backup-{}", &report.snapshot));
+    std::fs::write(&local, serde_json::to_vec(&snapshot).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    let sent = peer.upload(&config.snapshot_path(&report.snapshot), &local, None).await;
+    let _ = std::fs::remove_file(&local);
+    sent.map_err(|e| format!("unable to send snapshot '{}' because {e}", &report.snapshot))?;
+    peer.close().await;
+
+    save_index(&index, &snapshot)?;
+    Ok(report)
+}
+
+// Checks that every blob a snapshot points to is on the peer, and that the peer's hash of it still matches
+pub async fn verify_backup(config: &BackupConfig, name: Option<&str>, scratch: &Path, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<VerifyReport, String> {
+    let mut peer = Upstream::open(&config.peer, options, bounds).await?;
+    let name = resolve_snapshot(&mut peer, config, name).await?;
+    let snapshot = fetch_snapshot(&mut peer, config, &name, scratch).await?;
+
+    let mut report = VerifyReport { snapshot: name, ..Default::default() };
+    for entry in snapshot.blobs() {
+        report.checked += 1;
+        match peer.checksum(&config.blob_path(&entry.sha256)).await? {
+            None => report.missing.push(entry.path.clone()),
+            Some(c) if c.algorithm() != ChecksumAlgorithm::Sha256 || c.value() != entry.sha256 => report.corrupt.push(entry.path.clone()),
+            Some(_) => { }
+        }
+    }
+
+    peer.close().await;
+    Ok(report)
+}
+
+// Rebuilds the state files and the root from a snapshot on the peer. Like an import, existing state is only replaced when forced.
+pub async fn restore_backup(config: &BackupConfig, name: Option<&str>, paths: &StoragePaths, force: bool, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<MigrationReport, String> {
+    let (host, root) = (paths.host.as_path(), paths.root.as_path());
+    if holds_state(paths) && !force {
+        return Err(format!("'{}' already holds server state, pass --force to replace it", host.display()));
+    }
+
+    for dir in [host, root, paths.database.as_path()] {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut peer = Upstream::open(&config.peer, options, bounds).await?;
+    let name = resolve_snapshot(&mut peer, config, name).await?;
+    let snapshot = fetch_snapshot(&mut peer, config, &name, host).await?;
+
+    let mut report = MigrationReport::default();
+    let known = state_file_names();
+    for (file, contents) in &snapshot.state {
+        if !known.iter().any(|x| x.to_string_lossy() == file.as_str()) {
+            return Err(format!("unexpected state file '{file}' in snapshot '{name}'"));
+        }
+
+        let mut contents = contents.clone();
+        if !contents.trim().is_empty() {
+            let mut value: serde_json::Value = serde_json::from_str(&contents).map_err(|e| format!("'{file}' is unreadable because '{e}'"))?;
+            rewrite_root(&mut value, &snapshot.root, root);
+            contents = serde_json::to_string(&value).map_err(|e| e.to_string())?;
+        }
+
+        std::fs::write(paths.state_file(OsStr::new(file)), contents).map_err(|e| e.to_string())?;
+        report.state_files += 1;
+    }
+
+    for entry in &snapshot.files {
+        // Nothing in a snapshot may climb out of the root
+        let relative = Path::new(&entry.path);
+        if relative.as_os_str().is_empty() || !relative.components().all(|x| matches!(x, Component::Normal(_))) {
+            return Err(format!("unexpected path '{}' in snapshot '{name}'", &entry.path));
+        }
+
+        let target = root.join(relative);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        // Linked duplicates share their contents, so each file is written anew rather than over what is there
+        let _ = std::fs::remove_file(&target);
+        if entry.size == 0 {
+            std::fs::write(&target, b"").map_err(|e| e.to_string())?;
+        } else {
+            peer.fetch(&config.blob_path(&entry.sha256), &target).await.map_err(|e| format!("unable to restore '{}' because {e}", &entry.path))?;
+            verify_file_checksum(&target, &Checksum::new(ChecksumAlgorithm::Sha256, entry.sha256.clone())).map_err(|e| format!("'{}' does not match its snapshot because '{e}'", &entry.path))?;
+        }
+
+        report.blobs += 1;
+        report.blob_bytes += entry.size;
+    }
+
+    peer.close().await;
+
+    // Restored files carry new modification times, so any index left from before would only be missed
+    let index = index_path(host);
+    let _ = std::fs::remove_file(index);
+    Ok(report)
+}
+
+#[test]
+fn test_snapshot_reuses_hashes() {
+    let base = std::env::temp_dir().join(format!("hermes_backup_{}", std::process::id()));
+    let (host, root) = (base.clone(), base.join("data"));
+    let storage = StoragePaths::new(host.clone(), None, None);
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+
+    std::fs::write(root.join("docs").join("a.txt"), b"hello").unwrap();
+    std::fs::write(root.join("docs").join("b.txt"), b"hello").unwrap();
+    std::fs::write(root.join("empty.txt"), b"").unwrap();
+    std::fs::write(root.join("docs").join("c.txt.hermes.part"), b"partial").unwrap();
+    std::fs::write(host.join("users.json"), b"[]").unwrap();
+    std::fs::write(host.join("resume.json"), b"[]").unwrap();
+
+    let first = take_snapshot(&storage, &HashMap::new()).unwrap();
+    let paths: Vec<&str> = first.files.iter().map(|x| x.path.as_str()).collect();
+    assert_eq!(paths, vec!["docs/a.txt", "docs/b.txt", "empty.txt"]);
+    assert_eq!(first.state.keys().collect::<Vec<_>>(), vec!["users.json"]);
+
+    // Identical contents share one blob, and empty files need none
+    assert_eq!(first.blobs().len(), 1);
+    assert_eq!(first.files[0].sha256, checksum_file(&root.join("docs").join("a.txt"), ChecksumAlgorithm::Sha256).unwrap().value());
+
+    // An unchanged file keeps the hash it was stored under, while a changed one is hashed again
+    let mut previous: HashMap<String, SnapshotEntry> = first.files.iter().map(|x| (x.path.clone(), x.clone())).collect();
+    previous.get_mut("docs/a.txt").unwrap().sha256 = String::from("stored");
+    std::fs::write(root.join("docs").join("b.txt"), b"changed").unwrap();
+    let second = take_snapshot(&storage, &previous).unwrap();
+    assert_eq!(second.files[0].sha256, "stored");
+    assert_ne!(second.files[1].sha256, first.files[1].sha256);
+
+    // The index written after a backup reads back as the same entries
+    let index = base.join("backup_index.json");
+    save_index(&index, &second).unwrap();
+    let loaded = load_index(&index);
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded["docs/b.txt"], second.files[1]);
+
+    let _ = std::fs::remove_dir_all(&base);
+}
@@ -0,0 +1,83 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use hermes_common::progress::CancelToken;
+
+// Sends a download while listening for the client. Nothing but a Cancel may be sent during a download, so the first byte that arrives cancels it.
+// That byte starts the Cancel's frame, so it is kept for whoever reads the frame afterwards.
+pub struct CancelWatch<'a, T> {
+    inner: &'a mut T,
+    token: CancelToken,
+    first: Option<u8>,
+    closed: bool //The read side ended, which the next write will find out about too
+}
+impl<'a, T: AsyncRead + AsyncWrite + Unpin> CancelWatch<'a, T> {
+    pub fn new(inner: &'a mut T, token: CancelToken) -> Self {
+        Self {
+            inner,
+            token,
+            first: None,
+            closed: false
+        }
+    }
+
+    // The first byte of what the client sent during the transfer, if it sent anything
+    pub fn into_first(self) -> Option<u8> {
+        self.first
+    }
+
+    fn poll_peer(&mut self, cx: &mut Context<'_>) {
+        if self.first.is_some() || self.closed {
+            return;
+        }
+
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        match Pin::new(&mut *self.inner).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == 1 => {
+                self.first = Some(byte[0]);
+                self.token.cancel();
+            },
+            Poll::Ready(_) => self.closed = true,
+            Poll::Pending => { }
+        }
+    }
+}
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for CancelWatch<'_, T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_peer(cx);
+        Pin::new(&mut *this.inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_cancel_watch() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut server, mut client) = tokio::io::duplex(64);
+    let token = CancelToken::new();
+
+    // Writing alone does not cancel anything
+    let mut watch = CancelWatch::new(&mut server, token.clone());
+    watch.write_all(b"frame").await.unwrap();
+    assert!(!token.is_cancelled());
+    let mut received = [0u8; 5];
+    client.read_exact(&mut received).await.unwrap();
+
+    // The client speaking does, and the byte it sent is kept
+    client.write_all(b"cancel").await.unwrap();
+    watch.write_all(b"more").await.unwrap();
+    assert!(token.is_cancelled());
+    assert_eq!(watch.into_first(), Some(b'c'));
+    let mut rest = [0u8; 5];
+    server.read_exact(&mut rest).await.unwrap();
+    assert_eq!(&rest, b"ancel");
+}
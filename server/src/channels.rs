@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use hermes_common::file_io::FileChunkIter;
+use hermes_common::network_stats::Latency;
+
+// How much of a file goes out on its channel before the next channel has a turn
+pub const CHANNEL_CHUNK_SIZE: usize = 64 * 1024;
+
+// A download being sent on the channel of the request that asked for it
+struct ChannelStream {
+    request_id: u64,
+    chunks: FileChunkIter,
+    sent: u64,
+    started: Instant,
+    latency: Option<Latency>
+}
+
+// A channel that has sent all of its file, for recording the transfer once its end frame is out
+#[derive(PartialEq, Debug)]
+pub struct FinishedChannel {
+    pub request_id: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub latency: Option<Latency>
+}
+
+// What goes out next on one of the open channels
+#[derive(PartialEq, Debug)]
+pub enum Turn {
+    Data(u64, Vec<u8>),
+    Finished(FinishedChannel),
+    Failed(u64, String) //The file could not be read any further, and the request is answered with the reason
+}
+
+// The downloads a multiplexed connection is sending, between and alongside its other requests.
+// Channels take turns a chunk at a time, so a large file does not hold up the ones asked for after it.
+#[derive(Default)]
+pub struct ChannelScheduler {
+    streams: VecDeque<ChannelStream>
+}
+impl ChannelScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, request_id: u64, mut chunks: FileChunkIter, latency: Option<Latency>) {
+        chunks.set_chunk_size(CHANNEL_CHUNK_SIZE);
+        self.streams.push_back(
+            ChannelStream {
+                request_id,
+                chunks,
+                sent: 0,
+                started: Instant::now(),
+                latency
+            }
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    // The channel at the front sends one chunk and goes to the back, or is closed when it has nothing left
+    pub fn next_turn(&mut self) -> Option<Turn> {
+        let mut stream = self.streams.pop_front()?;
+        let turn = match stream.chunks.next() {
+            Some(Ok(chunk)) => {
+                stream.sent += chunk.len() as u64;
+                let turn = Turn::Data(stream.request_id, chunk);
+                self.streams.push_back(stream);
+                turn
+            },
+            Some(Err(e)) => Turn::Failed(stream.request_id, e.to_string()),
+            None => Turn::Finished(
+                FinishedChannel {
+                    request_id: stream.request_id,
+                    bytes: stream.sent,
+                    elapsed: stream.started.elapsed(),
+                    latency: stream.latency
+                }
+            )
+        };
+
+        Some(turn)
+    }
+}
+
+#[test]
+fn test_channels_take_turns() {
+    let dir = std::env::temp_dir().join(format!("hermes_channels_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (large, small) = (dir.join("large.bin"), dir.join("small.bin"));
+    std::fs::write(&large, vec![1u8; CHANNEL_CHUNK_SIZE * 2 + 10]).unwrap();
+    std::fs::write(&small, b"small").unwrap();
+
+    let mut scheduler = ChannelScheduler::new();
+    scheduler.open(1, FileChunkIter::open(&large, 0, None).unwrap(), None);
+    scheduler.open(2, FileChunkIter::open(&small, 0, None).unwrap(), None);
+    assert_eq!(scheduler.len(), 2);
+
+    // The small file is done after its first turn, without waiting for the large one
+    let mut turns = Vec::new();
+    while let Some(turn) = scheduler.next_turn() {
+        turns.push(match turn {
+            Turn::Data(id, chunk) => (id, chunk.len() as u64, false),
+            Turn::Finished(f) => (f.request_id, f.bytes, true),
+            Turn::Failed(id, e) => panic!("channel {id} failed because '{e}'")
+        });
+    }
+    let chunk = CHANNEL_CHUNK_SIZE as u64;
+    assert_eq!(turns, vec![(1, chunk, false), (2, 5, false), (1, chunk, false), (2, 5, true), (1, 10, false), (1, chunk * 2 + 10, true)]);
+    assert!(scheduler.is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
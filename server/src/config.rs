@@ -0,0 +1,438 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::access::AccessConfig;
+use crate::activity::DEFAULT_IDLE_TIMEOUT;
+use crate::backend::FileBackendConfig;
+use crate::shutdown::DEFAULT_SHUTDOWN_GRACE;
+use crate::credentials::Credentials;
+use crate::io_loc::{root_directory, server_config_path, default_host_directory, StoragePaths};
+use crate::logging::LoggingConfig;
+use crate::middleware::{MiddlewareConfig, SessionLimits};
+use crate::scanning::ScannerConfig;
+use crate::server::DEFAULT_BIND_ADDRESS;
+use crate::staging::DEFAULT_PARTIAL_MAX_AGE;
+use crate::storage::StorageBackend;
+use crate::tls::TlsPaths;
+use crate::transfers::TransferLimits;
+use crate::uploads::UploadPolicy;
+use crate::trash::DEFAULT_TRASH_RETENTION;
+use crate::usage::DEFAULT_USAGE_CACHE;
+use crate::lockout::{DEFAULT_LOCKOUT_THRESHOLD, DEFAULT_LOCKOUT_DURATION};
+use hermes_common::network_stats::StatsConfig;
+use hermes_common::socket::SocketOptions;
+
+pub const DEFAULT_MAX_VERSIONS: usize = 10;
+
+// Whether everyone works in the one root directory, or each user is jailed to a home directory beneath it
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HomeMode {
+    #[default]
+    Shared,
+    PerUser
+}
+
+// What is done with links found beneath the root. Listings never follow them, and no path may be followed through one to outside the root.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    // Links are left out of listings, and paths through them are not found
+    #[default]
+    Skip,
+    // Links are listed, and followed when they lead somewhere beneath the root
+    FollowWithinRoot,
+    // Links are listed, and any request for a path through one is refused
+    Error
+}
+
+fn default_partial_max_age() -> u64 {
+    DEFAULT_PARTIAL_MAX_AGE.as_secs()
+}
+fn default_trash_retention() -> u64 {
+    DEFAULT_TRASH_RETENTION.as_secs()
+}
+fn default_lockout_threshold() -> u32 {
+    DEFAULT_LOCKOUT_THRESHOLD
+}
+fn default_lockout() -> u64 {
+    DEFAULT_LOCKOUT_DURATION.as_secs()
+}
+fn default_max_versions() -> usize {
+    DEFAULT_MAX_VERSIONS
+}
+fn default_idle_timeout() -> u64 {
+    DEFAULT_IDLE_TIMEOUT.as_secs()
+}
+fn default_shutdown_grace() -> u64 {
+    DEFAULT_SHUTDOWN_GRACE.as_secs()
+}
+fn default_usage_cache() -> u64 {
+    DEFAULT_USAGE_CACHE.as_secs()
+}
+fn default_dotfiles() -> bool {
+    true
+}
+fn default_bind() -> String {
+    String::from(DEFAULT_BIND_ADDRESS)
+}
+
+// Settings read from config.json in the host directory, or the file given with --config. A missing file, or a missing setting, keeps the default.
+// Files ending in .toml are read as TOML, and anything else as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_bind")]
+    pub bind: String, //The address and port to listen on
+    #[serde(default)]
+    pub host_directory: Option<PathBuf>, //Where the stores are kept. None keeps ~/cnt.
+    #[serde(default)]
+    pub data_directory: Option<PathBuf>, //Where the shared files are kept. None keeps the data folder in the host directory.
+    #[serde(default)]
+    pub database_directory: Option<PathBuf>, //Where the user and file databases are kept. None keeps the host directory.
+    #[serde(default)]
+    pub max_connections: Option<usize>, //Connections past this many are refused. None leaves them unlimited.
+    #[serde(default)]
+    pub access: AccessConfig, //Which addresses may connect, and how many connections each may hold
+    #[serde(default)]
+    pub limits: SessionLimits, //How many requests a second each connection may send, and how many downloads it may have open at once
+    #[serde(default)]
+    pub middleware: MiddlewareConfig, //Which standard request stages are turned off
+    #[serde(default)]
+    pub uploads: UploadPolicy, //How large uploaded files may be, and which kinds of file may be uploaded at all
+    #[serde(default)]
+    pub transfers: TransferLimits, //How many transfers stream at once before the rest are queued
+    #[serde(default)]
+    pub send_buffer: Option<u32>, //Socket buffer sizes in bytes. None keeps the system's.
+    #[serde(default)]
+    pub recv_buffer: Option<u32>,
+    #[serde(default)]
+    pub tls: TlsPaths,
+    #[serde(default)]
+    pub homes: HomeMode,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy, //Whether links in the data directory are hidden, followed while they stay beneath it, or refused
+    #[serde(default = "default_dotfiles")]
+    pub dotfiles: bool, //Whether names starting with '.' are listed when a Dir request does not say
+    #[serde(default)]
+    pub list_internal: bool, //Whether administrators may ask to see the files the server keeps for itself in listings. Nobody else ever sees them.
+    #[serde(default)]
+    pub guest_directory: Option<PathBuf>, //The folder beneath the root that anonymous guests can browse and download from. None refuses guests.
+    #[serde(default = "default_partial_max_age")]
+    pub partial_max_age_secs: u64, //How long an interrupted upload is kept for resuming after the server restarts
+    #[serde(default)]
+    pub storage: StorageBackend, //Where the user and file databases are kept
+    #[serde(default)]
+    pub file_backend: FileBackendConfig, //Where the shared files are kept for good. Anything but local keeps a copy of the data directory.
+    #[serde(default = "default_trash_retention")]
+    pub trash_retention_secs: u64, //How long deleted files wait in the trash. Zero deletes them for good straight away.
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize, //How many replaced copies of each file are kept. Zero lets uploads overwrite.
+    #[serde(default)]
+    pub default_quota: Option<u64>, //Bytes each user without a quota of their own may store. None leaves them unlimited.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout_secs: u64, //How long a connection may go without sending a request before it is closed. Zero keeps idle connections open.
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace_secs: u64, //How long transfers are given to finish when the server is stopped
+    #[serde(default)]
+    pub metrics_bind: Option<String>, //Where Prometheus metrics are served over plain HTTP, such as '127.0.0.1:9100'. None serves none.
+    #[serde(default)]
+    pub quic_bind: Option<String>, //Where file frames are also carried over QUIC, such as '0.0.0.0:9091'. Needs the quic feature. None keeps them on TCP.
+    #[serde(default)]
+    pub http_bind: Option<String>, //Where files are also served over plain HTTP, such as '127.0.0.1:8080'. Needs the http feature. None serves none.
+    #[serde(default = "default_lockout_threshold")]
+    pub lockout_threshold: u32, //Failed logins from one address, or for one username, before it is locked out. Zero only slows them down.
+    #[serde(default = "default_lockout")]
+    pub lockout_secs: u64, //How long a lockout lasts
+    #[serde(default)]
+    pub dedupe: bool, //Whether an upload identical to a file already stored is linked to it, so the contents are stored once
+    #[serde(default = "default_usage_cache")]
+    pub usage_cache_secs: u64, //How long the sizes of top-level folders are remembered before they are walked again for a usage report
+    #[serde(default)]
+    pub scanner: Option<ScannerConfig>, //Where uploads are scanned for viruses before they are put in place. None scans nothing.
+    #[serde(default)]
+    pub lock_wait_secs: u64, //How long a request waits for a path another request is using. Zero refuses it at once.
+    #[serde(default)]
+    pub stats: StatsConfig, //How the network statistics are rotated and expired
+    #[serde(default)]
+    pub logging: LoggingConfig
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_bind(),
+            host_directory: None,
+            data_directory: None,
+            database_directory: None,
+            max_connections: None,
+            access: AccessConfig::default(),
+            limits: SessionLimits::default(),
+            middleware: MiddlewareConfig::default(),
+            uploads: UploadPolicy::default(),
+            transfers: TransferLimits::default(),
+            send_buffer: None,
+            recv_buffer: None,
+            tls: TlsPaths::default(),
+            homes: HomeMode::default(),
+            symlinks: SymlinkPolicy::default(),
+            dotfiles: default_dotfiles(),
+            list_internal: false,
+            guest_directory: None,
+            partial_max_age_secs: default_partial_max_age(),
+            storage: StorageBackend::default(),
+            file_backend: FileBackendConfig::default(),
+            trash_retention_secs: default_trash_retention(),
+            max_versions: default_max_versions(),
+            default_quota: None,
+            idle_timeout_secs: default_idle_timeout(),
+            shutdown_grace_secs: default_shutdown_grace(),
+            metrics_bind: None,
+            quic_bind: None,
+            http_bind: None,
+            lockout_threshold: default_lockout_threshold(),
+            lockout_secs: default_lockout(),
+            dedupe: false,
+            usage_cache_secs: default_usage_cache(),
+            scanner: None,
+            lock_wait_secs: 0,
+            stats: StatsConfig::default(),
+            logging: LoggingConfig::default()
+        }
+    }
+}
+impl ServerConfig {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.to_string())
+        };
+
+        Self::parse(&contents, path.extension().is_some_and(|x| x == "toml"))
+    }
+    pub fn parse(contents: &str, toml: bool) -> Result<Self, String> {
+        if toml {
+            toml::from_str(contents).map_err(|e| e.to_string())
+        }
+        else {
+            serde_json::from_str(contents).map_err(|e| e.to_string())
+        }
+    }
+    // Reads the configuration the server starts with. A file named on the command line, or by HERMES_CONFIG, must exist. Environment variables are applied last.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let named = path.map(|x| x.to_path_buf()).or_else(|| std::env::var_os("HERMES_CONFIG").map(PathBuf::from));
+        let mut config = match named {
+            Some(p) if !p.exists() => return Err(format!("'{}' does not exist", p.display())),
+            Some(p) => Self::open(&p).map_err(|e| format!("unable to read '{}' because '{e}'", p.display()))?,
+            None => Self::open(&server_config_path()).map_err(|e| format!("unable to read '{}' because '{e}'", server_config_path().display()))?
+        };
+
+        config.apply_env(|x| std::env::var(x).ok())?;
+        Ok(config)
+    }
+
+    // Overrides settings from HERMES_ variables, which var looks up
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), String> {
+        fn number<T: std::str::FromStr>(name: &str, value: String) -> Result<T, String> {
+            value.parse().map_err(|_| format!("{name} must be a number, not '{value}'"))
+        }
+
+        if let Some(x) = var("HERMES_BIND") {
+            self.bind = x;
+        }
+        if let Some(x) = var("HERMES_HOST_DIR") {
+            self.host_directory = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_DATA_DIR") {
+            self.data_directory = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_DATABASE_DIR") {
+            self.database_directory = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_MAX_CONNECTIONS") {
+            self.max_connections = Some(number("HERMES_MAX_CONNECTIONS", x)?);
+        }
+        if let Some(x) = var("HERMES_SEND_BUFFER") {
+            self.send_buffer = Some(number("HERMES_SEND_BUFFER", x)?);
+        }
+        if let Some(x) = var("HERMES_RECV_BUFFER") {
+            self.recv_buffer = Some(number("HERMES_RECV_BUFFER", x)?);
+        }
+        if let Some(x) = var("HERMES_TLS_CERT") {
+            self.tls.cert = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_TLS_KEY") {
+            self.tls.key = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_TLS_CLIENT_CA") {
+            self.tls.client_ca = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_GUEST_DIR") {
+            self.guest_directory = Some(PathBuf::from(x));
+        }
+        if let Some(x) = var("HERMES_METRICS_BIND") {
+            self.metrics_bind = Some(x);
+        }
+        if let Some(x) = var("HERMES_QUIC_BIND") {
+            self.quic_bind = Some(x);
+        }
+        if let Some(x) = var("HERMES_HTTP_BIND") {
+            self.http_bind = Some(x);
+        }
+        if let FileBackendConfig::S3(s3) = &mut self.file_backend {
+            if let Some(x) = var("HERMES_S3_ACCESS_KEY") {
+                s3.access_key = x;
+            }
+            if let Some(x) = var("HERMES_S3_SECRET_KEY") {
+                s3.secret_key = x;
+            }
+        }
+        if let Some(x) = var("HERMES_LOG") {
+            self.logging.level = x;
+        }
+
+        Ok(())
+    }
+
+    pub fn storage_paths(&self) -> StoragePaths {
+        let host = self.host_directory.clone().unwrap_or_else(default_host_directory);
+        StoragePaths::new(host, self.data_directory.clone(), self.database_directory.clone())
+    }
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            send_buffer: self.send_buffer,
+            recv_buffer: self.recv_buffer,
+            ..Default::default()
+        }
+    }
+
+    pub fn partial_max_age(&self) -> Duration {
+        Duration::from_secs(self.partial_max_age_secs)
+    }
+    pub fn trash_retention(&self) -> Duration {
+        Duration::from_secs(self.trash_retention_secs)
+    }
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+    pub fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout_secs)
+    }
+    pub fn usage_cache(&self) -> Duration {
+        Duration::from_secs(self.usage_cache_secs)
+    }
+    pub fn lock_wait(&self) -> Duration {
+        Duration::from_secs(self.lock_wait_secs)
+    }
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    // Where a user's sessions are jailed. With per-user homes, a user without one set gets a folder named after them, except administrators, who keep the whole root.
+    // Returns None if the configured home would not be a folder beneath the root.
+    pub fn home_directory(&self, user: &Credentials) -> Option<PathBuf> {
+        let relative = match (self.homes, user.home()) {
+            (HomeMode::Shared, _) => return Some(root_directory()),
+            (HomeMode::PerUser, Some(h)) => h.to_path_buf(),
+            (HomeMode::PerUser, None) if user.is_admin() => return Some(root_directory()),
+            (HomeMode::PerUser, None) => PathBuf::from(user.username())
+        };
+
+        beneath_root(&relative)
+    }
+    // Where guest sessions are jailed. None if guests are not allowed, or the folder would not be beneath the root.
+    pub fn guest_home(&self) -> Option<PathBuf> {
+        beneath_root(self.guest_directory.as_deref()?)
+    }
+}
+
+fn beneath_root(relative: &Path) -> Option<PathBuf> {
+    let plain = relative.components().count() > 0 && relative.components().all(|x| matches!(x, Component::Normal(_)));
+    plain.then(|| root_directory().join(relative))
+}
+
+#[test]
+fn test_home_directory() {
+    use hermes_common::messages::Role;
+
+    let mut user = Credentials::new(String::from("alice"), String::from("pass"));
+    let shared = ServerConfig::default();
+    let per_user = ServerConfig { homes: HomeMode::PerUser, ..Default::default() };
+
+    assert_eq!(shared.home_directory(&user), Some(root_directory()));
+    assert_eq!(per_user.home_directory(&user), Some(root_directory().join("alice")));
+
+    user.set_home(Some(PathBuf::from("teams/red")));
+    assert_eq!(per_user.home_directory(&user), Some(root_directory().join("teams").join("red")));
+    user.set_home(Some(PathBuf::from("../escape")));
+    assert_eq!(per_user.home_directory(&user), None);
+
+    let mut admin = Credentials::new(String::from("root"), String::from("pass"));
+    admin.set_role(Role::Admin);
+    assert_eq!(per_user.home_directory(&admin), Some(root_directory()));
+
+    assert_eq!(shared.guest_home(), None);
+    let guests = ServerConfig { guest_directory: Some(PathBuf::from("public")), ..Default::default() };
+    assert_eq!(guests.guest_home(), Some(root_directory().join("public")));
+    let escaping = ServerConfig { guest_directory: Some(PathBuf::from("/etc")), ..Default::default() };
+    assert_eq!(escaping.guest_home(), None);
+
+    let parsed: ServerConfig = serde_json::from_str(r#"{ "homes": "per_user", "symlinks": "follow_within_root" }"#).unwrap();
+    assert_eq!((parsed.homes, parsed.symlinks), (HomeMode::PerUser, SymlinkPolicy::FollowWithinRoot));
+    assert_eq!(parsed.partial_max_age(), DEFAULT_PARTIAL_MAX_AGE);
+}
+
+#[test]
+fn test_config_sources() {
+    use hermes_common::network_stats::{StatsRotation, DEFAULT_KEPT_STATS_FILES};
+
+    let parsed = ServerConfig::parse(r#"
+        bind = "127.0.0.1:7070"
+        max_connections = 200
+        send_buffer = 262144
+
+        [tls]
+        cert = "/etc/hermes/cert.pem"
+        key = "/etc/hermes/key.pem"
+
+        [stats]
+        rotation = "daily"
+        retention_days = 30
+
+        [logging]
+        level = "debug"
+
+        [middleware]
+        disabled = ["rate_limit"]
+
+        [file_backend]
+        kind = "s3"
+        endpoint = "http://127.0.0.1:9000"
+        bucket = "hermes"
+    "#, true).unwrap();
+    assert_eq!((parsed.bind.as_str(), parsed.max_connections, parsed.logging.level.as_str()), ("127.0.0.1:7070", Some(200), "debug"));
+    assert_eq!((parsed.socket_options().send_buffer, parsed.socket_options().recv_buffer), (Some(262144), None));
+    assert_eq!(parsed.tls.cert, Some(PathBuf::from("/etc/hermes/cert.pem")));
+    assert_eq!(parsed.idle_timeout_secs, DEFAULT_IDLE_TIMEOUT.as_secs());
+    assert_eq!((parsed.stats.rotation, parsed.stats.retention_days, parsed.stats.kept_files), (StatsRotation::Daily, Some(30), DEFAULT_KEPT_STATS_FILES));
+    assert_eq!(parsed.middleware.disabled, vec![String::from("rate_limit")]);
+    assert!(ServerConfig::parse("bind = ", true).is_err());
+
+    // The environment wins over the file, and a bad number is refused rather than ignored
+    let mut config = parsed.clone();
+    let env = |name: &str| match name {
+        "HERMES_BIND" => Some(String::from("0.0.0.0:8080")),
+        "HERMES_DATA_DIR" => Some(String::from("/srv/hermes")),
+        "HERMES_LOG" => Some(String::from("warn")),
+        "HERMES_S3_SECRET_KEY" => Some(String::from("secret")),
+        _ => None
+    };
+    config.apply_env(env).unwrap();
+    assert_eq!((config.bind.as_str(), config.max_connections, config.logging.level.as_str()), ("0.0.0.0:8080", Some(200), "warn"));
+    assert_eq!(config.storage_paths().root, PathBuf::from("/srv/hermes"));
+    match &config.file_backend {
+        FileBackendConfig::S3(s3) => assert_eq!((s3.bucket.as_str(), s3.region.as_str(), s3.access_key.as_str(), s3.secret_key.as_str()), ("hermes", "us-east-1", "", "secret")),
+        FileBackendConfig::Local => panic!("expected the S3 backend")
+    }
+    assert!(config.apply_env(|x| (x == "HERMES_MAX_CONNECTIONS").then(|| String::from("lots"))).is_err());
+}
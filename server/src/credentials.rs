@@ -1,13 +1,58 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use rand::RngCore;
 use serde::{Serialize, Deserialize};
-use serde_json::json;
 use std::fmt::{Debug, Display};
-use std::fs::File;
-use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-#[derive(PartialEq, Serialize, Deserialize)]
+use crate::storage::{JsonStorage, Record, Storage};
+use hermes_common::error::HermesError;
+use hermes_common::messages::Role;
+
+pub const GUEST_USERNAME: &str = "guest";
+
+// Produces an argon2id PHC string, which carries its own salt and parameters
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let mut salt_bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt_bytes);
+    let salt = SaltString::encode_b64(&salt_bytes).map_err(|e| e.to_string())?;
+
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(h) => Ok(h.to_string()),
+        Err(e) => Err(format!("unable to hash password because '{e}'"))
+    }
+}
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(h) => Argon2::default().verify_password(password.as_bytes(), &h).is_ok(),
+        Err(_) => false
+    }
+}
+// Anything that is not an argon2 PHC string is a plaintext password from before hashing was introduced
+pub fn is_password_hash(value: &str) -> bool {
+    value.starts_with("$argon2") && PasswordHash::new(value).is_ok()
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Credentials {
     username: String,
-    password: String
+    password: String, //Always an argon2 hash once loaded, the plaintext is never kept
+    #[serde(default)]
+    revision: u32, //Bumped on every password change, so anything issued against an older password can be invalidated
+    #[serde(default)]
+    role: Role,
+    #[serde(default, skip_serializing)]
+    admin: bool, //Only read, from databases written before roles. It becomes the admin role when the database is opened.
+    #[serde(default)]
+    disabled: bool, //Disabled users keep their files, but cannot log in
+    #[serde(default)]
+    home: Option<PathBuf>, //Relative to the root directory. Only used when the server gives each user their own home.
+    #[serde(default)]
+    groups: Vec<String>, //Files can be shared with a whole group at once
+    #[serde(default)]
+    quota: Option<u64>, //Bytes this user may store. Without one, the server's default applies.
+    #[serde(skip)]
+    guest: bool //Only the built-in guest, which is never stored
 }
 impl Debug for Credentials {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -20,43 +65,133 @@ impl Display for Credentials {
     }
 }
 impl Credentials {
+    // Takes the plaintext password, which is hashed immediately
     pub fn new(username: String, password: String) -> Self{
         Self {
             username,
-            password
+            password: hash_password(&password).expect("hashing with a generated salt cannot fail"),
+            revision: 0,
+            role: Role::User,
+            admin: false,
+            disabled: false,
+            home: None,
+            groups: Vec::new(),
+            quota: None,
+            guest: false
         }
     }
     pub fn from(username: &str, password: &str) -> Self{
-        Self {
-            username: username.to_string(),
-            password: password.to_string()
-        }
+        Self::new(username.to_string(), password.to_string())
     }
     // Returns the user that could be anyone
     pub fn any_user() -> Self {
         Self::from("any", "any")
     }
 
+    // The identity anonymous connections act as. It has no password, so it can never log in by name.
+    pub fn guest() -> Self {
+        Self {
+            username: String::from(GUEST_USERNAME),
+            password: String::new(),
+            revision: 0,
+            role: Role::ReadOnly,
+            admin: false,
+            disabled: false,
+            home: None,
+            groups: Vec::new(),
+            quota: None,
+            guest: true
+        }
+    }
+
     pub fn username(&self) -> &str {
         &self.username
     }
-    pub fn password(&self) -> &str {
+    pub fn password_hash(&self) -> &str {
         &self.password
     }
+    pub fn verify_password(&self, password: &str) -> bool {
+        verify_password(password, &self.password)
+    }
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.role == Role::ReadOnly
+    }
+    pub fn is_guest(&self) -> bool {
+        self.guest
+    }
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+    pub fn home(&self) -> Option<&Path> {
+        self.home.as_deref()
+    }
+    pub fn set_home(&mut self, home: Option<PathBuf>) {
+        self.home = home;
+    }
+    pub fn groups(&self) -> &Vec<String> {
+        &self.groups
+    }
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|x| x == group)
+    }
+    pub fn set_groups(&mut self, groups: Vec<String>) {
+        self.groups = groups;
+    }
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+    }
+
+    pub fn set_password(&mut self, password: String) -> Result<(), String> {
+        self.password = hash_password(&password)?;
+        self.revision += 1;
+        Ok(())
+    }
+}
+
+impl Record for Credentials {
+    fn key(&self) -> String {
+        self.username.clone()
+    }
+    fn kind(&self) -> Option<String> {
+        Some(self.role.to_string())
+    }
 }
 
 pub struct UserDatabase {
-    path: Option<String>,
+    storage: Option<Box<dyn Storage<Credentials>>>,
     users: Vec<Credentials>
 }
+impl Default for UserDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Debug for UserDatabase {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f, 
             "(Path: '{}', Users: {})", 
-            match self.path.as_ref() {
-                Some(s) => s,
-                None => "Unopened"
+            match self.storage.as_ref() {
+                Some(s) => s.location().display().to_string(),
+                None => String::from("Unopened")
             }, 
             self.users.len()
         )
@@ -67,9 +202,9 @@ impl Display for UserDatabase {
         write!(
             f, 
             "Users: {}",
-            match self.path.as_ref() {
-                Some(s) => s,
-                None => "Unopened"
+            match self.storage.as_ref() {
+                Some(s) => s.location().display().to_string(),
+                None => String::from("Unopened")
             }
         )
     }
@@ -77,75 +212,73 @@ impl Display for UserDatabase {
 impl UserDatabase {
     pub const fn new() -> Self {
         Self {
-            path: None,
+            storage: None,
             users: Vec::new()
         }
     }
 
-    pub fn open(&mut self, path: String) -> Result<(), String> {
-        if self.path.is_some() {
-            return Err(format!("already open at path '{}'", self.path.as_ref().unwrap()));
+    pub fn open(&mut self, path: String) -> Result<(), HermesError> {
+        self.open_storage(Box::new(JsonStorage::new(Path::new(&path))))
+    }
+    pub fn open_storage(&mut self, storage: Box<dyn Storage<Credentials>>) -> Result<(), HermesError> {
+        if let Some(s) = self.storage.as_ref() {
+            return Err(HermesError::Conflict(format!("already open at path '{}'", s.location().display())));
         }
 
-        let mut file = match File::open(&path) {
-            Ok(f) => f,
-            Err(e) => {
-                match File::create(&path) {
-                    Ok(f) => f,
-                    Err(e2) => return Err(format!("unable to open because '{}' and unable to create because '{}'", e, e2))
-                }
-            }
-        };
-
-        let mut contents = String::new();
-        if let Err(e) = file.read_to_string(&mut contents) {
-            return Err(format!("could not read because '{}'", e))
+        self.users = storage.load()?;
+        self.storage = Some(storage);
+        
+        if !self.validate() {
+            self.storage = None;
+            self.users.clear();
+            return Err(HermesError::Conflict(String::from("Duplicate or empty records found")));
         }
 
-        if contents.is_empty() {
-            contents = String::from("[ ]");
+        let migrated_passwords = self.migrate_passwords()?;
+        let migrated_roles = self.migrate_roles();
+        if migrated_passwords || migrated_roles {
+            self.save()?;
         }
 
-        let json_contents: Vec<Credentials> = match serde_json::from_str(&contents) {
-            Ok(j) => j,
-            Err(e) => return Err(format!("parsing error '{e}'"))
-        };
-
-        self.users = json_contents;
-        
-        if self.validate() {
-            Ok(())
-        } else {
-            Err(String::from("Duplicate or empty records found"))
-        }
+        Ok(())
     }
-    pub fn save(&self) -> Result<(), String> {
-        if self.path.is_none() {
-            return Err(String::from("no file opened"));
+    // Hashes any plaintext passwords left by older versions, returning true if anything changed
+    fn migrate_passwords(&mut self) -> Result<bool, HermesError> {
+        let mut changed = false;
+        for user in self.users.iter_mut().filter(|x| !is_password_hash(&x.password)) {
+            user.password = hash_password(&user.password).map_err(HermesError::Invalid)?;
+            changed = true;
         }
 
-        let mut file = match File::create(&self.path.as_ref().unwrap()) {
-            Ok(f) => f,
-            Err(e) => return Err(e.to_string())
-        };
-
-        let contents = json!(self.users).to_string();
+        Ok(changed)
+    }
+    // Turns the admin flag of older versions into the admin role, returning true if anything changed
+    fn migrate_roles(&mut self) -> bool {
+        let mut changed = false;
+        for user in self.users.iter_mut().filter(|x| x.admin) {
+            user.role = Role::Admin;
+            user.admin = false;
+            changed = true;
+        }
 
-        match file.write(contents.as_bytes()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string())
+        changed
+    }
+    pub fn save(&self) -> Result<(), HermesError> {
+        match self.storage.as_ref() {
+            Some(s) => s.save(&self.users),
+            None => Err(HermesError::NotOpen(String::from("no file opened")))
         }
     }
 
     // Determines that every user has a password & that there are no duplicates
     fn validate(&self) -> bool {
-        if self.path.is_none() {
+        if self.storage.is_none() {
             return false;
         }
 
         for (i, cred) in self.users.iter().enumerate() {
             for (j, cred2) in self.users.iter().enumerate() {
-                if cred.username.is_empty() || cred.password.is_empty() || (i != j && cred == cred2) {
+                if cred.username.is_empty() || cred.password.is_empty() || (i != j && cred.username == cred2.username) {
                     return false; //Something is empty or we have a duplicate
                 }
             }
@@ -155,16 +288,58 @@ impl UserDatabase {
     }
 
     pub fn get_user(&self, username: &str) -> Option<&Credentials> {
-        self.path.as_ref()?; //If we dont have a path then we return none
+        self.storage.as_ref()?; //If we dont have a path then we return none
         self.users.iter().find(|x| x.username == username)
     }
     pub fn get_user_mut(&mut self, username: &str) -> Option<&mut Credentials> {
-        self.path.as_ref(); //If we dont have a path then we return none
         self.users.iter_mut().find(|x| x.username == username)
     }
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+    pub fn add_user(&mut self, user: Credentials) -> Result<(), HermesError> {
+        if user.username.is_empty() {
+            return Err(HermesError::Invalid(String::from("usernames cannot be empty")));
+        }
+        if self.users.iter().any(|x| x.username == user.username) {
+            return Err(HermesError::Conflict(format!("user '{}' already exists", user.username)));
+        }
+
+        self.users.push(user);
+        Ok(())
+    }
+    pub fn remove_user(&mut self, username: &str) -> bool {
+        let prev_len = self.users.len();
+        self.users.retain(|x| x.username != username);
+
+        prev_len != self.users.len()
+    }
     // Determine if that user is in the database & if the passwords match. If the user is not in the database, it returns None. If it is, and the passwords match, it returns Some(true). Otherwise it returns Some(false)
     pub fn validate_user(&self, username: &str, password: &str) -> Option<bool> {
         let target = self.get_user(username)?;
-        Some(target.password == password)
+        Some(target.verify_password(password))
     }
+}
+
+#[test]
+fn test_password_migration() {
+    let path = std::env::temp_dir().join(format!("hermes_users_{}.json", std::process::id()));
+    std::fs::write(&path, r#"[{"username":"user","password":"plain"}]"#).unwrap();
+
+    let mut users = UserDatabase::new();
+    users.open(path.to_string_lossy().to_string()).unwrap();
+
+    // The plaintext is replaced on disk as soon as the database is opened
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(!contents.contains("\"plain\""));
+    assert!(is_password_hash(users.get_user("user").unwrap().password_hash()));
+
+    assert_eq!(users.validate_user("user", "plain"), Some(true));
+    assert_eq!(users.validate_user("user", "wrong"), Some(false));
+    assert_eq!(users.validate_user("nobody", "plain"), None);
+
+    let _ = std::fs::remove_file(&path);
 }
\ No newline at end of file
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+use crate::io_tools::FileDatabase;
+use crate::resume::generate_token;
+use crate::staging::STAGING_SUFFIX;
+use crate::storage::RecordFilter;
+use hermes_common::messages::DedupeReport;
+
+// Where a file's contents live on disk, so paths linked together are known to share them. Without a way to tell, every path is its own.
+#[cfg(unix)]
+fn storage_key(_path: &Path, metadata: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    format!("{}:{}", metadata.dev(), metadata.ino())
+}
+#[cfg(not(unix))]
+fn storage_key(path: &Path, _metadata: &Metadata) -> String {
+    path.to_string_lossy().to_string()
+}
+
+// Files with the same checksum and size, with where each is
+type ContentGroups = HashMap<(String, u64), Vec<(PathBuf, Metadata)>>;
+
+// Every file beneath the root whose checksum is still good, grouped by checksum and size, and how many were left out for having none.
+// Files held in quarantine are never compared.
+fn hashed_files(files: &FileDatabase, root: &Path) -> (ContentGroups, u64) {
+    let mut groups = ContentGroups::new();
+    let mut unhashed = 0;
+    for file in files.query(&RecordFilter::default()).into_iter().filter(|x| x.path().starts_with(root) && !x.is_quarantined()) {
+        let metadata = match std::fs::symlink_metadata(file.path()) {
+            Ok(m) if m.is_file() => m,
+            _ => continue
+        };
+        match file.checksum(&metadata) {
+            Some(c) => groups.entry((c.to_string(), metadata.len())).or_default().push((file.path().to_path_buf(), metadata)),
+            None => unhashed += 1
+        }
+    }
+
+    (groups, unhashed)
+}
+
+// Replaces a newly written file with a link to an older file with the same contents, so both are stored once. Returns the file it was linked to.
+// Files are only ever replaced by renaming over them, never written in place, so a change to one path never shows through another.
+// The match is looked up by checksum, so an upload only looks at the files it could be linked to.
+pub fn link_duplicate(files: &FileDatabase, root: &Path, path: &Path) -> Result<Option<PathBuf>, String> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_file() && m.len() > 0 => m,
+        _ => return Ok(None)
+    };
+    let checksum = match files.get_file_by_path(path).and_then(|x| x.checksum(&metadata)) {
+        Some(c) => c,
+        None => return Ok(None)
+    };
+
+    let key = storage_key(path, &metadata);
+    let original = files.files_with_checksum(checksum).into_iter()
+        .find(|(p, m)| p != path && p.starts_with(root) && m.len() == metadata.len() && storage_key(p, m) != key);
+    let original = match original {
+        Some((p, _)) => p,
+        None => return Ok(None)
+    };
+
+    // Linked beside the file first, so the file is never missing
+    let linked = path.with_file_name(format!(".{}{STAGING_SUFFIX}", generate_token()));
+    std::fs::hard_link(&original, &linked).map_err(|e| format!("unable to link to '{}' because '{e}'", original.display()))?;
+    if let Err(e) = std::fs::rename(&linked, path) {
+        let _ = std::fs::remove_file(&linked);
+        return Err(e.to_string());
+    }
+
+    Ok(Some(original))
+}
+
+pub fn dedupe_report(files: &FileDatabase, root: &Path) -> DedupeReport {
+    let (groups, unhashed) = hashed_files(files, root);
+    let mut report = DedupeReport { unhashed, unique: groups.len() as u64, ..Default::default() };
+    for ((_, size), members) in groups {
+        let stored: HashSet<String> = members.iter().map(|(p, m)| storage_key(p, m)).collect();
+        report.files += members.len() as u64;
+        if members.len() > 1 {
+            report.duplicates += members.len() as u64;
+        }
+        report.saved_bytes += (members.len() - stored.len()) as u64 * size;
+        report.linkable_bytes += (stored.len() as u64 - 1) * size;
+    }
+
+    report
+}
+
+#[test]
+fn test_dedupe() {
+    use hermes_common::checksum::{checksum_file, ChecksumAlgorithm};
+    use hermes_common::file_io::FileType;
+
+    let root = std::env::temp_dir().join(format!("hermes_dedupe_{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut files = FileDatabase::new();
+    for (name, contents) in [("a.txt", "same"), ("b.txt", "same"), ("c.txt", "different"), ("d.txt", "unhashed")] {
+        std::fs::write(root.join(name), contents).unwrap();
+        let id = files.register_file(root.join(name), None, FileType::Text).unwrap();
+        if name != "d.txt" {
+            let checksum = checksum_file(&root.join(name), ChecksumAlgorithm::Sha256).unwrap();
+            files.set_checksum(id, checksum);
+        }
+    }
+
+    let before = dedupe_report(&files, &root);
+    assert_eq!(before, DedupeReport { files: 3, unhashed: 1, unique: 2, duplicates: 2, saved_bytes: 0, linkable_bytes: 4 });
+    assert_eq!(link_duplicate(&files, &root, &root.join("c.txt")), Ok(None));
+    assert_eq!(link_duplicate(&files, &root, &root.join("b.txt")), Ok(Some(root.join("a.txt"))));
+    assert_eq!(std::fs::read_to_string(root.join("b.txt")).unwrap(), "same");
+
+    // Linking changes what the file's metadata says, so its checksum is taken again, as uploads do
+    let checksum = checksum_file(&root.join("b.txt"), ChecksumAlgorithm::Sha256).unwrap();
+    let id = files.get_file_id(&root.join("b.txt")).unwrap();
+    files.set_checksum(id, checksum);
+    #[cfg(unix)]
+    {
+        assert_eq!(dedupe_report(&files, &root), DedupeReport { saved_bytes: 4, linkable_bytes: 0, ..before });
+        assert_eq!(link_duplicate(&files, &root, &root.join("b.txt")), Ok(None));
+    }
+
+    // Replacing one of them leaves the other as it was
+    std::fs::write(root.join("b.new"), "replaced").unwrap();
+    std::fs::rename(root.join("b.new"), root.join("b.txt")).unwrap();
+    assert_eq!(std::fs::read_to_string(root.join("a.txt")).unwrap(), "same");
+
+    // Matches are looked up by checksum, which follows a record that moves and forgets one that goes, and skips a file changed since it was taken
+    let same = checksum_file(&root.join("a.txt"), ChecksumAlgorithm::Sha256).unwrap();
+    let matches = |files: &FileDatabase| files.files_with_checksum(&same).into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+    std::fs::rename(root.join("a.txt"), root.join("moved.txt")).unwrap();
+    files.rename_tree(&root.join("a.txt"), &root.join("moved.txt"));
+    assert_eq!(matches(&files), vec![root.join("moved.txt")]);
+    files.unregister_file(&root.join("moved.txt"));
+    assert!(matches(&files).is_empty());
+
+    let _ = std::fs::remove_dir_all(&root);
+}
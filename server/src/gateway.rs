@@ -0,0 +1,385 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::state::ServerState;
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use base64::Engine;
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use crate::server::loopback;
+use crate::webdav;
+use hermes_common::chunking::{ChunkReader, ChunkWriter};
+use hermes_common::file_io::{FileInfo, FileType, BUFF_SIZE, frame_count_for, receive_network_binary_async};
+use hermes_common::framing::{read_frame_async, write_frame_async};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, DirQuery, connect_message, resume_connect_message, grant_connect_message, share_connect_message, close_message, attach_session};
+use hermes_common::messages::{extract_connect_ack_message, extract_session_ack, extract_ack_message, advertise_capabilities};
+use hermes_common::messages::{download_message_request, extract_download_response_message, upload_message, extract_upload_response_message, delete_message, dir_query_request, extract_dir_response_message};
+use hermes_common::messages::{stat_message_request, extract_stat_response_message, subfolder_message, SubfolderAction};
+use hermes_common::protocol::{CURRENT_PROTOCOL_VERSION, Capabilities};
+use hermes_common::transport::AsyncTransport;
+use hermes_common::tuning::{FrameSizeBounds, FrameSizeTuner};
+
+// Rotated bearer tokens are handed back in this header
+const TOKEN_HEADER: &str = "x-hermes-token";
+
+// An HTTP front end for scripts and curl, beside the Hermes listener:
+//   GET /files/<path>     downloads a file
+//   PUT /files/<path>     uploads a file, which needs a Content-Length
+//   DELETE /files/<path>  deletes a file, or a directory with '?recursive=true'
+//   GET /dir/<path>       lists a directory as JSON
+// WebDAV clients are served beneath /dav.
+// Each request logs in over its own in-process connection, so it is checked exactly as a client's requests would be.
+pub async fn start_gateway(state: &Arc<ServerState>, bind: &str) -> Result<SocketAddr, String> {
+    let listener = tokio::net::TcpListener::bind(bind).await.map_err(|e| format!("unable to bind to '{bind}' because '{e}'"))?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+    let app = router(state);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+            tracing::warn!("the HTTP gateway stopped because '{e}'");
+        }
+    });
+    Ok(addr)
+}
+
+fn router(state: &Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/files/*path", get(get_file).put(put_file).delete(delete_file))
+        .route("/dir", get(get_root))
+        .route("/dir/", get(get_root))
+        .route("/dir/*path", get(get_dir))
+        .merge(webdav::routes())
+        .with_state(Arc::clone(state))
+}
+
+// What an Authorization header logs in with
+#[derive(PartialEq, Debug)]
+enum Login {
+    Password(String, String),
+    Token(String)
+}
+fn parse_authorization(header: &str) -> Option<Login> {
+    let (scheme, value) = header.trim().split_once(' ')?;
+    let value = value.trim();
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = base64::engine::general_purpose::STANDARD.decode(value).ok()?;
+        let (username, password) = String::from_utf8(decoded).ok()?.split_once(':').map(|(u, p)| (u.to_string(), p.to_string()))?;
+        Some(Login::Password(username, password))
+    }
+    else if scheme.eq_ignore_ascii_case("bearer") && !value.is_empty() {
+        Some(Login::Token(value.to_string()))
+    }
+    else {
+        None
+    }
+}
+
+pub fn reply(code: HttpCodes, message: &str) -> Response {
+    let status = StatusCode::from_u16(code.as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, format!("{message}\n")).into_response()
+}
+fn unauthorized(message: &str) -> Response {
+    let mut response = reply(HttpCodes::Unauthorized, message);
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"Hermes\""));
+    response
+}
+pub fn broken(e: String) -> Response {
+    reply(HttpCodes::InternalServerError, &format!("the request failed because '{e}'"))
+}
+// Passes an ack on. A request refused before its handler runs is answered with one, whatever it asked for.
+pub fn acked(message: Message, kind: &str) -> Response {
+    match extract_ack_message(message) {
+        Some((code, message)) => reply(code, &message),
+        None => broken(format!("malformed {kind} response"))
+    }
+}
+
+// One HTTP request's login, over a connection of its own
+pub struct Session {
+    transport: Box<dyn AsyncTransport>,
+    token: String,
+    rotated: Option<String>, //The replacement for the resume token this session was opened with
+    bounds: FrameSizeBounds
+}
+impl Session {
+    // Bearer tokens may be share links, upload grants, or resume tokens. Resume tokens are single use, so the next one is handed back with the response.
+    pub async fn open(state: &Arc<ServerState>, peer: SocketAddr, headers: &HeaderMap) -> Result<Self, Response> {
+        let login = match headers.get(header::AUTHORIZATION).and_then(|x| x.to_str().ok()) {
+            Some(h) => parse_authorization(h).ok_or_else(|| unauthorized("the Authorization header must be Basic or Bearer"))?,
+            None => return Err(unauthorized("log in with Basic or Bearer authorization"))
+        };
+        let resuming = matches!(login, Login::Token(_));
+        let request = match login {
+            Login::Password(u, p) => connect_message(u, p, CURRENT_PROTOCOL_VERSION),
+            Login::Token(t) if state.shares.read().await.owner_of(&t).is_some() => share_connect_message(&t, CURRENT_PROTOCOL_VERSION),
+            Login::Token(t) if state.grants.read().await.owner_of(&t).is_some() => grant_connect_message(&t, CURRENT_PROTOCOL_VERSION),
+            Login::Token(t) => resume_connect_message(&t, CURRENT_PROTOCOL_VERSION)
+        };
+
+        let mut transport = loopback(peer, Arc::clone(state));
+        write_frame_async(&mut transport, &advertise_capabilities(request, &Capabilities::legacy())).await.map_err(broken)?;
+        let response = read_frame_async(&mut transport).await.map_err(broken)?;
+
+        // A refusal from admission has the same shape as a refused login
+        let token = extract_session_ack(&response).map(|x| x.token().to_string());
+        let (resume, token) = match (extract_connect_ack_message(response), token) {
+            (Some((HttpCodes::Ok, _, _, resume)), Some(t)) => (resume, t),
+            (Some((HttpCodes::Unauthorized, message, _, _)), _) => return Err(unauthorized(&message)),
+            (Some((code, message, _, _)), _) => return Err(reply(code, &message)),
+            (None, _) => return Err(broken(String::from("malformed connect response")))
+        };
+
+        // A password login is given a resume token like any other, which nothing here would use
+        let rotated = match (resume, resuming) {
+            (Some(r), true) => Some(r.token().to_string()),
+            (Some(r), false) => {
+                let mut store = state.resume.write().await;
+                store.revoke(r.token());
+                let _ = store.save();
+                None
+            },
+            (None, _) => None
+        };
+
+        Ok(
+            Self {
+                transport,
+                token,
+                rotated,
+                bounds: state.frame_bounds
+            }
+        )
+    }
+
+    pub async fn request(&mut self, message: Message) -> Result<Message, Response> {
+        write_frame_async(&mut self.transport, &attach_session(message, &self.token)).await.map_err(broken)?;
+        read_frame_async(&mut self.transport).await.map_err(broken)
+    }
+    // Hands a rotated resume token back to whoever sent the old one
+    fn with_token(&self, mut response: Response) -> Response {
+        if let Some(t) = self.rotated.as_ref().and_then(|x| HeaderValue::from_str(x).ok()) {
+            response.headers_mut().insert(TOKEN_HEADER, t);
+        }
+        response
+    }
+    pub async fn finish(mut self, response: Result<Response, Response>) -> Response {
+        let _ = write_frame_async(&mut self.transport, &close_message()).await;
+        match response {
+            Ok(r) | Err(r) => self.with_token(r)
+        }
+    }
+
+    // The file is streamed as it arrives, so the response starts before the download is over. Nothing is compressed, since compression was never offered.
+    pub async fn download(mut self, path: &str) -> Response {
+        let response = match self.request(download_message_request(path, 0, None)).await {
+            Ok(m) => match extract_download_response_message(m.clone()) {
+                Some(r) => r,
+                None => return self.finish(Err(acked(m, "download"))).await
+            },
+            Err(e) => return self.finish(Err(e)).await
+        };
+        if response.status != HttpCodes::Ok {
+            return self.finish(Err(reply(response.status, &response.message))).await;
+        }
+
+        let (mut writer, reader) = tokio::io::duplex(BUFF_SIZE as usize);
+        let headers = [(header::CONTENT_TYPE, String::from("application/octet-stream")), (header::CONTENT_LENGTH, response.length.to_string())];
+        let body = self.with_token((headers, Body::from_stream(ReaderStream::new(reader))).into_response());
+        tokio::spawn(async move {
+            let mut chunks = ChunkReader::new(response.frame_count);
+            loop {
+                match chunks.read_frames_async(&mut self.transport, BUFF_SIZE as usize).await {
+                    Ok(Some(frame)) => if writer.write_all(&frame).await.is_err() {
+                        return; //The HTTP client went away, and dropping the connection ends the download
+                    },
+                    Ok(None) => break,
+                    Err(e) => return tracing::warn!("the download was interrupted because '{e}'")
+                }
+            }
+            self.finish(Ok(().into_response())).await;
+        });
+        body
+    }
+
+    pub async fn upload(mut self, path: &str, length: u64, body: Body) -> Response {
+        let response = self.send_upload(path, length, body).await;
+        self.finish(response).await
+    }
+    async fn send_upload(&mut self, path: &str, length: u64, mut body: Body) -> Result<Response, Response> {
+        let response = self.request(upload_message(path, FileType::Binary, frame_count_for(length), 0, None, None)).await?;
+        match extract_upload_response_message(response.clone()) {
+            Some((HttpCodes::Ok, _, _)) => (),
+            Some((code, message, _)) => return Err(reply(code, &message)),
+            None => return Err(acked(response, "upload"))
+        }
+
+        let mut writer = ChunkWriter::new(length);
+        while let Some(frame) = body.frame().await {
+            let data = match frame.map_err(|e| broken(e.to_string()))?.into_data() {
+                Ok(d) => d,
+                Err(_) => continue //Trailers
+            };
+            let encoded = writer.encode(&data).map_err(|e| reply(HttpCodes::BadRequest, &format!("the body is longer than its Content-Length, {e}")))?;
+            self.transport.write_all(&encoded).await.map_err(|e| broken(e.to_string()))?;
+        }
+        // Dropping the connection part way leaves the upload to be resumed or purged, like any other that was cut off
+        if !writer.is_finished() {
+            return Err(reply(HttpCodes::BadRequest, "the body ended before its Content-Length"));
+        }
+        self.transport.flush().await.map_err(|e| broken(e.to_string()))?;
+
+        match extract_ack_message(read_frame_async(&mut self.transport).await.map_err(broken)?) {
+            Some((code, message)) => Ok(reply(code, &message)),
+            None => Err(broken(String::from("malformed upload ack")))
+        }
+    }
+
+    pub async fn delete(mut self, path: &str, recursive: bool) -> Response {
+        let response = self.request(delete_message(path, recursive)).await.map(|x| acked(x, "delete"));
+        self.finish(response).await
+    }
+
+    // None when there is no file at the path, which may still be a directory
+    pub async fn stat(&mut self, path: &str) -> Result<Option<FileInfo>, Response> {
+        let response = self.request(stat_message_request(path)).await?;
+        match extract_stat_response_message(response.clone()) {
+            Some((HttpCodes::Ok, _, info)) => Ok(info),
+            Some((HttpCodes::NotFound, _, _)) => Ok(None),
+            Some((code, message, _)) => Err(reply(code, &message)),
+            None => Err(acked(response, "stat"))
+        }
+    }
+    pub async fn make_dir(mut self, path: &str) -> Response {
+        let response = self.request(subfolder_message(path, SubfolderAction::Add, false)).await.map(|x| acked(x, "subfolder"));
+        self.finish(response).await
+    }
+
+    // The listing is passed on in the JSON the server wrote it in
+    async fn list(mut self, path: Option<String>) -> Response {
+        let response = self.listing(path).await.map(|x| ([(header::CONTENT_TYPE, "application/json")], x).into_response());
+        self.finish(response).await
+    }
+    pub async fn listing(&mut self, path: Option<String>) -> Result<Vec<u8>, Response> {
+        let query = DirQuery {
+            path,
+            ..Default::default()
+        };
+        let response = self.request(dir_query_request(&query)).await?;
+        let frame_count = match extract_dir_response_message(response.clone()) {
+            Some((HttpCodes::Ok, _, _, f)) => f,
+            Some((code, message, _, _)) => return Err(reply(code, &message)),
+            None => return Err(acked(response, "dir"))
+        };
+
+        let mut tuner = FrameSizeTuner::new(self.bounds);
+        receive_network_binary_async(&mut self.transport, frame_count, &mut tuner).await.ok_or_else(|| broken(String::from("the listing was interrupted")))
+    }
+}
+
+// Uploads are framed as they arrive, so their length has to be known before they start
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH).and_then(|x| x.to_str().ok()).and_then(|x| x.parse::<u64>().ok())
+}
+pub fn length_required() -> Response {
+    (StatusCode::LENGTH_REQUIRED, "uploads need a Content-Length\n").into_response()
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    recursive: bool
+}
+
+async fn get_file(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Path(path): Path<String>, headers: HeaderMap) -> Response {
+    match Session::open(&state, peer, &headers).await {
+        Ok(s) => s.download(&path).await,
+        Err(response) => response
+    }
+}
+async fn put_file(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Path(path): Path<String>, headers: HeaderMap, body: Body) -> Response {
+    let length = match content_length(&headers) {
+        Some(l) => l,
+        None => return length_required()
+    };
+
+    match Session::open(&state, peer, &headers).await {
+        Ok(s) => s.upload(&path, length, body).await,
+        Err(response) => response
+    }
+}
+async fn delete_file(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Path(path): Path<String>, Query(query): Query<DeleteQuery>, headers: HeaderMap) -> Response {
+    match Session::open(&state, peer, &headers).await {
+        Ok(s) => s.delete(&path, query.recursive).await,
+        Err(response) => response
+    }
+}
+async fn get_dir(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, Path(path): Path<String>, headers: HeaderMap) -> Response {
+    match Session::open(&state, peer, &headers).await {
+        Ok(s) => s.list(Some(path)).await,
+        Err(response) => response
+    }
+}
+async fn get_root(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, headers: HeaderMap) -> Response {
+    match Session::open(&state, peer, &headers).await {
+        Ok(s) => s.list(None).await,
+        Err(response) => response
+    }
+}
+
+#[test]
+fn test_parse_authorization() {
+    // 'user:pa:ss', since only the first colon separates the two
+    assert_eq!(parse_authorization("Basic dXNlcjpwYTpzcw=="), Some(Login::Password(String::from("user"), String::from("pa:ss"))));
+    assert_eq!(parse_authorization("bearer  abc123 "), Some(Login::Token(String::from("abc123"))));
+    assert_eq!(parse_authorization("Basic not-base64"), None);
+    assert_eq!(parse_authorization("Digest username=\"user\""), None);
+    assert_eq!(parse_authorization("Bearer"), None);
+}
+#[tokio::test]
+async fn test_gateway_requests() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    let path = std::env::temp_dir().join(format!("hermes_gateway_users_{}.json", std::process::id()));
+    std::fs::write(&path, r#"[{"username":"user","password":"pass"}]"#).unwrap();
+    let mut state = ServerState::new();
+    state.users.get_mut().open(path.to_string_lossy().to_string()).unwrap();
+    let state = Arc::new(state);
+    let addr = start_gateway(&state, "127.0.0.1:0").await.unwrap();
+
+    let send = |request: String| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+    // 'user:pass' and 'user:wrong'
+    let request = |method: &str, target: &str, authorization: &str| format!("{method} {target} HTTP/1.1\r\nHost: hermes\r\nConnection: close\r\n{authorization}\r\n");
+    let user = "Authorization: Basic dXNlcjpwYXNz\r\n";
+
+    let response = send(request("GET", "/dir", "")).await;
+    assert!(response.starts_with("HTTP/1.1 401"));
+    assert!(response.to_lowercase().contains("www-authenticate: basic"));
+    assert!(send(request("GET", "/dir", "Authorization: Basic dXNlcjp3cm9uZw==\r\n")).await.starts_with("HTTP/1.1 401"));
+    assert!(send(request("GET", "/dir", "Authorization: Bearer not-a-token\r\n")).await.starts_with("HTTP/1.1 401"));
+    assert!(send(request("PUT", "/files/new.txt", user)).await.starts_with("HTTP/1.1 411"));
+
+    // A login goes all the way through to the handlers, which answer as they would a client
+    let missing = format!("/files/hermes-gateway-missing-{}", std::process::id());
+    assert!(send(request("GET", &missing, user)).await.starts_with("HTTP/1.1 404"));
+    assert!(send(request("DELETE", &missing, user)).await.starts_with("HTTP/1.1 404"));
+    assert!(send(request("GET", "/files/../outside", user)).await.starts_with("HTTP/1.1 403"));
+    assert!(state.resume.read().await.is_empty());
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,231 @@
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use std::fmt::{Debug, Display};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::credentials::Credentials;
+use crate::resume::generate_token;
+use hermes_common::file_io::JsonFile;
+use hermes_common::session::{UploadGrant, unix_now};
+
+pub const MAX_GRANT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum GrantAction {
+    Issued,
+    Connected,
+    Used,
+    Rejected,
+    Expired,
+    Revoked
+}
+impl Display for GrantAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Issued => "issued",
+            Self::Connected => "connected",
+            Self::Used => "used",
+            Self::Rejected => "rejected",
+            Self::Expired => "expired",
+            Self::Revoked => "revoked"
+        };
+
+        write!(f, "{text}")
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GrantRecord {
+    grant: UploadGrant,
+    owner: String,
+    used: bool
+}
+
+pub struct GrantStore {
+    file: JsonFile,
+    records: Vec<GrantRecord>,
+    audit_path: Option<PathBuf>
+}
+impl Debug for GrantStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Path: '{}', Grants: {})", self.file.path().unwrap_or("Unopened"), self.records.len())
+    }
+}
+impl Default for GrantStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl GrantStore {
+    pub fn new() -> Self {
+        Self {
+            file: JsonFile::new(),
+            records: Vec::new(),
+            audit_path: None
+        }
+    }
+
+    pub fn open(&mut self, path: &str, audit_path: &Path) -> Result<(), String> {
+        let contents = self.file.open(path)?;
+        self.audit_path = Some(audit_path.to_path_buf());
+
+        if contents.trim().is_empty() {
+            self.records.clear();
+            return Ok(());
+        }
+
+        match serde_json::from_str(&contents) {
+            Ok(l) => {
+                self.records = l;
+                self.prune();
+                Ok(())
+            },
+            Err(e) => Err(e.to_string())
+        }
+    }
+    pub fn save(&self) -> Result<(), String> {
+        let contents = match serde_json::to_string(&self.records) {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.file.save(&contents).map_err(|e| e.to_string())
+    }
+
+    // Every step in the life of a grant is appended to the audit log as a JSON line. Only a prefix of the token is written, so the log cannot be used to redeem grants.
+    fn audit(&self, action: GrantAction, grant: &UploadGrant, actor: &str, detail: &str) {
+        let path = match self.audit_path.as_ref() {
+            Some(p) => p,
+            None => return
+        };
+
+        let entry = json!({
+            "at": unix_now(),
+            "action": action,
+            "grant": grant.token().chars().take(8).collect::<String>(),
+            "path": grant.path(),
+            "actor": actor,
+            "detail": detail
+        });
+
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = writeln!(file, "{entry}");
+        }
+    }
+
+    fn prune(&mut self) {
+        let (expired, kept): (Vec<GrantRecord>, Vec<GrantRecord>) = self.records.drain(..).partition(|x| x.grant.is_expired() || x.used);
+        self.records = kept;
+
+        for record in expired.iter().filter(|x| !x.used) {
+            self.audit(GrantAction::Expired, &record.grant, &record.owner, "");
+        }
+    }
+    fn find(&self, token: &str) -> Option<&GrantRecord> {
+        self.records.iter().find(|x| x.grant.token() == token && !x.used && !x.grant.is_expired())
+    }
+
+    pub fn issue(&mut self, owner: &Credentials, path: String, max_size: u64, ttl: Duration) -> Result<UploadGrant, String> {
+        if max_size == 0 {
+            return Err(String::from("the maximum size of a grant must be greater than zero"));
+        }
+        if ttl.is_zero() || ttl > MAX_GRANT_TTL {
+            return Err(format!("the lifetime of a grant must be between 1 and {} seconds", MAX_GRANT_TTL.as_secs()));
+        }
+
+        self.prune();
+
+        let grant = UploadGrant::new(generate_token(), path, max_size, unix_now() + ttl.as_secs());
+        self.audit(GrantAction::Issued, &grant, owner.username(), &format!("max size {max_size}"));
+        self.records.push(
+            GrantRecord {
+                grant: grant.clone(),
+                owner: owner.username().to_string(),
+                used: false
+            }
+        );
+
+        Ok(grant)
+    }
+
+    pub fn authorize_connect(&self, token: &str, peer: &str) -> Option<UploadGrant> {
+        match self.find(token) {
+            Some(r) => {
+                self.audit(GrantAction::Connected, &r.grant, peer, "");
+                Some(r.grant.clone())
+            },
+            None => None
+        }
+    }
+    // Determines if an upload under a grant is allowed. Anything other than the exact path, or a file that is too large, is refused.
+    pub fn authorize_upload(&self, token: &str, path: &str, size: u64, peer: &str) -> Result<(), String> {
+        let record = match self.find(token) {
+            Some(r) => r,
+            None => return Err(String::from("grant is invalid, expired, or already used"))
+        };
+
+        let result = if record.grant.path() != path {
+            Err(format!("grant only permits uploading to '{}'", record.grant.path()))
+        } else if size > record.grant.max_size() {
+            Err(format!("grant only permits uploads up to {} bytes", record.grant.max_size()))
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = result.as_ref() {
+            self.audit(GrantAction::Rejected, &record.grant, peer, e);
+        }
+        result
+    }
+    pub fn complete(&mut self, token: &str, peer: &str) -> bool {
+        let grant = match self.records.iter_mut().find(|x| x.grant.token() == token && !x.used) {
+            Some(r) => {
+                r.used = true;
+                r.grant.clone()
+            },
+            None => return false
+        };
+
+        self.audit(GrantAction::Used, &grant, peer, "");
+        true
+    }
+    pub fn revoke(&mut self, token: &str, actor: &str) -> bool {
+        let index = match self.records.iter().position(|x| x.grant.token() == token) {
+            Some(i) => i,
+            None => return false
+        };
+
+        let record = self.records.remove(index);
+        self.audit(GrantAction::Revoked, &record.grant, actor, "");
+        true
+    }
+    // The user who issued a grant, who also owns whatever is uploaded with it
+    pub fn owner_of(&self, token: &str) -> Option<&str> {
+        self.records.iter().find(|x| x.grant.token() == token).map(|x| x.owner.as_str())
+    }
+    pub fn grants_of(&self, owner: &str) -> Vec<&UploadGrant> {
+        self.records.iter().filter(|x| x.owner == owner && !x.used).map(|x| &x.grant).collect()
+    }
+}
+
+#[test]
+fn test_grant_lifecycle() {
+    let owner = Credentials::from("owner", "pass");
+    let mut store = GrantStore::new();
+
+    assert!(store.issue(&owner, String::from("a.txt"), 0, Duration::from_secs(60)).is_err());
+    assert!(store.issue(&owner, String::from("a.txt"), 10, MAX_GRANT_TTL * 2).is_err());
+
+    let grant = store.issue(&owner, String::from("incoming/a.txt"), 100, Duration::from_secs(60)).unwrap();
+    assert!(store.authorize_connect(grant.token(), "peer").is_some());
+    assert!(store.authorize_upload(grant.token(), "incoming/b.txt", 10, "peer").is_err());
+    assert!(store.authorize_upload(grant.token(), "incoming/a.txt", 101, "peer").is_err());
+    assert!(store.authorize_upload(grant.token(), "incoming/a.txt", 100, "peer").is_ok());
+
+    assert!(store.complete(grant.token(), "peer"));
+    assert!(store.authorize_connect(grant.token(), "peer").is_none());
+    assert!(!store.complete(grant.token(), "peer"));
+}
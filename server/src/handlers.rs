@@ -0,0 +1,1836 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::{ServerConfig, SymlinkPolicy};
+use crate::credentials::{Credentials, UserDatabase};
+use crate::grants::GrantStore;
+use crate::shares::ShareDatabase;
+use crate::io_loc::{root_directory, is_internal_path};
+use crate::io_tools::{move_relative, is_path_valid, resolve_path, FileDatabase, FileMutation};
+use crate::resume::ResumeTokenStore;
+use crate::quota::QuotaManager;
+use crate::uploads::{UploadPolicy, least_upload_size};
+use crate::staging::staging_path;
+use crate::trash::TrashBin;
+use crate::hooks::HookRefusal;
+use crate::thumbnails::{ThumbnailCache, DEFAULT_THUMBNAIL_SIZE};
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, checksum_file};
+use hermes_common::delta::{Signature, write_delta_file};
+use hermes_common::archive::{pack_directory, list_archive, unpack_archive};
+use hermes_common::file_io::{DirectoryContent, DirectoryInfo, Encryption, FileInfo, FileType, Provenance, detect_file_type, get_file_type, FileChunkIter};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, extract_connect_message, extract_resume_connect_message, extract_grant_connect_message, connect_ack_message, extract_grant_request_message, grant_message_response};
+use hermes_common::messages::{extract_upload_provenance, extract_upload_encryption, extract_delta, extract_stat_request_message, stat_message_response};
+use hermes_common::messages::{extract_delete_message, extract_change_dir_message, extract_subfolder_message, dir_message_response, SubfolderAction};
+use hermes_common::messages::{extract_dir_request_message, dir_page_total, DirQuery};
+use hermes_common::messages::{extract_can_i_request, can_i_response, attach_removed_count, IntendedOperation};
+use hermes_common::messages::{extract_rename_message, extract_move_message, extract_copy_message, extract_share_message, Permission};
+use hermes_common::messages::{extract_user_admin_message, UserAdminAction};
+use hermes_common::messages::{extract_share_connect_message, extract_guest_connect_message, extract_share_link_request, share_link_response, ShareLinkAction};
+use hermes_common::messages::{extract_restore_message, extract_purge_trash_message};
+use hermes_common::messages::{extract_versions_message, versions_response, VersionInfo};
+use hermes_common::messages::{extract_signature_message, signature_response, extract_download_response_message, attach_delta};
+use hermes_common::messages::{extract_preview_message, preview_response, FilePreview, extract_thumbnail_message, thumbnail_response};
+use hermes_common::messages::extract_subscribe_message;
+use hermes_common::messages::{extract_upload_dir_message, upload_dir_response, extract_download_dir_message, download_dir_response};
+use hermes_common::messages::{extract_heartbeat_message, heartbeat_response, extract_diagnostics_message, extract_ping_message, pong_message};
+use hermes_common::messages::{extract_hold_message, extract_upload_message, upload_message_response, extract_download_request_message, download_message_response, ack_messsage, MessageDirection, DownloadResponse};
+use hermes_common::session::{ShareLink, UploadGrant, unix_now};
+use hermes_common::chunking::ChunkWriter;
+use hermes_common::codec::Codec;
+use hermes_common::protocol::{ProtocolVersion, CURRENT_PROTOCOL_VERSION, MIN_SUPPORTED_PROTOCOL_VERSION};
+
+// Converts a path sent by a client into a path on the server, refusing anything outside of the root directory.
+// The result is normalized, so it can be compared against the paths in the file database.
+pub fn resolve_target(raw_path: &str, curr_dir: &Path) -> Option<PathBuf> {
+    move_relative(raw_path, curr_dir).filter(|x| is_path_valid(x)).map(|x| normalize(&x))
+}
+
+fn negotiate_version(version: Option<ProtocolVersion>) -> Result<ProtocolVersion, Message> {
+    match version.and_then(|v| CURRENT_PROTOCOL_VERSION.negotiate(&v)) {
+        Some(v) => Ok(v),
+        None => {
+            let client_version = match version {
+                Some(v) => v.to_string(),
+                None => String::from("unversioned")
+            };
+
+            Err(
+                connect_ack_message(
+                    HttpCodes::VersionNotSupported,
+                    Some(format!("unsupported protocol version '{}', server supports {} to {}", client_version, MIN_SUPPORTED_PROTOCOL_VERSION, CURRENT_PROTOCOL_VERSION)),
+                    None,
+                    None
+                )
+            )
+        }
+    }
+}
+
+// Who a connection is acting as, once its Connect has been accepted
+#[derive(Clone, PartialEq, Debug)]
+pub enum SessionIdentity {
+    User(String),
+    Grant(UploadGrant),
+    Share(ShareLink),
+    Guest //Anonymous, read-only, and jailed to the public folder
+}
+
+// Validates the protocol version and the credentials (or resumption token) of a Connect request, returning the Ack to send back.
+// The Ack echoes the negotiated protocol version and carries a fresh resumption token only when the connection is accepted.
+// Guests are only let in when the server has a public folder for them.
+pub fn handle_connect(message: Message, users: &UserDatabase, resume: &mut ResumeTokenStore, grants: &GrantStore, shares: &ShareDatabase, guests: bool, peer: &str) -> (Message, Option<SessionIdentity>) {
+    if message.extract("token").is_some() {
+        return handle_resume_connect(message, users, resume);
+    }
+    if message.extract("grant").is_some() {
+        return handle_grant_connect(message, grants, peer);
+    }
+    if message.extract("share").is_some() {
+        return handle_share_connect(message, shares, peer);
+    }
+    if message.extract("guest").is_some() {
+        return handle_guest_connect(message, guests, peer);
+    }
+
+    let (username, password, version) = match extract_connect_message(message) {
+        Some(v) => v,
+        None => return (connect_ack_message(HttpCodes::BadRequest, Some(String::from("malformed connect request")), None, None), None)
+    };
+
+    let negotiated = match negotiate_version(version) {
+        Ok(v) => v,
+        Err(m) => return (m, None)
+    };
+
+    match (users.validate_user(&username, &password), users.get_user(&username)) {
+        (Some(true), Some(user)) if user.is_disabled() => (connect_ack_message(HttpCodes::Forbidden, Some(String::from("this account is disabled")), None, None), None),
+        (Some(true), Some(user)) => (
+            connect_ack_message(HttpCodes::Ok, None, Some(negotiated), resume.issue(user)),
+            Some(SessionIdentity::User(username))
+        ),
+        _ => (connect_ack_message(HttpCodes::Unauthorized, Some(String::from("invalid username or password")), None, None), None)
+    }
+}
+fn handle_resume_connect(message: Message, users: &UserDatabase, resume: &mut ResumeTokenStore) -> (Message, Option<SessionIdentity>) {
+    let (token, version) = match extract_resume_connect_message(message) {
+        Some(v) => v,
+        None => return (connect_ack_message(HttpCodes::BadRequest, Some(String::from("malformed connect request")), None, None), None)
+    };
+
+    let negotiated = match negotiate_version(version) {
+        Ok(v) => v,
+        Err(m) => return (m, None)
+    };
+
+    // Tokens are single use, so a successful resume rotates to a new token
+    let user = match resume.redeem(&token, users).and_then(|u| users.get_user(&u)).filter(|u| !u.is_disabled()) {
+        Some(u) => u,
+        None => return (connect_ack_message(HttpCodes::Unauthorized, Some(String::from("resumption token is invalid or expired")), None, None), None)
+    };
+
+    (
+        connect_ack_message(HttpCodes::Ok, None, Some(negotiated), resume.issue(user)),
+        Some(SessionIdentity::User(user.username().to_string()))
+    )
+}
+
+// A grant connection is not tied to any user, and may only perform the single upload the grant describes
+fn handle_grant_connect(message: Message, grants: &GrantStore, peer: &str) -> (Message, Option<SessionIdentity>) {
+    let (token, version) = match extract_grant_connect_message(message) {
+        Some(v) => v,
+        None => return (connect_ack_message(HttpCodes::BadRequest, Some(String::from("malformed connect request")), None, None), None)
+    };
+
+    let negotiated = match negotiate_version(version) {
+        Ok(v) => v,
+        Err(m) => return (m, None)
+    };
+
+    match grants.authorize_connect(&token, peer) {
+        Some(g) => (
+            connect_ack_message(HttpCodes::Ok, Some(format!("connected with {}", g)), Some(negotiated), None),
+            Some(SessionIdentity::Grant(g))
+        ),
+        None => (connect_ack_message(HttpCodes::Unauthorized, Some(String::from("upload grant is invalid, expired, or already used")), None, None), None)
+    }
+}
+
+// Like a grant connection, a share link connection belongs to no user. It may only download the one file the link names.
+fn handle_share_connect(message: Message, shares: &ShareDatabase, peer: &str) -> (Message, Option<SessionIdentity>) {
+    let (token, version) = match extract_share_connect_message(message) {
+        Some(v) => v,
+        None => return (connect_ack_message(HttpCodes::BadRequest, Some(String::from("malformed connect request")), None, None), None)
+    };
+
+    let negotiated = match negotiate_version(version) {
+        Ok(v) => v,
+        Err(m) => return (m, None)
+    };
+
+    match shares.authorize_connect(&token) {
+        Some(l) => {
+            tracing::info!(peer, path = l.path(), "connected with a share link");
+            (
+                connect_ack_message(HttpCodes::Ok, Some(format!("connected with {}", l)), Some(negotiated), None),
+                Some(SessionIdentity::Share(l))
+            )
+        },
+        None => (connect_ack_message(HttpCodes::Unauthorized, Some(String::from("share link is invalid or expired")), None, None), None)
+    }
+}
+
+fn handle_guest_connect(message: Message, guests: bool, peer: &str) -> (Message, Option<SessionIdentity>) {
+    let version = match extract_guest_connect_message(message) {
+        Some(v) => v,
+        None => return (connect_ack_message(HttpCodes::BadRequest, Some(String::from("malformed connect request")), None, None), None)
+    };
+
+    let negotiated = match negotiate_version(version) {
+        Ok(v) => v,
+        Err(m) => return (m, None)
+    };
+
+    if !guests {
+        return (connect_ack_message(HttpCodes::Unauthorized, Some(String::from("this server does not allow guests")), None, None), None);
+    }
+    tracing::info!(peer, "connected as a guest");
+    (
+        connect_ack_message(HttpCodes::Ok, Some(String::from("connected as a read-only guest")), Some(negotiated), None),
+        Some(SessionIdentity::Guest)
+    )
+}
+
+pub fn handle_grant_request(message: Message, user: &Credentials, curr_dir: &Path, grants: &mut GrantStore) -> Message {
+    let (path, max_size, ttl) = match extract_grant_request_message(message) {
+        Some(v) => v,
+        None => return grant_message_response(HttpCodes::BadRequest, "malformed grant request", None)
+    };
+
+    let root = root_directory();
+    let relative = match resolve_target(&path, curr_dir).and_then(|x| x.strip_prefix(&root).map(|p| p.to_path_buf()).ok()) {
+        Some(p) => p,
+        None => return grant_message_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+    let relative = match relative.to_str() {
+        Some(s) => s.to_string(),
+        None => return grant_message_response(HttpCodes::BadRequest, "path could not be expressed as a string", None)
+    };
+
+    match grants.issue(user, relative, max_size, Duration::from_secs(ttl)) {
+        Ok(g) => grant_message_response(HttpCodes::Ok, "grant issued", Some(g)),
+        Err(e) => grant_message_response(HttpCodes::BadRequest, &e, None)
+    }
+}
+
+// Creates a link to a file the user can read, or revokes one. The link names the file relative to the root, so it does not depend on the user's working directory.
+pub fn handle_share_link_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase, shares: &mut ShareDatabase) -> Message {
+    let (path, ttl) = match extract_share_link_request(message) {
+        Some(ShareLinkAction::Create { path, ttl_secs }) => (path, ttl_secs),
+        Some(ShareLinkAction::Revoke(token)) => return match shares.revoke(&token, user) {
+            Ok(()) => share_link_response(HttpCodes::Ok, "share link revoked", None),
+            Err(e) => share_link_response(HttpCodes::Forbidden, &e, None)
+        },
+        None => return share_link_response(HttpCodes::BadRequest, "malformed share link request", None)
+    };
+
+    let root = root_directory();
+    let target = match resolve_target(&path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return share_link_response(HttpCodes::NotFound, "file not found", None),
+        None => return share_link_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+    if let Err(e) = files.check_access(&target, Some(user), Permission::Read) {
+        return share_link_response(HttpCodes::Forbidden, &e.to_string(), None);
+    }
+    let relative = match target.strip_prefix(&root).ok().and_then(|x| x.to_str()) {
+        Some(s) => s.to_string(),
+        None => return share_link_response(HttpCodes::BadRequest, "path could not be expressed as a string", None)
+    };
+
+    match shares.issue(user, relative, Duration::from_secs(ttl)) {
+        Ok(l) => share_link_response(HttpCodes::Ok, "share link issued", Some(&l)),
+        Err(e) => share_link_response(HttpCodes::BadRequest, &e, None)
+    }
+}
+
+pub struct UploadPlan {
+    pub path: PathBuf,
+    pub staging: PathBuf, //Where the data is written until it is complete
+    pub kind: FileType,
+    pub frame_count: u64,
+    pub offset: u64,
+    pub checksum: Option<Checksum>,
+    pub provenance: Option<Provenance>,
+    pub encryption: Option<Encryption>
+}
+
+// Decides where an upload will be written. Resuming is only allowed from exactly the number of bytes the server already holds, so nothing is skipped or written twice.
+// Those bytes are in the staging file, since the destination is only replaced once the upload is complete.
+// Files the upload policy does not allow are refused here, before any frames are sent, going by what the request says of them.
+pub fn handle_upload_request(message: Message, user: Option<&Credentials>, curr_dir: &Path, files: &FileDatabase, quotas: &QuotaManager, policy: &UploadPolicy) -> (Message, Option<UploadPlan>) {
+    let provenance = extract_upload_provenance(&message);
+    let encryption = extract_upload_encryption(&message);
+    let delta = extract_delta(&message);
+    let (name, kind, frame_count, offset, checksum) = match extract_upload_message(message) {
+        Some(v) => v,
+        None => return (upload_message_response(HttpCodes::BadRequest, "malformed upload request", 0), None)
+    };
+
+    let path = match resolve_target(&name, curr_dir) {
+        Some(p) => p,
+        None => return (upload_message_response(HttpCodes::Forbidden, "path is outside of the server's root directory", 0), None)
+    };
+
+    let staging = staging_path(&path);
+    // An upload does not say its size up front, so until the data arrives it is only refused when the quota is already used up
+    if let Err((status, reason)) = check_upload(&path, offset, u64::from(frame_count > 0), user, files, quotas) {
+        return (upload_message_response(status, &reason, existing_size(&staging)), None);
+    }
+    // A delta's frames are not the file's, so how large the file will be is only known once the delta is applied
+    let least = if delta { 0 } else { least_upload_size(offset, frame_count) };
+    if let Err((status, reason)) = policy.check(&path, &kind, least) {
+        return (upload_message_response(status, &reason, existing_size(&staging)), None);
+    }
+
+    (
+        upload_message_response(HttpCodes::Ok, "ready", offset),
+        Some(
+            UploadPlan {
+                path,
+                staging,
+                kind,
+                frame_count,
+                offset,
+                checksum,
+                provenance,
+                encryption
+            }
+        )
+    )
+}
+
+fn existing_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|x| if x.is_dir() { 0 } else { x.len() }).unwrap_or(0)
+}
+// What an upload to this path must pass before any data is accepted. CanI runs the same checks, so a dry run never disagrees with the real request.
+// The uploader needs write permission on the file, or on the folder it goes in when the file is new, and room in their quota for the incoming bytes.
+pub fn check_upload(path: &Path, offset: u64, incoming: u64, user: Option<&Credentials>, files: &FileDatabase, quotas: &QuotaManager) -> Result<(), (HttpCodes, String)> {
+    if path.is_dir() {
+        return Err((HttpCodes::Conflict, String::from("path is a directory")));
+    }
+
+    files.check_access(path, user, Permission::Write).map_err(|e| (HttpCodes::Forbidden, e.to_string()))?;
+
+    if path.exists() {
+        check_hold(path, FileMutation::Overwrite, files)?;
+    }
+
+    let existing = existing_size(&staging_path(path));
+    if offset > 0 && offset != existing {
+        return Err((HttpCodes::Conflict, format!("cannot resume at {offset}, server holds {existing} bytes")));
+    }
+
+    match user {
+        Some(u) => quotas.check(u, files, incoming),
+        None => Ok(())
+    }
+}
+// A directory is only deleted when the request is recursive. Holds, or other users' files, anywhere beneath it block the whole delete.
+pub fn check_delete(path: &Path, recursive: bool, user: &Credentials, files: &FileDatabase) -> Result<(), (HttpCodes, String)> {
+    if path == root_directory() {
+        return Err((HttpCodes::Forbidden, String::from("the root directory cannot be deleted")));
+    }
+    if path.is_dir() && !recursive {
+        return Err((HttpCodes::Conflict, String::from("path is a directory, delete it recursively or use subfolder delete instead")));
+    }
+    if std::fs::symlink_metadata(path).is_err() {
+        return Err((HttpCodes::NotFound, String::from("file not found")));
+    }
+
+    files.check_access(path, Some(user), Permission::Delete).map_err(|e| (HttpCodes::Forbidden, e.to_string()))?;
+    check_hold(path, FileMutation::Delete, files)
+}
+// Every change a legal hold blocks is refused the same way, whichever request asked for it
+fn check_hold(path: &Path, mutation: FileMutation, files: &FileDatabase) -> Result<(), (HttpCodes, String)> {
+    files.check_mutation(path, mutation).map_err(|e| (HttpCodes::Forbidden, e.to_string()))
+}
+
+// Removes a file, or a directory and everything beneath it, counting each file and directory as it goes.
+// Symbolic links are removed, never followed.
+fn remove_tree(path: &Path, removed: &mut u64) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            remove_tree(&entry?.path(), removed)?;
+        }
+        std::fs::remove_dir(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+
+    *removed += 1;
+    Ok(())
+}
+// Deletes a path that passed its checks, and forgets the records of everything that went. When the delete stops part way,
+// whatever is left keeps its records, so its owner and access list still protect it.
+// While the trash is enabled, the path is moved into the user's trash instead, and its records go with it.
+fn remove_checked(path: &Path, raw_path: &str, user: &Credentials, files: &mut FileDatabase, trash: &mut TrashBin) -> Message {
+    if trash.is_enabled() {
+        return match trash.discard(path, user, files) {
+            Ok(moved) => attach_removed_count(ack(HttpCodes::Ok, &format!("moved '{raw_path}' to the trash ({moved} entries)")), moved),
+            Err(e) => ack(HttpCodes::Conflict, &format!("unable to move '{raw_path}' to the trash because '{e}'"))
+        };
+    }
+
+    let mut removed = 0;
+    match remove_tree(path, &mut removed) {
+        Ok(_) => {
+            files.unregister_tree(path);
+            attach_removed_count(ack(HttpCodes::Ok, &format!("deleted '{raw_path}' ({removed} entries)")), removed)
+        },
+        Err(e) => {
+            files.unregister_removed(path);
+            attach_removed_count(ack(HttpCodes::Conflict, &format!("stopped after removing {removed} entries because '{e}'")), removed)
+        }
+    }
+}
+
+// Answers whether an upload or delete would be accepted. Returns the target of an upload that passed, since upload grants still have to be checked by the caller.
+// Sessions opened with an upload grant have no user, and can only ask about uploads.
+pub fn handle_can_i_request(message: Message, user: Option<&Credentials>, curr_dir: &Path, files: &FileDatabase, quotas: &QuotaManager, policy: &UploadPolicy) -> (Message, Option<(PathBuf, u64)>) {
+    let operation = match extract_can_i_request(message) {
+        Some(o) => o,
+        None => return (can_i_response(HttpCodes::BadRequest, "malformed can i request"), None)
+    };
+
+    let raw_path = match &operation {
+        IntendedOperation::Upload { path, .. } | IntendedOperation::Delete { path, .. } => path
+    };
+    let path = match resolve_target(raw_path, curr_dir) {
+        Some(p) => p,
+        None => return (can_i_response(HttpCodes::Forbidden, "path is outside of the server's root directory"), None)
+    };
+
+    let result = match &operation {
+        // The kind of file is the one a client would send it as, going by its name
+        IntendedOperation::Upload { size, .. } => check_upload(&path, 0, *size, user, files, quotas)
+            .and_then(|_| policy.check(&path, &get_file_type(&path).unwrap_or(FileType::Binary), *size)),
+        IntendedOperation::Delete { recursive, .. } => match user {
+            Some(u) => check_delete(&path, *recursive, u, files),
+            None => Err((HttpCodes::Forbidden, String::from("upload grants may only upload")))
+        }
+    };
+
+    match (result, operation) {
+        (Err((status, reason)), _) => (can_i_response(status, &reason), None),
+        (Ok(()), IntendedOperation::Upload { size, .. }) => (can_i_response(HttpCodes::Ok, "the upload would be accepted"), Some((path, size))),
+        (Ok(()), IntendedOperation::Delete { .. }) => (can_i_response(HttpCodes::Ok, "the delete would be accepted"), None)
+    }
+}
+
+// Sent once every frame of an upload has been received. The upload is only acknowledged if the whole file matches the checksum the client promised.
+pub fn complete_upload(plan: &UploadPlan, received: Result<Checksum, String>) -> Message {
+    match (received, plan.checksum.as_ref()) {
+        (Err(e), _) => ack_messsage(MessageDirection::Response, HttpCodes::Conflict, Some(e)),
+        (Ok(actual), Some(expected)) if actual != *expected => ack_messsage(MessageDirection::Response, HttpCodes::Conflict, Some(format!("checksum mismatch, expected '{}' but received '{}'", expected, actual))),
+        (Ok(actual), _) => ack_messsage(MessageDirection::Response, HttpCodes::Ok, Some(format!("upload complete ({actual})")))
+    }
+}
+
+// Opens the file for a download, starting at the requested offset so that resumed downloads only receive what they are missing.
+// The contents are streamed from disk by the caller after the response is sent.
+pub fn handle_download_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase) -> (Message, Option<FileChunkIter>) {
+    let (raw_path, offset, length) = match extract_download_request_message(message) {
+        Some(v) => v,
+        None => return (download_message_response(DownloadResponse::failure(HttpCodes::BadRequest, "malformed download request")), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return (download_message_response(DownloadResponse::failure(HttpCodes::NotFound, "file not found")), None),
+        None => return (download_message_response(DownloadResponse::failure(HttpCodes::Forbidden, "path is outside of the server's root directory")), None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return (download_message_response(DownloadResponse::failure(HttpCodes::Forbidden, &e.to_string())), None);
+    }
+
+    let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+    let chunks = match FileChunkIter::open(&path, offset, length) {
+        Ok(c) => c,
+        Err(e) => return (download_message_response(DownloadResponse::failure(HttpCodes::Conflict, &e)), None)
+    };
+
+    let checksum = checksum_file(&path, ChecksumAlgorithm::Sha256).ok();
+
+    (
+        download_message_response(
+            DownloadResponse {
+                status: HttpCodes::Ok,
+                message: String::from("ok"),
+                kind,
+                frame_count: chunks.frame_count(),
+                offset,
+                length: chunks.remaining(),
+                checksum
+            }
+        ),
+        Some(chunks)
+    )
+}
+
+// Turns an accepted download into a delta against the client's copy, written to scratch so its size is known before it is sent
+pub fn delta_download(response: Message, mut chunks: FileChunkIter, signature: &Signature, scratch: &Path) -> Result<(Message, FileChunkIter), String> {
+    let mut details = extract_download_response_message(response).ok_or_else(|| String::from("malformed download response"))?;
+    if let Some(parent) = scratch.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    write_delta_file(signature, &mut chunks, scratch)?;
+    let delta = FileChunkIter::open(scratch, 0, None)?;
+    details.frame_count = delta.frame_count();
+    Ok((attach_delta(download_message_response(details)), delta))
+}
+
+// Packs a directory into an archive at scratch, leaving out partial uploads and anything the user cannot read. The user needs read permission on the directory itself.
+pub fn handle_download_dir_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase, scratch: &Path) -> (Message, Option<FileChunkIter>) {
+    let raw_path = match extract_download_dir_message(message) {
+        Some(p) => p,
+        None => return (download_dir_response(HttpCodes::BadRequest, "malformed download dir request", 0, None), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_dir() => p,
+        Some(_) => return (download_dir_response(HttpCodes::NotFound, "directory not found", 0, None), None),
+        None => return (download_dir_response(HttpCodes::Forbidden, "path is outside of the server's root directory", 0, None), None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return (download_dir_response(HttpCodes::Forbidden, &e.to_string(), 0, None), None);
+    }
+
+    if let Some(parent) = scratch.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            return (download_dir_response(HttpCodes::Conflict, &e.to_string(), 0, None), None);
+        }
+    }
+    let include = |x: &Path| !is_internal_path(x) && files.check_access(x, Some(user), Permission::Read).is_ok();
+    let packed = pack_directory(&path, scratch, include).and_then(|_| FileChunkIter::open(scratch, 0, None));
+    let chunks = match packed {
+        Ok(c) => c,
+        Err(e) => return (download_dir_response(HttpCodes::Conflict, &format!("unable to pack the directory because '{e}'"), 0, None), None)
+    };
+
+    let checksum = checksum_file(scratch, ChecksumAlgorithm::Sha256).ok();
+    (download_dir_response(HttpCodes::Ok, "ok", chunks.frame_count(), checksum), Some(chunks))
+}
+
+pub struct DirUploadPlan {
+    pub path: PathBuf,
+    pub staging: PathBuf, //Where the archive is unpacked before it is moved into place
+    pub frame_count: u64,
+    pub checksum: Option<Checksum>
+}
+
+// A directory upload makes a new directory, so nothing already there has to be merged with it or overwritten.
+// Like a file upload, its size is not known up front, so the quota is only checked properly once the archive has arrived.
+pub fn handle_upload_dir_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase, quotas: &QuotaManager) -> (Message, Option<DirUploadPlan>) {
+    let (raw_path, frame_count, checksum) = match extract_upload_dir_message(message) {
+        Some(v) => v,
+        None => return (upload_dir_response(HttpCodes::BadRequest, "malformed upload dir request"), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p != root_directory() => p,
+        _ => return (upload_dir_response(HttpCodes::Forbidden, "path is outside of the server's root directory"), None)
+    };
+    if std::fs::symlink_metadata(&path).is_ok() {
+        return (upload_dir_response(HttpCodes::Conflict, "path already exists"), None);
+    }
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Write) {
+        return (upload_dir_response(HttpCodes::Forbidden, &e.to_string()), None);
+    }
+    if let Err((status, reason)) = quotas.check(user, files, u64::from(frame_count > 0)) {
+        return (upload_dir_response(status, &reason), None);
+    }
+
+    let staging = staging_path(&path);
+    (upload_dir_response(HttpCodes::Ok, "ready"), Some(DirUploadPlan { path, staging, frame_count, checksum }))
+}
+
+// Unpacks a received archive beside its destination, then moves it into place and registers everything in it to the uploader.
+// Each file is screened with where it will go and where it is unpacked, as uploads are scanned and passed to the hooks, and any refusal throws the whole archive away.
+// Returns the files that were placed, which is none unless the upload succeeded.
+pub fn unpack_dir_upload(plan: &DirUploadPlan, archive: &Path, user: &Credentials, files: &mut FileDatabase, quotas: &QuotaManager, screen: &dyn Fn(&Path, &Path, &mut FileDatabase) -> Result<(), HookRefusal>) -> (Message, Vec<PathBuf>) {
+    let contents = match list_archive(archive) {
+        Ok(c) => c,
+        Err(e) => return (ack(HttpCodes::BadRequest, &format!("the archive is unusable because '{e}'")), Vec::new())
+    };
+    if let Err((status, reason)) = quotas.check(user, files, contents.bytes) {
+        return (ack(status, &reason), Vec::new());
+    }
+
+    let _ = std::fs::remove_dir_all(&plan.staging);
+    if let Err(e) = unpack_archive(archive, &plan.staging) {
+        let _ = std::fs::remove_dir_all(&plan.staging);
+        return (ack(HttpCodes::Conflict, &format!("unable to unpack the archive because '{e}'")), Vec::new());
+    }
+    let screened = contents.files.iter().try_for_each(|x| screen(&plan.path.join(x), &plan.staging.join(x), files));
+    if let Err((status, reason)) = screened {
+        let _ = std::fs::remove_dir_all(&plan.staging);
+        return (ack(status, &reason), Vec::new());
+    }
+    let placed = match plan.path.exists() {
+        true => Err(String::from("path was created while the archive was sent")),
+        false => std::fs::rename(&plan.staging, &plan.path).map_err(|e| e.to_string())
+    };
+    if let Err(e) = placed {
+        let _ = std::fs::remove_dir_all(&plan.staging);
+        return (ack(HttpCodes::Conflict, &format!("unable to unpack the archive because '{e}'")), Vec::new());
+    }
+
+    // Folders have no file type of their own, like those made through Subfolder
+    let _ = files.register_file(plan.path.clone(), Some(user.clone()), FileType::Binary);
+    for dir in contents.directories.iter() {
+        let _ = files.register_file(plan.path.join(dir), Some(user.clone()), FileType::Binary);
+    }
+    let mut placed = Vec::with_capacity(contents.files.len());
+    for file in contents.files.iter() {
+        let path = plan.path.join(file);
+        let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+        let _ = files.register_file(path.clone(), Some(user.clone()), kind);
+        placed.push(path);
+    }
+
+    (ack(HttpCodes::Ok, &format!("unpacked {} files ({} bytes)", contents.files.len(), contents.bytes)), placed)
+}
+
+// A directory can be watched by anyone who can read it. Returns the directory, and whether the subscription is being dropped.
+pub fn handle_subscribe_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase) -> (Message, Option<(PathBuf, bool)>) {
+    let (raw_path, unsubscribe) = match extract_subscribe_message(message) {
+        Some(v) => v,
+        None => return (ack(HttpCodes::BadRequest, "malformed subscribe request"), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_dir() || unsubscribe => p,
+        Some(_) => return (ack(HttpCodes::NotFound, "directory not found"), None),
+        None => return (ack(HttpCodes::Forbidden, "path is outside of the server's root directory"), None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return (ack(HttpCodes::Forbidden, &e.to_string()), None);
+    }
+
+    match unsubscribe {
+        true => (ack(HttpCodes::Ok, &format!("stopped watching '{raw_path}'")), Some((path, true))),
+        false => (ack(HttpCodes::Ok, &format!("watching '{raw_path}'")), Some((path, false)))
+    }
+}
+
+// Sends the signature of a file, so the client can upload only what changed. It needs read permission, like a download.
+pub fn handle_signature_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase) -> Message {
+    let raw_path = match extract_signature_message(message) {
+        Some(p) => p,
+        None => return signature_response(HttpCodes::BadRequest, "malformed signature request", None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return signature_response(HttpCodes::NotFound, "file not found", None),
+        None => return signature_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return signature_response(HttpCodes::Forbidden, &e.to_string(), None);
+    }
+
+    match Signature::of_file(&path) {
+        Ok(s) => signature_response(HttpCodes::Ok, "ok", Some(&s)),
+        Err(e) => signature_response(HttpCodes::Conflict, &e.to_string(), None)
+    }
+}
+
+// However the limit is counted, a preview never holds more than this, so it always fits in its response
+pub const MAX_PREVIEW_SIZE: u64 = 64 * 1024;
+
+// Sends the first lines of a text file, or the first bytes of any other, in the response itself. It needs read permission, like a download.
+pub fn handle_preview_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase) -> Message {
+    let (raw_path, limit) = match extract_preview_message(message) {
+        Some(v) => v,
+        None => return preview_response(HttpCodes::BadRequest, "malformed preview request", None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return preview_response(HttpCodes::NotFound, "file not found", None),
+        None => return preview_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return preview_response(HttpCodes::Forbidden, &e.to_string(), None);
+    }
+
+    let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+    match read_preview(&path, &kind, limit) {
+        Ok((content, truncated)) => preview_response(HttpCodes::Ok, "ok", Some(&FilePreview { kind, content, truncated })),
+        Err(e) => preview_response(HttpCodes::Conflict, &e.to_string(), None)
+    }
+}
+// Text is cut after its limit'th line, and anything else after limit bytes. Either way it stops at MAX_PREVIEW_SIZE.
+fn read_preview(path: &Path, kind: &FileType, limit: u64) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?).take(MAX_PREVIEW_SIZE);
+    let mut content = Vec::new();
+    match kind {
+        FileType::Text => {
+            for _ in 0..limit {
+                if reader.read_until(b'\n', &mut content)? == 0 {
+                    break;
+                }
+            }
+        },
+        _ => {
+            (&mut reader).take(limit).read_to_end(&mut content)?;
+        }
+    }
+
+    let mut rest = reader.into_inner();
+    let truncated = !rest.fill_buf()?.is_empty();
+    Ok((content, truncated))
+}
+
+// Sends a small PNG of a media file, made the first time it is asked for. It needs read permission, like a download.
+pub fn handle_thumbnail_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase, cache: &ThumbnailCache) -> Message {
+    let (raw_path, size) = match extract_thumbnail_message(message) {
+        Some(v) => v,
+        None => return thumbnail_response(HttpCodes::BadRequest, "malformed thumbnail request", None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return thumbnail_response(HttpCodes::NotFound, "file not found", None),
+        None => return thumbnail_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return thumbnail_response(HttpCodes::Forbidden, &e.to_string(), None);
+    }
+
+    let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+    match cache.thumbnail(&path, &kind, size.unwrap_or(DEFAULT_THUMBNAIL_SIZE)) {
+        Ok(png) => thumbnail_response(HttpCodes::Ok, "ok", Some(&png)),
+        Err(e) => thumbnail_response(e.status(), &e.to_string(), None)
+    }
+}
+
+// Lists the versions kept for a file, or starts sending one of them the way a download would. Reading a file's versions needs read permission on the file.
+pub fn handle_versions_request(message: Message, user: &Credentials, curr_dir: &Path, files: &FileDatabase) -> (Message, Option<FileChunkIter>) {
+    let (raw_path, number) = match extract_versions_message(message) {
+        Some(v) => v,
+        None => return (versions_response(HttpCodes::BadRequest, "malformed versions request", &[]), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) => p,
+        None => return (versions_response(HttpCodes::Forbidden, "path is outside of the server's root directory", &[]), None)
+    };
+    let file = match files.get_file_by_path(&path) {
+        Some(f) => f,
+        None => return (versions_response(HttpCodes::NotFound, "file not found", &[]), None)
+    };
+    if let Err(e) = files.check_access(&path, Some(user), Permission::Read) {
+        return (versions_response(HttpCodes::Forbidden, &e.to_string(), &[]), None);
+    }
+
+    let number = match number {
+        Some(n) => n,
+        None => {
+            let versions: Vec<VersionInfo> = file.versions().iter().rev().map(|x| x.info()).collect();
+            return (versions_response(HttpCodes::Ok, &format!("{} versions", versions.len()), &versions), None);
+        }
+    };
+
+    let stored = match file.version(number) {
+        Some(v) => v.stored().to_path_buf(),
+        None => return (download_message_response(DownloadResponse::failure(HttpCodes::NotFound, &format!("version {number} not found"))), None)
+    };
+    let chunks = match FileChunkIter::open(&stored, 0, None) {
+        Ok(c) => c,
+        Err(e) => return (download_message_response(DownloadResponse::failure(HttpCodes::Conflict, &e)), None)
+    };
+
+    (
+        download_message_response(
+            DownloadResponse {
+                status: HttpCodes::Ok,
+                message: format!("version {number}"),
+                kind: file.file_type().clone(),
+                frame_count: chunks.frame_count(),
+                offset: 0,
+                length: chunks.remaining(),
+                checksum: checksum_file(&stored, ChecksumAlgorithm::Sha256).ok()
+            }
+        ),
+        Some(chunks)
+    )
+}
+
+pub fn handle_hold_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase) -> Message {
+    let (raw_path, hold) = match extract_hold_message(message) {
+        Some(v) => v,
+        None => return ack_messsage(MessageDirection::Response, HttpCodes::BadRequest, Some(String::from("malformed hold request")))
+    };
+
+    if !user.is_admin() {
+        return ack_messsage(MessageDirection::Response, HttpCodes::Forbidden, Some(String::from("only administrators can change legal holds")));
+    }
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return ack_messsage(MessageDirection::Response, HttpCodes::NotFound, Some(String::from("file not found"))),
+        None => return ack_messsage(MessageDirection::Response, HttpCodes::Forbidden, Some(String::from("path is outside of the server's root directory")))
+    };
+
+    // Files that were never indexed still need a record to carry the hold
+    let id = match files.get_file_id(&path) {
+        Some(id) => id,
+        None => {
+            let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+            match files.register_file(path.clone(), None, kind) {
+                Ok(id) => id,
+                Err(e) => return ack_messsage(MessageDirection::Response, e.status(), Some(e.to_string()))
+            }
+        }
+    };
+
+    match files.set_immutable(id, hold) {
+        Ok(_) => ack_messsage(MessageDirection::Response, HttpCodes::Ok, Some(format!("legal hold {} on '{}'", if hold { "placed" } else { "cleared" }, raw_path))),
+        Err(e) => ack_messsage(MessageDirection::Response, e.status(), Some(e.to_string()))
+    }
+}
+
+// Describes a single file, including where it was uploaded from when the uploader said so
+pub fn handle_stat_request(message: Message, curr_dir: &Path, files: &FileDatabase) -> Message {
+    let raw_path = match extract_stat_request_message(message) {
+        Some(p) => p,
+        None => return stat_message_response(HttpCodes::BadRequest, "malformed stat request", None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) => p,
+        None => return stat_message_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)
+    };
+
+    let metadata = match std::fs::metadata(&path) {
+        Ok(m) if m.is_file() => m,
+        _ => return stat_message_response(HttpCodes::NotFound, "file not found", None)
+    };
+
+    let info = match files.get_file_by_path(&path).and_then(|f| f.file_info()) {
+        Some(i) => i,
+        None => FileInfo::from_disk(&path, &metadata)
+    };
+
+    stat_message_response(HttpCodes::Ok, "ok", Some(info))
+}
+
+fn ack(code: HttpCodes, message: &str) -> Message {
+    ack_messsage(MessageDirection::Response, code, Some(message.to_string()))
+}
+
+// Removes a single file. Folders are removed through Subfolder.
+pub fn handle_delete_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase, trash: &mut TrashBin) -> Message {
+    let (raw_path, recursive) = match extract_delete_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed delete request")
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) => p,
+        None => return ack(HttpCodes::Forbidden, "path is outside of the server's root directory")
+    };
+
+    if let Err((status, reason)) = check_delete(&path, recursive, user, files) {
+        return ack(status, &reason);
+    }
+
+    remove_checked(&path, &raw_path, user, files, trash)
+}
+
+pub fn handle_restore_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase, trash: &mut TrashBin) -> Message {
+    let raw_path = match extract_restore_message(message) {
+        Some(p) => p,
+        None => return ack(HttpCodes::BadRequest, "malformed restore request")
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) => p,
+        None => return ack(HttpCodes::Forbidden, "path is outside of the server's root directory")
+    };
+
+    match trash.restore(&path, user, files) {
+        Ok(entries) => ack(HttpCodes::Ok, &format!("restored '{raw_path}' ({entries} entries)")),
+        Err((status, reason)) => ack(status, &reason)
+    }
+}
+
+// Purging only ever touches the sender's own trash
+pub fn handle_purge_trash_request(message: Message, user: &Credentials, curr_dir: &Path, trash: &mut TrashBin) -> Message {
+    let raw_path = match extract_purge_trash_message(message) {
+        Some(p) => p,
+        None => return ack(HttpCodes::BadRequest, "malformed purge trash request")
+    };
+
+    let under = match raw_path.as_deref().map(|x| resolve_target(x, curr_dir)) {
+        None => None,
+        Some(Some(p)) => Some(p),
+        Some(None) => return ack(HttpCodes::Forbidden, "path is outside of the server's root directory")
+    };
+
+    let report = trash.purge(user, under.as_deref());
+    let purged = report.purged.len() as u64;
+    attach_removed_count(ack(HttpCodes::Ok, &format!("purged {purged} deletes from the trash ({} bytes)", report.reclaimed_bytes)), purged)
+}
+
+// Changes the working directory of a connection, returning the new directory when it is allowed
+pub fn handle_change_dir_request(message: Message, curr_dir: &Path) -> (Message, Option<PathBuf>) {
+    let raw_path = match extract_change_dir_message(message) {
+        Some(p) => p,
+        None => return (ack(HttpCodes::BadRequest, "malformed change dir request"), None)
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir).and_then(resolve_path).filter(|x| is_path_valid(x)) {
+        Some(p) => p,
+        None => return (ack(HttpCodes::Forbidden, "path is outside of the server's root directory"), None)
+    };
+
+    if !path.is_dir() {
+        return (ack(HttpCodes::NotFound, "directory not found"), None);
+    }
+
+    let display = display_path(&path);
+    (ack(HttpCodes::Ok, &display), Some(path))
+}
+
+pub fn handle_subfolder_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase, trash: &mut TrashBin) -> Message {
+    let (raw_path, action, recursive) = match extract_subfolder_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed subfolder request")
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p != root_directory() => p,
+        _ => return ack(HttpCodes::Forbidden, "path is outside of the server's root directory")
+    };
+
+    match action {
+        SubfolderAction::Add => {
+            if path.exists() {
+                return ack(HttpCodes::Conflict, "path already exists");
+            }
+            if let Err(e) = files.check_access(&path, Some(user), Permission::Write) {
+                return ack(HttpCodes::Forbidden, &e.to_string());
+            }
+
+            if let Err(e) = std::fs::create_dir(&path) {
+                return ack(HttpCodes::Conflict, &e.to_string());
+            }
+
+            // The folder belongs to whoever made it, so they can share it. Folders have no file type of their own.
+            match files.register_file(path.clone(), Some(user.clone()), FileType::Binary) {
+                Ok(_) => ack(HttpCodes::Ok, &format!("created '{raw_path}'")),
+                Err(e) => ack(e.status(), &e.to_string())
+            }
+        },
+        SubfolderAction::Delete => {
+            if !path.is_dir() {
+                return ack(HttpCodes::NotFound, "directory not found");
+            }
+            if let Err((status, reason)) = check_delete(&path, true, user, files) {
+                return ack(status, &reason);
+            }
+            if !recursive && std::fs::read_dir(&path).map(|mut x| x.next().is_some()).unwrap_or(false) {
+                return ack(HttpCodes::Conflict, "directory is not empty, delete it recursively to remove its contents");
+            }
+
+            remove_checked(&path, &raw_path, user, files, trash)
+        }
+    }
+}
+
+// Drops '.' and folds '..' into its parent, so a path matches the form the file database stores it in.
+// Only call this on paths that passed is_path_valid, since those never climb above the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for part in path.components() {
+        match part {
+            std::path::Component::CurDir => { },
+            std::path::Component::ParentDir => { result.pop(); },
+            other => result.push(other)
+        }
+    }
+
+    result
+}
+// Resolves both ends of a rename or copy. The source must exist, and nothing may already be at the destination.
+fn source_and_destination(source: &str, destination: &str, curr_dir: &Path) -> Result<(PathBuf, PathBuf), Message> {
+    let (from, to) = match (resolve_target(source, curr_dir), resolve_target(destination, curr_dir)) {
+        (Some(f), Some(t)) => (f, t),
+        _ => return Err(ack(HttpCodes::Forbidden, "path is outside of the server's root directory"))
+    };
+
+    if from == root_directory() || to == root_directory() {
+        return Err(ack(HttpCodes::Forbidden, "the root directory cannot be renamed or replaced"));
+    }
+    if std::fs::symlink_metadata(&from).is_err() {
+        return Err(ack(HttpCodes::NotFound, "file not found"));
+    }
+    if std::fs::symlink_metadata(&to).is_ok() {
+        return Err(ack(HttpCodes::Conflict, "destination already exists"));
+    }
+    if !to.parent().is_some_and(|x| x.is_dir()) {
+        return Err(ack(HttpCodes::NotFound, "destination directory not found"));
+    }
+
+    Ok((from, to))
+}
+
+// Puts a file or folder at its new path within the root directory. Its file records move with it, so owners and holds are kept.
+fn relocate(from: &Path, to: &Path, user: &Credentials, files: &mut FileDatabase) -> Result<(), Message> {
+    if to.starts_with(from) {
+        return Err(ack(HttpCodes::Conflict, "a folder cannot be moved inside of itself"));
+    }
+    if let Err(e) = files.check_access(from, Some(user), Permission::Write).and_then(|_| files.check_access(to, Some(user), Permission::Write)) {
+        return Err(ack(HttpCodes::Forbidden, &e.to_string()));
+    }
+    if let Err((code, reason)) = check_hold(from, FileMutation::Move, files) {
+        return Err(ack(code, &reason));
+    }
+
+    std::fs::rename(from, to).map_err(|e| ack(HttpCodes::Conflict, &e.to_string()))?;
+    files.rename_tree(from, to);
+    Ok(())
+}
+
+// Gives a file or folder exactly the path it was given
+pub fn handle_rename_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase) -> Message {
+    let (source, destination) = match extract_rename_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed rename request")
+    };
+
+    let result = source_and_destination(&source, &destination, curr_dir).and_then(|(from, to)| relocate(&from, &to, user, files));
+    match result {
+        Ok(_) => ack(HttpCodes::Ok, &format!("renamed '{source}' to '{destination}'")),
+        Err(response) => response
+    }
+}
+
+// Moves a file or folder. Moving it to a folder that already exists puts it inside, under its own name.
+pub fn handle_move_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase) -> Message {
+    let (source, mut destination) = match extract_move_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed move request")
+    };
+
+    let name = resolve_target(&source, curr_dir).and_then(|x| x.file_name().map(|n| n.to_os_string()));
+    if let Some(name) = name.filter(|_| resolve_target(&destination, curr_dir).is_some_and(|x| x.is_dir())) {
+        destination = Path::new(&destination).join(name).to_string_lossy().to_string();
+    }
+
+    let result = source_and_destination(&source, &destination, curr_dir).and_then(|(from, to)| relocate(&from, &to, user, files).map(|_| to));
+    match result {
+        Ok(to) => ack(HttpCodes::Ok, &format!("moved '{source}' to '{}'", display_path(&to))),
+        Err(response) => response
+    }
+}
+
+// Duplicates a single file. The copy is a new file owned by the user who made it, so it carries no hold.
+pub fn handle_copy_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase) -> Message {
+    let (source, destination) = match extract_copy_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed copy request")
+    };
+
+    let (from, to) = match source_and_destination(&source, &destination, curr_dir) {
+        Ok(v) => v,
+        Err(response) => return response
+    };
+
+    if !from.is_file() {
+        return ack(HttpCodes::Conflict, "only files can be copied");
+    }
+    if let Err(e) = files.check_access(&from, Some(user), Permission::Read).and_then(|_| files.check_access(&to, Some(user), Permission::Write)) {
+        return ack(HttpCodes::Forbidden, &e.to_string());
+    }
+
+    if let Err(e) = std::fs::copy(&from, &to) {
+        return ack(HttpCodes::Conflict, &e.to_string());
+    }
+
+    let kind = files.get_file_by_path(&from).map(|x| x.file_type().clone()).or_else(|| detect_file_type(&to)).unwrap_or(FileType::Binary);
+    match files.register_file(to, Some(user.clone()), kind) {
+        Ok(_) => ack(HttpCodes::Ok, &format!("copied '{source}' to '{destination}'")),
+        Err(e) => ack(e.status(), &e.to_string())
+    }
+}
+
+// Grants or revokes permissions on a file or folder. Paths without a record can only be shared by an administrator, and then stay unowned.
+pub fn handle_share_request(message: Message, user: &Credentials, curr_dir: &Path, files: &mut FileDatabase) -> Message {
+    let (raw_path, principal, permissions, revoke) = match extract_share_message(message) {
+        Some(v) => v,
+        None => return ack(HttpCodes::BadRequest, "malformed share request")
+    };
+
+    let path = match resolve_target(&raw_path, curr_dir) {
+        Some(p) if p != root_directory() => p,
+        _ => return ack(HttpCodes::Forbidden, "path is outside of the server's root directory")
+    };
+    if !path.exists() {
+        return ack(HttpCodes::NotFound, "file not found");
+    }
+    if !files.can_share(&path, user) {
+        return ack(HttpCodes::Forbidden, "only the owner of a file, or an administrator, can share it");
+    }
+
+    let id = match files.get_file_id(&path) {
+        Some(id) => id,
+        None => {
+            let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+            match files.register_file(path.clone(), None, kind) {
+                Ok(id) => id,
+                Err(e) => return ack(e.status(), &e.to_string())
+            }
+        }
+    };
+
+    let file = match files.get_file_mut(id) {
+        Some(f) => f,
+        None => return ack(HttpCodes::NotFound, "file not found")
+    };
+    let listed = permissions.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ");
+    if revoke {
+        file.revoke(&principal, &permissions);
+        ack(HttpCodes::Ok, &format!("revoked {listed} on '{raw_path}' from {principal}"))
+    } else {
+        file.permit(&principal, &permissions);
+        ack(HttpCodes::Ok, &format!("granted {listed} on '{raw_path}' to {principal}"))
+    }
+}
+
+// Changes the user database for an administrator. Returns the user whose sessions must end, since a deleted or disabled user,
+// or one whose password or role changed, should not keep acting on what they were allowed before.
+// Administrators cannot delete, disable, or demote themselves, so a server is never left without one by accident.
+pub fn handle_user_admin_request(message: Message, admin: &Credentials, users: &mut UserDatabase) -> (Message, Option<String>) {
+    if !admin.is_admin() {
+        return (ack(HttpCodes::Forbidden, "only administrators can change users"), None);
+    }
+
+    let action = match extract_user_admin_message(message) {
+        Some(a) => a,
+        None => return (ack(HttpCodes::BadRequest, "malformed user admin request"), None)
+    };
+
+    let username = action.username().to_string();
+    let is_self = username == admin.username();
+    if let UserAdminAction::Create { password, role, .. } = action {
+        if password.is_empty() {
+            return (ack(HttpCodes::BadRequest, "passwords cannot be empty"), None);
+        }
+
+        let mut user = Credentials::new(username.clone(), password);
+        user.set_role(role);
+        return match users.add_user(user) {
+            Ok(()) => (ack(HttpCodes::Ok, &format!("created {role} '{username}'")), None),
+            Err(e) => (ack(e.status(), &e.to_string()), None)
+        };
+    }
+
+    if is_self && matches!(action, UserAdminAction::Delete { .. } | UserAdminAction::Disable { disabled: true, .. } | UserAdminAction::SetRole { .. }) {
+        return (ack(HttpCodes::Conflict, "administrators cannot delete, disable, or change the role of themselves"), None);
+    }
+
+    if let UserAdminAction::Delete { .. } = action {
+        return match users.remove_user(&username) {
+            true => (ack(HttpCodes::Ok, &format!("deleted '{username}'")), Some(username)),
+            false => (ack(HttpCodes::NotFound, "user not found"), None)
+        };
+    }
+
+    let user = match users.get_user_mut(&username) {
+        Some(u) => u,
+        None => return (ack(HttpCodes::NotFound, "user not found"), None)
+    };
+
+    let response = match action {
+        UserAdminAction::Disable { disabled, .. } => {
+            user.set_disabled(disabled);
+            ack(HttpCodes::Ok, &format!("{} '{username}'", if disabled { "disabled" } else { "enabled" }))
+        },
+        UserAdminAction::SetPassword { password, .. } => match password.is_empty() {
+            true => return (ack(HttpCodes::BadRequest, "passwords cannot be empty"), None),
+            false => match user.set_password(password) {
+                Ok(()) => ack(HttpCodes::Ok, &format!("changed the password of '{username}'")),
+                Err(e) => return (ack(HttpCodes::Conflict, &e), None)
+            }
+        },
+        UserAdminAction::SetRole { role, .. } => {
+            user.set_role(role);
+            ack(HttpCodes::Ok, &format!("'{username}' is now {role}"))
+        },
+        // A quota only limits later uploads, so the user's sessions are left alone
+        UserAdminAction::SetQuota { quota, .. } => {
+            user.set_quota(quota);
+            let limit = quota.map(|x| format!("{x} bytes")).unwrap_or_else(|| String::from("the default"));
+            return (ack(HttpCodes::Ok, &format!("the quota of '{username}' is now {limit}")), None);
+        },
+        UserAdminAction::Create { .. } | UserAdminAction::Delete { .. } => return (ack(HttpCodes::BadRequest, "unexpected user admin request"), None)
+    };
+
+    // An administrator's own session survives a change to their own password
+    (response, Some(username).filter(|_| !is_self))
+}
+
+// Paths are shown to clients relative to the root directory, which they see as '/'
+pub fn display_path(path: &Path) -> String {
+    match path.strip_prefix(root_directory()) {
+        Ok(p) => format!("/{}", p.display()),
+        Err(_) => String::from("/")
+    }
+}
+
+// Nested listings go no deeper than this, however deep a client asks, so one request cannot walk the whole tree
+pub const MAX_DIR_DEPTH: u32 = 8;
+
+// What a listing may show, settled from the server's configuration and what the Dir request asked for
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct ListingPolicy {
+    pub links: SymlinkPolicy,
+    pub dotfiles: bool, //Whether names starting with '.' are shown
+    pub internal: bool //Whether files the server keeps for itself are shown
+}
+impl ListingPolicy {
+    // Internal files are only shown to administrators who ask, on servers that allow it. Anyone else asking is ignored, rather than refused.
+    pub fn settle(config: &ServerConfig, query: &DirQuery, admin: bool) -> Self {
+        Self {
+            links: config.symlinks,
+            dotfiles: query.dotfiles.unwrap_or(config.dotfiles),
+            internal: query.internal && admin && config.list_internal
+        }
+    }
+
+    pub fn shows(&self, name: &str, path: &Path) -> bool {
+        (self.dotfiles || !name.starts_with('.')) && (self.internal || !is_internal_path(path))
+    }
+}
+
+// Leaves out what the policy hides from an entry found at path, at every level, and gives each link the target clients may see.
+// Targets are only given for links that lead somewhere beneath the root, and as clients see paths, so a listing never reveals anything outside of it.
+fn apply_listing_policy(content: DirectoryContent, path: &Path, policy: ListingPolicy) -> Option<DirectoryContent> {
+    match content {
+        DirectoryContent::Symlink(_) if policy.links == SymlinkPolicy::Skip => None,
+        DirectoryContent::Symlink(mut l) => {
+            l.set_target(resolve_path(path.to_path_buf()).map(|x| display_path(&x)));
+            Some(DirectoryContent::Symlink(l))
+        },
+        DirectoryContent::Dir(d) => {
+            let name = d.name().to_string();
+            let contents = d.into_contents().into_iter().filter_map(|x| {
+                let child = path.join(x.name());
+                policy.shows(x.name(), &child).then_some(x).and_then(|x| apply_listing_policy(x, &child, policy))
+            });
+            Some(DirectoryContent::Dir(DirectoryInfo::new(name, contents.collect())))
+        },
+        file => Some(file)
+    }
+}
+
+// Lists one page of a directory, along with how many entries matched in total.
+// Only names are read for the whole directory. Metadata and file records are looked up just for the entries on the page, and the folders on it are filled in as deep as the query asks.
+// Special files are never listed, and links, dotfiles, and internal files only as the policy allows. What the policy hides is not counted.
+pub fn list_directory(path: &Path, files: &FileDatabase, query: &DirQuery, policy: ListingPolicy) -> Result<(DirectoryInfo, u64), String> {
+    let mut entries: Vec<(String, PathBuf)> = std::fs::read_dir(path).map_err(|e| e.to_string())?
+        .flatten()
+        .filter(|x| policy.links != SymlinkPolicy::Skip || !x.file_type().is_ok_and(|t| t.is_symlink()))
+        .map(|x| (x.file_name().to_string_lossy().to_string(), x.path()))
+        .filter(|(name, entry_path)| policy.shows(name, entry_path) && query.matches(name))
+        .collect();
+    entries.sort();
+
+    let total = entries.len() as u64;
+    let depth = query.depth.min(MAX_DIR_DEPTH);
+    let contents = entries.into_iter()
+        .skip(query.offset as usize)
+        .take(query.limit.map_or(usize::MAX, |x| x as usize))
+        .filter_map(|(_, entry_path)| {
+            let content = DirectoryContent::from_path(&entry_path, depth, &|p, _| files.get_file_by_path(p).and_then(|f| f.file_info()))?;
+            apply_listing_policy(content, &entry_path, policy)
+        })
+        .collect();
+
+    let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+    Ok((DirectoryInfo::new(name, contents), total))
+}
+// The same paging, applied to a listing that is already in memory
+pub fn page_listing(listing: DirectoryInfo, query: &DirQuery) -> (DirectoryInfo, u64) {
+    let name = listing.name().to_string();
+    let mut entries: Vec<(String, DirectoryContent)> = listing.into_contents().into_iter()
+        .map(|x| (x.name().to_string(), x))
+        .filter(|(name, _)| query.matches(name))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let total = entries.len() as u64;
+    let page = entries.into_iter().skip(query.offset as usize).take(query.limit.map_or(usize::MAX, |x| x as usize)).map(|x| x.1).collect();
+    (DirectoryInfo::new(name, page), total)
+}
+
+// Directories that do not exist yet are accepted as long as they cannot climb out of the root, since a proxy lists them before they exist locally
+pub fn resolve_directory(raw_path: &str, curr_dir: &Path) -> Option<PathBuf> {
+    let path = resolve_target(raw_path, curr_dir)?;
+    if path.exists() {
+        resolve_path(path).filter(|x| is_path_valid(x))
+    } else if path.strip_prefix(root_directory()).ok()?.components().all(|x| matches!(x, std::path::Component::Normal(_))) {
+        Some(path)
+    } else {
+        None
+    }
+}
+// Works out which directory a Dir request is for, or the response refusing it
+pub fn dir_request_target(message: Message, curr_dir: &Path) -> Result<(DirQuery, PathBuf), Message> {
+    let query = match extract_dir_request_message(message) {
+        Some(q) => q,
+        None => return Err(dir_message_response(HttpCodes::BadRequest, "malformed dir request", &display_path(curr_dir), 0))
+    };
+
+    let dir = match query.path.as_deref() {
+        Some(p) => match resolve_directory(p, curr_dir) {
+            Some(d) => d,
+            None => return Err(dir_message_response(HttpCodes::Forbidden, "path is outside of the server's root directory", &display_path(curr_dir), 0))
+        },
+        None => curr_dir.to_path_buf()
+    };
+
+    Ok((query, dir))
+}
+
+// Lists the working directory, or a path beneath it. The listing itself is sent as frames after the response, in the connection's codec.
+pub fn handle_dir_request(message: Message, curr_dir: &Path, files: &FileDatabase, config: &ServerConfig, admin: bool, codec: Codec) -> (Message, Option<Vec<Vec<u8>>>) {
+    match dir_request_target(message, curr_dir) {
+        Ok((query, dir)) => dir_response(&display_path(&dir), list_directory(&dir, files, &query, ListingPolicy::settle(config, &query, admin)), codec),
+        Err(response) => (response, None)
+    }
+}
+pub fn dir_response(display: &str, listing: Result<(DirectoryInfo, u64), String>, codec: Codec) -> (Message, Option<Vec<Vec<u8>>>) {
+    let (listing, total) = match listing {
+        Ok(l) => l,
+        Err(e) => return (dir_message_response(HttpCodes::NotFound, &e, display, 0), None)
+    };
+
+    let frames = match codec.encode(&listing) {
+        Ok(b) => ChunkWriter::frames(&b),
+        Err(e) => return (dir_message_response(HttpCodes::Conflict, &e, display, 0), None)
+    };
+
+    (dir_page_total(dir_message_response(HttpCodes::Ok, "ok", display, frames.len() as u64), total), Some(frames))
+}
+
+pub const MAX_HEARTBEAT_PADDING: usize = 1024 * 1024;
+pub const MAX_PROBE_FRAMES: u64 = 4096;
+pub const MAX_DIAGNOSTICS_SIZE: usize = 64 * 1024;
+
+pub fn handle_heartbeat(message: Message) -> Message {
+    match extract_heartbeat_message(message) {
+        Some((_, padding)) if padding.len() > MAX_HEARTBEAT_PADDING => ack(HttpCodes::PayloadTooLarge, &format!("heartbeats carry at most {MAX_HEARTBEAT_PADDING} bytes of padding")),
+        Some((sequence, padding)) => heartbeat_response(sequence, &padding),
+        None => ack(HttpCodes::BadRequest, "malformed heartbeat")
+    }
+}
+pub fn handle_ping(message: Message) -> Message {
+    match extract_ping_message(message) {
+        Some((sequence, sent_at)) => pong_message(sequence, sent_at),
+        None => ack(HttpCodes::BadRequest, "malformed ping")
+    }
+}
+
+// Appends a client's probe results to the diagnostics log, one JSON line each, stamped with who sent them and from where
+pub fn record_diagnostics(message: Message, username: &str, peer: &str, log: &Path) -> Message {
+    let report = match extract_diagnostics_message(message) {
+        Some(r) => r,
+        None => return ack(HttpCodes::BadRequest, "malformed diagnostics report")
+    };
+
+    let line = serde_json::json!({
+        "time": unix_now(),
+        "user": username,
+        "peer": peer,
+        "report": report
+    }).to_string();
+    if line.len() > MAX_DIAGNOSTICS_SIZE {
+        return ack(HttpCodes::PayloadTooLarge, &format!("diagnostics reports are limited to {MAX_DIAGNOSTICS_SIZE} bytes"));
+    }
+
+    let written = std::fs::OpenOptions::new().create(true).append(true).open(log)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, format!("{line}\n").as_bytes()));
+    match written {
+        Ok(_) => ack(HttpCodes::Ok, "recorded"),
+        Err(e) => ack(HttpCodes::Conflict, &format!("unable to record the report because '{e}'"))
+    }
+}
+
+#[test]
+fn test_heartbeat_and_diagnostics() {
+    use hermes_common::messages::{heartbeat_message, diagnostics_message, extract_ack_message, extract_heartbeat_message};
+
+    assert_eq!(extract_heartbeat_message(handle_heartbeat(heartbeat_message(7, 1500))), Some((7, "x".repeat(1500))));
+    assert_eq!(extract_ack_message(handle_heartbeat(heartbeat_message(7, MAX_HEARTBEAT_PADDING + 1))).unwrap().0, HttpCodes::PayloadTooLarge);
+
+    let log = std::env::temp_dir().join(format!("hermes_diagnostics_{}.log", std::process::id()));
+    let report = serde_json::json!({ "rtt": { "avg_ms": 12.5 } });
+    assert_eq!(extract_ack_message(record_diagnostics(diagnostics_message(&report), "alice", "10.0.0.1:5000", &log)).unwrap().0, HttpCodes::Ok);
+    assert_eq!(extract_ack_message(record_diagnostics(diagnostics_message(&serde_json::json!("text")), "alice", "10.0.0.1:5000", &log)).unwrap().0, HttpCodes::BadRequest);
+
+    let line: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+    assert_eq!(line["user"], "alice");
+    assert_eq!(line["report"], report);
+
+    let _ = std::fs::remove_file(&log);
+}
+
+#[test]
+fn test_handle_can_i_request() {
+    use hermes_common::messages::{can_i_request, extract_can_i_response, dir_message_request};
+
+    let files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    let curr_dir = root_directory();
+    let ask = |operation: IntendedOperation| extract_can_i_response(handle_can_i_request(can_i_request(&operation), Some(&user), &curr_dir, &files, &QuotaManager::default(), &UploadPolicy::default()).0).unwrap().0;
+
+    assert_eq!(ask(IntendedOperation::Delete { path: String::from("hermes-missing-file.txt"), recursive: false }), HttpCodes::NotFound);
+    assert_eq!(ask(IntendedOperation::Upload { path: String::from("/etc/passwd"), size: 1 }), HttpCodes::Forbidden);
+    assert_eq!(extract_can_i_response(handle_can_i_request(dir_message_request(), Some(&user), &curr_dir, &files, &QuotaManager::default(), &UploadPolicy::default()).0).unwrap().0, HttpCodes::BadRequest);
+}
+
+#[test]
+fn test_upload_policy_refusals() {
+    use hermes_common::file_io::BUFF_SIZE;
+    use hermes_common::messages::{upload_message, extract_upload_response_message, can_i_request, extract_can_i_response};
+
+    let files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    let curr_dir = root_directory();
+    let policy = UploadPolicy { max_file_size: Some(BUFF_SIZE * 2), blocked_extensions: vec![String::from("exe")], ..Default::default() };
+    let upload = |name: &str, frame_count: u64| {
+        let (response, plan) = handle_upload_request(upload_message(name, FileType::Binary, frame_count, 0, None, None), Some(&user), &curr_dir, &files, &QuotaManager::default(), &policy);
+        (extract_upload_response_message(response).unwrap().0, plan.is_some())
+    };
+
+    // Refused before any frames are sent, going by how many are coming
+    assert_eq!(upload("hermes-policy.bin", 2), (HttpCodes::Ok, true));
+    assert_eq!(upload("hermes-policy.bin", 3), (HttpCodes::PayloadTooLarge, false));
+    assert_eq!(upload("hermes-policy.exe", 1), (HttpCodes::UnsupportedMediaType, false));
+
+    // A dry run agrees
+    let ask = |path: &str, size: u64| extract_can_i_response(handle_can_i_request(can_i_request(&IntendedOperation::Upload { path: path.to_string(), size }), Some(&user), &curr_dir, &files, &QuotaManager::default(), &policy).0).unwrap().0;
+    assert_eq!(ask("hermes-policy.bin", BUFF_SIZE * 2), HttpCodes::Ok);
+    assert_eq!(ask("hermes-policy.bin", BUFF_SIZE * 2 + 1), HttpCodes::PayloadTooLarge);
+    assert_eq!(ask("hermes-policy.exe", 1), HttpCodes::UnsupportedMediaType);
+}
+
+#[test]
+fn test_rename_and_copy_refusals() {
+    use hermes_common::messages::{rename_message, copy_message, extract_ack_message, delete_message};
+
+    let mut files = FileDatabase::new();
+    let user = Credentials::new(String::from("user"), String::from("pass"));
+    let curr_dir = root_directory();
+    let code = |response: Message| extract_ack_message(response).unwrap().0;
+
+    assert_eq!(code(handle_rename_request(rename_message("hermes-missing-file.txt", "b.txt"), &user, &curr_dir, &mut files)), HttpCodes::NotFound);
+    assert_eq!(code(handle_rename_request(rename_message("a.txt", "../../escaped.txt"), &user, &curr_dir, &mut files)), HttpCodes::Forbidden);
+    assert_eq!(code(handle_rename_request(rename_message(".", "elsewhere"), &user, &curr_dir, &mut files)), HttpCodes::Forbidden);
+    assert_eq!(code(handle_copy_request(copy_message("/etc/passwd", "passwd"), &user, &curr_dir, &mut files)), HttpCodes::Forbidden);
+    assert_eq!(code(handle_copy_request(delete_message("a.txt", false), &user, &curr_dir, &mut files)), HttpCodes::BadRequest);
+}
+
+#[test]
+fn test_legal_hold() {
+    use hermes_common::messages::{hold_message, delete_message, rename_message, move_message, extract_ack_message, Role};
+
+    let dir = std::env::temp_dir().join(format!("hermes_hold_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("docs").join("a.txt"), "a").unwrap();
+    let mut files = FileDatabase::new();
+    let mut trash = TrashBin::new();
+    let user = Credentials::from("user", "pass");
+    let mut admin = Credentials::from("root", "pass");
+    admin.set_role(Role::Admin);
+    let code = |response: Message| extract_ack_message(response).unwrap().0;
+
+    assert_eq!(code(handle_hold_request(hold_message("docs/a.txt", true), &user, &dir, &mut files)), HttpCodes::Forbidden);
+    assert_eq!(code(handle_hold_request(hold_message("docs/missing.txt", true), &admin, &dir, &mut files)), HttpCodes::NotFound);
+    assert_eq!(code(handle_hold_request(hold_message("docs/a.txt", true), &admin, &dir, &mut files)), HttpCodes::Ok);
+    assert!(files.get_file_by_path(&dir.join("docs").join("a.txt")).is_some_and(|x| x.is_immutable()));
+
+    // Neither the file nor the folder it is in can be deleted, moved, or overwritten, even by an administrator
+    for (path, recursive) in [("docs/a.txt", false), ("docs", true)] {
+        assert_eq!(code(handle_delete_request(delete_message(path, recursive), &admin, &dir, &mut files, &mut trash)), HttpCodes::Forbidden);
+        assert_eq!(code(handle_rename_request(rename_message(path, "elsewhere"), &admin, &dir, &mut files)), HttpCodes::Forbidden);
+        assert_eq!(code(handle_move_request(move_message(path, "elsewhere"), &admin, &dir, &mut files)), HttpCodes::Forbidden);
+    }
+    let overwrite = check_upload(&dir.join("docs").join("a.txt"), 0, 1, Some(&admin), &files, &QuotaManager::default());
+    assert_eq!(overwrite.unwrap_err().0, HttpCodes::Forbidden);
+    assert!(dir.join("docs").join("a.txt").is_file());
+
+    // Once cleared, all of them go through again
+    assert_eq!(code(handle_hold_request(hold_message("docs/a.txt", false), &admin, &dir, &mut files)), HttpCodes::Ok);
+    assert_eq!(check_upload(&dir.join("docs").join("a.txt"), 0, 1, Some(&admin), &files, &QuotaManager::default()), Ok(()));
+    assert_eq!(code(handle_rename_request(rename_message("docs/a.txt", "docs/b.txt"), &admin, &dir, &mut files)), HttpCodes::Ok);
+    assert_eq!(code(handle_delete_request(delete_message("docs", true), &admin, &dir, &mut files, &mut trash)), HttpCodes::Ok);
+    assert!(!dir.join("docs").exists());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_delete_counts_and_partial_failures() {
+    use hermes_common::messages::{delete_message, subfolder_message, extract_ack_message, extract_removed_count};
+
+    let dir = std::env::temp_dir().join(format!("hermes_delete_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("docs").join("inner")).unwrap();
+    std::fs::write(dir.join("docs").join("a.txt"), "a").unwrap();
+    std::fs::write(dir.join("docs").join("inner").join("b.txt"), "b").unwrap();
+    let mut files = FileDatabase::new();
+    let mut trash = TrashBin::new();
+    let user = Credentials::from("user", "pass");
+    for path in [dir.join("docs"), dir.join("docs").join("a.txt"), dir.join("docs").join("inner").join("b.txt")] {
+        files.register_file(path, Some(user.clone()), FileType::Binary).unwrap();
+    }
+
+    // A directory with anything in it is only deleted recursively
+    let response = handle_delete_request(delete_message("docs", false), &user, &dir, &mut files, &mut trash);
+    assert_eq!(extract_ack_message(response).unwrap().0, HttpCodes::Conflict);
+    let response = handle_subfolder_request(subfolder_message("docs", SubfolderAction::Delete, false), &user, &dir, &mut files, &mut trash);
+    assert_eq!(extract_ack_message(response).unwrap().0, HttpCodes::Conflict);
+    assert!(dir.join("docs").join("a.txt").is_file());
+
+    // When a delete stops part way, whatever is left keeps its owner, so it is not opened up to everyone
+    std::fs::remove_file(dir.join("docs").join("a.txt")).unwrap();
+    assert_eq!(files.unregister_removed(&dir.join("docs")), 1);
+    assert!(files.get_file_by_path(&dir.join("docs").join("inner").join("b.txt")).is_some_and(|x| x.is_owned_by(&user)));
+    assert!(files.check_access(&dir.join("docs").join("inner").join("b.txt"), Some(&Credentials::from("other", "pass")), Permission::Read).is_err());
+
+    // Each file and folder that goes is counted, and every record beneath goes with them
+    let response = handle_delete_request(delete_message("docs", true), &user, &dir, &mut files, &mut trash);
+    assert_eq!(extract_removed_count(&response), Some(3));
+    assert_eq!(extract_ack_message(response).unwrap().0, HttpCodes::Ok);
+    assert!(!dir.join("docs").exists());
+    assert!(files.get_file_by_path(&dir.join("docs").join("inner").join("b.txt")).is_none());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_rename_and_copy_records() {
+    use hermes_common::messages::{rename_message, copy_message, extract_ack_message, Principal};
+
+    let dir = std::env::temp_dir().join(format!("hermes_rename_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    let mut files = FileDatabase::new();
+    let alice = Credentials::from("alice", "pass");
+    let bob = Credentials::from("bob", "pass");
+    let id = files.register_file(dir.join("a.txt"), Some(alice.clone()), FileType::Text).unwrap();
+    files.get_file_mut(id).unwrap().permit(&Principal::User(String::from("bob")), &[Permission::Read]);
+    let code = |response: Message| extract_ack_message(response).unwrap().0;
+
+    // The owner and access list follow the file to its new name, and nothing is left at the old one
+    assert_eq!(code(handle_rename_request(rename_message("a.txt", "b.txt"), &alice, &dir, &mut files)), HttpCodes::Ok);
+    assert!(!dir.join("a.txt").exists() && files.get_file_by_path(&dir.join("a.txt")).is_none());
+    let renamed = files.get_file_by_path(&dir.join("b.txt")).unwrap();
+    assert_eq!(renamed.id(), id);
+    assert!(renamed.is_owned_by(&alice) && renamed.grants(&bob, Permission::Read));
+
+    // A copy belongs to whoever made it, and starts without the original's access list
+    assert_eq!(code(handle_copy_request(copy_message("b.txt", "c.txt"), &bob, &dir, &mut files)), HttpCodes::Ok);
+    assert_eq!(std::fs::read_to_string(dir.join("c.txt")).unwrap(), "a");
+    let copy = files.get_file_by_path(&dir.join("c.txt")).unwrap();
+    assert!(copy.is_owned_by(&bob) && copy.acl().is_empty());
+    assert!(files.get_file_by_path(&dir.join("b.txt")).is_some_and(|x| x.is_owned_by(&alice)));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_move_and_change_dir() {
+    use hermes_common::file_io::FileType;
+    use hermes_common::messages::{move_message, change_dir_message, extract_ack_message};
+
+    let dir = std::env::temp_dir().join(format!("hermes_move_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("a.txt"), "a").unwrap();
+    let mut files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    files.register_file(dir.join("a.txt"), Some(user.clone()), FileType::Text).unwrap();
+    let code = |response: Message| extract_ack_message(response).unwrap().0;
+
+    // Changing directory changes nothing on disk
+    let (response, moved_to) = handle_change_dir_request(change_dir_message("docs"), &dir);
+    assert_eq!((code(response), moved_to), (HttpCodes::Ok, Some(dir.join("docs"))));
+    assert_eq!(code(handle_change_dir_request(change_dir_message("a.txt"), &dir).0), HttpCodes::NotFound);
+
+    // Into an existing folder keeps the name, and anywhere else takes the new one. The record goes along.
+    assert_eq!(code(handle_move_request(move_message("a.txt", "docs"), &user, &dir, &mut files)), HttpCodes::Ok);
+    assert!(dir.join("docs").join("a.txt").is_file() && files.get_file_by_path(&dir.join("docs").join("a.txt")).is_some_and(|x| x.is_owned_by(&user)));
+    assert_eq!(code(handle_move_request(move_message("docs/a.txt", "b.txt"), &user, &dir, &mut files)), HttpCodes::Ok);
+    assert!(dir.join("b.txt").is_file() && files.get_file_by_path(&dir.join("b.txt")).is_some());
+
+    assert_eq!(code(handle_move_request(move_message("docs", "docs"), &user, &dir, &mut files)), HttpCodes::Conflict);
+    assert_eq!(code(handle_move_request(move_message("b.txt", "../../../escaped.txt"), &user, &dir, &mut files)), HttpCodes::Forbidden);
+    assert_eq!(code(handle_move_request(change_dir_message("b.txt"), &user, &dir, &mut files)), HttpCodes::BadRequest);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_page_listing() {
+    let file = |name: &str| DirectoryContent::File(FileInfo::new(name.to_string(), String::from("owner"), FileType::Text, 1));
+    let listing = DirectoryInfo::new(
+        String::from("docs"),
+        vec![file("c.txt"), file("a.txt"), file("b.md"), DirectoryContent::Dir(DirectoryInfo::new(String::from("d.txt"), Vec::new()))]
+    );
+
+    let query = DirQuery { filters: vec![String::from("*.txt")], offset: 1, limit: Some(1), ..Default::default() };
+    let (page, total) = page_listing(listing.clone(), &query);
+    assert_eq!(total, 3);
+    assert_eq!(page.contents().iter().map(|x| x.name()).collect::<Vec<_>>(), vec!["c.txt"]);
+
+    let (page, total) = page_listing(listing, &DirQuery { offset: 10, ..Default::default() });
+    assert_eq!((page.contents().len(), total), (0, 4));
+}
+
+#[test]
+fn test_list_directory_depth() {
+    let dir = std::env::temp_dir().join(format!("hermes_tree_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("a").join("b").join("c")).unwrap();
+    std::fs::write(dir.join("a").join("one.txt"), "1").unwrap();
+    std::fs::write(dir.join("a").join("b").join("two.txt"), "22").unwrap();
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("a").join("loop")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.join("a").join("out")).unwrap();
+    }
+
+    // Names at each level, with folders marked, depth first
+    fn names(listing: &DirectoryInfo, prefix: &str, out: &mut Vec<String>) {
+        for entry in listing.contents() {
+            match entry {
+                DirectoryContent::File(f) => out.push(format!("{prefix}{}", f.name())),
+                DirectoryContent::Dir(d) => {
+                    out.push(format!("{prefix}{}/", d.name()));
+                    names(d, &format!("{prefix}{}/", d.name()), out);
+                },
+                DirectoryContent::Symlink(l) => out.push(format!("{prefix}{}@{}", l.name(), l.target().unwrap_or("?")))
+            }
+        }
+    }
+    let listed = |depth: u32, policy: ListingPolicy| {
+        let mut out = Vec::new();
+        names(&list_directory(&dir, &FileDatabase::new(), &DirQuery { depth, ..Default::default() }, policy).unwrap().0, "", &mut out);
+        out
+    };
+    let tree = |depth: u32| listed(depth, ListingPolicy::default());
+
+    assert_eq!(tree(0), vec!["a/"]);
+    assert_eq!(tree(1), vec!["a/", "a/b/", "a/one.txt"]);
+    assert_eq!(tree(2), vec!["a/", "a/b/", "a/b/c/", "a/b/two.txt", "a/one.txt"]);
+    assert_eq!(tree(u32::MAX), tree(3));
+
+    // Links are listed when the policy allows, though never followed, and their targets are only shown when they lead beneath the root
+    #[cfg(unix)]
+    assert_eq!(listed(1, ListingPolicy { links: SymlinkPolicy::Error, ..Default::default() }), vec![String::from("a/"), String::from("a/b/"), format!("a/loop@/hermes_tree_{}/a", std::process::id()), String::from("a/one.txt"), String::from("a/out@?")]);
+
+    // Dotfiles are hidden at every level unless asked for, and partial uploads are never shown
+    std::fs::write(dir.join(".profile"), "p").unwrap();
+    std::fs::write(dir.join("a").join(".hidden"), "h").unwrap();
+    std::fs::write(dir.join("a").join(format!("three.txt{}", crate::staging::STAGING_SUFFIX)), "3").unwrap();
+    assert_eq!(tree(1), vec!["a/", "a/b/", "a/one.txt"]);
+    assert_eq!(listed(1, ListingPolicy { dotfiles: true, ..Default::default() }), vec![".profile", "a/", "a/.hidden", "a/b/", "a/one.txt"]);
+    let config = ServerConfig { dotfiles: false, list_internal: true, ..Default::default() };
+    assert_eq!(ListingPolicy::settle(&config, &DirQuery { dotfiles: Some(true), internal: true, ..Default::default() }, false), ListingPolicy { dotfiles: true, ..Default::default() });
+    assert!(ListingPolicy::settle(&config, &DirQuery { internal: true, ..Default::default() }, true).internal);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_handle_connect_version() {
+    use hermes_common::messages::{connect_message, resume_connect_message, share_connect_message, guest_connect_message, extract_connect_ack_message};
+
+    let users = UserDatabase::new();
+    let mut resume = ResumeTokenStore::default();
+    let grants = GrantStore::new();
+    let mut shares = ShareDatabase::new();
+
+    let old_client = connect_message(String::from("user"), String::from("pass"), ProtocolVersion::new(0, 1));
+    let (code, _, version, _) = extract_connect_ack_message(handle_connect(old_client, &users, &mut resume, &grants, &shares, false, "127.0.0.1").0).unwrap();
+    assert_eq!(code, HttpCodes::VersionNotSupported);
+    assert_eq!(version, None);
+
+    let current_client = connect_message(String::from("user"), String::from("pass"), CURRENT_PROTOCOL_VERSION);
+    let (code, _, _, token) = extract_connect_ack_message(handle_connect(current_client, &users, &mut resume, &grants, &shares, false, "127.0.0.1").0).unwrap();
+    assert_eq!(code, HttpCodes::Unauthorized);
+    assert!(token.is_none());
+
+    let resuming_client = resume_connect_message("not-a-token", CURRENT_PROTOCOL_VERSION);
+    let (code, _, _, _) = extract_connect_ack_message(handle_connect(resuming_client, &users, &mut resume, &grants, &shares, false, "127.0.0.1").0).unwrap();
+    assert_eq!(code, HttpCodes::Unauthorized);
+
+    // A share link logs in as the link, without a resumption token
+    let link = shares.issue(&Credentials::from("owner", "pass"), String::from("a.txt"), Duration::from_secs(60)).unwrap();
+    let (response, identity) = handle_connect(share_connect_message(link.token(), CURRENT_PROTOCOL_VERSION), &users, &mut resume, &grants, &shares, false, "127.0.0.1");
+    assert_eq!((extract_connect_ack_message(response).unwrap().0, identity), (HttpCodes::Ok, Some(SessionIdentity::Share(link))));
+    let (code, _, _, _) = extract_connect_ack_message(handle_connect(share_connect_message("not-a-link", CURRENT_PROTOCOL_VERSION), &users, &mut resume, &grants, &shares, false, "127.0.0.1").0).unwrap();
+    assert_eq!(code, HttpCodes::Unauthorized);
+
+    // Guests are only let in when the server allows them
+    let (response, identity) = handle_connect(guest_connect_message(CURRENT_PROTOCOL_VERSION), &users, &mut resume, &grants, &shares, false, "127.0.0.1");
+    assert_eq!((extract_connect_ack_message(response).unwrap().0, identity), (HttpCodes::Unauthorized, None));
+    let (response, identity) = handle_connect(guest_connect_message(CURRENT_PROTOCOL_VERSION), &users, &mut resume, &grants, &shares, true, "127.0.0.1");
+    assert_eq!((extract_connect_ack_message(response).unwrap().0, identity), (HttpCodes::Ok, Some(SessionIdentity::Guest)));
+}
+
+#[test]
+fn test_user_admin() {
+    use hermes_common::messages::{user_admin_message, extract_ack_message, Role};
+
+    let path = std::env::temp_dir().join(format!("hermes_user_admin_{}.json", std::process::id()));
+    std::fs::write(&path, r#"[{"username":"root","password":"pass","admin":true},{"username":"bob","password":"pass"}]"#).unwrap();
+    let mut users = UserDatabase::new();
+    users.open(path.to_string_lossy().to_string()).unwrap();
+
+    // The admin flag of older databases becomes the admin role
+    let root = users.get_user("root").cloned().unwrap();
+    let bob = users.get_user("bob").cloned().unwrap();
+    assert_eq!(root.role(), Role::Admin);
+
+    let mut run = |admin: &Credentials, action: UserAdminAction| {
+        let (response, affected) = handle_user_admin_request(user_admin_message(&action), admin, &mut users);
+        (extract_ack_message(response).unwrap().0, affected)
+    };
+    let create = UserAdminAction::Create { username: String::from("carol"), password: String::from("secret"), role: Role::ReadOnly };
+    assert_eq!(run(&bob, create.clone()).0, HttpCodes::Forbidden);
+    assert_eq!(run(&root, create.clone()), (HttpCodes::Ok, None));
+    assert_eq!(run(&root, create).0, HttpCodes::Conflict);
+    assert_eq!(run(&root, UserAdminAction::Delete { username: String::from("root") }).0, HttpCodes::Conflict);
+    assert_eq!(run(&root, UserAdminAction::SetRole { username: String::from("nobody"), role: Role::User }).0, HttpCodes::NotFound);
+
+    // Disabled users cannot log in, and their sessions have to end
+    assert_eq!(run(&root, UserAdminAction::Disable { username: String::from("bob"), disabled: true }), (HttpCodes::Ok, Some(String::from("bob"))));
+    assert_eq!(run(&root, UserAdminAction::SetPassword { username: String::from("carol"), password: String::from("other") }), (HttpCodes::Ok, Some(String::from("carol"))));
+    assert_eq!(run(&root, UserAdminAction::Delete { username: String::from("carol") }), (HttpCodes::Ok, Some(String::from("carol"))));
+
+    let connect = |users: &UserDatabase| {
+        let message = hermes_common::messages::connect_message(String::from("bob"), String::from("pass"), CURRENT_PROTOCOL_VERSION);
+        let (response, identity) = handle_connect(message, users, &mut ResumeTokenStore::default(), &GrantStore::new(), &ShareDatabase::new(), false, "127.0.0.1");
+        (hermes_common::messages::extract_connect_ack_message(response).unwrap().0, identity.is_some())
+    };
+    assert_eq!(connect(&users), (HttpCodes::Forbidden, false));
+    assert!(users.get_user("carol").is_none());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_delta_download() {
+    use hermes_common::delta::apply_delta_file;
+    use hermes_common::checksum::ChecksumAlgorithm;
+    use hermes_common::messages::{signature_message, extract_signature_response, extract_delta};
+
+    let files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    let curr_dir = root_directory();
+    let code = |response: Message| extract_signature_response(response).unwrap().0;
+    assert_eq!(code(handle_signature_request(signature_message("hermes-missing-file.txt"), &user, &curr_dir, &files)), HttpCodes::NotFound);
+    assert_eq!(code(handle_signature_request(signature_message("/etc/passwd"), &user, &curr_dir, &files)), HttpCodes::Forbidden);
+
+    let dir = std::env::temp_dir().join(format!("hermes_delta_download_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let old: Vec<u8> = (0..100_000u32).map(|x| (x % 199) as u8).collect();
+    let mut new = old.clone();
+    new[50_000..50_010].copy_from_slice(b"0123456789");
+    std::fs::write(dir.join("old"), &old).unwrap();
+    std::fs::write(dir.join("new"), &new).unwrap();
+
+    let signature = Signature::of_file(&dir.join("old")).unwrap();
+    let chunks = FileChunkIter::open(&dir.join("new"), 0, None).unwrap();
+    let mut details = DownloadResponse::failure(HttpCodes::Ok, "ok");
+    details.frame_count = chunks.frame_count();
+    let (response, delta) = delta_download(download_message_response(details), chunks, &signature, &dir.join("scratch")).unwrap();
+    assert!(extract_delta(&response));
+    assert_eq!(extract_download_response_message(response).unwrap().frame_count, delta.frame_count());
+    assert!(std::fs::metadata(dir.join("scratch")).unwrap().len() < 10_000);
+
+    apply_delta_file(&dir.join("scratch"), &dir.join("old"), &dir.join("rebuilt"), ChecksumAlgorithm::default()).unwrap();
+    assert_eq!(std::fs::read(dir.join("rebuilt")).unwrap(), new);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_dir_transfers() {
+    use hermes_common::archive::pack_directory;
+    use hermes_common::messages::{download_dir_message, extract_download_dir_response, upload_dir_message, extract_upload_dir_response, extract_ack_message};
+
+    let files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    let curr_dir = root_directory();
+    let dir = std::env::temp_dir().join(format!("hermes_dir_transfers_{}", std::process::id()));
+    let download = |path: &str| extract_download_dir_response(handle_download_dir_request(download_dir_message(path), &user, &curr_dir, &files, &dir.join("scratch.tar")).0).unwrap().0;
+    assert_eq!(download("hermes-missing-dir"), HttpCodes::NotFound);
+    assert_eq!(download("/etc"), HttpCodes::Forbidden);
+    let upload = |path: &str| extract_upload_dir_response(handle_upload_dir_request(upload_dir_message(path, 1, None), &user, &curr_dir, &files, &QuotaManager::default()).0).unwrap().0;
+    assert_eq!(upload("/etc/hermes"), HttpCodes::Forbidden);
+    assert_eq!(upload("."), HttpCodes::Forbidden);
+
+    // A received archive is unpacked beside its destination, then moved into place
+    std::fs::create_dir_all(dir.join("source/nested")).unwrap();
+    std::fs::write(dir.join("source/nested/a.txt"), "alpha").unwrap();
+    pack_directory(&dir.join("source"), &dir.join("upload.tar"), |_| true).unwrap();
+    let plan = DirUploadPlan { path: dir.join("copy"), staging: staging_path(&dir.join("copy")), frame_count: 1, checksum: None };
+    let mut files = FileDatabase::new();
+    let (response, placed) = unpack_dir_upload(&plan, &dir.join("upload.tar"), &user, &mut files, &QuotaManager::default(), &|_, _, _| Ok(()));
+    assert_eq!(extract_ack_message(response).unwrap().0, HttpCodes::Ok);
+    assert_eq!(placed, vec![dir.join("copy/nested/a.txt")]);
+    assert_eq!(std::fs::read_to_string(dir.join("copy/nested/a.txt")).unwrap(), "alpha");
+    assert!(!plan.staging.exists());
+
+    std::fs::write(dir.join("garbage.tar"), "not an archive").unwrap();
+    let plan = DirUploadPlan { path: dir.join("other"), staging: staging_path(&dir.join("other")), frame_count: 1, checksum: None };
+    assert_eq!(extract_ack_message(unpack_dir_upload(&plan, &dir.join("garbage.tar"), &user, &mut files, &QuotaManager::default(), &|_, _, _| Ok(())).0).unwrap().0, HttpCodes::BadRequest);
+    assert!(!dir.join("other").exists());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_preview() {
+    use hermes_common::messages::{preview_message, extract_preview_response};
+
+    let files = FileDatabase::new();
+    let user = Credentials::from("user", "pass");
+    let curr_dir = root_directory();
+    let code = |path: &str| extract_preview_response(handle_preview_request(preview_message(path, 5), &user, &curr_dir, &files)).unwrap().0;
+    assert_eq!(code("hermes-missing-file.txt"), HttpCodes::NotFound);
+    assert_eq!(code("/etc/passwd"), HttpCodes::Forbidden);
+
+    // Text is counted in lines, and anything else in bytes
+    let dir = std::env::temp_dir().join(format!("hermes_preview_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("notes.txt"), "one\ntwo\nthree\n").unwrap();
+    assert_eq!(read_preview(&dir.join("notes.txt"), &FileType::Text, 2).unwrap(), (b"one\ntwo\n".to_vec(), true));
+    assert_eq!(read_preview(&dir.join("notes.txt"), &FileType::Text, 10).unwrap(), (b"one\ntwo\nthree\n".to_vec(), false));
+    assert_eq!(read_preview(&dir.join("notes.txt"), &FileType::Binary, 5).unwrap(), (b"one\nt".to_vec(), true));
+
+    // No limit gets past the cap
+    std::fs::write(dir.join("large.bin"), vec![7u8; MAX_PREVIEW_SIZE as usize + 1]).unwrap();
+    let (content, truncated) = read_preview(&dir.join("large.bin"), &FileType::Binary, u64::MAX).unwrap();
+    assert!(content.len() as u64 == MAX_PREVIEW_SIZE && truncated);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+use crate::handlers::display_path;
+use hermes_common::http_codes::HttpCodes;
+
+// What a hook refuses a request with, which the client is answered with
+pub type HookRefusal = (HttpCodes, String);
+
+// The file a hook is told about, and who asked
+#[derive(Clone, PartialEq, Debug)]
+pub struct HookEvent {
+    pub path: PathBuf, //Where the file is, or will be, in the data directory
+    pub user: Option<String>, //The acting user, which for an upload grant is whoever made it
+    pub peer: String
+}
+impl HookEvent {
+    pub fn new(path: &Path, user: Option<&str>, peer: &str) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            user: user.map(|x| x.to_string()),
+            peer: peer.to_string()
+        }
+    }
+
+    // As the server shows it, such as '/docs/a.txt'
+    pub fn display_path(&self) -> String {
+        display_path(&self.path)
+    }
+}
+
+// Callbacks around transfers and deletes, so scanning or indexing files needs no change to the handlers.
+// Every callback may block, as they are run off the async threads, and the paths they are told about are locked while they run.
+pub trait ServerHooks: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Runs once an upload has arrived in full, before it takes the place of anything. The contents are the received file, which stays where it is until every hook has passed it.
+    // Refusing throws the upload away. A folder upload asks about each of its files.
+    fn before_upload(&self, _event: &HookEvent, _contents: &Path) -> Result<(), HookRefusal> {
+        Ok(())
+    }
+    // Runs once an upload is in place and recorded, after the client has its answer
+    fn after_upload(&self, _event: &HookEvent) { }
+    // Runs before a file, or a folder as an archive, is sent. Refusing answers the download with the code and reason given.
+    fn before_download(&self, _event: &HookEvent) -> Result<(), HookRefusal> {
+        Ok(())
+    }
+    // Runs once a file or folder has been deleted, which moves it to the trash, after the client has its answer
+    fn after_delete(&self, _event: &HookEvent) { }
+}
+
+// The hooks registered at startup, which run in registration order
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Box<dyn ServerHooks>>
+}
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<H: ServerHooks + 'static>(&mut self, hook: H) -> &mut Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+    pub fn names(&self) -> Vec<&'static str> {
+        self.hooks.iter().map(|x| x.name()).collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    // The first refusal stops the later hooks from being asked
+    pub fn before_upload(&self, event: &HookEvent, contents: &Path) -> Result<(), HookRefusal> {
+        self.hooks.iter().try_for_each(|x| x.before_upload(event, contents).inspect_err(|(_, reason)| tracing::info!(hook = x.name(), "refused the upload of '{}' because '{reason}'", event.display_path())))
+    }
+    pub fn after_upload(&self, event: &HookEvent) {
+        for hook in &self.hooks {
+            hook.after_upload(event);
+        }
+    }
+    pub fn before_download(&self, event: &HookEvent) -> Result<(), HookRefusal> {
+        self.hooks.iter().try_for_each(|x| x.before_download(event).inspect_err(|(_, reason)| tracing::info!(hook = x.name(), "refused the download of '{}' because '{reason}'", event.display_path())))
+    }
+    pub fn after_delete(&self, event: &HookEvent) {
+        for hook in &self.hooks {
+            hook.after_delete(event);
+        }
+    }
+}
+
+#[test]
+fn test_hooks() {
+    use std::sync::Mutex;
+
+    // Refuses executables, and keeps a note of what it is told
+    struct Screen {
+        seen: std::sync::Arc<Mutex<Vec<String>>>
+    }
+    impl ServerHooks for Screen {
+        fn name(&self) -> &'static str {
+            "screen"
+        }
+        fn before_upload(&self, event: &HookEvent, contents: &Path) -> Result<(), HookRefusal> {
+            if std::fs::read(contents).unwrap_or_default().starts_with(b"MZ") {
+                return Err((HttpCodes::Forbidden, String::from("executables are not allowed")));
+            }
+            self.seen.lock().unwrap().push(format!("checked {}", event.display_path()));
+            Ok(())
+        }
+        fn after_upload(&self, event: &HookEvent) {
+            self.seen.lock().unwrap().push(format!("indexed {}", event.display_path()));
+        }
+        fn after_delete(&self, event: &HookEvent) {
+            self.seen.lock().unwrap().push(format!("forgot {}", event.display_path()));
+        }
+    }
+    struct Private;
+    impl ServerHooks for Private {
+        fn name(&self) -> &'static str {
+            "private"
+        }
+        fn before_download(&self, event: &HookEvent) -> Result<(), HookRefusal> {
+            match event.user.as_deref() {
+                Some("admin") => Ok(()),
+                _ => Err((HttpCodes::Forbidden, String::from("only the administrator may download")))
+            }
+        }
+    }
+
+    let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let mut hooks = Hooks::new();
+    assert!(hooks.is_empty());
+    hooks.register(Screen { seen: seen.clone() }).register(Private);
+    assert_eq!(hooks.names(), vec!["screen", "private"]);
+
+    let dir = std::env::temp_dir().join(format!("hermes_hooks_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.txt"), "alpha").unwrap();
+    std::fs::write(dir.join("b.exe"), "MZ...").unwrap();
+    let root = crate::io_loc::root_directory();
+    let event = HookEvent::new(&root.join("docs/a.txt"), Some("user"), "10.0.0.1");
+    assert_eq!(event.display_path(), "/docs/a.txt");
+
+    assert_eq!(hooks.before_upload(&event, &dir.join("a.txt")), Ok(()));
+    assert_eq!(hooks.before_upload(&event, &dir.join("b.exe")).unwrap_err().0, HttpCodes::Forbidden);
+    hooks.after_upload(&event);
+    hooks.after_delete(&event);
+    assert_eq!(*seen.lock().unwrap(), vec!["checked /docs/a.txt", "indexed /docs/a.txt", "forgot /docs/a.txt"]);
+
+    // Hooks with nothing to say about a callback pass it
+    assert_eq!(hooks.before_download(&event).unwrap_err(), (HttpCodes::Forbidden, String::from("only the administrator may download")));
+    assert_eq!(hooks.before_download(&HookEvent::new(&event.path, Some("admin"), "10.0.0.1")), Ok(()));
+    assert_eq!(Hooks::new().before_download(&event), Ok(()));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
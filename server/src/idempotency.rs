@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use hermes_common::messages::Message;
+use hermes_common::session::unix_now;
+
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct IdempotencyRecord {
+    response: Message,
+    expires_at: u64
+}
+
+// Remembers the response to every upload and delete that carried an idempotency key, so a client replaying its offline queue gets the original answer instead of doing the work twice.
+// An upload is remembered as one that needs no file sent, since the replay is answered before it would send one.
+// Keys are scoped to the user that sent them. Only successful requests are remembered, since a failed one should be tried again.
+pub struct IdempotencyCache {
+    records: HashMap<(String, String), IdempotencyRecord>,
+    ttl: Duration
+}
+impl Debug for IdempotencyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Keys: {}, TTL: {}s)", self.records.len(), self.ttl.as_secs())
+    }
+}
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDEMPOTENCY_TTL)
+    }
+}
+impl IdempotencyCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            records: HashMap::new(),
+            ttl
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn lookup(&self, user: &str, key: &str) -> Option<Message> {
+        let record = self.records.get(&(user.to_string(), key.to_string()))?;
+        if unix_now() >= record.expires_at {
+            None
+        } else {
+            Some(record.response.clone())
+        }
+    }
+    pub fn record(&mut self, user: &str, key: &str, response: Message) {
+        let now = unix_now();
+        self.records.retain(|_, x| now < x.expires_at);
+
+        self.records.insert(
+            (user.to_string(), key.to_string()),
+            IdempotencyRecord {
+                response,
+                expires_at: now + self.ttl.as_secs()
+            }
+        );
+    }
+}
+
+#[test]
+fn test_idempotency_cache() {
+    use hermes_common::http_codes::HttpCodes;
+    use hermes_common::messages::{ack_messsage, MessageDirection};
+
+    let mut cache = IdempotencyCache::default();
+    let response = ack_messsage(MessageDirection::Response, HttpCodes::Ok, Some(String::from("deleted")));
+
+    cache.record("user", "key", response.clone());
+    assert_eq!(cache.lookup("user", "key"), Some(response));
+
+    // Another user reusing the same key is a different request
+    assert_eq!(cache.lookup("other", "key"), None);
+    assert_eq!(cache.lookup("user", "other"), None);
+
+    let mut expired = IdempotencyCache::new(Duration::ZERO);
+    expired.record("user", "key", ack_messsage(MessageDirection::Response, HttpCodes::Ok, None));
+    assert_eq!(expired.lookup("user", "key"), None);
+}
@@ -1,12 +1,75 @@
-use crate::credentials::UserDatabase;
-use hermes_common::network_stats::NetworkAnalyzer;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::OnceLock;
 use homedir::my_home;
-use lazy_static::lazy_static;
+
+use crate::staging::STAGING_SUFFIX;
+
+// Where a server keeps everything. Set once at startup, before anything is opened. Until then the defaults beneath the home directory are used, and tests use the temp directory.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoragePaths {
+    pub host: PathBuf, //The stores, logs, and TLS keys
+    pub root: PathBuf, //The shared files
+    pub database: PathBuf //The user and file databases, which can sit on faster storage than everything else
+}
+impl Default for StoragePaths {
+    fn default() -> Self {
+        Self::new(default_host_directory(), None, None)
+    }
+}
+impl StoragePaths {
+    // The data and database folders default to the host directory's data folder, and the host directory itself
+    pub fn new(host: PathBuf, root: Option<PathBuf>, database: Option<PathBuf>) -> Self {
+        Self {
+            root: root.unwrap_or_else(|| host.join("data")),
+            database: database.unwrap_or_else(|| host.clone()),
+            host
+        }
+    }
+
+    // Where a state file of this name is kept: the databases in the database folder, and everything else in the host directory
+    pub fn state_file(&self, name: &OsStr) -> PathBuf {
+        if name == USERS_FILE || name == FILES_FILE {
+            self.database.join(name)
+        }
+        else {
+            self.host.join(name)
+        }
+    }
+}
+
+const USERS_FILE: &str = "users.json";
+const FILES_FILE: &str = "files.json";
+
+static STORAGE_PATHS: OnceLock<StoragePaths> = OnceLock::new();
+
+pub fn install_storage_paths(paths: StoragePaths) -> Result<(), String> {
+    STORAGE_PATHS.set(paths).map_err(|_| String::from("the storage paths were already set"))
+}
+pub fn storage_paths() -> StoragePaths {
+    match STORAGE_PATHS.get() {
+        Some(p) => p.clone(),
+        None => unset_storage_paths()
+    }
+}
+#[cfg(not(test))]
+fn unset_storage_paths() -> StoragePaths {
+    StoragePaths::default()
+}
+// Tests never install paths. They share the temp directory as their root, so whatever they make is beside their other scratch files and never in a real server's folders.
+#[cfg(test)]
+fn unset_storage_paths() -> StoragePaths {
+    let temp = std::env::temp_dir();
+    StoragePaths::new(temp.join(format!("hermes_host_{}", std::process::id())), Some(temp), None)
+}
 
 pub fn host_directory() -> PathBuf {
+    storage_paths().host
+}
+// Where the configuration is looked for, and where everything is kept unless it says otherwise
+pub fn default_host_directory() -> PathBuf {
     let home_r = my_home();
     let home = match home_r {
         Ok(p) => {
@@ -21,20 +84,121 @@ pub fn host_directory() -> PathBuf {
    home.join("cnt")
 }
 pub fn root_directory() -> PathBuf {
-    host_directory().join("data")
+    storage_paths().root
+}
+pub fn database_directory() -> PathBuf {
+    storage_paths().database
 }
 pub fn user_database_path() -> PathBuf {
-    host_directory().join("users.json")
+    database_directory().join(USERS_FILE)
 }
 pub fn file_owner_db_path() -> PathBuf {
-    host_directory().join("files.json")
+    database_directory().join(FILES_FILE)
 }
 pub fn network_analyzer_path() -> PathBuf {
     host_directory().join("stats.json")
 }
+pub fn resume_tokens_path() -> PathBuf {
+    host_directory().join("resume.json")
+}
+pub fn grants_path() -> PathBuf {
+    host_directory().join("grants.json")
+}
+pub fn shares_path() -> PathBuf {
+    host_directory().join("shares.json")
+}
+pub fn grants_audit_path() -> PathBuf {
+    host_directory().join("grants.log")
+}
+pub fn tls_directory() -> PathBuf {
+    host_directory().join("tls")
+}
+pub fn tls_cert_path() -> PathBuf {
+    tls_directory().join("cert.pem")
+}
+pub fn tls_key_path() -> PathBuf {
+    tls_directory().join("key.pem")
+}
+pub fn tls_client_ca_path() -> PathBuf {
+    tls_directory().join("client_ca.pem")
+}
+pub fn retention_path() -> PathBuf {
+    host_directory().join("retention.json")
+}
+pub fn retention_log_path() -> PathBuf {
+    host_directory().join("retention.log")
+}
+pub fn staging_registry_path() -> PathBuf {
+    host_directory().join("staging.json")
+}
+pub fn trash_directory() -> PathBuf {
+    host_directory().join(".trash")
+}
+pub fn trash_registry_path() -> PathBuf {
+    host_directory().join("trash.json")
+}
+pub fn versions_directory() -> PathBuf {
+    host_directory().join("versions")
+}
+pub fn delta_directory() -> PathBuf {
+    host_directory().join("deltas")
+}
+pub fn quarantine_directory() -> PathBuf {
+    host_directory().join("quarantine")
+}
+pub fn thumbnail_directory() -> PathBuf {
+    host_directory().join(".thumbs")
+}
+pub fn archive_directory() -> PathBuf {
+    host_directory().join("archives")
+}
+pub fn sqlite_database_path() -> PathBuf {
+    database_directory().join("hermes.db")
+}
+pub fn server_config_path() -> PathBuf {
+    default_host_directory().join("config.json")
+}
+pub fn audit_log_path() -> PathBuf {
+    host_directory().join("audit.log")
+}
+pub fn proxy_config_path() -> PathBuf {
+    host_directory().join("proxy.json")
+}
+pub fn diagnostics_log_path() -> PathBuf {
+    host_directory().join("diagnostics.log")
+}
+pub fn backup_config_path() -> PathBuf {
+    host_directory().join("backup.json")
+}
+pub fn backup_index_path() -> PathBuf {
+    host_directory().join("backup_index.json")
+}
+pub fn log_directory() -> PathBuf {
+    host_directory().join("logs")
+}
+
+// Everything the server keeps for itself in the host and database directories
+fn internal_paths() -> Vec<PathBuf> {
+    vec![
+        user_database_path(), file_owner_db_path(), sqlite_database_path(), network_analyzer_path(), resume_tokens_path(), grants_path(), shares_path(), grants_audit_path(),
+        tls_directory(), retention_path(), retention_log_path(), staging_registry_path(), trash_directory(), trash_registry_path(), versions_directory(), delta_directory(),
+        quarantine_directory(), thumbnail_directory(), archive_directory(), server_config_path(), audit_log_path(), proxy_config_path(), diagnostics_log_path(),
+        backup_config_path(), backup_index_path(), log_directory()
+    ]
+}
+// Whether a path is something the server keeps for itself, which is a partial upload, or any of its state and what is beneath it.
+// State only sits beneath the root when the data directory is set to hold the host or database directory, but is never shown to clients when it does.
+pub fn is_internal_path(path: &Path) -> bool {
+    if path.file_name().is_some_and(|x| x.to_string_lossy().ends_with(STAGING_SUFFIX)) {
+        return true;
+    }
+
+    let paths = storage_paths();
+    (path.starts_with(&paths.host) || path.starts_with(&paths.database)) && internal_paths().iter().any(|x| path.starts_with(x))
+}
 
 pub fn ensure_directories() -> bool {
-    if fs::create_dir_all(host_directory()).is_err() || fs::create_dir_all(root_directory()).is_err() {
+    if fs::create_dir_all(host_directory()).is_err() || fs::create_dir_all(root_directory()).is_err() || fs::create_dir_all(database_directory()).is_err() {
         return false;
     }
 
@@ -54,6 +218,26 @@ pub fn ensure_directories() -> bool {
             fs::OpenOptions::new().create_new(true).truncate(false).open(network_analyzer_path())
         );
     }
+    if !resume_tokens_path().exists() {
+        results.push(
+            fs::OpenOptions::new().create_new(true).truncate(false).open(resume_tokens_path())
+        );
+    }
+    if !grants_path().exists() {
+        results.push(
+            fs::OpenOptions::new().create_new(true).truncate(false).open(grants_path())
+        );
+    }
+    if !shares_path().exists() {
+        results.push(
+            fs::OpenOptions::new().create_new(true).truncate(false).open(shares_path())
+        );
+    }
+    if !retention_path().exists() {
+        results.push(
+            fs::OpenOptions::new().create_new(true).truncate(false).open(retention_path())
+        );
+    }
 
     for result in results {
         if result.is_err() && result.err().unwrap().kind() != ErrorKind::AlreadyExists {
@@ -64,7 +248,28 @@ pub fn ensure_directories() -> bool {
     true
  }
 
-lazy_static! {
-    pub static ref NETWORK_ANALYZER: NetworkAnalyzer = NetworkAnalyzer::new();
-    pub static ref USER_DB: UserDatabase = UserDatabase::new();
-}  
\ No newline at end of file
+#[test]
+fn test_storage_paths() {
+    let host = PathBuf::from("/var/lib/hermes");
+    let defaults = StoragePaths::new(host.clone(), None, None);
+    assert_eq!((defaults.root, defaults.database), (host.join("data"), host.clone()));
+
+    let mounted = StoragePaths::new(host.clone(), Some(PathBuf::from("/mnt/share")), Some(PathBuf::from("/mnt/ssd/hermes")));
+    assert_eq!(mounted.state_file(OsStr::new("users.json")), PathBuf::from("/mnt/ssd/hermes/users.json"));
+    assert_eq!(mounted.state_file(OsStr::new("grants.json")), host.join("grants.json"));
+    assert_eq!((mounted.host, mounted.root, mounted.database), (host, PathBuf::from("/mnt/share"), PathBuf::from("/mnt/ssd/hermes")));
+
+    // Nothing installs paths in tests, so everything stays in the temp directory
+    assert_eq!(root_directory(), std::env::temp_dir());
+    assert_eq!(user_database_path(), std::env::temp_dir().join(format!("hermes_host_{}", std::process::id())).join("users.json"));
+}
+
+#[test]
+fn test_internal_paths() {
+    assert!(is_internal_path(&root_directory().join("docs").join(format!("a.txt{STAGING_SUFFIX}"))));
+    assert!(is_internal_path(&trash_directory().join("alice").join("1")));
+    assert!(is_internal_path(&user_database_path()));
+    assert!(!is_internal_path(&root_directory().join("docs").join("a.txt")));
+    assert!(!is_internal_path(&root_directory().join(".trash")));
+    assert!(!is_internal_path(&root_directory()));
+}
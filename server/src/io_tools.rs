@@ -1,11 +1,19 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::fs::canonicalize;
 use std::collections::HashMap;
 use std::fmt::{Display, Debug};
 
+use crate::config::SymlinkPolicy;
 use crate::credentials::Credentials;
-use crate::io_loc::root_directory;
-use hermes_common::file_io::{FileInfo, DirectoryContent, FileType, JsonFile};
+use crate::io_loc::{root_directory, is_internal_path};
+use hermes_common::error::HermesError;
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, checksum_file};
+use crate::resume::generate_token;
+use crate::storage::{JsonStorage, Record, RecordFilter, Storage};
+use hermes_common::file_io::{DirectoryInfo, Encryption, FileInfo, FileType, Provenance, detect_file_type};
+use hermes_common::messages::{Permission, Principal, VersionInfo};
+use hermes_common::session::unix_now;
 use serde::{Deserialize, Serialize};
 
 pub fn move_relative(raw_path: &str, curr_dir: &Path) -> Option<PathBuf> {
@@ -17,11 +25,44 @@ pub fn move_relative(raw_path: &str, curr_dir: &Path) -> Option<PathBuf> {
         Some(curr_dir.join(as_path))
     }
 }
+// Follows every link in a path that exists, giving where it really is beneath the root directory. None when it does not exist, or leads outside of the root.
 pub fn resolve_path(path: PathBuf) -> Option<PathBuf> {
-    match canonicalize(path) {
-        Ok(f) => Some(f),
-        Err(_) => None
+    resolve_beneath(&path, &root_directory())
+}
+// The same, for any root. The root itself may be reached through links, so both are resolved before they are compared, and the answer is given beneath the root as written.
+pub fn resolve_beneath(path: &Path, root: &Path) -> Option<PathBuf> {
+    let real_root = canonicalize(root).ok()?;
+    let relative = canonicalize(path).ok()?.strip_prefix(&real_root).ok()?.to_path_buf();
+    match relative.as_os_str().is_empty() {
+        true => Some(root.to_path_buf()),
+        false => Some(root.join(relative))
+    }
+}
+// Checks each link a path beneath the session's home passes through, including what it names, against the policy. Returns the status and reason it is refused with.
+// Links are only followed while they stay inside the home, so one cannot lead into another user's folder. Parts that do not exist yet are fine, as uploads and new folders name them.
+// The path is walked as written, so it should already be normalized.
+pub fn check_links(path: &Path, home: &Path, policy: SymlinkPolicy) -> Result<(), (HttpCodes, &'static str)> {
+    let relative = match path.strip_prefix(home) {
+        Ok(r) => r,
+        Err(_) => return Ok(())
+    };
+
+    let mut current = home.to_path_buf();
+    for part in relative.components() {
+        current.push(part);
+        match std::fs::symlink_metadata(&current) {
+            Ok(m) if m.is_symlink() => match policy {
+                SymlinkPolicy::Skip => return Err((HttpCodes::NotFound, "file not found")),
+                SymlinkPolicy::Error => return Err((HttpCodes::Forbidden, "links cannot be used on this server")),
+                SymlinkPolicy::FollowWithinRoot if resolve_beneath(&current, home).is_none() => return Err((HttpCodes::Forbidden, "the link does not lead anywhere beneath your home directory")),
+                SymlinkPolicy::FollowWithinRoot => { }
+            },
+            Ok(_) => { },
+            Err(_) => return Ok(())
+        }
     }
+
+    Ok(())
 }
 pub fn make_relative(path: &Path) -> Option<PathBuf> {
     if !is_path_valid(path) {
@@ -34,21 +75,44 @@ pub fn make_relative(path: &Path) -> Option<PathBuf> {
         path.strip_prefix(root_directory()).map(|p| p.to_path_buf()).ok()
     }
 }
-pub fn is_path_valid(path: &Path) -> bool {
-    let root_dir = root_directory();
+pub fn modified_secs(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok().map(|x| x.as_secs())
+}
+// The year, month, and day a count of days since the Unix epoch falls on, for the date formats of the protocols the server bridges to.
+// Eras of 400 years are counted from the 1st of March in year 0, so the leap day is always the last day of a year.
+pub fn civil_date(days: u64) -> (u64, u64, u64) {
+    let shifted = days + 719468;
+    let era = shifted / 146097;
+    let day_of_era = shifted % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
 
-    let our_path_len = path.iter().count();
-    let target_size = root_dir.iter().count();
-    if our_path_len == target_size && path == root_dir {
-        true
-    } else if our_path_len < target_size {
-        false
-    } else {
-        //Compare each element up to our items
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
+// A path is valid when it names the root directory or something beneath it
+pub fn is_path_valid(path: &Path) -> bool {
+    jail_depth(path, &root_directory()).is_some()
+}
+// How many directories below the jail a path ends up, or None if it leaves the jail. This is checked on the components alone,
+// so '..' may step back out of a subdirectory but never above the jail, whether or not the path exists.
+pub fn jail_depth(path: &Path, jail: &Path) -> Option<usize> {
+    let relative = path.strip_prefix(jail).ok()?;
 
-        let target_parts = PathBuf::from_iter(path.components().take(target_size));
-        target_parts == root_dir
+    let mut depth = 0usize;
+    for part in relative.components() {
+        match part {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => { },
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return None
+        }
     }
+
+    Some(depth)
 }
 
 #[test]
@@ -56,13 +120,13 @@ pub fn test_move_relative() {
     let curr_dir = root_directory();
     println!("{:?}", &curr_dir);
 
-    assert_eq!( move_relative("thing", &curr_dir).unwrap(), PathBuf::from("/Users/exdisj/cnt/data/thing"));
+    assert_eq!( move_relative("thing", &curr_dir).unwrap(), curr_dir.join("thing"));
 
-    assert_eq!( move_relative("", &curr_dir).unwrap(), PathBuf::from("/Users/exdisj/cnt/data"));
+    assert_eq!( move_relative("", &curr_dir).unwrap(), curr_dir);
 
-    assert_eq!( move_relative(".", &curr_dir).unwrap(), PathBuf::from("/Users/exdisj/cnt/data"));
+    assert_eq!( move_relative(".", &curr_dir).unwrap(), curr_dir);
 
-    assert_eq!( move_relative("..", &curr_dir).unwrap(), PathBuf::from("/Users/exdisj/cnt/data/.."));
+    assert_eq!( move_relative("..", &curr_dir).unwrap(), curr_dir.join(".."));
 }
 #[test]
 pub fn test_make_relative() {
@@ -70,7 +134,203 @@ pub fn test_make_relative() {
 }
 #[test]
 pub fn test_is_valid() {
+    let root = root_directory();
+
+    assert!(is_path_valid(&root));
+    assert!(is_path_valid(&root.join("a").join("..").join("b")));
+    assert!(!is_path_valid(&root.join("..")));
+    assert!(!is_path_valid(&root.join("a").join("..").join("..").join("etc")));
+    assert!(!is_path_valid(Path::new("/etc/passwd")));
+    assert!(!is_path_valid(Path::new("relative")));
+
+    let home = root.join("alice");
+    assert_eq!(jail_depth(&home.join("docs").join("a.txt"), &home), Some(2));
+    assert_eq!(jail_depth(&home.join("docs").join(".."), &home), Some(0));
+    assert_eq!(jail_depth(&home.join("..").join("bob"), &home), None);
+    assert_eq!(jail_depth(&root.join("bob"), &home), None);
+}
+#[test]
+fn test_links() {
+    let root = std::env::temp_dir().join(format!("hermes_links_{}", std::process::id()));
+    let outside = std::env::temp_dir().join(format!("hermes_links_outside_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::create_dir_all(&outside).unwrap();
+    std::fs::write(root.join("docs").join("a.txt"), "a").unwrap();
+    std::fs::write(outside.join("secret.txt"), "s").unwrap();
+
+    // Real paths resolve to themselves, and missing ones to nothing
+    assert_eq!(resolve_beneath(&root, &root), Some(root.clone()));
+    assert_eq!(resolve_beneath(&root.join("docs").join("a.txt"), &root), Some(root.join("docs").join("a.txt")));
+    assert_eq!(resolve_beneath(&root.join("missing"), &root), None);
+    for policy in [SymlinkPolicy::Skip, SymlinkPolicy::FollowWithinRoot, SymlinkPolicy::Error] {
+        assert_eq!(check_links(&root.join("docs").join("a.txt"), &root, policy), Ok(()));
+        assert_eq!(check_links(&root.join("docs").join("new").join("b.txt"), &root, policy), Ok(()));
+    }
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(root.join("docs"), root.join("inside")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+
+        // A link is followed to where it really is, and one leading out of the root resolves to nothing
+        assert_eq!(resolve_beneath(&root.join("inside").join("a.txt"), &root), Some(root.join("docs").join("a.txt")));
+        assert_eq!(resolve_beneath(&root.join("escape").join("secret.txt"), &root), None);
+
+        assert_eq!(check_links(&root.join("inside").join("a.txt"), &root, SymlinkPolicy::Skip).unwrap_err().0, HttpCodes::NotFound);
+        assert_eq!(check_links(&root.join("inside").join("a.txt"), &root, SymlinkPolicy::Error).unwrap_err().0, HttpCodes::Forbidden);
+        assert_eq!(check_links(&root.join("inside").join("a.txt"), &root, SymlinkPolicy::FollowWithinRoot), Ok(()));
+        assert_eq!(check_links(&root.join("escape").join("secret.txt"), &root, SymlinkPolicy::FollowWithinRoot).unwrap_err().0, HttpCodes::Forbidden);
+        assert_eq!(check_links(&root.join("escape"), &root, SymlinkPolicy::FollowWithinRoot).unwrap_err().0, HttpCodes::Forbidden);
+
+        // A link from one home into another stays beneath the root, but not beneath the home it is followed from
+        std::fs::create_dir_all(root.join("alice")).unwrap();
+        std::fs::create_dir_all(root.join("bob")).unwrap();
+        std::fs::write(root.join("bob").join("private.txt"), "p").unwrap();
+        std::os::unix::fs::symlink(root.join("bob"), root.join("alice").join("bob")).unwrap();
+        assert_eq!(check_links(&root.join("alice").join("bob").join("private.txt"), &root, SymlinkPolicy::FollowWithinRoot), Ok(()));
+        assert_eq!(check_links(&root.join("alice").join("bob").join("private.txt"), &root.join("alice"), SymlinkPolicy::FollowWithinRoot).unwrap_err().0, HttpCodes::Forbidden);
+        assert_eq!(check_links(&root.join("alice").join("bob"), &root.join("alice"), SymlinkPolicy::FollowWithinRoot).unwrap_err().0, HttpCodes::Forbidden);
+    }
+
+    let _ = std::fs::remove_dir_all(&root);
+    let _ = std::fs::remove_dir_all(&outside);
+}
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Names a client might send, leaning on the ones that move around the tree
+    fn component() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::from("..")),
+            Just(String::from(".")),
+            Just(String::from("...")),
+            Just(String::from("..\\..")),
+            Just(String::from("~")),
+            "[^/\\x00]{1,12}"
+        ]
+    }
 
+    proptest! {
+        // Walking the components by hand and counting depth gives the same answer as is_path_valid
+        #[test]
+        fn test_is_path_valid_tracks_depth(parts in prop::collection::vec(component(), 0..10)) {
+            let mut path = root_directory();
+            let mut depth = 0i64;
+            let mut escaped = false;
+            for part in &parts {
+                path.push(part);
+                for c in Path::new(part).components() {
+                    match c {
+                        Component::ParentDir => depth -= 1,
+                        Component::Normal(_) => depth += 1,
+                        _ => { }
+                    }
+                    escaped |= depth < 0;
+                }
+            }
+
+            prop_assert_eq!(is_path_valid(&path), !escaped);
+        }
+
+        // Nothing a client sends can name a path outside the root, however it is spelled
+        #[test]
+        fn test_resolved_targets_stay_in_root(parts in prop::collection::vec(component(), 0..10)) {
+            let root = root_directory();
+            let raw = parts.join("/");
+            if let Some(path) = move_relative(&raw, &root).filter(|x| is_path_valid(x)) {
+                let mut kept = Vec::new();
+                for c in path.strip_prefix(&root).unwrap().components() {
+                    match c {
+                        Component::ParentDir => { prop_assert!(kept.pop().is_some()); },
+                        Component::Normal(n) => kept.push(n.to_os_string()),
+                        _ => { }
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_outside_paths_are_invalid(parts in prop::collection::vec(component(), 0..6)) {
+            let mut path = PathBuf::from("/hermes-not-the-root");
+            path.extend(&parts);
+            prop_assert!(!is_path_valid(&path));
+        }
+    }
+}
+
+// Permissions on a file, or on everything beneath a folder, given to someone other than its owner
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct AclEntry {
+    principal: Principal,
+    permissions: Vec<Permission>
+}
+impl AclEntry {
+    pub fn principal(&self) -> &Principal {
+        &self.principal
+    }
+    pub fn permissions(&self) -> &Vec<Permission> {
+        &self.permissions
+    }
+    pub fn applies_to(&self, user: &Credentials) -> bool {
+        match &self.principal {
+            Principal::User(u) => u == user.username(),
+            Principal::Group(g) => user.in_group(g)
+        }
+    }
+}
+
+// Content an upload replaced, moved aside into the versions directory rather than overwritten
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct FileVersion {
+    number: u32,
+    stored: PathBuf,
+    size: u64,
+    modified: Option<u64>,
+    replaced_at: u64,
+    replaced_by: Option<String>
+}
+impl FileVersion {
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+    pub fn stored(&self) -> &Path {
+        &self.stored
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn info(&self) -> VersionInfo {
+        VersionInfo {
+            number: self.number,
+            size: self.size,
+            modified: self.modified,
+            replaced_at: self.replaced_at,
+            replaced_by: self.replaced_by.clone()
+        }
+    }
+}
+
+// Where a file the scanner found infected was uploaded to, and what was found in it
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Quarantine {
+    original: PathBuf,
+    signature: String,
+    quarantined_at: u64
+}
+
+// A checksum of a file, which only describes it while its size and modification time are unchanged
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct KnownChecksum {
+    checksum: Checksum,
+    size: u64,
+    modified: Option<u64>
+}
+impl KnownChecksum {
+    fn describes(&self, metadata: &std::fs::Metadata) -> bool {
+        self.size == metadata.len() && self.modified == modified_secs(metadata)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -78,7 +338,37 @@ pub struct ServerFile {
     id: u32,
     path: PathBuf,
     kind: FileType,
-    owner: Option<Credentials>
+    owner: Option<Credentials>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    immutable: bool,
+    #[serde(default)]
+    provenance: Option<Provenance>,
+    #[serde(default)]
+    acl: Vec<AclEntry>,
+    #[serde(default)]
+    versions: Vec<FileVersion>, //Oldest first
+    #[serde(default)]
+    checksum: Option<KnownChecksum>,
+    #[serde(default)]
+    encryption: Option<Encryption>,
+    #[serde(default)]
+    quarantine: Option<Quarantine> //Set on files held in quarantine, whose path is where they are held
+}
+impl Record for ServerFile {
+    fn key(&self) -> String {
+        self.id.to_string()
+    }
+    fn owner(&self) -> Option<&str> {
+        self.owner.as_ref().map(|x| x.username())
+    }
+    fn kind(&self) -> Option<String> {
+        Some(self.kind.to_string())
+    }
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
 }
 impl Debug for ServerFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -133,7 +423,15 @@ impl ServerFile {
                     id,
                     path,
                     owner,
-                    kind
+                    kind,
+                    tags: Vec::new(),
+                    immutable: false,
+                    provenance: None,
+                    acl: Vec::new(),
+                    versions: Vec::new(),
+                    checksum: None,
+                    encryption: None,
+                    quarantine: None
                 }
             )
         }
@@ -151,15 +449,184 @@ impl ServerFile {
     pub fn set_owner(&mut self, cred: Option<Credentials>) {
         self.owner = cred
     }
-    pub fn file_type(&self) -> FileType {
-        self.kind
+    pub fn is_owned_by(&self, user: &Credentials) -> bool {
+        self.owner.as_ref().is_some_and(|x| x.username() == user.username())
+    }
+    pub fn file_type(&self) -> &FileType {
+        &self.kind
+    }
+
+    // A file under legal hold cannot be deleted, overwritten, moved, or expired until the hold is cleared
+    pub fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+    pub fn set_immutable(&mut self, immutable: bool) {
+        self.immutable = immutable;
+    }
+
+    pub fn file_info(&self) -> Option<FileInfo> {
+        let name = self.path.file_name()?.to_str()?.to_string();
+        let metadata = std::fs::metadata(&self.path).ok()?;
+        let owner = match self.owner.as_ref() {
+            Some(u) => u.username().to_string(),
+            None => String::from("any")
+        };
+
+        let mut result = FileInfo::new(name, owner, self.kind.clone(), metadata.len());
+        result.set_immutable(self.immutable);
+        result.set_provenance(self.provenance.clone());
+        result.set_metadata(&metadata);
+        result.set_checksum(self.checksum(&metadata).cloned());
+        result.set_encryption(self.encryption);
+        Some(result)
+    }
+
+    // Remembers the checksum of what is on disk now. Only the database sets it, since it keeps an index of them.
+    fn set_checksum(&mut self, checksum: Checksum) {
+        self.checksum = std::fs::metadata(&self.path).ok().map(|m| KnownChecksum { checksum, size: m.len(), modified: modified_secs(&m) });
+    }
+    // The remembered checksum, unless the file has changed since it was taken
+    pub fn checksum(&self, metadata: &std::fs::Metadata) -> Option<&Checksum> {
+        self.checksum.as_ref().filter(|k| k.describes(metadata)).map(|k| &k.checksum)
+    }
+
+    // Replaced on every upload to this path, since it describes the current contents
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+    pub fn set_provenance(&mut self, provenance: Option<Provenance>) {
+        self.provenance = provenance;
+    }
+
+    // Also replaced on every upload, as only the client that sent the contents knows whether it encrypted them
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+    pub fn set_encryption(&mut self, encryption: Option<Encryption>) {
+        self.encryption = encryption;
+    }
+
+    pub fn tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|x| x == tag)
+    }
+    pub fn add_tag(&mut self, tag: &str) {
+        if !self.has_tag(tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+    pub fn remove_tag(&mut self, tag: &str) {
+        self.tags.retain(|x| x != tag);
+    }
+
+    pub fn acl(&self) -> &Vec<AclEntry> {
+        &self.acl
+    }
+    pub fn versions(&self) -> &Vec<FileVersion> {
+        &self.versions
+    }
+    // The bytes this record holds on disk, counting its versions
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantine.is_some()
+    }
+    pub fn stored_bytes(&self) -> u64 {
+        let current = std::fs::symlink_metadata(&self.path).ok().filter(|x| x.is_file()).map(|x| x.len()).unwrap_or_default();
+        current + self.versions.iter().map(|x| x.size).sum::<u64>()
+    }
+    pub fn version(&self, number: u32) -> Option<&FileVersion> {
+        self.versions.iter().find(|x| x.number == number)
+    }
+    // Removes the stored copies of every version, for when the record itself goes for good
+    pub fn discard_versions(&mut self) {
+        for version in self.versions.drain(..) {
+            let _ = std::fs::remove_file(&version.stored);
+        }
+    }
+    pub fn grants(&self, user: &Credentials, permission: Permission) -> bool {
+        self.acl.iter().any(|x| x.applies_to(user) && x.permissions.contains(&permission))
+    }
+    pub fn permit(&mut self, principal: &Principal, permissions: &[Permission]) {
+        let entry = match self.acl.iter_mut().find(|x| x.principal == *principal) {
+            Some(e) => e,
+            None => {
+                self.acl.push(AclEntry { principal: principal.clone(), permissions: Vec::new() });
+                self.acl.last_mut().unwrap()
+            }
+        };
+
+        for permission in permissions {
+            if !entry.permissions.contains(permission) {
+                entry.permissions.push(*permission);
+            }
+        }
+    }
+    // Entries left with no permissions are dropped
+    pub fn revoke(&mut self, principal: &Principal, permissions: &[Permission]) {
+        for entry in self.acl.iter_mut().filter(|x| x.principal == *principal) {
+            entry.permissions.retain(|x| !permissions.contains(x));
+        }
+        self.acl.retain(|x| !x.permissions.is_empty());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileMutation {
+    Delete,
+    Overwrite,
+    Move,
+    Expire
+}
+impl Display for FileMutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Delete => "delete",
+            Self::Overwrite => "overwrite",
+            Self::Move => "move",
+            Self::Expire => "expire"
+        };
+
+        write!(f, "{text}")
+    }
+}
+
+// What a pass of FileDatabase::index changed
+#[derive(Default, PartialEq, Debug)]
+pub struct IndexReport {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize
+}
+
+// The files with each checksum, as last taken, and what each was taken against
+type ChecksumIndex = HashMap<String, HashMap<PathBuf, KnownChecksum>>;
+
+// Files held in quarantine are left out, so nothing is ever matched with one
+fn index_checksum(index: &mut ChecksumIndex, file: &ServerFile) {
+    if let Some(known) = file.checksum.as_ref().filter(|_| file.quarantine.is_none()) {
+        index.entry(known.checksum.to_string()).or_default().insert(file.path.clone(), known.clone());
+    }
+}
+fn unindex_checksum(index: &mut ChecksumIndex, file: &ServerFile) {
+    let key = match file.checksum.as_ref() {
+        Some(k) => k.checksum.to_string(),
+        None => return
+    };
+
+    if let Some(paths) = index.get_mut(&key) {
+        paths.remove(&file.path);
+        if paths.is_empty() {
+            index.remove(&key);
+        }
     }
 }
 
 pub struct FileDatabase {
-    file: JsonFile,
+    storage: Option<Box<dyn Storage<ServerFile>>>,
     data: Vec<ServerFile>,
-    curr_id: u32
+    curr_id: u32,
+    checksums: ChecksumIndex //Kept in step with every record that is added, removed, moved, or given a checksum
 }
 impl Default for FileDatabase {
     fn default() -> Self {
@@ -169,9 +636,10 @@ impl Default for FileDatabase {
 impl FileDatabase {
     pub fn new() -> Self {
         Self {
-            file: JsonFile::new(),
+            storage: None,
             data: vec![],
-            curr_id: 0
+            curr_id: 0,
+            checksums: HashMap::new()
         }
     }
 
@@ -181,65 +649,78 @@ impl FileDatabase {
         self.curr_id
     }
 
-    pub fn index(&mut self, host_dir: &Path) -> Result<(), String> {
-        /*
-            We need to:
-
-            1. Review everything in the whole directory structure
-            2. Load all contents into a HashMap<String, &ServerFile>
-            3. Find all files that are in our directory that are *not* in the HashMap
-            4. Add those files into the structure, under the Any user. 
-         */
-
-        if !self.file.is_open() {
-            return Err(String::from("database is not currently open"));
+    // Brings the database in line with what is on disk beneath the root. Files nobody registered are added under the Any user,
+    // and records of paths beneath the root that no longer exist are dropped. Partial uploads are left to the staging registry.
+    pub fn index(&mut self, root: &Path) -> Result<IndexReport, HermesError> {
+        if self.storage.is_none() {
+            return Err(HermesError::NotOpen(String::from("database is not currently open")));
         }
 
-        let mut loaded_files: HashMap<String, &ServerFile> = HashMap::new();
+        let mut loaded_files: HashMap<&Path, &ServerFile> = HashMap::new();
         for file in &self.data {
-            let path = match file.path.to_str() {
-                Some(s) => String::from(s),
-                None => return Err(String::from("could not convert path to string"))
-            };
-            
-            if let Some(f) = loaded_files.insert(path, file) {
-                return Err(format!("duplicate path determined at {:?}", f.path));
+            if let Some(f) = loaded_files.insert(&file.path, file) {
+                return Err(HermesError::Conflict(format!("duplicate path determined at {:?}", f.path)));
             }
         }
 
-        todo!()
-    }
-    fn open(&mut self, path: &str) -> Result<(), String> {
-        let contents = self.file.open(path)?;
-
-        let list: Result<Vec<ServerFile>, _> = serde_json::from_str(&contents);
-        match list {
-            Ok(l) => {
-                self.data = l;
+        // Every regular file beneath the root, leaving out links, partial uploads and anything else the server keeps for itself, and folders that cannot be read
+        let on_disk = match root.is_dir() {
+            true => DirectoryInfo::from_path(root, u32::MAX, &|_, _| None)?.file_paths(root),
+            false => Vec::new()
+        };
+        let unknown: Vec<PathBuf> = on_disk.into_iter().filter(|x| !loaded_files.contains_key(x.as_path()) && !is_internal_path(x)).collect();
 
-                let max_id = self.data.iter().map(|x| x.id).max();
-                self.curr_id = match max_id {
-                    Some(x) => x,
-                    None => 0
-                };
+        let removed = self.drop_where(|x| x.path.starts_with(root) && !x.path.exists());
+        let mut report = IndexReport { added: 0, removed, unchanged: self.data.len() };
 
-                Ok(())
-            },
-            Err(e) => Err(e.to_string())
+        for path in unknown {
+            let kind = detect_file_type(&path).unwrap_or(FileType::Binary);
+            let checksum = checksum_file(&path, ChecksumAlgorithm::Sha256).ok();
+            let id = self.register_file(path, None, kind)?;
+            if let Some(c) = checksum {
+                self.set_checksum(id, c);
+            }
+            report.added += 1;
         }
+
+        Ok(report)
     }
-    pub fn save(&self) -> Result<(), String> {
-        let contents_str = match serde_json::to_string(&self.data) {
-            Ok(c) => c,
-            Err(e) => return Err(e.to_string())
-        };
+    pub fn open(&mut self, path: &str) -> Result<(), HermesError> {
+        self.open_storage(Box::new(JsonStorage::new(Path::new(path))))
+    }
+    pub fn open_storage(&mut self, storage: Box<dyn Storage<ServerFile>>) -> Result<(), HermesError> {
+        if let Some(s) = self.storage.as_ref() {
+            return Err(HermesError::Conflict(format!("file already opened, at path '{}'", s.location().display())));
+        }
 
-        self.file.save(&contents_str)
+        self.data = storage.load()?;
+        self.storage = Some(storage);
+        self.checksums.clear();
+        for file in &self.data {
+            index_checksum(&mut self.checksums, file);
+        }
+
+        let max_id = self.data.iter().map(|x| x.id).max();
+        self.curr_id = max_id.unwrap_or_default();
+
+        Ok(())
+    }
+    pub fn save(&self) -> Result<(), HermesError> {
+        match self.storage.as_ref() {
+            Some(s) => s.save(&self.data),
+            None => Err(HermesError::NotOpen(String::from("database is not currently open")))
+        }
     }
 
     pub fn close(&mut self) {
         self.data.clear();
-        self.file.close();
+        self.checksums.clear();
+        self.storage = None;
+    }
+
+    // The records matching a filter, such as everything one user owns, or everything of one type beneath a folder
+    pub fn query(&self, filter: &RecordFilter) -> Vec<&ServerFile> {
+        self.data.iter().filter(|x| filter.matches(*x)).collect()
     }
 
     pub fn get_file(&self, id: u32) -> Option<&ServerFile> {
@@ -251,32 +732,228 @@ impl FileDatabase {
     pub fn get_file_id(&self, path: &Path) -> Option<u32> {
         Some( self.data.iter().find(|x| x.path == path)?.id )
     }
+    pub fn get_file_by_path(&self, path: &Path) -> Option<&ServerFile> {
+        self.data.iter().find(|x| x.path == path)
+    }
+
+    // Remembers the checksum of what is on disk now at a record's path
+    pub fn set_checksum(&mut self, id: u32, checksum: Checksum) -> bool {
+        let file = match self.data.iter_mut().find(|x| x.id == id) {
+            Some(f) => f,
+            None => return false
+        };
+
+        unindex_checksum(&mut self.checksums, file);
+        file.set_checksum(checksum);
+        index_checksum(&mut self.checksums, file);
+        true
+    }
+    // The files whose checksum is this one and still describes them, looked up without going through every record
+    pub fn files_with_checksum(&self, checksum: &Checksum) -> Vec<(PathBuf, std::fs::Metadata)> {
+        let paths = match self.checksums.get(&checksum.to_string()) {
+            Some(p) => p,
+            None => return Vec::new()
+        };
+
+        paths.iter()
+            .filter_map(|(path, known)| std::fs::symlink_metadata(path).ok().filter(|m| m.is_file() && known.describes(m)).map(|m| (path.clone(), m)))
+            .collect()
+    }
+
+    // Every handler that changes something on disk must ask here first. A path is blocked if it, anything beneath it, or a folder above it is under legal hold.
+    pub fn check_mutation(&self, path: &Path, mutation: FileMutation) -> Result<(), HermesError> {
+        match self.data.iter().find(|x| x.immutable && (x.path.starts_with(path) || path.starts_with(&x.path))) {
+            Some(f) => Err(HermesError::Conflict(format!("cannot {} '{}' because '{}' is under legal hold", mutation, path.display(), f.path.display()))),
+            None => Ok(())
+        }
+    }
+    // A record lets a user through when it has no owner, or when the user owns it or a folder above it, or when the access list
+    // of either gives them the permission. Without a user, as for upload grants whose issuer is gone, only records with no owner do.
+    fn allows(&self, file: &ServerFile, user: Option<&Credentials>, permission: Permission) -> bool {
+        if file.owner.is_none() {
+            return true;
+        }
+
+        let user = match user {
+            Some(u) => u,
+            None => return false
+        };
+        self.data.iter()
+            .filter(|x| file.path.starts_with(&x.path))
+            .any(|x| x.is_owned_by(user) || x.grants(user, permission))
+    }
+    // Every handler that reads or changes something another user could own must ask here first. The folders above a path, and everything
+    // beneath it, all have to let the user through, so a path that does not exist yet is checked against the folders it would go in.
+    // Administrators are never refused, and neither are guests reading, since they are jailed to the public folder.
+    pub fn check_access(&self, path: &Path, user: Option<&Credentials>, permission: Permission) -> Result<(), HermesError> {
+        if user.is_some_and(|x| x.is_admin() || (x.is_guest() && permission == Permission::Read)) {
+            return Ok(());
+        }
+
+        let refused = self.data.iter().find(|x| (x.path.starts_with(path) || path.starts_with(&x.path)) && !self.allows(x, user, permission));
+        match refused {
+            Some(f) => Err(HermesError::Auth(format!("no {} permission on '{}'", permission, f.path.display()))),
+            None => Ok(())
+        }
+    }
+    // The owner of a record, or of a folder above it, decides who it is shared with
+    pub fn can_share(&self, path: &Path, user: &Credentials) -> bool {
+        user.is_admin() || self.data.iter().any(|x| path.starts_with(&x.path) && x.is_owned_by(user))
+    }
+    pub fn set_immutable(&mut self, id: u32, immutable: bool) -> Result<(), HermesError> {
+        match self.get_file_mut(id) {
+            Some(f) => {
+                f.set_immutable(immutable);
+                Ok(())
+            },
+            None => Err(HermesError::NotFound(format!("file not found with id {}", id)))
+        }
+    }
+
+    // Drops matching records for good, along with their stored versions
+    fn drop_where(&mut self, selected: impl Fn(&ServerFile) -> bool) -> usize {
+        let (mut dropped, kept): (Vec<ServerFile>, Vec<ServerFile>) = std::mem::take(&mut self.data).into_iter().partition(selected);
+        self.data = kept;
+        for file in dropped.iter_mut() {
+            unindex_checksum(&mut self.checksums, file);
+            file.discard_versions();
+        }
+
+        dropped.len()
+    }
+    pub fn unregister_file(&mut self, path: &Path) -> bool {
+        self.drop_where(|x| x.path == path) > 0
+    }
+    // Points the records of a path, and of everything beneath it, at where it was renamed to. Owners, tags, and holds go with them.
+    pub fn rename_tree(&mut self, from: &Path, to: &Path) -> usize {
+        let mut renamed = 0;
+        for file in self.data.iter_mut() {
+            if let Ok(rest) = file.path.strip_prefix(from) {
+                let moved = if rest.as_os_str().is_empty() { to.to_path_buf() } else { to.join(rest) };
+                unindex_checksum(&mut self.checksums, file);
+                file.path = moved;
+                index_checksum(&mut self.checksums, file);
+                renamed += 1;
+            }
+        }
+
+        renamed
+    }
+    // Drops the records of a path and of everything beneath it, returning how many went
+    pub fn unregister_tree(&mut self, path: &Path) -> usize {
+        self.drop_where(|x| x.path.starts_with(path))
+    }
+    // Drops the records beneath a path whose files are gone from disk, leaving those that are still there, as after a delete that stopped part way
+    pub fn unregister_removed(&mut self, path: &Path) -> usize {
+        self.drop_where(|x| x.path.starts_with(path) && std::fs::symlink_metadata(&x.path).is_err())
+    }
+
+    // Records a file held in quarantine, which keeps its uploader as the owner, and says where it was sent to and what was found in it
+    pub fn quarantine(&mut self, stored: PathBuf, original: &Path, owner: Option<Credentials>, signature: &str) -> Result<u32, HermesError> {
+        let kind = detect_file_type(&stored).unwrap_or(FileType::Binary);
+        let id = self.register_file(stored, owner, kind)?;
+        if let Some(f) = self.get_file_mut(id) {
+            f.quarantine = Some(Quarantine { original: original.to_path_buf(), signature: signature.to_string(), quarantined_at: unix_now() });
+        }
+
+        Ok(id)
+    }
+
+    // Moves the file at a path aside as its newest version, before an upload replaces it. Only the newest max_versions are kept.
+    // Returns the version's number, or None when there was nothing to keep.
+    pub fn keep_version(&mut self, path: &Path, directory: &Path, replaced_by: Option<&str>, max_versions: usize) -> Result<Option<u32>, HermesError> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(m) if m.is_file() => m,
+            _ => return Ok(None)
+        };
+        let file = match self.data.iter_mut().find(|x| x.path == path) {
+            Some(f) => f,
+            None => return Ok(None)
+        };
+
+        std::fs::create_dir_all(directory)?;
+        let stored = directory.join(generate_token());
+        std::fs::rename(path, &stored)?;
+
+        let number = file.versions.iter().map(|x| x.number).max().unwrap_or_default() + 1;
+        file.versions.push(
+            FileVersion {
+                number,
+                stored,
+                size: metadata.len(),
+                modified: modified_secs(&metadata),
+                replaced_at: unix_now(),
+                replaced_by: replaced_by.map(String::from)
+            }
+        );
+
+        let excess = file.versions.len().saturating_sub(max_versions);
+        for version in file.versions.drain(..excess) {
+            let _ = std::fs::remove_file(&version.stored);
+        }
+
+        Ok(Some(number))
+    }
+    // Puts the newest version back in place, for when the upload that replaced it could not be moved in after all
+    pub fn revert_version(&mut self, path: &Path) -> Result<(), HermesError> {
+        let file = self.data.iter_mut().find(|x| x.path == path).ok_or_else(|| HermesError::NotFound(String::from("file not found")))?;
+        let version = file.versions.pop().ok_or_else(|| HermesError::NotFound(String::from("the file has no versions")))?;
+
+        std::fs::rename(&version.stored, path).map_err(HermesError::from)
+    }
 
-    pub fn set_file_owner(&mut self, id: u32, user: Credentials) -> Result<(), String> {
+    // Removes the records of a path and of everything beneath it, handing them back so they can be restored later
+    pub fn take_tree(&mut self, path: &Path) -> Vec<ServerFile> {
+        let (taken, kept): (Vec<ServerFile>, Vec<ServerFile>) = std::mem::take(&mut self.data).into_iter().partition(|x| x.path.starts_with(path));
+        self.data = kept;
+        for file in &taken {
+            unindex_checksum(&mut self.checksums, file);
+        }
+
+        taken
+    }
+    // Puts back records that take_tree removed, under fresh ids. Paths that were registered again in the meantime keep their new record.
+    pub fn restore_tree(&mut self, records: Vec<ServerFile>) -> usize {
+        let mut restored = 0;
+        for mut record in records {
+            if self.data.iter().any(|x| x.path == record.path) {
+                continue;
+            }
+
+            record.id = self.get_next_id();
+            index_checksum(&mut self.checksums, &record);
+            self.data.push(record);
+            restored += 1;
+        }
+
+        restored
+    }
+
+    pub fn set_file_owner(&mut self, id: u32, user: Credentials) -> Result<(), HermesError> {
         let file = match self.get_file_mut(id) {
             Some(s) => s,
-            None => return Err(format!("file not found with id {}", id))
+            None => return Err(HermesError::NotFound(format!("file not found with id {}", id)))
         };
 
         file.set_owner(Some(user));
         Ok(())
     }
 
-    pub fn register_file(&mut self, path: PathBuf, owner: Option<Credentials>, kind: FileType) -> Result<u32, String> {
+    pub fn register_file(&mut self, path: PathBuf, owner: Option<Credentials>, kind: FileType) -> Result<u32, HermesError> {
         //First we determine if it is already contained
 
         {
             let prev_contained = self.data.iter().find(|x| x.path == path);
             if let Some(i) = prev_contained {
                 return Err(
-                    format!(
+                    HermesError::Conflict(format!(
                         "path previously contained by owner '{}'",
                         if let Some(u) = i.owner() {
                             u.username()
                         } else {
                             "any"
                         }
-                    )
+                    ))
                 )
             }
         }
@@ -288,15 +965,165 @@ impl FileDatabase {
             self.get_next_id()
         );
 
-        match new_file {
-            Ok(f) => {
-                let id = f.id();
-                self.data.push(f);
+        let new_file = new_file?;
+        let id = new_file.id();
+        self.data.push(new_file);
 
-                Ok(id)
-            },
-            Err(e) => Err(e.to_string())
-        }
+        Ok(id)
     }
 
-}
\ No newline at end of file
+}
+#[test]
+fn test_index() {
+    let base = std::env::temp_dir().join(format!("hermes_index_{}", std::process::id()));
+    let dir = base.join("data");
+    std::fs::create_dir_all(dir.join("docs")).unwrap();
+    std::fs::write(dir.join("known.txt"), b"k").unwrap();
+    std::fs::write(dir.join("gone.txt"), b"g").unwrap();
+    std::fs::write(dir.join("docs").join("new.txt"), b"n").unwrap();
+    std::fs::write(dir.join("docs").join("half.txt.hermes.part"), b"h").unwrap();
+
+    std::fs::write(base.join("files.json"), b"").unwrap();
+    let mut files = FileDatabase::new();
+    files.open(base.join("files.json").to_str().unwrap()).unwrap();
+    files.register_file(dir.join("known.txt"), Some(Credentials::from("alice", "pass")), FileType::Text).unwrap();
+    files.register_file(dir.join("gone.txt"), None, FileType::Text).unwrap();
+    std::fs::remove_file(dir.join("gone.txt")).unwrap();
+
+    let report = files.index(&dir).unwrap();
+    assert_eq!(report, IndexReport { added: 1, removed: 1, unchanged: 1 });
+    assert!(files.get_file_by_path(&dir.join("gone.txt")).is_none());
+    assert!(files.get_file_by_path(&dir.join("docs").join("new.txt")).is_some_and(|x| x.owner().is_none()));
+    assert!(files.get_file_by_path(&dir.join("docs").join("half.txt.hermes.part")).is_none());
+    assert!(files.get_file_by_path(&dir.join("known.txt")).is_some_and(|x| x.owner().is_some()));
+
+    // A second pass finds nothing to do
+    assert_eq!(files.index(&dir).unwrap(), IndexReport { added: 0, removed: 0, unchanged: 2 });
+
+    // Indexed files are listed with their checksum until they change
+    let new = dir.join("docs").join("new.txt");
+    let info = files.get_file_by_path(&new).unwrap().file_info().unwrap();
+    assert_eq!(info.checksum(), checksum_file(&new, ChecksumAlgorithm::Sha256).ok().as_ref());
+    assert!(info.modified().is_some() && !info.is_hidden());
+    std::fs::write(&new, b"changed").unwrap();
+    assert!(files.get_file_by_path(&new).unwrap().file_info().unwrap().checksum().is_none());
+
+    let _ = std::fs::remove_dir_all(&base);
+}
+#[test]
+fn test_check_mutation() {
+    let dir = std::env::temp_dir().join(format!("hermes_mutation_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("held")).unwrap();
+    let mut files = FileDatabase::new();
+    let folder = files.register_file(dir.join("held"), None, FileType::Binary).unwrap();
+    let mutations = [FileMutation::Delete, FileMutation::Overwrite, FileMutation::Move, FileMutation::Expire];
+
+    // A held folder blocks itself, everything beneath it, and the folders above it
+    files.set_immutable(folder, true).unwrap();
+    for mutation in mutations {
+        assert!(files.check_mutation(&dir.join("held"), mutation).is_err());
+        assert!(files.check_mutation(&dir.join("held").join("inner").join("a.txt"), mutation).is_err());
+        assert!(files.check_mutation(&dir, mutation).is_err());
+    }
+    assert!(files.check_mutation(&dir.join("held.txt"), FileMutation::Delete).is_ok());
+    assert!(files.check_mutation(&dir.join("other"), FileMutation::Delete).is_ok());
+
+    files.set_immutable(folder, false).unwrap();
+    for mutation in mutations {
+        assert!(files.check_mutation(&dir.join("held").join("inner").join("a.txt"), mutation).is_ok());
+    }
+    assert!(files.set_immutable(folder + 1, true).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+#[test]
+fn test_check_access() {
+    use hermes_common::messages::Role;
+
+    let dir = std::env::temp_dir().join(format!("hermes_access_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("shared")).unwrap();
+    std::fs::write(dir.join("alice.txt"), b"a").unwrap();
+    std::fs::write(dir.join("open.txt"), b"o").unwrap();
+    std::fs::write(dir.join("shared").join("plan.txt"), b"p").unwrap();
+
+    let alice = Credentials::from("alice", "pass");
+    let bob = Credentials::from("bob", "pass");
+    let mut carol = Credentials::from("carol", "pass");
+    carol.set_groups(vec![String::from("team")]);
+    let mut admin = Credentials::from("root", "pass");
+    admin.set_role(Role::Admin);
+
+    let mut files = FileDatabase::new();
+    files.register_file(dir.join("alice.txt"), Some(alice.clone()), FileType::Text).unwrap();
+    files.register_file(dir.join("open.txt"), None, FileType::Text).unwrap();
+    let shared = files.register_file(dir.join("shared"), Some(alice.clone()), FileType::Binary).unwrap();
+    files.register_file(dir.join("shared").join("plan.txt"), Some(alice.clone()), FileType::Text).unwrap();
+
+    fn check(files: &FileDatabase, path: &Path, user: &Credentials, permission: Permission) -> bool {
+        files.check_access(path, Some(user), permission).is_ok()
+    }
+    assert!(check(&files, &dir.join("alice.txt"), &alice, Permission::Delete));
+    assert!(!check(&files, &dir.join("alice.txt"), &bob, Permission::Read));
+    assert!(check(&files, &dir.join("alice.txt"), &admin, Permission::Delete));
+    assert!(check(&files, &dir.join("open.txt"), &bob, Permission::Delete));
+    assert!(files.check_access(&dir.join("open.txt"), None, Permission::Write).is_ok());
+    assert!(files.check_access(&dir.join("alice.txt"), None, Permission::Read).is_err());
+
+    // Someone else's file anywhere beneath a folder blocks the whole folder
+    assert!(!check(&files, &dir, &bob, Permission::Delete));
+
+    // Sharing a folder covers what is in it, and what will be created in it
+    let folder = files.get_file_mut(shared).unwrap();
+    folder.permit(&Principal::User(String::from("bob")), &[Permission::Read, Permission::Write]);
+    folder.permit(&Principal::Group(String::from("team")), &[Permission::Read]);
+    assert!(check(&files, &dir.join("shared").join("plan.txt"), &bob, Permission::Read));
+    assert!(check(&files, &dir.join("shared").join("new.txt"), &bob, Permission::Write));
+    assert!(!check(&files, &dir.join("shared").join("plan.txt"), &bob, Permission::Delete));
+    assert!(check(&files, &dir.join("shared").join("plan.txt"), &carol, Permission::Read));
+    assert!(!check(&files, &dir.join("shared").join("new.txt"), &carol, Permission::Write));
+    assert!(files.can_share(&dir.join("shared").join("plan.txt"), &alice));
+    assert!(!files.can_share(&dir.join("shared").join("plan.txt"), &bob));
+
+    files.get_file_mut(shared).unwrap().revoke(&Principal::User(String::from("bob")), &[Permission::Read, Permission::Write]);
+    assert_eq!(files.get_file(shared).unwrap().acl().len(), 1);
+    assert!(!check(&files, &dir.join("shared").join("plan.txt"), &bob, Permission::Read));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+#[test]
+fn test_keep_version() {
+    let base = std::env::temp_dir().join(format!("hermes_versions_{}", std::process::id()));
+    let (dir, versions) = (base.join("data"), base.join("versions"));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(base.join("files.json"), b"").unwrap();
+    let path = dir.join("notes.txt");
+
+    let mut files = FileDatabase::new();
+    files.open(base.join("files.json").to_str().unwrap()).unwrap();
+    assert_eq!(files.keep_version(&path, &versions, None, 2).unwrap(), None);
+
+    std::fs::write(&path, b"v1").unwrap();
+    files.register_file(path.clone(), None, FileType::Text).unwrap();
+
+    // Each upload moves the current content aside, and only the newest two are kept
+    for (number, next) in [(1, "v2"), (2, "v3"), (3, "v4")] {
+        assert_eq!(files.keep_version(&path, &versions, Some("alice"), 2).unwrap(), Some(number));
+        std::fs::write(&path, next).unwrap();
+    }
+    let file = files.get_file_by_path(&path).unwrap();
+    assert_eq!(file.versions().iter().map(|x| x.number()).collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(std::fs::read(file.version(3).unwrap().stored()).unwrap(), b"v3");
+    assert_eq!(file.version(3).unwrap().info().replaced_by.as_deref(), Some("alice"));
+    assert_eq!(std::fs::read_dir(&versions).unwrap().count(), 2);
+
+    // A failed upload puts the newest version back
+    std::fs::remove_file(&path).unwrap();
+    files.revert_version(&path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"v3");
+
+    // Dropping the record drops its versions
+    assert!(files.unregister_file(&path));
+    assert_eq!(std::fs::read_dir(&versions).unwrap().count(), 0);
+
+    let _ = std::fs::remove_dir_all(&base);
+}
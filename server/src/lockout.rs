@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use hermes_common::messages::{Lockout, LockoutSubject};
+use hermes_common::session::unix_now;
+
+pub const DEFAULT_LOCKOUT_THRESHOLD: u32 = 10;
+pub const DEFAULT_LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+// Failures allowed before any delay, so a mistyped password costs nothing
+const FREE_FAILURES: u32 = 3;
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+struct FailureRecord {
+    failures: u32,
+    last_failure: u64,
+    until: u64 //No login is tried for the subject before this
+}
+
+// Counts failed password logins against both the address they came from and the username they named. Past a few failures each
+// subject waits twice as long as the last time before its next login is tried, and at the threshold it is locked out for the whole lockout.
+// A subject's count is forgotten once it has gone a lockout without failing, or when it logs in. Nothing is kept across restarts.
+pub struct LoginThrottle {
+    records: HashMap<LockoutSubject, FailureRecord>,
+    threshold: u32,
+    lockout: Duration
+}
+impl Debug for LoginThrottle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Subjects: {}, Threshold: {}, Lockout: {}s)", self.records.len(), self.threshold, self.lockout.as_secs())
+    }
+}
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOCKOUT_THRESHOLD, DEFAULT_LOCKOUT_DURATION)
+    }
+}
+impl LoginThrottle {
+    // A threshold of zero never locks anyone out, though repeated failures are still slowed down
+    pub fn new(threshold: u32, lockout: Duration) -> Self {
+        Self {
+            records: HashMap::new(),
+            threshold,
+            lockout
+        }
+    }
+
+    fn subjects(peer: &str, username: &str) -> [LockoutSubject; 2] {
+        [LockoutSubject::Address(peer.to_string()), LockoutSubject::User(username.to_string())]
+    }
+    fn is_locked(&self, record: &FailureRecord) -> bool {
+        self.threshold > 0 && record.failures >= self.threshold
+    }
+    fn prune(&mut self, now: u64) {
+        let lockout = self.lockout.as_secs();
+        self.records.retain(|_, x| now < x.until.max(x.last_failure + lockout));
+    }
+
+    // How many seconds are left before this login may be tried, if it has to wait
+    pub fn check(&self, peer: &str, username: &str) -> Option<u64> {
+        let now = unix_now();
+        Self::subjects(peer, username).iter()
+            .filter_map(|x| self.records.get(x))
+            .map(|x| x.until.saturating_sub(now))
+            .filter(|x| *x > 0)
+            .max()
+    }
+    pub fn record_failure(&mut self, peer: &str, username: &str) {
+        let now = unix_now();
+        self.prune(now);
+
+        for subject in Self::subjects(peer, username) {
+            let record = self.records.entry(subject.clone()).or_insert(FailureRecord { failures: 0, last_failure: now, until: now });
+            record.failures += 1;
+            record.last_failure = now;
+            record.until = now + delay(record.failures, self.threshold, self.lockout).as_secs();
+            let failures = record.failures;
+
+            if self.threshold > 0 && failures == self.threshold {
+                tracing::warn!(%subject, failures, lockout_secs = self.lockout.as_secs(), "locked out after repeated failed logins");
+            }
+            else if failures > FREE_FAILURES {
+                tracing::info!(%subject, failures, "slowing down logins after repeated failures");
+            }
+        }
+    }
+    // A successful login clears both of its subjects
+    pub fn record_success(&mut self, peer: &str, username: &str) {
+        for subject in Self::subjects(peer, username) {
+            self.records.remove(&subject);
+        }
+    }
+    pub fn unlock(&mut self, subject: &LockoutSubject) -> bool {
+        self.records.remove(subject).is_some()
+    }
+
+    // Every subject that is waiting or locked out, most recently failed first
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = unix_now();
+        let mut waiting: Vec<(u64, Lockout)> = self.records.iter()
+            .filter(|(_, x)| x.until > now)
+            .map(|(subject, x)| (x.last_failure, Lockout { subject: subject.clone(), failures: x.failures, until: x.until, locked: self.is_locked(x) }))
+            .collect();
+        waiting.sort_by_key(|x| std::cmp::Reverse(x.0));
+
+        waiting.into_iter().map(|x| x.1).collect()
+    }
+}
+
+// How long a subject waits after its latest failure
+fn delay(failures: u32, threshold: u32, lockout: Duration) -> Duration {
+    match failures {
+        f if threshold > 0 && f >= threshold => lockout,
+        f if f <= FREE_FAILURES => Duration::ZERO,
+        f => Duration::from_secs(1u64 << (f - FREE_FAILURES - 1).min(16)).min(MAX_DELAY)
+    }
+}
+
+#[test]
+fn test_login_throttle() {
+    let mut throttle = LoginThrottle::new(FREE_FAILURES + 3, Duration::from_secs(600));
+
+    // The first few failures cost nothing
+    for _ in 0..FREE_FAILURES {
+        throttle.record_failure("10.0.0.1", "alice");
+    }
+    assert_eq!(throttle.check("10.0.0.1", "alice"), None);
+    assert!(throttle.lockouts().is_empty());
+
+    // Then each one doubles the wait before the next try, for the address and for the username alike
+    assert_eq!((delay(FREE_FAILURES + 1, 0, Duration::ZERO), delay(FREE_FAILURES + 2, 0, Duration::ZERO)), (Duration::from_secs(1), Duration::from_secs(2)));
+    assert_eq!(delay(FREE_FAILURES + 20, 0, Duration::ZERO), MAX_DELAY);
+    throttle.record_failure("10.0.0.1", "alice");
+    throttle.record_failure("10.0.0.1", "alice");
+    assert!(throttle.check("10.0.0.1", "bob").is_some_and(|x| x <= 2));
+    assert!(throttle.check("10.0.0.2", "alice").is_some());
+    assert_eq!(throttle.check("10.0.0.2", "bob"), None);
+
+    // At the threshold, both are locked out for the whole lockout
+    throttle.record_failure("10.0.0.1", "alice");
+    assert!(throttle.check("10.0.0.2", "alice").is_some_and(|x| x > 500));
+    let lockouts = throttle.lockouts();
+    assert_eq!(lockouts.len(), 2);
+    assert!(lockouts.iter().all(|x| x.locked && x.failures == FREE_FAILURES + 3));
+
+    // An administrator can lift one, and a login clears what is left
+    assert!(throttle.unlock(&LockoutSubject::User(String::from("alice"))));
+    assert_eq!(throttle.check("10.0.0.2", "alice"), None);
+    assert!(throttle.check("10.0.0.1", "carol").is_some());
+    throttle.record_success("10.0.0.1", "carol");
+    assert_eq!(throttle.check("10.0.0.1", "carol"), None);
+    assert!(!throttle.unlock(&LockoutSubject::Address(String::from("10.0.0.1"))));
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// How a request uses a path while it runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockKind {
+    Shared, //Reading, such as a download. Any number may share a path.
+    Exclusive //Writing, such as an upload or a delete. Nothing else may use the path meanwhile.
+}
+
+// Keeps requests that write a path from overlapping with anything else using it. A lock covers everything beneath its path,
+// so a folder cannot be deleted while a file inside it is being downloaded.
+#[derive(Default, Debug)]
+pub struct LockManager {
+    held: Mutex<Held>,
+    released: Notify
+}
+#[derive(Default, Debug)]
+struct Held {
+    next_id: u64,
+    paths: HashMap<u64, (PathBuf, LockKind)>
+}
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Takes every lock or none of them, so a request naming two paths never holds one while it waits on the other
+    pub fn try_lock(&self, wanted: &[(PathBuf, LockKind)]) -> Option<PathGuard<'_>> {
+        let mut held = self.held.lock().unwrap();
+        let conflicts = |path: &Path, kind: LockKind| held.paths.values().any(|(p, k)| {
+            (kind == LockKind::Exclusive || *k == LockKind::Exclusive) && (p.starts_with(path) || path.starts_with(p))
+        });
+        if wanted.iter().any(|(p, k)| conflicts(p, *k)) {
+            return None;
+        }
+
+        let held = &mut *held;
+        let ids = wanted.iter().map(|(p, k)| {
+            held.next_id += 1;
+            held.paths.insert(held.next_id, (p.clone(), *k));
+            held.next_id
+        }).collect();
+
+        Some(PathGuard { manager: self, ids })
+    }
+    // Waits up to wait for the paths to be released. A zero wait gives up at once.
+    pub async fn lock(&self, wanted: &[(PathBuf, LockKind)], wait: Duration) -> Option<PathGuard<'_>> {
+        let deadline = Instant::now() + wait;
+        loop {
+            // Listening before trying, so a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if let Some(g) = self.try_lock(wanted) {
+                return Some(g);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.lock().unwrap().paths.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Releases its locks when dropped
+#[derive(Debug)]
+pub struct PathGuard<'a> {
+    manager: &'a LockManager,
+    ids: Vec<u64>
+}
+impl Drop for PathGuard<'_> {
+    fn drop(&mut self) {
+        {
+            let mut held = self.manager.held.lock().unwrap();
+            for id in &self.ids {
+                held.paths.remove(id);
+            }
+        }
+        self.manager.released.notify_waiters();
+    }
+}
+
+#[tokio::test]
+async fn test_path_locks() {
+    let locks = LockManager::new();
+    let (docs, file, other) = (PathBuf::from("/data/docs"), PathBuf::from("/data/docs/a.txt"), PathBuf::from("/data/b.txt"));
+
+    // Downloads share a file, but nothing may write it, or the folder it is in, meanwhile
+    let first = locks.try_lock(&[(file.clone(), LockKind::Shared)]).unwrap();
+    let second = locks.try_lock(&[(file.clone(), LockKind::Shared)]).unwrap();
+    assert!(locks.try_lock(&[(file.clone(), LockKind::Exclusive)]).is_none());
+    assert!(locks.try_lock(&[(docs.clone(), LockKind::Exclusive)]).is_none());
+    assert!(locks.try_lock(&[(other.clone(), LockKind::Exclusive)]).is_some());
+    drop((first, second));
+    assert!(locks.is_empty());
+
+    // Either every path is locked or none is
+    let writing = locks.try_lock(&[(other.clone(), LockKind::Exclusive)]).unwrap();
+    assert!(locks.try_lock(&[(file.clone(), LockKind::Exclusive), (other.clone(), LockKind::Exclusive)]).is_none());
+    assert_eq!(locks.len(), 1);
+
+    // A waiting request gets the path once it is released, or gives up after its wait
+    let reading = [(other.clone(), LockKind::Shared)];
+    assert!(locks.lock(&reading, Duration::ZERO).await.is_none());
+    let (waited, _) = tokio::join!(
+        locks.lock(&reading, Duration::from_secs(5)),
+        async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(writing);
+        }
+    );
+    assert!(waited.is_some());
+}
@@ -0,0 +1,112 @@
+use serde::{Serialize, Deserialize};
+use std::io::IsTerminal;
+use std::path::Path;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_KEPT_LOG_FILES: usize = 14;
+
+// How often the log file is started afresh. Old files are named after the period they cover.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never
+}
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER
+        }
+    }
+}
+
+fn default_level() -> String {
+    String::from(DEFAULT_LOG_LEVEL)
+}
+fn default_stdout() -> bool {
+    true
+}
+fn default_kept_files() -> usize {
+    DEFAULT_KEPT_LOG_FILES
+}
+
+// The logging section of config.json
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default = "default_level")]
+    pub level: String, //A level, or a filter such as "info,hermes_server::handlers=debug"
+    #[serde(default = "default_stdout")]
+    pub stdout: bool,
+    #[serde(default)]
+    pub file: bool, //Also writes JSON lines to server.<date>.log in the logs directory
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default = "default_kept_files")]
+    pub kept_files: usize //How many rotated files are kept before the oldest is deleted
+}
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_level(),
+            stdout: default_stdout(),
+            file: false,
+            rotation: LogRotation::default(),
+            kept_files: default_kept_files()
+        }
+    }
+}
+impl LoggingConfig {
+    pub fn filter(&self) -> Result<EnvFilter, String> {
+        EnvFilter::try_new(&self.level).map_err(|e| format!("'{}' is not a log filter because '{e}'", self.level))
+    }
+}
+
+// Installs the global subscriber. The guard must be held until the server exits, since dropping it stops the file writer, and anything still buffered is lost.
+pub fn init_logging(config: &LoggingConfig, directory: &Path) -> Result<Option<WorkerGuard>, String> {
+    let (file, guard) = if config.file {
+        let appender = RollingFileAppender::builder()
+            .rotation(config.rotation.into())
+            .filename_prefix("server")
+            .filename_suffix("log")
+            .max_log_files(config.kept_files.max(1))
+            .build(directory)
+            .map_err(|e| format!("unable to open the log file because '{e}'"))?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+
+        // One JSON object a line, so the file can be searched by field
+        (Some(tracing_subscriber::fmt::layer().json().with_writer(writer)), Some(guard))
+    }
+    else {
+        (None, None)
+    };
+    let stdout = config.stdout.then(|| tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal()));
+
+    tracing_subscriber::registry()
+        .with(config.filter()?)
+        .with(stdout)
+        .with(file)
+        .try_init()
+        .map_err(|e| format!("unable to start logging because '{e}'"))?;
+
+    Ok(guard)
+}
+
+#[test]
+fn test_logging_config() {
+    let parsed: LoggingConfig = serde_json::from_str(r#"{ "file": true, "rotation": "hourly" }"#).unwrap();
+    assert_eq!((parsed.level.as_str(), parsed.stdout, parsed.file, parsed.rotation, parsed.kept_files), (DEFAULT_LOG_LEVEL, true, true, LogRotation::Hourly, DEFAULT_KEPT_LOG_FILES));
+
+    let filtered = LoggingConfig { level: String::from("warn,hermes_server::handlers=debug"), ..Default::default() };
+    assert!(filtered.filter().is_ok());
+    let broken = LoggingConfig { level: String::from("hermes_server=loud"), ..Default::default() };
+    assert!(broken.filter().is_err());
+}
@@ -1,7 +1,414 @@
+pub mod config;
 pub mod credentials;
 pub mod io_loc;
 pub mod io_tools;
+pub mod handlers;
+pub mod resume;
+pub mod grants;
+pub mod shares;
+pub mod scheduler;
+pub mod retention;
+pub mod tls;
+pub mod state;
+pub mod server;
+pub mod sessions;
+pub mod idempotency;
+pub mod proxy;
+pub mod middleware;
+pub mod staging;
+pub mod migration;
+pub mod backup;
+pub mod storage;
+pub mod trash;
+pub mod quota;
+pub mod watch;
+pub mod activity;
+pub mod shutdown;
+pub mod logging;
+pub mod cancel;
+pub mod locks;
+pub mod metrics;
+pub mod channels;
+pub mod thumbnails;
+pub mod lockout;
+pub mod access;
+pub mod quic;
+#[cfg(feature = "http")]
+pub mod gateway;
+#[cfg(feature = "http")]
+pub mod webdav;
+pub mod backend;
+pub mod hooks;
+pub mod scanning;
+pub mod dedupe;
+pub mod usage;
+pub mod uploads;
+pub mod transfers;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(test)]
+mod soak;
 
-fn main() {
-    println!("Hello, world!");
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backup::{BackupConfig, run_backup, verify_backup, restore_backup};
+use crate::config::ServerConfig;
+use crate::io_loc::{ensure_directories, install_storage_paths, storage_paths, host_directory, retention_log_path, backup_config_path, log_directory};
+use crate::logging::init_logging;
+use crate::metrics::serve_metrics;
+use crate::migration::{export_state, import_state};
+use crate::retention::{enforce_retention, RetentionEvent};
+use crate::scheduler::Scheduler;
+use crate::server::run;
+use crate::state::ServerState;
+use crate::hooks::Hooks;
+use crate::tls::load_server_tls;
+use crate::quic::QuicPlane;
+#[cfg(feature = "http")]
+use crate::gateway::start_gateway;
+use crate::backend::{mirror, restore_missing};
+use crate::storage::RecordFilter;
+use hermes_common::socket::SocketOptions;
+use hermes_common::tuning::FrameSizeBounds;
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const USAGE: &str = "usage: hermes-server [--config <file>] [export-state <archive> [--with-blobs] | import-state <archive> [--force] | backup-now | verify-backup [snapshot] | restore-backup [snapshot] [--force]]";
+
+fn open_backup_config() -> Result<BackupConfig, String> {
+    BackupConfig::open(&backup_config_path())
+        .map_err(|e| format!("unable to open the backup configuration because '{e}'"))?
+        .ok_or_else(|| format!("no backup peer is configured in '{}'", backup_config_path().display()))
+}
+
+// Backup commands talk to the peer, then exit
+async fn run_backup_command(command: &str, args: &[String]) -> Result<(), String> {
+    let config = open_backup_config()?;
+    let (options, bounds) = (SocketOptions::default(), FrameSizeBounds::default());
+    let named = args.get(1).filter(|x| !x.starts_with("--")).map(|x| x.as_str());
+
+    match command {
+        "backup-now" => {
+            let report = run_backup(&config, &storage_paths(), &options, bounds).await?;
+            println!("backup {}: {} files, {} blobs sent ({} bytes), {} skipped", report.snapshot, report.files, report.uploaded, report.uploaded_bytes, report.skipped.len());
+            for path in report.skipped {
+                println!("  skipped '{path}', it changed while being sent");
+            }
+        },
+        "verify-backup" => {
+            let report = verify_backup(&config, named, &host_directory(), &options, bounds).await?;
+            println!("verify {}: {} blobs checked, {} missing, {} corrupt", report.snapshot, report.checked, report.missing.len(), report.corrupt.len());
+            for path in report.missing.iter() {
+                println!("  missing '{path}'");
+            }
+            for path in report.corrupt.iter() {
+                println!("  corrupt '{path}'");
+            }
+            if !report.is_intact() {
+                return Err(format!("snapshot {} cannot be fully restored", report.snapshot));
+            }
+        },
+        "restore-backup" => {
+            let force = args.iter().skip(1).any(|x| x == "--force");
+            let report = restore_backup(&config, named, &storage_paths(), force, &options, bounds).await?;
+            println!("{command}: {} state files, {} data files ({} bytes)", report.state_files, report.blobs, report.blob_bytes);
+        },
+        _ => return Err(String::from(USAGE))
+    }
+
+    Ok(())
+}
+
+// Where a build embedding the server registers its hooks. The stock server has none.
+fn register_hooks(_hooks: &mut Hooks) { }
+
+// Resolves with the name of the first stop signal received
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM"
+            },
+            Err(e) => {
+                tracing::warn!("unable to listen for SIGTERM because '{e}'");
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+// Takes --config and its file out of the arguments, leaving the command
+fn take_config_flag(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let index = match args.iter().position(|x| x == "--config") {
+        Some(i) => i,
+        None => return Ok(None)
+    };
+    if index + 1 >= args.len() {
+        return Err(String::from("--config needs a file"));
+    }
+
+    let path = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(PathBuf::from(path)))
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    // The configuration says where everything else is, so it is read before anything is opened, by the commands as well as the server
+    let config = match take_config_flag(&mut args).and_then(|x| ServerConfig::load(x.as_deref())) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("unable to load the server configuration because '{e}'");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = install_storage_paths(config.storage_paths()) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    // Migration commands run instead of the server
+    if let Some(command) = args.first() {
+        if matches!(command.as_str(), "backup-now" | "verify-backup" | "restore-backup") {
+            if let Err(e) = run_backup_command(command, &args).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+
+        let flag = |name: &str| args.iter().skip(2).any(|x| x == name);
+        let result = match (command.as_str(), args.get(1)) {
+            ("export-state", Some(archive)) => export_state(&storage_paths(), Path::new(archive), flag("--with-blobs")),
+            ("import-state", Some(archive)) => import_state(Path::new(archive), &storage_paths(), flag("--force")),
+            _ => Err(String::from(USAGE))
+        };
+
+        match result {
+            Ok(report) => println!("{command}: {} state files, {} data files ({} bytes)", report.state_files, report.blobs, report.blob_bytes),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if !ensure_directories() {
+        eprintln!("unable to create the host directory");
+        std::process::exit(1);
+    }
+
+    // Logging starts before the stores are opened, so their failures are logged
+    let _log_guard = match init_logging(&config.logging, &log_directory()) {
+        Ok(g) => g,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let state = match ServerState::load(config) {
+        Ok(mut s) => {
+            register_hooks(&mut s.hooks);
+            if !s.hooks.is_empty() {
+                tracing::info!("running the hooks {}", s.hooks.names().join(", "));
+            }
+            if let Some(scanning) = s.scanning.as_ref() {
+                tracing::info!("scanning uploads with {}", scanning.describe());
+            }
+            Arc::new(s)
+        },
+        Err(e) => {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Uploads cut off by a crash are either kept for their clients to resume, or deleted once too old
+    {
+        let mut staging = state.staging.write().await;
+        let report = staging.recover(state.config.partial_max_age());
+        if !report.resumable.is_empty() || !report.purged.is_empty() {
+            tracing::info!("partial uploads: {} kept for resuming, {} purged ({} bytes reclaimed)", report.resumable.len(), report.purged.len(), report.reclaimed_bytes);
+        }
+        if let Err(e) = staging.save() {
+            tracing::warn!("unable to save the partial upload registry because '{e}'");
+        }
+    }
+
+    // Files the database knows of are fetched back from the file backend when the data directory has lost them, before indexing would forget them
+    if !state.backend.is_local() {
+        tracing::info!("keeping the shared files in {}", state.backend.describe());
+        let paths: Vec<PathBuf> = state.files.read().await.query(&RecordFilter::default()).into_iter().map(|x| x.path().to_path_buf()).collect();
+        let (backend, root) = (Arc::clone(&state.backend), state.paths.root.clone());
+        match tokio::task::spawn_blocking(move || restore_missing(backend.as_ref(), &root, &paths)).await {
+            Ok(Ok(0)) => { },
+            Ok(Ok(restored)) => tracing::info!("restored {restored} file(s) missing from the data directory"),
+            Ok(Err(e)) => tracing::warn!("unable to restore the files missing from the data directory because {e}"),
+            Err(e) => tracing::warn!("unable to restore the files missing from the data directory because '{e}'")
+        }
+    }
+
+    // Files copied in or removed behind the server's back are brought into the database. A proxy's root is only a cache.
+    if state.proxy.is_none() {
+        let mut files = state.files.write().await;
+        match files.index(&state.paths.root) {
+            Ok(report) if report.added > 0 || report.removed > 0 => {
+                tracing::info!("file index: {} added, {} removed, {} unchanged", report.added, report.removed, report.unchanged);
+                if let Err(e) = files.save() {
+                    tracing::warn!("unable to save the file database because '{e}'");
+                }
+            },
+            Ok(_) => { },
+            Err(e) => tracing::warn!("unable to index the data directory because '{e}'")
+        }
+    }
+
+    // Subscriptions are refused if the data directory cannot be watched, but everything else still works
+    if let Err(e) = state.watch.start(&state.paths.root) {
+        tracing::warn!("unable to watch the data directory because '{e}'");
+    }
+
+    let tls = match load_server_tls(&state.config.tls) {
+        Ok(t) => t,
+        Err(e) => {
+            tracing::error!("unable to load TLS because '{e}'");
+            std::process::exit(1);
+        }
+    };
+
+    // The scheduler runs on its own thread, so it takes the async locks in their blocking form
+    let mut scheduler = Scheduler::new();
+    let retention_state = Arc::clone(&state);
+    let _ = scheduler.schedule("retention", RETENTION_INTERVAL, move || {
+        let mut manager = retention_state.retention.blocking_write();
+        let mut files = retention_state.files.blocking_write();
+
+        let events = enforce_retention(&mut manager, &mut files, &retention_state.paths.root, &retention_log_path());
+        let _ = manager.save();
+        let _ = files.save();
+        if !retention_state.backend.is_local() {
+            for event in events {
+                if let RetentionEvent::Deleted(p) = event {
+                    if let Err(e) = mirror(retention_state.backend.as_ref(), &retention_state.paths.root, &p) {
+                        tracing::warn!("unable to remove an expired file from {} because {e}", retention_state.backend.describe());
+                    }
+                }
+            }
+        }
+    });
+    // Deletes that have sat in the trash past the retention period are removed for good
+    let trash_state = Arc::clone(&state);
+    let _ = scheduler.schedule("trash", RETENTION_INTERVAL, move || {
+        let mut trash = trash_state.trash.blocking_write();
+        let report = trash.expire();
+        if !report.purged.is_empty() {
+            tracing::info!("trash: {} expired deletes purged ({} bytes reclaimed)", report.purged.len(), report.reclaimed_bytes);
+        }
+        let _ = trash.save();
+    });
+    // Network statistics are rotated and expired even while no transfers are being recorded
+    let stats_state = Arc::clone(&state);
+    let _ = scheduler.schedule("stats", RETENTION_INTERVAL, move || {
+        if let Err(e) = stats_state.stats.maintain() {
+            tracing::warn!("unable to rotate the network statistics because '{e}'");
+        }
+    });
+    // Backups run on the scheduler thread too, driving the transfer on the server's runtime
+    match BackupConfig::open(&backup_config_path()) {
+        Ok(Some(config)) => {
+            let runtime = tokio::runtime::Handle::current();
+            let backup_state = Arc::clone(&state);
+            let interval = Duration::from_secs(config.interval_secs.max(60));
+            let _ = scheduler.schedule("backup", interval, move || {
+                let result = runtime.block_on(run_backup(&config, &backup_state.paths, &backup_state.socket_options, backup_state.frame_bounds));
+                match result {
+                    Ok(report) => tracing::info!("backup {}: {} files, {} blobs sent ({} bytes), {} skipped", report.snapshot, report.files, report.uploaded, report.uploaded_bytes, report.skipped.len()),
+                    Err(e) => tracing::warn!("backup failed because {e}")
+                }
+            });
+        },
+        Ok(None) => { },
+        Err(e) => tracing::warn!("unable to open the backup configuration because '{e}'")
+    }
+    if let Err(e) = scheduler.start() {
+        tracing::warn!("unable to start the scheduler because '{e}'");
+    }
+
+    tracing::info!("listening on {} ({})", state.config.bind, if tls.is_some() { "TLS" } else { "plain TCP" });
+    if let Some(proxy) = state.proxy.as_ref() {
+        tracing::info!("serving as a read-only proxy for {}", proxy.upstream_address());
+        let proxy_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Some(p) = proxy_state.proxy.as_ref() {
+                p.follow_upstream(&proxy_state.socket_options, proxy_state.frame_bounds).await;
+            }
+        });
+    }
+    if let Some(bind) = state.config.metrics_bind.as_ref() {
+        match tokio::net::TcpListener::bind(bind).await {
+            Ok(listener) => {
+                tracing::info!("serving metrics on http://{bind}/metrics");
+                tokio::spawn(serve_metrics(listener, Arc::clone(&state)));
+            },
+            Err(e) => tracing::warn!("unable to serve metrics on {bind} because '{e}'")
+        }
+    }
+    // Without QUIC, file frames simply stay on TCP
+    if let Some(bind) = state.config.quic_bind.as_ref() {
+        match QuicPlane::start(&state, bind) {
+            Ok(addr) => tracing::info!("carrying file transfers over QUIC on {addr}"),
+            Err(e) => tracing::warn!("file transfers stay on TCP, since {e}")
+        }
+    }
+    if let Some(bind) = state.config.http_bind.as_ref() {
+        #[cfg(feature = "http")]
+        match start_gateway(&state, bind).await {
+            Ok(addr) => tracing::info!("serving the HTTP gateway on http://{addr}"),
+            Err(e) => tracing::warn!("unable to serve the HTTP gateway because {e}")
+        }
+        #[cfg(not(feature = "http"))]
+        tracing::warn!("unable to serve the HTTP gateway on {bind}, since this server was built without it, rebuild it with '--features http'");
+    }
+    tokio::select! {
+        result = run(&state.config.bind, Arc::clone(&state), tls) => {
+            if let Err(e) = result {
+                tracing::error!("{e}");
+            }
+        },
+        signal = shutdown_signal() => tracing::info!("received {signal}, shutting down")
+    }
+
+    // No new connections are accepted from here. Open ones finish their current request, are sent a Close, and end.
+    state.shutdown.begin("the server is shutting down");
+    let grace = state.config.shutdown_grace();
+    let remaining = state.shutdown.drain(&state.connections, grace).await;
+    if remaining > 0 {
+        tracing::warn!("{remaining} connection(s) were still open after {} seconds, and were cut off", grace.as_secs());
+    }
+
+    scheduler.stop();
+    if let Err(e) = state.save().await {
+        tracing::warn!("unable to save state because '{e}'");
+    }
+
+    let metrics = state.stats.lock_metrics();
+    if metrics.poison_recoveries > 0 {
+        tracing::warn!("the statistics lock was poisoned {} time(s) by a panicking connection, and recovered", metrics.poison_recoveries);
+    }
+    tracing::info!("statistics lock: {} acquisitions, {} contended, {} ms waiting", metrics.acquisitions, metrics.contended, metrics.wait_micros / 1000);
 }
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::state::ServerState;
+use hermes_common::messages::{Message, response_status};
+
+// Upper bounds of the transfer duration buckets, in seconds
+const DURATION_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 1800.0];
+// A scrape's request line and headers must fit in this many bytes
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Sent, //Downloads, to the client
+    Received //Uploads, from the client
+}
+impl Direction {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Received => "received"
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()], //Observations at or under each bound, not counting the ones under the bound before
+    count: AtomicU64,
+    sum_micros: AtomicU64
+}
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        if let Some(i) = DURATION_BUCKETS.iter().position(|x| value.as_secs_f64() <= *x) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let mut cumulative = 0;
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+// Counters kept since the server started, for Prometheus to scrape. The rest of what is exported is read from the rest of the state when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    sent: Histogram,
+    received: Histogram,
+    responses: Mutex<BTreeMap<u16, u64>> //By status code
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_transfer(&self, direction: Direction, bytes: u64, elapsed: Duration) {
+        let (total, histogram) = match direction {
+            Direction::Sent => (&self.bytes_sent, &self.sent),
+            Direction::Received => (&self.bytes_received, &self.received)
+        };
+
+        total.fetch_add(bytes, Ordering::Relaxed);
+        histogram.observe(elapsed);
+    }
+    // Responses without a status, such as listings' frames, are not counted
+    pub fn record_response(&self, response: &Message) {
+        if let Some(code) = response_status(response) {
+            *self.responses.lock().unwrap_or_else(|e| e.into_inner()).entry(code.as_u16()).or_default() += 1;
+        }
+    }
+
+    // In the Prometheus text format
+    pub fn render(&self, state: &ServerState) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+        };
+
+        metric("hermes_connections_active", "gauge", "Connections currently open.", state.connections.len().to_string());
+        metric("hermes_connections_total", "counter", "Connections accepted since the server started.", state.connections.opened().to_string());
+        metric("hermes_bytes_sent_total", "counter", "File bytes sent to clients.", self.bytes_sent.load(Ordering::Relaxed).to_string());
+        metric("hermes_bytes_received_total", "counter", "File bytes received from clients.", self.bytes_received.load(Ordering::Relaxed).to_string());
+
+        let (recorded, recorded_bytes) = state.stats.totals();
+        metric("hermes_recorded_transfers", "gauge", "Transfers in the live statistics file, including those from earlier runs.", recorded.to_string());
+        metric("hermes_recorded_transfer_bytes", "gauge", "Bytes carried by the transfers in the live statistics file.", recorded_bytes.to_string());
+        let lock = state.stats.lock_metrics();
+        metric("hermes_stats_lock_acquisitions_total", "counter", "Times the statistics lock was taken.", lock.acquisitions.to_string());
+        metric("hermes_stats_lock_contended_total", "counter", "Statistics lock acquisitions that had to wait.", lock.contended.to_string());
+        metric("hermes_stats_lock_wait_seconds_total", "counter", "Time spent waiting for the statistics lock.", (lock.wait_micros as f64 / 1e6).to_string());
+        metric("hermes_stats_lock_poison_recoveries_total", "counter", "Times the statistics lock was recovered after a panic.", lock.poison_recoveries.to_string());
+
+        let name = "hermes_transfer_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} How long finished transfers took.\n# TYPE {name} histogram");
+        for (direction, histogram) in [(Direction::Sent, &self.sent), (Direction::Received, &self.received)] {
+            histogram.render(name, &format!("direction=\"{}\"", direction.label()), &mut out);
+        }
+
+        let name = "hermes_responses_total";
+        let _ = writeln!(out, "# HELP {name} Responses sent, by status code.\n# TYPE {name} counter");
+        for (code, count) in self.responses.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let _ = writeln!(out, "{name}{{code=\"{code}\"}} {count}");
+        }
+
+        out
+    }
+}
+
+// Answers scrapes on their own port, one request a connection. Only GET /metrics is served.
+pub async fn serve_metrics(listener: TcpListener, state: Arc<ServerState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((s, _)) => s,
+            Err(e) => {
+                tracing::warn!("unable to accept a metrics scrape because '{e}'");
+                continue;
+            }
+        };
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = answer_scrape(stream, &state).await {
+                tracing::debug!("a metrics scrape failed because '{e}'");
+            }
+        });
+    }
+}
+async fn answer_scrape(mut stream: TcpStream, state: &ServerState) -> Result<(), String> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await.map_err(|_| String::from("the request timed out"))??;
+    let mut words = head.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", state.metrics.render(state)),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("only /metrics is served\n")),
+        _ => ("405 Method Not Allowed", String::from("only GET is served\n"))
+    };
+
+    let response = format!("HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+    stream.write_all(response.as_bytes()).await.map_err(|e| e.to_string())?;
+    stream.shutdown().await.map_err(|e| e.to_string())
+}
+async fn read_request_head(stream: &mut TcpStream) -> Result<String, String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|x| x == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Err(String::from("the request head is too large"));
+        }
+
+        match stream.read(&mut buffer).await.map_err(|e| e.to_string())? {
+            0 => break,
+            len => head.extend_from_slice(&buffer[..len])
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    use hermes_common::http_codes::HttpCodes;
+    use hermes_common::messages::{ack_messsage, MessageDirection};
+
+    let state = Arc::new(ServerState::new());
+    let connection = state.connections.open("10.0.0.1");
+    state.metrics.record_transfer(Direction::Sent, 1000, Duration::from_millis(300));
+    state.metrics.record_transfer(Direction::Sent, 24, Duration::from_secs(2));
+    state.metrics.record_response(&ack_messsage(MessageDirection::Response, HttpCodes::NotFound, None));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve_metrics(listener, Arc::clone(&state)));
+
+    let scrape = |request: &'static str| async move {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    let response = scrape("GET /metrics HTTP/1.1\r\nHost: hermes\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nhermes_connections_active 1\n"));
+    assert!(response.contains("\nhermes_bytes_sent_total 1024\n"));
+    assert!(response.contains("hermes_transfer_duration_seconds_bucket{direction=\"sent\",le=\"0.5\"} 1\n"));
+    assert!(response.contains("hermes_transfer_duration_seconds_bucket{direction=\"sent\",le=\"5\"} 2\n"));
+    assert!(response.contains("hermes_transfer_duration_seconds_count{direction=\"received\"} 0\n"));
+    assert!(response.contains("hermes_responses_total{code=\"404\"} 1\n"));
+
+    assert!(scrape("GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+    assert!(scrape("POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+    state.connections.close(connection);
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+use crate::credentials::GUEST_USERNAME;
+use crate::handlers::{SessionIdentity, display_path, resolve_target};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, MessageType, MessageDirection, AuditEntry, AuditFilter, ack_messsage, response_status};
+
+pub const DEFAULT_REQUEST_RATE: f64 = 50.0;
+pub const DEFAULT_REQUEST_BURST: f64 = 200.0;
+// Each session gets less than its address, so one misbehaving connection cannot spend what the others from there need
+pub const DEFAULT_SESSION_RATE: f64 = 20.0;
+pub const DEFAULT_SESSION_BURST: f64 = 100.0;
+pub const DEFAULT_MAX_TRANSFERS: usize = 8;
+pub const MAX_PATH_LENGTH: usize = 4096;
+
+// Idle buckets are only dropped once there are this many, so a busy server does not sweep on every request
+const RATE_LIMIT_SWEEP: usize = 1024;
+// How many audit entries an Audit request gets when it does not say, and the most it can ask for
+pub const DEFAULT_AUDIT_LIMIT: usize = 100;
+pub const MAX_AUDIT_LIMIT: usize = 1000;
+// The audit log is read backwards this much at a time
+const AUDIT_BLOCK: u64 = 64 * 1024;
+
+// What every stage can see of a request. Authentication, including keeping upload grants to uploads, is not a stage.
+// It always runs first and cannot be disabled, so the identity here is settled.
+pub struct RequestContext<'a> {
+    pub message: &'a Message,
+    pub identity: Option<&'a SessionIdentity>,
+    pub peer: &'a str,
+    pub directory: &'a Path //The session's working directory, which relative paths are taken from
+}
+impl RequestContext<'_> {
+    pub fn message_type(&self) -> MessageType {
+        *self.message.message_type()
+    }
+    // The path a request acts on, whichever field it travels in
+    pub fn target(&self) -> Option<String> {
+        self.message.extract_as("path").or_else(|| self.message.extract_as("name"))
+    }
+    // The target as the server shows it, such as '/docs/a.txt', or as it was sent when it does not resolve
+    pub fn resolved_target(&self) -> Option<String> {
+        let target = self.target()?;
+        Some(resolve_target(&target, self.directory).map(|x| display_path(&x)).unwrap_or(target))
+    }
+}
+
+fn refuse(code: HttpCodes, message: &str) -> Message {
+    ack_messsage(MessageDirection::Response, code, Some(message.to_string()))
+}
+// Requests that change files or server state. These are refused for read-only users.
+pub fn is_mutation(kind: MessageType) -> bool {
+    matches!(kind, MessageType::Upload | MessageType::Delete | MessageType::Subfolder | MessageType::Grant | MessageType::Hold | MessageType::Move | MessageType::Rename | MessageType::Copy | MessageType::Share | MessageType::ShareLink | MessageType::UserAdmin | MessageType::Restore | MessageType::PurgeTrash | MessageType::UploadDir)
+}
+
+// One cross-cutting concern of request handling. Stages only see requests that passed authentication.
+pub trait Middleware: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Runs before the handler, in registration order. Returning a response refuses the request, skipping the later stages and the handler.
+    fn before(&self, _ctx: &RequestContext) -> Result<(), Message> {
+        Ok(())
+    }
+    // Runs once the request has been answered, refused or not, with the last response sent
+    fn after(&self, _ctx: &RequestContext, _response: &Message) { }
+}
+
+// The standard stages a deployment may turn off. The read-only stage is left out, since a proxy cannot take changes.
+const OPTIONAL_STAGES: [&str; 3] = ["rate_limit", "path_safety", "audit"];
+
+// Which of the standard stages the configuration leaves out
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MiddlewareConfig {
+    #[serde(default)]
+    pub disabled: Vec<String> //Names of stages to turn off, such as 'rate_limit' or 'audit'
+}
+
+// The stages every authenticated request passes through on its way to a handler
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Middleware>>
+}
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Rate limiting, path safety, and the audit log. A proxy also refuses every change.
+    pub fn standard(read_only: bool, audit_path: PathBuf) -> Self {
+        let mut result = Self::new();
+        result.register(RateLimiter::new(DEFAULT_REQUEST_RATE, DEFAULT_REQUEST_BURST))
+            .register(PathSafety);
+        if read_only {
+            result.register(ReadOnly);
+        }
+        result.register(AuditLog::new(audit_path));
+
+        result
+    }
+    // The standard stages, less those the configuration turns off. A name that is not one of them is refused.
+    pub fn configured(read_only: bool, audit_path: PathBuf, config: &MiddlewareConfig) -> Result<Self, String> {
+        let mut result = Self::standard(read_only, audit_path);
+        for name in &config.disabled {
+            if !OPTIONAL_STAGES.contains(&name.as_str()) {
+                return Err(format!("'{name}' is not a middleware stage that can be disabled"));
+            }
+            result.disable(name);
+        }
+
+        Ok(result)
+    }
+
+    pub fn register<M: Middleware + 'static>(&mut self, stage: M) -> &mut Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+    // Removes a stage by name, returning false if it was not registered
+    pub fn disable(&mut self, name: &str) -> bool {
+        let prev_len = self.stages.len();
+        self.stages.retain(|x| x.name() != name);
+
+        prev_len != self.stages.len()
+    }
+    pub fn names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|x| x.name()).collect()
+    }
+
+    pub fn before(&self, ctx: &RequestContext) -> Result<(), Message> {
+        self.stages.iter().try_for_each(|x| x.before(ctx))
+    }
+    pub fn after(&self, ctx: &RequestContext, response: &Message) {
+        for stage in &self.stages {
+            stage.after(ctx, response);
+        }
+    }
+}
+
+// Tokens refill at a steady rate up to the burst size, and each request takes one
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant
+}
+impl TokenBucket {
+    // Starts full, so a client that has just arrived can burst straight away
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now()
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = (self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+    }
+    pub fn take(&mut self) -> bool {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+    // Whether it would be full by now, in which case forgetting it changes nothing
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+// A token bucket per address
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>
+}
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn take(&self, peer: &str) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= RATE_LIMIT_SWEEP {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        buckets.entry(peer.to_string()).or_insert_with(|| TokenBucket::new(self.rate, self.burst)).take()
+    }
+}
+impl Middleware for RateLimiter {
+    fn name(&self) -> &'static str {
+        "rate_limit"
+    }
+    fn before(&self, ctx: &RequestContext) -> Result<(), Message> {
+        match self.take(ctx.peer) {
+            true => Ok(()),
+            false => Err(refuse(HttpCodes::TooManyRequests, "too many requests, slow down"))
+        }
+    }
+}
+
+fn default_session_rate() -> f64 {
+    DEFAULT_SESSION_RATE
+}
+fn default_session_burst() -> f64 {
+    DEFAULT_SESSION_BURST
+}
+fn default_max_transfers() -> usize {
+    DEFAULT_MAX_TRANSFERS
+}
+
+// How hard one connection may push the server. It is kept apart from the pipeline's limit on each address, and applies even where the pipeline is empty.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionLimits {
+    #[serde(default = "default_session_rate")]
+    pub requests_per_sec: f64, //Zero lets a connection send as many requests as it likes
+    #[serde(default = "default_session_burst")]
+    pub burst: f64, //How many requests can come at once after a quiet spell
+    #[serde(default = "default_max_transfers")]
+    pub max_transfers: usize //Downloads a connection may have open on channels at once. Zero leaves them unlimited.
+}
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: default_session_rate(),
+            burst: default_session_burst(),
+            max_transfers: default_max_transfers()
+        }
+    }
+}
+impl SessionLimits {
+    pub fn unlimited() -> Self {
+        Self {
+            requests_per_sec: 0.0,
+            burst: 0.0,
+            max_transfers: 0
+        }
+    }
+
+    // The bucket a new connection draws its requests from, if it is limited at all
+    pub fn bucket(&self) -> Option<TokenBucket> {
+        (self.requests_per_sec > 0.0).then(|| TokenBucket::new(self.requests_per_sec, self.burst.max(1.0)))
+    }
+    pub fn allows_transfer(&self, open: usize) -> bool {
+        self.max_transfers == 0 || open < self.max_transfers
+    }
+}
+
+// Refuses paths no handler could accept before any of them touch the file system
+pub struct PathSafety;
+impl Middleware for PathSafety {
+    fn name(&self) -> &'static str {
+        "path_safety"
+    }
+    fn before(&self, ctx: &RequestContext) -> Result<(), Message> {
+        // Moves, renames, and copies name a second path, which has to be just as safe
+        let destination: Option<String> = ctx.message.extract_as("destination");
+        for target in ctx.target().iter().chain(destination.iter()) {
+            if target.len() > MAX_PATH_LENGTH || target.contains('\0') {
+                return Err(refuse(HttpCodes::BadRequest, "invalid path"));
+            } else if Path::new(target).has_root() {
+                return Err(refuse(HttpCodes::Forbidden, "path is outside of the server's root directory"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A proxy only serves what it can fetch from upstream
+pub struct ReadOnly;
+impl Middleware for ReadOnly {
+    fn name(&self) -> &'static str {
+        "read_only"
+    }
+    fn before(&self, ctx: &RequestContext) -> Result<(), Message> {
+        match ctx.message_type() {
+            MessageType::Download | MessageType::Dir | MessageType::ChangeDir | MessageType::Stats | MessageType::CanI | MessageType::Cancel => Ok(()),
+            _ => Err(refuse(HttpCodes::Forbidden, "this server is a read-only proxy"))
+        }
+    }
+}
+
+// Appends one JSON line per request to the audit log, whether or not it was allowed. Pings and heartbeats only keep the connection open, so they are left out.
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Mutex<()>
+}
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(())
+        }
+    }
+}
+impl Middleware for AuditLog {
+    fn name(&self) -> &'static str {
+        "audit"
+    }
+    fn after(&self, ctx: &RequestContext, response: &Message) {
+        if matches!(ctx.message_type(), MessageType::Ping | MessageType::Heartbeat) {
+            return;
+        }
+
+        let who = match ctx.identity {
+            Some(SessionIdentity::User(u)) => u.as_str(),
+            Some(SessionIdentity::Grant(_)) => "upload grant",
+            Some(SessionIdentity::Share(_)) => "share link",
+            Some(SessionIdentity::Guest) => GUEST_USERNAME,
+            None => "unknown"
+        };
+        let entry = AuditEntry {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default(),
+            peer: ctx.peer.to_string(),
+            user: who.to_string(),
+            operation: ctx.message_type().to_string(),
+            status: response_status(response).map(|x| x.as_u16()),
+            path: ctx.resolved_target()
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(l) => l + "\n",
+            Err(_) => return
+        };
+
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&self.path) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+// Logs written before entries were JSON hold tab separated lines, with the path as the client sent it
+fn parse_audit_line(line: &[u8]) -> Option<AuditEntry> {
+    if let Ok(entry) = serde_json::from_slice(line) {
+        return Some(entry);
+    }
+
+    let line = std::str::from_utf8(line).ok()?;
+    let fields: Vec<&str> = line.split('\t').collect();
+    match fields.as_slice() {
+        [time, peer, user, operation, status, path] => Some(
+            AuditEntry {
+                time: time.parse().ok()?,
+                peer: peer.to_string(),
+                user: user.to_string(),
+                operation: operation.to_string(),
+                status: status.parse().ok(),
+                path: Some(path.to_string()).filter(|x| !x.is_empty())
+            }
+        ),
+        _ => None
+    }
+}
+fn audit_matches(entry: &AuditEntry, filter: &AuditFilter) -> bool {
+    let user = filter.user.as_ref().is_none_or(|x| *x == entry.user);
+    let path = match (filter.path.as_deref().map(|x| x.trim_matches('/')), entry.path.as_deref().map(|x| x.trim_matches('/'))) {
+        (None, _) | (Some(""), Some(_)) => true,
+        (Some(wanted), Some(p)) => p == wanted || p.strip_prefix(wanted).is_some_and(|x| x.starts_with('/')),
+        (Some(_), None) => false
+    };
+
+    user && path
+}
+// The newest entries that match the filter, newest first. The log is read a block at a time from its end, so a long log is not read whole to find its last few lines.
+pub fn read_audit_log(path: &Path, filter: &AuditFilter) -> Result<Vec<AuditEntry>, String> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string())
+    };
+    let limit = filter.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).min(MAX_AUDIT_LIMIT);
+
+    let mut result = Vec::new();
+    let mut end = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut carry = Vec::new(); //The part of a line whose start is in the block before
+    while end > 0 && result.len() < limit {
+        let start = end.saturating_sub(AUDIT_BLOCK);
+        let mut block = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start)).and_then(|_| file.read_exact(&mut block)).map_err(|e| e.to_string())?;
+        block.append(&mut carry);
+        end = start;
+
+        let mut lines: Vec<&[u8]> = block.split(|x| *x == b'\n').collect();
+        if start > 0 {
+            carry = lines.remove(0).to_vec();
+        }
+        for entry in lines.into_iter().rev().filter_map(parse_audit_line).filter(|x| audit_matches(x, filter)) {
+            result.push(entry);
+            if result.len() == limit {
+                break;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[test]
+fn test_pipeline() {
+    use hermes_common::messages::{delete_message, dir_message_request, extract_ack_message};
+
+    let audit = std::env::temp_dir().join(format!("hermes_audit_{}.log", std::process::id()));
+    let mut pipeline = Pipeline::standard(true, audit.clone());
+    assert!(pipeline.disable("rate_limit"));
+    pipeline.register(RateLimiter::new(0.0, 1.0));
+    assert_eq!(pipeline.names(), vec!["path_safety", "read_only", "audit", "rate_limit"]);
+
+    let user = SessionIdentity::User(String::from("user"));
+    let docs = crate::io_loc::root_directory().join("docs");
+    let status = |result: Result<(), Message>| result.err().and_then(extract_ack_message).map(|x| x.0);
+
+    // Earlier stages refuse first, and the rate limit only spends its one token on the request that reaches it
+    let delete = delete_message("/etc/passwd", false);
+    let ctx = RequestContext { message: &delete, identity: Some(&user), peer: "10.0.0.1", directory: &docs };
+    assert_eq!(status(pipeline.before(&ctx)), Some(HttpCodes::Forbidden));
+
+    let delete = delete_message("a.txt", false);
+    let ctx = RequestContext { message: &delete, identity: Some(&user), peer: "10.0.0.1", directory: &docs };
+    assert_eq!(status(pipeline.before(&ctx)), Some(HttpCodes::Forbidden));
+
+    let dir = dir_message_request();
+
+    let ctx = RequestContext { message: &dir, identity: Some(&user), peer: "10.0.0.1", directory: &docs };
+    assert_eq!(status(pipeline.before(&ctx)), None);
+    assert_eq!(status(pipeline.before(&ctx)), Some(HttpCodes::TooManyRequests));
+
+    // Everything but keepalive traffic is audited, with paths taken from the session's working directory
+    let ping = hermes_common::messages::ping_message(1);
+    let _ = std::fs::remove_file(&audit);
+    std::fs::write(&audit, "100\t10.0.0.2\tolder\tdelete\t200\told.txt\n").unwrap();
+    pipeline.after(&RequestContext { message: &delete, identity: Some(&user), peer: "10.0.0.1", directory: &docs }, &refuse(HttpCodes::NotFound, "file not found"));
+    pipeline.after(&RequestContext { message: &dir, identity: Some(&user), peer: "10.0.0.1", directory: &docs }, &refuse(HttpCodes::Ok, "ok"));
+    pipeline.after(&RequestContext { message: &ping, identity: Some(&user), peer: "10.0.0.1", directory: &docs }, &refuse(HttpCodes::Ok, "ok"));
+
+    let contents = std::fs::read_to_string(&audit).unwrap();
+    assert_eq!(contents.lines().count(), 3);
+    let all = read_audit_log(&audit, &AuditFilter::default()).unwrap();
+    assert_eq!(all.iter().map(|x| x.operation.as_str()).collect::<Vec<_>>(), vec!["dir", "delete", "delete"]);
+    assert_eq!((all[1].status, all[1].path.as_deref()), (Some(404), Some("/docs/a.txt")));
+    assert_eq!((all[2].user.as_str(), all[2].time, all[2].path.as_deref()), ("older", 100, Some("old.txt")));
+
+    // Filters narrow it down, a path takes what is beneath it, and the limit keeps the newest
+    let filter = |user: Option<&str>, path: Option<&str>, limit: Option<usize>| AuditFilter { user: user.map(String::from), path: path.map(String::from), limit };
+    assert_eq!(read_audit_log(&audit, &filter(Some("older"), None, None)).unwrap().len(), 1);
+    assert_eq!(read_audit_log(&audit, &filter(None, Some("docs"), None)).unwrap()[0].operation, "delete");
+    assert!(read_audit_log(&audit, &filter(None, Some("/do"), None)).unwrap().is_empty());
+    assert_eq!(read_audit_log(&audit, &filter(None, None, Some(1))).unwrap()[0].operation, "dir");
+
+    // Lines longer than a block are put back together
+    let long = AuditEntry { time: 1, peer: String::from("10.0.0.3"), user: String::from("user"), operation: String::from("upload"), status: Some(200), path: Some(format!("/{}", "x".repeat(AUDIT_BLOCK as usize * 2))) };
+    std::fs::write(&audit, format!("{}\n{}\n", serde_json::to_string(&long).unwrap(), serde_json::to_string(&all[0]).unwrap())).unwrap();
+    assert_eq!(read_audit_log(&audit, &filter(None, None, None)).unwrap(), vec![all[0].clone(), long]);
+    assert!(read_audit_log(&audit.with_extension("missing"), &filter(None, None, None)).unwrap().is_empty());
+
+    let _ = std::fs::remove_file(&audit);
+}
+
+#[test]
+fn test_configured_pipeline() {
+    let audit = std::env::temp_dir().join(format!("hermes_audit_configured_{}.log", std::process::id()));
+    let config = |names: &[&str]| MiddlewareConfig { disabled: names.iter().map(|x| x.to_string()).collect() };
+
+    // Nothing disabled keeps the standard stages, and a proxy keeps refusing changes whatever is turned off
+    assert_eq!(Pipeline::configured(false, audit.clone(), &config(&[])).unwrap().names(), Pipeline::standard(false, audit.clone()).names());
+    assert_eq!(Pipeline::configured(false, audit.clone(), &config(&["rate_limit", "audit"])).unwrap().names(), vec!["path_safety"]);
+    assert_eq!(Pipeline::configured(true, audit.clone(), &config(&["rate_limit", "path_safety", "audit"])).unwrap().names(), vec!["read_only"]);
+
+    // Stages that do not exist, or cannot be turned off, are refused
+    assert!(Pipeline::configured(false, audit.clone(), &config(&["rate_limits"])).is_err());
+    assert!(Pipeline::configured(true, audit, &config(&["read_only"])).is_err());
+}
+
+#[test]
+fn test_session_limits() {
+    // Settings left out keep their defaults, and a rate of zero is no limit at all
+    let limits: SessionLimits = serde_json::from_str(r#"{ "requests_per_sec": 1.0, "burst": 2.0 }"#).unwrap();
+    assert_eq!(limits, SessionLimits { requests_per_sec: 1.0, burst: 2.0, max_transfers: DEFAULT_MAX_TRANSFERS });
+    assert!(SessionLimits::unlimited().bucket().is_none() && SessionLimits::unlimited().allows_transfer(usize::MAX));
+    assert!(limits.allows_transfer(DEFAULT_MAX_TRANSFERS - 1) && !limits.allows_transfer(DEFAULT_MAX_TRANSFERS));
+
+    // A bucket starts full, and refills at its rate without going past its burst
+    let mut bucket = limits.bucket().unwrap();
+    assert!(bucket.take() && bucket.take() && !bucket.take());
+    let later = bucket.last + std::time::Duration::from_secs(10);
+    assert!(bucket.is_full(later));
+    bucket.refill(later);
+    assert!(bucket.take() && bucket.take() && !bucket.take());
+}
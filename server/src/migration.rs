@@ -0,0 +1,237 @@
+use serde::{Serialize, Deserialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::io_loc::{StoragePaths, server_config_path, user_database_path, file_owner_db_path, grants_path, shares_path, retention_path, proxy_config_path, network_analyzer_path};
+use crate::staging::STAGING_SUFFIX;
+use hermes_common::session::unix_now;
+
+pub const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST_NAME: &str = "manifest.json";
+const STATE_DIR: &str = "state";
+const DATA_DIR: &str = "data";
+
+// Always the first entry of an archive, so an import knows where the paths inside it pointed before it reads them
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Manifest {
+    version: u32,
+    root: PathBuf, //The data root of the machine the state was exported from
+    created_at: u64,
+    blobs: bool
+}
+
+// What an export or import carried
+#[derive(Default, PartialEq, Debug)]
+pub struct MigrationReport {
+    pub state_files: usize,
+    pub blobs: usize,
+    pub blob_bytes: u64
+}
+
+// The stores that make up a server. Logs, session tokens, partial uploads, and the TLS keys stay on the old machine.
+pub(crate) fn state_file_names() -> Vec<OsString> {
+    [server_config_path(), user_database_path(), file_owner_db_path(), grants_path(), shares_path(), retention_path(), proxy_config_path(), network_analyzer_path()]
+        .iter()
+        .filter_map(|x| x.file_name().map(|n| n.to_os_string()))
+        .collect()
+}
+
+pub(crate) fn holds_state(paths: &StoragePaths) -> bool {
+    state_file_names().iter().any(|x| paths.state_file(x).metadata().is_ok_and(|m| m.len() > 0))
+}
+
+// Points every string that names something under one root at the same place under another
+pub(crate) fn rewrite_root(value: &mut serde_json::Value, from: &Path, to: &Path) {
+    match value {
+        serde_json::Value::String(s) => {
+            if let Ok(rest) = Path::new(s.as_str()).strip_prefix(from) {
+                let moved = if rest.as_os_str().is_empty() { to.to_path_buf() } else { to.join(rest) };
+                *s = moved.to_string_lossy().to_string();
+            }
+        },
+        serde_json::Value::Array(a) => a.iter_mut().for_each(|x| rewrite_root(x, from, to)),
+        serde_json::Value::Object(o) => o.values_mut().for_each(|x| rewrite_root(x, from, to)),
+        _ => { }
+    }
+}
+
+fn append_tree(builder: &mut tar::Builder<File>, dir: &Path, name: &Path, report: &mut MigrationReport) -> Result<(), String> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir).map_err(|e| e.to_string())?.flatten().collect();
+    entries.sort_by_key(|x| x.file_name());
+
+    for entry in entries {
+        let entry_name = name.join(entry.file_name());
+        let metadata = entry.path().symlink_metadata().map_err(|e| e.to_string())?;
+        if metadata.is_dir() {
+            builder.append_dir(&entry_name, entry.path()).map_err(|e| e.to_string())?;
+            append_tree(builder, &entry.path(), &entry_name, report)?;
+        } else if metadata.is_file() && !entry.file_name().to_string_lossy().ends_with(STAGING_SUFFIX) {
+            builder.append_path_with_name(entry.path(), &entry_name).map_err(|e| e.to_string())?;
+            report.blobs += 1;
+            report.blob_bytes += metadata.len();
+        }
+    }
+
+    Ok(())
+}
+
+// Writes the state files, and with blobs everything under the root, into a single tar archive.
+// The server should be stopped first, so the stores are not written to halfway through.
+pub fn export_state(paths: &StoragePaths, archive: &Path, blobs: bool) -> Result<MigrationReport, String> {
+    let root = paths.root.as_path();
+    let mut report = MigrationReport::default();
+    let mut builder = tar::Builder::new(File::create(archive).map_err(|e| e.to_string())?);
+    builder.follow_symlinks(false);
+
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        root: root.to_path_buf(),
+        created_at: unix_now(),
+        blobs
+    };
+    let contents = serde_json::to_vec(&manifest).map_err(|e| e.to_string())?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_NAME, contents.as_slice()).map_err(|e| e.to_string())?;
+
+    for name in state_file_names() {
+        let path = paths.state_file(&name);
+        if path.is_file() {
+            builder.append_path_with_name(&path, Path::new(STATE_DIR).join(&name)).map_err(|e| e.to_string())?;
+            report.state_files += 1;
+        }
+    }
+
+    if blobs && root.is_dir() {
+        append_tree(&mut builder, root, Path::new(DATA_DIR), &mut report)?;
+    }
+
+    builder.into_inner().and_then(|x| x.sync_all()).map_err(|e| e.to_string())?;
+    Ok(report)
+}
+
+// Restores an archive into the storage paths of this machine. Paths the stores kept under the old root are rewritten to
+// the new one. Existing state is only replaced when forced, since a careless import would wipe out a running server's users and files.
+pub fn import_state(archive: &Path, paths: &StoragePaths, force: bool) -> Result<MigrationReport, String> {
+    if holds_state(paths) && !force {
+        return Err(format!("'{}' already holds server state, pass --force to replace it", paths.host.display()));
+    }
+
+    let root = paths.root.as_path();
+    for dir in [&paths.host, &paths.root, &paths.database] {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut report = MigrationReport::default();
+    let mut archive = tar::Archive::new(File::open(archive).map_err(|e| e.to_string())?);
+    let mut manifest: Option<Manifest> = None;
+    let known = state_file_names();
+
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry.path().map_err(|e| e.to_string())?.to_path_buf();
+
+        if name == Path::new(MANIFEST_NAME) {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|e| e.to_string())?;
+            let read: Manifest = serde_json::from_str(&contents).map_err(|e| format!("the manifest is unreadable because '{e}'"))?;
+            if read.version > ARCHIVE_VERSION {
+                return Err(format!("the archive is version {}, but this server only reads up to version {}", read.version, ARCHIVE_VERSION));
+            }
+
+            manifest = Some(read);
+            continue;
+        }
+
+        let from = match manifest.as_ref() {
+            Some(m) => &m.root,
+            None => return Err(String::from("the archive does not start with a manifest"))
+        };
+
+        // Nothing in an archive may climb out of where it is restored to
+        let rest = match name.strip_prefix(STATE_DIR).or_else(|_| name.strip_prefix(DATA_DIR)) {
+            Ok(r) if r.components().all(|x| matches!(x, Component::Normal(_))) && !r.as_os_str().is_empty() => r.to_path_buf(),
+            _ => return Err(format!("unexpected entry '{}' in the archive", name.display()))
+        };
+
+        if name.starts_with(STATE_DIR) {
+            if !known.iter().any(|x| rest.as_os_str() == x) {
+                return Err(format!("unexpected state file '{}' in the archive", rest.display()));
+            }
+
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|e| e.to_string())?;
+            if !contents.trim().is_empty() {
+                let mut value: serde_json::Value = serde_json::from_str(&contents).map_err(|e| format!("'{}' is unreadable because '{e}'", rest.display()))?;
+                rewrite_root(&mut value, from, root);
+                contents = serde_json::to_string(&value).map_err(|e| e.to_string())?;
+            }
+
+            std::fs::write(paths.state_file(rest.as_os_str()), contents).map_err(|e| e.to_string())?;
+            report.state_files += 1;
+        } else {
+            let target = root.join(&rest);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+
+            let is_file = entry.header().entry_type().is_file();
+            let size = entry.header().size().unwrap_or_default();
+            entry.unpack(&target).map_err(|e| format!("unable to restore '{}' because '{e}'", rest.display()))?;
+            if is_file {
+                report.blobs += 1;
+                report.blob_bytes += size;
+            }
+        }
+    }
+
+    if manifest.is_none() {
+        return Err(String::from("the archive does not start with a manifest"));
+    }
+
+    Ok(report)
+}
+
+#[test]
+fn test_export_and_import() {
+    let base = std::env::temp_dir().join(format!("hermes_migration_{}", std::process::id()));
+    let (old_host, new_host) = (base.join("old"), base.join("new"));
+    let (old_root, new_root) = (old_host.join("data"), new_host.join("data"));
+    std::fs::create_dir_all(old_root.join("docs").join("empty")).unwrap();
+
+    std::fs::write(old_root.join("docs").join("a.txt"), b"hello").unwrap();
+    std::fs::write(old_root.join("docs").join("b.txt.hermes.part"), b"partial").unwrap();
+    std::fs::write(old_host.join("users.json"), r#"[{"username":"alice","password":"x","home":"alice"}]"#).unwrap();
+    let files = serde_json::json!([{ "id": 1, "path": old_root.join("docs").join("a.txt"), "kind": "Text", "owner": null }]);
+    std::fs::write(old_host.join("files.json"), files.to_string()).unwrap();
+    std::fs::write(old_host.join("resume.json"), b"[]").unwrap();
+
+    // The new machine keeps its databases apart from the other stores
+    let (old, new) = (StoragePaths::new(old_host.clone(), None, None), StoragePaths::new(new_host.clone(), None, Some(base.join("db"))));
+    let archive = base.join("state.tar");
+    let report = export_state(&old, &archive, true).unwrap();
+    assert_eq!(report, MigrationReport { state_files: 2, blobs: 1, blob_bytes: 5 });
+
+    let report = import_state(&archive, &new, false).unwrap();
+    assert_eq!(report, MigrationReport { state_files: 2, blobs: 1, blob_bytes: 5 });
+
+    // Paths move to the new root, relative ones are left alone, and partial uploads and session tokens stay behind
+    let files: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(base.join("db").join("files.json")).unwrap()).unwrap();
+    assert_eq!(files[0]["path"].as_str().map(PathBuf::from), Some(new_root.join("docs").join("a.txt")));
+    assert!(std::fs::read_to_string(base.join("db").join("users.json")).unwrap().contains(r#""home":"alice""#));
+    assert_eq!(std::fs::read(new_root.join("docs").join("a.txt")).unwrap(), b"hello");
+    assert!(new_root.join("docs").join("empty").is_dir());
+    assert!(!new_root.join("docs").join("b.txt.hermes.part").exists());
+    assert!(!new_host.join("resume.json").exists());
+
+    // A second import would replace what is there now, so it has to be forced
+    assert!(import_state(&archive, &new, false).is_err());
+    assert!(import_state(&archive, &new, true).is_ok());
+
+    let _ = std::fs::remove_dir_all(&base);
+}
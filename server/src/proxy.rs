@@ -0,0 +1,696 @@
+use rustls::ClientConfig;
+use rustls::pki_types::ServerName;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tokio_rustls::TlsConnector;
+
+use crate::io_loc::{root_directory, delta_directory};
+use crate::resume::generate_token;
+use crate::staging::staging_path;
+use hermes_common::checksum::{Checksum, ChecksumAlgorithm, checksum_file};
+use hermes_common::progress::Progress;
+use hermes_common::file_io::{receive_network_file_checked_async, ReceiveOptions, receive_network_binary_async, send_network_file_async, DirectoryInfo, FileInfo, FileChunkIter, FileType};
+use hermes_common::framing::{read_frame_async, read_message_async, write_frame_async, FrameError};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, DirQuery, connect_message, close_message, dir_query_request, download_message_request, attach_session};
+use hermes_common::messages::{upload_message, extract_upload_response_message, extract_ack_message, subfolder_message, SubfolderAction};
+use hermes_common::compression::Compression;
+use hermes_common::messages::{advertise_compressions, extract_compressions, attach_compression, extract_compression};
+use hermes_common::messages::{extract_connect_ack_message, extract_session_ack, extract_frame_bounds, extract_dir_response_message, extract_download_response_message};
+use hermes_common::protocol::{CURRENT_PROTOCOL_VERSION, Capabilities, Capability};
+use hermes_common::delta::{Signature, apply_delta_file};
+use hermes_common::messages::{advertise_capabilities, extract_capabilities, attach_signature, extract_delta};
+use hermes_common::messages::{change_dir_message, subscribe_message, extract_event_message, extract_idle_timeout, ping_message, FileEvent, FileEventKind};
+use hermes_common::socket::SocketOptions;
+use hermes_common::transport::{client_tls_config, AsyncTransport};
+use hermes_common::tuning::{FrameSizeBounds, FrameSizeTuner};
+
+pub const DEFAULT_PROXY_MAX_AGE: u64 = 5 * 60;
+// How often to ping an upstream server that did not say when it closes idle connections
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(60);
+// How long to wait before subscribing again once the upstream connection drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(10);
+
+fn default_max_age() -> u64 {
+    DEFAULT_PROXY_MAX_AGE
+}
+
+// Placing proxy.json in the host directory turns the server into a read-only cache in front of another Hermes server.
+// The proxy logs in upstream with its own account, while local users are still checked against the local user database.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UpstreamConfig {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub server_name: Option<String>, //Defaults to the host part of the address
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+    #[serde(default = "default_max_age")]
+    pub max_age_secs: u64
+}
+impl UpstreamConfig {
+    pub fn open(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string())
+        };
+
+        serde_json::from_str(&contents).map(Some).map_err(|e| e.to_string())
+    }
+
+    fn server_name(&self) -> String {
+        match self.server_name.as_ref() {
+            Some(s) => s.clone(),
+            None => self.address.rsplit_once(':').map(|(host, _)| host).unwrap_or(&self.address).trim_matches(['[', ']']).to_string()
+        }
+    }
+}
+
+// The path of a file or directory under the root directory, as the upstream server names it relative to its own root
+pub fn upstream_path(path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root_directory()).ok()?;
+    let parts: Vec<String> = relative.iter().map(|x| x.to_string_lossy().to_string()).collect();
+    Some(parts.join("/"))
+}
+fn local_path(upstream: &str) -> PathBuf {
+    root_directory().join(upstream)
+}
+// An event's path, which is from the top of the upstream server, as the proxy names it from where its login starts. None when it is outside of that.
+fn event_path(base: &str, path: &str) -> Option<String> {
+    let (base, path) = (base.trim_matches('/'), path.trim_matches('/'));
+    match (base.is_empty(), path.strip_prefix(base)) {
+        (true, _) => Some(path.to_string()),
+        (false, Some("")) => Some(String::new()),
+        (false, Some(rest)) => rest.strip_prefix('/').map(|x| x.to_string()),
+        (false, None) => None
+    }
+}
+fn is_beneath(path: &str, parent: &str) -> bool {
+    parent.is_empty() || path == parent || path.strip_prefix(parent).is_some_and(|x| x.starts_with('/'))
+}
+fn child_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+struct CachedListing {
+    listing: DirectoryInfo,
+    fetched_at: Instant,
+    evicted: bool //Changed upstream since, and only kept to fall back on
+}
+struct CachedFile {
+    size: u64,
+    modified: Option<u64>,
+    fetched_at: Instant
+}
+
+// Remembers when each listing and file was fetched. File contents live in the root directory, so only their freshness is tracked here.
+// Changes the upstream server reports are evicted as they arrive, and the max age is left to cover whatever is missed while it cannot report them.
+// A refreshed listing also drops every cached file it shows has changed upstream.
+pub struct ProxyCache {
+    max_age: Duration,
+    listings: HashMap<String, CachedListing>,
+    files: HashMap<String, CachedFile>,
+    expired_at: Option<Instant> //Nothing fetched before this is fresh
+}
+impl ProxyCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            listings: HashMap::new(),
+            files: HashMap::new(),
+            expired_at: None
+        }
+    }
+
+    fn is_fresh(&self, fetched_at: Instant) -> bool {
+        fetched_at.elapsed() < self.max_age && self.expired_at.is_none_or(|x| fetched_at > x)
+    }
+    pub fn fresh_listing(&self, path: &str) -> Option<&DirectoryInfo> {
+        self.listings.get(path).filter(|x| !x.evicted && self.is_fresh(x.fetched_at)).map(|x| &x.listing)
+    }
+    pub fn stale_listing(&self, path: &str) -> Option<&DirectoryInfo> {
+        self.listings.get(path).map(|x| &x.listing)
+    }
+    pub fn is_file_fresh(&self, path: &str) -> bool {
+        self.files.get(path).is_some_and(|x| self.is_fresh(x.fetched_at))
+    }
+
+    // A change to a path makes it, everything beneath it, and the listing of its folder stale. The old listings are kept to fall back on.
+    pub fn evict(&mut self, path: &str) {
+        let parent = path.rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+        for (key, listing) in self.listings.iter_mut() {
+            if key == parent || is_beneath(key, path) {
+                listing.evicted = true;
+            }
+        }
+        self.files.retain(|key, _| !is_beneath(key, path));
+    }
+    // Used when changes may have been missed, such as while the upstream server could not report them
+    pub fn expire_all(&mut self) {
+        self.expired_at = Some(Instant::now());
+    }
+
+    // Returns the cached files that are now out of date, so their local copies can be removed
+    pub fn store_listing(&mut self, path: &str, listing: DirectoryInfo) -> Vec<String> {
+        let mut stale = Vec::<String>::new();
+        let current: HashMap<&str, &FileInfo> = listing.get_files().into_iter().map(|x| (x.name(), x)).collect();
+
+        if let Some(old) = self.listings.get(path) {
+            for file in old.listing.get_files() {
+                let file_path = child_path(path, file.name());
+                let changed = match (current.get(file.name()), self.files.get(&file_path)) {
+                    (Some(new), Some(cached)) => new.size() != cached.size || new.modified() != cached.modified,
+                    (None, _) => true,
+                    (Some(_), None) => false
+                };
+
+                if changed {
+                    self.files.remove(&file_path);
+                    stale.push(file_path);
+                }
+            }
+        }
+
+        self.listings.insert(
+            path.to_string(),
+            CachedListing {
+                listing,
+                fetched_at: Instant::now(),
+                evicted: false
+            }
+        );
+        stale
+    }
+    pub fn store_file(&mut self, path: &str, size: u64, modified: Option<u64>) {
+        self.files.insert(
+            path.to_string(),
+            CachedFile {
+                size,
+                modified,
+                fetched_at: Instant::now()
+            }
+        );
+    }
+}
+
+// One short-lived login to another Hermes server, either the upstream of a proxy or the peer a server backs itself up to.
+// Every fetch uses a fresh connection, which starts at the root (or home) of the account it logs in with.
+pub struct Upstream {
+    transport: Box<dyn AsyncTransport>,
+    session: String,
+    bounds: FrameSizeBounds,
+    compression: Option<Compression>, //The other end's favourite of the compressions both sides speak
+    delta: bool, //Whether files already here can be updated by fetching only what changed
+    events: bool, //Whether the other end can report its changes
+    ping_interval: Duration,
+    pings: u64
+}
+impl Upstream {
+    // Builds the TLS configuration on the spot, for callers that only connect now and then
+    pub async fn open(config: &UpstreamConfig, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<Self, String> {
+        let tls = match config.tls {
+            true => Some(client_tls_config(config.ca_path.as_deref(), None)?),
+            false => None
+        };
+
+        Self::connect(config, tls.as_ref(), options, bounds).await
+    }
+    async fn connect(config: &UpstreamConfig, tls: Option<&Arc<ClientConfig>>, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<Self, String> {
+        let stream = TcpStream::connect(&config.address).await.map_err(|e| format!("unable to reach '{}' because '{e}'", &config.address))?;
+        options.apply_async(&stream)?;
+
+        let mut transport: Box<dyn AsyncTransport> = match tls {
+            Some(t) => {
+                let name = ServerName::try_from(config.server_name()).map_err(|e| e.to_string())?;
+                Box::new(TlsConnector::from(Arc::clone(t)).connect(name, stream).await.map_err(|e| e.to_string())?)
+            },
+            None => Box::new(stream)
+        };
+
+        // Everything a login assumed before capabilities were exchanged, plus delta fetches and change reports
+        let capabilities: Capabilities = Capabilities::legacy().iter().copied().chain([Capability::Delta, Capability::Events, Capability::Keepalive]).collect();
+        let request = advertise_compressions(connect_message(config.username.clone(), config.password.clone(), CURRENT_PROTOCOL_VERSION), &Compression::supported());
+        let request = advertise_capabilities(request, &capabilities);
+        write_frame_async(&mut transport, &request).await?;
+        let response = read_frame_async(&mut transport).await?;
+
+        let session = extract_session_ack(&response);
+        let bounds = extract_frame_bounds(&response).and_then(|x| x.intersect(&bounds)).unwrap_or(bounds);
+        let compression = extract_compressions(&response).first().copied();
+        let agreed = extract_capabilities(&response).unwrap_or_else(Capabilities::legacy);
+        // Pinged at half its idle timeout, so one late ping does not get the connection closed
+        let ping_interval = extract_idle_timeout(&response).map(|x| Duration::from_secs(x) / 2).unwrap_or(DEFAULT_PING_INTERVAL).max(Duration::from_secs(1));
+        match (extract_connect_ack_message(response), session) {
+            (Some((HttpCodes::Ok, _, _, _)), Some(s)) => Ok(
+                Self {
+                    transport,
+                    session: s.token().to_string(),
+                    bounds,
+                    compression,
+                    delta: agreed.contains(Capability::Delta),
+                    events: agreed.contains(Capability::Events) && agreed.contains(Capability::Keepalive),
+                    ping_interval,
+                    pings: 0
+                }
+            ),
+            (Some((code, message, _, _)), _) => Err(format!("the upstream server refused the login with {code} '{message}'")),
+            (None, _) => Err(String::from("malformed response from the upstream server"))
+        }
+    }
+
+    async fn request(&mut self, message: Message) -> Result<Message, String> {
+        write_frame_async(&mut self.transport, &attach_session(message, &self.session)).await?;
+        read_frame_async(&mut self.transport).await
+    }
+
+    pub async fn list(&mut self, path: &str) -> Result<DirectoryInfo, String> {
+        let query = DirQuery {
+            path: if path.is_empty() { None } else { Some(path.to_string()) },
+            ..Default::default()
+        };
+
+        let frame_count = match extract_dir_response_message(self.request(dir_query_request(&query)).await?) {
+            Some((HttpCodes::Ok, _, _, f)) => f,
+            Some((code, message, _, _)) => return Err(format!("{code} '{message}'")),
+            None => return Err(String::from("malformed dir response"))
+        };
+
+        let mut tuner = FrameSizeTuner::new(self.bounds);
+        let contents = receive_network_binary_async(&mut self.transport, frame_count, &mut tuner).await.ok_or_else(|| String::from("the listing was interrupted"))?;
+        serde_json::from_slice(&contents).map_err(|e| e.to_string())
+    }
+
+    // Downloads into a partial file beside the destination and moves it into place once the checksum matches.
+    // When an older copy is already there, only the blocks that changed are sent, and rebuilt against it.
+    pub async fn fetch(&mut self, path: &str, destination: &Path) -> Result<u64, String> {
+        let request = download_message_request(path, 0, None);
+        let request = match self.compression {
+            Some(c) => attach_compression(request, c),
+            None => request
+        };
+        let request = match self.delta && destination.is_file() {
+            true => attach_signature(request, &Signature::of_file(destination).map_err(|e| e.to_string())?),
+            false => request
+        };
+
+        let response = self.request(request).await?;
+        let compression = extract_compression(&response);
+        let delta = extract_delta(&response);
+        let response = extract_download_response_message(response).ok_or_else(|| String::from("malformed download response"))?;
+        if response.status != HttpCodes::Ok {
+            return Err(format!("{} '{}'", response.status, &response.message));
+        }
+
+        // Staged under the same name as an upload, so listings, the index, and backups never see a file that is still arriving
+        let partial = staging_path(destination);
+        let mut tuner = FrameSizeTuner::new(self.bounds);
+        let received = match delta {
+            true => self.receive_delta(destination, &partial, response.frame_count, response.checksum.as_ref(), compression, &mut tuner).await,
+            false => receive_network_file_checked_async(&partial, &mut self.transport, response.frame_count, ReceiveOptions { offset: 0, expected: response.checksum.as_ref(), compression, tuner: &mut tuner, progress: &mut Progress::none() }).await
+        };
+        if let Err(e) = received {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+
+        std::fs::rename(&partial, destination).map_err(|e| e.to_string())?;
+        Ok(response.length)
+    }
+
+    async fn receive_delta(&mut self, basis: &Path, partial: &Path, frame_count: u64, expected: Option<&Checksum>, compression: Option<Compression>, tuner: &mut FrameSizeTuner) -> Result<Checksum, String> {
+        let scratch = delta_directory().join(generate_token());
+        std::fs::create_dir_all(delta_directory()).map_err(|e| e.to_string())?;
+        let received = receive_network_file_checked_async(&scratch, &mut self.transport, frame_count, ReceiveOptions { offset: 0, expected: None, compression, tuner, progress: &mut Progress::none() }).await;
+        let algorithm = expected.map(|x| x.algorithm()).unwrap_or_default();
+        let result = received.and_then(|_| apply_delta_file(&scratch, basis, partial, algorithm));
+        let _ = std::fs::remove_file(&scratch);
+
+        match result {
+            Ok(actual) if expected.is_none_or(|x| *x == actual) => Ok(actual),
+            Ok(actual) => {
+                let _ = std::fs::remove_file(partial);
+                Err(format!("checksum mismatch, the delta rebuilt '{actual}'"))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    // Asks for the checksum of a file without transferring any of it, by downloading nothing from its end
+    pub async fn checksum(&mut self, path: &str) -> Result<Option<Checksum>, String> {
+        let response = extract_download_response_message(self.request(download_message_request(path, 0, Some(0))).await?).ok_or_else(|| String::from("malformed download response"))?;
+        match response.status {
+            HttpCodes::Ok => Ok(response.checksum),
+            HttpCodes::NotFound => Ok(None),
+            code => Err(format!("{code} '{}'", &response.message))
+        }
+    }
+
+    // Creating a directory that already exists is not an error
+    pub async fn make_dir(&mut self, path: &str) -> Result<(), String> {
+        match extract_ack_message(self.request(subfolder_message(path, SubfolderAction::Add, false)).await?) {
+            Some((HttpCodes::Ok | HttpCodes::Conflict, _)) => Ok(()),
+            Some((code, message)) => Err(format!("{code} '{message}'")),
+            None => Err(String::from("malformed subfolder response"))
+        }
+    }
+
+    // The upload is refused by the other end unless what arrives matches the expected checksum, so a file that changes on disk meanwhile is never stored under the wrong name
+    pub async fn upload(&mut self, path: &str, source: &Path, expected: Option<Checksum>) -> Result<u64, String> {
+        let chunks = FileChunkIter::open(source, 0, None)?;
+        let checksum = match expected {
+            Some(c) => c,
+            None => checksum_file(source, ChecksumAlgorithm::Sha256).map_err(|e| e.to_string())?
+        };
+
+        let request = upload_message(path, FileType::Binary, chunks.frame_count(), 0, Some(checksum), None);
+        let request = match self.compression {
+            Some(c) => attach_compression(request, c),
+            None => request
+        };
+
+        let response = self.request(request).await?;
+        match extract_upload_response_message(response) {
+            Some((HttpCodes::Ok, _, _)) => (),
+            Some((code, message, _)) => return Err(format!("{code} '{message}'")),
+            None => return Err(String::from("malformed upload response"))
+        }
+
+        let mut tuner = FrameSizeTuner::new(self.bounds);
+        let sent = send_network_file_async(&mut self.transport, chunks, self.compression, &mut tuner, &mut Progress::none()).await?;
+        match extract_ack_message(read_frame_async(&mut self.transport).await?) {
+            Some((HttpCodes::Ok, _)) => Ok(sent),
+            Some((code, message)) => Err(format!("{code} '{message}'")),
+            None => Err(String::from("malformed upload ack"))
+        }
+    }
+
+    // Watches everything the login can reach, and returns where that is from the top of the other end, which is how events name their paths.
+    // Err is only given when the other end could not be reached.
+    pub async fn subscribe(&mut self) -> Result<Result<String, String>, String> {
+        if !self.events {
+            return Ok(Err(String::from("it does not report changes")));
+        }
+
+        let base = match extract_ack_message(self.request(change_dir_message(".")).await?) {
+            Some((HttpCodes::Ok, b)) => b,
+            Some((code, message)) => return Ok(Err(format!("{code} '{message}'"))),
+            None => return Err(String::from("malformed change dir response"))
+        };
+        match extract_ack_message(self.request(subscribe_message("", false)).await?) {
+            Some((HttpCodes::Ok, _)) => Ok(Ok(base)),
+            Some((code, message)) => Ok(Err(format!("{code} '{message}'"))),
+            None => Err(String::from("malformed subscribe response"))
+        }
+    }
+    // Waits for the next change, pinging meanwhile, since events are not enough to keep the connection from being closed as idle. Pongs are skipped.
+    pub async fn next_event(&mut self) -> Result<FileEvent, String> {
+        let mut first = [0u8; 1];
+        let mut next_ping = tokio::time::Instant::now() + self.ping_interval;
+        loop {
+            let read = tokio::select! {
+                read = self.transport.read(&mut first) => Some(read),
+                _ = tokio::time::sleep_until(next_ping) => None
+            };
+
+            match read {
+                Some(Ok(0)) => return Err(String::from("the connection was closed")),
+                Some(Ok(_)) => match read_message_async(&mut (&first[..]).chain(&mut self.transport)).await {
+                    Ok(message) => if let Some(event) = extract_event_message(message) {
+                        return Ok(event);
+                    },
+                    Err(FrameError::Rejected(_)) => { }, //Something a newer server sends that this one does not know
+                    Err(FrameError::Broken(e)) => return Err(e)
+                },
+                Some(Err(e)) => return Err(e.to_string()),
+                None => {
+                    self.pings += 1;
+                    write_frame_async(&mut self.transport, &attach_session(ping_message(self.pings), &self.session)).await?;
+                    next_ping += self.ping_interval;
+                }
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        let _ = write_frame_async(&mut self.transport, &close_message()).await;
+    }
+}
+
+// Fetches of one path run one at a time, so two connections never write the same partial file or mirror the same folder.
+// Fetches of different paths go ahead side by side, so one slow file upstream holds up nothing but itself.
+#[derive(Default)]
+struct InFlight {
+    paths: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>
+}
+impl InFlight {
+    async fn lock(&self, path: &str) -> InFlightGuard<'_> {
+        let entry = Arc::clone(self.paths.lock().unwrap().entry(path.to_string()).or_default());
+        let guard = entry.lock_owned().await;
+
+        InFlightGuard { in_flight: self, path: path.to_string(), guard: Some(guard) }
+    }
+}
+// Lets the next fetch of its path go ahead when dropped, and forgets the path when nothing else is waiting on it
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    path: String,
+    guard: Option<OwnedMutexGuard<()>>
+}
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut paths = self.in_flight.paths.lock().unwrap();
+        self.guard.take();
+        if paths.get(&self.path).is_some_and(|x| Arc::strong_count(x) == 1) {
+            paths.remove(&self.path);
+        }
+    }
+}
+
+pub struct Proxy {
+    config: UpstreamConfig,
+    tls: Option<Arc<ClientConfig>>,
+    cache: RwLock<ProxyCache>,
+    listing: InFlight,
+    fetching: InFlight
+}
+impl Proxy {
+    pub fn new(config: UpstreamConfig) -> Result<Self, String> {
+        let tls = match config.tls {
+            true => Some(client_tls_config(config.ca_path.as_deref(), None)?),
+            false => None
+        };
+
+        Ok(
+            Self {
+                cache: RwLock::new(ProxyCache::new(Duration::from_secs(config.max_age_secs))),
+                config,
+                tls,
+                listing: InFlight::default(),
+                fetching: InFlight::default()
+            }
+        )
+    }
+
+    pub fn upstream_address(&self) -> &str {
+        &self.config.address
+    }
+
+    async fn connect(&self, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<Upstream, String> {
+        Upstream::connect(&self.config, self.tls.as_ref(), options, bounds).await
+    }
+
+    // Runs for as long as the server does, evicting whatever the upstream server says has changed. Changes may be missed while the subscription is down,
+    // so everything cached before it comes back is treated as stale. Stops if the upstream server cannot report changes, leaving the max age to cover them.
+    pub async fn follow_upstream(&self, options: &SocketOptions, bounds: FrameSizeBounds) {
+        loop {
+            let dropped = match self.connect(options, bounds).await {
+                Ok(mut upstream) => match upstream.subscribe().await {
+                    Ok(Ok(base)) => {
+                        self.cache.write().await.expire_all();
+                        tracing::info!("following changes on {}", self.upstream_address());
+                        loop {
+                            match upstream.next_event().await {
+                                Ok(event) => self.apply_event(&base, event).await,
+                                Err(e) => break e
+                            }
+                        }
+                    },
+                    Ok(Err(e)) => {
+                        tracing::warn!("the upstream server will not report changes because {e}, so the cache relies on its max age");
+                        upstream.close().await;
+                        return;
+                    },
+                    Err(e) => e
+                },
+                Err(e) => e
+            };
+
+            tracing::warn!("stopped following changes upstream because '{dropped}', trying again in {} seconds", RESUBSCRIBE_DELAY.as_secs());
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+    // A deleted file or folder is removed here too, so it is not served as a stale copy. Anything else is fetched again when next asked for.
+    async fn apply_event(&self, base: &str, event: FileEvent) {
+        let path = match event_path(base, &event.path) {
+            Some(p) => p,
+            None => return
+        };
+
+        tracing::debug!(path, kind = ?event.kind, "evicted a change upstream");
+        self.cache.write().await.evict(&path);
+        if event.kind == FileEventKind::Deleted && !path.is_empty() {
+            let local = local_path(&path);
+            let _ = match local.is_dir() {
+                true => std::fs::remove_dir_all(&local),
+                false => std::fs::remove_file(&local)
+            };
+        }
+    }
+
+    // A fresh listing comes from the cache. Otherwise it is fetched, falling back to the last known listing when the upstream server cannot be reached.
+    pub async fn listing(&self, path: &str, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<DirectoryInfo, String> {
+        if let Some(l) = self.cache.read().await.fresh_listing(path) {
+            return Ok(l.clone());
+        }
+
+        let _guard = self.listing.lock(path).await;
+        let fetched = match self.connect(options, bounds).await {
+            Ok(mut upstream) => {
+                let result = upstream.list(path).await;
+                upstream.close().await;
+                result
+            },
+            Err(e) => Err(e)
+        };
+
+        let listing = match fetched {
+            Ok(l) => l,
+            Err(e) => return self.cache.read().await.stale_listing(path).cloned().ok_or(e)
+        };
+
+        // Directories are mirrored locally so the working directory can move into them
+        std::fs::create_dir_all(local_path(path)).map_err(|e| e.to_string())?;
+        for dir in listing.get_directories() {
+            let _ = std::fs::create_dir_all(local_path(&child_path(path, dir.name())));
+        }
+
+        let stale = self.cache.write().await.store_listing(path, listing.clone());
+        for file in stale {
+            let _ = std::fs::remove_file(local_path(&file));
+        }
+
+        Ok(listing)
+    }
+
+    // Makes sure the root directory holds an up to date copy of the file. A stale copy is served when the upstream server cannot be reached.
+    pub async fn ensure_file(&self, path: &str, options: &SocketOptions, bounds: FrameSizeBounds) -> Result<(), String> {
+        if self.cache.read().await.is_file_fresh(path) {
+            return Ok(());
+        }
+
+        let parent = path.rsplit_once('/').map(|(p, _)| p).unwrap_or("");
+        let listing = self.listing(parent, options, bounds).await?;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let modified = listing.get_files().into_iter().find(|x| x.name() == name).and_then(|x| x.modified());
+
+        let _guard = self.fetching.lock(path).await;
+        if self.cache.read().await.is_file_fresh(path) {
+            return Ok(()); // Fetched by another connection while this one waited
+        }
+
+        let destination = local_path(path);
+        let fetched = match self.connect(options, bounds).await {
+            Ok(mut upstream) => {
+                let result = upstream.fetch(path, &destination).await;
+                upstream.close().await;
+                result
+            },
+            Err(e) => Err(e)
+        };
+
+        match fetched {
+            Ok(size) => {
+                self.cache.write().await.store_file(path, size, modified);
+                Ok(())
+            },
+            Err(_) if destination.is_file() => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+#[test]
+fn test_proxy_cache_freshness() {
+    fn listing(size: u64) -> DirectoryInfo {
+        let mut file = FileInfo::new(String::from("a.txt"), String::from("owner"), hermes_common::file_io::FileType::Text, size);
+        file.set_modified(Some(1));
+        DirectoryInfo::new(String::from("docs"), vec![hermes_common::file_io::DirectoryContent::File(file)])
+    }
+
+    let mut cache = ProxyCache::new(Duration::from_secs(60));
+    assert!(cache.fresh_listing("docs").is_none());
+
+    assert!(cache.store_listing("docs", listing(10)).is_empty());
+    cache.store_file("docs/a.txt", 10, Some(1));
+    assert!(cache.fresh_listing("docs").is_some());
+    assert!(cache.is_file_fresh("docs/a.txt"));
+
+    // An unchanged listing keeps the file, a changed one drops it
+    assert!(cache.store_listing("docs", listing(10)).is_empty());
+    assert_eq!(cache.store_listing("docs", listing(11)), vec![String::from("docs/a.txt")]);
+    assert!(!cache.is_file_fresh("docs/a.txt"));
+
+    // A change upstream drops the file, everything beneath a folder, and the folder's listing, which is still kept to fall back on
+    cache.store_file("docs/a.txt", 11, Some(1));
+    cache.store_file("docs/sub/b.txt", 1, None);
+    cache.store_listing("docs/sub", listing(1));
+    cache.evict("docs/sub");
+    assert!(cache.is_file_fresh("docs/a.txt") && !cache.is_file_fresh("docs/sub/b.txt"));
+    assert!(cache.fresh_listing("docs/sub").is_none() && cache.fresh_listing("docs").is_none());
+    assert!(cache.stale_listing("docs/sub").is_some());
+    cache.expire_all();
+    assert!(!cache.is_file_fresh("docs/a.txt"));
+    assert_eq!((event_path("/", "/docs/a.txt"), event_path("/home/proxy", "/home/proxy/docs")), (Some(String::from("docs/a.txt")), Some(String::from("docs"))));
+    assert_eq!((event_path("/home/proxy", "/home/proxy"), event_path("/home/proxy", "/home/proxyless/a")), (Some(String::new()), None));
+
+    // Nothing is fresh with no max age, but the old listing is still there to fall back on
+    let mut cache = ProxyCache::new(Duration::ZERO);
+    cache.store_listing("", listing(1));
+    assert!(cache.fresh_listing("").is_none());
+    assert!(cache.stale_listing("").is_some());
+
+    assert_eq!(upstream_path(&root_directory().join("docs").join("a.txt")), Some(String::from("docs/a.txt")));
+    assert_eq!(upstream_path(&root_directory()), Some(String::new()));
+}
+
+#[tokio::test]
+async fn test_in_flight_fetches() {
+    let in_flight = InFlight::default();
+
+    // A second fetch of the same path waits, while a fetch of any other path goes straight ahead
+    let first = in_flight.lock("docs/a.txt").await;
+    assert!(tokio::time::timeout(Duration::from_millis(20), in_flight.lock("docs/a.txt")).await.is_err());
+    let other = tokio::time::timeout(Duration::from_millis(20), in_flight.lock("docs/b.txt")).await.unwrap();
+    assert_eq!(in_flight.paths.lock().unwrap().len(), 2);
+
+    let (second, _) = tokio::join!(
+        in_flight.lock("docs/a.txt"),
+        async { drop(first) }
+    );
+    drop((second, other));
+    assert!(in_flight.paths.lock().unwrap().is_empty());
+}
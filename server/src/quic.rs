@@ -0,0 +1,220 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::state::ServerState;
+use hermes_common::messages::QuicOffer;
+use hermes_common::transport::AsyncTransport;
+
+#[cfg(feature = "quic")]
+use std::collections::HashMap;
+#[cfg(feature = "quic")]
+use std::sync::{Mutex, OnceLock};
+#[cfg(feature = "quic")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "quic")]
+use crate::io_loc::{tls_cert_path, tls_key_path};
+#[cfg(feature = "quic")]
+use crate::tls::TlsPaths;
+#[cfg(feature = "quic")]
+use hermes_common::quic::{QuicStream, Registration, certificate_fingerprint, generate_certificate, server_endpoint};
+#[cfg(feature = "quic")]
+use hermes_common::transport::{load_certificates, load_private_key};
+
+// Carries file frames for sessions whose clients also connected over QUIC, on a UDP port beside the TCP listener.
+// Every message still goes over TCP. A QUIC connection is only used once it has named a live session, and is dropped with that session.
+#[cfg(feature = "quic")]
+pub struct QuicPlane {
+    running: OnceLock<(quinn::Endpoint, QuicOffer)>,
+    connections: Mutex<HashMap<String, quinn::Connection>> //By session token
+}
+#[cfg(not(feature = "quic"))]
+pub struct QuicPlane;
+
+impl Debug for QuicPlane {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.offer() {
+            Some(o) => write!(f, "QuicPlane(port {})", o.port),
+            None => write!(f, "QuicPlane(stopped)")
+        }
+    }
+}
+impl Default for QuicPlane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "quic")]
+impl QuicPlane {
+    pub fn new() -> Self {
+        Self {
+            running: OnceLock::new(),
+            connections: Mutex::new(HashMap::new())
+        }
+    }
+
+    // The server's TLS certificate is presented when it has one. Otherwise one is made for this run, which clients trust through the fingerprint in the Connect ack.
+    pub fn start(state: &Arc<ServerState>, bind: &str) -> Result<SocketAddr, String> {
+        let bind: SocketAddr = bind.parse().map_err(|_| format!("'{bind}' is not an address and port to listen for QUIC on"))?;
+        let (certs, key) = match certificate(&state.config.tls)? {
+            Some(c) => c,
+            None => generate_certificate().map(|(cert, key)| (vec![cert], key))?
+        };
+        let fingerprint = certificate_fingerprint(&certs[0]);
+        let endpoint = server_endpoint(bind, certs, key)?;
+        let addr = endpoint.local_addr().map_err(|e| e.to_string())?;
+
+        if state.quic.running.set((endpoint.clone(), QuicOffer { port: addr.port(), fingerprint })).is_err() {
+            return Err(String::from("QUIC is already running"));
+        }
+        tokio::spawn(accept_connections(endpoint, Arc::clone(state)));
+        Ok(addr)
+    }
+
+    pub fn offer(&self) -> Option<QuicOffer> {
+        self.running.get().map(|x| x.1.clone())
+    }
+    fn connection(&self, session: &str) -> Option<quinn::Connection> {
+        self.connections.lock().ok()?.get(session).filter(|x| x.close_reason().is_none()).cloned()
+    }
+    pub fn is_registered(&self, session: &str) -> bool {
+        self.connection(session).is_some()
+    }
+    // Closes the session's QUIC connection, once its TCP connection has ended
+    pub fn forget(&self, session: &str) {
+        let removed = self.connections.lock().ok().and_then(|mut x| x.remove(session));
+        if let Some(c) = removed {
+            c.close(0u32.into(), b"session ended");
+        }
+    }
+
+    // Downloads are sent on a stream the server opens, and uploads arrive on one the client opens
+    pub async fn open(&self, session: &str) -> Result<Box<dyn AsyncTransport>, String> {
+        let connection = self.connection(session).ok_or_else(|| String::from("the session has no QUIC connection"))?;
+        Ok(Box::new(QuicStream::open(&connection).await?))
+    }
+    pub async fn accept(&self, session: &str) -> Result<Box<dyn AsyncTransport>, String> {
+        let connection = self.connection(session).ok_or_else(|| String::from("the session has no QUIC connection"))?;
+        Ok(Box::new(QuicStream::accept(&connection).await?))
+    }
+}
+
+#[cfg(not(feature = "quic"))]
+impl QuicPlane {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn start(_state: &Arc<ServerState>, _bind: &str) -> Result<SocketAddr, String> {
+        Err(String::from("this server was built without QUIC support, rebuild it with '--features quic'"))
+    }
+
+    pub fn offer(&self) -> Option<QuicOffer> {
+        None
+    }
+    pub fn is_registered(&self, _session: &str) -> bool {
+        false
+    }
+    pub fn forget(&self, _session: &str) { }
+
+    pub async fn open(&self, _session: &str) -> Result<Box<dyn AsyncTransport>, String> {
+        Err(String::from("this server was built without QUIC support"))
+    }
+    pub async fn accept(&self, _session: &str) -> Result<Box<dyn AsyncTransport>, String> {
+        Err(String::from("this server was built without QUIC support"))
+    }
+}
+
+// The TLS certificate, if the server has one installed
+#[cfg(feature = "quic")]
+fn certificate(paths: &TlsPaths) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>, String> {
+    let cert = paths.cert.clone().unwrap_or_else(tls_cert_path);
+    let key = paths.key.clone().unwrap_or_else(tls_key_path);
+    if !cert.exists() || !key.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some((load_certificates(&cert)?, load_private_key(&key)?)))
+}
+
+#[cfg(feature = "quic")]
+async fn accept_connections(endpoint: quinn::Endpoint, state: Arc<ServerState>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let peer = incoming.remote_address();
+            let connection = match incoming.await {
+                Ok(c) => c,
+                Err(e) => return tracing::debug!(%peer, "a QUIC handshake failed because '{e}'")
+            };
+            if let Err(e) = register(&connection, &state).await {
+                tracing::info!(%peer, "refused a QUIC connection because {e}");
+                connection.close(1u32.into(), b"refused");
+            }
+        });
+    }
+}
+// Hands the connection to the session it names, replacing any it had before
+#[cfg(feature = "quic")]
+async fn register(connection: &quinn::Connection, state: &ServerState) -> Result<(), String> {
+    let registration = Registration::accept(connection).await?;
+    let session = registration.session.clone();
+    if state.sessions.write().await.validate(&session).is_none() {
+        let _ = registration.answer(false).await;
+        return Err(String::from("it did not name a live session"));
+    }
+
+    let replaced = state.quic.connections.lock().map_err(|e| e.to_string())?.insert(session, connection.clone());
+    if let Some(c) = replaced {
+        c.close(0u32.into(), b"replaced");
+    }
+    tracing::debug!(peer = %connection.remote_address(), "a session connected over QUIC");
+    registration.answer(true).await
+}
+
+#[cfg(not(feature = "quic"))]
+#[test]
+fn test_quic_disabled() {
+    let state = Arc::new(ServerState::new());
+    assert!(QuicPlane::start(&state, "127.0.0.1:0").is_err());
+    assert_eq!(state.quic.offer(), None);
+    assert!(!state.quic.is_registered("session"));
+}
+#[cfg(feature = "quic")]
+#[tokio::test]
+async fn test_quic_plane() {
+    use crate::handlers::SessionIdentity;
+    use hermes_common::quic::{connect, register};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Pointed away from any installed certificate, so one is made for the test
+    let mut state = ServerState::new();
+    state.config.tls.cert = Some(std::path::PathBuf::from("/nonexistent/cert.pem"));
+    let state = Arc::new(state);
+    let addr = QuicPlane::start(&state, "127.0.0.1:0").unwrap();
+    let offer = state.quic.offer().unwrap();
+    assert_eq!(offer.port, addr.port());
+
+    // Only a live session is let in
+    let stranger = connect(addr, &offer.fingerprint).await.unwrap();
+    assert!(register(&stranger, "no such session").await.is_err());
+
+    let session = state.sessions.write().await.issue(SessionIdentity::User(String::from("user")));
+    let connection = connect(addr, &offer.fingerprint).await.unwrap();
+    register(&connection, session.token()).await.unwrap();
+    assert!(state.quic.is_registered(session.token()));
+
+    let mut sent = state.quic.open(session.token()).await.unwrap();
+    sent.write_all(b"frames").await.unwrap();
+    sent.shutdown().await.unwrap();
+    let mut received = QuicStream::accept(&connection).await.unwrap();
+    let mut data = Vec::new();
+    received.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, b"frames");
+
+    // The connection goes with its session
+    state.quic.forget(session.token());
+    assert!(!state.quic.is_registered(session.token()));
+    connection.closed().await;
+}
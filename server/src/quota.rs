@@ -0,0 +1,84 @@
+use crate::credentials::Credentials;
+use crate::io_tools::FileDatabase;
+use crate::storage::RecordFilter;
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::QuotaInfo;
+
+// Works out what each user stores from the file database: the files they own, and the versions kept of them.
+// Files held in quarantine are not counted, since their uploaders cannot reach them. Nothing is cached, so usage is always what is on disk right now.
+#[derive(Clone, Default, Debug)]
+pub struct QuotaManager {
+    default_quota: Option<u64> //For users without a quota of their own. None leaves them unlimited.
+}
+impl QuotaManager {
+    pub fn new(default_quota: Option<u64>) -> Self {
+        Self {
+            default_quota
+        }
+    }
+
+    pub fn quota_of(&self, user: &Credentials) -> Option<u64> {
+        user.quota().or(self.default_quota)
+    }
+
+    pub fn usage(&self, user: &Credentials, files: &FileDatabase) -> QuotaInfo {
+        let filter = RecordFilter { owner: Some(user.username().to_string()), ..Default::default() };
+        let used = files.query(&filter).iter().filter(|x| !x.is_quarantined()).map(|x| x.stored_bytes()).sum();
+        let quota = self.quota_of(user);
+
+        QuotaInfo {
+            used,
+            quota,
+            remaining: quota.map(|x| x.saturating_sub(used))
+        }
+    }
+
+    // Refuses storing this many more bytes if it would take the user past their quota
+    pub fn check(&self, user: &Credentials, files: &FileDatabase, incoming: u64) -> Result<(), (HttpCodes, String)> {
+        let usage = self.usage(user, files);
+        match (usage.quota, usage.remaining) {
+            (Some(quota), Some(remaining)) if incoming > remaining => Err((
+                HttpCodes::InsufficientStorage,
+                format!("storing {incoming} bytes would exceed your quota, {} of {quota} bytes are used", usage.used)
+            )),
+            _ => Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_quota_usage() {
+    use hermes_common::file_io::FileType;
+
+    let dir = std::env::temp_dir().join(format!("hermes_quota_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("files.json"), b"").unwrap();
+    std::fs::write(dir.join("data").join("a.bin"), vec![0u8; 600]).unwrap();
+    std::fs::write(dir.join("data").join("b.bin"), vec![0u8; 300]).unwrap();
+    std::fs::write(dir.join("data").join("open.bin"), vec![0u8; 5000]).unwrap();
+
+    let mut alice = Credentials::from("alice", "pass");
+    let mut files = FileDatabase::new();
+    files.open(dir.join("files.json").to_str().unwrap()).unwrap();
+    files.register_file(dir.join("data").join("a.bin"), Some(alice.clone()), FileType::Binary).unwrap();
+    files.register_file(dir.join("data").join("open.bin"), None, FileType::Binary).unwrap();
+
+    // Versions count against the owner too
+    files.keep_version(&dir.join("data").join("a.bin"), &dir.join("versions"), None, 5).unwrap();
+    std::fs::write(dir.join("data").join("a.bin"), vec![0u8; 100]).unwrap();
+
+    let unlimited = QuotaManager::default();
+    assert_eq!(unlimited.usage(&alice, &files), QuotaInfo { used: 700, quota: None, remaining: None });
+    assert!(unlimited.check(&alice, &files, u64::MAX).is_ok());
+
+    let limited = QuotaManager::new(Some(1000));
+    assert_eq!(limited.usage(&alice, &files).remaining, Some(300));
+    assert!(limited.check(&alice, &files, 300).is_ok());
+    assert_eq!(limited.check(&alice, &files, 301).unwrap_err().0, HttpCodes::InsufficientStorage);
+
+    // A user's own quota wins over the default
+    alice.set_quota(Some(100));
+    assert_eq!(limited.usage(&alice, &files), QuotaInfo { used: 700, quota: Some(100), remaining: Some(0) });
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,165 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::Debug;
+use std::time::Duration;
+use rand::RngCore;
+
+use crate::credentials::{Credentials, UserDatabase};
+use hermes_common::file_io::JsonFile;
+use hermes_common::session::{ResumeToken, unix_now};
+
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ResumeRecord {
+    token: String,
+    username: String,
+    revision: u32,
+    expires_at: u64
+}
+
+// Keeps the resumption tokens handed out to clients, so they can reconnect without credentials across client (and server) restarts.
+pub struct ResumeTokenStore {
+    file: JsonFile,
+    records: Vec<ResumeRecord>,
+    ttl: Duration
+}
+impl Debug for ResumeTokenStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Path: '{}', Tokens: {}, TTL: {}s)", self.file.path().unwrap_or("Unopened"), self.records.len(), self.ttl.as_secs())
+    }
+}
+impl Default for ResumeTokenStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESUME_TTL)
+    }
+}
+impl ResumeTokenStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            file: JsonFile::new(),
+            records: Vec::new(),
+            ttl
+        }
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<(), String> {
+        let contents = self.file.open(path)?;
+        if contents.trim().is_empty() {
+            self.records.clear();
+            return Ok(());
+        }
+
+        match serde_json::from_str(&contents) {
+            Ok(l) => {
+                self.records = l;
+                self.prune();
+                Ok(())
+            },
+            Err(e) => Err(e.to_string())
+        }
+    }
+    pub fn save(&self) -> Result<(), String> {
+        let contents = match serde_json::to_string(&self.records) {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.file.save(&contents).map_err(|e| e.to_string())
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+    // A TTL of zero disables resumption entirely
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    fn prune(&mut self) {
+        let now = unix_now();
+        self.records.retain(|x| x.expires_at > now);
+    }
+
+    pub fn issue(&mut self, user: &Credentials) -> Option<ResumeToken> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        self.prune();
+
+        let record = ResumeRecord {
+            token: generate_token(),
+            username: user.username().to_string(),
+            revision: user.revision(),
+            expires_at: unix_now() + self.ttl.as_secs()
+        };
+        let result = ResumeToken::new(record.token.clone(), record.expires_at);
+
+        self.records.push(record);
+        Some(result)
+    }
+
+    // Consumes a token, returning the username it was issued to. The token is only honored if it has not expired, and the user still exists with an unchanged password.
+    pub fn redeem(&mut self, token: &str, users: &UserDatabase) -> Option<String> {
+        self.prune();
+
+        let index = self.records.iter().position(|x| x.token == token)?;
+        let record = self.records.remove(index);
+
+        let user = users.get_user(&record.username)?;
+        if user.revision() != record.revision {
+            None
+        } else {
+            Some(record.username)
+        }
+    }
+
+    pub fn revoke(&mut self, token: &str) -> bool {
+        let prev_len = self.records.len();
+        self.records.retain(|x| x.token != token);
+
+        prev_len != self.records.len()
+    }
+    // Used by administrators to kill every outstanding session of a user
+    pub fn revoke_user(&mut self, username: &str) -> usize {
+        let prev_len = self.records.len();
+        self.records.retain(|x| x.username != username);
+
+        prev_len - self.records.len()
+    }
+}
+
+#[test]
+fn test_resume_token_lifecycle() {
+    let mut store = ResumeTokenStore::default();
+    let user = Credentials::from("user", "pass");
+    let users = UserDatabase::new();
+
+    let token = store.issue(&user).unwrap();
+    assert!(!token.is_expired());
+    assert_eq!(store.len(), 1);
+
+    // The user database is not open, so the user cannot be found and the token is consumed without success
+    assert_eq!(store.redeem(token.token(), &users), None);
+    assert!(store.is_empty());
+
+    store.issue(&user).unwrap();
+    store.issue(&user).unwrap();
+    assert_eq!(store.revoke_user("user"), 2);
+
+    store.set_ttl(Duration::ZERO);
+    assert!(store.issue(&user).is_none());
+}
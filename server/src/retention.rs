@@ -0,0 +1,267 @@
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt::{Debug, Display};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::io_tools::{FileDatabase, FileMutation};
+use hermes_common::file_io::JsonFile;
+
+// Files carrying this tag are never removed by a retention policy
+pub const RETENTION_EXEMPT_TAG: &str = "retain";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct RetentionPolicy {
+    path: PathBuf, //Relative to the root directory
+    max_age: u64, //Seconds since the last modification
+    #[serde(default)]
+    warn_before: u64 //Seconds before deletion to emit a warning
+}
+impl Display for RetentionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' kept for {} seconds", self.path.display(), self.max_age)
+    }
+}
+impl RetentionPolicy {
+    pub fn new(path: PathBuf, max_age: Duration, warn_before: Duration) -> Self {
+        Self {
+            path,
+            max_age: max_age.as_secs(),
+            warn_before: warn_before.as_secs()
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age)
+    }
+    pub fn warn_before(&self) -> Duration {
+        Duration::from_secs(self.warn_before)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum RetentionEvent {
+    Warning(PathBuf, SystemTime), //Path, when it will be removed
+    Deleted(PathBuf),
+    Failed(PathBuf, String)
+}
+impl Display for RetentionEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning(p, at) => write!(f, "'{}' will be removed by retention at {}", p.display(), at.duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default()),
+            Self::Deleted(p) => write!(f, "'{}' was removed by retention", p.display()),
+            Self::Failed(p, e) => write!(f, "'{}' could not be removed by retention because '{}'", p.display(), e)
+        }
+    }
+}
+impl RetentionEvent {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Warning(p, _) | Self::Deleted(p) | Self::Failed(p, _) => p
+        }
+    }
+}
+
+fn collect_files(dir: &Path, into: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => collect_files(&path, into),
+            Ok(t) if t.is_file() => into.push(path),
+            _ => continue
+        }
+    }
+}
+
+pub struct RetentionManager {
+    file: JsonFile,
+    policies: Vec<RetentionPolicy>,
+    warned: HashSet<PathBuf>
+}
+impl Debug for RetentionManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Path: '{}', Policies: {})", self.file.path().unwrap_or("Unopened"), self.policies.len())
+    }
+}
+impl Default for RetentionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl RetentionManager {
+    pub fn new() -> Self {
+        Self {
+            file: JsonFile::new(),
+            policies: Vec::new(),
+            warned: HashSet::new()
+        }
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<(), String> {
+        let contents = self.file.open(path)?;
+        if contents.trim().is_empty() {
+            self.policies.clear();
+            return Ok(());
+        }
+
+        match serde_json::from_str(&contents) {
+            Ok(l) => {
+                self.policies = l;
+                Ok(())
+            },
+            Err(e) => Err(e.to_string())
+        }
+    }
+    pub fn save(&self) -> Result<(), String> {
+        let contents = match serde_json::to_string(&self.policies) {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.file.save(&contents).map_err(|e| e.to_string())
+    }
+
+    pub fn policies(&self) -> &Vec<RetentionPolicy> {
+        &self.policies
+    }
+    pub fn set_policy(&mut self, policy: RetentionPolicy) {
+        self.policies.retain(|x| x.path != policy.path);
+        self.policies.push(policy);
+    }
+    pub fn remove_policy(&mut self, path: &Path) -> bool {
+        let prev_len = self.policies.len();
+        self.policies.retain(|x| x.path != path);
+
+        prev_len != self.policies.len()
+    }
+
+    // The most specific policy wins, so 'incoming/keep' can override 'incoming'
+    pub fn policy_for(&self, relative: &Path) -> Option<&RetentionPolicy> {
+        self.policies.iter().filter(|x| relative.starts_with(&x.path)).max_by_key(|x| x.path.components().count())
+    }
+
+    // Walks every directory with a policy, removing files older than their policy allows and warning about those that are close.
+    pub fn sweep<F>(&mut self, root: &Path, now: SystemTime, is_exempt: F) -> Vec<RetentionEvent> where F: Fn(&Path) -> bool {
+        let mut files = Vec::<PathBuf>::new();
+        for policy in &self.policies {
+            collect_files(&root.join(&policy.path), &mut files);
+        }
+        files.sort();
+        files.dedup();
+
+        let mut events = Vec::<RetentionEvent>::new();
+        for path in files {
+            let policy = match path.strip_prefix(root).ok().and_then(|x| self.policy_for(x)) {
+                Some(p) => p,
+                None => continue
+            };
+            if is_exempt(&path) {
+                continue;
+            }
+
+            let modified = match fs::metadata(&path).and_then(|m| m.modified()) {
+                Ok(m) => m,
+                Err(_) => continue
+            };
+
+            let expires_at = modified + policy.max_age();
+            if expires_at <= now {
+                events.push(
+                    match fs::remove_file(&path) {
+                        Ok(_) => RetentionEvent::Deleted(path.clone()),
+                        Err(e) => RetentionEvent::Failed(path.clone(), e.to_string())
+                    }
+                );
+                self.warned.remove(&path);
+            }
+            else if expires_at <= now + policy.warn_before() && !self.warned.contains(&path) {
+                events.push(RetentionEvent::Warning(path.clone(), expires_at));
+                self.warned.insert(path);
+            }
+        }
+
+        events
+    }
+}
+
+// Runs one retention pass against the file database, which decides what is exempt (tagged or under legal hold) and forgets what was removed
+pub fn enforce_retention(manager: &mut RetentionManager, files: &mut FileDatabase, root: &Path, log_path: &Path) -> Vec<RetentionEvent> {
+    let events = manager.sweep(
+        root,
+        SystemTime::now(),
+        |p| files.check_mutation(p, FileMutation::Expire).is_err() || files.get_file_by_path(p).map(|f| f.has_tag(RETENTION_EXEMPT_TAG)).unwrap_or(false)
+    );
+
+    for event in &events {
+        if let RetentionEvent::Deleted(p) = event {
+            files.unregister_file(p);
+        }
+    }
+
+    log_retention_events(log_path, &events);
+    events
+}
+
+pub fn log_retention_events(log_path: &Path, events: &[RetentionEvent]) {
+    if events.is_empty() {
+        return;
+    }
+
+    let mut file = match OpenOptions::new().create(true).append(true).open(log_path) {
+        Ok(f) => f,
+        Err(_) => return
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or_default();
+    for event in events {
+        let kind = match event {
+            RetentionEvent::Warning(_, _) => "warning",
+            RetentionEvent::Deleted(_) => "deleted",
+            RetentionEvent::Failed(_, _) => "failed"
+        };
+
+        let _ = writeln!(file, "{}", json!({ "at": now, "event": kind, "path": event.path(), "detail": event.to_string() }));
+    }
+}
+
+#[test]
+fn test_retention_sweep() {
+    let root = std::env::temp_dir().join(format!("hermes_retention_{}", std::process::id()));
+    let incoming = root.join("incoming");
+    fs::create_dir_all(incoming.join("keep")).unwrap();
+    fs::write(incoming.join("old.txt"), b"old").unwrap();
+    fs::write(incoming.join("tagged.txt"), b"tagged").unwrap();
+    fs::write(incoming.join("keep").join("kept.txt"), b"kept").unwrap();
+    fs::write(root.join("outside.txt"), b"outside").unwrap();
+
+    let mut manager = RetentionManager::new();
+    manager.set_policy(RetentionPolicy::new(PathBuf::from("incoming"), Duration::from_secs(60), Duration::from_secs(30)));
+    manager.set_policy(RetentionPolicy::new(PathBuf::from("incoming/keep"), Duration::from_secs(3600), Duration::from_secs(0)));
+
+    // Warned once when inside the warning window, not again on the next sweep
+    let soon = SystemTime::now() + Duration::from_secs(45);
+    let events = manager.sweep(&root, soon, |p| p.ends_with("tagged.txt"));
+    assert_eq!(events.len(), 1);
+    assert!(matches!(&events[0], RetentionEvent::Warning(p, _) if p.ends_with("old.txt")));
+    assert!(manager.sweep(&root, soon, |p| p.ends_with("tagged.txt")).is_empty());
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    let events = manager.sweep(&root, later, |p| p.ends_with("tagged.txt"));
+    assert_eq!(events, vec![RetentionEvent::Deleted(incoming.join("old.txt"))]);
+    assert!(incoming.join("tagged.txt").exists());
+    assert!(incoming.join("keep").join("kept.txt").exists());
+    assert!(root.join("outside.txt").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,251 @@
+use serde::{Serialize, Deserialize};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::credentials::Credentials;
+use crate::handlers::display_path;
+use crate::io_tools::FileDatabase;
+use crate::resume::generate_token;
+use hermes_common::http_codes::HttpCodes;
+
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(60);
+// clamd refuses chunks larger than its StreamMaxLength, which is far above this
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+fn default_scan_timeout() -> u64 {
+    DEFAULT_SCAN_TIMEOUT.as_secs()
+}
+
+// Where uploads are scanned before they are put in place. Without this in the configuration, nothing is scanned.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ScannerConfig {
+    pub clamd: String, //Where clamd listens for TCP, such as '127.0.0.1:3310'
+    #[serde(default = "default_scan_timeout")]
+    pub timeout_secs: u64, //How long connecting to clamd, and waiting on its answer, may take
+    #[serde(default)]
+    pub fail_open: bool //Whether uploads are let through unscanned when clamd cannot be reached. They are refused by default.
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Verdict {
+    Clean,
+    Infected(String) //What the scanner found, such as 'Eicar-Signature'
+}
+
+// Anything that can tell whether a file is safe to keep. Scans may block, as they are run off the async threads.
+pub trait Scanner: Send + Sync {
+    fn describe(&self) -> String;
+    fn scan(&self, path: &Path) -> Result<Verdict, String>;
+}
+
+// clamd, over its TCP protocol. Files are streamed to it, so it does not need to be able to read the data directory.
+pub struct ClamdScanner {
+    address: String,
+    timeout: Duration
+}
+impl ClamdScanner {
+    pub fn new(address: &str, timeout: Duration) -> Self {
+        Self {
+            address: address.to_string(),
+            timeout
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, String> {
+        let address = self.address.to_socket_addrs().map_err(|e| format!("unable to resolve '{}' because '{e}'", self.address))?
+            .next()
+            .ok_or_else(|| format!("'{}' resolved to no addresses", self.address))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout).map_err(|e| format!("unable to reach clamd at '{}' because '{e}'", self.address))?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(|e| e.to_string())?;
+
+        Ok(stream)
+    }
+}
+impl Scanner for ClamdScanner {
+    fn describe(&self) -> String {
+        format!("clamd at {}", self.address)
+    }
+
+    // INSTREAM takes the file as chunks, each after its length as 4 big-endian bytes, and ends at an empty chunk. clamd answers once, then closes the connection.
+    fn scan(&self, path: &Path) -> Result<Verdict, String> {
+        let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut stream = self.connect()?;
+        stream.write_all(b"zINSTREAM\0").map_err(|e| e.to_string())?;
+
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buffer).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&(read as u32).to_be_bytes()).map_err(|e| e.to_string())?;
+            stream.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).map_err(|e| e.to_string())?;
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).map_err(|e| format!("clamd did not answer because '{e}'"))?;
+        parse_reply(&reply)
+    }
+}
+
+// Answers look like 'stream: OK', 'stream: Eicar-Signature FOUND', or 'INSTREAM size limit exceeded. ERROR', and end in a NUL
+fn parse_reply(reply: &[u8]) -> Result<Verdict, String> {
+    let text = String::from_utf8_lossy(reply);
+    let text = text.trim_end_matches(['\0', '\n']).trim();
+    let body = text.strip_prefix("stream:").map(|x| x.trim()).unwrap_or(text);
+
+    match body.strip_suffix("FOUND") {
+        _ if body == "OK" => Ok(Verdict::Clean),
+        Some(signature) if !signature.trim().is_empty() => Ok(Verdict::Infected(signature.trim().to_string())),
+        _ => Err(format!("clamd answered '{text}'"))
+    }
+}
+
+// Moves a file to where it is held, copying it when the two are on different file systems
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+// Every upload is scanned while it is still staged. One found infected is moved into the quarantine directory instead of into place,
+// and recorded there in the file database under its uploader.
+pub struct ContentScanning {
+    scanner: Box<dyn Scanner>,
+    quarantine: PathBuf,
+    fail_open: bool
+}
+impl ContentScanning {
+    pub fn new(scanner: Box<dyn Scanner>, quarantine: &Path, fail_open: bool) -> Self {
+        Self {
+            scanner,
+            quarantine: quarantine.to_path_buf(),
+            fail_open
+        }
+    }
+    pub fn from_config(config: &ScannerConfig, quarantine: &Path) -> Self {
+        Self::new(Box::new(ClamdScanner::new(&config.clamd, Duration::from_secs(config.timeout_secs))), quarantine, config.fail_open)
+    }
+
+    pub fn describe(&self) -> String {
+        self.scanner.describe()
+    }
+
+    // Returns what was found in an infected file, which is left where it is for quarantine to move. A scanner that cannot
+    // give an answer refuses the upload, unless the configuration lets uploads through unscanned.
+    pub fn scan(&self, contents: &Path, destination: &Path) -> Result<Option<String>, (HttpCodes, String)> {
+        match self.scanner.scan(contents) {
+            Ok(Verdict::Clean) => Ok(None),
+            Ok(Verdict::Infected(signature)) => Ok(Some(signature)),
+            Err(e) if self.fail_open => {
+                tracing::warn!("let '{}' through unscanned, since {} failed because {e}", display_path(destination), self.describe());
+                Ok(None)
+            },
+            Err(e) => {
+                tracing::warn!("unable to scan '{}' with {} because {e}", display_path(destination), self.describe());
+                Err((HttpCodes::InternalServerError, String::from("the upload could not be scanned, try again later")))
+            }
+        }
+    }
+
+    // Moves an infected upload into quarantine and records it, returning what the uploader is answered with.
+    // Should the move fail, the upload is still refused, and its caller throws it away.
+    pub fn quarantine(&self, contents: &Path, destination: &Path, owner: Option<&Credentials>, signature: &str, files: &mut FileDatabase) -> (HttpCodes, String) {
+        let shown = display_path(destination);
+        let stored = self.quarantine.join(generate_token());
+        let held = std::fs::create_dir_all(&self.quarantine).and_then(|_| move_file(contents, &stored))
+            .map_err(|e| e.to_string())
+            .and_then(|_| files.quarantine(stored, destination, owner.cloned(), signature).map_err(|e| e.to_string()));
+        match held {
+            Ok(_) => tracing::warn!(signature, "quarantined the upload of '{shown}' by {}", owner.map(|x| x.username()).unwrap_or("a guest")),
+            Err(e) => tracing::warn!(signature, "unable to quarantine the upload of '{shown}' because '{e}'")
+        }
+
+        (HttpCodes::UnprocessableContent, format!("'{shown}' was quarantined because it contains '{signature}'"))
+    }
+
+    // Both in turn, for callers already holding the file database
+    pub fn screen(&self, contents: &Path, destination: &Path, owner: Option<&Credentials>, files: &mut FileDatabase) -> Result<(), (HttpCodes, String)> {
+        match self.scan(contents, destination)? {
+            Some(signature) => Err(self.quarantine(contents, destination, owner, &signature, files)),
+            None => Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_parse_reply() {
+    assert_eq!(parse_reply(b"stream: OK\0"), Ok(Verdict::Clean));
+    assert_eq!(parse_reply(b"stream: Eicar-Signature FOUND\0"), Ok(Verdict::Infected(String::from("Eicar-Signature"))));
+    assert!(parse_reply(b"INSTREAM size limit exceeded. ERROR\0").is_err());
+    assert!(parse_reply(b"stream: FOUND\0").is_err());
+    assert!(parse_reply(b"").is_err());
+}
+
+#[test]
+fn test_scanning() {
+    use std::net::TcpListener;
+    use crate::quota::QuotaManager;
+
+    // Stands in for clamd for one connection, flagging anything holding 'EICAR'
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let clamd = std::thread::spawn(move || {
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            let mut received = Vec::new();
+            loop {
+                let mut length = [0; 4];
+                stream.read_exact(&mut length).unwrap();
+                let mut chunk = vec![0; u32::from_be_bytes(length) as usize];
+                if chunk.is_empty() {
+                    break;
+                }
+                stream.read_exact(&mut chunk).unwrap();
+                received.extend(chunk);
+            }
+            let reply: &[u8] = if received.windows(5).any(|x| x == b"EICAR") { b"stream: Eicar-Signature FOUND\0" } else { b"stream: OK\0" };
+            stream.write_all(reply).unwrap();
+        }
+    });
+
+    let dir = std::env::temp_dir().join(format!("hermes_scanning_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("clean.part"), "nothing to see").unwrap();
+    std::fs::write(dir.join("infected.part"), "X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*").unwrap();
+
+    let scanning = ContentScanning::from_config(&ScannerConfig { clamd: address, timeout_secs: 5, fail_open: false }, &dir.join("quarantine"));
+    let user = Credentials::from("user", "pass");
+    let mut files = FileDatabase::new();
+    assert_eq!(scanning.screen(&dir.join("clean.part"), &dir.join("clean.txt"), Some(&user), &mut files), Ok(()));
+    let (status, reason) = scanning.screen(&dir.join("infected.part"), &dir.join("infected.txt"), Some(&user), &mut files).unwrap_err();
+    clamd.join().unwrap();
+    assert_eq!(status, HttpCodes::UnprocessableContent);
+    assert!(reason.contains("Eicar-Signature"));
+
+    // The infected file is held in quarantine under its uploader, and does not count against their quota
+    assert!(!dir.join("infected.part").exists());
+    let held: Vec<PathBuf> = std::fs::read_dir(dir.join("quarantine")).unwrap().flatten().map(|x| x.path()).collect();
+    assert_eq!(held.len(), 1);
+    let record = files.get_file_by_path(&held[0]).unwrap();
+    assert!(record.is_quarantined() && record.is_owned_by(&user));
+    assert_eq!(QuotaManager::default().usage(&user, &files).used, 0);
+
+    // A scanner that cannot be reached refuses uploads, unless told to let them through
+    let unreachable = ScannerConfig { clamd: String::from("127.0.0.1:1"), timeout_secs: 1, fail_open: false };
+    assert_eq!(ContentScanning::from_config(&unreachable, &dir).scan(&dir.join("clean.part"), &dir.join("clean.txt")).unwrap_err().0, HttpCodes::InternalServerError);
+    assert_eq!(ContentScanning::from_config(&ScannerConfig { fail_open: true, ..unreachable }, &dir).scan(&dir.join("clean.part"), &dir.join("clean.txt")), Ok(None));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
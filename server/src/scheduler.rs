@@ -0,0 +1,99 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(250);
+
+struct ScheduledJob {
+    name: String,
+    interval: Duration,
+    next_run: Instant,
+    job: Box<dyn FnMut() + Send>
+}
+
+// Runs maintenance jobs (retention, expiry of tokens, ...) periodically on a background thread
+pub struct Scheduler {
+    jobs: Vec<ScheduledJob>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>
+}
+impl Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.jobs.iter().map(|x| x.name.as_str()).collect();
+        write!(f, "Scheduler(jobs: {:?}, running: {})", names, self.is_running())
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            jobs: Vec::new(),
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None
+        }
+    }
+
+    // Jobs are first run one interval after the scheduler starts
+    pub fn schedule<F>(&mut self, name: &str, interval: Duration, job: F) -> Result<(), String> where F: FnMut() + Send + 'static {
+        if self.is_running() {
+            return Err(String::from("jobs cannot be added while the scheduler is running"));
+        }
+
+        self.jobs.push(
+            ScheduledJob {
+                name: name.to_string(),
+                interval,
+                next_run: Instant::now() + interval,
+                job: Box::new(job)
+            }
+        );
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.is_running() {
+            return Err(String::from("scheduler is already running"));
+        }
+
+        let mut jobs = std::mem::take(&mut self.jobs);
+        let running = Arc::clone(&self.running);
+        running.store(true, Ordering::SeqCst);
+
+        self.handle = Some(
+            std::thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    for job in jobs.iter_mut().filter(|x| x.next_run <= now) {
+                        (job.job)();
+                        job.next_run = now + job.interval;
+                    }
+
+                    std::thread::sleep(TICK);
+                }
+            })
+        );
+
+        Ok(())
+    }
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
@@ -0,0 +1,2236 @@
+use rustls::ServerConfig;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+
+use crate::credentials::{Credentials, GUEST_USERNAME};
+use crate::handlers::{handle_connect, handle_grant_request, handle_upload_request, complete_upload, handle_download_request, handle_hold_request, handle_rename_request, handle_copy_request, handle_share_request, handle_share_link_request, handle_user_admin_request};
+use crate::handlers::{handle_heartbeat, handle_ping, record_diagnostics, MAX_PROBE_FRAMES};
+use crate::handlers::{handle_restore_request, handle_purge_trash_request, handle_versions_request, handle_signature_request, handle_preview_request, handle_thumbnail_request, delta_download};
+use crate::handlers::{handle_upload_dir_request, unpack_dir_upload, handle_download_dir_request, handle_subscribe_request};
+use crate::watch::WatchEvent;
+use crate::handlers::{handle_delete_request, handle_change_dir_request, handle_move_request, handle_subfolder_request, handle_dir_request, handle_stat_request, handle_can_i_request, dir_response, dir_request_target, page_listing, resolve_directory, display_path, resolve_target, SessionIdentity, UploadPlan};
+use crate::io_loc::{root_directory, is_internal_path, audit_log_path, diagnostics_log_path, versions_directory, delta_directory, archive_directory, thumbnail_directory};
+use crate::thumbnails::ThumbnailCache;
+use crate::resume::generate_token;
+use crate::io_tools::{move_relative, jail_depth, check_links, FileDatabase};
+use crate::middleware::{RequestContext, TokenBucket, is_mutation, read_audit_log};
+use crate::proxy::upstream_path;
+use crate::cancel::CancelWatch;
+use crate::locks::LockKind;
+use crate::backend::mirror;
+use crate::channels::{ChannelScheduler, Turn, CHANNEL_CHUNK_SIZE};
+use crate::metrics::Direction;
+use crate::state::ServerState;
+use crate::hooks::{Hooks, HookEvent, HookRefusal};
+use crate::dedupe::{link_duplicate, dedupe_report};
+use crate::transfers::{TransferScheduler, TransferSlot, TransferPriority};
+use hermes_common::progress::{CancelToken, Progress};
+use hermes_common::chunking::ChunkWriter;
+use hermes_common::file_io::{FileChunkIter, receive_network_file_checked_async, ReceiveOptions, receive_network_binary_async, send_network_file_async, send_network_frames_async, BUFF_SIZE, detect_file_type};
+use hermes_common::framing::{read_frame_async, read_message_async, write_frame_async, write_frame_with_async, write_channel_frame_async, FrameError};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, MessageType, MessageDirection, MessageParseError, ack_messsage, connect_ack_message, advertise_frame_bounds, session_ack, extract_session, extract_idempotency_key, extract_ack_message, stats_response_message, upload_message_response};
+use hermes_common::messages::{extract_download_request_message, extract_change_dir_message, download_message_response, DownloadResponse};
+use hermes_common::messages::{extract_can_i_request, can_i_response, extract_subfolder_message, IntendedOperation, SubfolderAction};
+use hermes_common::messages::{extract_probe_message, ProbeDirection, advertise_capabilities, extract_capabilities, quota_response, advertise_compressions, extract_compressions, attach_compression, extract_compression};
+use hermes_common::compression::Compression;
+use hermes_common::codec::Codec;
+use hermes_common::messages::{attach_channel, extract_channel, attach_quic, extract_quic, advertise_quic, queued_message, extract_interactive};
+use hermes_common::messages::{extract_connect_message, extract_lockouts_request, lockouts_response, extract_audit_request, audit_response};
+use hermes_common::messages::{advertise_codecs, extract_codecs, extract_delete_message, extract_download_dir_message, dedupe_response, usage_response};
+use hermes_common::checksum::Checksum;
+use hermes_common::delta::apply_delta_file;
+use hermes_common::messages::{extract_signature, extract_delta, upload_dir_response, download_dir_response, event_message, FileEvent, Permission};
+use hermes_common::messages::{extract_changes_since_request, changes_since_response, ChangeReport, FileChange};
+use hermes_common::messages::{advertise_idle_timeout, extract_connections_request, connections_response_message, close_with_reason, extract_cancel_message, extract_latency};
+use hermes_common::network_stats::Latency;
+use hermes_common::protocol::{Capabilities, Capability};
+use hermes_common::transport::AsyncTransport;
+use hermes_common::tuning::FrameSizeTuner;
+
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:9090";
+// Each subscription is checked against every change under the root, so a connection may only hold a few
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+// Accepts connections forever, handing each to its own task. TLS is used for every connection when a configuration is given.
+pub async fn run(addr: &str, state: Arc<ServerState>, tls: Option<Arc<ServerConfig>>) -> Result<(), String> {
+    let listener = TcpListener::bind(addr).await.map_err(|e| format!("unable to bind to '{addr}' because '{e}'"))?;
+    serve(listener, state, tls).await
+}
+pub async fn serve(listener: TcpListener, state: Arc<ServerState>, tls: Option<Arc<ServerConfig>>) -> Result<(), String> {
+    let acceptor = tls.map(TlsAcceptor::from);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!("unable to accept a connection because '{e}'");
+                continue;
+            }
+        };
+
+        // Everything logged for this connection carries its address, and its user once it has logged in
+        let span = tracing::info_span!("connection", peer = %peer.ip(), username = tracing::field::Empty);
+
+        // A connection is still usable with the system defaults, so a failure here is only reported
+        if let Err(e) = state.socket_options.apply_async(&stream) {
+            span.in_scope(|| tracing::warn!("{e}"));
+        }
+
+        let state = Arc::clone(&state);
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match accept(stream, acceptor).await {
+                Ok(t) => serve_connection(t, peer, state).await,
+                Err(e) => tracing::warn!("{e}")
+            }
+        }.instrument(span));
+    }
+}
+// Serves a connection that is already up, from admission to disconnect
+async fn serve_connection(transport: Box<dyn AsyncTransport>, peer: SocketAddr, state: Arc<ServerState>) {
+    // Refused once the transport is up, so the client can read why
+    if let Some(refusal) = admission(&state, peer.ip()) {
+        tracing::info!("refused, {}", refusal.1);
+        let mut transport = transport;
+        let _ = write_frame_async(&mut transport, &ack(refusal.0, &refusal.1)).await;
+        return;
+    }
+
+    tracing::debug!("connected");
+    match Connection::new(transport, peer, state).run().await {
+        Ok(()) => tracing::debug!("disconnected"),
+        Err(e) => tracing::warn!("{e}")
+    }
+}
+// A connection served in-process, for front ends that speak another protocol on behalf of a client at this address.
+// Everything a TCP connection goes through applies to it, from the access lists to the locks.
+pub fn loopback(peer: SocketAddr, state: Arc<ServerState>) -> Box<dyn AsyncTransport> {
+    let (client, server) = tokio::io::duplex(2 * BUFF_SIZE as usize);
+    let span = tracing::info_span!("connection", peer = %peer.ip(), username = tracing::field::Empty);
+    tokio::spawn(serve_connection(Box::new(server), peer, state).instrument(span));
+    Box::new(client)
+}
+
+// Why a new connection from this address is turned away, if it is. The access lists are checked before the connection limits.
+fn admission(state: &ServerState, address: IpAddr) -> Option<(HttpCodes, String)> {
+    let access = &state.config.access;
+    if !access.permits(address) {
+        return Some((HttpCodes::Forbidden, format!("connections from {address} are not allowed")));
+    }
+    if let Some(max) = state.config.max_connections.filter(|x| state.connections.len() >= *x) {
+        return Some((HttpCodes::TooManyRequests, format!("the server is at its limit of {max} connections")));
+    }
+    if let Some(max) = access.max_per_address.filter(|x| state.connections.from_address(&address.to_string()) >= *x) {
+        return Some((HttpCodes::TooManyRequests, format!("{address} is at its limit of {max} connections")));
+    }
+
+    None
+}
+
+async fn accept(stream: TcpStream, acceptor: Option<TlsAcceptor>) -> Result<Box<dyn AsyncTransport>, String> {
+    match acceptor {
+        Some(a) => match a.accept(stream).await {
+            Ok(s) => Ok(Box::new(s)),
+            Err(e) => Err(format!("TLS handshake failed because '{e}'"))
+        },
+        None => Ok(Box::new(stream))
+    }
+}
+
+fn ack(code: HttpCodes, message: &str) -> Message {
+    ack_messsage(MessageDirection::Response, code, Some(message.to_string()))
+}
+// Everything this server can speak, which leaves out thumbnails when it was built without them, and QUIC when it is not listening for it
+fn served_capabilities(quic: bool) -> Capabilities {
+    Capabilities::supported().iter().copied().filter(|x| (*x != Capability::Thumbnail || cfg!(feature = "thumbnails")) && (*x != Capability::Quic || quic)).collect()
+}
+// Refuses a request whose paths another request is using, in the shape its client expects
+fn locked_response(kind: MessageType) -> Message {
+    let reason = "the path is in use by another request, try again later";
+    match kind {
+        MessageType::Upload => upload_message_response(HttpCodes::Conflict, reason, 0),
+        MessageType::Download => download_message_response(DownloadResponse::failure(HttpCodes::Conflict, reason)),
+        MessageType::UploadDir => upload_dir_response(HttpCodes::Conflict, reason),
+        _ => ack(HttpCodes::Conflict, reason)
+    }
+}
+// Tells the client its frames are coming compressed
+fn with_compression(response: Message, compression: Option<Compression>) -> Message {
+    match compression {
+        Some(c) => attach_compression(response, c),
+        None => response
+    }
+}
+
+// Refusals and failures are worth a line each. Everything else only with debug logging on.
+fn log_response(response: &Message) {
+    if *response.message_type() != MessageType::Ack {
+        return;
+    }
+
+    let (code, message) = match extract_ack_message(response.clone()) {
+        Some((code, message)) => (code.as_u16(), message),
+        None => return
+    };
+    match code {
+        500.. => tracing::warn!(code, "failed: {message}"),
+        400.. => tracing::info!(code, "refused: {message}"),
+        _ => tracing::debug!(code, "answered")
+    }
+}
+
+// What ended a wait for the next request
+enum Waited {
+    Request(Message),
+    Rejected(MessageParseError), //A frame that was read whole, but holds nothing that can be dispatched
+    Idle,
+    Shutdown(String)
+}
+// What woke a connection that was waiting for its next request with something else to do
+enum Waiting {
+    Read(std::io::Result<usize>),
+    Event(Result<WatchEvent, RecvError>),
+    Channel //A channel is ready for its next frame
+}
+// Never finishes for a connection without subscriptions
+async fn next_event(events: Option<&mut broadcast::Receiver<WatchEvent>>) -> Result<WatchEvent, RecvError> {
+    match events {
+        Some(e) => e.recv().await,
+        None => std::future::pending().await
+    }
+}
+
+// One client connection, from its Connect to its Close (or disconnect)
+struct Connection {
+    transport: Box<dyn AsyncTransport>,
+    peer: SocketAddr,
+    state: Arc<ServerState>,
+    identity: Option<SessionIdentity>,
+    session: Option<String>,
+    curr_dir: PathBuf,
+    home: PathBuf, //Every path a request names must stay beneath this
+    capabilities: Capabilities, //What both sides said they speak at Connect
+    compressions: Vec<Compression>, //The compressions both sides speak, in the order the client prefers them
+    codec: Codec, //What messages and listings are written in, JSON until the Connect ack is sent
+    request_id: u64, //Set on every response while its request is answered, and 0 between requests
+    channels: ChannelScheduler, //Multiplexed downloads still being sent
+    requests: Option<TokenBucket>, //What this connection may still send before it is told to slow down. None when it is not limited.
+    subscriptions: Vec<PathBuf>, //Directories whose changes are pushed to the client
+    events: Option<broadcast::Receiver<WatchEvent>>, //Only held while there are subscriptions
+    activity: u64, //This connection's entry in the server's connection tracker
+    span: tracing::Span, //The connection's log span, which the username is added to at login
+    last_response: Option<Message> //Handed to the pipeline once the request is finished
+}
+impl Connection {
+    fn new(transport: Box<dyn AsyncTransport>, peer: SocketAddr, state: Arc<ServerState>) -> Self {
+        let activity = state.connections.open(&peer.ip().to_string());
+        let requests = state.config.limits.bucket();
+        Self {
+            transport,
+            peer,
+            state,
+            identity: None,
+            session: None,
+            curr_dir: root_directory(),
+            home: root_directory(),
+            capabilities: Capabilities::default(),
+            compressions: Vec::new(),
+            codec: Codec::Json,
+            request_id: 0,
+            channels: ChannelScheduler::new(),
+            requests,
+            subscriptions: Vec::new(),
+            events: None,
+            activity,
+            span: tracing::Span::current(),
+            last_response: None
+        }
+    }
+
+    fn peer_ip(&self) -> String {
+        self.peer.ip().to_string()
+    }
+
+    async fn send(&mut self, message: &Message) -> Result<(), String> {
+        let message = message.clone().with_request_id(self.request_id);
+        let result = write_frame_with_async(&mut self.transport, &message, self.codec).await;
+        self.last_response = Some(message);
+        result
+    }
+
+    async fn run(mut self) -> Result<(), String> {
+        let result = self.serve_requests().await;
+
+        // However the connection ended, its session cannot be used again
+        if let Some(token) = self.session.take() {
+            self.state.sessions.write().await.invalidate(&token);
+            self.state.quic.forget(&token);
+        }
+        self.state.connections.close(self.activity);
+        // Interop problems are easier to chase knowing what the peer said it speaks
+        result.map_err(|e| format!("{e} (capabilities: {})", self.capabilities))
+    }
+
+    async fn serve_requests(&mut self) -> Result<(), String> {
+        loop {
+            let message = match self.wait_for_request().await {
+                Ok(Waited::Request(m)) => m,
+                // The frame was read whole, so the connection is still in step and only this request is refused
+                Ok(Waited::Rejected(e)) => {
+                    tracing::info!("refused a frame because '{e}'");
+                    self.request_id = match &e {
+                        MessageParseError::UnknownType(_, id) => *id,
+                        MessageParseError::Malformed(_) => 0
+                    };
+                    self.send(&ack(HttpCodes::BadRequest, &e.to_string())).await?;
+                    self.request_id = 0;
+                    if let Some(response) = self.last_response.take() {
+                        log_response(&response);
+                        self.state.metrics.record_response(&response);
+                    }
+                    continue;
+                },
+                // The client is told why, in case it is still there to read it
+                Ok(Waited::Idle) => {
+                    let idle = self.state.config.idle_timeout_secs;
+                    let _ = self.send(&ack(HttpCodes::RequestTimeout, &format!("closing the connection after {idle} seconds without a request"))).await;
+                    return Ok(());
+                },
+                Ok(Waited::Shutdown(reason)) => {
+                    let _ = self.send(&close_with_reason(&reason)).await;
+                    return Ok(());
+                },
+                Err(_) if self.session.is_some() => return Ok(()), // The client went away without a Close
+                Err(e) => return Err(e.to_string())
+            };
+            self.state.connections.touch(self.activity);
+
+            let span = tracing::info_span!("request", message_type = %message.message_type(), request_id = message.request_id());
+            self.request_id = message.request_id();
+            let handled = self.handle_request(message).instrument(span).await;
+            self.request_id = 0;
+            if !handled? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Answers one request. Returns false once the client has asked to close the connection.
+    async fn handle_request(&mut self, message: Message) -> Result<bool, String> {
+        tracing::debug!("received");
+        if *message.direction() != MessageDirection::Request {
+            self.send(&ack(HttpCodes::BadRequest, "expected a request")).await?;
+            return Ok(true);
+        }
+        // Nothing is dispatched that its handler could not read, or that goes past what a peer may send
+        if let Err(e) = message.validate() {
+            tracing::info!("refused because '{e}'");
+            self.send(&ack(HttpCodes::BadRequest, &e.to_string())).await?;
+            return Ok(true);
+        }
+
+        match *message.message_type() {
+            MessageType::Close => return Ok(false),
+            MessageType::Connect => {
+                self.connect(message).await?;
+                if let Some(response) = self.last_response.take() {
+                    log_response(&response);
+                    self.state.metrics.record_response(&response);
+                }
+                return Ok(true);
+            },
+            _ => {}
+        }
+
+        // A Cancel only ever ends something already running, so it is never held back
+        if *message.message_type() != MessageType::Cancel && self.requests.as_mut().is_some_and(|x| !x.take()) {
+            tracing::info!("refused because the connection is sending too many requests");
+            self.send(&ack(HttpCodes::TooManyRequests, "too many requests on this connection, slow down")).await?;
+            return Ok(true);
+        }
+        if let Err(e) = self.authenticate(&message).await {
+            tracing::info!("refused because '{e}'");
+            self.send(&ack(HttpCodes::Unauthorized, &e)).await?;
+            return Ok(true);
+        }
+
+        let state = Arc::clone(&self.state);
+        let (request, identity, peer, directory) = (message.clone(), self.identity.clone(), self.peer_ip(), self.curr_dir.clone());
+        let ctx = RequestContext {
+            message: &request,
+            identity: identity.as_ref(),
+            peer: &peer,
+            directory: &directory
+        };
+
+        // Credited both before and after, since the watcher can see a change before its request is answered
+        let mutation = is_mutation(*request.message_type());
+        if mutation {
+            self.attribute(&request).await;
+        }
+        let result = match state.pipeline.before(&ctx) {
+            Ok(()) => self.dispatch(message).await,
+            Err(response) => self.send(&response).await
+        };
+        if mutation {
+            self.attribute(&request).await;
+        }
+
+        if let Some(response) = self.last_response.take() {
+            log_response(&response);
+            state.metrics.record_response(&response);
+            state.pipeline.after(&ctx, &response);
+        }
+        result?;
+
+        Ok(true)
+    }
+
+    // Gives up once the connection has sent nothing for the idle timeout. Pushed events do not count, since they say nothing about whether the client is still there.
+    // A shutdown is only noticed here, between requests, so a transfer that has started is always finished. That includes every open channel.
+    async fn wait_for_request(&mut self) -> Result<Waited, FrameError> {
+        let state = Arc::clone(&self.state);
+        loop {
+            // A connection with channels still sending is not idle
+            let sending = !self.channels.is_empty();
+            let idle = match sending {
+                true => Duration::MAX,
+                false => state.config.idle_timeout().unwrap_or(Duration::MAX)
+            };
+
+            tokio::select! {
+                biased;
+                reason = state.shutdown.wait(), if !sending => return Ok(Waited::Shutdown(reason)),
+                result = tokio::time::timeout(idle, self.next_request()) => match result {
+                    Ok(Ok(Some(message))) => return Ok(Waited::Request(message)),
+                    Ok(Ok(None)) => continue, //The last channel finished, so the idle timeout starts from now
+                    Ok(Err(FrameError::Rejected(e))) => return Ok(Waited::Rejected(e)),
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => return Ok(Waited::Idle)
+                }
+            }
+        }
+    }
+    // Waits for the next request, pushing events to a subscribed client and sending open channels in the meantime.
+    // Only a single byte is read while waiting, so an event or channel frame never cuts into a frame halfway through.
+    // Returns None once the last open channel has finished.
+    async fn next_request(&mut self) -> Result<Option<Message>, FrameError> {
+        loop {
+            let sending = !self.channels.is_empty();
+            if self.events.is_none() && !sending {
+                return read_message_async(&mut self.transport).await.map(Some);
+            }
+
+            let mut first = [0u8; 1];
+            let waited = tokio::select! {
+                biased;
+                read = self.transport.read(&mut first) => Waiting::Read(read),
+                event = next_event(self.events.as_mut()) => Waiting::Event(event),
+                _ = std::future::ready(()), if sending => Waiting::Channel
+            };
+
+            match waited {
+                Waiting::Read(Ok(0)) => return Err(FrameError::Broken(String::from("the connection was closed"))),
+                Waiting::Read(Ok(_)) => return read_message_async(&mut (&first[..]).chain(&mut self.transport)).await.map(Some),
+                Waiting::Read(Err(e)) => return Err(FrameError::Broken(e.to_string())),
+                Waiting::Event(Ok(event)) => self.push_event(event).await?,
+                Waiting::Event(Err(RecvError::Lagged(_))) => { }, //The client was too slow, and some events were skipped
+                Waiting::Event(Err(RecvError::Closed)) => self.events = None,
+                Waiting::Channel => {
+                    self.send_channel_turn().await?;
+                    if self.channels.is_empty() {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+    // Sends the next frame of the open channels. A channel that fails is answered with why, under its request's ID.
+    async fn send_channel_turn(&mut self) -> Result<(), String> {
+        match self.channels.next_turn() {
+            Some(Turn::Data(id, chunk)) => write_channel_frame_async(&mut self.transport, id, &chunk).await,
+            Some(Turn::Finished(finished)) => {
+                write_channel_frame_async(&mut self.transport, finished.request_id, &[]).await?;
+                self.state.metrics.record_transfer(Direction::Sent, finished.bytes, finished.elapsed);
+                if finished.elapsed.as_secs_f32() > 0.0 {
+                    let _ = self.state.stats.record_tuned_transfer(finished.bytes, finished.elapsed.as_secs_f32(), &self.peer_ip(), vec![CHANNEL_CHUNK_SIZE as u32], finished.latency);
+                }
+                Ok(())
+            },
+            Some(Turn::Failed(id, e)) => {
+                let response = ack(HttpCodes::Conflict, &format!("the download stopped because '{e}'")).with_request_id(id);
+                log_response(&response);
+                self.state.metrics.record_response(&response);
+                write_frame_with_async(&mut self.transport, &response, self.codec).await
+            },
+            None => Ok(())
+        }
+    }
+
+    async fn push_event(&mut self, event: WatchEvent) -> Result<(), String> {
+        if !self.subscriptions.iter().any(|x| event.path.starts_with(x)) {
+            return Ok(());
+        }
+        let user = self.user().await;
+        if self.state.files.read().await.check_access(&event.path, user.as_ref(), Permission::Read).is_err() {
+            return Ok(());
+        }
+
+        let event = FileEvent {
+            kind: event.kind,
+            path: display_path(&event.path),
+            actor: event.actor
+        };
+        write_frame_with_async(&mut self.transport, &event_message(&event), self.codec).await
+    }
+    // Credits the changes a request makes, to every path it names, to whoever sent it
+    async fn attribute(&self, message: &Message) {
+        if !self.state.watch.is_running() {
+            return;
+        }
+        let actor = match self.acting_user().await {
+            Some(u) => u.username().to_string(),
+            None => return
+        };
+
+        for raw in ["path", "name", "destination"].into_iter().filter_map(|x| message.extract_as::<String>(x)) {
+            if let Some(path) = resolve_target(&raw, &self.curr_dir) {
+                self.state.watch.attribute(&path, &actor);
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, message: Message) -> Result<(), String> {
+        if let Some(response) = self.replayed_response(&message).await {
+            return self.send(&response).await;
+        }
+
+        if let Err(response) = self.check_jail(&message) {
+            return self.send(&response).await;
+        }
+        if let Some(capability) = message.message_type().capability().filter(|x| !self.capabilities.contains(*x)) {
+            return self.send(&ack(HttpCodes::BadRequest, &format!("the '{capability}' capability was not negotiated at connect"))).await;
+        }
+        if is_mutation(*message.message_type()) && self.identity == Some(SessionIdentity::Guest) {
+            return self.send(&ack(HttpCodes::Unauthorized, "guests cannot change anything, log in to do that")).await;
+        }
+        if is_mutation(*message.message_type()) && self.user().await.is_some_and(|u| u.is_read_only()) {
+            return self.send(&ack(HttpCodes::Forbidden, "read-only users cannot change anything")).await;
+        }
+
+        // Held until the request has been answered and its transfer is over
+        let state = Arc::clone(&self.state);
+        let _locks = match state.locks.lock(&self.lock_targets(&message), state.config.lock_wait()).await {
+            Some(l) => l,
+            None => return self.send(&locked_response(*message.message_type())).await
+        };
+
+        let written = self.written_targets(&message);
+        let result = match *message.message_type() {
+            MessageType::Download if matches!(self.identity, Some(SessionIdentity::Share(_))) => self.download(message).await,
+            _ if matches!(self.identity, Some(SessionIdentity::Share(_))) => self.send(&ack(HttpCodes::Forbidden, "share links may only download their file")).await,
+            MessageType::Upload => self.upload(message).await,
+            MessageType::CanI => self.can_i(message).await,
+            MessageType::Ping => self.send(&handle_ping(message)).await,
+            MessageType::Cancel => self.send(&ack(HttpCodes::Conflict, "there is no transfer to cancel")).await,
+            _ if !matches!(self.identity, Some(SessionIdentity::User(_) | SessionIdentity::Guest)) => self.send(&ack(HttpCodes::Forbidden, "upload grants may only upload")).await,
+            MessageType::Download => self.download(message).await,
+            MessageType::Versions => self.versions(message).await,
+            MessageType::Quota => self.quota().await,
+            MessageType::Signature => self.signature(message).await,
+            MessageType::Preview => self.preview(message).await,
+            MessageType::Thumbnail => self.thumbnail(message).await,
+            MessageType::UploadDir => self.upload_dir(message).await,
+            MessageType::DownloadDir => self.download_dir(message).await,
+            MessageType::Subscribe => self.subscribe(message).await,
+            MessageType::ChangesSince => self.changes_since(message).await,
+            MessageType::Dir => self.dir(message).await,
+            MessageType::Stats => self.stats(message).await,
+            MessageType::Lockouts => self.lockouts(message).await,
+            MessageType::Audit => self.audit(message).await,
+            MessageType::Dedupe => self.dedupe().await,
+            MessageType::Usage => self.usage().await,
+            MessageType::Stat => self.stat(message).await,
+            MessageType::Heartbeat => self.send(&handle_heartbeat(message)).await,
+            MessageType::Probe => self.probe(message).await,
+            MessageType::Diagnostics => self.diagnostics(message).await,
+            MessageType::Delete | MessageType::ChangeDir | MessageType::Move | MessageType::Subfolder | MessageType::Grant | MessageType::Hold | MessageType::Rename | MessageType::Copy | MessageType::Share | MessageType::ShareLink | MessageType::UserAdmin | MessageType::Restore | MessageType::PurgeTrash => self.modify(message).await,
+            MessageType::Ack | MessageType::Event | MessageType::Pong | MessageType::Queued | MessageType::Connect | MessageType::Close => self.send(&ack(HttpCodes::BadRequest, "unexpected message")).await
+        };
+
+        self.mirror(written).await;
+        result
+    }
+
+    // The paths a request reads or writes while it runs. Paths that do not resolve are left for the handlers to refuse.
+    fn lock_targets(&self, message: &Message) -> Vec<(PathBuf, LockKind)> {
+        let fields: &[(&str, LockKind)] = match *message.message_type() {
+            MessageType::Download | MessageType::DownloadDir | MessageType::Preview | MessageType::Thumbnail => &[("path", LockKind::Shared)],
+            MessageType::Upload => &[("name", LockKind::Exclusive)],
+            MessageType::UploadDir | MessageType::Delete | MessageType::Subfolder => &[("path", LockKind::Exclusive)],
+            MessageType::Move | MessageType::Rename => &[("path", LockKind::Exclusive), ("destination", LockKind::Exclusive)],
+            MessageType::Copy => &[("path", LockKind::Shared), ("destination", LockKind::Exclusive)],
+            _ => &[]
+        };
+
+        fields.iter()
+            .filter_map(|(field, kind)| message.extract_as::<String>(field).and_then(|x| resolve_target(&x, &self.curr_dir)).map(|x| (x, *kind)))
+            .collect()
+    }
+
+    // The paths a request may change in the data directory, which a file backend other than the local one is brought in step with once it is answered
+    fn written_targets(&self, message: &Message) -> Vec<PathBuf> {
+        if self.state.backend.is_local() {
+            return Vec::new();
+        }
+
+        let mut written: Vec<PathBuf> = self.lock_targets(message).into_iter().filter(|(_, k)| *k == LockKind::Exclusive).map(|(p, _)| p).collect();
+        if *message.message_type() == MessageType::Restore {
+            written.extend(message.extract_as::<String>("path").and_then(|x| resolve_target(&x, &self.curr_dir)));
+        }
+        written
+    }
+    // Runs while the request's locks are still held, so nothing can change the paths in between.
+    // The client has its answer by now, so a failure is only logged, and the next change to the path tries again.
+    async fn mirror(&self, paths: Vec<PathBuf>) {
+        if paths.is_empty() {
+            return;
+        }
+
+        let backend = Arc::clone(&self.state.backend);
+        let root = self.state.paths.root.clone();
+        let mirrored = tokio::task::spawn_blocking(move || {
+            paths.iter().map(|p| mirror(backend.as_ref(), &root, p).map_err(|e| format!("unable to update '{}' in {} because {e}", display_path(p), backend.describe()))).collect::<Vec<_>>()
+        }).await;
+
+        match mirrored {
+            Ok(results) => {
+                for result in results {
+                    match result {
+                        Ok((stored, removed)) => tracing::debug!(stored, removed, "brought the file backend in step"),
+                        Err(e) => tracing::warn!("{e}")
+                    }
+                }
+            },
+            Err(e) => tracing::warn!("the file backend could not be updated because '{e}'")
+        }
+    }
+
+    // Every request after Connect must carry this connection's session token, and the session must not have lapsed
+    async fn authenticate(&mut self, message: &Message) -> Result<(), String> {
+        let session = match self.session.as_ref() {
+            Some(s) => s.clone(),
+            None => return Err(String::from("connect before sending requests"))
+        };
+
+        match extract_session(message) {
+            Some(t) if t == session => (),
+            _ => return Err(String::from("missing or invalid session token"))
+        }
+
+        match self.state.sessions.write().await.validate(&session) {
+            Some(identity) => {
+                self.identity = Some(identity);
+                Ok(())
+            },
+            None => {
+                self.identity = None;
+                self.session = None;
+                Err(String::from("session has expired, connect again"))
+            }
+        }
+    }
+
+    // Idempotency keys are scoped to whoever sent them
+    fn idempotency_scope(&self) -> Option<String> {
+        match self.identity.as_ref()? {
+            SessionIdentity::User(u) => Some(u.clone()),
+            SessionIdentity::Grant(g) => Some(format!("grant:{}", g.token())),
+            SessionIdentity::Share(l) => Some(format!("share:{}", l.token())),
+            SessionIdentity::Guest => None
+        }
+    }
+    // A replay of an upload or delete that already succeeded is answered from the cache, without doing the work again
+    async fn replayed_response(&self, message: &Message) -> Option<Message> {
+        if !matches!(*message.message_type(), MessageType::Upload | MessageType::Delete) {
+            return None;
+        }
+
+        let key = extract_idempotency_key(message)?;
+        self.state.idempotency.read().await.lookup(&self.idempotency_scope()?, &key)
+    }
+    // Remembers what a replay is answered with, once the request's outcome is a success
+    async fn remember_response(&self, key: Option<String>, outcome: &Message, replay: Message) {
+        let (key, scope) = match (key, self.idempotency_scope()) {
+            (Some(k), Some(s)) => (k, s),
+            _ => return
+        };
+
+        if let Some((HttpCodes::Ok, _)) = extract_ack_message(outcome.clone()) {
+            self.state.idempotency.write().await.record(&scope, &key, replay);
+        }
+    }
+
+    async fn user(&self) -> Option<Credentials> {
+        match self.identity.as_ref() {
+            Some(SessionIdentity::User(u)) => self.state.users.read().await.get_user(u).cloned(),
+            Some(SessionIdentity::Guest) => Some(Credentials::guest()),
+            _ => None
+        }
+    }
+    // Who a transfer acts for. Uploads through a grant, and downloads through a share link, act for the user who issued it.
+    async fn acting_user(&self) -> Option<Credentials> {
+        let owner = match self.identity.as_ref() {
+            Some(SessionIdentity::Grant(g)) => self.state.grants.read().await.owner_of(g.token()).map(|x| x.to_string()),
+            Some(SessionIdentity::Share(l)) => self.state.shares.read().await.owner_of(l.token()).map(|x| x.to_string()),
+            _ => return self.user().await
+        };
+
+        match owner {
+            Some(o) => self.state.users.read().await.get_user(&o).cloned(),
+            None => None
+        }
+    }
+
+    // Sessions are jailed to their home directory, which is the whole root unless users have their own homes.
+    // Each path a request names is resolved the way its handler will resolve it, and must stay inside. The home itself cannot be removed or renamed.
+    // Any link along the way is then held to the symlink policy, so no path can be followed out of the home through one, and files the server keeps for itself are never found.
+    fn check_jail(&self, message: &Message) -> Result<(), Message> {
+        let removes_source = match *message.message_type() {
+            MessageType::Delete | MessageType::Move | MessageType::Rename => true,
+            MessageType::Subfolder => extract_subfolder_message(message.clone()).is_some_and(|x| x.1 == SubfolderAction::Delete),
+            _ => false
+        };
+
+        // Each path, and whether the request would remove or rename what it names
+        let mut targets: Vec<(String, bool)> = Vec::new();
+        targets.extend(message.extract_as::<String>("path").map(|x| (x, removes_source)));
+        targets.extend(["name", "destination"].into_iter().filter_map(|x| message.extract_as::<String>(x)).map(|x| (x, false)));
+        if let Some(IntendedOperation::Upload { path, .. } | IntendedOperation::Delete { path, .. }) = extract_can_i_request(message.clone()) {
+            targets.push((path, false));
+        }
+
+        for (raw, removed) in targets {
+            // Absolute paths are left for the handlers to refuse
+            let path = match move_relative(&raw, &self.curr_dir) {
+                Some(p) => p,
+                None => continue
+            };
+
+            match jail_depth(&path, &self.home) {
+                None => return Err(ack(HttpCodes::Forbidden, "path is outside of your home directory")),
+                Some(0) if removed => return Err(ack(HttpCodes::Forbidden, "your home directory cannot be removed or renamed")),
+                Some(_) => { }
+            }
+            if let Some(resolved) = resolve_target(&raw, &self.curr_dir) {
+                if is_internal_path(&resolved) {
+                    return Err(ack(HttpCodes::NotFound, "file not found"));
+                }
+                check_links(&resolved, &self.home, self.state.config.symlinks).map_err(|(code, reason)| ack(code, reason))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn connect(&mut self, message: Message) -> Result<(), String> {
+        if self.session.is_some() {
+            return self.send(&ack(HttpCodes::Conflict, "already connected")).await;
+        }
+
+        let peer = self.peer_ip();
+        let offered = extract_capabilities(&message).unwrap_or_else(Capabilities::legacy);
+        let offered_compressions = extract_compressions(&message);
+        let offered_codecs = extract_codecs(&message);
+        // Password logins are throttled, and refused while their address or username is waiting
+        let login = extract_connect_message(message.clone()).map(|x| x.0).filter(|_| ["token", "grant", "share", "guest"].iter().all(|x| message.extract(x).is_none()));
+        let wait = match login.as_deref() {
+            Some(u) => self.state.logins.read().await.check(&peer, u),
+            None => None
+        };
+        if let Some(wait) = wait {
+            return self.send(&connect_ack_message(HttpCodes::TooManyRequests, Some(format!("too many failed logins, try again in {wait} seconds")), None, None)).await;
+        }
+        let (response, identity) = {
+            let users = self.state.users.read().await;
+            let grants = self.state.grants.read().await;
+            let shares = self.state.shares.read().await;
+            let mut resume = self.state.resume.write().await;
+
+            let result = handle_connect(message, &users, &mut resume, &grants, &shares, self.state.config.guest_home().is_some(), &peer);
+            if result.1.is_some() {
+                let _ = resume.save();
+            }
+            result
+        };
+        if let Some(username) = login.as_deref() {
+            match (identity.is_some(), extract_ack_message(response.clone())) {
+                (true, _) => self.state.logins.write().await.record_success(&peer, username),
+                (false, Some((HttpCodes::Unauthorized, _))) => self.state.logins.write().await.record_failure(&peer, username),
+                _ => { }
+            }
+        }
+
+        // Grants and share links are already limited to one path, so only users and guests are given a home
+        let home = match identity.as_ref() {
+            Some(SessionIdentity::User(u)) => self.state.users.read().await.get_user(u).and_then(|x| self.state.config.home_directory(x)),
+            Some(SessionIdentity::Guest) => self.state.config.guest_home(),
+            _ => Some(root_directory())
+        };
+        match home {
+            Some(h) if std::fs::create_dir_all(&h).is_ok() => {
+                self.curr_dir = h.clone();
+                self.home = h;
+            },
+            _ if identity.is_some() => return self.send(&connect_ack_message(HttpCodes::Forbidden, Some(String::from("your home directory is not set up correctly")), None, None)).await,
+            _ => { }
+        }
+
+        let mut codec = Codec::Json;
+        let response = match identity.as_ref() {
+            Some(i) => {
+                let session = self.state.sessions.write().await.issue(i.clone());
+                self.session = Some(session.token().to_string());
+                self.capabilities = served_capabilities(self.state.quic.offer().is_some()).negotiate(&offered);
+                self.compressions = Compression::negotiate(&Compression::supported(), &offered_compressions);
+
+                let response = advertise_capabilities(response, &self.capabilities);
+                let response = advertise_compressions(response, &self.compressions);
+                codec = Codec::negotiate(&Codec::supported(), &offered_codecs);
+                let response = advertise_codecs(response, &[codec]);
+                let response = match self.capabilities.contains(Capability::FrameTuning) {
+                    true => advertise_frame_bounds(response, self.state.frame_bounds),
+                    false => response
+                };
+                let response = match (self.capabilities.contains(Capability::Keepalive), self.state.config.idle_timeout()) {
+                    (true, Some(idle)) => advertise_idle_timeout(response, idle.as_secs()),
+                    _ => response
+                };
+                let response = match self.state.quic.offer().filter(|_| self.capabilities.contains(Capability::Quic)) {
+                    Some(offer) => advertise_quic(response, &offer),
+                    None => response
+                };
+                session_ack(response, &session)
+            },
+            None => response
+        };
+
+        let username = match identity.as_ref() {
+            Some(SessionIdentity::User(u)) => Some(u.as_str()),
+            Some(SessionIdentity::Grant(_)) => Some("upload grant"),
+            Some(SessionIdentity::Share(_)) => Some("share link"),
+            Some(SessionIdentity::Guest) => Some(GUEST_USERNAME),
+            None => None
+        };
+        self.state.connections.identify(self.activity, username);
+        if let Some(u) = username {
+            self.span.record("username", u);
+        }
+
+        // The ack itself is JSON, since the client cannot know the codec before reading it
+        self.identity = identity;
+        self.send(&response).await?;
+        self.codec = codec;
+        Ok(())
+    }
+
+    async fn upload(&mut self, message: Message) -> Result<(), String> {
+        let compression = extract_compression(&message);
+        let latency = extract_latency(&message);
+        if let Some(c) = compression.filter(|x| !self.compressions.contains(x)) {
+            return self.send(&upload_message_response(HttpCodes::BadRequest, &format!("the '{c}' compression was not negotiated at connect"), 0)).await;
+        }
+        let delta = extract_delta(&message);
+        if delta && !self.capabilities.contains(Capability::Delta) {
+            return self.send(&upload_message_response(HttpCodes::BadRequest, "the 'delta' capability was not negotiated at connect", 0)).await;
+        }
+
+        let quic = !delta && self.uses_quic(&message);
+        let interactive = extract_interactive(&message);
+        let key = extract_idempotency_key(&message);
+
+        let user = self.acting_user().await;
+        let (response, plan) = {
+            let files = self.state.files.read().await;
+            handle_upload_request(message, user.as_ref(), &self.curr_dir, &files, &self.state.quotas, &self.state.config.uploads)
+        };
+
+        let plan = match plan {
+            Some(p) => p,
+            None => return self.send(&response).await
+        };
+        // A delta is applied to the file it replaces, so there must be one, and the result must be checked against what the client has
+        if delta && (plan.offset != 0 || plan.checksum.is_none() || !plan.path.is_file()) {
+            return self.send(&upload_message_response(HttpCodes::BadRequest, "a delta upload must replace an existing file from the start, and carry a checksum", 0)).await;
+        }
+
+        if let Err(e) = self.authorize_grant(&plan.path, 0).await {
+            return self.send(&upload_message_response(HttpCodes::Forbidden, &e, 0)).await;
+        }
+        let state = Arc::clone(&self.state);
+        let priority = state.config.transfers.priority(plan.frame_count.saturating_mul(BUFF_SIZE), interactive, false);
+        let _slot = match self.queue_transfer(&state.transfers, priority).await? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+
+        // Registered before any data can arrive, so a crash never leaves a staging file the registry does not know about
+        {
+            let mut staging = self.state.staging.write().await;
+            staging.begin(&plan.staging, &plan.path);
+            let _ = staging.save();
+        }
+        let response = match quic {
+            true => attach_quic(response),
+            false => response
+        };
+        self.send(&response).await?;
+
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        let mut progress = Progress::none();
+        let start = Instant::now();
+        let received = match (delta, quic) {
+            (true, _) => self.receive_delta(&plan, compression, &mut tuner, &mut progress).await,
+            (false, true) => self.receive_over_quic(&plan, compression, &mut tuner, &mut progress).await,
+            (false, false) => receive_network_file_checked_async(&plan.staging, &mut self.transport, plan.frame_count, ReceiveOptions { offset: plan.offset, expected: plan.checksum.as_ref(), compression, tuner: &mut tuner, progress: &mut progress }).await
+        };
+        if progress.is_cancelled() {
+            return self.cancel_upload(&plan).await;
+        }
+        let elapsed = start.elapsed().as_secs_f32();
+        let checksum = received.as_ref().ok().cloned();
+        let (mut result, kept) = match received {
+            Ok(c) => match self.screen_upload(&plan.path, &plan.staging, plan.encryption.is_some()).await {
+                Ok(()) => match self.move_into_place(&plan, user.as_ref()).await {
+                    Ok(kept) => (complete_upload(&plan, Ok(c)), kept),
+                    Err(response) => (response, None)
+                },
+                Err((status, reason)) => {
+                    let _ = std::fs::remove_file(&plan.staging);
+                    (ack(status, &reason), None)
+                }
+            },
+            Err(e) => (complete_upload(&plan, Err(e)), None)
+        };
+
+        // An interrupted upload keeps its staging file, and its record, until it is resumed or purged
+        if !plan.staging.exists() {
+            let mut staging = self.state.staging.write().await;
+            staging.finish(&plan.staging);
+            let _ = staging.save();
+        }
+
+        if let Some((HttpCodes::Ok, _)) = extract_ack_message(result.clone()) {
+            result = self.finish_upload(&plan, kept, checksum, elapsed, tuner.into_chosen_sizes(), latency).await.unwrap_or(result);
+        }
+
+        // A replay is told the upload is already done, in place of being asked for the file again
+        self.remember_response(key, &result, upload_message_response(HttpCodes::NoContent, "this upload was already received", 0)).await;
+        self.send(&result).await?;
+        if let Some((HttpCodes::Ok, _)) = extract_ack_message(result) {
+            self.notify_hooks(Hooks::after_upload, vec![plan.path]).await;
+        }
+        Ok(())
+    }
+
+    // Holds a transfer until the scheduler has a slot for it, telling a client that understands where it is in the queue each time that changes.
+    // The client may only send a Cancel meanwhile, which is answered in place of the transfer. Returns None once the transfer has been answered.
+    async fn queue_transfer<'a>(&mut self, transfers: &'a TransferScheduler, priority: TransferPriority) -> Result<Option<TransferSlot<'a>>, String> {
+        let max = self.state.config.transfers.max_active;
+        let mut queued = transfers.enqueue(priority);
+        let mut position = match queued.try_start(max) {
+            Ok(slot) => return Ok(Some(slot)),
+            Err(p) => p
+        };
+
+        tracing::debug!(position, "the transfer was queued");
+        loop {
+            if self.capabilities.contains(Capability::Queue) {
+                write_frame_with_async(&mut self.transport, &queued_message(position).with_request_id(self.request_id), self.codec).await?;
+            }
+
+            let mut first = [0u8; 1];
+            tokio::select! {
+                waited = queued.wait(max, position) => match waited {
+                    Ok(slot) => return Ok(Some(slot)),
+                    Err(p) => position = p
+                },
+                read = self.transport.read(&mut first) => {
+                    drop(queued);
+                    return match read {
+                        Ok(0) | Err(_) => Err(String::from("the connection was closed while the transfer was queued")),
+                        Ok(_) => match self.read_cancel(Some(first[0])).await? {
+                            Some(_) => self.send(&ack(HttpCodes::Ok, "the transfer was cancelled while it was queued")).await.map(|_| None),
+                            None => self.send(&ack(HttpCodes::BadRequest, "only a Cancel may be sent while a transfer is queued")).await.map(|_| None)
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    // The client sent the cancel block instead of the rest of the file, and a Cancel follows it.
+    // Unlike an interrupted upload, nothing is kept to resume from, and the response is not remembered, so retrying with the same key starts over.
+    async fn cancel_upload(&mut self, plan: &UploadPlan) -> Result<(), String> {
+        let _ = std::fs::remove_file(&plan.staging);
+        {
+            let mut staging = self.state.staging.write().await;
+            staging.finish(&plan.staging);
+            let _ = staging.save();
+        }
+
+        match self.read_cancel(None).await? {
+            Some(reason) => {
+                tracing::info!("the upload was cancelled{}", reason.map(|x| format!(" because '{x}'")).unwrap_or_default());
+                self.send(&ack(HttpCodes::Ok, "the upload was cancelled")).await
+            },
+            None => self.send(&ack(HttpCodes::BadRequest, "expected a Cancel after the cancel block")).await
+        }
+    }
+    // Reads the Cancel sent part way through a transfer, whose first byte may already have been read
+    async fn read_cancel(&mut self, first: Option<u8>) -> Result<Option<Option<String>>, String> {
+        let message = match first {
+            Some(b) => read_frame_async(&mut (&[b][..]).chain(&mut self.transport)).await?,
+            None => read_frame_async(&mut self.transport).await?
+        };
+
+        Ok(extract_cancel_message(message))
+    }
+
+    // Whether a transfer's frames go over QUIC. The client has to ask, and its QUIC connection has to be up, so anything else stays on TCP.
+    fn uses_quic(&self, message: &Message) -> bool {
+        extract_quic(message) && self.capabilities.contains(Capability::Quic) && self.session.as_deref().is_some_and(|x| self.state.quic.is_registered(x))
+    }
+    // The client opens a stream for the frames, and the response still goes over TCP. A cancel block is followed by a Cancel over TCP, as it is without QUIC.
+    async fn receive_over_quic(&mut self, plan: &UploadPlan, compression: Option<Compression>, tuner: &mut FrameSizeTuner, progress: &mut Progress<'_>) -> Result<Checksum, String> {
+        let session = self.session.clone().unwrap_or_default();
+        let mut stream = self.state.quic.accept(&session).await?;
+        receive_network_file_checked_async(&plan.staging, &mut stream, plan.frame_count, ReceiveOptions { offset: plan.offset, expected: plan.checksum.as_ref(), compression, tuner, progress }).await
+    }
+
+    // A delta is received whole before it is applied to the file it replaces. Only the result is staged, and it is thrown away unless it matches the client's checksum.
+    async fn receive_delta(&mut self, plan: &UploadPlan, compression: Option<Compression>, tuner: &mut FrameSizeTuner, progress: &mut Progress<'_>) -> Result<Checksum, String> {
+        let scratch = delta_directory().join(generate_token());
+        std::fs::create_dir_all(delta_directory()).map_err(|e| e.to_string())?;
+
+        let received = receive_network_file_checked_async(&scratch, &mut self.transport, plan.frame_count, ReceiveOptions { offset: 0, expected: None, compression, tuner, progress }).await;
+        let algorithm = plan.checksum.as_ref().map(|x| x.algorithm()).unwrap_or_default();
+        let result = received.and_then(|_| apply_delta_file(&scratch, &plan.path, &plan.staging, algorithm));
+        let _ = std::fs::remove_file(&scratch);
+
+        match result {
+            Ok(actual) if plan.checksum.as_ref().is_none_or(|x| *x == actual) => Ok(actual),
+            Ok(actual) => {
+                let _ = std::fs::remove_file(&plan.staging);
+                Err(format!("checksum mismatch, expected '{}' but the delta rebuilt '{actual}'", plan.checksum.as_ref().map(|x| x.to_string()).unwrap_or_default()))
+            },
+            Err(e) => {
+                let _ = std::fs::remove_file(&plan.staging);
+                Err(e)
+            }
+        }
+    }
+
+    // Events name the user a transfer acts for, so a grant's or share link's is its issuer's
+    async fn hook_event(&self, path: &Path) -> HookEvent {
+        let user = self.acting_user().await;
+        HookEvent::new(path, user.as_ref().map(|x| x.username()), &self.peer_ip())
+    }
+    // A received file is held to the upload policy, scanned, then passed to the hooks, while it is still staged. Scans and hooks happen off the async threads, since they may block.
+    // The file database is only locked to quarantine a file the scanner found infected.
+    async fn screen_upload(&self, path: &Path, contents: &Path, sealed: bool) -> Result<(), HookRefusal> {
+        self.state.config.uploads.check_received(path, contents, sealed)?;
+        if self.state.scanning.is_none() && self.state.hooks.is_empty() {
+            return Ok(());
+        }
+
+        let (state, event, contents) = (Arc::clone(&self.state), self.hook_event(path).await, contents.to_path_buf());
+        let owner = self.acting_user().await;
+        tokio::task::spawn_blocking(move || {
+            if let Some(scanning) = state.scanning.as_ref() {
+                if let Some(signature) = scanning.scan(&contents, &event.path)? {
+                    let mut files = state.files.blocking_write();
+                    let refusal = scanning.quarantine(&contents, &event.path, owner.as_ref(), &signature, &mut files);
+                    let _ = files.save();
+                    return Err(refusal);
+                }
+            }
+            state.hooks.before_upload(&event, &contents)
+        }).await.unwrap_or_else(|_| Err((HttpCodes::InternalServerError, String::from("a hook failed while checking the upload"))))
+    }
+    async fn screen_download(&self, path: &Path) -> Result<(), HookRefusal> {
+        if self.state.hooks.is_empty() {
+            return Ok(());
+        }
+
+        let (state, event) = (Arc::clone(&self.state), self.hook_event(path).await);
+        tokio::task::spawn_blocking(move || state.hooks.before_download(&event)).await.unwrap_or_else(|_| Err((HttpCodes::InternalServerError, String::from("a hook failed while checking the download"))))
+    }
+    // Runs once the client has its answer, so nothing a hook does can change it
+    async fn notify_hooks(&self, callback: fn(&Hooks, &HookEvent), paths: Vec<PathBuf>) {
+        if self.state.hooks.is_empty() || paths.is_empty() {
+            return;
+        }
+
+        let mut events = Vec::with_capacity(paths.len());
+        for path in paths.iter() {
+            events.push(self.hook_event(path).await);
+        }
+        let state = Arc::clone(&self.state);
+        let notified = tokio::task::spawn_blocking(move || {
+            for event in events.iter() {
+                callback(&state.hooks, event);
+            }
+        }).await;
+        if let Err(e) = notified {
+            tracing::warn!("a hook failed because '{e}'");
+        }
+    }
+
+    // A grant only covers its one path, and the finished file must fit inside its size limit
+    async fn authorize_grant(&self, path: &Path, size: u64) -> Result<(), String> {
+        let grant = match self.identity.as_ref() {
+            Some(SessionIdentity::Grant(g)) => g,
+            _ => return Ok(())
+        };
+
+        let relative = display_path(path);
+        let relative = relative.trim_start_matches('/');
+        self.state.grants.read().await.authorize_upload(grant.token(), relative, size, &self.peer_ip())
+    }
+    // A share link only covers its one file, and only for as long as it has not expired or been revoked
+    async fn authorize_share(&self, message: &Message) -> Result<(), String> {
+        let link = match self.identity.as_ref() {
+            Some(SessionIdentity::Share(l)) => l,
+            _ => return Ok(())
+        };
+
+        let path = match extract_download_request_message(message.clone()).and_then(|(p, _, _)| resolve_target(&p, &self.curr_dir)) {
+            Some(p) => p,
+            None => return Err(String::from("path is outside of the server's root directory"))
+        };
+        let relative = display_path(&path);
+        self.state.shares.read().await.authorize_download(link.token(), relative.trim_start_matches('/'))
+    }
+
+    // Moves a received upload over its destination. The file it replaces is kept as a version first, and put back if the upload cannot take its place.
+    // Now that its size is known, an upload that would take the uploader past their quota is thrown away instead.
+    // Returns the number of the version that was kept, or the response refusing the upload.
+    async fn move_into_place(&self, plan: &UploadPlan, uploader: Option<&Credentials>) -> Result<Option<u32>, Message> {
+        let mut files = self.state.files.write().await;
+        if let Some(u) = uploader {
+            let size = std::fs::metadata(&plan.staging).map(|x| x.len()).unwrap_or_default();
+            if let Err((status, reason)) = self.state.quotas.check(u, &files, size) {
+                let _ = std::fs::remove_file(&plan.staging);
+                return Err(ack(status, &reason));
+            }
+        }
+
+        let kept = match self.state.config.max_versions {
+            0 => None,
+            max => files.keep_version(&plan.path, &versions_directory(), uploader.map(|x| x.username()), max).map_err(|e| ack(HttpCodes::Conflict, &format!("unable to keep the previous version because '{e}'")))?
+        };
+
+        if let Err(e) = std::fs::rename(&plan.staging, &plan.path) {
+            if kept.is_some() {
+                let _ = files.revert_version(&plan.path);
+            }
+            return Err(ack(HttpCodes::Conflict, &e.to_string()));
+        }
+        if kept.is_some() {
+            let _ = files.save();
+        }
+        Ok(kept)
+    }
+
+    // Records the finished upload. Returns a replacement response if the upload had to be refused after the fact.
+    async fn finish_upload(&self, plan: &UploadPlan, kept: Option<u32>, checksum: Option<Checksum>, elapsed: f32, frame_sizes: Vec<u32>, latency: Option<Latency>) -> Option<Message> {
+        let size = std::fs::metadata(&plan.path).map(|x| x.len()).unwrap_or_default();
+
+        if let Some(SessionIdentity::Grant(g)) = self.identity.as_ref() {
+            if let Err(e) = self.authorize_grant(&plan.path, size).await {
+                let _ = std::fs::remove_file(&plan.path);
+                if kept.is_some() {
+                    let mut files = self.state.files.write().await;
+                    let _ = files.revert_version(&plan.path);
+                    let _ = files.save();
+                }
+                return Some(ack(HttpCodes::Forbidden, &e));
+            }
+
+            let mut grants = self.state.grants.write().await;
+            grants.complete(g.token(), &self.peer_ip());
+            let _ = grants.save();
+        }
+
+        let owner = self.acting_user().await;
+
+        {
+            let mut files = self.state.files.write().await;
+            let id = match files.get_file_id(&plan.path) {
+                Some(id) => Some(id),
+                None => files.register_file(plan.path.clone(), owner, detect_file_type(&plan.path).unwrap_or_else(|| plan.kind.clone())).ok()
+            };
+
+            if let Some(f) = id.and_then(|x| files.get_file_mut(x)) {
+                f.set_provenance(plan.provenance.clone());
+                f.set_encryption(plan.encryption);
+            }
+            if let Some((id, c)) = id.zip(checksum.clone()) {
+                files.set_checksum(id, c);
+            }
+            if self.state.config.dedupe {
+                match link_duplicate(&files, &self.state.paths.root, &plan.path) {
+                    Ok(Some(original)) => {
+                        // The file now has the metadata of the one it was linked to, which the checksum is kept against
+                        if let Some((id, c)) = id.zip(checksum) {
+                            files.set_checksum(id, c);
+                        }
+                        tracing::debug!("stored '{}' once with '{}'", display_path(&plan.path), display_path(&original));
+                    },
+                    Ok(None) => { },
+                    Err(e) => tracing::warn!("unable to deduplicate '{}' because {e}", display_path(&plan.path))
+                }
+            }
+            let _ = files.save();
+        }
+
+        self.state.metrics.record_transfer(Direction::Received, size, Duration::from_secs_f32(elapsed));
+        if elapsed > 0.0 {
+            let _ = self.state.stats.record_tuned_transfer(size, elapsed, &self.peer_ip(), frame_sizes, latency);
+        }
+
+        None
+    }
+
+    // In proxy mode the file is brought up to date from the upstream server, and then served like any other
+    async fn fetch_from_upstream(&self, message: &Message) -> Result<(), String> {
+        let proxy = match self.state.proxy.as_ref() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+
+        // Malformed requests and paths outside of the root are left for the handler to refuse
+        match extract_download_request_message(message.clone()).and_then(|(p, _, _)| resolve_target(&p, &self.curr_dir)).and_then(|p| upstream_path(&p)) {
+            Some(p) => proxy.ensure_file(&p, &self.state.socket_options, self.state.frame_bounds).await,
+            None => Ok(())
+        }
+    }
+    // Directories the proxy has not listed yet do not exist locally, so they are fetched before moving into them
+    async fn fetch_directory_from_upstream(&self, message: &Message) -> Result<(), String> {
+        let proxy = match self.state.proxy.as_ref() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+
+        let target = match extract_change_dir_message(message.clone()).and_then(|p| resolve_directory(&p, &self.curr_dir)) {
+            Some(t) if !t.exists() => t,
+            _ => return Ok(())
+        };
+
+        match upstream_path(&target) {
+            Some(p) => proxy.listing(&p, &self.state.socket_options, self.state.frame_bounds).await.map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    async fn download(&mut self, message: Message) -> Result<(), String> {
+        if let Err(e) = self.fetch_from_upstream(&message).await {
+            return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::NotFound, &format!("unable to fetch the file from the upstream server because '{e}'")))).await;
+        }
+
+        if let Err(e) = self.authorize_share(&message).await {
+            return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::Forbidden, &e))).await;
+        }
+        let user = match self.acting_user().await {
+            Some(u) => u,
+            None => return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::Unauthorized, "user no longer exists"))).await
+        };
+        // A download on a channel is sent between other requests, a chunk at a time, so it is never compressed or sent as a delta
+        let channel = extract_channel(&message);
+        if channel && !self.capabilities.contains(Capability::Multiplex) {
+            return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::BadRequest, "the 'multiplex' capability was not negotiated at connect"))).await;
+        }
+        if channel && self.request_id == 0 {
+            return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::BadRequest, "a download on a channel needs a request ID"))).await;
+        }
+        if channel && !self.state.config.limits.allows_transfer(self.channels.len()) {
+            let max = self.state.config.limits.max_transfers;
+            return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::TooManyRequests, &format!("at most {max} downloads can be open at once on a connection")))).await;
+        }
+        let compression = self.requested_compression(&message).filter(|_| !channel);
+        let latency = extract_latency(&message);
+        let quic = !channel && self.uses_quic(&message);
+        let interactive = extract_interactive(&message);
+        // Only whole files are sent as deltas, so a ranged download ignores the signature
+        let signature = match extract_signature(&message) {
+            Some(_) if !self.capabilities.contains(Capability::Delta) => return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::BadRequest, "the 'delta' capability was not negotiated at connect"))).await,
+            s => s.filter(|_| !channel && matches!(extract_download_request_message(message.clone()), Some((_, 0, None))))
+        };
+        let original = message.clone();
+        let (response, chunks) = {
+            let files = self.state.files.read().await;
+            handle_download_request(message, &user, &self.curr_dir, &files)
+        };
+
+        let chunks = match chunks {
+            Some(c) => c,
+            None => return self.send(&response).await
+        };
+        if let Some(path) = extract_download_request_message(original).and_then(|(p, _, _)| resolve_target(&p, &self.curr_dir)) {
+            if let Err((status, reason)) = self.screen_download(&path).await {
+                return self.send(&download_message_response(DownloadResponse::failure(status, &reason))).await;
+            }
+        }
+        if let Some(SessionIdentity::Share(l)) = self.identity.as_ref() {
+            let mut shares = self.state.shares.write().await;
+            shares.record_download(l.token());
+            let _ = shares.save();
+        }
+        if channel {
+            self.send(&attach_channel(response)).await?;
+            self.channels.open(self.request_id, chunks, latency);
+            return Ok(());
+        }
+        let state = Arc::clone(&self.state);
+        let priority = state.config.transfers.priority(chunks.remaining(), interactive, false);
+        let _slot = match self.queue_transfer(&state.transfers, priority).await? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+        let scratch = delta_directory().join(generate_token());
+        let (response, chunks) = match signature {
+            Some(s) => match delta_download(response, chunks, &s, &scratch) {
+                Ok(v) => v,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&scratch);
+                    return self.send(&download_message_response(DownloadResponse::failure(HttpCodes::Conflict, &format!("unable to make a delta because '{e}'")))).await;
+                }
+            },
+            None => (response, chunks)
+        };
+        if quic {
+            return self.download_over_quic(with_compression(response, compression), chunks, compression, latency, &scratch).await;
+        }
+
+        // Only a compressed stream can be ended early, so an uncompressed one is sent in full and the Cancel answered afterwards
+        let token = CancelToken::new();
+        let mut progress = match compression {
+            Some(_) => Progress::none().with_cancel(token.clone()),
+            None => Progress::none()
+        };
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        let start = Instant::now();
+        let (sent, first) = match self.send(&with_compression(response, compression)).await {
+            Ok(()) => {
+                let mut watch = CancelWatch::new(&mut self.transport, token);
+                let sent = send_network_file_async(&mut watch, chunks, compression, &mut tuner, &mut progress).await;
+                (sent, watch.into_first())
+            },
+            Err(e) => (Err(e), None)
+        };
+        let _ = std::fs::remove_file(&scratch);
+        if let Some(first) = first {
+            return match (self.read_cancel(Some(first)).await?, progress.is_cancelled()) {
+                (Some(reason), true) => {
+                    tracing::info!("the download was cancelled{}", reason.map(|x| format!(" because '{x}'")).unwrap_or_default());
+                    self.send(&ack(HttpCodes::Ok, "the download was cancelled")).await
+                },
+                (Some(_), false) => self.send(&ack(HttpCodes::Conflict, "the download was sent in full before it could be cancelled")).await,
+                (None, _) => self.send(&ack(HttpCodes::BadRequest, "only a Cancel may be sent during a download")).await
+            };
+        }
+        let size = match sent {
+            Ok(s) => s,
+            Err(e) => return Err(format!("the download was interrupted because '{e}'"))
+        };
+
+        self.state.metrics.record_transfer(Direction::Sent, size, start.elapsed());
+        let elapsed = start.elapsed().as_secs_f32();
+        if elapsed > 0.0 {
+            let _ = self.state.stats.record_tuned_transfer(size, elapsed, &self.peer_ip(), tuner.into_chosen_sizes(), latency);
+        }
+
+        Ok(())
+    }
+
+    // The frames go on a stream the server opens, and an ack over TCP follows them to say how it ended.
+    // The client cancels by stopping the stream, so a QUIC download can be ended early whether it is compressed or not.
+    async fn download_over_quic(&mut self, response: Message, chunks: FileChunkIter, compression: Option<Compression>, latency: Option<Latency>, scratch: &Path) -> Result<(), String> {
+        self.send(&attach_quic(response)).await?;
+
+        let session = self.session.clone().unwrap_or_default();
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        let start = Instant::now();
+        let sent = match self.state.quic.open(&session).await {
+            Ok(mut stream) => match send_network_file_async(&mut stream, chunks, compression, &mut tuner, &mut Progress::none()).await {
+                Ok(size) => stream.shutdown().await.map(|_| size).map_err(|e| e.to_string()),
+                Err(e) => Err(e)
+            },
+            Err(e) => Err(e)
+        };
+        let _ = std::fs::remove_file(scratch);
+
+        let size = match sent {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::info!("the download over QUIC was stopped because '{e}'");
+                return self.send(&ack(HttpCodes::Conflict, "the download was stopped before it was sent in full")).await;
+            }
+        };
+        self.state.metrics.record_transfer(Direction::Sent, size, start.elapsed());
+        let elapsed = start.elapsed().as_secs_f32();
+        if elapsed > 0.0 {
+            let _ = self.state.stats.record_tuned_transfer(size, elapsed, &self.peer_ip(), tuner.into_chosen_sizes(), latency);
+        }
+        self.send(&ack(HttpCodes::Ok, "the download was sent")).await
+    }
+
+    // A download asking for a compression that was not agreed at Connect is sent as it is
+    fn requested_compression(&self, message: &Message) -> Option<Compression> {
+        extract_compression(message).filter(|x| self.compressions.contains(x))
+    }
+
+    async fn subscribe(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+        if !self.state.watch.is_running() {
+            return self.send(&ack(HttpCodes::Conflict, "this server is not watching for changes")).await;
+        }
+
+        let (response, target) = handle_subscribe_request(message, &user, &self.curr_dir, &*self.state.files.read().await);
+        match target {
+            Some((path, true)) if !self.subscriptions.contains(&path) => return self.send(&ack(HttpCodes::NotFound, "not watching that directory")).await,
+            Some((path, true)) => {
+                self.subscriptions.retain(|x| *x != path);
+                if self.subscriptions.is_empty() {
+                    self.events = None;
+                }
+            },
+            Some((_, false)) if self.subscriptions.len() >= MAX_SUBSCRIPTIONS => return self.send(&ack(HttpCodes::Conflict, &format!("a connection can watch at most {MAX_SUBSCRIPTIONS} directories"))).await,
+            Some((path, false)) => {
+                if !self.subscriptions.contains(&path) {
+                    self.subscriptions.push(path);
+                }
+                if self.events.is_none() {
+                    self.events = Some(self.state.watch.subscribe());
+                }
+            },
+            None => { }
+        }
+
+        self.send(&response).await
+    }
+
+    // Answered from the watcher's journal. Changes the user cannot read are left out, except the deletion of a folder above the path.
+    async fn changes_since(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+        if !self.state.watch.is_running() {
+            return self.send(&changes_since_response(HttpCodes::Conflict, "this server is not watching for changes", None)).await;
+        }
+
+        let (raw_path, since) = match extract_changes_since_request(message) {
+            Some(v) => v,
+            None => return self.send(&changes_since_response(HttpCodes::BadRequest, "malformed changes request", None)).await
+        };
+        let path = match resolve_target(&raw_path, &self.curr_dir) {
+            Some(p) => p,
+            None => return self.send(&changes_since_response(HttpCodes::Forbidden, "path is outside of the server's root directory", None)).await
+        };
+
+        let response = {
+            let files = self.state.files.read().await;
+            match files.check_access(&path, Some(&user), Permission::Read) {
+                Ok(_) => {
+                    let (changes, complete) = self.state.watch.changes_since(&path, since);
+                    let changes = changes.into_iter()
+                        .filter(|(_, e)| path.starts_with(&e.path) || files.check_access(&e.path, Some(&user), Permission::Read).is_ok())
+                        .map(|(at, e)| FileChange { event: FileEvent { kind: e.kind, path: display_path(&e.path), actor: e.actor }, at })
+                        .collect::<Vec<_>>();
+
+                    changes_since_response(HttpCodes::Ok, &format!("{} changes to '{raw_path}'", changes.len()), Some(&ChangeReport { changes, complete }))
+                },
+                Err(e) => changes_since_response(HttpCodes::Forbidden, &e.to_string(), None)
+            }
+        };
+        self.send(&response).await
+    }
+
+    async fn upload_dir(&mut self, message: Message) -> Result<(), String> {
+        let compression = extract_compression(&message);
+        let latency = extract_latency(&message);
+        if let Some(c) = compression.filter(|x| !self.compressions.contains(x)) {
+            return self.send(&upload_dir_response(HttpCodes::BadRequest, &format!("the '{c}' compression was not negotiated at connect"))).await;
+        }
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let (response, plan) = {
+            let files = self.state.files.read().await;
+            handle_upload_dir_request(message, &user, &self.curr_dir, &files, &self.state.quotas)
+        };
+        let plan = match plan {
+            Some(p) => p,
+            None => return self.send(&response).await
+        };
+        let state = Arc::clone(&self.state);
+        let _slot = match self.queue_transfer(&state.transfers, TransferPriority::Bulk).await? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+        self.send(&response).await?;
+
+        // The archive is received whole before any of it is unpacked, so an interrupted upload leaves nothing behind
+        let archive = archive_directory().join(generate_token());
+        std::fs::create_dir_all(archive_directory()).map_err(|e| e.to_string())?;
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        let start = Instant::now();
+        let received = receive_network_file_checked_async(&archive, &mut self.transport, plan.frame_count, ReceiveOptions { offset: 0, expected: plan.checksum.as_ref(), compression, tuner: &mut tuner, progress: &mut Progress::none() }).await;
+        let elapsed = start.elapsed().as_secs_f32();
+
+        // Unpacking runs off the async threads, since the hooks are asked about every file in the archive
+        let (result, unpacked) = match received {
+            Ok(_) => {
+                let (state, path, event) = (Arc::clone(&self.state), archive.clone(), self.hook_event(&plan.path).await);
+                let unpacked = tokio::task::spawn_blocking(move || {
+                    let screen = |destination: &Path, contents: &Path, files: &mut FileDatabase| {
+                        state.config.uploads.check_received(destination, contents, false)?;
+                        if let Some(scanning) = state.scanning.as_ref() {
+                            scanning.screen(contents, destination, Some(&user), files)?;
+                        }
+                        state.hooks.before_upload(&HookEvent { path: destination.to_path_buf(), ..event.clone() }, contents)
+                    };
+                    let mut files = state.files.blocking_write();
+                    let result = unpack_dir_upload(&plan, &path, &user, &mut files, &state.quotas, &screen);
+                    let _ = files.save();
+                    result
+                }).await;
+                unpacked.unwrap_or_else(|e| (ack(HttpCodes::InternalServerError, &format!("unable to unpack the archive because '{e}'")), Vec::new()))
+            },
+            Err(e) => (ack(HttpCodes::Conflict, &format!("the archive was not received because '{e}'")), Vec::new())
+        };
+        let size = std::fs::metadata(&archive).map(|x| x.len()).unwrap_or_default();
+        let _ = std::fs::remove_file(&archive);
+
+        if matches!(extract_ack_message(result.clone()), Some((HttpCodes::Ok, _))) {
+            self.state.metrics.record_transfer(Direction::Received, size, Duration::from_secs_f32(elapsed));
+            if elapsed > 0.0 {
+                let _ = self.state.stats.record_tuned_transfer(size, elapsed, &self.peer_ip(), tuner.into_chosen_sizes(), latency);
+            }
+        }
+        self.send(&result).await?;
+        self.notify_hooks(Hooks::after_upload, unpacked).await;
+        Ok(())
+    }
+
+    async fn download_dir(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let compression = self.requested_compression(&message);
+        let archive = archive_directory().join(generate_token());
+        let target = extract_download_dir_message(message.clone()).and_then(|p| resolve_target(&p, &self.curr_dir));
+        let (response, chunks) = {
+            let files = self.state.files.read().await;
+            handle_download_dir_request(message, &user, &self.curr_dir, &files, &archive)
+        };
+        let chunks = match chunks {
+            Some(c) => c,
+            None => {
+                let _ = std::fs::remove_file(&archive);
+                return self.send(&response).await;
+            }
+        };
+        if let Some(Err((status, reason))) = match target.as_deref() {
+            Some(p) => Some(self.screen_download(p).await),
+            None => None
+        } {
+            let _ = std::fs::remove_file(&archive);
+            return self.send(&download_dir_response(status, &reason, 0, None)).await;
+        }
+        let state = Arc::clone(&self.state);
+        let slot = self.queue_transfer(&state.transfers, TransferPriority::Bulk).await;
+        if !matches!(slot, Ok(Some(_))) {
+            let _ = std::fs::remove_file(&archive);
+            return slot.map(|_| ());
+        }
+
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        let start = Instant::now();
+        let sent = match self.send(&with_compression(response, compression)).await {
+            Ok(()) => send_network_file_async(&mut self.transport, chunks, compression, &mut tuner, &mut Progress::none()).await,
+            Err(e) => Err(e)
+        };
+        if sent.is_ok() {
+            let size = std::fs::metadata(&archive).map(|x| x.len()).unwrap_or_default();
+            self.state.metrics.record_transfer(Direction::Sent, size, start.elapsed());
+        }
+        let _ = std::fs::remove_file(&archive);
+        sent.map(|_| ()).map_err(|e| format!("the directory download was interrupted because '{e}'"))
+    }
+
+    async fn signature(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let response = handle_signature_request(message, &user, &self.curr_dir, &*self.state.files.read().await);
+        self.send(&response).await
+    }
+
+    async fn preview(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let response = handle_preview_request(message, &user, &self.curr_dir, &*self.state.files.read().await);
+        self.send(&response).await
+    }
+
+    async fn thumbnail(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let cache = ThumbnailCache::new(&thumbnail_directory());
+        let response = handle_thumbnail_request(message, &user, &self.curr_dir, &*self.state.files.read().await, &cache);
+        self.send(&response).await
+    }
+
+    async fn quota(&mut self) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+
+        let usage = self.state.quotas.usage(&user, &*self.state.files.read().await);
+        self.send(&quota_response(&usage)).await
+    }
+
+    // Listing versions is a single response. Fetching one is answered like a download, with its frames after.
+    async fn versions(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+        let compression = self.requested_compression(&message);
+        let (response, chunks) = {
+            let files = self.state.files.read().await;
+            handle_versions_request(message, &user, &self.curr_dir, &files)
+        };
+
+        let chunks = match chunks {
+            Some(c) => c,
+            None => return self.send(&response).await
+        };
+        self.send(&with_compression(response, compression)).await?;
+
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        send_network_file_async(&mut self.transport, chunks, compression, &mut tuner, &mut Progress::none()).await.map_err(|e| format!("sending a version was interrupted because '{e}'"))?;
+        Ok(())
+    }
+
+    async fn dir(&mut self, message: Message) -> Result<(), String> {
+        let (response, frames) = match self.state.proxy.as_ref() {
+            Some(proxy) => {
+                let (query, dir) = match dir_request_target(message, &self.curr_dir) {
+                    Ok(v) => v,
+                    Err(response) => return self.send(&response).await
+                };
+
+                let listing = match upstream_path(&dir) {
+                    Some(p) => proxy.listing(&p, &self.state.socket_options, self.state.frame_bounds).await.map(|x| page_listing(x, &query)),
+                    None => Err(String::from("path is outside of the server's root directory"))
+                };
+                dir_response(&display_path(&dir), listing, self.codec)
+            },
+            None => {
+                let admin = self.user().await.is_some_and(|u| u.is_admin());
+                let files = self.state.files.read().await;
+                handle_dir_request(message, &self.curr_dir, &files, &self.state.config, admin, self.codec)
+            }
+        };
+
+        self.send(&response).await?;
+        if let Some(f) = frames {
+            let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+            if !send_network_frames_async(&mut self.transport, &f, &mut tuner).await {
+                return Err(String::from("the listing was interrupted"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Without asking for connections, this is the last transfer from the client's own address
+    async fn stats(&mut self, message: Message) -> Result<(), String> {
+        if extract_connections_request(&message) {
+            let response = match self.user().await {
+                Some(u) if u.is_admin() => connections_response_message(&self.state.connections.snapshot()),
+                _ => ack(HttpCodes::Forbidden, "only administrators can list connections")
+            };
+            return self.send(&response).await;
+        }
+
+        let response = match self.state.stats.get_last_stat_by_ip(&self.peer_ip()) {
+            Some(s) => stats_response_message(s),
+            None => ack(HttpCodes::NotFound, "no transfers have been recorded for this address")
+        };
+
+        self.send(&response).await
+    }
+
+    // Lists the addresses and usernames that are waiting to log in again, or lifts one of their lockouts
+    async fn lockouts(&mut self, message: Message) -> Result<(), String> {
+        let admin = match self.user().await {
+            Some(u) if u.is_admin() => u,
+            _ => return self.send(&lockouts_response(HttpCodes::Forbidden, "only administrators can see lockouts", &[])).await
+        };
+
+        let response = match extract_lockouts_request(message) {
+            Some(Some(subject)) => match self.state.logins.write().await.unlock(&subject) {
+                true => {
+                    tracing::info!(admin = admin.username(), %subject, "lifted a login lockout");
+                    lockouts_response(HttpCodes::Ok, &format!("unlocked {subject}"), &[])
+                },
+                false => lockouts_response(HttpCodes::NotFound, &format!("{subject} has no failed logins"), &[])
+            },
+            Some(None) => lockouts_response(HttpCodes::Ok, "lockouts", &self.state.logins.read().await.lockouts()),
+            None => lockouts_response(HttpCodes::BadRequest, "malformed lockouts request", &[])
+        };
+        self.send(&response).await
+    }
+
+    // The newest entries in the audit log, narrowed down to a user or a path
+    async fn audit(&mut self, message: Message) -> Result<(), String> {
+        match self.user().await {
+            Some(u) if u.is_admin() => (),
+            _ => return self.send(&audit_response(HttpCodes::Forbidden, "only administrators can read the audit log", &[])).await
+        }
+        let filter = match extract_audit_request(message) {
+            Some(f) => f,
+            None => return self.send(&audit_response(HttpCodes::BadRequest, "malformed audit request", &[])).await
+        };
+
+        let response = match tokio::task::spawn_blocking(move || read_audit_log(&audit_log_path(), &filter)).await {
+            Ok(Ok(entries)) => audit_response(HttpCodes::Ok, "audit", &entries),
+            Ok(Err(e)) => {
+                tracing::warn!("unable to read the audit log because '{e}'");
+                audit_response(HttpCodes::InternalServerError, "unable to read the audit log", &[])
+            },
+            Err(_) => audit_response(HttpCodes::InternalServerError, "unable to read the audit log", &[])
+        };
+        self.send(&response).await
+    }
+
+    // Summarizes how much space identical files take, and how much linking them saves
+    async fn dedupe(&mut self) -> Result<(), String> {
+        match self.user().await {
+            Some(u) if u.is_admin() => (),
+            _ => return self.send(&dedupe_response(HttpCodes::Forbidden, "only administrators can see the dedupe report", None)).await
+        }
+
+        let report = dedupe_report(&*self.state.files.read().await, &self.state.paths.root);
+        self.send(&dedupe_response(HttpCodes::Ok, "dedupe report", Some(&report))).await
+    }
+
+    // How full the storage is, as seen from the session's home. The walk of its folders may block for a while, so it is run off the async threads.
+    async fn usage(&mut self) -> Result<(), String> {
+        if !matches!(self.identity, Some(SessionIdentity::User(_) | SessionIdentity::Guest)) {
+            return self.send(&usage_response(HttpCodes::Forbidden, "upload grants and share links cannot see storage usage", None)).await;
+        }
+
+        let (state, asker, home) = (Arc::clone(&self.state), self.user().await, self.home.clone());
+        let report = tokio::task::spawn_blocking(move || state.usage.report(asker.as_ref(), &home, &state.files.blocking_read())).await;
+        let response = match report {
+            Ok(r) => usage_response(HttpCodes::Ok, "storage usage", Some(&r)),
+            Err(_) => usage_response(HttpCodes::InternalServerError, "unable to measure storage usage", None)
+        };
+        self.send(&response).await
+    }
+
+    // Runs the same checks as the real request without doing anything
+    async fn can_i(&mut self, message: Message) -> Result<(), String> {
+        if self.state.proxy.is_some() {
+            return self.send(&can_i_response(HttpCodes::Forbidden, "this server is a read-only proxy")).await;
+        }
+
+        let is_upload = matches!(extract_can_i_request(message.clone()), Some(IntendedOperation::Upload { .. }));
+        if !is_upload && !matches!(self.identity, Some(SessionIdentity::User(_) | SessionIdentity::Guest)) {
+            return self.send(&can_i_response(HttpCodes::Forbidden, "upload grants may only upload")).await;
+        }
+
+        let user = self.user().await;
+        if user.as_ref().is_some_and(|u| u.is_read_only()) {
+            return self.send(&can_i_response(HttpCodes::Forbidden, "read-only users cannot change anything")).await;
+        }
+        let (response, upload) = {
+            let files = self.state.files.read().await;
+            handle_can_i_request(message, user.as_ref(), &self.curr_dir, &files, &self.state.quotas, &self.state.config.uploads)
+        };
+
+        let response = match upload {
+            Some((path, size)) => match self.authorize_grant(&path, size).await {
+                Ok(()) => response,
+                Err(e) => can_i_response(HttpCodes::Forbidden, &e)
+            },
+            None => response
+        };
+
+        self.send(&response).await
+    }
+
+    async fn stat(&mut self, message: Message) -> Result<(), String> {
+        let response = {
+            let files = self.state.files.read().await;
+            handle_stat_request(message, &self.curr_dir, &files)
+        };
+
+        self.send(&response).await
+    }
+
+    // Moves throwaway frames so a client can measure throughput. Nothing touches the disk.
+    async fn probe(&mut self, message: Message) -> Result<(), String> {
+        let (direction, frame_count) = match extract_probe_message(message) {
+            Some((_, 0)) => return self.send(&ack(HttpCodes::BadRequest, &format!("probes move between 1 and {MAX_PROBE_FRAMES} frames"))).await,
+            Some((_, f)) if f > MAX_PROBE_FRAMES => return self.send(&ack(HttpCodes::PayloadTooLarge, &format!("probes move between 1 and {MAX_PROBE_FRAMES} frames"))).await,
+            Some(p) => p,
+            None => return self.send(&ack(HttpCodes::BadRequest, "malformed probe")).await
+        };
+
+        self.send(&ack(HttpCodes::Ok, "ready")).await?;
+        let mut tuner = FrameSizeTuner::new(self.state.frame_bounds);
+        match direction {
+            ProbeDirection::Upload => {
+                let response = match receive_network_binary_async(&mut self.transport, frame_count, &mut tuner).await {
+                    Some(_) => ack(HttpCodes::Ok, "received"),
+                    None => ack(HttpCodes::BadRequest, "the probe was interrupted")
+                };
+                self.send(&response).await
+            },
+            ProbeDirection::Download => {
+                let frames = ChunkWriter::frames(&vec![0u8; (frame_count * BUFF_SIZE) as usize]);
+                match send_network_frames_async(&mut self.transport, &frames, &mut tuner).await {
+                    true => Ok(()),
+                    false => Err(String::from("the probe was interrupted"))
+                }
+            }
+        }
+    }
+
+    async fn diagnostics(&mut self, message: Message) -> Result<(), String> {
+        let username = self.user().await.map(|u| u.username().to_string()).unwrap_or_default();
+        let response = record_diagnostics(message, &username, &self.peer.to_string(), &diagnostics_log_path());
+        self.send(&response).await
+    }
+
+    // Requests that only touch the stores and the file system, and never stream frames
+    async fn modify(&mut self, message: Message) -> Result<(), String> {
+        let user = match self.user().await {
+            Some(u) => u,
+            None => return self.send(&ack(HttpCodes::Unauthorized, "user no longer exists")).await
+        };
+        let deleted = match *message.message_type() {
+            MessageType::Delete => extract_delete_message(message.clone()).and_then(|(p, _)| resolve_target(&p, &self.curr_dir)),
+            MessageType::Subfolder => extract_subfolder_message(message.clone()).filter(|x| x.1 == SubfolderAction::Delete).and_then(|(p, _, _)| resolve_target(&p, &self.curr_dir)),
+            _ => None
+        };
+
+        let response = match *message.message_type() {
+            MessageType::Delete => {
+                let key = extract_idempotency_key(&message);
+                let response = {
+                    let mut files = self.state.files.write().await;
+                    let mut trash = self.state.trash.write().await;
+                    let response = handle_delete_request(message, &user, &self.curr_dir, &mut files, &mut trash);
+                    let _ = files.save();
+                    let _ = trash.save();
+                    response
+                };
+
+                self.remember_response(key, &response, response.clone()).await;
+                response
+            },
+            MessageType::ChangeDir => {
+                if let Err(e) = self.fetch_directory_from_upstream(&message).await {
+                    return self.send(&ack(HttpCodes::NotFound, &format!("unable to list the directory on the upstream server because '{e}'"))).await;
+                }
+
+                let (response, dir) = handle_change_dir_request(message, &self.curr_dir);
+                if let Some(d) = dir {
+                    self.curr_dir = d;
+                }
+                response
+            },
+            MessageType::Subfolder => {
+                let mut files = self.state.files.write().await;
+                let mut trash = self.state.trash.write().await;
+                let response = handle_subfolder_request(message, &user, &self.curr_dir, &mut files, &mut trash);
+                let _ = files.save();
+                let _ = trash.save();
+                response
+            },
+            MessageType::Restore => {
+                let mut files = self.state.files.write().await;
+                let mut trash = self.state.trash.write().await;
+                let response = handle_restore_request(message, &user, &self.curr_dir, &mut files, &mut trash);
+                let _ = files.save();
+                let _ = trash.save();
+                response
+            },
+            MessageType::PurgeTrash => {
+                let mut trash = self.state.trash.write().await;
+                let response = handle_purge_trash_request(message, &user, &self.curr_dir, &mut trash);
+                let _ = trash.save();
+                response
+            },
+            MessageType::Grant => {
+                let mut grants = self.state.grants.write().await;
+                let response = handle_grant_request(message, &user, &self.curr_dir, &mut grants);
+                let _ = grants.save();
+                response
+            },
+            MessageType::ShareLink => {
+                let files = self.state.files.read().await;
+                let mut shares = self.state.shares.write().await;
+                let response = handle_share_link_request(message, &user, &self.curr_dir, &files, &mut shares);
+                let _ = shares.save();
+                response
+            },
+            MessageType::Hold => {
+                let mut files = self.state.files.write().await;
+                let response = handle_hold_request(message, &user, &self.curr_dir, &mut files);
+                let _ = files.save();
+                response
+            },
+            MessageType::Move => {
+                let mut files = self.state.files.write().await;
+                let response = handle_move_request(message, &user, &self.curr_dir, &mut files);
+                let _ = files.save();
+                response
+            },
+            MessageType::Rename => {
+                let mut files = self.state.files.write().await;
+                let response = handle_rename_request(message, &user, &self.curr_dir, &mut files);
+                let _ = files.save();
+                response
+            },
+            MessageType::Copy => {
+                let mut files = self.state.files.write().await;
+                let response = handle_copy_request(message, &user, &self.curr_dir, &mut files);
+                let _ = files.save();
+                response
+            },
+            MessageType::Share => {
+                let mut files = self.state.files.write().await;
+                let response = handle_share_request(message, &user, &self.curr_dir, &mut files);
+                let _ = files.save();
+                response
+            },
+            MessageType::UserAdmin => {
+                let (response, affected) = {
+                    let mut users = self.state.users.write().await;
+                    let result = handle_user_admin_request(message, &user, &mut users);
+                    let _ = users.save();
+                    result
+                };
+
+                if let Some(username) = affected {
+                    self.state.sessions.write().await.invalidate_user(&username);
+                    let mut resume = self.state.resume.write().await;
+                    resume.revoke_user(&username);
+                    let _ = resume.save();
+                }
+                response
+            },
+            _ => ack(HttpCodes::BadRequest, "unsupported request")
+        };
+
+        self.send(&response).await?;
+        if let (Some(path), Some((HttpCodes::Ok, _))) = (deleted, extract_ack_message(response)) {
+            self.notify_hooks(Hooks::after_delete, vec![path]).await;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_connect_and_close() {
+    use hermes_common::messages::{connect_message, close_message, extract_connect_ack_message, dir_message_request};
+    use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, Arc::new(ServerState::new()), None));
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    // Nothing but Connect is allowed before authenticating, and the refusal carries the request's ID
+    write_frame_async(&mut client, &dir_message_request().with_request_id(5)).await.unwrap();
+    let response = read_frame_async(&mut client).await.unwrap();
+    assert_eq!(response.request_id(), 5);
+    assert_eq!(hermes_common::messages::extract_ack_message(response).unwrap().0, HttpCodes::Unauthorized);
+
+    // The user database is not open, so every login is refused
+    write_frame_async(&mut client, &connect_message(String::from("user"), String::from("pass"), CURRENT_PROTOCOL_VERSION)).await.unwrap();
+    let response = read_frame_async(&mut client).await.unwrap();
+    assert_eq!(extract_connect_ack_message(response).unwrap().0, HttpCodes::Unauthorized);
+
+    // Every connection is its own task, so a second client is answered while the first is still open
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    write_frame_async(&mut second, &dir_message_request()).await.unwrap();
+    let response = read_frame_async(&mut second).await.unwrap();
+    assert_eq!(hermes_common::messages::extract_ack_message(response).unwrap().0, HttpCodes::Unauthorized);
+
+    write_frame_async(&mut client, &close_message()).await.unwrap();
+    assert!(read_frame_async(&mut client).await.is_err());
+}
+
+#[tokio::test]
+async fn test_upload_then_stat() {
+    use hermes_common::checksum::{checksum_bytes, ChecksumAlgorithm};
+    use hermes_common::file_io::{FileType, Provenance};
+    use hermes_common::messages::{connect_message, attach_session, extract_session_ack, upload_message, extract_upload_response_message, extract_ack_message, stat_message_request, extract_stat_response_message};
+    use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+    use tokio::io::AsyncWriteExt;
+
+    let users = std::env::temp_dir().join(format!("hermes_stat_users_{}.json", std::process::id()));
+    std::fs::write(&users, r#"[{"username":"alice","password":"pass"}]"#).unwrap();
+    let mut state = ServerState::new();
+    state.users.get_mut().open(users.to_string_lossy().to_string()).unwrap();
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::new(state));
+
+    write_frame_async(&mut client, &connect_message(String::from("alice"), String::from("pass"), CURRENT_PROTOCOL_VERSION)).await.unwrap();
+    let session = extract_session_ack(&read_frame_async(&mut client).await.unwrap()).unwrap().token().to_string();
+
+    // Sent with where the client says it came from
+    let name = format!("hermes_stat_{}.bin", std::process::id());
+    let contents = vec![7u8; 2 * BUFF_SIZE as usize + 100];
+    let provenance = Provenance { hostname: Some(String::from("build-07")), client_version: Some(String::from("1.2.3")), local_path: Some(String::from("/home/alice/out.bin")) };
+    let upload = upload_message(&name, FileType::Binary, 3, 0, Some(checksum_bytes(&contents, ChecksumAlgorithm::Sha256)), Some(provenance.clone()));
+    write_frame_async(&mut client, &attach_session(upload, &session)).await.unwrap();
+    assert_eq!(extract_upload_response_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    client.write_all(&ChunkWriter::frames(&contents).concat()).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+
+    write_frame_async(&mut client, &attach_session(stat_message_request(&name), &session)).await.unwrap();
+    let (code, _, info) = extract_stat_response_message(read_frame_async(&mut client).await.unwrap()).unwrap();
+    let info = info.unwrap();
+    assert_eq!(code, HttpCodes::Ok);
+    assert_eq!((info.size(), info.owner()), (contents.len() as u64, "alice"));
+    assert_eq!(info.provenance(), Some(&provenance));
+
+    let _ = std::fs::remove_file(root_directory().join(&name));
+    let _ = std::fs::remove_file(&users);
+}
+
+#[tokio::test]
+async fn test_idempotent_replay() {
+    use hermes_common::checksum::{checksum_bytes, ChecksumAlgorithm};
+    use hermes_common::file_io::FileType;
+    use hermes_common::messages::{connect_message, attach_session, extract_session_ack, upload_message, extract_upload_response_message, extract_ack_message, attach_idempotency_key, delete_message, changes_since_request, extract_changes_since_response};
+    use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+    use tokio::io::AsyncWriteExt;
+
+    let users = std::env::temp_dir().join(format!("hermes_replay_users_{}.json", std::process::id()));
+    std::fs::write(&users, r#"[{"username":"alice","password":"pass"}]"#).unwrap();
+    let mut state = ServerState::new();
+    state.users.get_mut().open(users.to_string_lossy().to_string()).unwrap();
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::new(state));
+
+    write_frame_async(&mut client, &connect_message(String::from("alice"), String::from("pass"), CURRENT_PROTOCOL_VERSION)).await.unwrap();
+    let session = extract_session_ack(&read_frame_async(&mut client).await.unwrap()).unwrap().token().to_string();
+
+    // A replayed upload is told it is already done, rather than being asked for the file again
+    let name = format!("hermes_replay_{}.bin", std::process::id());
+    let contents = vec![7u8; 100];
+    let upload = attach_idempotency_key(upload_message(&name, FileType::Binary, 1, 0, Some(checksum_bytes(&contents, ChecksumAlgorithm::Sha256)), None), "upload-key");
+    write_frame_async(&mut client, &attach_session(upload.clone(), &session)).await.unwrap();
+    assert_eq!(extract_upload_response_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    client.write_all(&ChunkWriter::frames(&contents).concat()).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    write_frame_async(&mut client, &attach_session(upload, &session)).await.unwrap();
+    assert_eq!(extract_upload_response_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::NoContent);
+
+    // A replayed delete gets the answer the first one did, rather than being told the file is gone
+    let delete = attach_idempotency_key(delete_message(&name, false), "delete-key");
+    for _ in 0..2 {
+        write_frame_async(&mut client, &attach_session(delete.clone(), &session)).await.unwrap();
+        assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    }
+    assert!(!root_directory().join(&name).exists());
+
+    // Without a watcher there is no journal to ask, so the client has to fall back on when the file last changed
+    write_frame_async(&mut client, &attach_session(changes_since_request(&name, 0), &session)).await.unwrap();
+    assert_eq!(extract_changes_since_response(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Conflict);
+
+    let _ = std::fs::remove_file(root_directory().join(&name));
+    let _ = std::fs::remove_file(&users);
+}
+
+#[tokio::test]
+async fn test_guest_session() {
+    use hermes_common::messages::{guest_connect_message, extract_connect_ack_message, attach_session, extract_session_ack, dir_message_request, extract_dir_response_message, change_dir_message, stat_message_request, extract_stat_response_message, delete_message, extract_ack_message};
+    use hermes_common::file_io::DirectoryInfo;
+    use hermes_common::tuning::FrameSizeBounds;
+    use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+
+    let public = format!("hermes_guest_{}", std::process::id());
+    std::fs::create_dir_all(root_directory().join(&public).join("docs")).unwrap();
+    std::fs::write(root_directory().join(&public).join("docs").join("a.txt"), "a").unwrap();
+    let mut state = ServerState::new();
+    state.config.guest_directory = Some(PathBuf::from(&public));
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::new(state));
+
+    write_frame_async(&mut client, &guest_connect_message(CURRENT_PROTOCOL_VERSION)).await.unwrap();
+    let connected = read_frame_async(&mut client).await.unwrap();
+    let session = extract_session_ack(&connected).unwrap().token().to_string();
+    assert_eq!(extract_connect_ack_message(connected).unwrap().0, HttpCodes::Ok);
+
+    // A guest browses its folder like anyone else
+    write_frame_async(&mut client, &attach_session(dir_message_request(), &session)).await.unwrap();
+    let (code, _, _, frame_count) = extract_dir_response_message(read_frame_async(&mut client).await.unwrap()).unwrap();
+    assert_eq!(code, HttpCodes::Ok);
+    let listing = receive_network_binary_async(&mut client, frame_count, &mut FrameSizeTuner::new(FrameSizeBounds::default())).await.unwrap();
+    let listing: DirectoryInfo = serde_json::from_slice(&listing).unwrap();
+    assert_eq!(listing.contents().iter().map(|x| x.name()).collect::<Vec<_>>(), vec!["docs"]);
+
+    write_frame_async(&mut client, &attach_session(change_dir_message("docs"), &session)).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    write_frame_async(&mut client, &attach_session(stat_message_request("a.txt"), &session)).await.unwrap();
+    assert_eq!(extract_stat_response_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+
+    // But cannot change anything
+    write_frame_async(&mut client, &attach_session(delete_message("a.txt", false), &session)).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Unauthorized);
+    assert!(root_directory().join(&public).join("docs").join("a.txt").is_file());
+
+    let _ = std::fs::remove_dir_all(root_directory().join(&public));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_links_stay_in_home() {
+    use hermes_common::messages::{guest_connect_message, extract_connect_ack_message, attach_session, extract_session_ack, stat_message_request, extract_stat_response_message};
+    use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+    use crate::config::SymlinkPolicy;
+
+    let public = format!("hermes_link_home_{}", std::process::id());
+    let private = format!("hermes_link_private_{}", std::process::id());
+    std::fs::create_dir_all(root_directory().join(&public).join("docs")).unwrap();
+    std::fs::create_dir_all(root_directory().join(&private)).unwrap();
+    std::fs::write(root_directory().join(&public).join("docs").join("a.txt"), "a").unwrap();
+    std::fs::write(root_directory().join(&private).join("secret.txt"), "s").unwrap();
+    std::os::unix::fs::symlink(root_directory().join(&public).join("docs"), root_directory().join(&public).join("inside")).unwrap();
+    std::os::unix::fs::symlink(root_directory().join(&private), root_directory().join(&public).join("other")).unwrap();
+
+    let mut state = ServerState::new();
+    state.config.guest_directory = Some(PathBuf::from(&public));
+    state.config.symlinks = SymlinkPolicy::FollowWithinRoot;
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::new(state));
+    write_frame_async(&mut client, &guest_connect_message(CURRENT_PROTOCOL_VERSION)).await.unwrap();
+    let connected = read_frame_async(&mut client).await.unwrap();
+    let session = extract_session_ack(&connected).unwrap().token().to_string();
+    assert_eq!(extract_connect_ack_message(connected).unwrap().0, HttpCodes::Ok);
+
+    // A link within the home is followed, but one into another folder beneath the root is not
+    write_frame_async(&mut client, &attach_session(stat_message_request("inside/a.txt"), &session)).await.unwrap();
+    assert_eq!(extract_stat_response_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Ok);
+    write_frame_async(&mut client, &attach_session(stat_message_request("other/secret.txt"), &session)).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0, HttpCodes::Forbidden);
+
+    let _ = std::fs::remove_dir_all(root_directory().join(&public));
+    let _ = std::fs::remove_dir_all(root_directory().join(&private));
+}
+
+#[tokio::test]
+async fn test_hostile_frames() {
+    use hermes_common::framing::FRAME_MAGIC;
+    use hermes_common::messages::{dir_message_request, extract_ack_message};
+    use tokio::io::AsyncWriteExt;
+
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::new(ServerState::new()));
+    let mut send_raw = async |payload: &str| {
+        let mut frame = FRAME_MAGIC.to_vec();
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload.as_bytes());
+        client.write_all(&frame).await.unwrap();
+        extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap()
+    };
+
+    // A type the server does not know, and a request missing what its handler needs, are both refused without hanging up
+    let (code, message) = send_raw(r#"{"message_type":"Teleport","direction":"Request","request_id":3,"data":{}}"#).await;
+    assert_eq!((code, message.as_str()), (HttpCodes::BadRequest, "unknown message type 'Teleport'"));
+    let (code, message) = send_raw(r#"{"message_type":"Delete","direction":"Request","request_id":4,"data":{}}"#).await;
+    assert_eq!(code, HttpCodes::BadRequest);
+    assert!(message.contains("path"));
+    assert_eq!(send_raw("not a message").await.0, HttpCodes::BadRequest);
+
+    write_frame_async(&mut client, &dir_message_request().with_request_id(5)).await.unwrap();
+    let response = read_frame_async(&mut client).await.unwrap();
+    assert_eq!((response.request_id(), extract_ack_message(response).unwrap().0), (5, HttpCodes::Unauthorized));
+}
+
+#[tokio::test]
+async fn test_session_limits() {
+    use crate::middleware::SessionLimits;
+    use hermes_common::messages::{cancel_message, dir_message_request, extract_ack_message};
+
+    let mut state = ServerState::new();
+    state.config.limits = SessionLimits { requests_per_sec: 0.001, burst: 2.0, max_transfers: 1 };
+    let state = Arc::new(state);
+    let mut client = loopback("127.0.0.1:1".parse().unwrap(), Arc::clone(&state));
+    let mut status = async |message: Message| {
+        write_frame_async(&mut client, &message).await.unwrap();
+        extract_ack_message(read_frame_async(&mut client).await.unwrap()).unwrap().0
+    };
+
+    // Once the burst is spent the connection is told to slow down, but a Cancel still gets through
+    assert_eq!(status(dir_message_request()).await, HttpCodes::Unauthorized);
+    assert_eq!(status(dir_message_request()).await, HttpCodes::Unauthorized);
+    assert_eq!(status(dir_message_request()).await, HttpCodes::TooManyRequests);
+    assert_eq!(status(cancel_message(None)).await, HttpCodes::Unauthorized);
+
+    // Each connection has a bucket of its own
+    let mut other = loopback("127.0.0.1:1".parse().unwrap(), state);
+    write_frame_async(&mut other, &dir_message_request()).await.unwrap();
+    assert_eq!(extract_ack_message(read_frame_async(&mut other).await.unwrap()).unwrap().0, HttpCodes::Unauthorized);
+}
+
+#[test]
+fn test_admission() {
+    let mut state = ServerState::new();
+    state.config.access = serde_json::from_str(r#"{ "deny": ["10.0.0.0/8"], "max_per_address": 1 }"#).unwrap();
+    let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+
+    assert_eq!(admission(&state, ip("10.1.1.1")).map(|x| x.0), Some(HttpCodes::Forbidden));
+    assert_eq!(admission(&state, ip("127.0.0.1")), None);
+
+    // Each address has its own limit
+    state.connections.open("127.0.0.1");
+    assert_eq!(admission(&state, ip("127.0.0.1")).map(|x| x.0), Some(HttpCodes::TooManyRequests));
+    assert_eq!(admission(&state, ip("127.0.0.2")), None);
+
+    state.config.max_connections = Some(1);
+    assert_eq!(admission(&state, ip("127.0.0.2")).map(|x| x.0), Some(HttpCodes::TooManyRequests));
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::handlers::SessionIdentity;
+use crate::resume::generate_token;
+use hermes_common::session::{SessionToken, unix_now};
+
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(30 * 60);
+
+struct SessionRecord {
+    identity: SessionIdentity,
+    expires_at: u64
+}
+
+// Tracks the session tokens of live connections. Sessions only live in memory, since a restart drops every connection anyway (resumption tokens cover reconnecting).
+pub struct SessionManager {
+    sessions: HashMap<String, SessionRecord>,
+    ttl: Duration
+}
+impl Debug for SessionManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Sessions: {}, TTL: {}s)", self.sessions.len(), self.ttl.as_secs())
+    }
+}
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TTL)
+    }
+}
+impl SessionManager {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            ttl
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn issue(&mut self, identity: SessionIdentity) -> SessionToken {
+        self.prune();
+
+        let token = generate_token();
+        let expires_at = unix_now() + self.ttl.as_secs();
+        self.sessions.insert(token.clone(), SessionRecord { identity, expires_at });
+
+        SessionToken::new(token, expires_at)
+    }
+    // Returns who the session belongs to. The expiry is idle based, so every accepted request pushes it out again.
+    pub fn validate(&mut self, token: &str) -> Option<SessionIdentity> {
+        let now = unix_now();
+        let record = self.sessions.get_mut(token)?;
+        if now >= record.expires_at {
+            self.sessions.remove(token);
+            return None;
+        }
+
+        record.expires_at = now + self.ttl.as_secs();
+        Some(record.identity.clone())
+    }
+    pub fn invalidate(&mut self, token: &str) -> bool {
+        self.sessions.remove(token).is_some()
+    }
+    // Ends every session of a user, such as when their password changes
+    pub fn invalidate_user(&mut self, username: &str) -> usize {
+        let prev_len = self.sessions.len();
+        self.sessions.retain(|_, x| x.identity != SessionIdentity::User(username.to_string()));
+
+        prev_len - self.sessions.len()
+    }
+
+    fn prune(&mut self) {
+        let now = unix_now();
+        self.sessions.retain(|_, x| now < x.expires_at);
+    }
+}
+
+#[test]
+fn test_session_lifecycle() {
+    let mut sessions = SessionManager::default();
+
+    let token = sessions.issue(SessionIdentity::User(String::from("user")));
+    assert_eq!(sessions.validate(token.token()), Some(SessionIdentity::User(String::from("user"))));
+    assert_eq!(sessions.validate("not a token"), None);
+
+    assert!(sessions.invalidate(token.token()));
+    assert_eq!(sessions.validate(token.token()), None);
+
+    sessions.issue(SessionIdentity::User(String::from("user")));
+    sessions.issue(SessionIdentity::User(String::from("other")));
+    assert_eq!(sessions.invalidate_user("user"), 1);
+    assert_eq!(sessions.len(), 1);
+
+    // A session with no lifetime has already expired by the time it is used
+    let mut expired = SessionManager::new(Duration::ZERO);
+    let token = expired.issue(SessionIdentity::User(String::from("user")));
+    assert_eq!(expired.validate(token.token()), None);
+}
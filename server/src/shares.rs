@@ -0,0 +1,158 @@
+use serde::{Serialize, Deserialize};
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::credentials::Credentials;
+use crate::resume::generate_token;
+use hermes_common::file_io::JsonFile;
+use hermes_common::session::{ShareLink, unix_now};
+
+pub const MAX_SHARE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ShareRecord {
+    link: ShareLink,
+    owner: String,
+    downloads: u64
+}
+
+// The share links users have handed out. Unlike grants, a link can be used any number of times until it expires.
+pub struct ShareDatabase {
+    file: JsonFile,
+    records: Vec<ShareRecord>
+}
+impl Debug for ShareDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Path: '{}', Links: {})", self.file.path().unwrap_or("Unopened"), self.records.len())
+    }
+}
+impl Default for ShareDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ShareDatabase {
+    pub fn new() -> Self {
+        Self {
+            file: JsonFile::new(),
+            records: Vec::new()
+        }
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<(), String> {
+        let contents = self.file.open(path)?;
+
+        if contents.trim().is_empty() {
+            self.records.clear();
+            return Ok(());
+        }
+
+        match serde_json::from_str(&contents) {
+            Ok(l) => {
+                self.records = l;
+                self.prune();
+                Ok(())
+            },
+            Err(e) => Err(e.to_string())
+        }
+    }
+    pub fn save(&self) -> Result<(), String> {
+        let contents = match serde_json::to_string(&self.records) {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.file.save(&contents).map_err(|e| e.to_string())
+    }
+
+    fn prune(&mut self) {
+        self.records.retain(|x| !x.link.is_expired());
+    }
+    fn find(&self, token: &str) -> Option<&ShareRecord> {
+        self.records.iter().find(|x| x.link.token() == token && !x.link.is_expired())
+    }
+
+    // The path is relative to the root, and the owner must already be able to read it
+    pub fn issue(&mut self, owner: &Credentials, path: String, ttl: Duration) -> Result<ShareLink, String> {
+        if ttl.is_zero() || ttl > MAX_SHARE_TTL {
+            return Err(format!("the lifetime of a share link must be between 1 and {} seconds", MAX_SHARE_TTL.as_secs()));
+        }
+
+        self.prune();
+
+        let link = ShareLink::new(generate_token(), path, unix_now() + ttl.as_secs());
+        tracing::info!(owner = owner.username(), path = link.path(), expires_at = link.expires_at(), "issued a share link");
+        self.records.push(
+            ShareRecord {
+                link: link.clone(),
+                owner: owner.username().to_string(),
+                downloads: 0
+            }
+        );
+
+        Ok(link)
+    }
+
+    pub fn authorize_connect(&self, token: &str) -> Option<ShareLink> {
+        self.find(token).map(|x| x.link.clone())
+    }
+    // Anything other than the link's own path is refused, and so is a link that has expired or been revoked since the connection was made
+    pub fn authorize_download(&self, token: &str, path: &str) -> Result<(), String> {
+        match self.find(token) {
+            Some(r) if r.link.path() == path => Ok(()),
+            Some(r) => Err(format!("this share link only permits downloading '{}'", r.link.path())),
+            None => Err(String::from("share link is invalid or expired"))
+        }
+    }
+    pub fn record_download(&mut self, token: &str) {
+        if let Some(r) = self.records.iter_mut().find(|x| x.link.token() == token) {
+            r.downloads += 1;
+        }
+    }
+    // Only the user who issued a link, or an administrator, can revoke it
+    pub fn revoke(&mut self, token: &str, actor: &Credentials) -> Result<(), String> {
+        let index = match self.records.iter().position(|x| x.link.token() == token) {
+            Some(i) => i,
+            None => return Err(String::from("no such share link"))
+        };
+        if self.records[index].owner != actor.username() && !actor.is_admin() {
+            return Err(String::from("only the user who created a share link can revoke it"));
+        }
+
+        let record = self.records.remove(index);
+        tracing::info!(actor = actor.username(), path = record.link.path(), downloads = record.downloads, "revoked a share link");
+        Ok(())
+    }
+    // The user who issued a link, whose access to the file it relies on
+    pub fn owner_of(&self, token: &str) -> Option<&str> {
+        self.records.iter().find(|x| x.link.token() == token).map(|x| x.owner.as_str())
+    }
+    pub fn downloads_of(&self, token: &str) -> Option<u64> {
+        self.records.iter().find(|x| x.link.token() == token).map(|x| x.downloads)
+    }
+}
+
+#[test]
+fn test_share_link_lifecycle() {
+    let owner = Credentials::from("owner", "pass");
+    let other = Credentials::from("other", "pass");
+    let mut shares = ShareDatabase::new();
+
+    assert!(shares.issue(&owner, String::from("a.txt"), Duration::ZERO).is_err());
+    assert!(shares.issue(&owner, String::from("a.txt"), MAX_SHARE_TTL * 2).is_err());
+
+    // A link is good for its one path, as often as it is used
+    let link = shares.issue(&owner, String::from("docs/a.txt"), Duration::from_secs(60)).unwrap();
+    assert_eq!(shares.authorize_connect(link.token()), Some(link.clone()));
+    assert!(shares.authorize_download(link.token(), "docs/b.txt").is_err());
+    for _ in 0..2 {
+        assert!(shares.authorize_download(link.token(), "docs/a.txt").is_ok());
+        shares.record_download(link.token());
+    }
+    assert_eq!((shares.owner_of(link.token()), shares.downloads_of(link.token())), (Some("owner"), Some(2)));
+
+    assert!(shares.revoke(link.token(), &other).is_err());
+    assert!(shares.revoke(link.token(), &owner).is_ok());
+    assert!(shares.authorize_connect(link.token()).is_none());
+    assert!(shares.authorize_download(link.token(), "docs/a.txt").is_err());
+}
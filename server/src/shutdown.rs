@@ -0,0 +1,83 @@
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::activity::ConnectionTracker;
+
+// How long connections are given to finish what they are doing once the server is asked to stop
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+// Tells every connection the server is going away. Each one finishes the request it is on, sends its client a Close with the reason, and ends.
+pub struct ShutdownController {
+    sender: watch::Sender<Option<String>>
+}
+impl Default for ShutdownController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ShutdownController {
+    pub fn new() -> Self {
+        Self {
+            sender: watch::channel(None).0
+        }
+    }
+
+    pub fn begin(&self, reason: &str) {
+        self.sender.send_replace(Some(reason.to_string()));
+    }
+    pub fn reason(&self) -> Option<String> {
+        self.sender.borrow().clone()
+    }
+    // Resolves with the reason once shutdown has begun, straight away if it already has
+    pub async fn wait(&self) -> String {
+        let mut receiver = self.sender.subscribe();
+        let reason = receiver.wait_for(|x| x.is_some()).await.ok().and_then(|x| x.clone());
+        match reason {
+            Some(reason) => reason,
+            None => std::future::pending().await //The sender lives as long as we do
+        }
+    }
+
+    // Waits for every connection to end, up to the grace period. Returns how many were still open when it ran out.
+    pub async fn drain(&self, connections: &ConnectionTracker, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        loop {
+            let open = connections.len();
+            if open == 0 || Instant::now() >= deadline {
+                return open;
+            }
+
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_drain() {
+    use std::sync::Arc;
+
+    let controller = Arc::new(ShutdownController::new());
+    let connections = Arc::new(ConnectionTracker::new());
+    assert_eq!(controller.reason(), None);
+
+    // A connection waiting for its next request hears about the shutdown, and ends
+    let id = connections.open("10.0.0.1");
+    let task = {
+        let (controller, connections) = (Arc::clone(&controller), Arc::clone(&connections));
+        tokio::spawn(async move {
+            let reason = controller.wait().await;
+            connections.close(id);
+            reason
+        })
+    };
+    controller.begin("maintenance");
+    assert_eq!(controller.drain(&connections, Duration::from_secs(5)).await, 0);
+    assert_eq!(task.await.unwrap(), "maintenance");
+
+    // Waiting after shutdown began returns at once, and a connection that never ends is counted once the grace period runs out
+    assert_eq!(controller.wait().await, "maintenance");
+    connections.open("10.0.0.2");
+    assert_eq!(controller.drain(&connections, Duration::from_millis(100)).await, 1);
+}
@@ -0,0 +1,357 @@
+// A soak test that runs hundreds of simulated clients against one server at once, injecting faults as they go.
+// It is slow and writes under the real root directory, so it only runs when asked for:
+//
+//     cargo test --release soak -- --ignored --nocapture
+//
+// HERMES_SOAK_CLIENTS, HERMES_SOAK_ROUNDS, and HERMES_SOAK_SEED change its size and replay a failing run.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+use crate::io_loc::root_directory;
+use crate::middleware::{Pipeline, SessionLimits};
+use crate::server::serve;
+use crate::staging::STAGING_SUFFIX;
+use crate::state::ServerState;
+use hermes_common::checksum::{checksum_bytes, ChecksumAlgorithm};
+use hermes_common::file_io::FileType;
+use hermes_common::framing::{read_frame_async, write_frame_async, FRAME_MAGIC};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::messages::{Message, attach_session, connect_message, close_message, extract_ack_message, extract_connect_ack_message};
+use hermes_common::messages::{extract_session_ack, extract_upload_response_message, stat_message_request, stats_request_message, upload_message};
+use hermes_common::protocol::CURRENT_PROTOCOL_VERSION;
+
+const DEFAULT_CLIENTS: usize = 200;
+const DEFAULT_ROUNDS: usize = 5;
+const FRAME_SIZE: usize = 4096;
+// Every file is two frames, so an upload cut off between them can be resumed on a frame boundary
+const FILE_FRAMES: u64 = 2;
+const FILE_SIZE: usize = FILE_FRAMES as usize * FRAME_SIZE;
+// No single step of a healthy server takes anywhere near this long. Hitting it is reported as a deadlock.
+const STEP_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|x| x.parse().ok()).unwrap_or(default)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Fault {
+    None,
+    DisconnectBeforeData, //Accepted, then gone before a single byte arrives
+    DisconnectBetweenFrames, //Gone after the first frame of data
+    SlowWrites, //Data trickles in, a little at a time
+    SlowReads, //Every response sits unread for a while
+    CorruptFrame, //A request frame that cannot be decoded
+    TruncatedFrame, //A header promising more than is ever sent
+    CorruptData //The checksum is for different contents than the ones sent
+}
+impl Fault {
+    fn pick(rng: &mut StdRng) -> Self {
+        match rng.gen_range(0..10) {
+            0 => Self::DisconnectBeforeData,
+            1 => Self::DisconnectBetweenFrames,
+            2 => Self::SlowWrites,
+            3 => Self::SlowReads,
+            4 => Self::CorruptFrame,
+            5 => Self::TruncatedFrame,
+            6 => Self::CorruptData,
+            _ => Self::None
+        }
+    }
+    // Whether the upload for this round should leave nothing behind
+    fn leaves_nothing(self) -> bool {
+        matches!(self, Self::CorruptFrame | Self::TruncatedFrame | Self::CorruptData)
+    }
+    fn disconnects(self) -> bool {
+        !matches!(self, Self::None | Self::SlowWrites | Self::SlowReads | Self::CorruptData)
+    }
+}
+
+// What one simulated client was trying to write, so the data directory can be checked against it afterwards
+struct Expected {
+    name: String,
+    contents: Vec<u8>,
+    fault: Fault
+}
+
+fn contents_for(client: usize, round: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(((client as u64) << 32) | round as u64);
+    (0..FILE_SIZE).map(|_| rng.gen()).collect()
+}
+
+struct SoakClient {
+    stream: TcpStream,
+    session: String
+}
+impl SoakClient {
+    async fn connect(addr: SocketAddr) -> Result<Self, String> {
+        let mut stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+        write_frame_async(&mut stream, &connect_message(String::from("soak"), String::from("soak"), CURRENT_PROTOCOL_VERSION)).await?;
+        let response = read_frame_async(&mut stream).await?;
+
+        let session = extract_session_ack(&response);
+        match (extract_connect_ack_message(response), session) {
+            (Some((HttpCodes::Ok, _, _, _)), Some(s)) => Ok(Self { stream, session: s.token().to_string() }),
+            (code, _) => Err(format!("login refused with {:?}", code.map(|x| x.0)))
+        }
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), String> {
+        write_frame_async(&mut self.stream, &attach_session(message, &self.session)).await
+    }
+    async fn read(&mut self) -> Result<Message, String> {
+        read_frame_async(&mut self.stream).await
+    }
+    async fn request(&mut self, message: Message) -> Result<Message, String> {
+        self.send(message).await?;
+        self.read().await
+    }
+
+    // Sends the upload request and waits to be told to go ahead
+    async fn begin_upload(&mut self, name: &str, contents: &[u8], offset: u64, pause: Option<Duration>) -> Result<(), String> {
+        let checksum = checksum_bytes(contents, ChecksumAlgorithm::Sha256);
+        self.send(upload_message(name, FileType::Binary, (contents.len() as u64 - offset) / FRAME_SIZE as u64, offset, Some(checksum), None)).await?;
+        if let Some(p) = pause {
+            tokio::time::sleep(p).await;
+        }
+
+        match extract_upload_response_message(self.read().await?) {
+            Some((HttpCodes::Ok, _, _)) => Ok(()),
+            other => Err(format!("upload of '{name}' refused with {:?}", other))
+        }
+    }
+    async fn finish_upload(&mut self) -> Result<HttpCodes, String> {
+        extract_ack_message(self.read().await?).map(|x| x.0).ok_or_else(|| String::from("malformed upload ack"))
+    }
+    async fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.stream.write_all(data).await.map_err(|e| e.to_string())?;
+        self.stream.flush().await.map_err(|e| e.to_string())
+    }
+
+    async fn close(mut self) {
+        let _ = write_frame_async(&mut self.stream, &close_message()).await;
+    }
+}
+
+// One round for one client. The connection is handed back unless the fault threw it away.
+async fn run_round(mut client: SoakClient, rng: &mut StdRng, expected: &Expected) -> Result<Option<SoakClient>, String> {
+    let name = expected.name.as_str();
+    let contents = expected.contents.as_slice();
+
+    match expected.fault {
+        Fault::None => {
+            client.begin_upload(name, contents, 0, None).await?;
+            client.write(contents).await?;
+        },
+        Fault::DisconnectBeforeData => {
+            client.begin_upload(name, contents, 0, None).await?;
+            return Ok(None);
+        },
+        Fault::DisconnectBetweenFrames => {
+            client.begin_upload(name, contents, 0, None).await?;
+            client.write(&contents[..FRAME_SIZE]).await?;
+            return Ok(None);
+        },
+        Fault::SlowWrites => {
+            client.begin_upload(name, contents, 0, None).await?;
+            for piece in contents.chunks(rng.gen_range(64..1024)) {
+                client.write(piece).await?;
+                tokio::time::sleep(Duration::from_millis(rng.gen_range(0..4))).await;
+            }
+        },
+        Fault::SlowReads => {
+            let pause = Duration::from_millis(rng.gen_range(20..200));
+            client.begin_upload(name, contents, 0, Some(pause)).await?;
+            client.write(contents).await?;
+            tokio::time::sleep(pause).await;
+        },
+        Fault::CorruptFrame => {
+            let mut garbage: Vec<u8> = FRAME_MAGIC.to_vec();
+            let payload: Vec<u8> = (0..rng.gen_range(1..256)).map(|_| rng.gen()).collect();
+            garbage.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            garbage.extend_from_slice(&payload);
+            if rng.gen_bool(0.5) {
+                garbage[0] ^= 0xFF;
+            }
+            client.write(&garbage).await?;
+
+            // The server drops a connection it can no longer make sense of
+            return match client.read().await {
+                Err(_) => Ok(None),
+                Ok(m) => Err(format!("a corrupt frame was answered with {:?}", m))
+            };
+        },
+        Fault::TruncatedFrame => {
+            let mut header: Vec<u8> = FRAME_MAGIC.to_vec();
+            header.extend_from_slice(&1024u32.to_be_bytes());
+            header.extend_from_slice(b"{\"message_type\"");
+            client.write(&header).await?;
+            return Ok(None);
+        },
+        Fault::CorruptData => {
+            client.begin_upload(name, contents, 0, None).await?;
+            let mut corrupt = contents.to_vec();
+            let at = rng.gen_range(0..corrupt.len());
+            corrupt[at] ^= 0xFF;
+            client.write(&corrupt).await?;
+
+            return match client.finish_upload().await? {
+                HttpCodes::Conflict => Ok(Some(client)),
+                code => Err(format!("corrupt upload of '{name}' finished with {code}"))
+            };
+        }
+    }
+
+    match client.finish_upload().await? {
+        HttpCodes::Ok => Ok(Some(client)),
+        code => Err(format!("upload of '{name}' finished with {code}"))
+    }
+}
+
+async fn run_client(addr: SocketAddr, id: usize, rounds: usize, seed: u64, dir: String) -> Result<Vec<Expected>, String> {
+    let mut rng = StdRng::seed_from_u64(seed ^ id as u64);
+    let mut result = Vec::<Expected>::new();
+    let mut client: Option<SoakClient> = None;
+
+    for round in 0..rounds {
+        let connected = match client.take() {
+            Some(c) => c,
+            None => timeout(STEP_TIMEOUT, SoakClient::connect(addr)).await.map_err(|_| format!("client {id} timed out logging in"))??
+        };
+
+        let expected = Expected {
+            name: format!("{dir}/c{id}-r{round}.bin"),
+            contents: contents_for(id, round),
+            fault: Fault::pick(&mut rng)
+        };
+
+        client = timeout(STEP_TIMEOUT, run_round(connected, &mut rng, &expected)).await
+            .map_err(|_| format!("client {id} round {round} ({:?}) timed out", expected.fault))?
+            .map_err(|e| format!("client {id} round {round} ({:?}): {e}", expected.fault))?;
+        assert_eq!(client.is_none(), expected.fault.disconnects());
+
+        result.push(expected);
+    }
+
+    if let Some(c) = client {
+        c.close().await;
+    }
+    Ok(result)
+}
+
+// Removes the soak's files from the root directory however the test ends
+struct SoakDirectory(PathBuf);
+impl Drop for SoakDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hermes_soak_{}_{name}", std::process::id()))
+}
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+#[ignore]
+async fn soak() {
+    let clients: usize = env_or("HERMES_SOAK_CLIENTS", DEFAULT_CLIENTS);
+    let rounds: usize = env_or("HERMES_SOAK_ROUNDS", DEFAULT_ROUNDS);
+    let seed: u64 = env_or("HERMES_SOAK_SEED", rand::random());
+    println!("soak: {clients} clients, {rounds} rounds, seed {seed}");
+
+    let dir_name = format!("hermes-soak-{}", std::process::id());
+    let dir = SoakDirectory(root_directory().join(&dir_name));
+    std::fs::create_dir_all(&dir.0).unwrap();
+
+    // Everything but the data lives in the temporary directory. Rate limiting is left out, since every client shares one address.
+    let users_path = temp_path("users.json");
+    std::fs::write(&users_path, r#"[{"username":"soak","password":"soak"}]"#).unwrap();
+    let mut state = ServerState::new();
+    state.users.get_mut().open(path_string(&users_path)).unwrap();
+    std::fs::write(temp_path("stats.json"), "").unwrap();
+    state.stats.open(&path_string(&temp_path("stats.json"))).unwrap();
+    state.pipeline = Pipeline::standard(false, temp_path("audit.log"));
+    state.pipeline.disable("rate_limit");
+    state.config.limits = SessionLimits::unlimited();
+    let state = Arc::new(state);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, Arc::clone(&state), None));
+
+    let tasks: Vec<_> = (0..clients).map(|id| tokio::spawn(run_client(addr, id, rounds, seed, dir_name.clone()))).collect();
+    let mut expected = HashMap::<String, Expected>::new();
+    for task in tasks {
+        for e in task.await.unwrap().unwrap_or_else(|e| panic!("seed {seed}: {e}")) {
+            expected.insert(e.name.rsplit('/').next().unwrap().to_string(), e);
+        }
+    }
+
+    // Every connection has to wind down on its own, which it cannot do if it is stuck on a lock
+    timeout(STEP_TIMEOUT, async {
+        while !state.sessions.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }).await.unwrap_or_else(|_| panic!("seed {seed}: {} connections never finished", state.sessions.try_read().map(|x| x.len()).unwrap_or_default()));
+
+    // Interrupted uploads may only leave a staging file holding a prefix that ends on a frame, and resuming it must finish the file.
+    // Finished files are only ever whole. Anything else in the directory was leaked.
+    let mut client = SoakClient::connect(addr).await.unwrap();
+    let mut present = 0u64;
+    let mut staged = 0usize;
+    for entry in std::fs::read_dir(&dir.0).unwrap() {
+        let entry = entry.unwrap();
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let name = file_name.strip_suffix(STAGING_SUFFIX).unwrap_or(&file_name).to_string();
+        let partial = name.len() != file_name.len();
+        let e = expected.get(&name).unwrap_or_else(|| panic!("seed {seed}: stray file '{file_name}' in the data directory"));
+        assert!(!e.fault.leaves_nothing(), "seed {seed}: '{file_name}' ({:?}) was left behind", e.fault);
+
+        let on_disk = std::fs::read(entry.path()).unwrap();
+        assert!(on_disk.len() % FRAME_SIZE == 0 && e.contents.starts_with(&on_disk), "seed {seed}: '{file_name}' ({:?}) holds {} bytes that are not a prefix of what was sent", e.fault, on_disk.len());
+        assert!(partial || on_disk.len() == FILE_SIZE, "seed {seed}: '{name}' ({:?}) was moved into place unfinished", e.fault);
+
+        if partial {
+            assert!(!dir.0.join(&name).exists(), "seed {seed}: '{name}' is both finished and staged");
+            staged += 1;
+            timeout(STEP_TIMEOUT, async {
+                client.begin_upload(&e.name, &e.contents, on_disk.len() as u64, None).await?;
+                client.write(&e.contents[on_disk.len()..]).await?;
+                client.finish_upload().await
+            }).await.unwrap().map(|code| assert_eq!(code, HttpCodes::Ok, "seed {seed}: resuming '{name}'")).unwrap();
+            assert!(!entry.path().exists(), "seed {seed}: '{file_name}' outlived its upload");
+            assert_eq!(std::fs::read(dir.0.join(&name)).unwrap(), e.contents, "seed {seed}: '{name}' was resumed wrong");
+        }
+        present += 1;
+    }
+    // Every staging file left behind was registered, and resuming them cleared the registry
+    assert!(state.staging.read().await.is_empty(), "seed {seed}: {staged} staging files were resumed but the registry still holds some");
+
+    let missing: Vec<&String> = expected.iter().filter(|(n, e)| !e.fault.leaves_nothing() && !dir.0.join(n).exists()).map(|x| x.0).collect();
+    assert!(missing.is_empty(), "seed {seed}: uploads went missing: {:?}", missing);
+
+    // The server is still answering, and it recorded each finished file exactly once
+    if let Some(name) = expected.keys().next() {
+        timeout(STEP_TIMEOUT, client.request(stat_message_request(&format!("{dir_name}/{name}")))).await.unwrap().unwrap();
+    }
+    timeout(STEP_TIMEOUT, client.request(stats_request_message())).await.unwrap().unwrap();
+    client.close().await;
+
+    assert_eq!(state.stats.totals(), (present as usize, present * FILE_SIZE as u64), "seed {seed}: stats do not match the files on disk");
+    println!("soak: {present} files checked, {} uploads were meant to fail", expected.len() as u64 - present);
+
+    for name in ["users.json", "stats.json", "audit.log"] {
+        let _ = std::fs::remove_file(temp_path(name));
+    }
+}
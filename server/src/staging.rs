@@ -0,0 +1,161 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hermes_common::session::unix_now;
+
+// Uploads are written beside their destination under this suffix, and only moved into place once they are complete
+pub const STAGING_SUFFIX: &str = ".hermes.part";
+pub const DEFAULT_PARTIAL_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn staging_path(destination: &Path) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(STAGING_SUFFIX);
+    destination.with_file_name(name)
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct StagingRecord {
+    staging: PathBuf,
+    destination: PathBuf,
+    updated_at: u64 //The last time an upload wrote to it
+}
+
+// What startup did with the partial uploads a previous run left behind
+#[derive(Default, PartialEq, Debug)]
+pub struct RecoveryReport {
+    pub resumable: Vec<PathBuf>, //Destinations whose uploads can still be resumed
+    pub purged: Vec<PathBuf>,
+    pub reclaimed_bytes: u64
+}
+
+// Every staging file an upload has started, so a crash cannot leave partial files behind that nobody knows about.
+// The registry is written before any data arrives and replaced atomically, so it is never older than the files on disk.
+#[derive(Default)]
+pub struct StagingRegistry {
+    path: Option<PathBuf>,
+    records: Vec<StagingRecord>
+}
+impl StagingRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, path: &Path) -> Result<(), String> {
+        self.records = match std::fs::read_to_string(path) {
+            Ok(c) if c.trim().is_empty() => Vec::new(),
+            Ok(c) => serde_json::from_str(&c).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+    // Writes to a temporary file and renames it over the registry, so a crash mid-save leaves the old registry intact
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+
+        let contents = serde_json::to_string(&self.records).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, contents).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, path).map_err(|e| e.to_string())
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // Called before an upload writes anything, including when it resumes
+    pub fn begin(&mut self, staging: &Path, destination: &Path) {
+        self.records.retain(|x| x.staging != staging);
+        self.records.push(
+            StagingRecord {
+                staging: staging.to_path_buf(),
+                destination: destination.to_path_buf(),
+                updated_at: unix_now()
+            }
+        );
+    }
+    // Called once the staging file is gone, whether it was moved into place or thrown away
+    pub fn finish(&mut self, staging: &Path) -> bool {
+        let prev_len = self.records.len();
+        self.records.retain(|x| x.staging != staging);
+
+        prev_len != self.records.len()
+    }
+
+    // Partial uploads younger than the max age are kept so their clients can resume them. Older ones are deleted.
+    // Records whose staging file has already gone are dropped. Files that were never registered are not touched, since they are not ours.
+    pub fn recover(&mut self, max_age: Duration) -> RecoveryReport {
+        let now = unix_now();
+        let mut report = RecoveryReport::default();
+
+        self.records.retain(|record| {
+            let size = match std::fs::metadata(&record.staging) {
+                Ok(m) if m.is_file() => m.len(),
+                _ => return false
+            };
+
+            if now.saturating_sub(record.updated_at) < max_age.as_secs() {
+                report.resumable.push(record.destination.clone());
+                true
+            } else if std::fs::remove_file(&record.staging).is_ok() {
+                report.purged.push(record.destination.clone());
+                report.reclaimed_bytes += size;
+                false
+            } else {
+                true
+            }
+        });
+
+        report
+    }
+}
+
+#[test]
+fn test_staging_recovery() {
+    let dir = std::env::temp_dir().join(format!("hermes_staging_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let registry_path = dir.join("staging.json");
+
+    let fresh = dir.join("fresh.bin");
+    let stale = dir.join("stale.bin");
+    let gone = dir.join("gone.bin");
+    assert_eq!(staging_path(&fresh), dir.join("fresh.bin.hermes.part"));
+
+    let mut registry = StagingRegistry::new();
+    registry.open(&registry_path).unwrap();
+    for destination in [&fresh, &stale, &gone] {
+        registry.begin(&staging_path(destination), destination);
+    }
+    registry.records.iter_mut().find(|x| x.destination == stale).unwrap().updated_at = 0;
+    std::fs::write(staging_path(&fresh), b"abc").unwrap();
+    std::fs::write(staging_path(&stale), b"abcdef").unwrap();
+    std::fs::write(dir.join("unknown.hermes.part"), b"x").unwrap();
+    registry.save().unwrap();
+
+    // A fresh open sees exactly what was saved, as it would after a crash
+    let mut registry = StagingRegistry::new();
+    registry.open(&registry_path).unwrap();
+    assert_eq!(registry.len(), 3);
+
+    let report = registry.recover(Duration::from_secs(60));
+    assert_eq!(report.resumable, vec![fresh.clone()]);
+    assert_eq!(report.purged, vec![stale.clone()]);
+    assert_eq!(report.reclaimed_bytes, 6);
+    assert!(!staging_path(&stale).exists());
+    assert!(dir.join("unknown.hermes.part").exists());
+    assert_eq!(registry.len(), 1);
+
+    assert!(registry.finish(&staging_path(&fresh)));
+    assert!(registry.is_empty());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,203 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::config::ServerConfig;
+use crate::credentials::UserDatabase;
+use crate::grants::GrantStore;
+use crate::shares::ShareDatabase;
+use crate::lockout::LoginThrottle;
+use crate::io_loc::{storage_paths, StoragePaths, quarantine_directory, user_database_path, file_owner_db_path, network_analyzer_path, resume_tokens_path, grants_path, grants_audit_path, shares_path, retention_path, proxy_config_path, audit_log_path, staging_registry_path, sqlite_database_path, trash_registry_path, trash_directory};
+use crate::storage::open_storage;
+use crate::io_tools::FileDatabase;
+use crate::resume::ResumeTokenStore;
+use crate::retention::RetentionManager;
+use crate::sessions::SessionManager;
+use crate::idempotency::IdempotencyCache;
+use crate::proxy::{Proxy, UpstreamConfig};
+use crate::middleware::Pipeline;
+use crate::staging::StagingRegistry;
+use crate::trash::TrashBin;
+use crate::quota::QuotaManager;
+use crate::watch::WatchHub;
+use crate::activity::ConnectionTracker;
+use crate::locks::LockManager;
+use crate::metrics::Metrics;
+use crate::shutdown::ShutdownController;
+use crate::quic::QuicPlane;
+use crate::backend::{FileBackend, LocalBackend, open_backend};
+use crate::hooks::Hooks;
+use crate::scanning::ContentScanning;
+use crate::usage::UsageCache;
+use crate::transfers::TransferScheduler;
+use hermes_common::network_stats::NetworkAnalyzer;
+use hermes_common::socket::SocketOptions;
+use hermes_common::tuning::FrameSizeBounds;
+
+// Everything connections share. It is held in an Arc by every connection task, and each store sits behind its own lock so a long write to one does not block the others.
+// Locks must never be held across network I/O.
+pub struct ServerState {
+    pub users: RwLock<UserDatabase>,
+    pub files: RwLock<FileDatabase>,
+    pub resume: RwLock<ResumeTokenStore>,
+    pub grants: RwLock<GrantStore>,
+    pub shares: RwLock<ShareDatabase>,
+    pub logins: RwLock<LoginThrottle>, //Failed logins, kept only in memory
+    pub retention: RwLock<RetentionManager>,
+    pub sessions: RwLock<SessionManager>,
+    pub idempotency: RwLock<IdempotencyCache>,
+    pub staging: RwLock<StagingRegistry>,
+    pub trash: RwLock<TrashBin>,
+    pub quotas: QuotaManager,
+    pub stats: NetworkAnalyzer,
+    pub frame_bounds: FrameSizeBounds,
+    pub socket_options: SocketOptions,
+    pub proxy: Option<Proxy>, //Set when this server is a read-only cache in front of another
+    pub pipeline: Pipeline,
+    pub watch: WatchHub, //Started once the server is listening, so tests and tools never watch anything
+    pub connections: ConnectionTracker, //Every open connection, for the administrators' view
+    pub locks: LockManager, //Paths in use by the transfers and changes in flight
+    pub transfers: TransferScheduler, //Transfers streaming now, and those queued for a slot
+    pub metrics: Metrics, //Counters for the metrics endpoint
+    pub paths: StoragePaths, //Where everything is kept, as installed at startup
+    pub shutdown: ShutdownController,
+    pub quic: QuicPlane, //Started once the server is listening, when it is configured
+    pub backend: Arc<dyn FileBackend>, //Where the shared files are kept for good, which is only the data directory unless configured
+    pub hooks: Hooks, //Registered before the server starts listening, and never changed after
+    pub scanning: Option<ContentScanning>, //What uploads are scanned with, when anything is configured
+    pub usage: UsageCache, //Remembers how much the top-level folders take, since walking them is slow
+    pub config: ServerConfig
+}
+impl Debug for ServerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ServerState")
+    }
+}
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ServerState {
+    pub fn new() -> Self {
+        Self {
+            users: RwLock::new(UserDatabase::new()),
+            files: RwLock::new(FileDatabase::new()),
+            resume: RwLock::new(ResumeTokenStore::default()),
+            grants: RwLock::new(GrantStore::new()),
+            shares: RwLock::new(ShareDatabase::new()),
+            logins: RwLock::new(LoginThrottle::default()),
+            retention: RwLock::new(RetentionManager::new()),
+            sessions: RwLock::new(SessionManager::default()),
+            idempotency: RwLock::new(IdempotencyCache::default()),
+            staging: RwLock::new(StagingRegistry::new()),
+            trash: RwLock::new(TrashBin::new()),
+            quotas: QuotaManager::default(),
+            stats: NetworkAnalyzer::new(),
+            frame_bounds: FrameSizeBounds::default(),
+            socket_options: SocketOptions::default(),
+            proxy: None,
+            pipeline: Pipeline::new(), //No stages, so nothing is rate limited or written to the audit log
+            watch: WatchHub::new(),
+            locks: LockManager::new(),
+            transfers: TransferScheduler::new(),
+            metrics: Metrics::new(),
+            connections: ConnectionTracker::new(),
+            paths: storage_paths(),
+            shutdown: ShutdownController::new(),
+            quic: QuicPlane::new(),
+            backend: Arc::new(LocalBackend::new(&storage_paths().root)),
+            hooks: Hooks::new(),
+            scanning: None,
+            usage: UsageCache::default(),
+            config: ServerConfig::default()
+        }
+    }
+
+    // Opens every store from where the installed storage paths put it. install_storage_paths and ensure_directories should be called first.
+    pub fn load(config: ServerConfig) -> Result<Self, String> {
+        let mut users = UserDatabase::new();
+        let mut files = FileDatabase::new();
+        let mut resume = ResumeTokenStore::default();
+        let mut grants = GrantStore::new();
+        let mut shares = ShareDatabase::new();
+        let mut retention = RetentionManager::new();
+        let mut staging = StagingRegistry::new();
+        let mut trash = TrashBin::new();
+        let stats = NetworkAnalyzer::new();
+
+        // The configuration says where the user and file databases are kept
+        let user_storage = open_storage(config.storage, &user_database_path(), &sqlite_database_path(), "users").map_err(|e| format!("unable to open the user database because '{e}'"))?;
+        let file_storage = open_storage(config.storage, &file_owner_db_path(), &sqlite_database_path(), "files").map_err(|e| format!("unable to open the file database because '{e}'"))?;
+
+        users.open_storage(user_storage).map_err(|e| format!("unable to open the user database because '{e}'"))?;
+        files.open_storage(file_storage).map_err(|e| format!("unable to open the file database because '{e}'"))?;
+        resume.open(&path_string(resume_tokens_path())).map_err(|e| format!("unable to open the resumption tokens because '{e}'"))?;
+        grants.open(&path_string(grants_path()), &grants_audit_path()).map_err(|e| format!("unable to open the upload grants because '{e}'"))?;
+        shares.open(&path_string(shares_path())).map_err(|e| format!("unable to open the share links because '{e}'"))?;
+        retention.open(&path_string(retention_path())).map_err(|e| format!("unable to open the retention policies because '{e}'"))?;
+        staging.open(&staging_registry_path()).map_err(|e| format!("unable to open the partial upload registry because '{e}'"))?;
+        trash.open(&trash_registry_path(), &trash_directory(), config.trash_retention()).map_err(|e| format!("unable to open the trash because '{e}'"))?;
+        stats.open_with(&path_string(network_analyzer_path()), config.stats.clone()).map_err(|e| format!("unable to open the network statistics because '{e}'"))?;
+
+        let backend = open_backend(&config.file_backend, &storage_paths().root).map_err(|e| format!("unable to open the file backend because {e}"))?;
+        let proxy = match UpstreamConfig::open(&proxy_config_path()).map_err(|e| format!("unable to open the proxy configuration because '{e}'"))? {
+            Some(c) => Some(Proxy::new(c)?),
+            None => None
+        };
+        let pipeline = Pipeline::configured(proxy.is_some(), audit_log_path(), &config.middleware).map_err(|e| format!("unable to set up the middleware because {e}"))?;
+
+        Ok(
+            Self {
+                users: RwLock::new(users),
+                files: RwLock::new(files),
+                resume: RwLock::new(resume),
+                grants: RwLock::new(grants),
+                shares: RwLock::new(shares),
+                logins: RwLock::new(LoginThrottle::new(config.lockout_threshold, config.lockout())),
+                retention: RwLock::new(retention),
+                sessions: RwLock::new(SessionManager::default()),
+                idempotency: RwLock::new(IdempotencyCache::default()),
+                staging: RwLock::new(staging),
+                trash: RwLock::new(trash),
+                quotas: QuotaManager::new(config.default_quota),
+                stats,
+                frame_bounds: FrameSizeBounds::default(),
+                socket_options: config.socket_options(),
+                pipeline,
+                proxy,
+                watch: WatchHub::new(),
+                locks: LockManager::new(),
+                transfers: TransferScheduler::new(),
+                metrics: Metrics::new(),
+                connections: ConnectionTracker::new(),
+                paths: storage_paths(),
+                shutdown: ShutdownController::new(),
+                quic: QuicPlane::new(),
+                backend,
+                hooks: Hooks::new(),
+                scanning: config.scanner.as_ref().map(|x| ContentScanning::from_config(x, &quarantine_directory())),
+                usage: UsageCache::new(config.usage_cache()),
+                config
+            }
+        )
+    }
+
+    // Writes every store back to disk, reporting the first failure
+    pub async fn save(&self) -> Result<(), String> {
+        self.users.read().await.save()?;
+        self.files.read().await.save()?;
+        self.resume.read().await.save()?;
+        self.grants.read().await.save()?;
+        self.shares.read().await.save()?;
+        self.retention.read().await.save()?;
+        self.staging.read().await.save()?;
+        self.trash.read().await.save()?;
+        self.stats.save()?;
+        Ok(())
+    }
+}
+
+fn path_string(path: std::path::PathBuf) -> String {
+    path.to_string_lossy().to_string()
+}
@@ -0,0 +1,317 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+use hermes_common::error::HermesError;
+
+// Which backend the user and file databases keep their records in
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Json,
+    Sqlite
+}
+
+// Something a store keeps, named by a unique key. The other fields are what a backend can index.
+pub trait Record: Serialize + DeserializeOwned {
+    fn key(&self) -> String;
+    fn owner(&self) -> Option<&str> {
+        None
+    }
+    fn kind(&self) -> Option<String> {
+        None
+    }
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+// Narrows a lookup down. Fields left as None match everything.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct RecordFilter {
+    pub owner: Option<String>,
+    pub path_prefix: Option<PathBuf>, //Matches the path itself and everything beneath it
+    pub kind: Option<String>
+}
+impl RecordFilter {
+    pub fn matches<R: Record>(&self, record: &R) -> bool {
+        self.owner.as_ref().is_none_or(|o| record.owner() == Some(o.as_str()))
+            && self.kind.as_ref().is_none_or(|k| record.kind().as_ref() == Some(k))
+            && self.path_prefix.as_ref().is_none_or(|p| record.path().is_some_and(|x| x.starts_with(p)))
+    }
+}
+
+pub trait Storage<R: Record>: Send + Sync {
+    fn load(&self) -> Result<Vec<R>, HermesError>;
+    // Stores the records given, and forgets any it held that are not among them
+    fn save(&self, records: &[R]) -> Result<(), HermesError>;
+    // Reads the matching records straight from storage. Backends with indexes override this.
+    fn find(&self, filter: &RecordFilter) -> Result<Vec<R>, HermesError> {
+        Ok(self.load()?.into_iter().filter(|x| filter.matches(x)).collect())
+    }
+    fn location(&self) -> &Path;
+}
+
+// The whole store as one JSON array, rewritten on every save
+pub struct JsonStorage {
+    path: PathBuf
+}
+impl JsonStorage {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf()
+        }
+    }
+}
+impl<R: Record> Storage<R> for JsonStorage {
+    fn load(&self) -> Result<Vec<R>, HermesError> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into())
+        };
+
+        if contents.trim().is_empty() {
+            Ok(Vec::new())
+        } else {
+            Ok(serde_json::from_str(&contents)?)
+        }
+    }
+    fn save(&self, records: &[R]) -> Result<(), HermesError> {
+        let contents = serde_json::to_string(records)?;
+        std::fs::write(&self.path, contents)?;
+        Ok(())
+    }
+    fn location(&self) -> &Path {
+        &self.path
+    }
+}
+
+// One table per store, keeping each record as JSON beside the columns it is indexed by. Saves only write the records
+// that changed since the last load or save, so a large store is not rewritten for every change.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    path: PathBuf,
+    table: &'static str,
+    connection: std::sync::Mutex<rusqlite::Connection>,
+    stored: std::sync::Mutex<std::collections::HashMap<String, String>> //Key to the JSON last written for it
+}
+#[cfg(feature = "sqlite")]
+fn sql_error(e: rusqlite::Error) -> HermesError {
+    HermesError::Io(std::io::Error::other(e))
+}
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    pub fn open(path: &Path, table: &'static str) -> Result<Self, HermesError> {
+        if !table.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+            return Err(HermesError::Invalid(format!("'{table}' is not a valid table name")));
+        }
+
+        let connection = rusqlite::Connection::open(path).map_err(sql_error)?;
+        connection.execute_batch(&format!(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS {table} (key TEXT PRIMARY KEY NOT NULL, owner TEXT, kind TEXT, path TEXT, record TEXT NOT NULL);
+            CREATE INDEX IF NOT EXISTS {table}_owner ON {table} (owner);
+            CREATE INDEX IF NOT EXISTS {table}_kind ON {table} (kind);
+            CREATE INDEX IF NOT EXISTS {table}_path ON {table} (path);"
+        )).map_err(sql_error)?;
+
+        Ok(
+            Self {
+                path: path.to_path_buf(),
+                table,
+                connection: std::sync::Mutex::new(connection),
+                stored: std::sync::Mutex::new(std::collections::HashMap::new())
+            }
+        )
+    }
+
+    fn query<R: Record>(&self, clause: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<R>, HermesError> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection.prepare(&format!("SELECT record FROM {} {clause} ORDER BY rowid", self.table)).map_err(sql_error)?;
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0)).map_err(sql_error)?;
+
+        let mut result = Vec::new();
+        for row in rows {
+            result.push(serde_json::from_str(&row.map_err(sql_error)?)?);
+        }
+
+        Ok(result)
+    }
+}
+#[cfg(feature = "sqlite")]
+impl<R: Record> Storage<R> for SqliteStorage {
+    fn load(&self) -> Result<Vec<R>, HermesError> {
+        let records: Vec<R> = self.query("", &[])?;
+
+        let mut stored = self.stored.lock().unwrap_or_else(|e| e.into_inner());
+        stored.clear();
+        for record in &records {
+            stored.insert(record.key(), serde_json::to_string(record)?);
+        }
+
+        Ok(records)
+    }
+    fn save(&self, records: &[R]) -> Result<(), HermesError> {
+        let mut stored = self.stored.lock().unwrap_or_else(|e| e.into_inner());
+        let mut current = std::collections::HashMap::with_capacity(records.len());
+        let mut changed = Vec::new();
+        for record in records {
+            let key = record.key();
+            let json = serde_json::to_string(record)?;
+            if stored.get(&key) != Some(&json) {
+                changed.push((record, key.clone(), json.clone()));
+            }
+            current.insert(key, json);
+        }
+        let removed: Vec<&String> = stored.keys().filter(|x| !current.contains_key(*x)).collect();
+
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction().map_err(sql_error)?;
+        {
+            // Updating in place keeps each record's row, and with it the order records load in
+            let mut upsert = transaction.prepare(&format!("INSERT INTO {} (key, owner, kind, path, record) VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (key) DO UPDATE SET owner = excluded.owner, kind = excluded.kind, path = excluded.path, record = excluded.record", self.table)).map_err(sql_error)?;
+            for (record, key, json) in &changed {
+                let path = record.path().map(|x| x.to_string_lossy().to_string());
+                upsert.execute(rusqlite::params![key, record.owner(), record.kind(), path, json]).map_err(sql_error)?;
+            }
+
+            let mut delete = transaction.prepare(&format!("DELETE FROM {} WHERE key = ?1", self.table)).map_err(sql_error)?;
+            for key in removed {
+                delete.execute([key]).map_err(sql_error)?;
+            }
+        }
+        transaction.commit().map_err(sql_error)?;
+
+        *stored = current;
+        Ok(())
+    }
+    fn find(&self, filter: &RecordFilter) -> Result<Vec<R>, HermesError> {
+        let mut clauses = Vec::new();
+        let mut params: Vec<String> = Vec::new();
+        if let Some(owner) = filter.owner.as_ref() {
+            params.push(owner.clone());
+            clauses.push(format!("owner = ?{}", params.len()));
+        }
+        if let Some(kind) = filter.kind.as_ref() {
+            params.push(kind.clone());
+            clauses.push(format!("kind = ?{}", params.len()));
+        }
+        // Everything beneath a path sorts between 'path/' and 'path0', since '0' follows '/', so the path index covers the range
+        if let Some(prefix) = filter.path_prefix.as_ref() {
+            let prefix = prefix.to_string_lossy().trim_end_matches('/').to_string();
+            params.push(prefix.clone());
+            params.push(format!("{prefix}/"));
+            params.push(format!("{prefix}0"));
+            let n = params.len();
+            clauses.push(format!("(path = ?{} OR (path >= ?{} AND path < ?{}))", n - 2, n - 1, n));
+        }
+
+        let clause = if clauses.is_empty() { String::new() } else { format!("WHERE {}", clauses.join(" AND ")) };
+        let params: Vec<&dyn rusqlite::ToSql> = params.iter().map(|x| x as &dyn rusqlite::ToSql).collect();
+        self.query(&clause, &params)
+    }
+    fn location(&self) -> &Path {
+        &self.path
+    }
+}
+
+// Opens a store in the chosen backend. The first time SQLite is used, the records of the JSON store it replaces are copied
+// into it, and the JSON file is left where it was.
+pub fn open_storage<R: Record + 'static>(backend: StorageBackend, json_path: &Path, sqlite_path: &Path, table: &'static str) -> Result<Box<dyn Storage<R>>, HermesError> {
+    match backend {
+        StorageBackend::Json => Ok(Box::new(JsonStorage::new(json_path))),
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => {
+            let storage = SqliteStorage::open(sqlite_path, table)?;
+            let existing: Vec<R> = storage.load()?;
+            if existing.is_empty() {
+                let legacy: Vec<R> = JsonStorage::new(json_path).load()?;
+                if !legacy.is_empty() {
+                    storage.save(&legacy)?;
+                }
+            }
+
+            Ok(Box::new(storage))
+        },
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => {
+            let _ = (sqlite_path, table);
+            Err(HermesError::Invalid(String::from("this server was built without SQLite support, rebuild it with '--features sqlite'")))
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct TestItem {
+    id: u32,
+    owner: String,
+    path: PathBuf
+}
+#[cfg(test)]
+impl Record for TestItem {
+    fn key(&self) -> String {
+        self.id.to_string()
+    }
+    fn owner(&self) -> Option<&str> {
+        Some(&self.owner)
+    }
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+#[cfg(test)]
+fn test_item(id: u32, owner: &str, path: &str) -> TestItem {
+    TestItem { id, owner: owner.to_string(), path: PathBuf::from(path) }
+}
+
+#[test]
+fn test_json_storage() {
+    let path = std::env::temp_dir().join(format!("hermes_storage_{}.json", std::process::id()));
+    let storage = JsonStorage::new(&path);
+    assert_eq!(Storage::<TestItem>::load(&storage).unwrap(), vec![]);
+
+    let items = vec![test_item(1, "alice", "/data/docs"), test_item(2, "alice", "/data/docs/a.txt"), test_item(3, "bob", "/data/docs-old/b.txt")];
+    storage.save(&items).unwrap();
+    assert_eq!(Storage::<TestItem>::load(&storage).unwrap(), items);
+
+    // A sibling that only shares the prefix is not beneath it
+    let found: Vec<TestItem> = storage.find(&RecordFilter { owner: Some(String::from("alice")), path_prefix: Some(PathBuf::from("/data/docs")), ..Default::default() }).unwrap();
+    assert_eq!(found, items[..2].to_vec());
+    assert!(Storage::<TestItem>::find(&storage, &RecordFilter { kind: Some(String::from("Text")), ..Default::default() }).unwrap().is_empty());
+
+    let _ = std::fs::remove_file(&path);
+}
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_storage() {
+    let base = std::env::temp_dir().join(format!("hermes_sqlite_{}", std::process::id()));
+    std::fs::create_dir_all(&base).unwrap();
+
+    // Records of the JSON store are carried over the first time
+    let legacy = vec![test_item(1, "alice", "/data/docs"), test_item(2, "alice", "/data/docs/a.txt"), test_item(3, "bob", "/data/docs-old/b.txt")];
+    JsonStorage::new(&base.join("items.json")).save(&legacy).unwrap();
+    let storage = open_storage::<TestItem>(StorageBackend::Sqlite, &base.join("items.json"), &base.join("hermes.db"), "items").unwrap();
+    assert_eq!(storage.load().unwrap(), legacy);
+
+    // Lookups go through the indexes, and give the same answers as the JSON store
+    let by_path = storage.find(&RecordFilter { path_prefix: Some(PathBuf::from("/data/docs")), ..Default::default() }).unwrap();
+    assert_eq!(by_path, legacy[..2].to_vec());
+    let by_owner = storage.find(&RecordFilter { owner: Some(String::from("bob")), ..Default::default() }).unwrap();
+    assert_eq!(by_owner, vec![legacy[2].clone()]);
+
+    // Changes and removals are written, and reopening sees them without copying the JSON store again
+    let updated = vec![test_item(1, "carol", "/data/docs"), legacy[2].clone()];
+    storage.save(&updated).unwrap();
+    drop(storage);
+    let storage = open_storage::<TestItem>(StorageBackend::Sqlite, &base.join("items.json"), &base.join("hermes.db"), "items").unwrap();
+    assert_eq!(storage.load().unwrap(), updated);
+
+    let _ = std::fs::remove_dir_all(&base);
+}
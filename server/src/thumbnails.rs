@@ -0,0 +1,190 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use hermes_common::checksum::{ChecksumAlgorithm, checksum_bytes};
+use hermes_common::file_io::FileType;
+use hermes_common::http_codes::HttpCodes;
+
+pub const DEFAULT_THUMBNAIL_SIZE: u32 = 128;
+pub const MAX_THUMBNAIL_SIZE: u32 = 512;
+// Audio and video are read through this, which has to be on the server's PATH
+#[cfg(feature = "thumbnails")]
+const FFMPEG: &str = "ffmpeg";
+
+// Why a file has no thumbnail
+#[derive(Debug, PartialEq)]
+pub enum ThumbnailError {
+    Disabled, //The server was built without the thumbnails feature
+    Unsupported(FileType),
+    Failed(String)
+}
+impl Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "this server was built without thumbnail support, rebuild it with '--features thumbnails'"),
+            Self::Unsupported(kind) => write!(f, "there are no thumbnails for {kind} files"),
+            Self::Failed(reason) => write!(f, "unable to make a thumbnail because '{reason}'")
+        }
+    }
+}
+impl ThumbnailError {
+    pub fn status(&self) -> HttpCodes {
+        match self {
+            Self::Disabled | Self::Unsupported(_) => HttpCodes::BadRequest,
+            Self::Failed(_) => HttpCodes::Conflict
+        }
+    }
+}
+
+// Thumbnails are PNGs named after the file's path, size, modification time, and the edge asked for,
+// so a file that changes gets a new one. The cache can be emptied at any time.
+pub struct ThumbnailCache {
+    directory: PathBuf
+}
+impl ThumbnailCache {
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_path_buf()
+        }
+    }
+
+    fn entry(&self, path: &Path, edge: u32) -> std::io::Result<PathBuf> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let key = checksum_bytes(format!("{}:{}:{modified}:{edge}", path.display(), metadata.len()).as_bytes(), ChecksumAlgorithm::Sha256);
+
+        Ok(self.directory.join(format!("{}.png", key.value())))
+    }
+
+    // Made the first time it is asked for, and read from the cache after that. The edge is clamped to MAX_THUMBNAIL_SIZE.
+    pub fn thumbnail(&self, path: &Path, kind: &FileType, edge: u32) -> Result<Vec<u8>, ThumbnailError> {
+        let edge = edge.clamp(1, MAX_THUMBNAIL_SIZE);
+        let entry = self.entry(path, edge).map_err(|e| ThumbnailError::Failed(e.to_string()))?;
+        if let Ok(cached) = std::fs::read(&entry) {
+            return Ok(cached);
+        }
+
+        let png = generate(path, kind, edge)?;
+        // Written beside its name first, so a reader never finds half of one
+        let partial = entry.with_extension("part");
+        let stored = std::fs::create_dir_all(&self.directory)
+            .and_then(|_| std::fs::write(&partial, &png))
+            .and_then(|_| std::fs::rename(&partial, &entry));
+        if let Err(e) = stored {
+            tracing::warn!(path = %entry.display(), "unable to cache a thumbnail because '{e}'");
+        }
+
+        Ok(png)
+    }
+}
+
+// Images are scaled down, video gets a representative frame, and audio gets its waveform
+#[cfg(feature = "thumbnails")]
+fn generate(path: &Path, kind: &FileType, edge: u32) -> Result<Vec<u8>, ThumbnailError> {
+    let failed = |e: image::ImageError| ThumbnailError::Failed(e.to_string());
+    let image = match kind {
+        FileType::Other(mime) if mime.starts_with("image/") => image::ImageReader::open(path)
+            .map_err(|e| ThumbnailError::Failed(e.to_string()))?
+            .with_guessed_format()
+            .map_err(|e| ThumbnailError::Failed(e.to_string()))?
+            .decode()
+            .map_err(failed)?,
+        FileType::Video => {
+            let frame = ffmpeg(path, &["-vf", "thumbnail", "-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"])?;
+            image::load_from_memory(&frame).map_err(failed)?
+        },
+        FileType::Audio => {
+            let pcm = ffmpeg(path, &["-ac", "1", "-ar", "4000", "-f", "s16le", "-"])?;
+            let samples: Vec<i16> = pcm.chunks_exact(2).map(|x| i16::from_le_bytes([x[0], x[1]])).collect();
+            image::DynamicImage::ImageLuma8(draw_waveform(&samples, edge))
+        },
+        kind => return Err(ThumbnailError::Unsupported(kind.clone()))
+    };
+
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.thumbnail(edge, edge).write_to(&mut png, image::ImageFormat::Png).map_err(failed)?;
+    Ok(png.into_inner())
+}
+#[cfg(not(feature = "thumbnails"))]
+fn generate(_path: &Path, _kind: &FileType, _edge: u32) -> Result<Vec<u8>, ThumbnailError> {
+    Err(ThumbnailError::Disabled)
+}
+
+// Decodes the file with ffmpeg, and answers with what it wrote to stdout
+#[cfg(feature = "thumbnails")]
+fn ffmpeg(path: &Path, output: &[&str]) -> Result<Vec<u8>, ThumbnailError> {
+    let result = std::process::Command::new(FFMPEG)
+        .args(["-v", "error", "-nostdin", "-i"])
+        .arg(path)
+        .args(output)
+        .stderr(std::process::Stdio::piped())
+        .output()
+        .map_err(|e| ThumbnailError::Failed(format!("unable to run {FFMPEG}: {e}")))?;
+
+    match result.status.success() && !result.stdout.is_empty() {
+        true => Ok(result.stdout),
+        false => Err(ThumbnailError::Failed(format!("{FFMPEG} could not read the file: {}", String::from_utf8_lossy(&result.stderr).trim())))
+    }
+}
+
+// One column per slice of the samples, as tall as the loudest sample in it, dark on white and centred on the middle line
+#[cfg(feature = "thumbnails")]
+fn draw_waveform(samples: &[i16], edge: u32) -> image::GrayImage {
+    let (width, height) = (edge, (edge / 2).max(1));
+    let mut image = image::GrayImage::from_pixel(width, height, image::Luma([255]));
+    let per_column = samples.len().div_ceil(width as usize).max(1);
+    for (x, column) in samples.chunks(per_column).enumerate() {
+        let peak = column.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0) as u32;
+        let half = peak * height / (2 * (i16::MAX as u32 + 1));
+        let middle = height / 2;
+        for y in middle.saturating_sub(half)..=(middle + half).min(height - 1) {
+            image.put_pixel(x as u32, y, image::Luma([40]));
+        }
+    }
+
+    image
+}
+
+#[cfg(not(feature = "thumbnails"))]
+#[test]
+fn test_thumbnails_disabled() {
+    let dir = std::env::temp_dir().join(format!("hermes_thumbnails_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.png"), b"not really").unwrap();
+
+    let cache = ThumbnailCache::new(&dir.join(".thumbs"));
+    let result = cache.thumbnail(&dir.join("a.png"), &FileType::Other(String::from("image/png")), DEFAULT_THUMBNAIL_SIZE);
+    assert_eq!(result.map_err(|e| e.status()), Err(HttpCodes::BadRequest));
+    assert!(!dir.join(".thumbs").exists());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+#[cfg(feature = "thumbnails")]
+#[test]
+fn test_thumbnails() {
+    let dir = std::env::temp_dir().join(format!("hermes_thumbnails_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    image::RgbImage::from_pixel(400, 200, image::Rgb([200, 10, 10])).save(dir.join("wide.png")).unwrap();
+
+    // Scaled to fit the edge, keeping its shape, and kept for the next time
+    let cache = ThumbnailCache::new(&dir.join(".thumbs"));
+    let png = cache.thumbnail(&dir.join("wide.png"), &FileType::Other(String::from("image/png")), 100).unwrap();
+    let thumbnail = image::load_from_memory(&png).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (100, 50));
+    assert_eq!(std::fs::read_dir(dir.join(".thumbs")).unwrap().count(), 1);
+    assert_eq!(cache.thumbnail(&dir.join("wide.png"), &FileType::Other(String::from("image/png")), 100).unwrap(), png);
+
+    std::fs::write(dir.join("notes.txt"), "text").unwrap();
+    assert_eq!(cache.thumbnail(&dir.join("notes.txt"), &FileType::Text, 100), Err(ThumbnailError::Unsupported(FileType::Text)));
+
+    // Silence is a flat line, and a loud stretch reaches the edges
+    let mut samples = vec![0i16; 1000];
+    samples[500..600].fill(i16::MAX);
+    let waveform = draw_waveform(&samples, 10);
+    assert_eq!((waveform.width(), waveform.height()), (10, 5));
+    assert_eq!((waveform.get_pixel(0, 0).0[0], waveform.get_pixel(0, 2).0[0]), (255, 40));
+    assert_eq!((waveform.get_pixel(5, 0).0[0], waveform.get_pixel(5, 4).0[0]), (40, 40));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
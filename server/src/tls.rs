@@ -0,0 +1,42 @@
+use rustls::ServerConfig;
+use serde::{Serialize, Deserialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::io_loc::{tls_cert_path, tls_key_path, tls_client_ca_path};
+use hermes_common::transport::server_tls_config;
+
+// Where the certificate and keys are read from, when they are not in the tls directory of the host directory
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TlsPaths {
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>
+}
+
+// TLS is turned on by placing cert.pem and key.pem in the tls directory of the host directory, or by naming them in the configuration. Adding client_ca.pem also requires clients to present a certificate signed by it.
+// Returns None when no certificate is installed, meaning the server runs over plain TCP. Files named in the configuration must exist.
+pub fn load_server_tls(paths: &TlsPaths) -> Result<Option<Arc<ServerConfig>>, String> {
+    let cert = paths.cert.clone().unwrap_or_else(tls_cert_path);
+    let key = paths.key.clone().unwrap_or_else(tls_key_path);
+    let configured = paths.cert.is_some() || paths.key.is_some();
+
+    match (cert.exists(), key.exists()) {
+        (false, false) if !configured => Ok(None),
+        (false, false) => Err(format!("neither '{}' nor '{}' exist", cert.display(), key.display())),
+        (true, true) => {
+            let client_ca = match paths.client_ca.clone() {
+                Some(c) if !c.exists() => return Err(format!("'{}' does not exist", c.display())),
+                Some(c) => Some(c),
+                None => Some(tls_client_ca_path()).filter(|x| x.exists())
+            };
+
+            server_tls_config(&cert, &key, client_ca.as_deref()).map(Some)
+        },
+        (true, false) => Err(format!("found '{}' but not '{}'", cert.display(), key.display())),
+        (false, true) => Err(format!("found '{}' but not '{}'", key.display(), cert.display()))
+    }
+}
@@ -0,0 +1,190 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+pub const DEFAULT_MAX_ACTIVE_TRANSFERS: usize = 32;
+pub const DEFAULT_SMALL_TRANSFER: u64 = 1024 * 1024;
+
+fn default_max_active() -> usize {
+    DEFAULT_MAX_ACTIVE_TRANSFERS
+}
+fn default_small_file_bytes() -> u64 {
+    DEFAULT_SMALL_TRANSFER
+}
+
+// Which transfers start first when there is no room for all of them. Within a priority, transfers start in the order they were asked for.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum TransferPriority {
+    Interactive, //Someone is waiting on it, such as a file fetched from the shell
+    Small,
+    Bulk //Large files, and whole directories
+}
+
+// How many transfers stream their frames at once, across every connection, before the rest wait their turn
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TransferLimits {
+    #[serde(default = "default_max_active")]
+    pub max_active: usize, //Zero starts every transfer straight away
+    #[serde(default = "default_small_file_bytes")]
+    pub small_file_bytes: u64 //Files no larger than this go ahead of larger ones
+}
+impl Default for TransferLimits {
+    fn default() -> Self {
+        Self {
+            max_active: default_max_active(),
+            small_file_bytes: default_small_file_bytes()
+        }
+    }
+}
+impl TransferLimits {
+    // A directory is always bulk, however it was asked for, so only single files can go ahead for being interactive
+    pub fn priority(&self, size: u64, interactive: bool, directory: bool) -> TransferPriority {
+        match (directory, interactive) {
+            (true, _) => TransferPriority::Bulk,
+            (false, true) => TransferPriority::Interactive,
+            (false, false) if size <= self.small_file_bytes => TransferPriority::Small,
+            (false, false) => TransferPriority::Bulk
+        }
+    }
+}
+
+// Keeps the number of transfers streaming at once under a limit, queueing the rest by priority.
+// A queued transfer is started by its own connection once nothing is ahead of it and a slot is free, so nothing has to hand slots out.
+#[derive(Default, Debug)]
+pub struct TransferScheduler {
+    queue: Mutex<Queue>,
+    changed: Notify //Woken whenever a transfer starts, finishes, or leaves the queue, since any of them can move the others up
+}
+#[derive(Default, Debug)]
+struct Queue {
+    next_ticket: u64,
+    active: usize,
+    waiting: BTreeSet<(TransferPriority, u64)>
+}
+impl TransferScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Joins the queue behind everything of the same or a higher priority
+    pub fn enqueue(&self, priority: TransferPriority) -> QueuedTransfer<'_> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.next_ticket += 1;
+        let key = (priority, queue.next_ticket);
+        queue.waiting.insert(key);
+
+        QueuedTransfer { scheduler: self, key }
+    }
+
+    pub fn active(&self) -> usize {
+        self.queue.lock().unwrap().active
+    }
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().waiting.len()
+    }
+}
+
+// A transfer waiting for a slot. Dropping it leaves the queue.
+#[derive(Debug)]
+pub struct QueuedTransfer<'a> {
+    scheduler: &'a TransferScheduler,
+    key: (TransferPriority, u64)
+}
+impl<'a> QueuedTransfer<'a> {
+    // Starts the transfer when nothing is ahead of it and fewer than max_active are streaming, or says where it is in the queue. Position 1 is next.
+    pub fn try_start(&mut self, max_active: usize) -> Result<TransferSlot<'a>, u64> {
+        {
+            let mut queue = self.scheduler.queue.lock().unwrap();
+            let position = queue.waiting.iter().take_while(|x| **x != self.key).count() as u64 + 1;
+            if position > 1 || (max_active != 0 && queue.active >= max_active) {
+                return Err(position);
+            }
+
+            queue.waiting.remove(&self.key);
+            queue.active += 1;
+        }
+
+        // Everyone behind it has moved up
+        self.scheduler.changed.notify_waiters();
+        Ok(TransferSlot { scheduler: self.scheduler })
+    }
+
+    // Waits until the transfer starts, or its position is no longer last. Nothing is lost when this is dropped part way, so it can be raced against other work.
+    pub async fn wait(&mut self, max_active: usize, last: u64) -> Result<TransferSlot<'a>, u64> {
+        loop {
+            // Listening before trying, so a change in between is not missed
+            let changed = self.scheduler.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            match self.try_start(max_active) {
+                Ok(slot) => return Ok(slot),
+                Err(position) if position != last => return Err(position),
+                Err(_) => changed.await
+            }
+        }
+    }
+}
+impl Drop for QueuedTransfer<'_> {
+    fn drop(&mut self) {
+        let left = self.scheduler.queue.lock().unwrap().waiting.remove(&self.key);
+        if left {
+            self.scheduler.changed.notify_waiters();
+        }
+    }
+}
+
+// A transfer that is streaming. Dropping it frees the slot for the next in the queue.
+#[derive(Debug)]
+pub struct TransferSlot<'a> {
+    scheduler: &'a TransferScheduler
+}
+impl Drop for TransferSlot<'_> {
+    fn drop(&mut self) {
+        self.scheduler.queue.lock().unwrap().active -= 1;
+        self.scheduler.changed.notify_waiters();
+    }
+}
+
+#[tokio::test]
+async fn test_transfer_scheduler() {
+    use std::time::Duration;
+
+    let limits: TransferLimits = toml::from_str("max_active = 1").unwrap();
+    assert_eq!(limits.priority(10, false, false), TransferPriority::Small);
+    assert_eq!(limits.priority(DEFAULT_SMALL_TRANSFER + 1, false, false), TransferPriority::Bulk);
+    assert_eq!(limits.priority(DEFAULT_SMALL_TRANSFER + 1, true, false), TransferPriority::Interactive);
+    assert_eq!(limits.priority(10, true, true), TransferPriority::Bulk);
+
+    // Past the limit transfers queue, and smaller ones go ahead of bulk ones asked for earlier
+    let scheduler = TransferScheduler::new();
+    let running = scheduler.enqueue(TransferPriority::Bulk).try_start(1).unwrap();
+    let mut bulk = scheduler.enqueue(TransferPriority::Bulk);
+    let mut small = scheduler.enqueue(TransferPriority::Small);
+    let mut interactive = scheduler.enqueue(TransferPriority::Interactive);
+    assert_eq!(bulk.try_start(1).unwrap_err(), 3);
+    assert_eq!(small.try_start(1).unwrap_err(), 2);
+    assert_eq!(interactive.try_start(1).unwrap_err(), 1);
+    assert_eq!((scheduler.active(), scheduler.queued()), (1, 3));
+
+    // A waiter hears when it moves up, and starts once the slot ahead of it is freed
+    drop(interactive);
+    assert_eq!(small.wait(1, 2).await.unwrap_err(), 1);
+    let (started, _) = tokio::join!(
+        small.wait(1, 1),
+        async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(running);
+        }
+    );
+    let started = started.unwrap();
+    assert_eq!(bulk.try_start(1).unwrap_err(), 1);
+    assert_eq!((scheduler.active(), scheduler.queued()), (1, 1));
+
+    // Without a limit, nothing waits
+    let unlimited = bulk.try_start(0).unwrap();
+    assert_eq!((scheduler.active(), scheduler.queued()), (2, 0));
+    drop((started, unlimited));
+    assert_eq!(scheduler.active(), 0);
+}
@@ -0,0 +1,271 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::credentials::Credentials;
+use crate::io_tools::{FileDatabase, ServerFile};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::session::unix_now;
+
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// What a delete moved into the trash, and who deleted it. The file database records go with it, so a restore brings back owners, shares, tags, and versions.
+#[derive(Serialize, Deserialize)]
+pub struct Tombstone {
+    id: u64,
+    original: PathBuf,
+    trashed: PathBuf,
+    deleted_at: u64,
+    owner: String, //Whose trash it sits in, which is whoever deleted it
+    entries: u64, //Files and directories, counted the way a delete counts them
+    #[serde(default)]
+    records: Vec<ServerFile>
+}
+impl Tombstone {
+    pub fn original(&self) -> &Path {
+        &self.original
+    }
+    pub fn deleted_at(&self) -> u64 {
+        self.deleted_at
+    }
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+}
+
+// What a purge or an expiry removed for good
+#[derive(Default, PartialEq, Debug)]
+pub struct PurgeReport {
+    pub purged: Vec<PathBuf>, //Original paths
+    pub reclaimed_bytes: u64
+}
+
+// Counts the files and directories at and beneath a path, and the bytes they hold. Symbolic links are counted, never followed.
+fn tree_size(path: &Path) -> (u64, u64) {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return (0, 0)
+    };
+    if !metadata.is_dir() {
+        return (1, metadata.len());
+    }
+
+    let mut total = (1, 0);
+    for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+        let (entries, bytes) = tree_size(&entry.path());
+        total.0 += entries;
+        total.1 += bytes;
+    }
+
+    total
+}
+
+// Deleted files are moved under a folder per user in the host directory, one folder per delete, so the same name can be deleted twice.
+// The tombstones are kept in their own registry, which is replaced atomically like the staging registry.
+#[derive(Default)]
+pub struct TrashBin {
+    path: Option<PathBuf>,
+    directory: Option<PathBuf>,
+    retention: Duration,
+    tombstones: Vec<Tombstone>
+}
+impl TrashBin {
+    // A trash that is never opened is disabled, so deletes are permanent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, path: &Path, directory: &Path, retention: Duration) -> Result<(), String> {
+        self.tombstones = match std::fs::read_to_string(path) {
+            Ok(c) if c.trim().is_empty() => Vec::new(),
+            Ok(c) => serde_json::from_str(&c).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string())
+        };
+
+        self.path = Some(path.to_path_buf());
+        self.directory = Some(directory.to_path_buf());
+        self.retention = retention;
+        Ok(())
+    }
+    pub fn save(&self) -> Result<(), String> {
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(())
+        };
+
+        let contents = serde_json::to_string(&self.tombstones).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, contents).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, path).map_err(|e| e.to_string())
+    }
+
+    // A retention of zero turns the trash off
+    pub fn is_enabled(&self) -> bool {
+        self.directory.is_some() && !self.retention.is_zero()
+    }
+    pub fn tombstones(&self) -> &Vec<Tombstone> {
+        &self.tombstones
+    }
+    pub fn len(&self) -> usize {
+        self.tombstones.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.tombstones.iter().map(|x| x.id).max().unwrap_or_default() + 1
+    }
+
+    // Moves a path that passed its delete checks into the user's trash, taking its records out of the file database. Returns how many entries went.
+    pub fn discard(&mut self, path: &Path, user: &Credentials, files: &mut FileDatabase) -> Result<u64, String> {
+        let directory = self.directory.as_ref().ok_or_else(|| String::from("the trash is not open"))?;
+        let name = path.file_name().ok_or_else(|| String::from("path has no name"))?;
+        if !matches!(Path::new(user.username()).components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+            return Err(String::from("the username cannot name a trash folder"));
+        }
+
+        let id = self.next_id();
+        let folder = directory.join(user.username()).join(id.to_string());
+        std::fs::create_dir_all(&folder).map_err(|e| e.to_string())?;
+
+        let trashed = folder.join(name);
+        let (entries, _) = tree_size(path);
+        if let Err(e) = std::fs::rename(path, &trashed) {
+            let _ = std::fs::remove_dir(&folder);
+            return Err(e.to_string());
+        }
+
+        self.tombstones.push(
+            Tombstone {
+                id,
+                original: path.to_path_buf(),
+                trashed,
+                deleted_at: unix_now(),
+                owner: user.username().to_string(),
+                entries,
+                records: files.take_tree(path)
+            }
+        );
+        Ok(entries)
+    }
+
+    // Puts the most recent delete of a path back where it was. Only whoever deleted it, or an administrator, can restore it.
+    pub fn restore(&mut self, original: &Path, user: &Credentials, files: &mut FileDatabase) -> Result<u64, (HttpCodes, String)> {
+        let index = self.tombstones.iter()
+            .enumerate()
+            .filter(|(_, x)| x.original == original && (x.owner == user.username() || user.is_admin()))
+            .max_by_key(|(_, x)| x.id)
+            .map(|(i, _)| i)
+            .ok_or_else(|| (HttpCodes::NotFound, String::from("nothing deleted at that path is in the trash")))?;
+
+        if std::fs::symlink_metadata(original).is_ok() {
+            return Err((HttpCodes::Conflict, String::from("something already exists at that path, move it out of the way first")));
+        }
+        if !original.parent().is_some_and(|x| x.is_dir()) {
+            return Err((HttpCodes::Conflict, String::from("the folder it was deleted from no longer exists")));
+        }
+
+        std::fs::rename(&self.tombstones[index].trashed, original).map_err(|e| (HttpCodes::Conflict, e.to_string()))?;
+
+        let tombstone = self.tombstones.remove(index);
+        if let Some(folder) = tombstone.trashed.parent() {
+            let _ = std::fs::remove_dir(folder);
+        }
+        files.restore_tree(tombstone.records);
+        Ok(tombstone.entries)
+    }
+
+    // Removes a user's trash for good, or only what they deleted at or beneath a path
+    pub fn purge(&mut self, user: &Credentials, under: Option<&Path>) -> PurgeReport {
+        self.remove_where(|x| x.owner == user.username() && under.is_none_or(|p| x.original.starts_with(p)))
+    }
+    // Removes everything that has been in the trash longer than the retention period, and forgets tombstones whose files were removed by hand
+    pub fn expire(&mut self) -> PurgeReport {
+        let now = unix_now();
+        let retention = self.retention.as_secs();
+        self.remove_where(|x| now.saturating_sub(x.deleted_at) >= retention || std::fs::symlink_metadata(&x.trashed).is_err())
+    }
+
+    fn remove_where(&mut self, selected: impl Fn(&Tombstone) -> bool) -> PurgeReport {
+        let mut report = PurgeReport::default();
+        self.tombstones.retain_mut(|x| {
+            if !selected(x) {
+                return true;
+            }
+
+            let (_, bytes) = tree_size(&x.trashed);
+            let result = match x.trashed.parent() {
+                Some(folder) => std::fs::remove_dir_all(folder),
+                None => Ok(())
+            };
+            match result {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => true,
+                _ => {
+                    x.records.iter_mut().for_each(|r| r.discard_versions());
+                    report.purged.push(x.original.clone());
+                    report.reclaimed_bytes += bytes;
+                    false
+                }
+            }
+        });
+
+        report
+    }
+}
+
+#[test]
+fn test_trash_round_trip() {
+    use hermes_common::file_io::FileType;
+
+    let dir = std::env::temp_dir().join(format!("hermes_trash_{}", std::process::id()));
+    let root = dir.join("data");
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(dir.join("files.json"), "").unwrap();
+    std::fs::write(root.join("docs").join("a.txt"), b"hello").unwrap();
+    std::fs::write(root.join("b.txt"), b"abc").unwrap();
+
+    let alice = Credentials::new(String::from("alice"), String::from("pass"));
+    let bob = Credentials::new(String::from("bob"), String::from("pass"));
+    let mut files = FileDatabase::new();
+    files.open(&dir.join("files.json").to_string_lossy()).unwrap();
+    files.register_file(root.join("docs"), Some(alice.clone()), FileType::Binary).unwrap();
+    files.register_file(root.join("docs").join("a.txt"), Some(alice.clone()), FileType::Text).unwrap();
+
+    let mut trash = TrashBin::new();
+    assert!(!trash.is_enabled());
+    trash.open(&dir.join("trash.json"), &dir.join(".trash"), Duration::from_secs(60)).unwrap();
+    assert!(trash.is_enabled());
+
+    assert_eq!(trash.discard(&root.join("docs"), &alice, &mut files), Ok(2));
+    assert_eq!(trash.discard(&root.join("b.txt"), &alice, &mut files), Ok(1));
+    assert!(!root.join("docs").exists());
+    assert!(files.get_file_by_path(&root.join("docs").join("a.txt")).is_none());
+    trash.save().unwrap();
+
+    // A fresh open sees what was saved, and only whoever deleted a path can restore it
+    let mut trash = TrashBin::new();
+    trash.open(&dir.join("trash.json"), &dir.join(".trash"), Duration::from_secs(60)).unwrap();
+    assert_eq!(trash.len(), 2);
+    assert_eq!(trash.restore(&root.join("docs"), &bob, &mut files).unwrap_err().0, HttpCodes::NotFound);
+    assert_eq!(trash.restore(&root.join("docs"), &alice, &mut files), Ok(2));
+    assert_eq!(std::fs::read(root.join("docs").join("a.txt")).unwrap(), b"hello");
+    assert!(files.get_file_by_path(&root.join("docs").join("a.txt")).is_some_and(|x| x.is_owned_by(&alice)));
+
+    // Restoring over something new is refused
+    std::fs::write(root.join("b.txt"), b"new").unwrap();
+    assert_eq!(trash.restore(&root.join("b.txt"), &alice, &mut files).unwrap_err().0, HttpCodes::Conflict);
+
+    assert_eq!(trash.purge(&bob, None), PurgeReport::default());
+    assert_eq!(trash.expire(), PurgeReport::default());
+    trash.tombstones[0].deleted_at = 0;
+    assert_eq!(trash.expire(), PurgeReport { purged: vec![root.join("b.txt")], reclaimed_bytes: 3 });
+    assert!(trash.is_empty());
+    assert!(!dir.join(".trash").join("alice").join("2").exists());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
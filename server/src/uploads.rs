@@ -0,0 +1,128 @@
+use serde::{Serialize, Deserialize};
+use std::io::Read;
+use std::path::Path;
+
+use hermes_common::file_io::{FileType, BUFF_SIZE};
+use hermes_common::http_codes::HttpCodes;
+use hermes_common::sniff;
+
+// Which files may be uploaded at all. Nothing in the configuration lets any file through.
+// A kind of file is named as the server records it, such as 'text', 'archive', or 'image/png', and 'image/*' names every image.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct UploadPolicy {
+    #[serde(default)]
+    pub max_file_size: Option<u64>, //Uploads that would leave a file larger than this many bytes are refused. None leaves them unlimited.
+    #[serde(default)]
+    pub allowed_types: Vec<String>, //When given, only these kinds of file may be uploaded
+    #[serde(default)]
+    pub blocked_types: Vec<String>,
+    #[serde(default)]
+    pub allowed_extensions: Vec<String>, //When given, only names ending in one of these may be uploaded. Extensions are compared without case, and without the '.'.
+    #[serde(default)]
+    pub blocked_extensions: Vec<String>
+}
+
+fn type_matches(rule: &str, kind: &FileType) -> bool {
+    let kind = kind.to_string();
+    match rule.strip_suffix("/*") {
+        Some(family) => kind.split_once('/').is_some_and(|(x, _)| x.eq_ignore_ascii_case(family)),
+        None => kind.eq_ignore_ascii_case(rule)
+    }
+}
+fn extension_matches(rule: &str, extension: &str) -> bool {
+    rule.trim_start_matches('.').eq_ignore_ascii_case(extension)
+}
+
+// The fewest bytes a file sent in this many frames can hold. Frames are counted against full blocks whatever size they go out at,
+// so only the last can be short.
+pub fn least_upload_size(offset: u64, frame_count: u64) -> u64 {
+    match frame_count {
+        0 => offset,
+        n => offset.saturating_add((n - 1).saturating_mul(BUFF_SIZE)).saturating_add(1)
+    }
+}
+
+impl UploadPolicy {
+    pub fn check_size(&self, size: u64) -> Result<(), (HttpCodes, String)> {
+        match self.max_file_size {
+            Some(max) if size > max => Err((HttpCodes::PayloadTooLarge, format!("uploads are limited to {max} bytes"))),
+            _ => Ok(())
+        }
+    }
+
+    // Both the name and the kind of file have to be let through
+    pub fn check_type(&self, path: &Path, kind: &FileType) -> Result<(), (HttpCodes, String)> {
+        let extension = path.extension().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        let refuse = |reason: String| Err((HttpCodes::UnsupportedMediaType, reason));
+        if self.blocked_extensions.iter().any(|x| extension_matches(x, &extension)) {
+            return refuse(format!("files ending in '.{extension}' may not be uploaded"));
+        }
+        if !self.allowed_extensions.is_empty() && !self.allowed_extensions.iter().any(|x| extension_matches(x, &extension)) {
+            return refuse(format!("only files ending in {} may be uploaded", self.allowed_extensions.iter().map(|x| format!("'.{}'", x.trim_start_matches('.'))).collect::<Vec<_>>().join(", ")));
+        }
+        if self.blocked_types.iter().any(|x| type_matches(x, kind)) || (!self.allowed_types.is_empty() && !self.allowed_types.iter().any(|x| type_matches(x, kind))) {
+            return refuse(format!("'{kind}' files may not be uploaded"));
+        }
+
+        Ok(())
+    }
+
+    // What an upload says about itself, checked before any of it is sent
+    pub fn check(&self, path: &Path, kind: &FileType, size: u64) -> Result<(), (HttpCodes, String)> {
+        self.check_type(path, kind)?;
+        self.check_size(size)
+    }
+
+    // What actually arrived, once it is staged. The client only says how many frames it sends, and what kind of file it thinks it has,
+    // so both are checked again. Encrypted files cannot be looked inside, so they are only held to the kind they were sent as.
+    pub fn check_received(&self, path: &Path, contents: &Path, sealed: bool) -> Result<(), (HttpCodes, String)> {
+        let metadata = std::fs::metadata(contents).map_err(|e| (HttpCodes::InternalServerError, e.to_string()))?;
+        self.check_size(metadata.len())?;
+        if sealed || (self.allowed_types.is_empty() && self.blocked_types.is_empty()) {
+            return Ok(());
+        }
+
+        let mut start = Vec::with_capacity(sniff::SNIFF_LENGTH);
+        if let Ok(f) = std::fs::File::open(contents) {
+            let _ = f.take(sniff::SNIFF_LENGTH as u64).read_to_end(&mut start);
+        }
+        let kind = sniff::detect(path.extension().and_then(|x| x.to_str()), &start).unwrap_or(FileType::Binary);
+        self.check_type(path, &kind)
+    }
+}
+
+#[test]
+fn test_upload_policy() {
+    let policy: UploadPolicy = toml::from_str(r#"
+        max_file_size = 10000
+        allowed_types = ["text", "image/*"]
+        blocked_extensions = [".exe"]
+    "#).unwrap();
+
+    assert_eq!(policy.check(Path::new("notes.txt"), &FileType::Text, 10000), Ok(()));
+    assert_eq!(policy.check(Path::new("photo.PNG"), &FileType::Other(String::from("image/png")), 10), Ok(()));
+    assert_eq!(policy.check(Path::new("notes.txt"), &FileType::Text, 10001).unwrap_err().0, HttpCodes::PayloadTooLarge);
+    assert_eq!(policy.check(Path::new("song.mp3"), &FileType::Audio, 10).unwrap_err().0, HttpCodes::UnsupportedMediaType);
+    assert_eq!(policy.check(Path::new("setup.EXE"), &FileType::Text, 10).unwrap_err().0, HttpCodes::UnsupportedMediaType);
+    assert_eq!(UploadPolicy::default().check(Path::new("setup.exe"), &FileType::Binary, u64::MAX), Ok(()));
+
+    let listed = UploadPolicy { allowed_extensions: vec![String::from("md")], ..Default::default() };
+    assert_eq!(listed.check_type(Path::new("README.md"), &FileType::Text), Ok(()));
+    assert!(listed.check_type(Path::new("README"), &FileType::Text).is_err());
+
+    // The last frame may be short, and every other is a full block
+    assert_eq!(least_upload_size(0, 0), 0);
+    assert_eq!(least_upload_size(0, 1), 1);
+    assert_eq!(least_upload_size(100, 3), 100 + 2 * BUFF_SIZE + 1);
+
+    // A file that claimed to be text when it is not is caught once it arrives
+    let dir = std::env::temp_dir().join(format!("hermes_upload_policy_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("staged"), b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0").unwrap();
+    assert_eq!(policy.check_received(Path::new("notes.txt"), &dir.join("staged"), false).unwrap_err().0, HttpCodes::UnsupportedMediaType);
+    assert_eq!(policy.check_received(Path::new("notes.txt"), &dir.join("staged"), true), Ok(()));
+    std::fs::write(dir.join("staged"), vec![b'a'; 10001]).unwrap();
+    assert_eq!(policy.check_received(Path::new("notes.txt"), &dir.join("staged"), false).unwrap_err().0, HttpCodes::PayloadTooLarge);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
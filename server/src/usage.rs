@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::credentials::Credentials;
+use crate::io_tools::FileDatabase;
+use crate::storage::RecordFilter;
+use hermes_common::messages::{FolderUsage, UsageReport};
+
+pub const DEFAULT_USAGE_CACHE: Duration = Duration::from_secs(5 * 60);
+
+// Everything beneath a folder, not following links. Anything that cannot be read is left out.
+fn bytes_beneath(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return 0
+    };
+
+    entries.flatten()
+        .map(|entry| match entry.path().symlink_metadata() {
+            Ok(m) if m.is_dir() => bytes_beneath(&entry.path()),
+            Ok(m) if m.is_file() => m.len(),
+            _ => 0
+        })
+        .sum()
+}
+
+// The bytes beneath each folder, by its name
+type FolderSizes = BTreeMap<String, u64>;
+
+// The bytes beneath each folder directly inside the root
+fn walk_folders(root: &Path) -> FolderSizes {
+    let entries = match std::fs::read_dir(root) {
+        Ok(e) => e,
+        Err(_) => return FolderSizes::new()
+    };
+
+    entries.flatten()
+        .filter(|x| x.path().symlink_metadata().is_ok_and(|m| m.is_dir()))
+        .map(|x| (x.file_name().to_string_lossy().to_string(), bytes_beneath(&x.path())))
+        .collect()
+}
+
+// Walking a large tree is slow, so what each top-level folder takes on disk is remembered for a while, for each root walked.
+// Walks happen on the blocking threads, so the lock is never held across an await.
+pub struct UsageCache {
+    lifetime: Duration,
+    walks: Mutex<HashMap<PathBuf, (Instant, FolderSizes)>>
+}
+impl Default for UsageCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_USAGE_CACHE)
+    }
+}
+impl UsageCache {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            walks: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Walks the root again once what was remembered of it is too old
+    pub fn folder_bytes(&self, root: &Path) -> FolderSizes {
+        if let Some((walked, sizes)) = self.walks.lock().unwrap_or_else(|e| e.into_inner()).get(root) {
+            if walked.elapsed() < self.lifetime {
+                return sizes.clone();
+            }
+        }
+
+        let sizes = walk_folders(root);
+        self.walks.lock().unwrap_or_else(|e| e.into_inner()).insert(root.to_path_buf(), (Instant::now(), sizes.clone()));
+        sizes
+    }
+
+    // Builds the report for someone whose home is this root. Administrators see what every user stores, and everyone else only themselves.
+    // What users store comes from the file database, as their quotas do. Files nobody owns, and files in quarantine, are not counted.
+    pub fn report(&self, asker: Option<&Credentials>, root: &Path, files: &FileDatabase) -> UsageReport {
+        let mut report = UsageReport {
+            total_bytes: fs2::total_space(root).ok(),
+            free_bytes: fs2::available_space(root).ok(),
+            ..Default::default()
+        };
+
+        let everyone = asker.is_some_and(|x| x.is_admin());
+        if let Some(user) = asker.filter(|_| !everyone) {
+            report.users.insert(user.username().to_string(), 0);
+        }
+        for (name, bytes) in self.folder_bytes(root) {
+            report.folders.insert(name, FolderUsage { files: 0, bytes });
+        }
+
+        for file in files.query(&RecordFilter::default()).into_iter().filter(|x| !x.is_quarantined()) {
+            if let Some(owner) = file.owner().filter(|x| everyone || asker.is_some_and(|a| x.username() == a.username())) {
+                *report.users.entry(owner.username().to_string()).or_default() += file.stored_bytes();
+            }
+
+            let folder = file.path().strip_prefix(root).ok().and_then(|x| x.components().next()).filter(|_| file.path().parent() != Some(root));
+            if let Some(folder) = folder {
+                report.folders.entry(folder.as_os_str().to_string_lossy().to_string()).or_default().files += 1;
+            }
+        }
+
+        report
+    }
+}
+
+#[test]
+fn test_usage_report() {
+    use hermes_common::file_io::FileType;
+
+    let root = std::env::temp_dir().join(format!("hermes_usage_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("docs").join("old")).unwrap();
+    std::fs::create_dir_all(root.join("media")).unwrap();
+    std::fs::write(root.join("docs").join("a.txt"), vec![b'a'; 100]).unwrap();
+    std::fs::write(root.join("docs").join("old").join("b.txt"), vec![b'b'; 50]).unwrap();
+    std::fs::write(root.join("media").join("c.bin"), vec![0; 400]).unwrap();
+    std::fs::write(root.join("loose.txt"), vec![b'l'; 10]).unwrap();
+
+    let (alice, bob, mut admin) = (Credentials::from("alice", "pass"), Credentials::from("bob", "pass"), Credentials::from("admin", "pass"));
+    admin.set_role(hermes_common::messages::Role::Admin);
+    let mut files = FileDatabase::new();
+    files.register_file(root.join("docs").join("a.txt"), Some(alice.clone()), FileType::Text).unwrap();
+    files.register_file(root.join("docs").join("old").join("b.txt"), Some(alice.clone()), FileType::Text).unwrap();
+    files.register_file(root.join("loose.txt"), Some(bob.clone()), FileType::Text).unwrap();
+
+    // Folders count every byte beneath them, recorded or not, and loose files belong to no folder
+    let cache = UsageCache::default();
+    let report = cache.report(Some(&admin), &root, &files);
+    assert_eq!(report.users, BTreeMap::from([(String::from("alice"), 150), (String::from("bob"), 10)]));
+    assert_eq!(report.folders, BTreeMap::from([(String::from("docs"), FolderUsage { files: 2, bytes: 150 }), (String::from("media"), FolderUsage { files: 0, bytes: 400 })]));
+    assert!(report.total_bytes.is_some() && report.free_bytes <= report.total_bytes);
+
+    // Everyone else sees only themselves, even when they store nothing
+    assert_eq!(cache.report(Some(&alice), &root, &files).users, BTreeMap::from([(String::from("alice"), 150)]));
+    assert_eq!(cache.report(Some(&Credentials::from("carol", "pass")), &root, &files).users, BTreeMap::from([(String::from("carol"), 0)]));
+    assert!(cache.report(None, &root, &files).users.is_empty());
+
+    // The walk is remembered until it is too old, while the database is read every time
+    std::fs::write(root.join("media").join("d.bin"), vec![0; 100]).unwrap();
+    files.register_file(root.join("media").join("d.bin"), Some(bob.clone()), FileType::Binary).unwrap();
+    assert_eq!(cache.report(Some(&admin), &root, &files).folders["media"], FolderUsage { files: 1, bytes: 400 });
+    assert_eq!(UsageCache::new(Duration::ZERO).report(Some(&admin), &root, &files).folders["media"], FolderUsage { files: 1, bytes: 500 });
+
+    let _ = std::fs::remove_dir_all(&root);
+}
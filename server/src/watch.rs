@@ -0,0 +1,204 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::event::{ModifyKind, RenameMode};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use crate::staging::STAGING_SUFFIX;
+use hermes_common::messages::FileEventKind;
+use hermes_common::session::unix_now;
+
+// How many events a slow subscriber can fall behind before it misses some
+const EVENT_BACKLOG: usize = 1024;
+// A change is credited to the user whose request named its path, or a folder above it, this recently
+const ACTOR_WINDOW: Duration = Duration::from_secs(30);
+// How many changes are remembered for clients asking what changed while they were away
+const JOURNAL_LENGTH: usize = 4096;
+
+// A change under the root, as the watcher saw it
+#[derive(Clone, PartialEq, Debug)]
+pub struct WatchEvent {
+    pub kind: FileEventKind,
+    pub path: PathBuf,
+    pub actor: Option<String>
+}
+
+// The latest changes, oldest first, with when each was seen. Nothing older than complete_since can be missing.
+#[derive(Default)]
+struct Journal {
+    changes: VecDeque<(u64, WatchEvent)>,
+    complete_since: u64
+}
+
+struct Shared {
+    sender: broadcast::Sender<WatchEvent>,
+    actors: Mutex<HashMap<PathBuf, (String, Instant)>>,
+    journal: Mutex<Journal>
+}
+impl Shared {
+    fn record(&self, event: &WatchEvent) {
+        let mut journal = self.journal.lock().unwrap_or_else(|e| e.into_inner());
+        if journal.changes.len() >= JOURNAL_LENGTH {
+            if let Some((at, _)) = journal.changes.pop_front() {
+                journal.complete_since = at;
+            }
+        }
+        journal.changes.push_back((unix_now(), event.clone()));
+    }
+
+    fn actor_of(&self, path: &Path) -> Option<String> {
+        let actors = self.actors.lock().unwrap_or_else(|e| e.into_inner());
+        path.ancestors()
+            .filter_map(|x| actors.get(x))
+            .find(|(_, at)| at.elapsed() <= ACTOR_WINDOW)
+            .map(|(name, _)| name.clone())
+    }
+
+    // Renames are split into the removal of the old name and the creation of the new one. Partial uploads are not reported until they are moved into place.
+    fn translate(&self, event: &Event) -> Vec<WatchEvent> {
+        let kinds: Vec<FileEventKind> = match event.kind {
+            EventKind::Create(_) => vec![FileEventKind::Created],
+            EventKind::Remove(_) => vec![FileEventKind::Deleted],
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => vec![FileEventKind::Deleted],
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => vec![FileEventKind::Created],
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => vec![FileEventKind::Deleted, FileEventKind::Created],
+            EventKind::Modify(ModifyKind::Metadata(_)) => Vec::new(),
+            EventKind::Modify(_) => vec![FileEventKind::Modified],
+            _ => Vec::new()
+        };
+
+        // A rename lists both names, in the same order as its kinds. Anything else has one kind for all of its paths.
+        let kind_of = |i: usize| match kinds.len() {
+            1 => Some(kinds[0]),
+            _ => kinds.get(i).copied()
+        };
+
+        event.paths.iter()
+            .enumerate()
+            .filter_map(|(i, path)| Some((path, kind_of(i)?)))
+            .filter(|(path, _)| !path.to_string_lossy().ends_with(STAGING_SUFFIX))
+            .map(|(path, kind)| WatchEvent { kind, path: path.clone(), actor: self.actor_of(path) })
+            .collect()
+    }
+}
+
+// Watches the root for changes and hands them to every connection that subscribed. Changes made through the server are credited to the user who made them.
+pub struct WatchHub {
+    shared: Arc<Shared>,
+    watcher: Mutex<Option<RecommendedWatcher>>
+}
+impl Debug for WatchHub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WatchHub(running: {}, subscribers: {})", self.is_running(), self.shared.sender.receiver_count())
+    }
+}
+impl Default for WatchHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl WatchHub {
+    // Nothing is watched until start is called
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(
+                Shared {
+                    sender: broadcast::channel(EVENT_BACKLOG).0,
+                    actors: Mutex::new(HashMap::new()),
+                    journal: Mutex::new(Journal::default())
+                }
+            ),
+            watcher: Mutex::new(None)
+        }
+    }
+
+    pub fn start(&self, root: &Path) -> Result<(), String> {
+        let shared = Arc::clone(&self.shared);
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            if let Ok(event) = result {
+                for e in shared.translate(&event) {
+                    shared.record(&e);
+                    // Fails only when nobody is subscribed
+                    let _ = shared.sender.send(e);
+                }
+            }
+        }).map_err(|e| e.to_string())?;
+        watcher.watch(root, RecursiveMode::Recursive).map_err(|e| e.to_string())?;
+        self.shared.journal.lock().unwrap_or_else(|e| e.into_inner()).complete_since = unix_now();
+
+        *self.watcher.lock().unwrap_or_else(|e| e.into_inner()) = Some(watcher);
+        Ok(())
+    }
+    pub fn is_running(&self) -> bool {
+        self.watcher.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WatchEvent> {
+        self.shared.sender.subscribe()
+    }
+
+    // The changes at or beneath a path after a time, oldest first, and whether that is all of them. The deletion of a folder above the path counts too.
+    pub fn changes_since(&self, path: &Path, since: u64) -> (Vec<(u64, WatchEvent)>, bool) {
+        let journal = self.shared.journal.lock().unwrap_or_else(|e| e.into_inner());
+        let changes = journal.changes.iter()
+            .filter(|(at, _)| *at > since)
+            .filter(|(_, e)| e.path.starts_with(path) || (e.kind == FileEventKind::Deleted && path.starts_with(&e.path)))
+            .cloned()
+            .collect();
+
+        (changes, self.is_running() && journal.complete_since <= since)
+    }
+
+    // Called with every path a change request names, before and after it runs, since the watcher can see the change before the request finishes
+    pub fn attribute(&self, path: &Path, actor: &str) {
+        let mut actors = self.shared.actors.lock().unwrap_or_else(|e| e.into_inner());
+        actors.retain(|_, (_, at)| at.elapsed() <= ACTOR_WINDOW);
+        actors.insert(path.to_path_buf(), (actor.to_string(), Instant::now()));
+    }
+}
+
+#[test]
+fn test_watch_translation() {
+    use notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    let hub = WatchHub::new();
+    hub.attribute(Path::new("/data/docs"), "alice");
+    let event = |kind: EventKind, paths: &[&str]| Event { kind, paths: paths.iter().map(PathBuf::from).collect(), attrs: Default::default() };
+    let kinds = |event: Event| hub.shared.translate(&event).into_iter().map(|x| (x.kind, x.path, x.actor)).collect::<Vec<_>>();
+
+    assert_eq!(kinds(event(EventKind::Create(CreateKind::File), &["/data/docs/a.txt"])), vec![(FileEventKind::Created, PathBuf::from("/data/docs/a.txt"), Some(String::from("alice")))]);
+    assert_eq!(kinds(event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &["/data/b.txt"])), vec![(FileEventKind::Modified, PathBuf::from("/data/b.txt"), None)]);
+    assert_eq!(kinds(event(EventKind::Remove(RemoveKind::File), &["/data/b.txt"]))[0].0, FileEventKind::Deleted);
+    assert!(kinds(event(EventKind::Modify(ModifyKind::Metadata(MetadataKind::AccessTime)), &["/data/b.txt"])).is_empty());
+
+    // A finished upload is the rename of its partial file, which is only reported under its real name
+    let finished = kinds(event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/data/c.txt.hermes.part", "/data/c.txt"]));
+    assert_eq!(finished, vec![(FileEventKind::Created, PathBuf::from("/data/c.txt"), None)]);
+    let renamed = kinds(event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/data/d.txt", "/data/e.txt"]));
+    assert_eq!(renamed.iter().map(|x| x.0).collect::<Vec<_>>(), vec![FileEventKind::Deleted, FileEventKind::Created]);
+}
+
+#[test]
+fn test_watch_journal() {
+    let hub = WatchHub::new();
+    let event = |kind: FileEventKind, path: &str| WatchEvent { kind, path: PathBuf::from(path), actor: None };
+    hub.shared.record(&event(FileEventKind::Created, "/data/docs/a.txt"));
+    hub.shared.record(&event(FileEventKind::Modified, "/data/b.txt"));
+    hub.shared.record(&event(FileEventKind::Deleted, "/data/docs"));
+
+    // Nothing is complete until the watcher runs
+    let (changes, complete) = hub.changes_since(Path::new("/data/docs/a.txt"), 0);
+    assert_eq!(changes.into_iter().map(|(_, e)| e.path).collect::<Vec<_>>(), vec![PathBuf::from("/data/docs/a.txt"), PathBuf::from("/data/docs")]);
+    assert!(!complete);
+    assert!(hub.changes_since(Path::new("/data/docs"), unix_now() + 1).0.is_empty());
+
+    // The oldest changes are dropped once the journal is full, and it is only complete from the oldest one left
+    for _ in 0..JOURNAL_LENGTH {
+        hub.shared.record(&event(FileEventKind::Modified, "/data/c.txt"));
+    }
+    assert!(hub.changes_since(Path::new("/data/docs/a.txt"), 0).0.is_empty());
+    assert!(hub.shared.journal.lock().unwrap().complete_since > 0);
+}
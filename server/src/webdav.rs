@@ -0,0 +1,251 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+
+use crate::gateway::{Session, broken, content_length, length_required};
+use crate::io_tools::civil_date;
+use crate::state::ServerState;
+use hermes_common::file_io::{DirectoryContent, DirectoryInfo, FileInfo};
+
+// Where WebDAV is mounted on the gateway
+const PREFIX: &str = "/dav";
+const ALLOWED: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, MKCOL, DELETE";
+
+// WebDAV class 1, so the server can be mounted as a network drive. Each method is answered with the requests a client would send,
+// over the gateway's in-process connections, so ownership and permissions are checked exactly as they are for the client.
+// There is no locking, which some clients need before they will write.
+pub fn routes() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/dav", any(dav_root))
+        .route("/dav/", any(dav_root))
+        .route("/dav/*path", any(dav))
+}
+
+async fn dav_root(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, method: Method, headers: HeaderMap, body: Body) -> Response {
+    serve(state, peer, method, "", headers, body).await
+}
+async fn dav(State(state): State<Arc<ServerState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, method: Method, Path(path): Path<String>, headers: HeaderMap, body: Body) -> Response {
+    serve(state, peer, method, &path, headers, body).await
+}
+
+async fn serve(state: Arc<ServerState>, peer: SocketAddr, method: Method, path: &str, headers: HeaderMap, body: Body) -> Response {
+    // Clients ask what is spoken before they log in
+    if method == Method::OPTIONS {
+        return (StatusCode::OK, [("dav", "1"), ("allow", ALLOWED), ("ms-author-via", "DAV")]).into_response();
+    }
+    if !ALLOWED.split(", ").any(|x| x == method.as_str()) {
+        return (StatusCode::METHOD_NOT_ALLOWED, [("allow", ALLOWED)], "locking, copying, and moving are not supported\n").into_response();
+    }
+    let length = match method == Method::PUT {
+        true => match content_length(&headers) {
+            Some(l) => l,
+            None => return length_required()
+        },
+        false => 0
+    };
+
+    let mut session = match Session::open(&state, peer, &headers).await {
+        Ok(s) => s,
+        Err(response) => return response
+    };
+    let path = path.trim_end_matches('/');
+    match method.as_str() {
+        "PROPFIND" => {
+            // A missing Depth means infinity, which is answered like 1
+            let children = headers.get("depth").and_then(|x| x.to_str().ok()) != Some("0");
+            let response = propfind(&mut session, path, children).await;
+            session.finish(response).await
+        },
+        "HEAD" => {
+            let response = head(&mut session, path).await;
+            session.finish(response).await
+        },
+        "GET" => session.download(path).await,
+        "PUT" => session.upload(path, length, body).await,
+        "MKCOL" => {
+            let mut response = session.make_dir(path).await;
+            if response.status() == StatusCode::OK {
+                *response.status_mut() = StatusCode::CREATED;
+            }
+            response
+        },
+        // A collection always goes with everything in it
+        _ => session.delete(path, true).await
+    }
+}
+
+enum Resource {
+    File(FileInfo),
+    Collection(DirectoryInfo)
+}
+// The root is always a collection. Anything else is a file if it can be stat'ed, and otherwise listed as a collection.
+async fn resource(session: &mut Session, path: &str) -> Result<Resource, Response> {
+    if !path.is_empty() {
+        if let Some(f) = session.stat(path).await? {
+            return Ok(Resource::File(f));
+        }
+    }
+
+    let listing = session.listing(Some(path.to_string()).filter(|x| !x.is_empty())).await?;
+    serde_json::from_slice(&listing).map(Resource::Collection).map_err(|e| broken(e.to_string()))
+}
+
+async fn propfind(session: &mut Session, path: &str, children: bool) -> Result<Response, Response> {
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    match resource(session, path).await? {
+        Resource::File(file) => body.push_str(&file_properties(path, &file)),
+        Resource::Collection(listing) => {
+            body.push_str(&collection_properties(path));
+            for entry in listing.contents().iter().filter(|_| children) {
+                match entry {
+                    DirectoryContent::File(f) => body.push_str(&file_properties(&child_path(path, f.name()), f)),
+                    DirectoryContent::Dir(d) => body.push_str(&collection_properties(&child_path(path, d.name()))),
+                    // WebDAV has no way to describe a link, so clients reach what it leads to by its target instead
+                    DirectoryContent::Symlink(_) => { }
+                }
+            }
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+
+    Ok((StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response())
+}
+async fn head(session: &mut Session, path: &str) -> Result<Response, Response> {
+    match resource(session, path).await? {
+        Resource::File(file) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file.size()));
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+            if let Ok(e) = HeaderValue::from_str(&etag(&file)) {
+                headers.insert(header::ETAG, e);
+            }
+            if let Some(m) = file.modified().and_then(|x| HeaderValue::from_str(&http_date(x)).ok()) {
+                headers.insert(header::LAST_MODIFIED, m);
+            }
+            Ok((StatusCode::OK, headers).into_response())
+        },
+        Resource::Collection(_) => Ok(StatusCode::OK.into_response())
+    }
+}
+
+fn child_path(parent: &str, name: &str) -> String {
+    match parent.is_empty() {
+        true => name.to_string(),
+        false => format!("{parent}/{name}")
+    }
+}
+// Collections end with a slash, which clients rely on when they resolve the entries beneath them
+fn href(path: &str, collection: bool) -> String {
+    let mut result = format!("{PREFIX}/");
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => result.push(b as char),
+            _ => result.push_str(&format!("%{b:02X}"))
+        }
+    }
+    if collection && !path.is_empty() {
+        result.push('/');
+    }
+    result
+}
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+fn display_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+// The checksum when the server knows it, since it only changes with the contents. Otherwise the size and modification time.
+fn etag(file: &FileInfo) -> String {
+    match file.checksum() {
+        Some(c) => format!("\"{c}\""),
+        None => format!("\"{}-{}\"", file.size(), file.modified().unwrap_or_default())
+    }
+}
+
+fn properties(path: &str, collection: bool, props: &str) -> String {
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>{props}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape_xml(&href(path, collection)),
+        escape_xml(display_name(path))
+    )
+}
+fn collection_properties(path: &str) -> String {
+    properties(path, true, "<D:resourcetype><D:collection/></D:resourcetype>")
+}
+fn file_properties(path: &str, file: &FileInfo) -> String {
+    let mut props = format!("<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>application/octet-stream</D:getcontenttype><D:getetag>{}</D:getetag>", file.size(), escape_xml(&etag(file)));
+    if let Some(m) = file.modified() {
+        props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(m)));
+    }
+    properties(path, false, &props)
+}
+
+// Unix seconds in the IMF-fixdate form HTTP uses, such as 'Thu, 01 Jan 1970 00:00:00 GMT'
+fn http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; //The epoch was a Thursday
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (days, time) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days);
+
+    format!("{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT", WEEKDAYS[(days % 7) as usize], MONTHS[(month - 1) as usize], time / 3600, time % 3600 / 60, time % 60)
+}
+
+#[test]
+fn test_webdav_formatting() {
+    assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert_eq!(http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+    assert_eq!(http_date(1792192354), "Fri, 16 Oct 2026 23:12:34 GMT");
+
+    assert_eq!(href("", true), "/dav/");
+    assert_eq!(href("my docs/a&b.txt", false), "/dav/my%20docs/a%26b.txt");
+    assert_eq!(href("my docs", true), "/dav/my%20docs/");
+    assert_eq!(display_name("my docs/a&b.txt"), "a&b.txt");
+    assert_eq!(escape_xml("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+}
+#[tokio::test]
+async fn test_webdav_requests() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::gateway::start_gateway;
+
+    let path = std::env::temp_dir().join(format!("hermes_webdav_users_{}.json", std::process::id()));
+    std::fs::write(&path, r#"[{"username":"user","password":"pass"}]"#).unwrap();
+    let mut state = ServerState::new();
+    state.users.get_mut().open(path.to_string_lossy().to_string()).unwrap();
+    let state = Arc::new(state);
+    let addr = start_gateway(&state, "127.0.0.1:0").await.unwrap();
+
+    let send = |request: String| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+    // 'user:pass'
+    let request = |method: &str, target: &str, extra: &str| format!("{method} {target} HTTP/1.1\r\nHost: hermes\r\nConnection: close\r\nAuthorization: Basic dXNlcjpwYXNz\r\n{extra}\r\n");
+
+    let response = send(String::from("OPTIONS /dav/ HTTP/1.1\r\nHost: hermes\r\nConnection: close\r\n\r\n")).await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("dav: 1\r\n"));
+    assert!(send(request("LOCK", "/dav/file.txt", "")).await.starts_with("HTTP/1.1 405"));
+    assert!(send(String::from("PROPFIND /dav/ HTTP/1.1\r\nHost: hermes\r\nConnection: close\r\n\r\n")).await.starts_with("HTTP/1.1 401"));
+
+    // The home directory is always a collection
+    let response = send(request("PROPFIND", "/dav/", "Depth: 0\r\n")).await;
+    assert!(response.starts_with("HTTP/1.1 207"));
+    assert!(response.contains("<D:href>/dav/</D:href>"));
+    assert!(response.contains("<D:collection/>"));
+    assert_eq!(response.matches("<D:response>").count(), 1);
+
+    let missing = format!("/dav/hermes-webdav-missing-{}", std::process::id());
+    assert!(send(request("PROPFIND", &missing, "Depth: 1\r\n")).await.starts_with("HTTP/1.1 404"));
+    assert!(send(request("DELETE", &missing, "")).await.starts_with("HTTP/1.1 404"));
+
+    let _ = std::fs::remove_file(&path);
+}